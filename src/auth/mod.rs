@@ -0,0 +1,159 @@
+//! Authentication for the HTTP API
+//!
+//! Every API route requires a bearer token, either the bootstrap admin token
+//! from the config, an API token stored in the database, or a JWT from the configured
+//! OIDC issuer.
+pub mod oidc;
+
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::{header, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::config::CONFIG;
+use crate::db::namespace::Namespace;
+use crate::db::permission::{Permission, Role, ANY_TAG};
+use crate::db::token::ApiToken;
+use crate::namespace::ScopedNamespace;
+use oidc::OidcIdentity;
+use crate::errors::{Error, Result};
+
+/// The authenticated caller of a request
+#[derive(Debug, Clone)]
+pub enum Identity {
+    /// The bootstrap admin token from the config
+    Admin,
+    Token(ApiToken),
+    Oidc(OidcIdentity),
+}
+
+impl Identity {
+    pub fn is_admin(&self) -> bool {
+        match self {
+            Identity::Admin => true,
+            Identity::Token(token) => token.admin,
+            Identity::Oidc(oidc) => oidc.admin,
+        }
+    }
+
+    /// Human readable name of the caller
+    pub fn name(&self) -> String {
+        match self {
+            Identity::Admin => "admin".to_owned(),
+            Identity::Token(token) => token.name.clone(),
+            Identity::Oidc(oidc) => oidc.name.clone(),
+        }
+    }
+
+    /// Fail unless the caller is an admin
+    pub fn require_admin(&self) -> Result<()> {
+        if self.is_admin() {
+            Ok(())
+        } else {
+            Err(Error::Forbidden)
+        }
+    }
+
+    /// Fail unless the caller administers the namespace of the request
+    ///
+    /// That's admins in the global scope, and callers granted the admin role on every tag of the
+    /// namespace under `/ns/{namespace}/`.
+    pub async fn require_namespace_admin(&self) -> Result<()> {
+        match crate::namespace::current() {
+            Some(_) => self.require(Role::Admin, ANY_TAG).await,
+            None => self.require_admin(),
+        }
+    }
+
+    /// Fail unless the caller has been granted a role on a tag
+    ///
+    /// Admins are allowed everything.
+    pub async fn require(&self, role: Role, tag: &str) -> Result<()> {
+        match self {
+            _ if self.is_admin() => Ok(()),
+            Identity::Token(token) if Permission::allows(&token.id, role, tag).await? => Ok(()),
+            Identity::Oidc(oidc) if oidc.allows(role, tag) => Ok(()),
+            _ => {
+                tracing::debug!(caller = ?self.name(), ?role, ?tag, "permission denied");
+                Err(Error::Forbidden)
+            }
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Identity {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self> {
+        parts
+            .extensions
+            .get::<Identity>()
+            .cloned()
+            .ok_or(Error::Unauthorized)
+    }
+}
+
+/// Compare two strings without short-circuiting on the first difference
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+fn bearer_token(req: &Request) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Resolve a bearer token into the identity it belongs to
+pub async fn authenticate(token: &str) -> Result<Option<Identity>> {
+    let config = CONFIG
+        .get()
+        .ok_or_else(|| color_eyre::eyre::eyre!("config not loaded"))?;
+    let admin_token = config.admin_token.as_deref();
+    if admin_token.is_some_and(|admin| constant_time_eq(admin, token)) {
+        return Ok(Some(Identity::Admin));
+    }
+
+    if oidc::is_jwt(token) && config.oidc_issuer.is_some() {
+        return match oidc::authenticate(config, token).await {
+            Ok(identity) => Ok(identity.map(Identity::Oidc)),
+            Err(e) => {
+                tracing::debug!(?e, "rejected JWT");
+                Ok(None)
+            }
+        };
+    }
+
+    Ok(ApiToken::find(token).await?.map(Identity::Token))
+}
+
+/// Middleware rejecting requests without a valid bearer token
+///
+/// The resolved [`Identity`] is added to the request extensions for handlers to extract, and
+/// the request is handled in the scope of its namespace, see [`crate::namespace`].
+pub async fn require_auth(mut req: Request, next: Next) -> Result<Response> {
+    let token = bearer_token(&req).ok_or(Error::Unauthorized)?.to_owned();
+    let identity = authenticate(&token).await?.ok_or(Error::Unauthorized)?;
+
+    // only looked up once authenticated, so namespaces can't be probed without a token
+    let namespace = req
+        .extensions()
+        .get::<ScopedNamespace>()
+        .map(|ns| ns.0.clone());
+    if let Some(namespace) = &namespace {
+        Namespace::get(namespace).await?.ok_or(Error::NotFound)?;
+    }
+
+    tracing::trace!(caller = ?identity.name(), ?namespace, "authenticated request");
+    req.extensions_mut().insert(identity);
+    Ok(crate::namespace::scope(namespace, next.run(req)).await)
+}
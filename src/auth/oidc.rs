@@ -0,0 +1,278 @@
+//! OIDC / JWT authentication
+//!
+//! Bearer tokens that look like JWTs are validated against the JWKS of the configured
+//! OIDC issuer, and their claims are mapped to roles.
+//!
+//! Role claims are either the configured admin role, or `<role>:<tag>` strings,
+//! i.e `upload:updates-testing` or `assemble:*`.
+use std::time::{Duration, Instant};
+
+use color_eyre::{eyre::eyre, Result};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+
+use crate::config::Config;
+use crate::db::permission::Role;
+
+/// How long fetched signing keys are trusted before refetching
+const JWKS_TTL: Duration = Duration::from_secs(3600);
+/// Minimum time between refetches for tokens signed with an unknown key,
+/// so tokens with made up `kid`s can't make us hammer the issuer
+const JWKS_MIN_REFETCH: Duration = Duration::from_secs(60);
+
+static JWKS: RwLock<Option<(JwkSet, Instant)>> = RwLock::const_new(None);
+
+/// Caller authenticated through OIDC
+#[derive(Debug, Clone)]
+pub struct OidcIdentity {
+    pub name: String,
+    pub admin: bool,
+    /// Roles granted per tag, `*` for every tag
+    pub grants: Vec<(Role, String)>,
+}
+
+impl OidcIdentity {
+    pub fn allows(&self, role: Role, tag: &str) -> bool {
+        self.grants.iter().any(|(r, t)| {
+            (*r == role || *r == Role::Admin) && (t == tag || t == crate::db::permission::ANY_TAG)
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    jwks_uri: String,
+}
+
+/// Whether a bearer token looks like a JWT rather than an API token
+pub fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+async fn jwks_url(config: &Config, issuer: &str) -> Result<String> {
+    if let Some(url) = &config.oidc_jwks_url {
+        return Ok(url.clone());
+    }
+
+    let discovery_url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+    let discovery: Discovery = reqwest::get(&discovery_url)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(discovery.jwks_uri)
+}
+
+/// Whether cached signing keys can be used as-is for a token signed with `kid`
+fn jwks_fresh(set: &JwkSet, age: Duration, kid: Option<&str>) -> bool {
+    let known = kid.is_none_or(|kid| set.find(kid).is_some());
+    age < JWKS_TTL && (known || age < JWKS_MIN_REFETCH)
+}
+
+/// Get the issuer's signing keys, refetching them if stale or if `kid` is unknown
+///
+/// Unknown keys are refetched at most once per [`JWKS_MIN_REFETCH`].
+async fn jwks(config: &Config, issuer: &str, kid: Option<&str>) -> Result<JwkSet> {
+    if let Some((set, fetched)) = JWKS.read().await.as_ref() {
+        if jwks_fresh(set, fetched.elapsed(), kid) {
+            return Ok(set.clone());
+        }
+    }
+
+    // hold the lock while fetching, so concurrent requests don't all refetch
+    let mut cached = JWKS.write().await;
+    if let Some((set, fetched)) = cached.as_ref() {
+        if jwks_fresh(set, fetched.elapsed(), kid) {
+            return Ok(set.clone());
+        }
+    }
+
+    let url = jwks_url(config, issuer).await?;
+    tracing::debug!(?url, "fetching OIDC signing keys");
+    let set: JwkSet = reqwest::get(&url).await?.error_for_status()?.json().await?;
+    *cached = Some((set.clone(), Instant::now()));
+    Ok(set)
+}
+
+/// Algorithms a signing key may be used with, from its `alg` or else its key type
+///
+/// The token header can't be trusted to choose, symmetric keys are never accepted.
+fn key_algorithms(jwk: &Jwk) -> Result<Vec<Algorithm>> {
+    if let Some(alg) = &jwk.common.key_algorithm {
+        let alg = match alg {
+            KeyAlgorithm::RS256 => Algorithm::RS256,
+            KeyAlgorithm::RS384 => Algorithm::RS384,
+            KeyAlgorithm::RS512 => Algorithm::RS512,
+            KeyAlgorithm::PS256 => Algorithm::PS256,
+            KeyAlgorithm::PS384 => Algorithm::PS384,
+            KeyAlgorithm::PS512 => Algorithm::PS512,
+            KeyAlgorithm::ES256 => Algorithm::ES256,
+            KeyAlgorithm::ES384 => Algorithm::ES384,
+            KeyAlgorithm::EdDSA => Algorithm::EdDSA,
+            alg => return Err(eyre!("unsupported signing key algorithm {alg:?}")),
+        };
+        return Ok(vec![alg]);
+    }
+
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => Ok(vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ]),
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => Ok(vec![Algorithm::ES256]),
+            EllipticCurve::P384 => Ok(vec![Algorithm::ES384]),
+            ref curve => Err(eyre!("unsupported signing key curve {curve:?}")),
+        },
+        AlgorithmParameters::OctetKeyPair(params) if params.curve == EllipticCurve::Ed25519 => {
+            Ok(vec![Algorithm::EdDSA])
+        }
+        _ => Err(eyre!("unsupported signing key type")),
+    }
+}
+
+/// Look up a claim by a dotted path, i.e `realm_access.roles`
+fn claim<'a>(claims: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    path.split('.')
+        .try_fold(claims, |value, key| value.get(key))
+}
+
+/// Map the role claim of a token into an identity
+fn identity_from_claims(config: &Config, claims: &serde_json::Value) -> Result<OidcIdentity> {
+    let subject = claims
+        .get("sub")
+        .and_then(|s| s.as_str())
+        .ok_or_else(|| eyre!("token has no subject"))?;
+    let name = ["preferred_username", "email", "name"]
+        .iter()
+        .find_map(|c| claims.get(c).and_then(|v| v.as_str()))
+        .unwrap_or(subject)
+        .to_owned();
+
+    let roles = claim(claims, &config.oidc_roles_claim)
+        .and_then(|r| r.as_array())
+        .map(|r| r.iter().filter_map(|v| v.as_str()).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let admin = roles.contains(&config.oidc_admin_role.as_str());
+    let grants = roles
+        .iter()
+        .filter_map(|r| {
+            let (role, tag) = r.split_once(':')?;
+            Some((role.parse().ok()?, tag.to_owned()))
+        })
+        .collect();
+
+    Ok(OidcIdentity {
+        name,
+        admin,
+        grants,
+    })
+}
+
+/// Validate a JWT against the configured issuer
+///
+/// Returns `None` if OIDC is not configured.
+pub async fn authenticate(config: &Config, token: &str) -> Result<Option<OidcIdentity>> {
+    let Some(issuer) = &config.oidc_issuer else {
+        return Ok(None);
+    };
+
+    let header = decode_header(token)?;
+    let set = jwks(config, issuer, header.kid.as_deref()).await?;
+    let jwk = match &header.kid {
+        Some(kid) => set.find(kid),
+        None => set.keys.first(),
+    }
+    .ok_or_else(|| eyre!("no matching signing key for token"))?;
+
+    let algorithms = key_algorithms(jwk)?;
+    if !algorithms.contains(&header.alg) {
+        return Err(eyre!("token algorithm {:?} doesn't match its signing key", header.alg));
+    }
+    let mut validation = Validation::new(header.alg);
+    validation.algorithms = algorithms;
+    validation.set_issuer(&[issuer]);
+    match &config.oidc_audience {
+        Some(aud) => validation.set_audience(&[aud]),
+        None => validation.validate_aud = false,
+    }
+
+    let claims = decode::<serde_json::Value>(token, &DecodingKey::from_jwk(jwk)?, &validation)?;
+    identity_from_claims(config, &claims.claims).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_identity_from_claims() {
+        let config = Config::parse_from(["subatomic-ng", "--host=localhost"]);
+        let claims = serde_json::json!({
+            "sub": "1234",
+            "preferred_username": "jdoe",
+            "realm_access": {
+                "roles": ["upload:updates-testing", "assemble:*", "offline_access"]
+            }
+        });
+
+        let identity = identity_from_claims(&config, &claims).unwrap();
+        assert_eq!(identity.name, "jdoe");
+        assert!(!identity.admin);
+        assert!(identity.allows(Role::Upload, "updates-testing"));
+        assert!(!identity.allows(Role::Upload, "updates"));
+        assert!(identity.allows(Role::Assemble, "updates"));
+    }
+
+    #[test]
+    fn test_key_algorithms() {
+        let jwk = |value: serde_json::Value| serde_json::from_value::<Jwk>(value).unwrap();
+        let rsa = serde_json::json!({ "kty": "RSA", "n": "AQAB", "e": "AQAB" });
+        let algorithms = key_algorithms(&jwk(rsa.clone())).unwrap();
+        assert!(algorithms.contains(&Algorithm::RS256));
+        assert!(!algorithms.contains(&Algorithm::HS256));
+
+        let mut pinned = rsa;
+        pinned["alg"] = "PS256".into();
+        assert_eq!(key_algorithms(&jwk(pinned)).unwrap(), [Algorithm::PS256]);
+
+        let ec = serde_json::json!({ "kty": "EC", "crv": "P-256", "x": "AA", "y": "AA" });
+        assert_eq!(key_algorithms(&jwk(ec)).unwrap(), [Algorithm::ES256]);
+
+        let oct = serde_json::json!({ "kty": "oct", "k": "c2VjcmV0" });
+        assert!(key_algorithms(&jwk(oct)).is_err());
+        let hmac = serde_json::json!({ "kty": "oct", "k": "c2VjcmV0", "alg": "HS256" });
+        assert!(key_algorithms(&jwk(hmac)).is_err());
+    }
+
+    #[test]
+    fn test_jwks_fresh() {
+        let set: JwkSet = serde_json::from_value(serde_json::json!({
+            "keys": [{ "kty": "RSA", "kid": "known", "n": "AQAB", "e": "AQAB" }]
+        }))
+        .unwrap();
+        let just_fetched = Duration::from_secs(1);
+        assert!(jwks_fresh(&set, just_fetched, Some("known")));
+        // just fetched, an unknown key won't show up by refetching
+        assert!(jwks_fresh(&set, just_fetched, Some("unknown")));
+
+        let minutes = Duration::from_secs(120);
+        assert!(jwks_fresh(&set, minutes, Some("known")));
+        assert!(!jwks_fresh(&set, minutes, Some("unknown")));
+        assert!(!jwks_fresh(&set, Duration::from_secs(7200), Some("known")));
+    }
+}
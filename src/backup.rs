@@ -0,0 +1,329 @@
+//! Backups of the database, and of the objects it expects the object store to hold
+//!
+//! A backup is a directory under `BACKUP_DIR` holding the records of every table as SurrealQL
+//! `INSERT` statements, and a manifest listing the objects stored when it was taken. Objects
+//! themselves are left to the object store's own backups, or to its replica: restoring a
+//! backup replays the records into an empty database, applies the migrations newer than the
+//! backup to them, then checks the store still has every object of the manifest.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+use crate::db::{event_log::LOG_TABLE, job::JOB_TABLE, lease::LEASE_TABLE, migrations, DB};
+use crate::obj_store::object_store;
+
+/// File of a backup listing its tables and objects
+pub const MANIFEST_FILE: &str = "manifest.json";
+/// File of a backup holding the records, one SurrealQL statement per line, JSON-encoded as
+/// records can hold newlines
+pub const DATA_FILE: &str = "data.jsonl";
+/// Prefix of backup names, they're followed by a ULID
+const NAME_PREFIX: &str = "backup-";
+/// Table marking a restore in progress, so one that failed part way can be retried
+const RESTORE_TABLE: &str = "backup_restore";
+/// Tables left out of backups: the schema is recreated by migrations, leases and jobs only
+/// make sense to the servers that held or ran them, and restores to the server running them
+const SKIPPED_TABLES: &[&str] = &[
+    migrations::MIGRATION_TABLE,
+    LEASE_TABLE,
+    JOB_TABLE,
+    RESTORE_TABLE,
+];
+
+/// What a backup holds, written alongside its data
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub name: String,
+    pub created: chrono::DateTime<chrono::Utc>,
+    /// Migration version of the database when it was backed up, see [`migrations`]
+    pub schema_version: u32,
+    /// Records backed up, by table
+    pub tables: BTreeMap<String, usize>,
+    /// Size in bytes of every stored object, by key
+    pub objects: BTreeMap<String, u64>,
+}
+
+/// Summary of a backup, without its object list
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupInfo {
+    pub name: String,
+    pub created: chrono::DateTime<chrono::Utc>,
+    pub schema_version: u32,
+    pub tables: BTreeMap<String, usize>,
+    /// Number of objects in the manifest
+    pub objects: usize,
+}
+
+impl From<&BackupManifest> for BackupInfo {
+    fn from(manifest: &BackupManifest) -> Self {
+        Self {
+            name: manifest.name.clone(),
+            created: manifest.created,
+            schema_version: manifest.schema_version,
+            tables: manifest.tables.clone(),
+            objects: manifest.objects.len(),
+        }
+    }
+}
+
+/// Outcome of [`restore`]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RestoreReport {
+    /// Records restored, by table
+    pub tables: BTreeMap<String, usize>,
+    /// Objects of the manifest found in the store
+    pub objects: usize,
+    /// Objects of the manifest the store doesn't have, or has with another size
+    pub missing: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DbInfo {
+    /// Definition of every table, by name
+    tables: BTreeMap<String, String>,
+}
+
+/// Tables whose records are backed up, leaving out views, which are computed from others
+async fn backed_up_tables() -> Result<Vec<String>> {
+    let info: Option<DbInfo> = DB.retry(|| DB.query("INFO FOR DB;")).await?.take(0)?;
+    let info = info.ok_or_else(|| eyre!("no database info returned"))?;
+    Ok(info
+        .tables
+        .into_iter()
+        .filter(|(name, definition)| {
+            !SKIPPED_TABLES.contains(&name.as_str()) && !definition.contains(" AS SELECT ")
+        })
+        .map(|(name, _)| name)
+        .collect())
+}
+
+/// Whether `name` can be the name of a backup, so it's safe to join to the backup dir
+pub fn is_valid_name(name: &str) -> bool {
+    name.strip_prefix(NAME_PREFIX)
+        .is_some_and(|id| ulid::Ulid::from_string(id).is_ok())
+}
+
+/// Read the records of every table in a single transaction, so the backup is a consistent
+/// snapshot, returning each table's record count and records rendered as SurrealQL
+///
+/// Every record is held in memory until it's written out.
+async fn snapshot(tables: &[String]) -> Result<Vec<(usize, String)>> {
+    let mut query = DB.query("BEGIN TRANSACTION;");
+    let mut dumps = Vec::with_capacity(tables.len());
+    for (i, table) in tables.iter().enumerate() {
+        query = query
+            .query(format!(
+                "LET $rows_{i} = (SELECT * FROM type::table($table_{i}) ORDER BY id);"
+            ))
+            .bind((format!("table_{i}"), table.clone()));
+        // casting records to a string renders them as SurrealQL, keeping record IDs and datetimes
+        dumps.push(format!("[array::len($rows_{i}), <string> $rows_{i}]"));
+    }
+    // a RETURN ends the transaction and stands in for the result of all of its statements,
+    // so every table is returned at once
+    let mut res = query
+        .query(format!("RETURN [{}];", dumps.join(", ")))
+        .query("COMMIT TRANSACTION;")
+        .await?;
+
+    let dumps: Vec<(usize, String)> = res.take(0)?;
+    if dumps.len() != tables.len() {
+        return Err(eyre!("{} of {} tables returned", dumps.len(), tables.len()));
+    }
+    Ok(dumps)
+}
+
+/// Back up every table and the object list into a new directory under `dir`
+pub async fn create(dir: PathBuf) -> Result<BackupInfo> {
+    let name = format!("{NAME_PREFIX}{}", ulid::Ulid::new());
+    // written under a hidden name first, so unfinished backups are never listed
+    let staging = dir.join(format!(".{name}"));
+    tokio::fs::create_dir_all(&staging).await?;
+    tracing::info!(%name, "backing up database");
+
+    let res = async {
+        let file = tokio::fs::File::create(staging.join(DATA_FILE)).await?;
+        let mut out = tokio::io::BufWriter::new(file);
+        let mut tables = BTreeMap::new();
+        let names = backed_up_tables().await?;
+        let dumps = snapshot(&names).await?;
+        for (table, (count, rows)) in names.into_iter().zip(dumps) {
+            if count > 0 {
+                // records restored by an earlier, failed attempt are skipped
+                let statement = format!("INSERT IGNORE INTO {table} {rows};");
+                let line = serde_json::to_string(&statement)?;
+                out.write_all(format!("{line}\n").as_bytes()).await?;
+            }
+            tables.insert(table, count);
+        }
+        out.flush().await?;
+
+        let manifest = BackupManifest {
+            name: name.clone(),
+            created: chrono::Utc::now(),
+            schema_version: migrations::current_version().await?,
+            tables,
+            objects: object_store().backend.list_objects().await?,
+        };
+        tokio::fs::write(
+            staging.join(MANIFEST_FILE),
+            serde_json::to_vec_pretty(&manifest)?,
+        )
+        .await?;
+        tokio::fs::rename(&staging, dir.join(&name)).await?;
+        Ok(BackupInfo::from(&manifest))
+    }
+    .await;
+    if res.is_err() {
+        let _ = tokio::fs::remove_dir_all(&staging).await;
+    }
+    res
+}
+
+/// Read the manifest of a backup
+pub async fn read_manifest(dir: &Path, name: &str) -> Result<BackupManifest> {
+    if !is_valid_name(name) {
+        return Err(eyre!("invalid backup name {name}"));
+    }
+    let manifest = tokio::fs::read(dir.join(name).join(MANIFEST_FILE)).await?;
+    Ok(serde_json::from_slice(&manifest)?)
+}
+
+/// Every finished backup under `dir`, oldest first
+pub async fn list(dir: &Path) -> Result<Vec<BackupInfo>> {
+    let mut backups = Vec::new();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(backups),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !is_valid_name(&name) {
+            continue;
+        }
+        match read_manifest(dir, &name).await {
+            Ok(manifest) => backups.push(BackupInfo::from(&manifest)),
+            Err(e) => tracing::warn!(%name, ?e, "skipping unreadable backup"),
+        }
+    }
+    // ULIDs sort by creation time
+    backups.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(backups)
+}
+
+/// Backup being restored, if a restore was started and didn't finish
+async fn unfinished_restore() -> Result<Option<String>> {
+    let backup: Option<String> = DB
+        .retry(|| {
+            DB.query("RETURN type::thing($table, 'current').backup;")
+                .bind(("table", RESTORE_TABLE))
+        })
+        .await?
+        .take(0)?;
+    Ok(backup)
+}
+
+/// Check a backup can be restored: the database must be empty, or hold what a failed restore
+/// left behind, and the backup can't be from a newer release
+///
+/// The event log doesn't count, a fresh server already logs the restore itself.
+pub async fn check_restorable(manifest: &BackupManifest) -> Result<()> {
+    let latest = migrations::latest_version();
+    if manifest.schema_version > latest {
+        return Err(eyre!(
+            "backup {} is at schema version {}, newer than this release's {latest}",
+            manifest.name,
+            manifest.schema_version
+        ));
+    }
+    if let Some(backup) = unfinished_restore().await? {
+        tracing::info!(%backup, "retrying an unfinished restore");
+        return Ok(());
+    }
+    for table in backed_up_tables().await? {
+        if table == LOG_TABLE {
+            continue;
+        }
+        let count: Option<usize> = DB
+            .retry(|| {
+                DB.query("RETURN count(SELECT id FROM type::table($table) LIMIT 1);")
+                    .bind(("table", table.clone()))
+            })
+            .await?
+            .take(0)?;
+        if count.unwrap_or_default() > 0 {
+            return Err(eyre!("the database isn't empty, {table} has records"));
+        }
+    }
+    Ok(())
+}
+
+/// Replay a backup into the database and migrate its records, then check the object store
+/// has its objects
+///
+/// The database must be empty, see [`check_restorable`]. Until the restore is done, the
+/// database is marked as being restored, so a restore failing part way can be started again.
+pub async fn restore(dir: PathBuf, name: String) -> Result<RestoreReport> {
+    let manifest = read_manifest(&dir, &name).await?;
+    check_restorable(&manifest).await?;
+    tracing::info!(%name, "restoring database");
+    DB.query(
+        "UPSERT type::thing($table, 'current') SET backup = $backup, started_at = time::now();",
+    )
+    .bind(("table", RESTORE_TABLE))
+    .bind(("backup", name.clone()))
+    .await?
+    .check()?;
+
+    let file = tokio::fs::File::open(dir.join(&name).join(DATA_FILE)).await?;
+    let mut lines = tokio::io::BufReader::new(file).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.is_empty() {
+            continue;
+        }
+        let statement: String = serde_json::from_str(&line)?;
+        DB.query(statement).await?.check()?;
+    }
+    // the records are as they were at the backup's schema version
+    migrations::rewind(manifest.schema_version).await?;
+    let applied = migrations::migrate().await?;
+    tracing::info!(%name, applied, "migrated restored records");
+    DB.query("DELETE type::thing($table, 'current');")
+        .bind(("table", RESTORE_TABLE))
+        .await?
+        .check()?;
+
+    let stored = object_store().backend.list_objects().await?;
+    let mut report = RestoreReport {
+        tables: manifest.tables.clone(),
+        ..Default::default()
+    };
+    for (key, size) in &manifest.objects {
+        if stored.get(key) == Some(size) {
+            report.objects += 1;
+        } else {
+            report.missing.push(key.clone());
+        }
+    }
+    if !report.missing.is_empty() {
+        tracing::warn!(%name, missing = report.missing.len(), "objects missing after restore");
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_name() {
+        assert!(is_valid_name(&format!("backup-{}", ulid::Ulid::new())));
+        assert!(!is_valid_name("backup-../../etc"));
+        assert!(!is_valid_name(&format!(".backup-{}", ulid::Ulid::new())));
+        assert!(!is_valid_name("manifest.json"));
+    }
+}
@@ -1,13 +1,184 @@
-use std::{env, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
 
 use crate::obj_store::OBJECT_STORE;
-use color_eyre::Result;
+use color_eyre::{eyre::eyre, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
 use tracing::trace;
+
+/// Directory of the cache holding the expected digest and size of each entry,
+/// as `<sha256> <size>` at the entry's key
+const DIGEST_DIR: &str = ".digests";
+
+/// Objects served from the cache since startup
+static HITS: AtomicU64 = AtomicU64::new(0);
+/// Objects downloaded from the object store since startup
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Count a lookup of an object in the cache, see [`CacheStats`]
+pub fn record_lookup(hit: bool) {
+    let counter = if hit { &HITS } else { &MISSES };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct PrefixStats {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// Contents of the cache and how well it's been serving objects
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct CacheStats {
+    pub entries: u64,
+    pub bytes: u64,
+    /// Size limit of the cache, if any
+    pub max_bytes: Option<u64>,
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries by the first component of their key, i.e. `rpm`
+    pub prefixes: BTreeMap<String, PrefixStats>,
+}
+
+/// Index of every size-limited cache directory, shared by the [`Cache`]s of the same directory
+static INDEXES: LazyLock<Mutex<HashMap<PathBuf, Arc<SharedIndex>>>> =
+    LazyLock::new(Default::default);
+
 /// Object storage cache for S3 objects
 #[derive(Debug)]
 pub struct Cache {
     /// The directory where objects are stored
     cache_dir: PathBuf,
+    limit: Option<CacheLimit>,
+    index: Option<Arc<SharedIndex>>,
+    /// Directories inside the cache directory that aren't cache entries, see [`Cache::excluding`]
+    excluded: Vec<PathBuf>,
+}
+
+/// Size limit of a cache, see [`Cache::with_limit`]
+#[derive(Debug, Clone)]
+struct CacheLimit {
+    max_size: u64,
+    /// Directory of composes, whose packages are symlinks to cache entries
+    linked_from: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    size: u64,
+    last_access: SystemTime,
+}
+
+/// Index of a size-limited cache directory
+#[derive(Debug)]
+struct SharedIndex {
+    state: Mutex<CacheIndex>,
+    /// Held while evicting, so concurrent writes past the limit don't each look for linked entries
+    eviction: tokio::sync::Mutex<()>,
+}
+
+/// Size and last access of every entry of a cache directory, for LRU eviction
+///
+/// Only files in subdirectories are entries, since every object key has a prefix.
+/// Files at the top of the cache directory are uploads being staged.
+#[derive(Debug, Default)]
+struct CacheIndex {
+    entries: HashMap<String, IndexEntry>,
+    /// Total size of the entries
+    total: u64,
+}
+
+impl CacheIndex {
+    /// Index the entries already in a cache directory, by their last access or modification
+    fn load(cache_dir: &Path, excluded: &[PathBuf]) -> Self {
+        let mut index = Self::default();
+        for file in entry_files(cache_dir, excluded) {
+            let Ok(meta) = file.metadata() else {
+                continue;
+            };
+            let key = file.path().strip_prefix(cache_dir).unwrap();
+            let last_access = meta
+                .accessed()
+                .or_else(|_| meta.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            index.insert(&key.to_string_lossy(), meta.len(), last_access);
+        }
+        index
+    }
+
+    fn insert(&mut self, key: &str, size: u64, last_access: SystemTime) {
+        let entry = IndexEntry { size, last_access };
+        if let Some(old) = self.entries.insert(key.to_owned(), entry) {
+            self.total -= old.size;
+        }
+        self.total += size;
+    }
+
+    fn touch(&mut self, key: &str) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.last_access = SystemTime::now();
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(old) = self.entries.remove(key) {
+            self.total -= old.size;
+        }
+    }
+}
+
+/// Files of the entries of a cache directory
+///
+/// Files at the top of the cache directory are uploads being staged, not entries, and the
+/// `excluded` directories belong to something else.
+fn entry_files<'a>(
+    cache_dir: &Path,
+    excluded: &'a [PathBuf],
+) -> impl Iterator<Item = walkdir::DirEntry> + 'a {
+    walkdir::WalkDir::new(cache_dir)
+        .into_iter()
+        .filter_entry(|e: &walkdir::DirEntry| {
+            (e.depth() != 1 || e.file_name() != DIGEST_DIR)
+                && !excluded.iter().any(|dir| e.path() == dir)
+        })
+        .filter_map(|e| e.ok())
+        .filter(|e| e.depth() >= 2 && e.file_type().is_file())
+}
+
+/// Hex encoded SHA256 digest and size of a file
+async fn file_digest(path: &Path) -> Result<(String, u64)> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1024 * 1024];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        size += read as u64;
+    }
+    Ok((hex::encode(hasher.finalize()), size))
+}
+
+/// Canonical paths of the files symlinked from a directory
+pub fn linked_files(dir: &Path) -> HashSet<PathBuf> {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path_is_symlink())
+        .filter_map(|e| std::fs::canonicalize(e.path()).ok())
+        .collect()
 }
 
 pub fn cache() -> Cache {
@@ -25,7 +196,111 @@ impl Cache {
         } else {
             std::fs::create_dir_all(&cache_dir).unwrap();
         }
-        Self { cache_dir }
+        Self {
+            cache_dir,
+            limit: None,
+            index: None,
+            excluded: Vec::new(),
+        }
+    }
+
+    /// Skip directories inside the cache directory that aren't cache entries, i.e. the repo
+    /// cache when it's kept below `CACHE_DIR`, so they're never indexed, evicted or scrubbed
+    ///
+    /// Set before [`Cache::with_limit`], which indexes the cache directory.
+    pub fn excluding(mut self, dirs: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.excluded = dirs.into_iter().collect();
+        self
+    }
+
+    /// Limit the total size of the cache, evicting the least recently used entries past it
+    ///
+    /// Entries symlinked from a compose in `linked_from` are never evicted, so published
+    /// repositories and the composes kept for rollbacks stay intact.
+    pub fn with_limit(mut self, max_size: u64, linked_from: PathBuf) -> Self {
+        let index = INDEXES
+            .lock()
+            .unwrap()
+            .entry(self.cache_dir.clone())
+            .or_insert_with(|| {
+                Arc::new(SharedIndex {
+                    state: Mutex::new(CacheIndex::load(&self.cache_dir, &self.excluded)),
+                    eviction: tokio::sync::Mutex::new(()),
+                })
+            })
+            .clone();
+        self.limit = Some(CacheLimit {
+            max_size,
+            linked_from,
+        });
+        self.index = Some(index);
+        self
+    }
+
+    /// Record a new entry in the index, evicting other entries if the cache is now too large
+    async fn record(&self, key: &str, dest: &Path) -> Result<()> {
+        let Some(index) = &self.index else {
+            return Ok(());
+        };
+        let size = tokio::fs::metadata(dest).await?.len();
+        index
+            .state
+            .lock()
+            .unwrap()
+            .insert(key, size, SystemTime::now());
+        self.evict(key).await
+    }
+
+    /// Evict the least recently used entries until the cache fits its size limit
+    ///
+    /// `keep` is never evicted, it's the entry that was just added.
+    async fn evict(&self, keep: &str) -> Result<()> {
+        let (Some(limit), Some(index)) = (&self.limit, &self.index) else {
+            return Ok(());
+        };
+        let over_limit = || index.state.lock().unwrap().total > limit.max_size;
+        if !over_limit() {
+            return Ok(());
+        }
+        // another write may have already made room while this one waited
+        let _eviction = index.eviction.lock().await;
+        if !over_limit() {
+            return Ok(());
+        }
+
+        let mut candidates: Vec<(String, IndexEntry)> = index
+            .state
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .filter(|(key, _)| key.as_str() != keep)
+            .map(|(key, entry)| (key.clone(), *entry))
+            .collect();
+        candidates.sort_by_key(|(_, entry)| entry.last_access);
+
+        let linked = linked_files(&limit.linked_from);
+        let cache_dir = self.cache_dir.canonicalize()?;
+        for (key, entry) in candidates {
+            if !over_limit() {
+                return Ok(());
+            }
+            if linked.contains(&cache_dir.join(&key)) {
+                continue;
+            }
+            trace!(?key, size = entry.size, "evicting cache entry");
+            if let Err(e) = self.remove(&key).await {
+                tracing::warn!(?key, ?e, "failed to evict cache entry");
+            }
+        }
+
+        if over_limit() {
+            tracing::warn!(
+                max_size = limit.max_size,
+                "cache is over its size limit, but every remaining entry is in use"
+            );
+        }
+        Ok(())
     }
     
     pub fn cache_dir(&self) -> &PathBuf {
@@ -36,15 +311,58 @@ impl Cache {
     ///
     /// This only gets the entry if it exists.
     /// If you would like to download the object when it doesn't exist, use `get_or_download`.
+    /// Entries whose size doesn't match the one they were cached with, i.e. a truncated
+    /// download, are removed instead so they're downloaded again.
     pub fn get(&self, key: &str) -> Option<PathBuf> {
+        self.get_expecting(key, None)
+    }
+
+    /// Get a cache entry, like [`Cache::get`], if it was cached with the SHA256 digest `sha256`
+    ///
+    /// The digest comes from the caller, i.e. a package's digest in the database, entries cached
+    /// with another one are removed so they're downloaded again.
+    pub fn get_expecting(&self, key: &str, sha256: Option<&str>) -> Option<PathBuf> {
         let path = self.cache_dir.join(key);
-        path.exists().then_some(path)
+        let size = std::fs::metadata(&path).ok()?.len();
+        if let Some((digest, expected)) = self.expected_digest(key) {
+            if size != expected || sha256.is_some_and(|sha256| sha256 != digest) {
+                tracing::warn!(?key, size, expected, "cached object is corrupt, removing it");
+                let _ = std::fs::remove_file(&path);
+                let _ = std::fs::remove_file(self.digest_path(key));
+                if let Some(index) = &self.index {
+                    index.state.lock().unwrap().remove(key);
+                }
+                return None;
+            }
+        }
+        if let Some(index) = &self.index {
+            index.state.lock().unwrap().touch(key);
+        }
+        Some(path)
     }
 
     /// Set a cache entry from a file
-    #[tracing::instrument]
     pub async fn put(&self, key: &str, path: &PathBuf) -> Result<PathBuf> {
+        self.put_expecting(key, path, None).await
+    }
+
+    /// Set a cache entry from a file, unless its SHA256 digest isn't `sha256`
+    ///
+    /// The file is removed either way. The digest comes from the caller, i.e. a package's digest
+    /// in the database, so a corrupt download is never cached as if it was intact.
+    #[tracing::instrument]
+    pub async fn put_expecting(
+        &self,
+        key: &str,
+        path: &PathBuf,
+        sha256: Option<&str>,
+    ) -> Result<PathBuf> {
         trace!("putting {} into cache", key);
+        let (digest, size) = file_digest(path).await?;
+        if sha256.is_some_and(|sha256| sha256 != digest) {
+            tokio::fs::remove_file(path).await?;
+            return Err(eyre!("{key} doesn't match its expected digest"));
+        }
         let dest = self.cache_dir.join(key);
 
         // make preceding directories if they don't exist
@@ -54,6 +372,8 @@ impl Cache {
 
         tokio::fs::copy(&path, &dest).await?;
         tokio::fs::remove_file(path).await?;
+        self.write_digest(key, &digest, size).await?;
+        self.record(key, &dest).await?;
 
         Ok(dest)
     }
@@ -69,6 +389,9 @@ impl Cache {
         }
 
         tokio::fs::write(&dest, bytes).await?;
+        let digest = hex::encode(Sha256::digest(bytes));
+        self.write_digest(key, &digest, bytes.len() as u64).await?;
+        self.record(key, &dest).await?;
 
         Ok(dest)
     }
@@ -114,6 +437,10 @@ impl Cache {
 
     #[tracing::instrument]
     pub async fn remove(&self, key: &str) -> Result<()> {
+        if let Some(index) = &self.index {
+            index.state.lock().unwrap().remove(key);
+        }
+        let _ = tokio::fs::remove_file(self.digest_path(key)).await;
         let path = self.cache_dir.join(key);
         tokio::fs::remove_file(&path).await?;
 
@@ -142,6 +469,91 @@ impl Cache {
         Ok(())
     }
 
+    fn digest_path(&self, key: &str) -> PathBuf {
+        self.cache_dir.join(DIGEST_DIR).join(key)
+    }
+
+    async fn write_digest(&self, key: &str, digest: &str, size: u64) -> Result<()> {
+        let path = self.digest_path(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, format!("{digest} {size}")).await?;
+        Ok(())
+    }
+
+    /// Digest and size an entry was cached with, `None` for entries cached before digests were
+    fn expected_digest(&self, key: &str) -> Option<(String, u64)> {
+        let contents = std::fs::read_to_string(self.digest_path(key)).ok()?;
+        let (digest, size) = contents.trim().split_once(' ')?;
+        Some((digest.to_owned(), size.parse().ok()?))
+    }
+
+    /// Check an entry's contents against the digest it was cached with
+    ///
+    /// Entries cached without a digest are assumed to be intact.
+    pub async fn verify(&self, key: &str) -> Result<bool> {
+        let Some(expected) = self.expected_digest(key) else {
+            return Ok(true);
+        };
+        Ok(file_digest(&self.cache_dir.join(key)).await? == expected)
+    }
+
+    /// Verify every entry, downloading corrupt ones again from the object store
+    ///
+    /// Returns the keys of the entries that were corrupt.
+    pub async fn scrub(&self) -> Result<Vec<String>> {
+        let keys: Vec<String> = entry_files(&self.cache_dir, &self.excluded)
+            .filter_map(|file| {
+                let key = file.path().strip_prefix(&self.cache_dir).ok()?;
+                Some(key.to_string_lossy().into_owned())
+            })
+            .collect();
+
+        let mut corrupt = Vec::new();
+        for key in keys {
+            if self.verify(&key).await? {
+                continue;
+            }
+            tracing::warn!(?key, "cached object is corrupt, downloading it again");
+            if let Err(e) = OBJECT_STORE.get().unwrap().refresh(&key).await {
+                tracing::error!(?key, ?e, "failed to download corrupt object again");
+            }
+            corrupt.push(key);
+        }
+        Ok(corrupt)
+    }
+
+    /// Whether a key is a path inside the cache, so it can't point outside of it
+    pub fn is_valid_key(key: &str) -> bool {
+        let mut components = Path::new(key).components().peekable();
+        components.peek().is_some() && components.all(|c| matches!(c, Component::Normal(_)))
+    }
+
+    /// Count the entries of the cache and their size, by prefix
+    pub async fn stats(&self) -> Result<CacheStats> {
+        let mut stats = CacheStats {
+            max_bytes: self.limit.as_ref().map(|limit| limit.max_size),
+            hits: HITS.load(Ordering::Relaxed),
+            misses: MISSES.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        for file in entry_files(&self.cache_dir, &self.excluded) {
+            let size = file.metadata()?.len();
+            let key = file.path().strip_prefix(&self.cache_dir)?;
+            let prefix = key.components().next().unwrap().as_os_str();
+            let prefix = stats
+                .prefixes
+                .entry(prefix.to_string_lossy().into_owned())
+                .or_default();
+            prefix.entries += 1;
+            prefix.bytes += size;
+            stats.entries += 1;
+            stats.bytes += size;
+        }
+        Ok(stats)
+    }
+
     pub async fn list_cached(&self) -> Result<Vec<String>> {
         // read 2 levels deep to get the actual keys
         let files = walkdir::WalkDir::new(&self.cache_dir)
@@ -169,3 +581,111 @@ impl Cache {
     //     self.remove(key).await
     // }
 }
+
+/// Spawn the background task scrubbing the cache for corrupt entries, see [`Cache::scrub`]
+pub fn spawn_scrub_task(interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        // the first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match cache().scrub().await {
+                Ok(corrupt) => tracing::info!(corrupt = corrupt.len(), "scrubbed cache"),
+                Err(e) => tracing::error!(?e, "failed to scrub cache"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_evict() {
+        let root = std::env::temp_dir().join(format!("subatomic-cache-{}", ulid::Ulid::new()));
+        let composes = root.join("composes");
+        std::fs::create_dir_all(&composes).unwrap();
+        let cache = Cache::new(root.join("cache")).with_limit(10, composes.clone());
+
+        cache.put_bytes("rpm/a", b"aaaaaa").await.unwrap();
+        cache.put_bytes("rpm/b", b"bbbbbb").await.unwrap();
+        // the least recently used entry is evicted
+        assert!(cache.get("rpm/a").is_none());
+        let b = cache.get("rpm/b").unwrap();
+
+        // entries linked from a compose are kept, even past the limit
+        std::os::unix::fs::symlink(&b, composes.join("b.rpm")).unwrap();
+        cache.put_bytes("rpm/c", b"cccccc").await.unwrap();
+        assert!(cache.get("rpm/b").is_some());
+        assert!(cache.get("rpm/c").is_some());
+
+        // directories that aren't cache entries are left alone
+        let excluded = Cache::new(root.clone())
+            .excluding([root.join("composes"), root.join("cache")])
+            .with_limit(1, composes.clone());
+        excluded.put_bytes("rpm/d", b"dddddd").await.unwrap();
+        assert!(cache.get("rpm/c").is_some());
+        assert_eq!(excluded.stats().await.unwrap().entries, 1);
+
+        let stats = cache.stats().await.unwrap();
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.bytes, 12);
+        assert_eq!(stats.max_bytes, Some(10));
+        assert_eq!(stats.prefixes["rpm"].entries, 2);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_is_valid_key() {
+        assert!(Cache::is_valid_key("rpm/sha256/ab/cd/foo.rpm"));
+        assert!(!Cache::is_valid_key(""));
+        assert!(!Cache::is_valid_key("../etc/passwd"));
+        assert!(!Cache::is_valid_key("rpm/../../etc/passwd"));
+        assert!(!Cache::is_valid_key("/etc/passwd"));
+    }
+
+    #[tokio::test]
+    async fn test_integrity() {
+        let root = std::env::temp_dir().join(format!("subatomic-cache-{}", ulid::Ulid::new()));
+        let cache = Cache::new(root.clone());
+
+        cache.put_bytes("rpm/a", b"aaaaaa").await.unwrap();
+        assert!(cache.verify("rpm/a").await.unwrap());
+        // the digests aren't entries
+        assert_eq!(cache.stats().await.unwrap().entries, 1);
+
+        // same size, different contents
+        std::fs::write(root.join("rpm/a"), b"bbbbbb").unwrap();
+        assert!(cache.get("rpm/a").is_some());
+        assert!(!cache.verify("rpm/a").await.unwrap());
+
+        // truncated
+        std::fs::write(root.join("rpm/a"), b"aaa").unwrap();
+        assert!(cache.get("rpm/a").is_none());
+        assert!(!root.join("rpm/a").exists());
+
+        // checked against a digest from the caller
+        let sha256 = hex::encode(Sha256::digest(b"cccccc"));
+        let download = root.join("download");
+        std::fs::write(&download, b"cccccd").unwrap();
+        assert!(cache
+            .put_expecting("rpm/c", &download, Some(&sha256))
+            .await
+            .is_err());
+        assert!(!download.exists());
+        assert!(cache.get("rpm/c").is_none());
+        std::fs::write(&download, b"cccccc").unwrap();
+        cache
+            .put_expecting("rpm/c", &download, Some(&sha256))
+            .await
+            .unwrap();
+        assert!(cache.get_expecting("rpm/c", Some(&sha256)).is_some());
+        assert!(cache.get_expecting("rpm/c", Some("0000")).is_none());
+        assert!(!root.join("rpm/c").exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
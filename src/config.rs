@@ -1,13 +1,41 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
 
-use crate::{cache::Cache, obj_store::{ObjectStorage, StorageBackend}};
-use clap::{Parser, ValueEnum};
+use crate::{
+    cache::Cache,
+    obj_store::{
+        replicated::ReplicatedBackend, retry::RetryPolicy, ObjectStorage, SigningBackend,
+        StorageBackend,
+    },
+};
+use arc_swap::ArcSwap;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, ValueEnum};
 use object_store::ObjectStore;
+use serde::Serialize;
 use std::sync::OnceLock;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Registry};
 
 pub static CONFIG: OnceLock<Config> = OnceLock::new();
+/// Options changed by [`reload()`], see [`dynamic`]
+static DYNAMIC: OnceLock<ArcSwap<DynamicConfig>> = OnceLock::new();
+/// Arguments the config was parsed from, parsed again on reload
+static ARGS: OnceLock<Vec<OsString>> = OnceLock::new();
+/// Environment variables set from the config file on startup, which don't count as environment
+/// on reload
+static FILE_VARS: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+/// Held while reloading, so concurrent reloads apply their configs in order
+static RELOAD_LOCK: Mutex<()> = Mutex::new(());
+/// Handle to change the log filter set up by [`init_logging`]
+static LOG_FILTER: OnceLock<tracing_subscriber::reload::Handle<EnvFilter, Registry>> =
+    OnceLock::new();
 
-#[derive(ValueEnum, Debug, Clone)]
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ObjectStoreType {
     /// S3 object store
     #[value(name = "s3")]
@@ -21,31 +49,105 @@ pub enum ObjectStoreType {
     CacheOnly,
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbEngine {
+    /// External SurrealDB server, at `SURREAL_HOST`
+    #[value(name = "remote")]
+    Remote,
+    /// Embedded RocksDB database, stored at `SURREAL_PATH`
+    #[value(name = "rocksdb")]
+    RocksDb,
+    /// Embedded SurrealKV database, stored at `SURREAL_PATH`
+    #[value(name = "surrealkv")]
+    SurrealKv,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbAuthLevel {
+    /// Root user, with access to every namespace
+    #[value(name = "root")]
+    Root,
+    /// User defined on `SURREAL_NS`
+    #[value(name = "namespace")]
+    Namespace,
+    /// User defined on `SURREAL_DB` in `SURREAL_NS`
+    #[value(name = "database")]
+    Database,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepodataBackend {
+    /// Generate repodata in-process
+    #[value(name = "native")]
+    Native,
+    /// Shell out to `createrepo_c`
+    #[value(name = "createrepo")]
+    Createrepo,
+}
+
+/// Options of the S3 object store, only parsed if any of them is set
 #[derive(Parser, Debug, Clone)]
 #[group(id = "object_store", multiple = true)]
 #[group(requires = "object_store_type")]
 pub struct S3StoreConfig {
-    #[clap(long, env = "S3_BUCKET")]
+    #[clap(long, env = "S3_BUCKET", required = false)]
     pub s3_bucket: String,
 
-    #[clap(long, env = "S3_REGION")]
+    #[clap(long, env = "S3_REGION", required = false)]
     pub s3_region: String,
 
-    #[clap(long, env = "S3_ACCESS_KEY")]
+    #[clap(long, env = "S3_ACCESS_KEY", required = false)]
     pub s3_access_key: String,
 
-    #[clap(long, env = "S3_SECRET_KEY")]
+    #[clap(long, env = "S3_SECRET_KEY", required = false)]
     pub s3_secret_key: String,
 
-    #[clap(long, env = "S3_ENDPOINT")]
+    #[clap(long, env = "S3_ENDPOINT", required = false)]
     pub s3_endpoint: String,
 }
 
 #[derive(Parser, Debug, Clone)]
 pub struct Config {
-    #[clap(long, env = "SURREAL_HOST")]
+    /// Path to a TOML or YAML configuration file
+    ///
+    /// Keys map to the environment variable names of each option, i.e `s3_bucket`
+    /// for `S3_BUCKET`. Environment variables and CLI flags take precedence over the file.
+    #[clap(long, env = "SUBATOMIC_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address of the SurrealDB server, for the remote engine
+    ///
+    /// Either `host:port`, or a `ws://`/`wss://` URL to pick whether to connect over TLS.
+    #[clap(long, env = "SURREAL_HOST", default_value = "localhost:8000")]
     pub host: String,
 
+    /// Database engine, an external SurrealDB server or one embedded in the process
+    ///
+    /// The embedded engines need Subatomic to be built with the `embedded-db` feature.
+    #[clap(long, env = "SURREAL_ENGINE", default_value = "remote")]
+    pub surreal_engine: DbEngine,
+
+    /// On-disk path of the embedded database
+    #[clap(long, env = "SURREAL_PATH")]
+    pub surreal_path: Option<PathBuf>,
+
+    /// User to sign in to the SurrealDB server as
+    #[clap(long, env = "SURREAL_USER")]
+    pub surreal_user: Option<String>,
+
+    #[clap(long, env = "SURREAL_PASS")]
+    pub surreal_pass: Option<String>,
+
+    /// Level the SurrealDB user is defined on
+    #[clap(long, env = "SURREAL_AUTH_LEVEL", default_value = "root")]
+    pub surreal_auth_level: DbAuthLevel,
+
+    /// Sign in as SurrealDB's default `root`/`root` user when no credentials are set
+    ///
+    /// Only meant for local development, Subatomic refuses to start with the default credentials otherwise.
+    #[clap(long, env = "SURREAL_INSECURE_CREDENTIALS", default_value = "false")]
+    pub surreal_insecure_credentials: bool,
+
     #[clap(long, env = "SURREAL_DB", default_value = "subatomic")]
     pub surreal_db: String,
 
@@ -57,14 +159,73 @@ pub struct Config {
 
     #[clap(long, env = "OBJECT_STORE_TYPE", default_value = "s3")]
     pub object_store_type: ObjectStoreType,
-    
-    
-    /// Delete RPMs when they are marked as unavailable
-    /// 
-    /// This mimics old subatomic behavior, where setting the prune flag
-    /// would delete the object entirely, marking them permanently unavailable.
-    #[clap(long, env = "DELETE_WHEN_PRUNE", default_value = "false")]
-    pub delete_when_prune: bool,
+
+    /// Start without checking that the object store can be written to
+    ///
+    /// By default, a sentinel object is written and deleted on startup, so wrong credentials
+    /// or a missing bucket stop the server instead of failing the first upload.
+    #[clap(long, env = "SKIP_OBJECT_STORE_CHECK", default_value = "false")]
+    pub skip_object_store_check: bool,
+
+    /// Times object store operations failing with transient errors are retried,
+    /// i.e. server errors and timeouts
+    #[clap(long, env = "OBJECT_STORE_RETRIES", default_value = "3")]
+    pub object_store_retries: u32,
+
+    /// Delay in milliseconds before retrying an object store operation, doubled after each retry
+    #[clap(long, env = "OBJECT_STORE_RETRY_DELAY", default_value = "200")]
+    pub object_store_retry_delay: u64,
+
+    /// Second object store every object is mirrored to, i.e. `s3` to back up a local store
+    ///
+    /// Writes only succeed once both stores have the object, reads prefer `OBJECT_STORE_TYPE`.
+    /// Must be a different type than `OBJECT_STORE_TYPE`, and can't be `cacheonly`.
+    #[clap(long, env = "OBJECT_STORE_REPLICA")]
+    pub object_store_replica: Option<ObjectStoreType>,
+
+    /// Directory a `local` replica stores objects in
+    ///
+    /// Must be outside `CACHE_DIR`, which is evicted from and cleaned up.
+    #[clap(
+        long,
+        env = "OBJECT_STORE_REPLICA_DIR",
+        default_value = "/var/lib/subatomic/replica"
+    )]
+    pub object_store_replica_dir: PathBuf,
+
+    /// Key RPM objects by the SHA256 digest of their contents instead of their ID
+    ///
+    /// Identical packages uploaded to several tags then share one stored object,
+    /// which is only removed once no package references it anymore.
+    #[clap(long, env = "CONTENT_ADDRESSED_OBJECTS", default_value = "false")]
+    pub content_addressed_objects: bool,
+
+    /// Redirect package and artifact downloads to presigned object store URLs
+    ///
+    /// Only supported by the S3 object store, other stores keep serving
+    /// downloads through the API server.
+    #[clap(long, env = "PRESIGNED_DOWNLOADS", default_value = "false")]
+    pub presigned_downloads: bool,
+
+    /// Time in seconds presigned download URLs stay valid for
+    #[clap(long, env = "PRESIGNED_URL_TTL", default_value = "3600")]
+    pub presigned_url_ttl: u64,
+
+    /// Size in bytes of the parts objects are uploaded in
+    ///
+    /// Objects larger than this are streamed from disk as multipart uploads, at least 5 MiB
+    /// as required by S3.
+    #[clap(
+        long,
+        env = "UPLOAD_PART_SIZE",
+        default_value = "10485760",
+        value_parser = clap::value_parser!(u64).range(5 * 1024 * 1024..)
+    )]
+    pub upload_part_size: u64,
+
+    /// Parts of a multipart upload sent at the same time
+    #[clap(long, env = "UPLOAD_CONCURRENCY", default_value = "8")]
+    pub upload_concurrency: usize,
 
     // #[clap(long, env = "S3_BUCKET")]
     // pub s3_bucket: String,
@@ -84,6 +245,45 @@ pub struct Config {
     #[clap(long, env = "CACHE_DIR", default_value = "/tmp/subatomic")]
     pub cache_dir: PathBuf,
 
+    /// Largest total size of the cache directory in bytes, unlimited if unset
+    ///
+    /// The least recently used objects are evicted past it, except those linked from a compose.
+    #[clap(long, env = "CACHE_MAX_SIZE")]
+    pub cache_max_size: Option<u64>,
+
+    /// Interval in seconds to verify every cached object against its digest, never if unset
+    ///
+    /// Corrupt objects are downloaded again. Truncated objects are always caught when read.
+    #[clap(long, env = "CACHE_SCRUB_INTERVAL")]
+    pub cache_scrub_interval: Option<u64>,
+
+    /// Interval in seconds to clean up left over uploads, staging directories and cache
+    /// entries, never if unset
+    #[clap(long, env = "JANITOR_INTERVAL")]
+    pub janitor_interval: Option<u64>,
+
+    /// Tags whose packages are downloaded into the cache on startup, comma separated
+    ///
+    /// So the first assembly after a restart doesn't download every package.
+    #[clap(long, env = "PREFETCH_TAGS", value_delimiter = ',')]
+    pub prefetch_tags: Vec<String>,
+
+    /// Packages downloaded at the same time when prefetching a tag
+    #[clap(long, env = "PREFETCH_CONCURRENCY", default_value = "8")]
+    pub prefetch_concurrency: usize,
+
+    /// Directory bulk imports read packages from, see `POST /admin/import`
+    ///
+    /// Imports are disabled when this is not set.
+    #[clap(long, env = "IMPORT_ROOT")]
+    pub import_root: Option<PathBuf>,
+
+    /// Directory backups are written to and restored from, see `POST /admin/backups`
+    ///
+    /// Backups are disabled when this is not set.
+    #[clap(long, env = "BACKUP_DIR")]
+    pub backup_dir: Option<PathBuf>,
+
     #[clap(long, env = "REPO_CACHE_DIR", default_value = "/tmp/subatomic/repo")]
     /// Directory to cache generated repos to
     ///
@@ -111,15 +311,453 @@ pub struct Config {
     #[clap(long, env = "EXPORT_DIR", default_value = "/tmp/subatomic/export")]
     pub export_dir: PathBuf,
 
+    /// Largest request body accepted for an upload, in bytes
+    ///
+    /// Batch uploads count every file of the request, resumable uploads the whole package.
+    #[clap(long, env = "MAX_UPLOAD_SIZE", default_value = "4294967296")]
+    pub max_upload_size: u64,
+
+    /// Free space to always leave in the cache directory, in bytes
+    ///
+    /// Uploads that would leave less free space are refused with 507 Insufficient Storage.
+    #[clap(long, env = "MIN_FREE_SPACE", default_value = "1073741824")]
+    pub min_free_space: u64,
+
     /// Address to listen on for the HTTP API
     #[clap(long, env = "LISTEN_ADDR", default_value = "0.0.0.0:3000")]
     pub listen_addr: String,
+
+    /// Unix socket to listen on for the HTTP API instead of `LISTEN_ADDR`
+    ///
+    /// i.e. for a reverse proxy on the same host. A stale socket left at the path is replaced.
+    #[clap(long, env = "LISTEN_SOCKET", conflicts_with = "tls_cert")]
+    pub listen_socket: Option<PathBuf>,
+
+    /// Permissions of the unix socket, in octal
+    #[clap(long, env = "LISTEN_SOCKET_MODE", default_value = "660", value_parser = parse_mode)]
+    pub listen_socket_mode: u32,
+
+    /// PEM certificate chain to serve the HTTP API over HTTPS with
+    ///
+    /// The API is served over plain HTTP when this is not set.
+    #[clap(long, env = "TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the TLS certificate
+    #[clap(long, env = "TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Interval in seconds to check the certificate and key for renewals, reloading them if changed
+    ///
+    /// Certificates are only loaded on startup when this is not set.
+    #[clap(long, env = "TLS_RELOAD_INTERVAL")]
+    pub tls_reload_interval: Option<u64>,
+
+    /// Bootstrap admin token for the HTTP API
+    ///
+    /// Every API route requires a bearer token. This token always has admin access,
+    /// and can be used to create API tokens stored in the database.
+    #[clap(long, env = "ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// OIDC issuer to accept JWTs from, alongside API tokens
+    ///
+    /// i.e `https://keycloak.example.com/realms/fyra`
+    #[clap(long, env = "OIDC_ISSUER")]
+    pub oidc_issuer: Option<String>,
+
+    /// JWKS URL of the OIDC issuer, discovered from the issuer if not set
+    #[clap(long, env = "OIDC_JWKS_URL")]
+    pub oidc_jwks_url: Option<String>,
+
+    /// Required audience of accepted JWTs
+    #[clap(long, env = "OIDC_AUDIENCE")]
+    pub oidc_audience: Option<String>,
+
+    /// Claim containing the caller's roles, nested claims are separated by dots
+    ///
+    /// Roles are mapped as `<role>:<tag>`, i.e `upload:updates-testing` or `admin:*`.
+    #[clap(long, env = "OIDC_ROLES_CLAIM", default_value = "realm_access.roles")]
+    pub oidc_roles_claim: String,
+
+    /// Role granting full admin access
+    #[clap(long, env = "OIDC_ADMIN_ROLE", default_value = "subatomic-admin")]
+    pub oidc_admin_role: String,
+
+    /// Hosts that remote comps, mirrors and Koji hubs may be fetched from even though they resolve
+    /// to loopback, private or link-local addresses, comma separated
+    ///
+    /// i.e. an internal mirror. Every other non-public address is refused.
+    #[clap(long, env = "REMOTE_ALLOWED_HOSTS", value_delimiter = ',')]
+    pub remote_allowed_hosts: Vec<String>,
+
+    /// How repodata is generated when assembling a tag
+    ///
+    /// Tags generating delta RPMs always use `createrepo_c`.
+    #[clap(long, env = "REPODATA_BACKEND", default_value = "native")]
+    pub repodata_backend: RepodataBackend,
+
+    /// Command to run after assembling a tag to build installer trees/images
+    ///
+    /// The command is run through `sh -c` for tags with `build_images` enabled,
+    /// e.g. a lorax or osbuild invocation. The composed repo is passed in
+    /// `SUBATOMIC_REPO_DIR`, and everything written to `SUBATOMIC_OUTPUT_DIR`
+    /// is uploaded as an artifact of the compose.
+    #[clap(long, env = "IMAGE_BUILD_CMD")]
+    pub image_build_cmd: Option<String>,
+
+    /// SMTP relay to send notification emails through
+    ///
+    /// Email notifications are disabled when this is not set.
+    #[clap(long, env = "SMTP_HOST")]
+    pub smtp_host: Option<String>,
+
+    #[clap(long, env = "SMTP_PORT", default_value = "587")]
+    pub smtp_port: u16,
+
+    #[clap(long, env = "SMTP_USER")]
+    pub smtp_user: Option<String>,
+
+    #[clap(long, env = "SMTP_PASS")]
+    pub smtp_pass: Option<String>,
+
+    /// Sender address for notification emails
+    #[clap(long, env = "SMTP_FROM", default_value = "subatomic@localhost")]
+    pub smtp_from: String,
+
+    /// Interval in seconds between notification digests
+    #[clap(long, env = "DIGEST_INTERVAL", default_value = "86400")]
+    pub digest_interval: u64,
+
+    /// Days before a signing key expires to start sending `key_expiring` notifications
+    ///
+    /// Checked on every digest interval, so subscribers are reminded until the key is rotated.
+    #[clap(long, env = "KEY_EXPIRY_WARNING_DAYS", default_value = "14")]
+    pub key_expiry_warning_days: u32,
+
+    /// Time in seconds the leader lease is held before it must be renewed
+    ///
+    /// When running multiple replicas, background tasks only run on the instance
+    /// holding the leader lease. If the leader goes away, another instance takes over
+    /// after at most this long.
+    #[clap(long, env = "LEADER_LEASE_TTL", default_value = "30")]
+    pub leader_lease_ttl: u64,
+
+    /// Number of packages signed at once, defaults to the number of CPU cores
+    ///
+    /// Signing runs on the blocking thread pool, so it doesn't hold up the HTTP server.
+    #[clap(long, env = "SIGN_WORKERS")]
+    pub sign_workers: Option<usize>,
+
+    /// Commands keys with a `command` signer backend may sign with, as `name=program arg...`
+    ///
+    /// Separated by `;`, arguments by whitespace. Keys refer to a command by its name, so API
+    /// callers can't run anything else on the server.
+    #[clap(long, env = "SIGNING_COMMANDS", value_delimiter = ';', value_parser = parse_signing_command)]
+    pub signing_commands: Vec<SigningCommand>,
+
+    /// Options reloaded at runtime, as they were on startup
+    ///
+    /// Use [`dynamic`] for their current values.
+    #[clap(flatten)]
+    pub dynamic: DynamicConfig,
+}
+
+/// Options that can be changed without a restart, see [`reload()`]
+#[derive(Args, Debug, Clone, Serialize)]
+pub struct DynamicConfig {
+    /// Log filter, i.e. `info` or `subatomic_ng=debug,info`, `RUST_LOG` is used if unset
+    #[clap(long, env = "LOG_LEVEL", value_parser = parse_log_filter)]
+    pub log_level: Option<String>,
+
+    /// Requests per minute each caller can make to read-only API routes, unlimited if unset
+    ///
+    /// Callers are identified by their bearer token, or by their IP address without one.
+    #[clap(long, env = "RATE_LIMIT_READS")]
+    pub rate_limit_reads: Option<u32>,
+
+    /// Requests per minute each caller can make to API routes changing state, unlimited if unset
+    ///
+    /// Limited separately from reads, so uploads and assemblies can be held to a lower rate.
+    #[clap(long, env = "RATE_LIMIT_WRITES")]
+    pub rate_limit_writes: Option<u32>,
+
+    /// Age in seconds a left over file must reach before the janitor removes it
+    ///
+    /// Should be well above the longest upload or assembly, which are left alone until then.
+    #[clap(long, env = "JANITOR_MIN_AGE", default_value = "86400")]
+    pub janitor_min_age: u64,
+
+    /// Delete RPMs when they are marked as unavailable
+    ///
+    /// This mimics old subatomic behavior, where setting the prune flag
+    /// would delete the object entirely, marking them permanently unavailable.
+    #[clap(long, env = "DELETE_WHEN_PRUNE", default_value = "false")]
+    pub delete_when_prune: bool,
+}
+
+/// Local command for signing with a secret key kept outside of Subatomic, see [`Config::signing_commands`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SigningCommand {
+    pub name: String,
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+/// Parse a signing command, i.e. `token=gpg --detach-sign -u 0xDEADBEEF`
+fn parse_signing_command(command: &str) -> Result<SigningCommand, String> {
+    let (name, command) = command
+        .split_once('=')
+        .ok_or_else(|| format!("signing command needs a name: {command}"))?;
+    let mut words = command.split_whitespace().map(str::to_owned);
+    let program = words
+        .next()
+        .ok_or_else(|| format!("signing command {name} has no program"))?;
+    Ok(SigningCommand {
+        name: name.trim().to_owned(),
+        program,
+        args: words.collect(),
+    })
+}
+
+/// Parse octal file permissions, i.e. `660`
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("invalid octal mode: {mode}"))
+}
+
+/// Whether one of two directories is inside the other, or they're the same
+///
+/// Compared lexically, as the directories may not exist yet.
+fn paths_overlap(a: &Path, b: &Path) -> bool {
+    let normalize = |path: &Path| {
+        let path = std::path::absolute(path).unwrap_or_else(|_| path.to_owned());
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    normalized.pop();
+                }
+                component => normalized.push(component),
+            }
+        }
+        normalized
+    };
+    let (a, b) = (normalize(a), normalize(b));
+    a.starts_with(&b) || b.starts_with(&a)
+}
+
+/// Find the config file path from the CLI arguments or environment,
+/// before the rest of the configuration is parsed
+fn config_file_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1).filter_map(|arg| arg.to_str());
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os("SUBATOMIC_CONFIG").map(PathBuf::from)
+}
+
+/// Flatten a config document into environment variable names and values
+///
+/// Nested tables are joined with `_`, so `[s3] bucket = "foo"` becomes `S3_BUCKET=foo`.
+fn flatten_config(prefix: &str, value: &serde_json::Value, out: &mut Vec<(String, String)>) {
+    let key = prefix.replace('-', "_").to_uppercase();
+    match value {
+        serde_json::Value::Object(map) => {
+            for (k, v) in map {
+                let prefix = if prefix.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{prefix}_{k}")
+                };
+                flatten_config(&prefix, v, out);
+            }
+        }
+        serde_json::Value::Array(values) => {
+            let joined = values
+                .iter()
+                .map(|v| match v {
+                    serde_json::Value::String(s) => s.clone(),
+                    v => v.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            out.push((key, joined));
+        }
+        serde_json::Value::String(s) => out.push((key, s.clone())),
+        serde_json::Value::Null => {}
+        v => out.push((key, v.to_string())),
+    }
+}
+
+/// Read a config file into environment variable names and values
+fn read_config_file(path: &Path) -> color_eyre::Result<Vec<(String, String)>> {
+    let contents = std::fs::read_to_string(path)?;
+    let value: serde_json::Value = match path.extension().and_then(|e| e.to_str()) {
+        Some("yaml" | "yml") => serde_yaml::from_str(&contents)?,
+        _ => toml::from_str(&contents)?,
+    };
+
+    let mut vars = Vec::new();
+    flatten_config("", &value, &mut vars);
+    Ok(vars)
+}
+
+/// Load a config file, exporting its values as environment variables
+/// for every option that isn't already set
+///
+/// Only done on startup, [`reload()`] reads the file again without changing the environment.
+fn load_config_file(path: &Path) -> color_eyre::Result<()> {
+    let mut exported = FILE_VARS.lock().unwrap();
+    for (key, value) in read_config_file(path)? {
+        if std::env::var_os(&key).is_none() {
+            std::env::set_var(&key, value);
+            exported.insert(key);
+        }
+    }
+    Ok(())
+}
+
+/// Parse the config from the command line, then the environment, then the values of a config
+/// file, then the defaults, without changing the environment
+///
+/// Variables exported by [`load_config_file`] on startup are passed over, so options removed
+/// from the file go back to their defaults.
+fn parse_layered(args: &[OsString], file: &HashMap<String, String>) -> Result<Config, clap::Error> {
+    let exported = FILE_VARS.lock().unwrap();
+    let mut command = Config::command();
+    let keys: Vec<(clap::Id, String)> = command
+        .get_arguments()
+        .filter_map(|arg| Some((arg.get_id().clone(), arg.get_env()?.to_str()?.to_owned())))
+        .collect();
+    for (id, key) in keys {
+        let value = std::env::var(&key)
+            .ok()
+            .filter(|_| !exported.contains(&key))
+            .or_else(|| file.get(&key).cloned());
+        // the layered value stands in for the default, so the command line still takes precedence
+        command = command.mut_arg(id, |arg| {
+            let arg = arg.env(None::<&'static str>);
+            match value {
+                Some(value) => arg.default_value(value),
+                None => arg,
+            }
+        });
+    }
+    Config::from_arg_matches(&command.try_get_matches_from(args)?)
+}
+
+/// Check a log filter is valid, in the syntax of `RUST_LOG`
+fn parse_log_filter(filter: &str) -> Result<String, String> {
+    EnvFilter::try_new(filter)
+        .map(|_| filter.to_owned())
+        .map_err(|e| e.to_string())
+}
+
+/// Set up logging, with a filter [`reload()`] can change
+pub fn init_logging() {
+    let (filter, handle) = tracing_subscriber::reload::Layer::new(EnvFilter::from_default_env());
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    _ = LOG_FILTER.set(handle);
+}
+
+/// Apply the log filter of the config, falling back to `RUST_LOG`
+fn apply_log_filter(dynamic: &DynamicConfig) {
+    let Some(handle) = LOG_FILTER.get() else {
+        return;
+    };
+    let filter = match &dynamic.log_level {
+        // validated when parsed, see `parse_log_filter`
+        Some(filter) => EnvFilter::new(filter),
+        None => EnvFilter::from_default_env(),
+    };
+    if let Err(e) = handle.reload(filter) {
+        tracing::error!(?e, "cannot change the log filter");
+    }
+}
+
+/// Current values of the options that can be changed at runtime
+pub fn dynamic() -> Arc<DynamicConfig> {
+    DYNAMIC.get().expect("config is not installed").load_full()
+}
+
+/// Read the config file and environment again, applying the new [`DynamicConfig`]
+///
+/// Other options only take effect on restart, and are left as they are.
+pub fn reload() -> color_eyre::Result<Arc<DynamicConfig>> {
+    let _guard = RELOAD_LOCK.lock().unwrap();
+    let args = ARGS.get().cloned().unwrap_or_default();
+    let file = match config_file_path(&args) {
+        Some(path) => read_config_file(&path)?.into_iter().collect(),
+        None => HashMap::new(),
+    };
+    let dynamic = Arc::new(parse_layered(&args, &file)?.dynamic);
+    apply_log_filter(&dynamic);
+    DYNAMIC
+        .get()
+        .expect("config is not installed")
+        .store(dynamic.clone());
+    tracing::info!(config = ?dynamic, "reloaded configuration");
+    Ok(dynamic)
+}
+
+/// Reload the config whenever the process gets SIGHUP
+pub fn spawn_reload_task() {
+    tokio::spawn(async {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                tracing::error!(?e, "cannot listen for SIGHUP, config reloads are disabled");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            if let Err(e) = reload() {
+                tracing::error!(?e, "cannot reload configuration, keeping the current one");
+            }
+        }
+    });
 }
 
 impl Config {
     pub fn init() -> Self {
-            let cfg = Self::parse();
+            Self::init_from(std::env::args_os())
+        }
+
+    /// Parse the config from the given arguments, the config file and the environment,
+    /// then install it
+    ///
+    /// The arguments are kept for [`reload()`].
+    pub fn init_from<I, T>(args: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        if let Some(path) = config_file_path(&args) {
+            load_config_file(&path).expect("cannot load config file");
+        }
+        _ = ARGS.set(args.clone());
+        Self::parse_from(args).install()
+    }
+
+    /// Set this config as the global config, and set up the object store with it
+    pub fn install(self) -> Self {
+            let cfg = self;
             CONFIG.set(cfg.clone()).expect("cannot read CLI configs");
+            apply_log_filter(&cfg.dynamic);
+            _ = DYNAMIC.set(ArcSwap::from_pointee(cfg.dynamic.clone()));
             // let region = s3::Region::Custom {
             //     region: cfg.s3_config.s3_region.clone(),
             //     endpoint: cfg.s3_config.s3_endpoint.clone(),
@@ -136,60 +774,204 @@ impl Config {
             // ObjectStore::init(region, creds, &cfg.s3_config.s3_bucket);
             //
 
-            match cfg.object_store_type {
-                ObjectStoreType::Local => {
-                    let obj_cache_dir = cfg.object_cache_dir.clone();
-                    std::fs::create_dir_all(&obj_cache_dir).expect("cannot create object cache dir");
-                    let local_objstore = object_store::local::LocalFileSystem::new_with_prefix(
-                        obj_cache_dir
-                    )
-                    .expect("cannot create local object store")
-                    .with_automatic_cleanup(true);
-
-                    let store = Arc::new(local_objstore) as Arc<dyn ObjectStore>;
-                    let store = Arc::new(store) as Arc<dyn StorageBackend>;
-
-                    let store = ObjectStorage::new(store, cfg.cache());
-                    crate::obj_store::OBJECT_STORE
-                        .set(store)
-                        .unwrap_or_else(|_| panic!("cannot set object store"));
-                }
-                ObjectStoreType::S3 => {
-                    let s3_config = cfg.s3_config.clone().expect("no S3 config");
-                    let s3_store = object_store::aws::AmazonS3Builder::new()
-                        .with_bucket_name(s3_config.s3_bucket)
-                        .with_region(s3_config.s3_region)
-                        .with_endpoint(s3_config.s3_endpoint)
-                        .with_access_key_id(s3_config.s3_access_key)
-                        .with_secret_access_key(s3_config.s3_secret_key)
-                        .build()
-                        .expect("cannot create S3 object store");
-
-                    let store = Arc::new(s3_store) as Arc<dyn ObjectStore>;
-                    let store = Arc::new(store) as Arc<dyn StorageBackend>;
-
-                    let store = ObjectStorage::new(store, cfg.cache());
-                    crate::obj_store::OBJECT_STORE
-                        .set(store)
-                        .unwrap_or_else(|_| panic!("cannot set object store"));
-                },
-                ObjectStoreType::CacheOnly => {
-                    let store = crate::obj_store::CacheOnlyBackend::new();
-                    let store = Arc::new(store) as Arc<dyn StorageBackend>;
-                    
-                    let store = ObjectStorage::new(store, cfg.cache());
-                
-                    
-                    crate::obj_store::OBJECT_STORE
-                        .set(store)
-                        .unwrap_or_else(|_| panic!("cannot set object store"));
-                    
-                }
-            }
+        let mut store = cfg.storage_backend(cfg.object_store_type, &cfg.object_cache_dir);
+        if let Some(replica) = cfg.object_store_replica {
+            assert!(
+                replica != cfg.object_store_type && replica != ObjectStoreType::CacheOnly,
+                "OBJECT_STORE_REPLICA must be a different store than OBJECT_STORE_TYPE"
+            );
+            assert!(
+                replica != ObjectStoreType::Local
+                    || !paths_overlap(&cfg.object_store_replica_dir, &cfg.cache_dir),
+                "OBJECT_STORE_REPLICA_DIR must be outside CACHE_DIR"
+            );
+            let replica = cfg.storage_backend(replica, &cfg.object_store_replica_dir);
+            store = Arc::new(ReplicatedBackend::new(store, replica));
+        }
+
+            let retry = RetryPolicy {
+                retries: cfg.object_store_retries,
+                base_delay: Duration::from_millis(cfg.object_store_retry_delay),
+            };
+            let store = ObjectStorage::new(store, cfg.cache()).with_retry(retry);
+            crate::obj_store::OBJECT_STORE
+                .set(store)
+                .unwrap_or_else(|_| panic!("cannot set object store"));
             cfg
         }
 
+    /// Create an object store backend, a local one storing objects in `local_dir`
+    fn storage_backend(&self, kind: ObjectStoreType, local_dir: &Path) -> Arc<dyn StorageBackend> {
+        match kind {
+            ObjectStoreType::Local => {
+                std::fs::create_dir_all(local_dir).expect("cannot create object store dir");
+                let local_objstore =
+                    object_store::local::LocalFileSystem::new_with_prefix(local_dir)
+                        .expect("cannot create local object store")
+                        .with_automatic_cleanup(true);
+
+                let store = Arc::new(local_objstore) as Arc<dyn ObjectStore>;
+                Arc::new(store)
+            }
+            ObjectStoreType::S3 => {
+                let s3_config = self.s3_config.clone().expect("no S3 config");
+                let s3_store = object_store::aws::AmazonS3Builder::new()
+                    .with_bucket_name(s3_config.s3_bucket)
+                    .with_region(s3_config.s3_region)
+                    .with_endpoint(s3_config.s3_endpoint)
+                    .with_access_key_id(s3_config.s3_access_key)
+                    .with_secret_access_key(s3_config.s3_secret_key)
+                    .build()
+                    .expect("cannot create S3 object store");
+
+                Arc::new(SigningBackend::new(s3_store))
+            }
+            ObjectStoreType::CacheOnly => Arc::new(crate::obj_store::CacheOnlyBackend::new()),
+        }
+    }
+
     pub fn cache(&self) -> Cache {
-        Cache::new(self.cache_dir.clone())
+        let cache = Cache::new(self.cache_dir.clone()).excluding(
+            [
+                &self.repo_cache_dir,
+                &self.object_cache_dir,
+                &self.export_dir,
+                &self.object_store_replica_dir,
+            ]
+            .into_iter()
+            .chain(&self.backup_dir)
+            .cloned(),
+        );
+        match self.cache_max_size {
+            Some(max_size) => cache.with_limit(max_size, self.repo_cache_dir.clone()),
+            None => cache,
+        }
+    }
+
+    /// Configured signing command by name, see [`Config::signing_commands`]
+    pub fn signing_command(&self, name: &str) -> Option<&SigningCommand> {
+        self.signing_commands
+            .iter()
+            .find(|command| command.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert_eq!(parse_mode("0o600"), Ok(0o600));
+        assert!(parse_mode("999").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+
+    #[test]
+    fn test_paths_overlap() {
+        let overlap = |a: &str, b: &str| paths_overlap(Path::new(a), Path::new(b));
+        assert!(overlap("/tmp/subatomic/replica", "/tmp/subatomic"));
+        assert!(overlap("/tmp/subatomic", "/tmp/subatomic/replica"));
+        assert!(overlap("/tmp/subatomic/", "/tmp/subatomic"));
+        assert!(overlap("/tmp/other/../subatomic/replica", "/tmp/subatomic"));
+        assert!(!overlap("/var/lib/subatomic/replica", "/tmp/subatomic"));
+        assert!(!overlap("/tmp/subatomic-replica", "/tmp/subatomic"));
+    }
+
+    #[test]
+    fn test_parse_signing_command() {
+        let command = parse_signing_command("token=gpg --detach-sign -u 0xDEADBEEF").unwrap();
+        assert_eq!(command.name, "token");
+        assert_eq!(command.program, "gpg");
+        assert_eq!(command.args, ["--detach-sign", "-u", "0xDEADBEEF"]);
+        assert!(parse_signing_command("gpg --detach-sign").is_err());
+        assert!(parse_signing_command("token= ").is_err());
+    }
+
+    #[test]
+    fn test_parse_layered() {
+        let args = |extra: &[&str]| {
+            ["subatomic-ng", "--object-store-type=local"]
+                .iter()
+                .chain(extra)
+                .map(OsString::from)
+                .collect::<Vec<_>>()
+        };
+        let file = HashMap::from([
+            ("JANITOR_MIN_AGE".to_owned(), "60".to_owned()),
+            ("LOG_LEVEL".to_owned(), "debug".to_owned()),
+        ]);
+        // the command line takes precedence over the file
+        let config = parse_layered(&args(&["--log-level=info"]), &file).unwrap();
+        assert_eq!(config.dynamic.janitor_min_age, 60);
+        assert_eq!(config.dynamic.log_level.as_deref(), Some("info"));
+
+        let config = parse_layered(&args(&[]), &HashMap::new()).unwrap();
+        assert_eq!(config.dynamic.janitor_min_age, 86400);
+
+        let file = HashMap::from([("LOG_LEVEL".to_owned(), "subatomic_ng=loud".to_owned())]);
+        assert!(parse_layered(&args(&[]), &file).is_err());
+    }
+
+    #[test]
+    fn test_flatten_config() {
+        let value: serde_json::Value = toml::from_str(
+            r#"
+            listen_addr = "127.0.0.1:3000"
+            delete-when-prune = true
+
+            [s3]
+            bucket = "subatomic"
+
+            [cache]
+            dir = "/var/cache/subatomic"
+            max_size = 10737418240
+
+            [tls]
+            cert = "/etc/subatomic/cert.pem"
+            key = "/etc/subatomic/key.pem"
+            "#,
+        )
+        .unwrap();
+
+        let mut vars = Vec::new();
+        flatten_config("", &value, &mut vars);
+        vars.sort();
+
+        assert_eq!(
+            vars,
+            vec![
+                ("CACHE_DIR".to_owned(), "/var/cache/subatomic".to_owned()),
+                ("CACHE_MAX_SIZE".to_owned(), "10737418240".to_owned()),
+                ("DELETE_WHEN_PRUNE".to_owned(), "true".to_owned()),
+                ("LISTEN_ADDR".to_owned(), "127.0.0.1:3000".to_owned()),
+                ("S3_BUCKET".to_owned(), "subatomic".to_owned()),
+                ("TLS_CERT".to_owned(), "/etc/subatomic/cert.pem".to_owned()),
+                ("TLS_KEY".to_owned(), "/etc/subatomic/key.pem".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_flatten_yaml_config() {
+        let value: serde_json::Value = serde_yaml::from_str(
+            "cache:\n  scrub_interval: 3600\n\
+             prefetch_tags: [f41, f42]\n\
+             tls:\n  reload_interval: null\n",
+        )
+        .unwrap();
+
+        let mut vars = Vec::new();
+        flatten_config("", &value, &mut vars);
+        vars.sort();
+
+        assert_eq!(
+            vars,
+            vec![
+                ("CACHE_SCRUB_INTERVAL".to_owned(), "3600".to_owned()),
+                ("PREFETCH_TAGS".to_owned(), "f41,f42".to_owned()),
+            ]
+        );
     }
 }
@@ -0,0 +1,118 @@
+//! Advisories (errata) attached to packages of a tag, published as `updateinfo.xml`
+use color_eyre::{eyre::ContextCompat, Result};
+use serde::{Deserialize, Serialize};
+use surrealdb::{sql::Thing, RecordId};
+
+use super::{rpm::RpmRef, tag::TAG_TABLE, DB};
+
+pub const ADVISORY_TABLE: &str = "advisory";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdvisoryKind {
+    Security,
+    Bugfix,
+    Enhancement,
+    Newpackage,
+}
+
+impl AdvisoryKind {
+    /// The `type` of the advisory in `updateinfo.xml`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Security => "security",
+            Self::Bugfix => "bugfix",
+            Self::Enhancement => "enhancement",
+            Self::Newpackage => "newpackage",
+        }
+    }
+}
+
+/// A link to a bug, CVE or other document related to an advisory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdvisoryReference {
+    pub href: String,
+    /// i.e `bugzilla`, `cve` or `self`
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Advisory {
+    /// The advisory ID, i.e `FYRA-2025-0001`
+    pub id: Thing,
+    pub tag: RecordId,
+    pub title: String,
+    #[serde(rename = "type")]
+    pub kind: AdvisoryKind,
+    /// i.e `Critical`, `Important`, `Moderate` or `Low`
+    #[serde(default)]
+    pub severity: Option<String>,
+    pub description: String,
+    #[serde(default)]
+    pub references: Vec<AdvisoryReference>,
+    /// Packages fixing the issue
+    pub packages: Vec<RpmRef>,
+    pub issued: surrealdb::sql::Datetime,
+    pub updated: surrealdb::sql::Datetime,
+}
+
+impl Advisory {
+    pub fn new(
+        id: &str,
+        tag: &str,
+        title: String,
+        kind: AdvisoryKind,
+        description: String,
+        packages: Vec<RpmRef>,
+    ) -> Self {
+        let now = chrono::Utc::now();
+        Self {
+            id: Thing::from((ADVISORY_TABLE, surrealdb::sql::Id::String(id.to_owned()))),
+            tag: RecordId::from_table_key(TAG_TABLE, tag),
+            title,
+            kind,
+            severity: None,
+            description,
+            references: Vec::new(),
+            packages,
+            issued: now.into(),
+            updated: now.into(),
+        }
+    }
+
+    pub async fn save(&self) -> Result<Self> {
+        let query = DB
+            .upsert((ADVISORY_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+
+        query.context("nothing returned from insert")
+    }
+
+    pub async fn get(id: &str) -> Result<Option<Self>> {
+        Ok(DB.retry(|| DB.select((ADVISORY_TABLE, id))).await?)
+    }
+
+    /// Fetch every advisory of a tag, oldest first
+    pub async fn get_by_tag(tag: &str) -> Result<Vec<Self>> {
+        let advisories: Vec<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM advisory WHERE tag = $tag ORDER BY issued;")
+                    .bind(("tag", RecordId::from_table_key(TAG_TABLE, tag)))
+            })
+            .await?
+            .take(0)?;
+
+        Ok(advisories)
+    }
+
+    pub async fn delete(&self) -> Result<()> {
+        let _: Option<Self> = DB.delete((ADVISORY_TABLE, self.id.id.to_raw())).await?;
+        Ok(())
+    }
+}
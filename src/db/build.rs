@@ -0,0 +1,225 @@
+//! Builds group every artifact of a single build (SRPM, binary subpackages and logs)
+//! into one entity, so they can be promoted, signed and deleted together.
+use color_eyre::{
+    eyre::{eyre, ContextCompat},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use surrealdb::{sql::Thing, RecordId};
+
+use super::{
+    generic::normalize_path,
+    record_key,
+    rpm::Rpm,
+    tag::{Tag, TAG_TABLE},
+    DB,
+};
+use crate::obj_store::object_store;
+
+pub const BUILD_TABLE: &str = "build";
+pub const BUILD_LOG_PREFIX: &str = "build";
+
+/// A non-package file attached to a build, such as a build log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildLog {
+    pub name: String,
+    pub object_key: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Build {
+    pub id: Thing,
+    /// Name-version-release of the build, i.e `ctwm-4.1.0-1.fc41`
+    ///
+    /// Taken from the first uploaded package if not set explicitly.
+    pub nvr: Option<String>,
+    pub tag: RecordId,
+    #[serde(default)]
+    pub logs: Vec<BuildLog>,
+    pub created_at: surrealdb::sql::Datetime,
+}
+
+impl Build {
+    pub fn new(tag: &str, nvr: Option<String>) -> Self {
+        Self {
+            id: Thing::from((BUILD_TABLE, surrealdb::sql::Id::ulid())),
+            nvr,
+            tag: RecordId::from_table_key(TAG_TABLE, tag),
+            logs: Vec::new(),
+            created_at: surrealdb::sql::Datetime::default(),
+        }
+    }
+
+    /// Record ID of this build, as referenced by its packages
+    pub fn record_id(&self) -> RecordId {
+        RecordId::from_table_key(BUILD_TABLE, self.id.id.to_raw())
+    }
+
+    pub async fn save(&self) -> Result<Self> {
+        let query = DB
+            .upsert((BUILD_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+
+        query.context("nothing returned from insert")
+    }
+
+    /// Fetch a build, if its tag is visible from the current namespace scope
+    pub async fn get(id: &str) -> Result<Option<Self>> {
+        let build: Option<Self> = DB.retry(|| DB.select((BUILD_TABLE, id))).await?;
+        match build {
+            Some(build) if Tag::visible(&record_key(&build.tag)).await? => Ok(Some(build)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Fetch every build whose tag is visible from the current namespace scope
+    pub async fn get_all() -> Result<Vec<Self>> {
+        let builds: Vec<Self> = DB.retry(|| DB.select(BUILD_TABLE)).await?;
+        let mut visible = Vec::with_capacity(builds.len());
+        for build in builds {
+            if Tag::visible(&record_key(&build.tag)).await? {
+                visible.push(build);
+            }
+        }
+        Ok(visible)
+    }
+
+    /// Fetch every package belonging to this build
+    pub async fn packages(&self) -> Result<Vec<Rpm>> {
+        let pkgs: Vec<Rpm> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM rpm_package WHERE build = $build;")
+                    .bind(("build", self.record_id()))
+            })
+            .await?
+            .take(0)?;
+
+        Ok(pkgs)
+    }
+
+    /// Add an uploaded package to this build, committing it to the database
+    pub async fn add_package(&mut self, mut rpm: Rpm) -> Result<Rpm> {
+        rpm.build = Some(self.record_id());
+        if let Err(e) = rpm.commit_to_db(false, false).await {
+            rpm.release_object().await?;
+            return Err(e);
+        }
+
+        if self.nvr.is_none() {
+            self.nvr = Some(format!("{}-{}-{}", rpm.name, rpm.version, rpm.release));
+            *self = self.save().await?;
+        }
+
+        Ok(rpm)
+    }
+
+    /// Attach a log (or any other non-package file) to this build
+    ///
+    /// The name is normalized like the path of a generic file, see [`normalize_path`].
+    pub async fn add_log(&mut self, name: &str, data: Vec<u8>) -> Result<Self> {
+        let name = normalize_path(name)?;
+        let object_key = crate::namespace::object_key(format!(
+            "{BUILD_LOG_PREFIX}/{id}/logs/{name}",
+            id = self.id.id.to_raw()
+        ));
+        object_store().put_bytes(&object_key, data).await?;
+
+        self.logs.retain(|l| l.name != name);
+        self.logs.push(BuildLog { name, object_key });
+        self.save().await
+    }
+
+    /// Mark every package of this build as available in its tag
+    ///
+    /// Every package is checked first, so nothing is marked if any of them can't be.
+    pub async fn mark_available(&self, force: bool) -> Result<Vec<Rpm>> {
+        let pkgs = self.packages().await?;
+        for pkg in &pkgs {
+            pkg.check_available(force).await?;
+        }
+
+        let mut marked = Vec::new();
+        for pkg in pkgs {
+            marked.push(pkg.mark_available(force).await?);
+        }
+        Ok(marked)
+    }
+
+    /// Sign every package of this build with the tag's signing key
+    ///
+    /// The packages are only updated once every one of them is signed, in one transaction.
+    pub async fn sign(&self) -> Result<Vec<Rpm>> {
+        let tag: Option<Tag> = DB.retry(|| DB.select(self.tag.clone())).await?;
+        let tag = tag.ok_or_else(|| eyre!("tag not found"))?;
+        let key = tag.get_signing_key().await?;
+
+        let pkgs = self.packages().await?;
+        let results = crate::signing::sign_objects(&pkgs, &key).await;
+        if results.iter().any(Result::is_err) {
+            // drop the new signed objects, the packages keep their old ones
+            for signed in results.iter().flatten() {
+                if let Some(signed_key) = &signed.signed_object_key {
+                    object_store().remove(signed_key).await.ok();
+                }
+            }
+        }
+        let signed = results.into_iter().collect::<Result<Vec<_>>>()?;
+
+        Rpm::save_signed(&signed).await?;
+
+        for pkg in &pkgs {
+            if let Some(old) = &pkg.signed_object_key {
+                pkg.remove_signed_object(old).await;
+            }
+        }
+        Ok(signed)
+    }
+
+    /// Move this build and all of its packages to another tag
+    ///
+    /// Packages are not available in the new tag until marked as such.
+    pub async fn promote(&self, tag: &str) -> Result<Self> {
+        let tag = RecordId::from_table_key(TAG_TABLE, tag);
+
+        DB.query("BEGIN;")
+            .query("UPDATE rpm_package SET tag = $tag, available = false WHERE build = $build;")
+            .query("UPDATE $build SET tag = $tag;")
+            .query("COMMIT;")
+            .bind(("tag", tag.clone()))
+            .bind(("build", self.record_id()))
+            .await?;
+
+        Ok(Self {
+            tag,
+            ..self.clone()
+        })
+    }
+
+    /// Delete this build, including all of its packages and logs
+    pub async fn delete(&self) -> Result<()> {
+        let pkgs = self.packages().await?;
+
+        DB.query("BEGIN;")
+            .query("DELETE rpm_package WHERE build = $build;")
+            .query("DELETE $build;")
+            .query("COMMIT;")
+            .bind(("build", self.record_id()))
+            .await?;
+
+        let objstore = object_store();
+        for pkg in pkgs {
+            // content-addressed objects may still be shared with packages outside the build
+            pkg.release_object().await?;
+            if let Some(signed) = &pkg.signed_object_key {
+                objstore.remove(signed).await.ok();
+            }
+        }
+        for log in &self.logs {
+            objstore.remove(&log.object_key).await?;
+        }
+
+        tracing::debug!(build = ?self.id, "deleted build");
+        Ok(())
+    }
+}
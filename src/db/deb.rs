@@ -0,0 +1,516 @@
+//! Debian packages, stored next to RPMs
+//!
+//! A `.deb` is an `ar` archive holding a `control.tar` with the package's metadata and a
+//! `data.tar` with its files. Only the `control` file is read on upload, and kept as-is so
+//! APT indexes can be generated from it later.
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use color_eyre::eyre::{eyre, WrapErr};
+use serde::{Deserialize, Serialize};
+use surrealdb::{sql::Thing, RecordId};
+use ulid::Ulid;
+
+use crate::debvercmp::{split_version, version_cmp};
+use crate::obj_store::object_store;
+
+use super::{
+    record_key,
+    rpm::{file_sha256, get_split_id_string, Count},
+    tag::{Tag, TAG_TABLE},
+    DB,
+};
+
+pub const DEB_PREFIX: &str = "deb";
+pub const DEB_TABLE: &str = "deb_package";
+
+/// MIME type Debian packages are served as
+pub const DEB_CONTENT_TYPE: &str = "application/vnd.debian.binary-package";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Deb {
+    pub id: Thing,
+    /// The `Package` field
+    pub name: String,
+    /// Full `[epoch:]upstream[-revision]` version
+    pub version: String,
+    /// Debian architecture, i.e. `amd64` or `all`
+    pub arch: String,
+    /// Dependencies, alternatives are kept together, i.e. `bash | zsh`
+    #[serde(default)]
+    pub depends: Vec<String>,
+    #[serde(default)]
+    pub pre_depends: Vec<String>,
+    #[serde(default)]
+    pub provides: Vec<String>,
+    pub maintainer: Option<String>,
+    pub section: Option<String>,
+    pub priority: Option<String>,
+    pub description: Option<String>,
+    /// Size of the installed files in KiB, as declared by the package
+    pub installed_size: Option<u64>,
+    /// The package's control file as shipped
+    pub control: String,
+    pub object_key: String,
+    pub tag: RecordId,
+    pub timestamp: surrealdb::sql::Datetime,
+    /// Whether the package is the one published in the tag, for its name + architecture
+    #[serde(default)]
+    pub available: bool,
+    pub sha256: Option<String>,
+    /// Size of the `.deb` in bytes
+    pub size: u64,
+}
+
+/// A lighter reference to a Debian package, for listings
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DebRef {
+    pub id: Ulid,
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+    pub object_key: String,
+    pub tag: String,
+    pub available: bool,
+}
+
+impl From<&Deb> for DebRef {
+    fn from(deb: &Deb) -> Self {
+        Self {
+            id: Ulid::from_string(&deb.id.id.to_raw()).unwrap_or_default(),
+            name: deb.name.clone(),
+            version: deb.version.clone(),
+            arch: deb.arch.clone(),
+            object_key: deb.object_key.clone(),
+            tag: record_key(&deb.tag),
+            available: deb.available,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DebFilter {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub arch: Option<String>,
+    pub tag: Option<String>,
+}
+
+const DEB_FILTER_CLAUSE: &str = "($name = NONE OR name = $name) \
+    AND ($version = NONE OR version = $version) \
+    AND ($arch = NONE OR arch = $arch) \
+    AND ($tag = NONE OR tag = $tag)";
+
+/// Parse a control paragraph into its fields, keeping continuation lines
+///
+/// Field names are case-insensitive, so they're keyed in lowercase.
+pub fn parse_control(control: &str) -> color_eyre::Result<BTreeMap<String, String>> {
+    let mut fields: BTreeMap<String, String> = BTreeMap::new();
+    let mut last: Option<String> = None;
+    for line in control.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.starts_with([' ', '\t']) {
+            let field = last
+                .as_ref()
+                .and_then(|name| fields.get_mut(name))
+                .ok_or_else(|| eyre!("continuation line without a field: {line:?}"))?;
+            field.push('\n');
+            field.push_str(line);
+            continue;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| eyre!("invalid control line: {line:?}"))?;
+        let name = name.trim().to_lowercase();
+        fields.insert(name.clone(), value.trim().to_owned());
+        last = Some(name);
+    }
+    Ok(fields)
+}
+
+/// Split a relationship field, i.e. `Depends`, into its comma separated entries
+fn relations(fields: &BTreeMap<String, String>, name: &str) -> Vec<String> {
+    fields
+        .get(name)
+        .map(|value| {
+            value
+                .split(',')
+                .map(|rel| rel.split_whitespace().collect::<Vec<_>>().join(" "))
+                .filter(|rel| !rel.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Decompress a member of the package, by its extension
+fn decompress<'a>(name: &str, reader: impl Read + 'a) -> color_eyre::Result<Box<dyn Read + 'a>> {
+    Ok(match name.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("gz") => Box::new(flate2::read::GzDecoder::new(reader)),
+        Some("xz") => Box::new(xz2::read::XzDecoder::new(reader)),
+        Some("zst") => Box::new(zstd::Decoder::new(reader)?),
+        Some("tar") => Box::new(reader),
+        _ => return Err(eyre!("unsupported compression of {name}")),
+    })
+}
+
+/// Read the `control` file out of a `.deb`
+pub fn read_control(path: &std::path::Path) -> color_eyre::Result<String> {
+    let file = std::fs::File::open(path)?;
+    let mut archive = ar::Archive::new(file);
+    while let Some(entry) = archive.next_entry() {
+        let entry = entry.wrap_err("not a Debian package")?;
+        let name = String::from_utf8_lossy(entry.header().identifier()).into_owned();
+        if !name.starts_with("control.tar") {
+            continue;
+        }
+
+        let mut control = tar::Archive::new(decompress(&name, entry)?);
+        for file in control.entries()? {
+            let mut file = file?;
+            if file.path()?.file_name() != Some("control".as_ref()) {
+                continue;
+            }
+            let mut text = String::new();
+            file.read_to_string(&mut text)?;
+            return Ok(text);
+        }
+        return Err(eyre!("{name} has no control file"));
+    }
+    Err(eyre!("not a Debian package, no control archive"))
+}
+
+/// Object key of a package, named like Debian names its files, without the epoch
+fn deb_object_key(id: &str, name: &str, version: &str, arch: &str) -> String {
+    let (_, upstream, revision) = split_version(version);
+    let version = if revision.is_empty() {
+        upstream.to_owned()
+    } else {
+        format!("{upstream}-{revision}")
+    };
+    crate::namespace::object_key(format!(
+        "{DEB_PREFIX}/{}/{name}_{version}_{arch}.deb",
+        get_split_id_string(id)
+    ))
+}
+
+/// Check the fields naming a package against the charsets allowed by Debian policy
+///
+/// They end up in object keys, repository paths and `Packages` indexes, so anything else is
+/// refused rather than escaped.
+fn check_fields(name: &str, version: &str, arch: &str) -> color_eyre::Result<()> {
+    let lower_alnum = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    if name.len() < 2
+        || !name.starts_with(lower_alnum)
+        || !name.chars().all(|c| lower_alnum(c) || "+.-".contains(c))
+    {
+        return Err(eyre!("invalid package name {name:?}"));
+    }
+
+    let (epoch, rest) = version.split_once(':').unwrap_or(("0", version));
+    let (upstream, revision) = match rest.rsplit_once('-') {
+        Some((upstream, revision)) => (upstream, Some(revision)),
+        None => (rest, None),
+    };
+    let version_char = |c: char| c.is_ascii_alphanumeric() || ".+~".contains(c);
+    let valid_version = !epoch.is_empty()
+        && epoch.chars().all(|c| c.is_ascii_digit())
+        && upstream.starts_with(|c: char| c.is_ascii_digit())
+        // hyphens are only allowed in the upstream version if there's a revision
+        && upstream
+            .chars()
+            .all(|c| version_char(c) || (c == '-' && revision.is_some()))
+        && revision.is_none_or(|r| !r.is_empty() && r.chars().all(version_char));
+    if !valid_version {
+        return Err(eyre!("invalid version {version:?}"));
+    }
+
+    if arch.is_empty() || !arch.chars().all(|c| lower_alnum(c) || c == '-') {
+        return Err(eyre!("invalid architecture {arch:?}"));
+    }
+    Ok(())
+}
+
+impl Deb {
+    /// Parse a package from its control file
+    pub fn from_control(control: String, tag: &str) -> color_eyre::Result<Self> {
+        let fields = parse_control(&control)?;
+        let field = |name: &str| fields.get(name).cloned();
+        let required = |name: &str| field(name).ok_or_else(|| eyre!("control file has no {name}"));
+
+        let id = Thing::from((DEB_TABLE, surrealdb::sql::Id::ulid()));
+        let name = required("package")?;
+        let version = required("version")?;
+        let arch = required("architecture")?;
+        check_fields(&name, &version, &arch)?;
+        Ok(Self {
+            object_key: deb_object_key(&id.id.to_raw(), &name, &version, &arch),
+            id,
+            depends: relations(&fields, "depends"),
+            pre_depends: relations(&fields, "pre-depends"),
+            provides: relations(&fields, "provides"),
+            maintainer: field("maintainer"),
+            section: field("section"),
+            priority: field("priority"),
+            description: field("description"),
+            installed_size: field("installed-size").and_then(|s| s.parse().ok()),
+            name,
+            version,
+            arch,
+            control,
+            tag: RecordId::from_table_key(TAG_TABLE, tag),
+            timestamp: chrono::Utc::now().into(),
+            available: false,
+            sha256: None,
+            size: 0,
+        })
+    }
+
+    pub fn from_path(path: impl AsRef<std::path::Path>, tag: &str) -> color_eyre::Result<Self> {
+        let path = path.as_ref();
+        let mut deb = Self::from_control(read_control(path)?, tag)?;
+        deb.sha256 = Some(file_sha256(path)?);
+        deb.size = std::fs::metadata(path)?.len();
+        Ok(deb)
+    }
+
+    /// `name_version_arch`, the way Debian identifies a package
+    pub fn full_name(&self) -> String {
+        format!("{}_{}_{}", self.name, self.version, self.arch)
+    }
+
+    /// File name of the package, the last component of its object key
+    pub fn file_name(&self) -> &str {
+        self.object_key.rsplit('/').next().unwrap_or(&self.object_key)
+    }
+
+    #[tracing::instrument]
+    pub async fn get(id: Ulid) -> color_eyre::Result<Option<Self>> {
+        let a: Option<Self> = DB
+            .retry(|| async move { DB.select((DEB_TABLE, id.to_string())).await })
+            .await?;
+        match a {
+            Some(a) if Tag::visible(&record_key(&a.tag)).await? => Ok(Some(a)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Fetches a page of packages matching a filter, along with the total number of matches
+    pub async fn get_page(
+        filter: &DebFilter,
+        limit: u32,
+        offset: u32,
+    ) -> color_eyre::Result<(Vec<Self>, u64)> {
+        let mut query = DB
+            .retry(|| {
+                DB.query(format!(
+                    "SELECT * FROM deb_package WHERE {DEB_FILTER_CLAUSE} ORDER BY id LIMIT $limit START $offset;"
+                ))
+                .query(format!(
+                    "SELECT count() FROM deb_package WHERE {DEB_FILTER_CLAUSE} GROUP ALL;"
+                ))
+                .bind(("name", filter.name.clone()))
+                .bind(("version", filter.version.clone()))
+                .bind(("arch", filter.arch.clone()))
+                .bind((
+                    "tag",
+                    filter
+                        .tag
+                        .as_ref()
+                        .map(|t| RecordId::from_table_key(TAG_TABLE, t)),
+                ))
+                .bind(("limit", limit))
+                .bind(("offset", offset))
+            })
+            .await?;
+
+        let page: Vec<Self> = query.take(0)?;
+        let total: Option<Count> = query.take(1)?;
+
+        Ok((page, total.map_or(0, |c| c.count)))
+    }
+
+    /// Fetches the packages available in a tag
+    pub async fn get_available_by_tag(tag: &str) -> color_eyre::Result<Vec<Self>> {
+        let a: Vec<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM deb_package WHERE tag = $tag AND available = true;")
+                    .bind(("tag", RecordId::from_table_key(TAG_TABLE, tag)))
+            })
+            .await?
+            .take(0)?;
+        Ok(a)
+    }
+
+    /// Find a package in this tag with the same name, version, architecture and digest,
+    /// i.e. an earlier upload of the same file
+    pub async fn find_duplicate(&self) -> color_eyre::Result<Option<Self>> {
+        if self.sha256.is_none() {
+            return Ok(None);
+        }
+        let a: Option<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM deb_package WHERE sha256 = $sha256 AND name = $name AND version = $version AND arch = $arch AND tag = $tag AND id != $id LIMIT 1;")
+                    .bind(("sha256", self.sha256.clone()))
+                    .bind(("name", self.name.clone()))
+                    .bind(("version", self.version.clone()))
+                    .bind(("arch", self.arch.clone()))
+                    .bind(("tag", self.tag.clone()))
+                    .bind(("id", self.id.clone()))
+            })
+            .await?
+            .take(0)?;
+        Ok(a)
+    }
+
+    /// Fetch the other available packages with the same name + architecture in the tag
+    async fn available_siblings(&self) -> color_eyre::Result<Vec<Self>> {
+        let siblings: Vec<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM deb_package WHERE name = $name AND arch = $arch AND tag = $tag AND available = true AND id != $id;")
+                    .bind(("name", self.name.clone()))
+                    .bind(("arch", self.arch.clone()))
+                    .bind(("tag", self.tag.clone()))
+                    .bind(("id", self.id.clone()))
+            })
+            .await?
+            .take(0)?;
+        Ok(siblings)
+    }
+
+    /// Find an available package with the same name + architecture in the tag, with a newer version
+    pub async fn newer_sibling(&self) -> color_eyre::Result<Option<Self>> {
+        Ok(self
+            .available_siblings()
+            .await?
+            .into_iter()
+            .find(|s| version_cmp(&s.version, &self.version) == std::cmp::Ordering::Greater))
+    }
+
+    /// Mark this package as the available one for its name + architecture in the tag
+    ///
+    /// Fails, unless `force` is set, if an already available package has a newer version.
+    pub async fn mark_available(&self, force: bool) -> color_eyre::Result<Self> {
+        if !force {
+            if let Some(newer) = self.newer_sibling().await? {
+                return Err(eyre!(
+                    "{} has a newer version available ({}), not marking as available",
+                    self.name,
+                    newer.version
+                ));
+            }
+        }
+
+        DB.query("BEGIN;")
+            .query("UPDATE deb_package SET available = false WHERE name = $name AND arch = $arch AND tag = $tag AND id != $id;")
+            .query("UPDATE $id SET available = true;")
+            .query("COMMIT;")
+            .bind(("name", self.name.clone()))
+            .bind(("arch", self.arch.clone()))
+            .bind(("tag", self.tag.clone()))
+            .bind(("id", self.record_id()))
+            .await?
+            .check()?;
+
+        let id = Ulid::from_string(&self.id.id.to_raw())?;
+        Self::get(id).await?.ok_or_else(|| eyre!("failed to update entry"))
+    }
+
+    pub async fn mark_unavailable(&self) -> color_eyre::Result<()> {
+        DB.query("UPDATE $id SET available = false;")
+            .bind(("id", self.record_id()))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    fn record_id(&self) -> RecordId {
+        RecordId::from_table_key(DEB_TABLE, self.id.id.to_raw())
+    }
+
+    /// Commits the package to the database, optionally marking it as the latest version in
+    /// its tag unless a newer version is available (without `force`)
+    pub async fn commit_to_db(&self, latest: bool, force: bool) -> color_eyre::Result<Self> {
+        let inserted: Option<Self> = DB
+            .insert((DEB_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+        let inserted = inserted.ok_or_else(|| eyre!("nothing returned from insert"))?;
+
+        if latest {
+            match self.mark_available(force).await {
+                Ok(available) => return Ok(available),
+                Err(e) => tracing::info!("{e}"),
+            }
+        }
+        Ok(inserted)
+    }
+
+    pub async fn delete(&self) -> color_eyre::Result<()> {
+        let _: Option<Self> = DB.delete((DEB_TABLE, self.id.id.to_raw())).await?;
+        object_store().remove(&self.object_key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEB_PATH: &str = "test/data/subatomic-hello_1.0.2-1_all.deb";
+
+    #[test]
+    fn test_parse_control() {
+        let fields = parse_control(
+            "Package: foo\nVersion: 1.0-1\nDescription: short\n more\n .\n last\n",
+        )
+        .unwrap();
+        assert_eq!(fields["package"], "foo");
+        assert_eq!(fields["description"], "short\n more\n .\n last");
+        assert!(parse_control(" orphan\n").is_err());
+        assert!(parse_control("no colon\n").is_err());
+    }
+
+    #[test]
+    fn test_check_fields() {
+        assert!(check_fields("subatomic-hello", "1:1.0.2-1", "all").is_ok());
+        assert!(check_fields("libc++1", "2.0~rc1-1-2+b1", "amd64").is_ok());
+        assert!(check_fields("g++", "13.2.0", "linux-any").is_ok());
+        for (name, version, arch) in [
+            ("Foo", "1.0", "all"),
+            ("f", "1.0", "all"),
+            ("-foo", "1.0", "all"),
+            ("foo/../bar", "1.0", "all"),
+            ("foo", "a1.0", "all"),
+            ("foo", "x:1.0", "all"),
+            ("foo", "1.0-", "all"),
+            ("foo", "1.0_1", "all"),
+            ("foo", "1.0/../x", "all"),
+            ("foo", "1.0", "amd64/.."),
+            ("foo", "1.0", ""),
+        ] {
+            assert!(
+                check_fields(name, version, arch).is_err(),
+                "{name} {version} {arch}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_deb_from_path() {
+        let deb = Deb::from_path(DEB_PATH, "foobar").unwrap();
+        assert_eq!(deb.name, "subatomic-hello");
+        assert_eq!(deb.version, "1:1.0.2-1");
+        assert_eq!(deb.arch, "all");
+        assert_eq!(deb.depends, vec!["libc6 (>= 2.34)", "bash | zsh"]);
+        assert_eq!(deb.pre_depends, vec!["dpkg (>= 1.15)"]);
+        assert_eq!(deb.provides, vec!["hello-world"]);
+        assert_eq!(deb.installed_size, Some(1));
+        assert_eq!(deb.sha256.as_deref().map(str::len), Some(64));
+        assert_eq!(deb.file_name(), "subatomic-hello_1.0.2-1_all.deb");
+        assert!(deb.object_key.starts_with("deb/"));
+
+        let rpm = "test/data/anda-srpm-macros-0:0.2.6-1.fc41.noarch.rpm";
+        assert!(Deb::from_path(rpm, "foobar").is_err());
+    }
+}
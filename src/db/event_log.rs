@@ -0,0 +1,342 @@
+//! Audit log of actions taken on tags, packages and keys, stored in the `log` table
+use color_eyre::Result;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+
+use super::{
+    deb::{Deb, DEB_TABLE},
+    generic::{GenericFile, GENERIC_TABLE},
+    oci::{OciManifest, OCI_MANIFEST_TABLE},
+    ostree::{OstreeCommit, OSTREE_TABLE},
+    pacman::{PacmanPackage, PACMAN_TABLE},
+    rpm::{Count, Rpm, RPM_TABLE},
+    DB,
+};
+
+pub const LOG_TABLE: &str = "log";
+/// Actor of events not caused by a caller, i.e. scheduled assemblies
+pub const SYSTEM_ACTOR: &str = "subatomic";
+/// Events fetched at a time when walking the whole log, see [`LogEvent::stream`]
+const STREAM_PAGE_SIZE: u32 = 500;
+/// Filter and last event of the previous page when walking the log, `None` after the last page
+type StreamState = Option<(EventFilter, Option<LogEvent>)>;
+/// Header row of [`LogEvent::to_csv`]
+pub const CSV_HEADER: &str = "id,timestamp,actor,action,tag,resource,data\n";
+
+/// Filter for listing events, unset fields match every event
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EventFilter {
+    /// Action of the events, i.e. `upload`
+    #[serde(rename = "type", alias = "action")]
+    pub action: Option<String>,
+    pub tag: Option<String>,
+    /// Name of the caller that took the action
+    pub actor: Option<String>,
+    /// Affected record, either as `table:key` or only its key, i.e. a package's ULID
+    pub resource: Option<String>,
+    /// Only events at or after this time
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only events before this time
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// WHERE clause applying an [`EventFilter`], with each field bound as a parameter
+const EVENT_FILTER_CLAUSE: &str = "($action = NONE OR action = $action) \
+    AND ($tag = NONE OR tag = $tag) \
+    AND ($actor = NONE OR actor = $actor) \
+    AND ($resource = NONE OR resource = $resource \
+        OR string::ends_with(resource ?? '', ':' + $resource)) \
+    AND ($since = NONE OR timestamp >= $since) \
+    AND ($until = NONE OR timestamp < $until)";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogEvent {
+    pub id: Thing,
+    /// What happened, i.e `promote`
+    pub action: String,
+    /// Name of the caller that took the action
+    pub actor: String,
+    /// Stored as NONE when null
+    #[serde(default)]
+    pub data: serde_json::Value,
+    /// Tag the action was taken on, if any
+    #[serde(default)]
+    pub tag: Option<String>,
+    /// Record affected by the action, i.e. `rpm_package:01J...` or `gpg_key:fyralabs`
+    #[serde(default)]
+    pub resource: Option<String>,
+    pub timestamp: Datetime,
+    /// When the event expires and is removed from the log, kept forever if unset
+    #[serde(default)]
+    pub ttl: Option<Datetime>,
+}
+
+impl LogEvent {
+    pub fn new(action: &str, actor: String, data: serde_json::Value) -> Self {
+        Self {
+            id: Thing::from((
+                LOG_TABLE,
+                surrealdb::sql::Id::String(ulid::Ulid::new().to_string()),
+            )),
+            action: action.to_owned(),
+            actor,
+            data,
+            tag: None,
+            resource: None,
+            timestamp: Datetime::default(),
+            ttl: None,
+        }
+    }
+
+    /// Event about a package in a tag, with the package's ID and NEVRA as data
+    pub fn for_rpm(action: &str, actor: String, rpm: &Rpm, tag: &str) -> Self {
+        let id = rpm.id.id.to_raw();
+        Self::new(action, actor, serde_json::json!({ "rpm": id, "nevra": rpm.nevra() }))
+            .tag(tag)
+            .resource(RPM_TABLE, &id)
+    }
+
+    /// Event about a Debian package in a tag, with the package's ID and full name as data
+    pub fn for_deb(action: &str, actor: String, deb: &Deb, tag: &str) -> Self {
+        let id = deb.id.id.to_raw();
+        Self::new(action, actor, serde_json::json!({ "deb": id, "package": deb.full_name() }))
+            .tag(tag)
+            .resource(DEB_TABLE, &id)
+    }
+
+    pub fn for_pacman(action: &str, actor: String, pkg: &PacmanPackage, tag: &str) -> Self {
+        let id = pkg.id.id.to_raw();
+        Self::new(action, actor, serde_json::json!({ "pacman": id, "package": pkg.full_name() }))
+            .tag(tag)
+            .resource(PACMAN_TABLE, &id)
+    }
+
+    pub fn for_file(action: &str, actor: String, file: &GenericFile, tag: &str) -> Self {
+        let id = file.id.id.to_raw();
+        Self::new(action, actor, serde_json::json!({ "file": id, "path": file.path }))
+            .tag(tag)
+            .resource(GENERIC_TABLE, &id)
+    }
+
+    /// Event about an image manifest, with the image and the manifest's digest as data
+    pub fn for_oci(action: &str, actor: String, manifest: &OciManifest, tag: &str) -> Self {
+        let id = manifest.id.id.to_raw();
+        let image = format!("{tag}/{}", manifest.name);
+        Self::new(action, actor, serde_json::json!({ "image": image, "digest": manifest.digest }))
+            .tag(tag)
+            .resource(OCI_MANIFEST_TABLE, &id)
+    }
+
+    pub fn for_ostree(action: &str, actor: String, commit: &OstreeCommit, tag: &str) -> Self {
+        let id = commit.id.id.to_raw();
+        let data = serde_json::json!({ "ref": commit.ref_name, "commit": commit.checksum });
+        Self::new(action, actor, data).tag(tag).resource(OSTREE_TABLE, &id)
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tag = Some(tag.to_owned());
+        self
+    }
+
+    pub fn resource(mut self, table: &str, key: &str) -> Self {
+        self.resource = Some(format!("{table}:{key}"));
+        self
+    }
+
+    /// Write the event to the log and send it to the matching webhooks
+    pub async fn record(&self) -> Result<()> {
+        let _: Option<Self> = DB
+            .create((LOG_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+        crate::webhook::fire(self).await;
+        Ok(())
+    }
+
+    /// Fetch the events of an action, oldest first
+    #[cfg(test)]
+    pub async fn get_by_action(action: &str) -> Result<Vec<Self>> {
+        let events: Vec<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM log WHERE action = $action ORDER BY timestamp;")
+                    .bind(("action", action.to_owned()))
+            })
+            .await?
+            .take(0)?;
+
+        Ok(events)
+    }
+
+    /// Fetch a page of the events matching the filter, with the total number of matches
+    ///
+    /// Events are listed newest first, or in the order they happened with `oldest_first`.
+    pub async fn get_page(
+        filter: &EventFilter,
+        oldest_first: bool,
+        limit: u32,
+        offset: u32,
+    ) -> Result<(Vec<Self>, u64)> {
+        let order = if oldest_first { "ASC" } else { "DESC" };
+        let mut query = DB
+            .retry(|| {
+                DB.query(format!(
+                    "SELECT * FROM log WHERE {EVENT_FILTER_CLAUSE} \
+                     ORDER BY timestamp {order} LIMIT $limit START $offset;"
+                ))
+                .query(format!(
+                    "SELECT count() FROM log WHERE {EVENT_FILTER_CLAUSE} GROUP ALL;"
+                ))
+                .bind(("action", filter.action.clone()))
+                .bind(("tag", filter.tag.clone()))
+                .bind(("actor", filter.actor.clone()))
+                .bind(("resource", filter.resource.clone()))
+                .bind(("since", filter.since.map(Datetime::from)))
+                .bind(("until", filter.until.map(Datetime::from)))
+                .bind(("limit", limit))
+                .bind(("offset", offset))
+            })
+            .await?;
+
+        let page: Vec<Self> = query.take(0)?;
+        let total: Option<Count> = query.take(1)?;
+
+        Ok((page, total.map_or(0, |c| c.count)))
+    }
+
+    /// Events after `after` in the order they happened
+    ///
+    /// Pages continue from the last event of the previous one rather than an offset, so walking
+    /// the log neither skips nor repeats events when others are recorded or expire in between.
+    pub async fn get_after(
+        filter: &EventFilter,
+        after: Option<&Self>,
+        limit: u32,
+    ) -> Result<Vec<Self>> {
+        let events: Vec<Self> = DB
+            .retry(|| {
+                DB.query(format!(
+                    "SELECT * FROM log WHERE {EVENT_FILTER_CLAUSE} \
+                     AND ($after_id = NONE OR timestamp > $after_timestamp \
+                         OR (timestamp = $after_timestamp AND id > $after_id)) \
+                     ORDER BY timestamp, id LIMIT $limit;"
+                ))
+                .bind(("action", filter.action.clone()))
+                .bind(("tag", filter.tag.clone()))
+                .bind(("actor", filter.actor.clone()))
+                .bind(("resource", filter.resource.clone()))
+                .bind(("since", filter.since.map(Datetime::from)))
+                .bind(("until", filter.until.map(Datetime::from)))
+                .bind(("after_id", after.map(|event| event.id.clone())))
+                .bind((
+                    "after_timestamp",
+                    after.map(|event| event.timestamp.clone()),
+                ))
+                .bind(("limit", limit))
+            })
+            .await?
+            .take(0)?;
+        Ok(events)
+    }
+
+    /// Every event matching the filter in the order they happened, fetched a page at a time
+    pub fn stream(filter: EventFilter) -> impl Stream<Item = Result<Vec<Self>>> {
+        futures::stream::try_unfold(Some((filter, None)), Self::next_page)
+    }
+
+    /// Fetch the next page for [`LogEvent::stream`], `None` once the log was walked
+    async fn next_page(state: StreamState) -> Result<Option<(Vec<Self>, StreamState)>> {
+        let Some((filter, after)) = state else {
+            return Ok(None);
+        };
+        let page = Self::get_after(&filter, after.as_ref(), STREAM_PAGE_SIZE).await?;
+        let Some(last) = page.last().cloned() else {
+            return Ok(None);
+        };
+        let next = (page.len() == STREAM_PAGE_SIZE as usize).then_some((filter, Some(last)));
+        Ok(Some((page, next)))
+    }
+
+    /// Render events as CSV, one row per event with its data as a JSON column
+    #[cfg(test)]
+    pub fn to_csv(events: &[Self]) -> String {
+        let mut csv = String::from(CSV_HEADER);
+        csv.push_str(&Self::to_csv_rows(events));
+        csv
+    }
+
+    /// Render events as CSV rows, without the [`CSV_HEADER`]
+    pub fn to_csv_rows(events: &[Self]) -> String {
+        let mut csv = String::new();
+        for event in events {
+            let row = [
+                event.id.id.to_raw(),
+                event.timestamp.to_rfc3339(),
+                event.actor.clone(),
+                event.action.clone(),
+                event.tag.clone().unwrap_or_default(),
+                event.resource.clone().unwrap_or_default(),
+                event.data.to_string(),
+            ];
+            let row: Vec<_> = row.iter().map(|field| csv_field(field)).collect();
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+}
+
+/// Quote a CSV field if it contains a separator, quote or line break
+///
+/// Fields a spreadsheet would evaluate as a formula, i.e. an actor named `=HYPERLINK(...)`, are
+/// prefixed with `'` so they're shown as text instead.
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{field}")
+    } else {
+        field.to_owned()
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_csv() {
+        let event = LogEvent::new(
+            "promote",
+            "ci".to_owned(),
+            serde_json::json!({ "from": "f41-testing", "to": "f41" }),
+        )
+        .tag("f41");
+        let csv = LogEvent::to_csv(std::slice::from_ref(&event));
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("id,timestamp,actor,action,tag,resource,data"));
+        assert_eq!(
+            lines.next().unwrap(),
+            format!(
+                r#"{},{},ci,promote,f41,,"{{""from"":""f41-testing"",""to"":""f41""}}""#,
+                event.id.id.to_raw(),
+                event.timestamp.to_rfc3339()
+            )
+        );
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("ci"), "ci");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("\tcmd"), "'\tcmd");
+    }
+}
@@ -0,0 +1,261 @@
+//! Generic files, i.e. ISOs, tarballs and checksums hosted next to a tag's repos
+//!
+//! Files are identified by their path in the tag, and exported under its `files/` directory.
+//! Uploading to a path already in use replaces the file there, the older uploads are kept but
+//! no longer available.
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use surrealdb::{sql::Thing, RecordId};
+use ulid::Ulid;
+
+use crate::obj_store::object_store;
+
+use super::{
+    record_key,
+    rpm::{file_sha256, get_split_id_string, Count},
+    tag::{Tag, TAG_TABLE},
+    DB,
+};
+
+pub const GENERIC_PREFIX: &str = "generic";
+pub const GENERIC_TABLE: &str = "generic_file";
+/// Directory of a tag's export the files are published under
+pub const FILES_SUBDIR: &str = "files";
+
+/// MIME type files are served as when none was given on upload
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenericFile {
+    pub id: Thing,
+    /// Path of the file relative to the tag's `files/` directory, i.e. `iso/live.iso`
+    pub path: String,
+    pub content_type: String,
+    pub object_key: String,
+    pub tag: RecordId,
+    pub timestamp: surrealdb::sql::Datetime,
+    /// Whether this is the file currently at its path, as opposed to an older upload
+    #[serde(default)]
+    pub available: bool,
+    pub sha256: Option<String>,
+    pub size: u64,
+}
+
+/// A lighter reference to a file, for listings
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenericFileRef {
+    pub id: Ulid,
+    pub path: String,
+    pub object_key: String,
+    pub tag: String,
+    pub available: bool,
+    pub size: u64,
+}
+
+impl From<&GenericFile> for GenericFileRef {
+    fn from(file: &GenericFile) -> Self {
+        Self {
+            id: Ulid::from_string(&file.id.id.to_raw()).unwrap_or_default(),
+            path: file.path.clone(),
+            object_key: file.object_key.clone(),
+            tag: record_key(&file.tag),
+            available: file.available,
+            size: file.size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GenericFileFilter {
+    pub path: Option<String>,
+    pub tag: Option<String>,
+    pub available: Option<bool>,
+}
+
+const GENERIC_FILTER_CLAUSE: &str = "($path = NONE OR path = $path) \
+    AND ($tag = NONE OR tag = $tag) \
+    AND ($available = NONE OR available = $available)";
+
+/// Normalize a file path given on upload, rejecting anything that could escape `files/`
+///
+/// Leading and duplicate slashes are dropped, `.` and `..` components aren't allowed.
+pub fn normalize_path(path: &str) -> color_eyre::Result<String> {
+    let components = path
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .collect::<Vec<_>>();
+    if components.is_empty() {
+        return Err(eyre!("path is empty"));
+    }
+    if let Some(component) = components
+        .iter()
+        .find(|component| matches!(**component, "." | "..") || component.contains('\0'))
+    {
+        return Err(eyre!("invalid path component {component:?}"));
+    }
+    Ok(components.join("/"))
+}
+
+impl GenericFile {
+    /// A file uploaded to `path` of a tag, read from a local file
+    pub fn from_path(
+        local_path: impl AsRef<std::path::Path>,
+        path: &str,
+        content_type: Option<String>,
+        tag: &str,
+    ) -> color_eyre::Result<Self> {
+        let local_path = local_path.as_ref();
+        let path = normalize_path(path)?;
+        let id = Thing::from((GENERIC_TABLE, surrealdb::sql::Id::ulid()));
+        let file_name = path.rsplit('/').next().unwrap_or(&path);
+        Ok(Self {
+            object_key: crate::namespace::object_key(format!(
+                "{GENERIC_PREFIX}/{}/{file_name}",
+                get_split_id_string(&id.id.to_raw())
+            )),
+            id,
+            content_type: content_type.unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_owned()),
+            tag: RecordId::from_table_key(TAG_TABLE, tag),
+            timestamp: chrono::Utc::now().into(),
+            available: false,
+            sha256: Some(file_sha256(local_path)?),
+            size: std::fs::metadata(local_path)?.len(),
+            path,
+        })
+    }
+
+    /// File name of the file, the last component of its path
+    pub fn file_name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+
+    #[tracing::instrument]
+    pub async fn get(id: Ulid) -> color_eyre::Result<Option<Self>> {
+        let a: Option<Self> = DB
+            .retry(|| async move { DB.select((GENERIC_TABLE, id.to_string())).await })
+            .await?;
+        match a {
+            Some(a) if Tag::visible(&record_key(&a.tag)).await? => Ok(Some(a)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Fetches a page of files matching a filter, along with the total number of matches
+    pub async fn get_page(
+        filter: &GenericFileFilter,
+        limit: u32,
+        offset: u32,
+    ) -> color_eyre::Result<(Vec<Self>, u64)> {
+        let mut query = DB
+            .retry(|| {
+                DB.query(format!(
+                    "SELECT * FROM generic_file WHERE {GENERIC_FILTER_CLAUSE} ORDER BY id LIMIT $limit START $offset;"
+                ))
+                .query(format!(
+                    "SELECT count() FROM generic_file WHERE {GENERIC_FILTER_CLAUSE} GROUP ALL;"
+                ))
+                .bind(("path", filter.path.clone()))
+                .bind((
+                    "tag",
+                    filter
+                        .tag
+                        .as_ref()
+                        .map(|t| RecordId::from_table_key(TAG_TABLE, t)),
+                ))
+                .bind(("available", filter.available))
+                .bind(("limit", limit))
+                .bind(("offset", offset))
+            })
+            .await?;
+
+        let page: Vec<Self> = query.take(0)?;
+        let total: Option<Count> = query.take(1)?;
+
+        Ok((page, total.map_or(0, |c| c.count)))
+    }
+
+    /// Fetches the files currently available in a tag
+    pub async fn get_available_by_tag(tag: &str) -> color_eyre::Result<Vec<Self>> {
+        let a: Vec<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM generic_file WHERE tag = $tag AND available = true;")
+                    .bind(("tag", RecordId::from_table_key(TAG_TABLE, tag)))
+            })
+            .await?
+            .take(0)?;
+        Ok(a)
+    }
+
+    /// Find the file currently at the same path in this tag, if it has the same content
+    pub async fn find_duplicate(&self) -> color_eyre::Result<Option<Self>> {
+        let a: Option<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM generic_file WHERE sha256 = $sha256 AND path = $path AND tag = $tag AND available = true AND id != $id LIMIT 1;")
+                    .bind(("sha256", self.sha256.clone()))
+                    .bind(("path", self.path.clone()))
+                    .bind(("tag", self.tag.clone()))
+                    .bind(("id", self.id.clone()))
+            })
+            .await?
+            .take(0)?;
+        Ok(a)
+    }
+
+    /// Commits the file to the database, replacing the file currently at its path
+    pub async fn commit_to_db(&self) -> color_eyre::Result<Self> {
+        DB.query("BEGIN;")
+            .query("UPDATE generic_file SET available = false WHERE path = $path AND tag = $tag;")
+            .query("CREATE $id CONTENT $content;")
+            .query("UPDATE $id SET available = true;")
+            .query("COMMIT;")
+            .bind(("path", self.path.clone()))
+            .bind(("tag", self.tag.clone()))
+            .bind(("id", self.record_id()))
+            .bind(("content", self.clone()))
+            .await?
+            .check()?;
+
+        let id = Ulid::from_string(&self.id.id.to_raw())?;
+        Self::get(id).await?.ok_or_else(|| eyre!("failed to create entry"))
+    }
+
+    fn record_id(&self) -> RecordId {
+        RecordId::from_table_key(GENERIC_TABLE, self.id.id.to_raw())
+    }
+
+    pub async fn delete(&self) -> color_eyre::Result<()> {
+        let _: Option<Self> = DB.delete((GENERIC_TABLE, self.id.id.to_raw())).await?;
+        object_store().remove(&self.object_key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("iso/live.iso").unwrap(), "iso/live.iso");
+        assert_eq!(normalize_path("/iso//live.iso").unwrap(), "iso/live.iso");
+        assert!(normalize_path("").is_err());
+        assert!(normalize_path("/").is_err());
+        assert!(normalize_path("../etc/passwd").is_err());
+        assert!(normalize_path("iso/./live.iso").is_err());
+    }
+
+    #[test]
+    fn test_generic_from_path() {
+        let file = GenericFile::from_path(
+            "test/data/subatomic-hello_1.0.2-1_all.deb",
+            "/debs/hello.deb",
+            None,
+            "foobar",
+        )
+        .unwrap();
+        assert_eq!(file.path, "debs/hello.deb");
+        assert_eq!(file.file_name(), "hello.deb");
+        assert_eq!(file.content_type, DEFAULT_CONTENT_TYPE);
+        assert!(file.object_key.starts_with("generic/"));
+        assert!(file.object_key.ends_with("/hello.deb"));
+    }
+}
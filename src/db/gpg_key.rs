@@ -1,18 +1,122 @@
-use color_eyre::{eyre::ContextCompat, Result};
-use pgp::{types::SecretKeyTrait, ArmorOptions, Deserializable, SecretKeyParamsBuilder};
+use std::num::NonZeroU32;
+use std::sync::Arc;
+
+use color_eyre::{
+    eyre::{eyre, ContextCompat},
+    Result,
+};
+use pgp::{
+    crypto::{ecc_curve::ECCCurve, public_key::PublicKeyAlgorithm},
+    types::{PublicKeyTrait, SecretKeyTrait},
+    ArmorOptions, Deserializable, SecretKeyParamsBuilder,
+};
+use rpm::signature::AlgorithmType;
 use serde::{Deserialize, Serialize};
 use surrealdb::sql::{Datetime, Thing};
 
 use super::DB;
+use crate::signing::signer::{ArmoredSigner, CommandSigner, HttpSigner, Signer};
 pub const GPG_KEY_TABLE: &str = "gpg_key";
 
+/// Public key algorithm of a generated key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyAlgorithm {
+    /// RSA with a 4096 bit modulus, for older rpm versions that can't verify anything else
+    ///
+    /// External RSA keys of any size are recorded as this too.
+    #[serde(rename = "rsa-4096")]
+    Rsa4096,
+    #[default]
+    #[serde(rename = "ed25519")]
+    Ed25519,
+    /// ECDSA over NIST P-256
+    #[serde(rename = "ecdsa")]
+    Ecdsa,
+}
+
+impl KeyAlgorithm {
+    fn key_type(self) -> pgp::KeyType {
+        match self {
+            Self::Rsa4096 => pgp::KeyType::Rsa(4096),
+            // rpm only takes Ed25519 keys in the legacy EdDSA format of v4 keys
+            Self::Ed25519 => pgp::KeyType::EdDSALegacy,
+            Self::Ecdsa => pgp::KeyType::ECDSA(ECCCurve::P256),
+        }
+    }
+
+    /// Algorithm of an existing key, for keys whose secret key is kept outside of Subatomic
+    fn of(key: &pgp::SignedPublicKey) -> Result<Self> {
+        match key.algorithm() {
+            PublicKeyAlgorithm::RSA | PublicKeyAlgorithm::RSASign => Ok(Self::Rsa4096),
+            PublicKeyAlgorithm::EdDSALegacy | PublicKeyAlgorithm::Ed25519 => Ok(Self::Ed25519),
+            PublicKeyAlgorithm::ECDSA => Ok(Self::Ecdsa),
+            algorithm => Err(eyre!("unsupported signing key algorithm {algorithm:?}")),
+        }
+    }
+
+    /// Signature algorithm for rpm headers, for keys whose secret key rpm can't see
+    fn rpm_algorithm(self) -> Result<AlgorithmType> {
+        match self {
+            Self::Rsa4096 => Ok(AlgorithmType::RSA),
+            Self::Ed25519 => Ok(AlgorithmType::EdDSA),
+            Self::Ecdsa => Err(eyre!("ECDSA keys can only sign packages from the database")),
+        }
+    }
+}
+
+/// Where a key's secret key lives, and so how signatures are made with it
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SignerBackend {
+    /// Armored in the database, with the key
+    #[default]
+    Database,
+    /// External signing service, see [`HttpSigner`]
+    Http {
+        url: String,
+        /// Bearer token to authenticate to the service with
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Local command, i.e. for a PKCS#11 token, see [`CommandSigner`]
+    Command {
+        /// Name of the command in the server's `SIGNING_COMMANDS`
+        command: String,
+    },
+}
+
+impl SignerBackend {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Database => "database",
+            Self::Http { .. } => "http",
+            Self::Command { .. } => "command",
+        }
+    }
+}
+
+/// How to generate a new key, see [`GpgKey::new`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyOptions {
+    #[serde(default)]
+    pub algorithm: KeyAlgorithm,
+    /// Days until the key expires, never if unset
+    #[serde(default)]
+    pub expires_in_days: Option<NonZeroU32>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct GpgKeyRef {
     pub id: String,
     pub user_id: String,
     pub description: Option<String>,
     pub public_key: String,
+    pub algorithm: KeyAlgorithm,
+    /// Where the secret key lives, see [`SignerBackend`]
+    pub backend: String,
+    pub allowed_tags: Vec<String>,
     pub created_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 /// When querying, we should return a GPGKeyRef instead for security reasons
@@ -21,11 +125,24 @@ pub struct GpgKey {
     pub id: Thing,
     pub description: Option<String>,
     pub user_id: String,
-    /// Armored secret key
-    pub secret_key: String,
+    /// Armored secret key, unset for keys with an external [`SignerBackend`]
+    #[serde(default)]
+    pub secret_key: Option<String>,
     /// Armored public key
     pub public_key: String,
+    #[serde(default)]
+    pub algorithm: KeyAlgorithm,
+    #[serde(default)]
+    pub backend: SignerBackend,
+    /// Tags allowed to be signed with the key, any tag if empty
+    #[serde(default)]
+    pub allowed_tags: Vec<String>,
+    /// Namespace the key was created in, only visible from its scope, see [`crate::namespace`]
+    #[serde(default)]
+    pub namespace: Option<String>,
     pub created_at: surrealdb::sql::Datetime,
+    #[serde(default)]
+    pub expires_at: Option<surrealdb::sql::Datetime>,
 }
 
 impl From<&GpgKey> for GpgKeyRef {
@@ -35,19 +152,33 @@ impl From<&GpgKey> for GpgKeyRef {
             user_id: key.user_id.clone(),
             description: key.description.clone(),
             public_key: key.public_key.clone(),
+            algorithm: key.algorithm,
+            backend: key.backend.kind().to_owned(),
+            allowed_tags: key.allowed_tags.clone(),
             created_at: key.created_at.to_utc(),
+            expires_at: key.expires_at.as_ref().map(|at| at.to_utc()),
         }
     }
 }
 
 impl GpgKey {
     #[tracing::instrument]
-    pub fn new(id: &str, description: Option<String>, user_id: &str) -> Result<Self> {
+    pub fn new(
+        id: &str,
+        description: Option<String>,
+        user_id: &str,
+        options: &KeyOptions,
+    ) -> Result<Self> {
+        let created_at = chrono::Utc::now();
+        let expiration = options
+            .expires_in_days
+            .map(|days| std::time::Duration::from_secs(u64::from(days.get()) * 86_400));
         let secret_key = SecretKeyParamsBuilder::default()
             .can_certify(false)
-            .key_type(pgp::KeyType::Ed25519)
+            .key_type(options.algorithm.key_type())
             .can_sign(true)
             .primary_user_id(user_id.to_owned())
+            .expiration(expiration)
             .build()?;
 
         let mut thread_rng = rand::thread_rng();
@@ -65,15 +196,66 @@ impl GpgKey {
             id: Thing::from((GPG_KEY_TABLE, id)),
             description,
             user_id: user_id.to_owned(),
-            secret_key: secret_key_armored,
+            secret_key: Some(secret_key_armored),
             public_key: public_key_armored,
+            algorithm: options.algorithm,
+            backend: SignerBackend::Database,
+            allowed_tags: Vec::new(),
+            namespace: crate::namespace::current(),
+            created_at: Datetime::from(created_at),
+            expires_at: options
+                .expires_in_days
+                .map(|days| Datetime::from(created_at + chrono::Duration::days(days.get().into()))),
+        })
+    }
+
+    /// Generate a key like [`GpgKey::new`] on the blocking thread pool
+    ///
+    /// RSA keys take seconds to generate, which would stall every other request on the runtime.
+    pub async fn generate(
+        id: &str,
+        description: Option<String>,
+        user_id: &str,
+        options: &KeyOptions,
+    ) -> Result<Self> {
+        let (id, user_id, options) = (id.to_owned(), user_id.to_owned(), options.clone());
+        tokio::task::spawn_blocking(move || Self::new(&id, description, &user_id, &options)).await?
+    }
+
+    /// Register a key whose secret key is kept outside of Subatomic, by its armored public key
+    pub fn external(
+        id: &str,
+        description: Option<String>,
+        user_id: &str,
+        public_key: String,
+        backend: SignerBackend,
+    ) -> Result<Self> {
+        if backend == SignerBackend::Database {
+            return Err(eyre!("external keys need an external signer backend"));
+        }
+        // make sure the public key can be served as-is
+        let (parsed, _headers) = pgp::SignedPublicKey::from_string(&public_key)?;
+        let algorithm = KeyAlgorithm::of(&parsed)?;
+
+        Ok(GpgKey {
+            id: Thing::from((GPG_KEY_TABLE, id)),
+            description,
+            user_id: user_id.to_owned(),
+            secret_key: None,
+            public_key,
+            algorithm,
+            backend,
+            allowed_tags: Vec::new(),
+            namespace: crate::namespace::current(),
             created_at: Datetime::default(),
+            expires_at: None,
         })
     }
 
     #[tracing::instrument]
     pub fn secret_key(&self) -> Result<pgp::SignedSecretKey> {
-        let (key, _headers) = pgp::SignedSecretKey::from_string(&self.secret_key)?;
+        let secret_key = self.secret_key.as_deref().context("key has no stored secret key")?;
+        let (key, _headers) = pgp::SignedSecretKey::from_string(secret_key)?;
         Ok(key)
     }
 
@@ -83,6 +265,55 @@ impl GpgKey {
         Ok(key)
     }
     
+    /// Whether a tag may be signed with this key, see [`GpgKey::allowed_tags`]
+    pub fn allows(&self, tag: &str) -> bool {
+        self.allowed_tags.is_empty() || self.allowed_tags.iter().any(|t| t == tag)
+    }
+
+    /// Signer making signatures with the key's secret key, wherever it lives
+    pub fn signer(&self) -> Result<Arc<dyn Signer>> {
+        let signer: Arc<dyn Signer> = match &self.backend {
+            SignerBackend::Database => {
+                let secret_key = self
+                    .secret_key
+                    .as_deref()
+                    .context("key has no stored secret key")?;
+                Arc::new(ArmoredSigner::new(secret_key)?)
+            }
+            SignerBackend::Http { url, token } => Arc::new(HttpSigner::new(
+                url,
+                token.clone(),
+                self.algorithm.rpm_algorithm()?,
+            )),
+            SignerBackend::Command { command } => {
+                let command = crate::config::CONFIG
+                    .get()
+                    .and_then(|config| config.signing_command(command))
+                    .ok_or_else(|| eyre!("signing command {command} is not configured"))?;
+                Arc::new(CommandSigner::new(
+                    &command.program,
+                    command.args.clone(),
+                    self.algorithm.rpm_algorithm()?,
+                ))
+            }
+        };
+        Ok(signer)
+    }
+
+    /// Create an armored detached signature of some data, i.e `repomd.xml.asc`
+    #[tracing::instrument(skip(data))]
+    pub async fn sign_detached(&self, data: &[u8]) -> Result<String> {
+        let signature = self.signer()?.sign(data.to_vec()).await?;
+        let signature = pgp::StandaloneSignature::from_bytes(signature.as_slice())?;
+        Ok(signature.to_armored_string(ArmorOptions::default())?)
+    }
+
+    /// Create a binary detached signature of some data, i.e. pacman's `.sig` files
+    #[tracing::instrument(skip(data))]
+    pub async fn sign_detached_binary(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.signer()?.sign(data.to_vec()).await
+    }
+
     #[tracing::instrument]
     pub async fn save(&self) -> Result<Self> {
         let query = DB
@@ -96,9 +327,19 @@ impl GpgKey {
     
     #[tracing::instrument]
     pub async fn get(id: &str) -> Result<Option<Self>> {
-        Ok(DB.select((GPG_KEY_TABLE, id)).await?)
+        let key: Option<Self> = DB
+            .retry(|| async move { DB.select((GPG_KEY_TABLE, id)).await })
+            .await?;
+        Ok(key.filter(|key| crate::namespace::visible(key.namespace.as_deref())))
     }
     
+    /// Whether a key exists in any namespace, key IDs are unique across namespaces
+    #[tracing::instrument]
+    pub async fn exists(id: &str) -> Result<bool> {
+        let key: Option<Self> = DB.retry(|| DB.select((GPG_KEY_TABLE, id))).await?;
+        Ok(key.is_some())
+    }
+
     #[tracing::instrument]
     pub async fn delete(&self) -> Result<()> {
         DB
@@ -109,7 +350,11 @@ impl GpgKey {
     
     #[tracing::instrument]
     pub async fn get_all() -> Result<Vec<Self>> {
-        Ok(DB.select(GPG_KEY_TABLE).await?)
+        let keys: Vec<Self> = DB.retry(|| DB.select(GPG_KEY_TABLE)).await?;
+        Ok(keys
+            .into_iter()
+            .filter(|key| crate::namespace::visible(key.namespace.as_deref()))
+            .collect())
     }
 }
 
@@ -119,11 +364,84 @@ mod tests {
     // use spectral::prelude::*;
     #[test]
     fn test_new_gpg_key() {
-        let key = GpgKey::new("test", None, "test").unwrap();
+        let key = GpgKey::new("test", None, "test", &KeyOptions::default()).unwrap();
         println!("{:?}", key);
 
         let key_ref = GpgKeyRef::from(&key);
 
         println!("{:?}", key_ref);
     }
+
+    #[tokio::test]
+    async fn test_sign_detached() {
+        let key = GpgKey::new("test", None, "test", &KeyOptions::default()).unwrap();
+        let signature = key.sign_detached(b"<repomd/>").await.unwrap();
+        assert!(signature.starts_with("-----BEGIN PGP SIGNATURE-----"));
+    }
+
+    #[test]
+    fn test_key_options_expiry() {
+        let options: KeyOptions = serde_json::from_str(r#"{"expires_in_days": 30}"#).unwrap();
+        assert_eq!(options.expires_in_days, NonZeroU32::new(30));
+        assert!(serde_json::from_str::<KeyOptions>(r#"{"expires_in_days": 0}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_key_algorithms() {
+        for algorithm in [KeyAlgorithm::Rsa4096, KeyAlgorithm::Ecdsa] {
+            let options = KeyOptions {
+                algorithm,
+                expires_in_days: NonZeroU32::new(365),
+            };
+            let key = GpgKey::new("test", None, "test", &options).unwrap();
+            key.public_key().unwrap();
+            assert_eq!(key.algorithm, algorithm);
+            assert!(key.expires_at.is_some());
+            key.sign_detached(b"<repomd/>").await.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_allowed_tags() {
+        let mut key = GpgKey::new("test", None, "test", &KeyOptions::default()).unwrap();
+        assert!(key.allows("nightly"));
+
+        key.allowed_tags = vec!["release".to_owned()];
+        assert!(key.allows("release"));
+        assert!(!key.allows("nightly"));
+    }
+
+    #[tokio::test]
+    async fn test_external_key() {
+        let options = KeyOptions {
+            algorithm: KeyAlgorithm::Ecdsa,
+            ..Default::default()
+        };
+        let generated = GpgKey::new("test", None, "test", &options).unwrap();
+        // commands are only looked up in the server config
+        let backend = SignerBackend::Command {
+            command: "unconfigured".to_owned(),
+        };
+        let key = GpgKey::external(
+            "external",
+            None,
+            "test",
+            generated.public_key.clone(),
+            backend,
+        )
+        .unwrap();
+        assert!(key.secret_key.is_none());
+        assert_eq!(key.algorithm, KeyAlgorithm::Ecdsa);
+        assert_eq!(GpgKeyRef::from(&key).backend, "command");
+        assert!(key.sign_detached(b"<repomd/>").await.is_err());
+
+        assert!(GpgKey::external(
+            "external",
+            None,
+            "test",
+            generated.public_key,
+            SignerBackend::Database,
+        )
+        .is_err());
+    }
 }
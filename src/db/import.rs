@@ -0,0 +1,82 @@
+//! Files handled by bulk imports, so an interrupted import can resume where it stopped
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use surrealdb::{sql::Thing, RecordId};
+
+use super::{tag::TAG_TABLE, DB};
+
+pub const IMPORT_TABLE: &str = "import_entry";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportEntry {
+    /// Keyed by a digest of the tag and path, see [`ImportEntry::key`]
+    pub id: Thing,
+    pub tag: RecordId,
+    /// Absolute path of the imported file
+    pub path: String,
+    pub size: u64,
+    /// Modification time of the file when it was imported, in seconds since the epoch
+    pub modified: i64,
+    /// Package the file was imported as
+    pub rpm: String,
+    pub timestamp: surrealdb::sql::Datetime,
+}
+
+impl ImportEntry {
+    pub fn new(tag: &str, path: &str, size: u64, modified: i64, rpm: String) -> Self {
+        Self {
+            id: Thing::from((IMPORT_TABLE, Self::key(tag, path).as_str())),
+            tag: RecordId::from_table_key(TAG_TABLE, tag),
+            path: path.to_owned(),
+            size,
+            modified,
+            rpm,
+            timestamp: chrono::Utc::now().into(),
+        }
+    }
+
+    /// Record key of a file imported into a tag
+    pub fn key(tag: &str, path: &str) -> String {
+        hex::encode(Sha256::digest(format!("{tag}\0{path}")))
+    }
+
+    pub async fn get(tag: &str, path: &str) -> Result<Option<Self>> {
+        let key = Self::key(tag, path);
+        let a: Option<Self> = DB
+            .retry(|| {
+                let key = key.clone();
+                async move { DB.select((IMPORT_TABLE, key)).await }
+            })
+            .await?;
+        Ok(a)
+    }
+
+    /// Whether the file is unchanged since it was imported
+    pub fn matches(&self, size: u64, modified: i64) -> bool {
+        self.size == size && self.modified == modified
+    }
+
+    pub async fn save(&self) -> Result<Self> {
+        let saved: Option<Self> = DB
+            .upsert((IMPORT_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+        saved.ok_or_else(|| color_eyre::eyre::eyre!("nothing returned from upsert"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_entry_key() {
+        let key = ImportEntry::key("foobar", "/srv/repo/a.rpm");
+        assert_eq!(key.len(), 64);
+        assert_ne!(key, ImportEntry::key("other", "/srv/repo/a.rpm"));
+        let entry = ImportEntry::new("foobar", "/srv/repo/a.rpm", 10, 20, "rpm".to_owned());
+        assert!(entry.matches(10, 20));
+        assert!(!entry.matches(10, 21));
+    }
+}
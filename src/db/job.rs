@@ -0,0 +1,177 @@
+//! Background jobs, i.e. assemblies started from the API
+use std::future::Future;
+
+use color_eyre::{eyre::ContextCompat, Result};
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use surrealdb::{
+    sql::{Datetime, Thing},
+    RecordId,
+};
+
+use super::{tag::TAG_TABLE, DB};
+
+pub const JOB_TABLE: &str = "job";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Success,
+    Failure,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: Thing,
+    /// What the job does, i.e `assemble`
+    pub kind: String,
+    /// Tag the job works on, if any
+    pub tag: Option<RecordId>,
+    pub status: JobStatus,
+    /// Error output of a failed job
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Output of a successful job, i.e. per-package results of a signing job
+    #[serde(default)]
+    pub result: Option<serde_json::Value>,
+    /// Progress of a running job, for jobs reporting it, see [`Job::set_progress`]
+    #[serde(default)]
+    pub progress: Option<serde_json::Value>,
+    /// Namespace of the request starting the job, only visible from its scope, see
+    /// [`crate::namespace`]
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Instance running the job, see [`crate::leader::INSTANCE_ID`]
+    #[serde(default)]
+    pub instance: Option<String>,
+    pub created_at: Datetime,
+    #[serde(default)]
+    pub started_at: Option<Datetime>,
+    #[serde(default)]
+    pub finished_at: Option<Datetime>,
+}
+
+impl Job {
+    pub fn new(kind: &str, tag: Option<&str>) -> Self {
+        Self {
+            id: Thing::from((JOB_TABLE, surrealdb::sql::Id::ulid())),
+            kind: kind.to_owned(),
+            tag: tag.map(|t| RecordId::from_table_key(TAG_TABLE, t)),
+            status: JobStatus::Queued,
+            error: None,
+            result: None,
+            progress: None,
+            namespace: crate::namespace::current(),
+            instance: Some(crate::leader::INSTANCE_ID.clone()),
+            created_at: Datetime::default(),
+            started_at: None,
+            finished_at: None,
+        }
+    }
+
+    pub async fn save(&self) -> Result<Self> {
+        let query = DB
+            .upsert((JOB_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+
+        query.context("nothing returned from insert")
+    }
+
+    /// Fetch a job, if it was started from the current namespace scope
+    pub async fn get(id: &str) -> Result<Option<Self>> {
+        let job: Option<Self> = DB
+            .retry(|| async move { DB.select((JOB_TABLE, id)).await })
+            .await?;
+        Ok(job.filter(|job| crate::namespace::visible(job.namespace.as_deref())))
+    }
+
+    /// Record the progress of a running job
+    pub async fn set_progress(id: &Thing, progress: impl Serialize) -> Result<()> {
+        DB.query("UPDATE $id SET progress = $progress;")
+            .bind(("id", RecordId::from_table_key(JOB_TABLE, id.id.to_raw())))
+            .bind(("progress", serde_json::to_value(progress)?))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    /// Mark the unfinished jobs of instances that went away failed, returning how many
+    ///
+    /// Their tasks went away with the instance, so they'd stay queued or running forever.
+    pub async fn fail_interrupted() -> Result<usize> {
+        let unfinished: Vec<Self> = DB
+            .retry(|| DB.query("SELECT * FROM job WHERE status IN ['queued', 'running'];"))
+            .await?
+            .take(0)?;
+
+        let mut interrupted = Vec::new();
+        for job in unfinished {
+            let alive = match &job.instance {
+                Some(instance) if *instance == *crate::leader::INSTANCE_ID => true,
+                Some(instance) => crate::leader::is_alive(instance).await?,
+                None => false,
+            };
+            if !alive {
+                interrupted.push(RecordId::from_table_key(JOB_TABLE, job.id.id.to_raw()));
+            }
+        }
+        if interrupted.is_empty() {
+            return Ok(0);
+        }
+
+        tracing::warn!(jobs = ?interrupted, "marking interrupted jobs failed");
+        DB.query(
+            "UPDATE job SET status = 'failure', error = 'interrupted by a restart', \
+             finished_at = time::now() WHERE id IN $ids;",
+        )
+        .bind(("ids", interrupted.clone()))
+        .await?
+        .check()?;
+        Ok(interrupted.len())
+    }
+
+    /// Run the job's work in the background, recording its status and output as it goes
+    ///
+    /// The work runs in the current span, so its logs carry the ID of the request starting it,
+    /// and in the namespace scope of that request.
+    pub fn spawn<F, T>(self, work: F) -> tokio::task::JoinHandle<Result<Self>>
+    where
+        F: Future<Output = Result<T>> + Send + 'static,
+        T: Serialize + Send + 'static,
+    {
+        let span = tracing::Span::current();
+        let scope = crate::namespace::current_scope();
+        let task = async move {
+            let mut job = self;
+            job.status = JobStatus::Running;
+            job.started_at = Some(Datetime::default());
+            let mut job = job.save().await?;
+
+            let res = work.await;
+            if let Err(e) = &res {
+                tracing::error!(job = ?job.id, ?e, "job failed");
+            }
+            match res {
+                Ok(output) => {
+                    job.status = JobStatus::Success;
+                    job.result = Some(serde_json::to_value(output)?).filter(|v| !v.is_null());
+                }
+                Err(e) => {
+                    job.status = JobStatus::Failure;
+                    job.error = Some(format!("{e:#}"));
+                }
+            }
+            job.finished_at = Some(Datetime::default());
+            job.save().await
+        };
+        match scope {
+            Some(namespace) => {
+                tokio::spawn(crate::namespace::scope(namespace, task).instrument(span))
+            }
+            None => tokio::spawn(task.instrument(span)),
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Thing;
+
+use super::DB;
+
+pub const LEASE_TABLE: &str = "lease";
+
+/// A time-limited claim on a named role, held by a single instance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    pub id: Thing,
+    /// ID of the instance holding the lease
+    pub holder: String,
+    pub expires_at: surrealdb::sql::Datetime,
+}
+
+impl Lease {
+    /// Try to acquire or renew a lease
+    ///
+    /// Succeeds if the lease is free, expired, or already held by `holder`,
+    /// in which case the lease is extended to `ttl` from now.
+    pub async fn try_acquire(name: &str, holder: &str, ttl: Duration) -> Result<bool> {
+        let lease: Option<Self> = DB
+            .query(
+                "UPSERT type::thing($table, $name) \
+                 SET holder = $holder, expires_at = time::now() + duration::from::secs($ttl) \
+                 WHERE holder = NONE OR holder = $holder OR expires_at < time::now() \
+                 RETURN AFTER;",
+            )
+            .bind(("table", LEASE_TABLE))
+            .bind(("name", name.to_owned()))
+            .bind(("holder", holder.to_owned()))
+            .bind(("ttl", ttl.as_secs()))
+            .await?
+            .take(0)?;
+
+        Ok(lease.is_some_and(|l| l.holder == holder))
+    }
+
+    /// Give up a lease if it is held by `holder`
+    pub async fn release(name: &str, holder: &str) -> Result<()> {
+        DB.query("DELETE type::thing($table, $name) WHERE holder = $holder;")
+            .bind(("table", LEASE_TABLE))
+            .bind(("name", name.to_owned()))
+            .bind(("holder", holder.to_owned()))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get(name: &str) -> Result<Option<Self>> {
+        Ok(DB.retry(|| DB.select((LEASE_TABLE, name))).await?)
+    }
+}
@@ -0,0 +1,111 @@
+//! Membership of packages in tags other than the one they were uploaded to
+//!
+//! A package belongs to the tag it was uploaded to through [`Rpm::tag`], and can be shared into
+//! any number of other tags without storing it again. Each of those tags tracks the package's
+//! availability separately.
+use color_eyre::{eyre::ContextCompat, Result};
+use serde::{Deserialize, Serialize};
+use surrealdb::{sql::Thing, RecordId};
+
+use super::{
+    rpm::{Rpm, RPM_TABLE},
+    tag::TAG_TABLE,
+    DB,
+};
+
+pub const MEMBERSHIP_TABLE: &str = "tag_member";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TagMembership {
+    pub id: Thing,
+    pub rpm: RecordId,
+    pub tag: RecordId,
+    /// Whether the package is included when the tag is assembled, like [`Rpm`]'s own availability
+    #[serde(default)]
+    pub available: bool,
+    pub created_at: surrealdb::sql::Datetime,
+}
+
+impl TagMembership {
+    /// Record key of a package's membership in a tag, there's at most one per package and tag
+    fn key(rpm: &Rpm, tag: &str) -> String {
+        format!("{}_{tag}", rpm.id.id.to_raw())
+    }
+
+    pub fn new(rpm: &Rpm, tag: &str) -> Self {
+        Self {
+            id: Thing::from((MEMBERSHIP_TABLE, Self::key(rpm, tag).as_str())),
+            rpm: RecordId::from_table_key(RPM_TABLE, rpm.id.id.to_raw()),
+            tag: RecordId::from_table_key(TAG_TABLE, tag),
+            available: false,
+            created_at: surrealdb::sql::Datetime::default(),
+        }
+    }
+
+    pub async fn save(&self) -> Result<Self> {
+        let query = DB
+            .upsert((MEMBERSHIP_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+
+        query.context("nothing returned from insert")
+    }
+
+    pub async fn get(rpm: &Rpm, tag: &str) -> Result<Option<Self>> {
+        Ok(DB
+            .retry(|| DB.select((MEMBERSHIP_TABLE, Self::key(rpm, tag))))
+            .await?)
+    }
+
+    /// Fetch every tag a package was shared into
+    pub async fn get_by_rpm(rpm: &Rpm) -> Result<Vec<Self>> {
+        let memberships: Vec<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM tag_member WHERE rpm = $rpm ORDER BY created_at;")
+                    .bind((
+                        "rpm",
+                        RecordId::from_table_key(RPM_TABLE, rpm.id.id.to_raw()),
+                    ))
+            })
+            .await?
+            .take(0)?;
+
+        Ok(memberships)
+    }
+
+    /// Set whether the package is available in this tag
+    pub async fn set_available(&self, available: bool) -> Result<()> {
+        DB.query("UPDATE $id SET available = $available;")
+            .bind((
+                "id",
+                RecordId::from_table_key(MEMBERSHIP_TABLE, self.id.id.to_raw()),
+            ))
+            .bind(("available", available))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    pub async fn delete(&self) -> Result<()> {
+        let _: Option<Self> = DB.delete((MEMBERSHIP_TABLE, self.id.id.to_raw())).await?;
+        Ok(())
+    }
+
+    /// Remove a package from every tag it was shared into, i.e. when it's deleted
+    pub async fn delete_by_rpm(rpm: &Rpm) -> Result<()> {
+        DB.query("DELETE tag_member WHERE rpm = $rpm;")
+            .bind(("rpm", RecordId::from_table_key(RPM_TABLE, rpm.id.id.to_raw())))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    /// Remove every package shared into a tag, i.e. when the tag is deleted
+    pub async fn delete_by_tag(tag: &str) -> Result<()> {
+        DB.query("DELETE tag_member WHERE tag = $tag;")
+            .bind(("tag", RecordId::from_table_key(TAG_TABLE, tag)))
+            .await?
+            .check()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+//! Versioned schema migrations
+//!
+//! Each migration is a `.surql` file in `schema/`, applied once and in order on startup.
+//! Applied migrations are recorded in the [`MIGRATION_TABLE`], so only new ones run on later boots.
+//! Migrations are up-only: to change the schema, add a new migration instead of editing an applied one.
+//! Replicas starting at once take turns through a lease, so each migration is applied by one of them.
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+
+use super::{lease::Lease, DB};
+use crate::leader::INSTANCE_ID;
+
+pub const MIGRATION_TABLE: &str = "schema_migration";
+/// Lease held while migrating
+const MIGRATION_LEASE: &str = "migrations";
+/// How long the migration lease is held, so another instance takes over if the holder dies mid-migration
+const MIGRATION_LEASE_TTL: Duration = Duration::from_secs(600);
+/// Time between attempts to take the migration lease from another instance
+const MIGRATION_LEASE_RETRY: Duration = Duration::from_secs(1);
+
+/// A schema change, applied once
+pub struct Migration {
+    /// Position of the migration, migrations are applied in ascending order
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+    /// Log failing statements instead of failing the migration, and don't use a transaction
+    ///
+    /// Only for the schemas that predate versioning, which were re-applied on every boot
+    /// without checking errors.
+    pub lenient: bool,
+}
+
+macro_rules! migration {
+    ($version:literal, $file:literal) => {
+        migration!($version, $file, false)
+    };
+    ($version:literal, $file:literal, lenient) => {
+        migration!($version, $file, true)
+    };
+    ($version:literal, $file:literal, $lenient:literal) => {
+        Migration {
+            version: $version,
+            name: $file,
+            sql: include_str!(concat!("schema/", $file, ".surql")),
+            lenient: $lenient,
+        }
+    };
+}
+
+/// Every migration, in order. New migrations go at the end, with the next version
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_rpm", lenient),
+    migration!(2, "0002_tag", lenient),
+    migration!(3, "0003_available_pkgs", lenient),
+    migration!(4, "0004_event_log", lenient),
+    migration!(5, "0005_event_log_index"),
+    migration!(6, "0006_webhook"),
+    migration!(7, "0007_deb_package"),
+    migration!(8, "0008_pacman_package"),
+    migration!(9, "0009_generic_file"),
+    migration!(10, "0010_oci"),
+    migration!(11, "0011_ostree_commit"),
+    migration!(12, "0012_import_entry"),
+    migration!(13, "0013_namespace"),
+    migration!(14, "0014_object_ref"),
+    migration!(15, "0015_webhook_delivery"),
+    migration!(16, "0016_oci_published"),
+    migration!(17, "0017_event_log_ttl"),
+];
+
+/// Latest applied migration version, 0 for a fresh database
+pub async fn current_version() -> Result<u32> {
+    let version: Option<u32> = DB
+        .retry(|| {
+            DB.query("SELECT VALUE version FROM schema_migration ORDER BY version DESC LIMIT 1;")
+        })
+        .await?
+        .take(0)?;
+    Ok(version.unwrap_or_default())
+}
+
+/// Version of the latest migration of this release
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// Forget the migrations newer than `version`, so [`migrate`] applies them again
+///
+/// For records restored from a backup taken at `version`, as migrations can change records too.
+/// Applied migrations only define what doesn't exist yet or overwrite definitions, so applying
+/// them again is safe.
+pub async fn rewind(version: u32) -> Result<()> {
+    DB.query("DELETE type::table($table) WHERE version > $version;")
+        .bind(("table", MIGRATION_TABLE))
+        .bind(("version", version))
+        .await?
+        .check()?;
+    Ok(())
+}
+
+/// Apply every migration newer than the database's current version, returning how many were applied
+///
+/// Each migration runs in a transaction together with its record, so a failed migration
+/// leaves no trace and is retried on the next boot. Waits for other instances migrating the
+/// same database first, and then only applies what they didn't.
+pub async fn migrate() -> Result<usize> {
+    let latest = latest_version();
+    if current_version().await? == latest {
+        return Ok(0);
+    }
+
+    while !Lease::try_acquire(MIGRATION_LEASE, &INSTANCE_ID, MIGRATION_LEASE_TTL).await? {
+        tracing::debug!("waiting for another instance to finish migrating");
+        tokio::time::sleep(MIGRATION_LEASE_RETRY).await;
+    }
+    let res = apply_pending(latest).await;
+    if let Err(e) = Lease::release(MIGRATION_LEASE, &INSTANCE_ID).await {
+        tracing::warn!(?e, "failed to release migration lease");
+    }
+    res
+}
+
+async fn apply_pending(latest: u32) -> Result<usize> {
+    // read while holding the lease, another instance may have migrated in the meantime
+    let current = current_version().await?;
+    if current > latest {
+        return Err(eyre!(
+            "database schema is at version {current}, newer than this release's {latest}"
+        ));
+    }
+
+    let pending = MIGRATIONS.iter().filter(|m| m.version > current);
+    let mut applied = 0;
+    for migration in pending {
+        tracing::info!(version = migration.version, name = migration.name, "applying migration");
+        if migration.lenient {
+            apply_lenient(migration).await?;
+            applied += 1;
+            continue;
+        }
+        DB.query(format!(
+            "BEGIN TRANSACTION;\n{}\n;\nCREATE type::thing($table, $version) \
+             SET version = $version, name = $name, applied_at = time::now();\nCOMMIT TRANSACTION;",
+            migration.sql
+        ))
+        .bind(("table", MIGRATION_TABLE))
+        .bind(("version", migration.version))
+        .bind(("name", migration.name))
+        .await?
+        .check()
+        .map_err(|e| eyre!("migration {} failed: {e}", migration.name))?;
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+async fn apply_lenient(migration: &Migration) -> Result<()> {
+    let errors = DB.query(migration.sql).await?.take_errors();
+    for (statement, e) in errors {
+        tracing::warn!(name = migration.name, statement, ?e, "migration statement failed");
+    }
+    DB.query(
+        "CREATE type::thing($table, $version) \
+         SET version = $version, name = $name, applied_at = time::now();",
+    )
+    .bind(("table", MIGRATION_TABLE))
+    .bind(("version", migration.version))
+    .bind(("name", migration.name))
+    .await?
+    .check()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_ordered() {
+        assert!(MIGRATIONS.windows(2).all(|w| w[0].version < w[1].version));
+        assert!(MIGRATIONS
+            .iter()
+            .all(|m| m.name.starts_with(&format!("{:04}_", m.version))));
+    }
+}
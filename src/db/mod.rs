@@ -1,22 +1,72 @@
 pub mod rpm;
 pub mod tag;
+pub mod advisory;
+pub mod build;
+pub mod deb;
+pub mod event_log;
+pub mod generic;
 pub mod gpg_key;
-use std::sync::LazyLock;
+pub mod import;
+pub mod job;
+pub mod lease;
+pub mod membership;
+pub mod migrations;
+pub mod namespace;
+pub mod notification;
+pub mod oci;
+pub mod ostree;
+pub mod pacman;
+pub mod permission;
+pub mod token;
+pub mod upload;
+pub mod webhook;
+use std::future::IntoFuture;
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    LazyLock,
+};
+use std::time::Duration;
 
+use color_eyre::eyre::{eyre, WrapErr};
 use surrealdb::{
-    engine::remote::ws::{Client, Ws},
-    opt::auth::Root,
-    Surreal,
+    engine::any::Any,
+    opt::auth::{Database, Namespace, Root},
+    RecordId, Surreal,
 };
 
+use crate::config::{Config, DbAuthLevel, DbEngine};
+
+/// SurrealDB's default credentials, see [`Config::surreal_insecure_credentials`]
+const DEFAULT_CREDENTIALS: (&str, &str) = ("root", "root");
+
+/// Interval between database health checks, see [`spawn_health_task`]
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// First delay between retries while the database is unreachable, doubled after each attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Attempts of an operation retried with [`SurrealClient::retry`]
+const RETRY_ATTEMPTS: u32 = 5;
+/// Messages of errors meaning the server doesn't know our session anymore, i.e. after it restarted
+const SESSION_ERRORS: &[&str] = &[
+    "Specify a namespace to use",
+    "Specify a database to use",
+    "There was a problem with authentication",
+    "The session has expired",
+    "The token has expired",
+    "Not enough permissions",
+];
+
 pub static DB: SurrealClient = SurrealClient::new();
 
 pub struct SurrealClient {
-    pub db: LazyLock<Surreal<Client>>,
+    pub db: LazyLock<Surreal<Any>>,
+    healthy: AtomicBool,
+    /// Set once connected and migrated, see [`connect_db`]
+    connected: AtomicBool,
 }
 
 impl std::ops::Deref for SurrealClient {
-    type Target = Surreal<Client>;
+    type Target = Surreal<Any>;
     fn deref(&self) -> &Self::Target {
         &self.db
     }
@@ -26,43 +76,317 @@ impl SurrealClient {
     const fn new() -> Self {
         SurrealClient {
             db: LazyLock::new(Surreal::init),
+            healthy: AtomicBool::new(true),
+            connected: AtomicBool::new(false),
         }
     }
 
-    pub fn get(&self) -> &Surreal<Client> {
+    pub fn get(&self) -> &Surreal<Any> {
         &DB
     }
 
+    /// Whether the database was connected to and its schema migrated
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Run an idempotent operation, i.e. a query only reading, retrying with backoff while the
+    /// database is unreachable or has forgotten our session
+    ///
+    /// The session is restored before retrying a query rejected for it. Other errors are returned
+    /// right away if the database still answers a health check, retrying wouldn't fix a failing
+    /// query. Queries are usually passed as-is, i.e. `DB.retry(|| DB.query(...).bind(...))`.
+    pub async fn retry<T, F, Q>(&self, mut op: F) -> surrealdb::Result<T>
+    where
+        F: FnMut() -> Q,
+        Q: IntoFuture<Output = surrealdb::Result<T>>,
+    {
+        let mut delay = RETRY_BASE_DELAY;
+        let mut attempt = 1;
+        loop {
+            let e = match op().await {
+                Ok(res) => return Ok(res),
+                Err(e) if attempt >= RETRY_ATTEMPTS => return Err(e),
+                Err(e) => e,
+            };
+            if is_session_error(&e) {
+                tracing::warn!(attempt, ?e, "database session lost, restoring");
+                if let Err(e) = self.restore_session().await {
+                    tracing::error!(?e, "failed to restore database session");
+                }
+            } else if self.get().health().await.is_ok() {
+                return Err(e);
+            } else {
+                tracing::warn!(attempt, ?e, "database unreachable, retrying");
+            }
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// Sign in and select the namespace and database again, i.e. after the server restarted
+    async fn restore_session(&self) -> color_eyre::Result<()> {
+        let config = crate::config::CONFIG
+            .get()
+            .ok_or_else(|| eyre!("no configuration to restore the session from"))?;
+        if config.surreal_engine == DbEngine::Remote {
+            sign_in(config).await?;
+        }
+        self.use_ns(&config.surreal_ns)
+            .use_db(&config.surreal_db)
+            .await?;
+        Ok(())
+    }
+
+    /// Whether the server still knows our session, by running a query that needs it
+    async fn check_session(&self) -> surrealdb::Result<()> {
+        self.query(format!(
+            "SELECT id FROM {} LIMIT 1;",
+            migrations::MIGRATION_TABLE
+        ))
+        .await?
+        .check()?;
+        Ok(())
+    }
+
+    /// Connect to a SurrealDB server, see [`ws_url`] for the accepted addresses
     pub async fn connect_ws(&self, addr: &str) -> color_eyre::Result<()> {
-        self.get().connect::<Ws>(addr).await?;
+        let url = ws_url(addr);
+        tracing::info!(%url, "connecting to SurrealDB");
+        self.get()
+            .connect(url.as_str())
+            .await
+            .wrap_err_with(|| format!("cannot reach SurrealDB at {url}"))?;
         Ok(())
     }
+
+    /// Open a database embedded in the process, stored at `path`
+    pub async fn connect_embedded(
+        &self,
+        engine: DbEngine,
+        path: &Path,
+    ) -> color_eyre::Result<()> {
+        if !cfg!(feature = "embedded-db") {
+            return Err(eyre!(
+                "the {engine:?} engine needs Subatomic to be built with the `embedded-db` feature"
+            ));
+        }
+        let scheme = match engine {
+            DbEngine::RocksDb => "rocksdb",
+            DbEngine::SurrealKv => "surrealkv",
+            DbEngine::Remote => return Err(eyre!("the remote engine isn't embedded")),
+        };
+        tokio::fs::create_dir_all(path).await?;
+        self.get()
+            .connect(format!("{scheme}://{}", path.display()))
+            .await?;
+        Ok(())
+    }
+}
+
+/// WebSocket URL of a SurrealDB server
+///
+/// `wss://` and `https://` addresses connect over TLS, `ws://`, `http://` and bare `host:port`
+/// addresses without.
+pub fn ws_url(addr: &str) -> String {
+    let addr = addr.trim_end_matches('/');
+    if let Some(host) = addr
+        .strip_prefix("wss://")
+        .or_else(|| addr.strip_prefix("https://"))
+    {
+        format!("wss://{host}")
+    } else {
+        let host = addr
+            .strip_prefix("ws://")
+            .or_else(|| addr.strip_prefix("http://"))
+            .unwrap_or(addr);
+        format!("ws://{host}")
+    }
+}
+
+/// Get the raw key of a record ID
+///
+/// The `Display` implementation escapes keys that aren't plain identifiers,
+/// i.e `repo_tag:⟨updates-testing⟩`, which is not what we want for names.
+pub fn record_key(id: &RecordId) -> String {
+    let key = id.key().to_string();
+    key.strip_prefix('⟨')
+        .and_then(|k| k.strip_suffix('⟩'))
+        .map(|k| k.replace("\\⟩", "⟩"))
+        .unwrap_or(key)
+}
+
+pub async fn connect_db(config: &Config) -> color_eyre::Result<()> {
+    match config.surreal_engine {
+        DbEngine::Remote => {
+            // fail on missing credentials before trying to connect
+            credentials(config)?;
+            DB.connect_ws(&config.host).await?;
+            sign_in(config).await?;
+        }
+        engine => {
+            let path = config
+                .surreal_path
+                .as_deref()
+                .ok_or_else(|| eyre!("SURREAL_PATH is required for the {engine:?} engine"))?;
+            DB.connect_embedded(engine, path).await?;
+        }
+    }
+
+    setup_db(&config.surreal_ns, &config.surreal_db).await
+}
+
+/// Sign in to the SurrealDB server with the configured credentials
+async fn sign_in(config: &Config) -> color_eyre::Result<()> {
+    let (username, password) = credentials(config)?;
+    match config.surreal_auth_level {
+        DbAuthLevel::Root => DB.signin(Root { username, password }).await?,
+        DbAuthLevel::Namespace => {
+            DB.signin(Namespace {
+                namespace: &config.surreal_ns,
+                username,
+                password,
+            })
+            .await?
+        }
+        DbAuthLevel::Database => {
+            DB.signin(Database {
+                namespace: &config.surreal_ns,
+                database: &config.surreal_db,
+                username,
+                password,
+            })
+            .await?
+        }
+    };
+    Ok(())
+}
+
+/// Whether a query failed because the server doesn't know our session, see [`SESSION_ERRORS`]
+fn is_session_error(e: &surrealdb::Error) -> bool {
+    let message = e.to_string();
+    SESSION_ERRORS
+        .iter()
+        .any(|session| message.contains(session))
 }
 
-// TODO: should use Surreal<Any>
-pub async fn connect_db(namespace: &str, db: &str) -> color_eyre::Result<()> {
-    DB.connect::<Ws>("localhost:8000").await?;
+/// Spawn the task monitoring the database connection
+///
+/// The client reconnects dropped connections by itself, but a restarted server doesn't know
+/// the session anymore. It's restored once the database answers again, or as soon as a query
+/// needing it fails, even if the restart was too quick to be noticed. While the database is
+/// unreachable, it's checked with backoff instead of every `interval`.
+pub fn spawn_health_task(interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut delay = interval;
+        loop {
+            tokio::time::sleep(delay).await;
+            let mut healthy = DB.get().health().await.is_ok();
+            let was_healthy = DB.healthy.load(Ordering::Relaxed);
+            let session_lost = healthy
+                && DB
+                    .check_session()
+                    .await
+                    .is_err_and(|e| is_session_error(&e));
+            if healthy && (!was_healthy || session_lost) {
+                tracing::info!(session_lost, "restoring database session");
+                if let Err(e) = DB.restore_session().await {
+                    tracing::error!(?e, "failed to restore database session");
+                    healthy = false;
+                }
+            } else if !healthy && was_healthy {
+                tracing::error!("database unreachable, reconnecting");
+            }
+            DB.healthy.store(healthy, Ordering::Relaxed);
 
-    DB.signin(Root {
-        username: "root",
-        password: "root",
+            delay = match (healthy, was_healthy) {
+                (true, _) => interval,
+                (false, true) => RETRY_BASE_DELAY,
+                (false, false) => (delay * 2).min(interval),
+            };
+        }
     })
-    .await?;
+}
 
-    let schemas = vec![
-        include_str!("schema/rpm.surql"),
-        include_str!("schema/tag.surql"),
-        include_str!("schema/available_pkgs.surql"),
-        include_str!("schema/event_log.surql"),
-    ];
+/// Credentials to sign in to the SurrealDB server with, refusing the defaults unless explicitly allowed
+fn credentials(config: &Config) -> color_eyre::Result<(&str, &str)> {
+    let credentials = match (&config.surreal_user, &config.surreal_pass) {
+        (Some(user), Some(pass)) => (user.as_str(), pass.as_str()),
+        (None, None) => DEFAULT_CREDENTIALS,
+        _ => return Err(eyre!("SURREAL_USER and SURREAL_PASS must be set together")),
+    };
+    if credentials == DEFAULT_CREDENTIALS {
+        if !config.surreal_insecure_credentials {
+            return Err(eyre!(
+                "refusing to sign in to SurrealDB with the default credentials, set SURREAL_USER and \
+                 SURREAL_PASS, or SURREAL_INSECURE_CREDENTIALS for local development"
+            ));
+        }
+        tracing::warn!("signing in to SurrealDB with the default credentials");
+    }
+    Ok(credentials)
+}
+
+/// Connect to a fresh in-memory database, for testing
+#[cfg(all(test, feature = "test-harness"))]
+pub async fn connect_mem(namespace: &str, db: &str) -> color_eyre::Result<()> {
+    DB.connect("mem://").await?;
+    setup_db(namespace, db).await
+}
 
+/// Select the namespace and database, and apply pending schema migrations
+async fn setup_db(namespace: &str, db: &str) -> color_eyre::Result<()> {
     DB.use_ns(namespace).use_db(db).await?;
 
-    // todo: schema migration
-    for schema in schemas {
-        DB.query(schema).await?;
+    let applied = migrations::migrate().await?;
+    if applied > 0 {
+        tracing::info!(applied, "database schema migrated");
     }
-
-    // println!("{:?}", q);
+    DB.connected.store(true, Ordering::Relaxed);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_ws_url() {
+        assert_eq!(ws_url("localhost:8000"), "ws://localhost:8000");
+        assert_eq!(ws_url("ws://db:8000/"), "ws://db:8000");
+        assert_eq!(ws_url("http://db:8000"), "ws://db:8000");
+        assert_eq!(ws_url("wss://db.example.com"), "wss://db.example.com");
+        assert_eq!(ws_url("https://db.example.com"), "wss://db.example.com");
+    }
+
+    #[test]
+    fn test_credentials() {
+        let config = |args: &[&str]| {
+            Config::parse_from(["subatomic-ng"].iter().chain(args))
+        };
+        assert!(credentials(&config(&[])).is_err());
+        assert!(credentials(&config(&["--surreal-user=root", "--surreal-pass=root"])).is_err());
+        assert!(credentials(&config(&["--surreal-user=subatomic"])).is_err());
+        assert_eq!(
+            credentials(&config(&["--surreal-insecure-credentials"])).unwrap(),
+            DEFAULT_CREDENTIALS
+        );
+        assert_eq!(
+            credentials(&config(&["--surreal-user=subatomic", "--surreal-pass=hunter2"])).unwrap(),
+            ("subatomic", "hunter2")
+        );
+    }
+
+    #[test]
+    fn test_session_error() {
+        let error =
+            |message: &str| surrealdb::Error::Api(surrealdb::error::Api::Query(message.to_owned()));
+        assert!(is_session_error(&error("Specify a namespace to use")));
+        assert!(is_session_error(&error(
+            "There was a problem with authentication"
+        )));
+        assert!(!is_session_error(&error("Parse error: unexpected token")));
+    }
+}
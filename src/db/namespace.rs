@@ -0,0 +1,96 @@
+//! Namespaces, isolating the tags and signing keys of a team, see [`crate::namespace`]
+use color_eyre::{eyre::ContextCompat, Result};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+
+use super::{rpm::Count, DB};
+
+pub const NAMESPACE_TABLE: &str = "namespace";
+/// Longest namespace name accepted
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Namespace {
+    pub id: Thing,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub created_at: Datetime,
+}
+
+impl Namespace {
+    /// Whether a name can be used for a namespace, it ends up in URLs and object keys
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.len() <= MAX_NAME_LEN
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+            && !name.starts_with('-')
+    }
+
+    pub fn new(name: String, description: Option<String>) -> Self {
+        Self {
+            id: Thing::from((NAMESPACE_TABLE, surrealdb::sql::Id::String(name.clone()))),
+            name,
+            description,
+            created_at: Datetime::default(),
+        }
+    }
+
+    pub async fn save(&self) -> Result<Self> {
+        let query = DB
+            .upsert((NAMESPACE_TABLE, self.name.as_str()))
+            .content(self.clone())
+            .await?;
+
+        query.context("nothing returned from insert")
+    }
+
+    pub async fn get(name: &str) -> Result<Option<Self>> {
+        Ok(DB.retry(|| DB.select((NAMESPACE_TABLE, name))).await?)
+    }
+
+    pub async fn get_all() -> Result<Vec<Self>> {
+        Ok(DB
+            .retry(|| DB.query("SELECT * FROM namespace ORDER BY name;"))
+            .await?
+            .take(0)?)
+    }
+
+    /// Whether no tag or signing key belongs to the namespace anymore
+    pub async fn is_empty(&self) -> Result<bool> {
+        let mut res = DB
+            .retry(|| {
+                DB.query("SELECT count() FROM repo_tag WHERE namespace = $namespace GROUP ALL;")
+                    .query("SELECT count() FROM gpg_key WHERE namespace = $namespace GROUP ALL;")
+                    .bind(("namespace", self.name.clone()))
+            })
+            .await?;
+        let tags: Option<Count> = res.take(0)?;
+        let keys: Option<Count> = res.take(1)?;
+        Ok(tags.map_or(0, |c| c.count) == 0 && keys.map_or(0, |c| c.count) == 0)
+    }
+
+    pub async fn delete(&self) -> Result<()> {
+        let _: Option<Self> = DB.delete((NAMESPACE_TABLE, self.name.as_str())).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_name() {
+        assert!(Namespace::is_valid_name("team-a"));
+        assert!(Namespace::is_valid_name("fyra2"));
+        assert!(!Namespace::is_valid_name(""));
+        assert!(!Namespace::is_valid_name("-team"));
+        assert!(!Namespace::is_valid_name("Team"));
+        assert!(!Namespace::is_valid_name("team/a"));
+        assert!(!Namespace::is_valid_name("../etc"));
+        assert!(!Namespace::is_valid_name(&"a".repeat(MAX_NAME_LEN + 1)));
+    }
+}
@@ -0,0 +1,156 @@
+use color_eyre::{eyre::ContextCompat, Result};
+use serde::{Deserialize, Serialize};
+use surrealdb::{sql::Thing, RecordId};
+
+use super::DB;
+use crate::notify::{Notification, NotificationKind};
+
+pub const EMAIL_SUBSCRIPTION_TABLE: &str = "email_subscription";
+pub const NOTIFICATION_QUEUE_TABLE: &str = "notification_queue";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestMode {
+    /// Send an email for every notification as it happens
+    Immediate,
+    /// Collect notifications and send them as one email per digest interval
+    Daily,
+}
+
+/// An email address subscribed to notifications
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmailSubscription {
+    pub id: Thing,
+    pub address: String,
+    /// Tags to receive notifications for, empty for all tags
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Notification kinds to receive, empty for all kinds
+    #[serde(default)]
+    pub events: Vec<NotificationKind>,
+    pub mode: DigestMode,
+}
+
+impl EmailSubscription {
+    pub fn new(
+        address: String,
+        tags: Vec<String>,
+        events: Vec<NotificationKind>,
+        mode: DigestMode,
+    ) -> Self {
+        Self {
+            id: Thing::from((EMAIL_SUBSCRIPTION_TABLE, surrealdb::sql::Id::ulid())),
+            address,
+            tags,
+            events,
+            mode,
+        }
+    }
+
+    /// Whether this subscription wants to receive the given notification
+    pub fn matches(&self, notification: &Notification) -> bool {
+        let tag_matches = self.tags.is_empty()
+            || notification
+                .tag
+                .as_ref()
+                .is_some_and(|t| self.tags.contains(t));
+        let kind_matches = self.events.is_empty() || self.events.contains(&notification.kind);
+
+        tag_matches && kind_matches
+    }
+
+    pub async fn save(&self) -> Result<Self> {
+        let query = DB
+            .upsert((EMAIL_SUBSCRIPTION_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+
+        query.context("nothing returned from insert")
+    }
+
+    pub async fn get(id: &str) -> Result<Option<Self>> {
+        Ok(DB
+            .retry(|| DB.select((EMAIL_SUBSCRIPTION_TABLE, id)))
+            .await?)
+    }
+
+    pub async fn get_all() -> Result<Vec<Self>> {
+        Ok(DB.retry(|| DB.select(EMAIL_SUBSCRIPTION_TABLE)).await?)
+    }
+
+    pub async fn delete(&self) -> Result<()> {
+        let _: Option<Self> = DB
+            .delete((EMAIL_SUBSCRIPTION_TABLE, self.id.id.to_raw()))
+            .await?;
+        Ok(())
+    }
+
+    /// Queue a notification to be sent with the next digest
+    pub async fn enqueue(&self, notification: &Notification) -> Result<()> {
+        let queued = QueuedNotification {
+            id: Thing::from((NOTIFICATION_QUEUE_TABLE, surrealdb::sql::Id::ulid())),
+            subscription: RecordId::from_table_key(
+                EMAIL_SUBSCRIPTION_TABLE,
+                self.id.id.to_raw(),
+            ),
+            notification: notification.clone(),
+        };
+
+        let _: Option<QueuedNotification> = DB
+            .create((NOTIFICATION_QUEUE_TABLE, queued.id.id.to_raw()))
+            .content(queued)
+            .await?;
+        Ok(())
+    }
+
+    /// Take all queued notifications for this subscription, removing them from the queue
+    pub async fn take_queued(&self) -> Result<Vec<Notification>> {
+        let sub = RecordId::from_table_key(EMAIL_SUBSCRIPTION_TABLE, self.id.id.to_raw());
+        // a single statement, so notifications queued in between are neither lost nor sent twice
+        let mut queued: Vec<QueuedNotification> = DB
+            .query("DELETE notification_queue WHERE subscription = $sub RETURN BEFORE;")
+            .bind(("sub", sub))
+            .await?
+            .take(0)?;
+
+        queued.sort_by_key(|q| q.notification.timestamp);
+        Ok(queued.into_iter().map(|q| q.notification).collect())
+    }
+}
+
+/// A notification waiting to be sent out in a digest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedNotification {
+    pub id: Thing,
+    pub subscription: RecordId,
+    pub notification: Notification,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let upload = Notification::new(NotificationKind::Upload, Some("f40"), "uploaded");
+        let expiring = Notification::new(NotificationKind::KeyExpiring, None, "expiring");
+
+        let all = EmailSubscription::new(String::new(), vec![], vec![], DigestMode::Daily);
+        assert!(all.matches(&upload));
+        assert!(all.matches(&expiring));
+
+        let tagged =
+            EmailSubscription::new(String::new(), vec!["f40".into()], vec![], DigestMode::Daily);
+        assert!(tagged.matches(&upload));
+        assert!(!tagged.matches(&expiring));
+
+        let uploads = EmailSubscription::new(
+            String::new(),
+            vec![],
+            vec![NotificationKind::Upload],
+            DigestMode::Daily,
+        );
+        assert!(uploads.matches(&upload));
+        assert!(!uploads.matches(&expiring));
+    }
+}
@@ -0,0 +1,451 @@
+//! OCI images, stored as content addressed blobs and manifests
+//!
+//! Blobs, i.e. layers and image configs, are shared by every tag and keyed by their digest.
+//! Manifests belong to an image of a tag, served as `<tag>/<name>` by the registry API, and are
+//! pointed at by the image's tags, i.e. `latest`. An image tag only ever points at one manifest
+//! of an image, pushing it again moves it.
+//!
+//! Like packages, pushed manifests are only served once the tag is assembled and published, and
+//! blobs only as part of an image with a published manifest referring to them.
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use surrealdb::{sql::Thing, RecordId};
+use ulid::Ulid;
+
+use super::{record_key, rpm::Count, tag::TAG_TABLE, DB};
+
+pub const OCI_PREFIX: &str = "oci";
+pub const OCI_BLOB_TABLE: &str = "oci_blob";
+pub const OCI_MANIFEST_TABLE: &str = "oci_manifest";
+
+/// Media types of manifests listing other manifests, per platform
+const INDEX_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+/// Media types of manifests listing an image's config and layers
+const MANIFEST_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+/// Check a `sha256:<hex>` digest, returning its hex part
+///
+/// Only sha256 is supported, like most registries.
+pub fn parse_digest(digest: &str) -> color_eyre::Result<&str> {
+    let hex = digest
+        .strip_prefix("sha256:")
+        .ok_or_else(|| eyre!("unsupported digest algorithm in {digest:?}"))?;
+    if hex.len() != 64 || !hex.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')) {
+        return Err(eyre!("invalid digest {digest:?}"));
+    }
+    Ok(hex)
+}
+
+/// `sha256:<hex>` digest of some content
+pub fn digest_of(data: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(data)))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OciBlob {
+    /// Keyed by the hex part of the digest
+    pub id: Thing,
+    pub digest: String,
+    pub size: u64,
+    pub object_key: String,
+    pub timestamp: surrealdb::sql::Datetime,
+}
+
+impl OciBlob {
+    /// A blob with a digest that was already checked, see [`parse_digest`]
+    pub fn new(digest: &str, size: u64) -> color_eyre::Result<Self> {
+        let hex = parse_digest(digest)?;
+        Ok(Self {
+            id: Thing::from((OCI_BLOB_TABLE, hex)),
+            digest: digest.to_owned(),
+            size,
+            object_key: format!("{OCI_PREFIX}/blobs/sha256/{hex}"),
+            timestamp: chrono::Utc::now().into(),
+        })
+    }
+
+    pub async fn get(digest: &str) -> color_eyre::Result<Option<Self>> {
+        let hex = parse_digest(digest)?.to_owned();
+        let a: Option<Self> = DB
+            .retry(|| {
+                let hex = hex.clone();
+                async move { DB.select((OCI_BLOB_TABLE, hex)).await }
+            })
+            .await?;
+        Ok(a)
+    }
+
+    pub async fn save(&self) -> color_eyre::Result<Self> {
+        let saved: Option<Self> = DB
+            .upsert((OCI_BLOB_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+        saved.ok_or_else(|| eyre!("nothing returned from upsert"))
+    }
+}
+
+/// Blobs and manifests a manifest refers to, which must exist before it can be pushed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct References {
+    pub blobs: Vec<String>,
+    pub manifests: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OciManifest {
+    pub id: Thing,
+    pub tag: RecordId,
+    /// Name of the image in the tag, i.e. `toolbox` or `fedora/base`
+    pub name: String,
+    pub digest: String,
+    pub media_type: String,
+    /// The manifest as pushed, served byte for byte so its digest doesn't change
+    pub content: String,
+    pub size: u64,
+    /// Image tags pointing at this manifest, i.e. `latest`
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Whether the manifest was published by assembling its tag, and is served by the registry
+    #[serde(default)]
+    pub published: bool,
+    pub timestamp: surrealdb::sql::Datetime,
+}
+
+/// A lighter reference to a manifest, for listings
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OciManifestRef {
+    pub id: Ulid,
+    pub tag: String,
+    pub name: String,
+    pub digest: String,
+    pub media_type: String,
+    pub tags: Vec<String>,
+    pub published: bool,
+}
+
+impl From<&OciManifest> for OciManifestRef {
+    fn from(manifest: &OciManifest) -> Self {
+        Self {
+            id: Ulid::from_string(&manifest.id.id.to_raw()).unwrap_or_default(),
+            tag: record_key(&manifest.tag),
+            name: manifest.name.clone(),
+            digest: manifest.digest.clone(),
+            media_type: manifest.media_type.clone(),
+            tags: manifest.tags.clone(),
+            published: manifest.published,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OciManifestFilter {
+    pub tag: Option<String>,
+    pub name: Option<String>,
+}
+
+const OCI_FILTER_CLAUSE: &str = "($tag = NONE OR tag = $tag) AND ($name = NONE OR name = $name)";
+
+/// Check an image name, lowercase path components as the distribution spec allows them
+pub fn validate_name(name: &str) -> color_eyre::Result<()> {
+    let valid_component = |component: &str| {
+        !component.is_empty()
+            && component
+                .bytes()
+                .all(|c| matches!(c, b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'-'))
+            && component.starts_with(|c: char| c.is_ascii_alphanumeric())
+    };
+    if !name.split('/').all(valid_component) {
+        return Err(eyre!("invalid image name {name:?}"));
+    }
+    Ok(())
+}
+
+impl OciManifest {
+    /// A manifest pushed to an image of a tag
+    ///
+    /// The media type is taken from the manifest's `mediaType`, falling back to the one given.
+    pub fn new(
+        tag: &str,
+        name: &str,
+        content: String,
+        media_type: Option<String>,
+    ) -> color_eyre::Result<Self> {
+        validate_name(name)?;
+        let json: serde_json::Value =
+            serde_json::from_str(&content).map_err(|e| eyre!("manifest isn't JSON: {e}"))?;
+        let media_type = json
+            .get("mediaType")
+            .and_then(|t| t.as_str())
+            .map(str::to_owned)
+            .or(media_type)
+            .ok_or_else(|| eyre!("manifest has no media type"))?;
+        if !INDEX_MEDIA_TYPES.contains(&media_type.as_str())
+            && !MANIFEST_MEDIA_TYPES.contains(&media_type.as_str())
+        {
+            return Err(eyre!("unsupported manifest media type {media_type:?}"));
+        }
+
+        Ok(Self {
+            id: Thing::from((OCI_MANIFEST_TABLE, surrealdb::sql::Id::ulid())),
+            tag: RecordId::from_table_key(TAG_TABLE, tag),
+            name: name.to_owned(),
+            digest: digest_of(content.as_bytes()),
+            media_type,
+            size: content.len() as u64,
+            content,
+            tags: Vec::new(),
+            published: false,
+            timestamp: chrono::Utc::now().into(),
+        })
+    }
+
+    /// Blobs and manifests the manifest refers to
+    pub fn references(&self) -> color_eyre::Result<References> {
+        let json: serde_json::Value = serde_json::from_str(&self.content)?;
+        let digests = |items: Option<&serde_json::Value>| -> color_eyre::Result<Vec<String>> {
+            items
+                .and_then(|items| items.as_array())
+                .into_iter()
+                .flatten()
+                .map(|item| {
+                    item.get("digest")
+                        .and_then(|d| d.as_str())
+                        .map(str::to_owned)
+                        .ok_or_else(|| eyre!("descriptor has no digest"))
+                })
+                .collect()
+        };
+
+        let mut references = References::default();
+        if INDEX_MEDIA_TYPES.contains(&self.media_type.as_str()) {
+            references.manifests = digests(json.get("manifests"))?;
+        } else {
+            let config = json
+                .get("config")
+                .and_then(|c| c.get("digest"))
+                .and_then(|d| d.as_str())
+                .ok_or_else(|| eyre!("manifest has no config"))?;
+            references.blobs.push(config.to_owned());
+            references.blobs.extend(digests(json.get("layers"))?);
+        }
+        for digest in references.blobs.iter().chain(&references.manifests) {
+            parse_digest(digest)?;
+        }
+        Ok(references)
+    }
+
+    #[tracing::instrument]
+    pub async fn get(id: Ulid) -> color_eyre::Result<Option<Self>> {
+        let a: Option<Self> = DB
+            .retry(|| async move { DB.select((OCI_MANIFEST_TABLE, id.to_string())).await })
+            .await?;
+        Ok(a)
+    }
+
+    /// Find a manifest of an image by digest or image tag
+    pub async fn find(tag: &str, name: &str, reference: &str) -> color_eyre::Result<Option<Self>> {
+        Self::lookup(tag, name, reference, None).await
+    }
+
+    /// Find a published manifest of an image, the only ones the registry API serves
+    pub async fn find_published(
+        tag: &str,
+        name: &str,
+        reference: &str,
+    ) -> color_eyre::Result<Option<Self>> {
+        Self::lookup(tag, name, reference, Some(true)).await
+    }
+
+    async fn lookup(
+        tag: &str,
+        name: &str,
+        reference: &str,
+        published: Option<bool>,
+    ) -> color_eyre::Result<Option<Self>> {
+        let by = if reference.starts_with("sha256:") {
+            "digest = $reference"
+        } else {
+            "tags CONTAINS $reference"
+        };
+        let a: Option<Self> = DB
+            .retry(|| {
+                DB.query(format!(
+                    "SELECT * FROM oci_manifest WHERE tag = $tag AND name = $name AND {by} AND ($published = NONE OR published = $published) LIMIT 1;"
+                ))
+                .bind(("tag", RecordId::from_table_key(TAG_TABLE, tag)))
+                .bind(("name", name.to_owned()))
+                .bind(("reference", reference.to_owned()))
+                .bind(("published", published))
+            })
+            .await?
+            .take(0)?;
+        Ok(a)
+    }
+
+    /// Image tags of an image's published manifests, sorted
+    pub async fn list_tags(tag: &str, name: &str) -> color_eyre::Result<Vec<String>> {
+        let tags: Vec<Vec<String>> = DB
+            .retry(|| {
+                DB.query("SELECT VALUE tags FROM oci_manifest WHERE tag = $tag AND name = $name AND published = true;")
+                    .bind(("tag", RecordId::from_table_key(TAG_TABLE, tag)))
+                    .bind(("name", name.to_owned()))
+            })
+            .await?
+            .take(0)?;
+        let mut tags = tags.into_iter().flatten().collect::<Vec<_>>();
+        tags.sort();
+        Ok(tags)
+    }
+
+    /// Whether a published manifest of an image refers to a blob, so it can be pulled with it
+    pub async fn references_blob(tag: &str, name: &str, digest: &str) -> color_eyre::Result<bool> {
+        // narrowed down by the digest showing up anywhere in the manifest, then checked properly
+        let manifests: Vec<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM oci_manifest WHERE tag = $tag AND name = $name AND published = true AND string::contains(content, $digest);")
+                    .bind(("tag", RecordId::from_table_key(TAG_TABLE, tag)))
+                    .bind(("name", name.to_owned()))
+                    .bind(("digest", digest.to_owned()))
+            })
+            .await?
+            .take(0)?;
+        Ok(manifests.iter().any(|manifest| {
+            manifest
+                .references()
+                .is_ok_and(|references| references.blobs.iter().any(|blob| blob == digest))
+        }))
+    }
+
+    /// Publish every manifest pushed to a tag, once it's assembled
+    pub async fn publish_all(tag: &str) -> color_eyre::Result<()> {
+        DB.query(
+            "UPDATE oci_manifest SET published = true WHERE tag = $tag AND published = false;",
+        )
+        .bind(("tag", RecordId::from_table_key(TAG_TABLE, tag)))
+        .await?
+        .check()?;
+        Ok(())
+    }
+
+    /// Fetches a page of manifests matching a filter, along with the total number of matches
+    pub async fn get_page(
+        filter: &OciManifestFilter,
+        limit: u32,
+        offset: u32,
+    ) -> color_eyre::Result<(Vec<Self>, u64)> {
+        let mut query = DB
+            .retry(|| {
+                DB.query(format!(
+                    "SELECT * FROM oci_manifest WHERE {OCI_FILTER_CLAUSE} ORDER BY id LIMIT $limit START $offset;"
+                ))
+                .query(format!(
+                    "SELECT count() FROM oci_manifest WHERE {OCI_FILTER_CLAUSE} GROUP ALL;"
+                ))
+                .bind((
+                    "tag",
+                    filter
+                        .tag
+                        .as_ref()
+                        .map(|t| RecordId::from_table_key(TAG_TABLE, t)),
+                ))
+                .bind(("name", filter.name.clone()))
+                .bind(("limit", limit))
+                .bind(("offset", offset))
+            })
+            .await?;
+
+        let page: Vec<Self> = query.take(0)?;
+        let total: Option<Count> = query.take(1)?;
+
+        Ok((page, total.map_or(0, |c| c.count)))
+    }
+
+    /// Save the manifest, pointing `reference` at it if it's an image tag
+    ///
+    /// A manifest already pushed to the image is reused, so it keeps its other image tags.
+    pub async fn commit(&self, reference: Option<&str>) -> color_eyre::Result<Self> {
+        let mut manifest = Self::find(&record_key(&self.tag), &self.name, &self.digest)
+            .await?
+            .unwrap_or_else(|| self.clone());
+        let image_tag = reference.filter(|r| !r.starts_with("sha256:"));
+        if let Some(image_tag) = image_tag {
+            if !manifest.tags.iter().any(|t| t == image_tag) {
+                manifest.tags.push(image_tag.to_owned());
+            }
+        }
+
+        DB.query("BEGIN;")
+            .query("UPDATE oci_manifest SET tags -= $reference WHERE tag = $tag AND name = $name AND id != $id;")
+            .query("UPSERT $id CONTENT $content;")
+            .query("COMMIT;")
+            .bind(("reference", image_tag.map(str::to_owned)))
+            .bind(("tag", manifest.tag.clone()))
+            .bind(("name", manifest.name.clone()))
+            .bind((
+                "id",
+                RecordId::from_table_key(OCI_MANIFEST_TABLE, manifest.id.id.to_raw()),
+            ))
+            .bind(("content", manifest.clone()))
+            .await?
+            .check()?;
+
+        let id = Ulid::from_string(&manifest.id.id.to_raw())?;
+        Self::get(id).await?.ok_or_else(|| eyre!("failed to save manifest"))
+    }
+
+    /// Delete the manifest, its blobs are kept as other manifests may share them
+    pub async fn delete(&self) -> color_eyre::Result<()> {
+        let _: Option<Self> = DB
+            .delete((OCI_MANIFEST_TABLE, self.id.id.to_raw()))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn test_parse_digest() {
+        assert_eq!(digest_of(b""), DIGEST);
+        assert!(parse_digest(DIGEST).is_ok());
+        assert!(parse_digest("sha512:abcd").is_err());
+        assert!(parse_digest("sha256:ABCD").is_err());
+        assert!(parse_digest("sha256:../../etc").is_err());
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("toolbox").is_ok());
+        assert!(validate_name("fedora/base-image").is_ok());
+        assert!(validate_name("Toolbox").is_err());
+        assert!(validate_name("fedora//base").is_err());
+        assert!(validate_name("../base").is_err());
+    }
+
+    #[test]
+    fn test_manifest_references() {
+        let content = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"digest":"{DIGEST}","size":0}},"layers":[{{"digest":"{DIGEST}","size":0}}]}}"#
+        );
+        let manifest = OciManifest::new("foobar", "hello", content.clone(), None).unwrap();
+        assert_eq!(manifest.digest, digest_of(content.as_bytes()));
+        assert_eq!(manifest.references().unwrap().blobs, vec![DIGEST, DIGEST]);
+
+        let index = r#"{"schemaVersion":2,"manifests":[]}"#.to_owned();
+        assert!(OciManifest::new("foobar", "hello", index.clone(), None).is_err());
+        let media_type = "application/vnd.oci.image.index.v1+json".to_owned();
+        let index = OciManifest::new("foobar", "hello", index, Some(media_type)).unwrap();
+        assert_eq!(index.references().unwrap(), References::default());
+    }
+}
@@ -0,0 +1,312 @@
+//! OSTree commits, uploaded as tarballs of a repo holding them or as static deltas
+//!
+//! Every commit uploaded to a tag stays available, so the exported repo keeps the history
+//! clients upgrade along. Each ref points at the newest commit uploaded for it.
+use std::collections::BTreeMap;
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use surrealdb::{sql::Thing, RecordId};
+use ulid::Ulid;
+
+use crate::obj_store::object_store;
+
+use super::{
+    record_key,
+    rpm::{file_sha256, get_split_id_string, Count},
+    tag::{Tag, TAG_TABLE},
+    DB,
+};
+
+pub const OSTREE_PREFIX: &str = "ostree";
+pub const OSTREE_TABLE: &str = "ostree_commit";
+/// Directory of a tag's export the ostree repo is published under
+pub const OSTREE_SUBDIR: &str = "ostree";
+
+/// How a commit was uploaded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OstreeArtifact {
+    /// A tarball of an ostree repo containing the commit, i.e. the output of `ostree commit`
+    #[default]
+    Tarball,
+    /// A static delta to the commit, from `ostree static-delta generate --filename`, applied
+    /// on top of the commits uploaded before it
+    Delta,
+}
+
+impl OstreeArtifact {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tarball => "tarball",
+            Self::Delta => "delta",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OstreeCommit {
+    pub id: Thing,
+    /// Ref the commit was uploaded for, i.e. `fedora/41/x86_64/silverblue`
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    /// Checksum of the commit object
+    pub checksum: String,
+    pub artifact: OstreeArtifact,
+    pub object_key: String,
+    pub tag: RecordId,
+    pub timestamp: surrealdb::sql::Datetime,
+    #[serde(default)]
+    pub available: bool,
+    pub sha256: Option<String>,
+    pub size: u64,
+}
+
+/// A lighter reference to a commit, for listings
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OstreeCommitRef {
+    pub id: Ulid,
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    pub checksum: String,
+    pub artifact: OstreeArtifact,
+    pub object_key: String,
+    pub tag: String,
+    pub available: bool,
+}
+
+impl From<&OstreeCommit> for OstreeCommitRef {
+    fn from(commit: &OstreeCommit) -> Self {
+        Self {
+            id: Ulid::from_string(&commit.id.id.to_raw()).unwrap_or_default(),
+            ref_name: commit.ref_name.clone(),
+            checksum: commit.checksum.clone(),
+            artifact: commit.artifact,
+            object_key: commit.object_key.clone(),
+            tag: record_key(&commit.tag),
+            available: commit.available,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OstreeCommitFilter {
+    #[serde(rename = "ref")]
+    pub ref_name: Option<String>,
+    pub tag: Option<String>,
+    pub available: Option<bool>,
+}
+
+const OSTREE_FILTER_CLAUSE: &str = "($ref = NONE OR ref = $ref) \
+    AND ($tag = NONE OR tag = $tag) \
+    AND ($available = NONE OR available = $available)";
+
+/// Check a ref name the way ostree does, `/` separated components of letters, digits, `-`, `_`
+/// and `.`, not starting with a `-` or `.`
+pub fn validate_ref(ref_name: &str) -> color_eyre::Result<()> {
+    let valid_component = |component: &str| {
+        component.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+            && component
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'_' | b'.'))
+    };
+    if !ref_name.split('/').all(valid_component) {
+        return Err(eyre!("invalid ref {ref_name:?}"));
+    }
+    Ok(())
+}
+
+/// Check a commit checksum, 64 lowercase hex characters
+pub fn validate_checksum(checksum: &str) -> color_eyre::Result<()> {
+    if checksum.len() != 64 || !checksum.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')) {
+        return Err(eyre!("invalid commit checksum {checksum:?}"));
+    }
+    Ok(())
+}
+
+/// The commit each ref points at, the newest of the commits given in upload order
+pub fn heads<'a>(
+    commits: impl IntoIterator<Item = &'a OstreeCommitRef>,
+) -> BTreeMap<&'a str, &'a str> {
+    commits
+        .into_iter()
+        .map(|commit| (commit.ref_name.as_str(), commit.checksum.as_str()))
+        .collect()
+}
+
+impl OstreeCommit {
+    /// A commit uploaded for a ref of a tag, read from a local file
+    pub fn from_path(
+        local_path: impl AsRef<std::path::Path>,
+        ref_name: &str,
+        checksum: &str,
+        artifact: OstreeArtifact,
+        tag: &str,
+    ) -> color_eyre::Result<Self> {
+        let local_path = local_path.as_ref();
+        validate_ref(ref_name)?;
+        validate_checksum(checksum)?;
+        let id = Thing::from((OSTREE_TABLE, surrealdb::sql::Id::ulid()));
+        Ok(Self {
+            object_key: crate::namespace::object_key(format!(
+                "{OSTREE_PREFIX}/{}/{checksum}.{}",
+                get_split_id_string(&id.id.to_raw()),
+                artifact.as_str()
+            )),
+            id,
+            ref_name: ref_name.to_owned(),
+            checksum: checksum.to_owned(),
+            artifact,
+            tag: RecordId::from_table_key(TAG_TABLE, tag),
+            timestamp: chrono::Utc::now().into(),
+            available: false,
+            sha256: Some(file_sha256(local_path)?),
+            size: std::fs::metadata(local_path)?.len(),
+        })
+    }
+
+    #[tracing::instrument]
+    pub async fn get(id: Ulid) -> color_eyre::Result<Option<Self>> {
+        let a: Option<Self> = DB
+            .retry(|| async move { DB.select((OSTREE_TABLE, id.to_string())).await })
+            .await?;
+        match a {
+            Some(a) if Tag::visible(&record_key(&a.tag)).await? => Ok(Some(a)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Fetches a page of commits matching a filter, along with the total number of matches
+    pub async fn get_page(
+        filter: &OstreeCommitFilter,
+        limit: u32,
+        offset: u32,
+    ) -> color_eyre::Result<(Vec<Self>, u64)> {
+        let mut query = DB
+            .retry(|| {
+                DB.query(format!(
+                    "SELECT * FROM ostree_commit WHERE {OSTREE_FILTER_CLAUSE} ORDER BY id LIMIT $limit START $offset;"
+                ))
+                .query(format!(
+                    "SELECT count() FROM ostree_commit WHERE {OSTREE_FILTER_CLAUSE} GROUP ALL;"
+                ))
+                .bind(("ref", filter.ref_name.clone()))
+                .bind((
+                    "tag",
+                    filter
+                        .tag
+                        .as_ref()
+                        .map(|t| RecordId::from_table_key(TAG_TABLE, t)),
+                ))
+                .bind(("available", filter.available))
+                .bind(("limit", limit))
+                .bind(("offset", offset))
+            })
+            .await?;
+
+        let page: Vec<Self> = query.take(0)?;
+        let total: Option<Count> = query.take(1)?;
+
+        Ok((page, total.map_or(0, |c| c.count)))
+    }
+
+    /// Fetches the commits available in a tag, in upload order so deltas apply on their parents
+    pub async fn get_available_by_tag(tag: &str) -> color_eyre::Result<Vec<Self>> {
+        let a: Vec<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM ostree_commit WHERE tag = $tag AND available = true ORDER BY id;")
+                    .bind(("tag", RecordId::from_table_key(TAG_TABLE, tag)))
+            })
+            .await?
+            .take(0)?;
+        Ok(a)
+    }
+
+    /// Find the same commit already uploaded for this ref of the tag
+    pub async fn find_duplicate(&self) -> color_eyre::Result<Option<Self>> {
+        let a: Option<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM ostree_commit WHERE checksum = $checksum AND ref = $ref AND tag = $tag AND available = true AND id != $id LIMIT 1;")
+                    .bind(("checksum", self.checksum.clone()))
+                    .bind(("ref", self.ref_name.clone()))
+                    .bind(("tag", self.tag.clone()))
+                    .bind(("id", self.id.clone()))
+            })
+            .await?
+            .take(0)?;
+        Ok(a)
+    }
+
+    /// Commits the commit to the database, available right away
+    pub async fn commit_to_db(&self) -> color_eyre::Result<Self> {
+        let content = Self {
+            available: true,
+            ..self.clone()
+        };
+        let _: Option<Self> = DB
+            .create((OSTREE_TABLE, self.id.id.to_raw()))
+            .content(content)
+            .await?;
+
+        let id = Ulid::from_string(&self.id.id.to_raw())?;
+        Self::get(id).await?.ok_or_else(|| eyre!("failed to create entry"))
+    }
+
+    /// Sets whether the commit is published, i.e. to take a broken commit out of the history
+    pub async fn mark_available(&self, available: bool) -> color_eyre::Result<Self> {
+        let a: Option<Self> = DB
+            .query("UPDATE $id SET available = $available RETURN AFTER;")
+            .bind(("id", RecordId::from_table_key(OSTREE_TABLE, self.id.id.to_raw())))
+            .bind(("available", available))
+            .await?
+            .take(0)?;
+        a.ok_or_else(|| eyre!("commit {} was deleted", self.id.id.to_raw()))
+    }
+
+    pub async fn delete(&self) -> color_eyre::Result<()> {
+        let _: Option<Self> = DB.delete((OSTREE_TABLE, self.id.id.to_raw())).await?;
+        object_store().remove(&self.object_key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECKSUM: &str = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+
+    #[test]
+    fn test_validate_ref() {
+        assert!(validate_ref("fedora/41/x86_64/silverblue").is_ok());
+        assert!(validate_ref("ultramarine_base-1.0").is_ok());
+        assert!(validate_ref("").is_err());
+        assert!(validate_ref("fedora//silverblue").is_err());
+        assert!(validate_ref("../heads").is_err());
+        assert!(validate_ref("-rf").is_err());
+    }
+
+    #[test]
+    fn test_validate_checksum() {
+        assert!(validate_checksum(CHECKSUM).is_ok());
+        assert!(validate_checksum(&CHECKSUM.to_uppercase()).is_err());
+        assert!(validate_checksum(&CHECKSUM[1..]).is_err());
+    }
+
+    #[test]
+    fn test_heads() {
+        let commit = |ref_name: &str, checksum: &str| OstreeCommitRef {
+            id: Ulid::new(),
+            ref_name: ref_name.to_owned(),
+            checksum: checksum.to_owned(),
+            artifact: OstreeArtifact::Tarball,
+            object_key: String::new(),
+            tag: "foobar".to_owned(),
+            available: true,
+        };
+        let commits = [commit("a", "1"), commit("b", "2"), commit("a", "3")];
+        let heads = heads(&commits);
+        assert_eq!(heads.len(), 2);
+        assert_eq!(heads["a"], "3");
+        assert_eq!(heads["b"], "2");
+    }
+}
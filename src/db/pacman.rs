@@ -0,0 +1,534 @@
+//! Arch Linux (pacman) packages
+//!
+//! A `.pkg.tar.zst` is a zstd compressed tarball of the package's files, with its metadata in a
+//! `.PKGINFO` file at the root. Only `.PKGINFO` is read on upload, the repo database entries are
+//! generated from the parsed fields on assembly.
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use base64::Engine;
+use color_eyre::eyre::{eyre, WrapErr};
+use serde::{Deserialize, Serialize};
+use surrealdb::{sql::Thing, RecordId};
+use ulid::Ulid;
+
+use crate::obj_store::object_store;
+use crate::rpmvercmp::evr_cmp;
+
+use super::{
+    record_key,
+    rpm::{file_sha256, get_split_id_string, Count},
+    tag::{Tag, TAG_TABLE},
+    DB,
+};
+
+pub const PACMAN_PREFIX: &str = "pacman";
+pub const PACMAN_TABLE: &str = "pacman_package";
+/// Extension of the package files
+pub const PACMAN_PKG_EXT: &str = "pkg.tar.zst";
+
+/// MIME type pacman packages are served as
+pub const PACMAN_CONTENT_TYPE: &str = "application/zstd";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PacmanPackage {
+    pub id: Thing,
+    /// The `pkgname` field
+    pub name: String,
+    /// The `pkgbase` field, the PKGBUILD the package was built from
+    pub base: Option<String>,
+    /// Full `[epoch:]pkgver-pkgrel` version
+    pub version: String,
+    /// Architecture, i.e. `x86_64` or `any`
+    pub arch: String,
+    pub description: Option<String>,
+    pub url: Option<String>,
+    #[serde(default)]
+    pub licenses: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Dependencies with their version constraints, i.e. `bash>=5`
+    #[serde(default)]
+    pub depends: Vec<String>,
+    /// Optional dependencies with why they're useful, i.e. `zsh: alternative shell`
+    #[serde(default)]
+    pub optdepends: Vec<String>,
+    #[serde(default)]
+    pub makedepends: Vec<String>,
+    #[serde(default)]
+    pub checkdepends: Vec<String>,
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    #[serde(default)]
+    pub provides: Vec<String>,
+    #[serde(default)]
+    pub replaces: Vec<String>,
+    pub packager: Option<String>,
+    /// Unix timestamp of the build
+    pub build_date: Option<i64>,
+    /// Size of the installed files in bytes, as declared by the package
+    pub installed_size: Option<u64>,
+    pub object_key: String,
+    pub tag: RecordId,
+    pub timestamp: surrealdb::sql::Datetime,
+    /// Whether the package is the one published in the tag, for its name + architecture
+    #[serde(default)]
+    pub available: bool,
+    pub sha256: Option<String>,
+    /// Size of the package file in bytes
+    pub size: u64,
+    /// Base64 encoded binary detached signature of the package file, published as `<file>.sig`
+    pub signature: Option<String>,
+}
+
+/// A lighter reference to a pacman package, for listings
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PacmanRef {
+    pub id: Ulid,
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+    pub object_key: String,
+    pub tag: String,
+    pub available: bool,
+    pub signed: bool,
+}
+
+impl From<&PacmanPackage> for PacmanRef {
+    fn from(pkg: &PacmanPackage) -> Self {
+        Self {
+            id: Ulid::from_string(&pkg.id.id.to_raw()).unwrap_or_default(),
+            name: pkg.name.clone(),
+            version: pkg.version.clone(),
+            arch: pkg.arch.clone(),
+            object_key: pkg.object_key.clone(),
+            tag: record_key(&pkg.tag),
+            available: pkg.available,
+            signed: pkg.signature.is_some(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PacmanFilter {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub arch: Option<String>,
+    pub tag: Option<String>,
+}
+
+const PACMAN_FILTER_CLAUSE: &str = "($name = NONE OR name = $name) \
+    AND ($version = NONE OR version = $version) \
+    AND ($arch = NONE OR arch = $arch) \
+    AND ($tag = NONE OR tag = $tag)";
+
+/// Parse a `.PKGINFO` file into its fields
+///
+/// Fields like `depend` are repeated once per value, so every field maps to a list.
+pub fn parse_pkginfo(pkginfo: &str) -> color_eyre::Result<BTreeMap<String, Vec<String>>> {
+    let mut fields: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for line in pkginfo.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| eyre!("invalid .PKGINFO line: {line:?}"))?;
+        fields
+            .entry(name.trim().to_owned())
+            .or_default()
+            .push(value.trim().to_owned());
+    }
+    Ok(fields)
+}
+
+/// Read the `.PKGINFO` file out of a `.pkg.tar.zst`
+pub fn read_pkginfo(path: &std::path::Path) -> color_eyre::Result<String> {
+    let file = std::fs::File::open(path)?;
+    let decoder = zstd::Decoder::new(file).wrap_err("not a pacman package")?;
+    let mut archive = tar::Archive::new(decoder);
+    for entry in archive.entries().wrap_err("not a pacman package")? {
+        let mut entry = entry.wrap_err("not a pacman package")?;
+        if entry.path()?.as_os_str() != ".PKGINFO" {
+            continue;
+        }
+        let mut text = String::new();
+        entry.read_to_string(&mut text)?;
+        return Ok(text);
+    }
+    Err(eyre!("not a pacman package, no .PKGINFO"))
+}
+
+/// Split a full `[epoch:]pkgver-pkgrel` version into its parts, a missing epoch is 0
+pub fn split_version(version: &str) -> (u32, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => (epoch.parse().unwrap_or_default(), rest),
+        None => (0, version),
+    };
+    let (pkgver, pkgrel) = rest.rsplit_once('-').unwrap_or((rest, ""));
+    (epoch, pkgver, pkgrel)
+}
+
+/// Compare two full versions the way `vercmp` does
+pub fn version_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    evr_cmp(split_version(a), split_version(b))
+}
+
+/// Object key of a package, named like makepkg names its files
+fn pacman_object_key(id: &str, name: &str, version: &str, arch: &str) -> String {
+    crate::namespace::object_key(format!(
+        "{PACMAN_PREFIX}/{}/{name}-{version}-{arch}.{PACMAN_PKG_EXT}",
+        get_split_id_string(id)
+    ))
+}
+
+/// Check the fields naming a package against the charsets makepkg allows
+///
+/// They end up in object keys, repository paths and the `.db` archive, so anything else is
+/// refused rather than escaped.
+fn check_fields(name: &str, version: &str, arch: &str) -> color_eyre::Result<()> {
+    let name_char = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || "@._+-".contains(c);
+    if name.is_empty() || name.starts_with(['-', '.']) || !name.chars().all(name_char) {
+        return Err(eyre!("invalid pkgname {name:?}"));
+    }
+
+    let (epoch, rest) = version.split_once(':').unwrap_or(("0", version));
+    let valid_version = rest.rsplit_once('-').is_some_and(|(pkgver, pkgrel)| {
+        let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+        let (rel, minor) = pkgrel.split_once('.').unwrap_or((pkgrel, "0"));
+        digits(epoch)
+            && !pkgver.is_empty()
+            && pkgver
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._+".contains(c))
+            && digits(rel)
+            && (minor.is_empty() || digits(minor))
+    });
+    if !valid_version {
+        return Err(eyre!("invalid pkgver {version:?}"));
+    }
+
+    if arch.is_empty() || !arch.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(eyre!("invalid arch {arch:?}"));
+    }
+    Ok(())
+}
+
+impl PacmanPackage {
+    /// Parse a package from its `.PKGINFO`
+    pub fn from_pkginfo(pkginfo: &str, tag: &str) -> color_eyre::Result<Self> {
+        let mut fields = parse_pkginfo(pkginfo)?;
+        let mut list = |name: &str| fields.remove(name).unwrap_or_default();
+        let first = |values: Vec<String>| values.into_iter().next();
+
+        let id = Thing::from((PACMAN_TABLE, surrealdb::sql::Id::ulid()));
+        let name = first(list("pkgname")).ok_or_else(|| eyre!(".PKGINFO has no pkgname"))?;
+        let version = first(list("pkgver")).ok_or_else(|| eyre!(".PKGINFO has no pkgver"))?;
+        let arch = first(list("arch")).ok_or_else(|| eyre!(".PKGINFO has no arch"))?;
+        check_fields(&name, &version, &arch)?;
+        Ok(Self {
+            object_key: pacman_object_key(&id.id.to_raw(), &name, &version, &arch),
+            id,
+            base: first(list("pkgbase")),
+            description: first(list("pkgdesc")),
+            url: first(list("url")),
+            licenses: list("license"),
+            groups: list("group"),
+            depends: list("depend"),
+            optdepends: list("optdepend"),
+            makedepends: list("makedepend"),
+            checkdepends: list("checkdepend"),
+            conflicts: list("conflict"),
+            provides: list("provides"),
+            replaces: list("replaces"),
+            packager: first(list("packager")),
+            build_date: first(list("builddate")).and_then(|s| s.parse().ok()),
+            installed_size: first(list("size")).and_then(|s| s.parse().ok()),
+            name,
+            version,
+            arch,
+            tag: RecordId::from_table_key(TAG_TABLE, tag),
+            timestamp: chrono::Utc::now().into(),
+            available: false,
+            sha256: None,
+            size: 0,
+            signature: None,
+        })
+    }
+
+    pub fn from_path(path: impl AsRef<std::path::Path>, tag: &str) -> color_eyre::Result<Self> {
+        let path = path.as_ref();
+        let mut pkg = Self::from_pkginfo(&read_pkginfo(path)?, tag)?;
+        pkg.sha256 = Some(file_sha256(path)?);
+        pkg.size = std::fs::metadata(path)?.len();
+        Ok(pkg)
+    }
+
+    /// `name-version-arch`, the way pacman identifies a package
+    pub fn full_name(&self) -> String {
+        format!("{}-{}-{}", self.name, self.version, self.arch)
+    }
+
+    /// File name of the package, the last component of its object key
+    pub fn file_name(&self) -> &str {
+        self.object_key.rsplit('/').next().unwrap_or(&self.object_key)
+    }
+
+    /// The package's binary detached signature, if it has one
+    pub fn signature_bytes(&self) -> color_eyre::Result<Option<Vec<u8>>> {
+        self.signature
+            .as_ref()
+            .map(|sig| Ok(base64::engine::general_purpose::STANDARD.decode(sig)?))
+            .transpose()
+    }
+
+    #[tracing::instrument]
+    pub async fn get(id: Ulid) -> color_eyre::Result<Option<Self>> {
+        let a: Option<Self> = DB
+            .retry(|| async move { DB.select((PACMAN_TABLE, id.to_string())).await })
+            .await?;
+        match a {
+            Some(a) if Tag::visible(&record_key(&a.tag)).await? => Ok(Some(a)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Fetches a page of packages matching a filter, along with the total number of matches
+    pub async fn get_page(
+        filter: &PacmanFilter,
+        limit: u32,
+        offset: u32,
+    ) -> color_eyre::Result<(Vec<Self>, u64)> {
+        let mut query = DB
+            .retry(|| {
+                DB.query(format!(
+                    "SELECT * FROM pacman_package WHERE {PACMAN_FILTER_CLAUSE} ORDER BY id LIMIT $limit START $offset;"
+                ))
+                .query(format!(
+                    "SELECT count() FROM pacman_package WHERE {PACMAN_FILTER_CLAUSE} GROUP ALL;"
+                ))
+                .bind(("name", filter.name.clone()))
+                .bind(("version", filter.version.clone()))
+                .bind(("arch", filter.arch.clone()))
+                .bind((
+                    "tag",
+                    filter
+                        .tag
+                        .as_ref()
+                        .map(|t| RecordId::from_table_key(TAG_TABLE, t)),
+                ))
+                .bind(("limit", limit))
+                .bind(("offset", offset))
+            })
+            .await?;
+
+        let page: Vec<Self> = query.take(0)?;
+        let total: Option<Count> = query.take(1)?;
+
+        Ok((page, total.map_or(0, |c| c.count)))
+    }
+
+    /// Fetches the packages available in a tag
+    pub async fn get_available_by_tag(tag: &str) -> color_eyre::Result<Vec<Self>> {
+        let a: Vec<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM pacman_package WHERE tag = $tag AND available = true;")
+                    .bind(("tag", RecordId::from_table_key(TAG_TABLE, tag)))
+            })
+            .await?
+            .take(0)?;
+        Ok(a)
+    }
+
+    /// Find a package in this tag with the same name, version, architecture and digest,
+    /// i.e. an earlier upload of the same file
+    pub async fn find_duplicate(&self) -> color_eyre::Result<Option<Self>> {
+        if self.sha256.is_none() {
+            return Ok(None);
+        }
+        let a: Option<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM pacman_package WHERE sha256 = $sha256 AND name = $name AND version = $version AND arch = $arch AND tag = $tag AND id != $id LIMIT 1;")
+                    .bind(("sha256", self.sha256.clone()))
+                    .bind(("name", self.name.clone()))
+                    .bind(("version", self.version.clone()))
+                    .bind(("arch", self.arch.clone()))
+                    .bind(("tag", self.tag.clone()))
+                    .bind(("id", self.id.clone()))
+            })
+            .await?
+            .take(0)?;
+        Ok(a)
+    }
+
+    /// Find an available package with the same name + architecture in the tag, with a newer version
+    pub async fn newer_sibling(&self) -> color_eyre::Result<Option<Self>> {
+        let siblings: Vec<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM pacman_package WHERE name = $name AND arch = $arch AND tag = $tag AND available = true AND id != $id;")
+                    .bind(("name", self.name.clone()))
+                    .bind(("arch", self.arch.clone()))
+                    .bind(("tag", self.tag.clone()))
+                    .bind(("id", self.id.clone()))
+            })
+            .await?
+            .take(0)?;
+        Ok(siblings
+            .into_iter()
+            .find(|s| version_cmp(&s.version, &self.version) == std::cmp::Ordering::Greater))
+    }
+
+    /// Mark this package as the available one for its name + architecture in the tag
+    ///
+    /// Fails, unless `force` is set, if an already available package has a newer version.
+    pub async fn mark_available(&self, force: bool) -> color_eyre::Result<Self> {
+        if !force {
+            if let Some(newer) = self.newer_sibling().await? {
+                return Err(eyre!(
+                    "{} has a newer version available ({}), not marking as available",
+                    self.name,
+                    newer.version
+                ));
+            }
+        }
+
+        DB.query("BEGIN;")
+            .query("UPDATE pacman_package SET available = false WHERE name = $name AND arch = $arch AND tag = $tag AND id != $id;")
+            .query("UPDATE $id SET available = true;")
+            .query("COMMIT;")
+            .bind(("name", self.name.clone()))
+            .bind(("arch", self.arch.clone()))
+            .bind(("tag", self.tag.clone()))
+            .bind(("id", self.record_id()))
+            .await?
+            .check()?;
+
+        self.refetch().await
+    }
+
+    pub async fn mark_unavailable(&self) -> color_eyre::Result<()> {
+        DB.query("UPDATE $id SET available = false;")
+            .bind(("id", self.record_id()))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    /// Store a binary detached signature of the package file
+    pub async fn set_signature(&self, signature: &[u8]) -> color_eyre::Result<Self> {
+        DB.query("UPDATE $id SET signature = $signature;")
+            .bind(("id", self.record_id()))
+            .bind((
+                "signature",
+                base64::engine::general_purpose::STANDARD.encode(signature),
+            ))
+            .await?
+            .check()?;
+        self.refetch().await
+    }
+
+    async fn refetch(&self) -> color_eyre::Result<Self> {
+        let id = Ulid::from_string(&self.id.id.to_raw())?;
+        Self::get(id).await?.ok_or_else(|| eyre!("failed to update entry"))
+    }
+
+    fn record_id(&self) -> RecordId {
+        RecordId::from_table_key(PACMAN_TABLE, self.id.id.to_raw())
+    }
+
+    /// Commits the package to the database, optionally marking it as the latest version in
+    /// its tag unless a newer version is available (without `force`)
+    pub async fn commit_to_db(&self, latest: bool, force: bool) -> color_eyre::Result<Self> {
+        let inserted: Option<Self> = DB
+            .insert((PACMAN_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+        let inserted = inserted.ok_or_else(|| eyre!("nothing returned from insert"))?;
+
+        if latest {
+            match self.mark_available(force).await {
+                Ok(available) => return Ok(available),
+                Err(e) => tracing::info!("{e}"),
+            }
+        }
+        Ok(inserted)
+    }
+
+    pub async fn delete(&self) -> color_eyre::Result<()> {
+        let _: Option<Self> = DB.delete((PACMAN_TABLE, self.id.id.to_raw())).await?;
+        object_store().remove(&self.object_key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PKG_PATH: &str = "test/data/subatomic-hello-1:1.0.2-1-any.pkg.tar.zst";
+
+    #[test]
+    fn test_parse_pkginfo() {
+        let fields =
+            parse_pkginfo("# generated\npkgname = foo\ndepend = a\ndepend = b>=2\npkgdesc = \n")
+                .unwrap();
+        assert_eq!(fields["pkgname"], vec!["foo"]);
+        assert_eq!(fields["depend"], vec!["a", "b>=2"]);
+        assert_eq!(fields["pkgdesc"], vec![""]);
+        assert!(parse_pkginfo("no equals sign\n").is_err());
+    }
+
+    #[test]
+    fn test_version_cmp() {
+        use std::cmp::Ordering;
+        assert_eq!(split_version("1:2.0-1"), (1, "2.0", "1"));
+        assert_eq!(version_cmp("1:1.0-1", "2.0-1"), Ordering::Greater);
+        assert_eq!(version_cmp("1.0-2", "1.0-10"), Ordering::Less);
+        assert_eq!(version_cmp("1.0rc1-1", "1.0-1"), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_check_fields() {
+        assert!(check_fields("subatomic-hello", "1:1.0.2-1", "any").is_ok());
+        assert!(check_fields("lib32-gcc-libs", "13.2.1+r1_x-3.1", "x86_64").is_ok());
+        assert!(check_fields("@scope.pkg", "1.0-1.", "aarch64").is_ok());
+        for (name, version, arch) in [
+            ("Foo", "1.0-1", "any"),
+            ("-foo", "1.0-1", "any"),
+            (".foo", "1.0-1", "any"),
+            ("foo/../bar", "1.0-1", "any"),
+            ("foo", "1.0", "any"),
+            ("foo", "1.0-a", "any"),
+            ("foo", "1.0-1.x", "any"),
+            ("foo", "x:1.0-1", "any"),
+            ("foo", "1.0/..-1", "any"),
+            ("foo", "-1", "any"),
+            ("foo", "1.0-1", "x86-64"),
+            ("foo", "1.0-1", ""),
+        ] {
+            assert!(
+                check_fields(name, version, arch).is_err(),
+                "{name} {version} {arch}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_pacman_from_path() {
+        let pkg = PacmanPackage::from_path(PKG_PATH, "foobar").unwrap();
+        assert_eq!(pkg.name, "subatomic-hello");
+        assert_eq!(pkg.version, "1:1.0.2-1");
+        assert_eq!(pkg.arch, "any");
+        assert_eq!(pkg.depends, vec!["glibc", "bash>=5"]);
+        assert_eq!(pkg.optdepends, vec!["zsh: alternative shell"]);
+        assert_eq!(pkg.provides, vec!["hello-world"]);
+        assert_eq!(pkg.licenses, vec!["MIT"]);
+        assert_eq!(pkg.build_date, Some(1700000000));
+        assert_eq!(pkg.sha256.as_deref().map(str::len), Some(64));
+        assert_eq!(pkg.file_name(), "subatomic-hello-1:1.0.2-1-any.pkg.tar.zst");
+        assert!(pkg.object_key.starts_with("pacman/"));
+
+        let deb = "test/data/subatomic-hello_1.0.2-1_all.deb";
+        assert!(PacmanPackage::from_path(deb, "foobar").is_err());
+    }
+}
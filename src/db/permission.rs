@@ -0,0 +1,136 @@
+use color_eyre::{eyre::ContextCompat, Result};
+use serde::{Deserialize, Serialize};
+use surrealdb::{sql::Thing, RecordId};
+
+use super::{token::TOKEN_TABLE, DB};
+
+pub const PERMISSION_TABLE: &str = "permission";
+/// Tag name granting a permission on every tag
+pub const ANY_TAG: &str = "*";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Upload packages and change their availability
+    Upload,
+    /// Sign packages
+    Sign,
+    /// Assemble the tag
+    Assemble,
+    /// Everything above, plus managing and deleting the tag and its packages
+    Admin,
+}
+
+impl std::str::FromStr for Role {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "upload" => Ok(Role::Upload),
+            "sign" => Ok(Role::Sign),
+            "assemble" => Ok(Role::Assemble),
+            "admin" => Ok(Role::Admin),
+            _ => Err(color_eyre::eyre::eyre!("unknown role: {s}")),
+        }
+    }
+}
+
+/// A role granted to an API token on a tag
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Permission {
+    pub id: Thing,
+    pub token: RecordId,
+    /// Name of the tag, or `*` for every tag
+    pub tag: String,
+    pub role: Role,
+    /// Namespace the grant is limited to, i.e. `*` for every tag of the namespace
+    ///
+    /// Only applies to requests under `/ns/{namespace}/`, see [`crate::namespace`]. A `*` grant
+    /// without a namespace covers the global tags only.
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+impl Permission {
+    pub fn new(token: &Thing, tag: String, role: Role) -> Self {
+        Self {
+            id: Thing::from((PERMISSION_TABLE, surrealdb::sql::Id::ulid())),
+            token: RecordId::from_table_key(TOKEN_TABLE, token.id.to_raw()),
+            tag,
+            role,
+            namespace: None,
+        }
+    }
+
+    /// Limit the grant to a namespace
+    pub fn namespace(mut self, namespace: Option<String>) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    pub async fn save(&self) -> Result<Self> {
+        let query = DB
+            .upsert((PERMISSION_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+
+        query.context("nothing returned from insert")
+    }
+
+    pub async fn get(id: &str) -> Result<Option<Self>> {
+        Ok(DB.retry(|| DB.select((PERMISSION_TABLE, id))).await?)
+    }
+
+    pub async fn delete(&self) -> Result<()> {
+        let _: Option<Self> = DB.delete((PERMISSION_TABLE, self.id.id.to_raw())).await?;
+        Ok(())
+    }
+
+    /// Fetch every permission granted to a token
+    pub async fn get_for_token(token: &Thing) -> Result<Vec<Self>> {
+        let a: Vec<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM permission WHERE token = $grantee;")
+                    .bind((
+                        "grantee",
+                        RecordId::from_table_key(TOKEN_TABLE, token.id.to_raw()),
+                    ))
+            })
+            .await?
+            .take(0)?;
+
+        Ok(a)
+    }
+
+    /// Remove every permission granted to a token
+    pub async fn delete_for_token(token: &Thing) -> Result<()> {
+        DB.query("DELETE permission WHERE token = $grantee;")
+            .bind((
+                "grantee",
+                RecordId::from_table_key(TOKEN_TABLE, token.id.to_raw()),
+            ))
+            .await?;
+        Ok(())
+    }
+
+    /// Whether a token has been granted a role on a tag
+    ///
+    /// The admin role on a tag implies every other role on it. Grants limited to a namespace
+    /// only count in that namespace's scope, and `*` grants only in the scope they were made
+    /// for, so a global one doesn't reach into namespaces.
+    pub async fn allows(token: &Thing, role: Role, tag: &str) -> Result<bool> {
+        let grant: Option<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM permission WHERE token = $grantee AND ((tag = $tag AND (namespace = NONE OR namespace = $namespace)) OR (tag = $any AND namespace = $namespace)) AND (role = $role OR role = 'admin') LIMIT 1;")
+                    .bind(("grantee", RecordId::from_table_key(TOKEN_TABLE, token.id.to_raw())))
+                    .bind(("tag", tag.to_owned()))
+                    .bind(("any", ANY_TAG))
+                    .bind(("namespace", crate::namespace::current()))
+                    .bind(("role", role))
+            })
+            .await?
+            .take(0)?;
+
+        Ok(grant.is_some())
+    }
+}
@@ -1,27 +1,64 @@
+use std::collections::HashSet;
+
 use color_eyre::eyre::eyre;
 use rpm::{signature::Signing, DependencyFlags, PackageMetadata};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use surrealdb::{sql::Thing, RecordId};
 use tracing::trace;
 use ulid::Ulid;
 
+use crate::config::CONFIG;
 use crate::obj_store::object_store;
+use crate::signing::signer::BlockingSigner;
 
-use super::{gpg_key::GpgKey, tag::TAG_TABLE, DB};
+use super::{
+    gpg_key::GpgKey,
+    membership::{TagMembership, MEMBERSHIP_TABLE},
+    record_key,
+    tag::{Tag, TAG_TABLE},
+    DB,
+};
+use crate::rpmvercmp::evr_cmp;
 pub const RPM_PREFIX: &str = "rpm";
 pub const RPM_TABLE: &str = "rpm_package";
+/// Number of packages stored under each object key, see [`Rpm::acquire_object`]
+pub const OBJECT_REF_TABLE: &str = "object_ref";
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 /// A lighter reference to an RPM object, used for linking to the full object
 /// in lockfiles and other places where the full object is not needed.
 pub struct RpmRef {
     pub id: ulid::Ulid,
-    #[serde(skip_serializing)]
-    rpm_id: RecordId,
+    // not stored, so references read back from the database don't have it
+    #[serde(skip)]
+    rpm_id: Option<RecordId>,
     pub name: String,
+    #[serde(default)]
+    pub epoch: u32,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub release: String,
+    #[serde(default)]
+    pub arch: String,
     pub object_key: String,
     pub signed_object_key: Option<String>,
     pub tag: Option<String>,
+    /// Hex-encoded SHA256 digest of the package file, if known
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Hex-encoded SHA256 digest of the signed package file, if signed
+    #[serde(default)]
+    pub signed_sha256: Option<String>,
+    /// Size of the signed package file in bytes, if signed
+    #[serde(default)]
+    pub signed_size: Option<u64>,
+    #[serde(default)]
+    pub is_source: bool,
+    /// File name of the source package this package was built from, if known
+    #[serde(default)]
+    pub source_rpm: Option<String>,
 }
 
 impl RpmRef {
@@ -29,15 +66,23 @@ impl RpmRef {
         Self {
             id,
             name,
-            rpm_id: RecordId::from_table_key(RPM_TABLE, id.to_string()),
+            rpm_id: Some(RecordId::from_table_key(RPM_TABLE, id.to_string())),
+            epoch: 0,
+            version: String::new(),
+            release: String::new(),
+            arch: String::new(),
             object_key,
             signed_object_key: None,
             tag: None,
+            sha256: None,
+            signed_sha256: None,
+            signed_size: None,
+            is_source: false,
+            source_rpm: None,
         }
     }
     pub async fn get(id: ulid::Ulid) -> color_eyre::Result<Option<Self>> {
-        DB.get()
-            .select((RPM_TABLE, id.to_string()))
+        DB.retry(|| async move { DB.select((RPM_TABLE, id.to_string())).await })
             .await
             .map_err(Into::into)
     }
@@ -45,6 +90,30 @@ impl RpmRef {
     pub async fn get_full(&self) -> color_eyre::Result<Rpm> {
         Rpm::get(self.id).await?.ok_or_else(|| eyre!("not found"))
     }
+
+    /// File name of the package in an assembled repo, prefixed with its ID
+    pub fn file_name(&self) -> String {
+        let object_name = self.object_key.rsplit('/').next().unwrap_or(&self.object_key);
+        format!("{}-{object_name}", self.id)
+    }
+
+    /// Object key of the package file to publish, the signed one if the package was signed
+    pub fn staged_object_key(&self) -> &str {
+        self.signed_object_key.as_deref().unwrap_or(&self.object_key)
+    }
+
+    /// Digest of the package file to publish, like [`RpmRef::staged_object_key`]
+    pub fn staged_sha256(&self) -> Option<&str> {
+        match &self.signed_object_key {
+            Some(_) => self.signed_sha256.as_deref(),
+            None => self.sha256.as_deref(),
+        }
+    }
+
+    /// Whether this is a `-debuginfo` or `-debugsource` package
+    pub fn is_debuginfo(&self) -> bool {
+        self.name.ends_with("-debuginfo") || self.name.ends_with("-debugsource")
+    }
 }
 
 impl From<&Rpm> for RpmRef {
@@ -52,10 +121,19 @@ impl From<&Rpm> for RpmRef {
         Self {
             id: Ulid::from_string(&rpm.id.id.to_raw()).unwrap(),
             name: rpm.name.clone(),
+            epoch: rpm.epoch,
+            version: rpm.version.clone(),
+            release: rpm.release.clone(),
+            arch: rpm.arch.clone(),
             object_key: rpm.object_key.clone(),
-            rpm_id: RecordId::from_table_key(RPM_TABLE, rpm.id.id.to_raw()),
+            rpm_id: Some(RecordId::from_table_key(RPM_TABLE, rpm.id.id.to_raw())),
             signed_object_key: rpm.signed_object_key.clone(),
-            tag: Some(rpm.tag.key().to_string())
+            tag: Some(record_key(&rpm.tag)),
+            sha256: rpm.sha256.clone(),
+            signed_sha256: rpm.signed_sha256.clone(),
+            signed_size: rpm.signed_size,
+            is_source: rpm.is_source,
+            source_rpm: rpm.source_rpm.clone(),
         }
     }
 }
@@ -106,6 +184,56 @@ impl From<&rpm::Dependency> for PkgDependency {
     }
 }
 
+/// An available package that would have unsatisfied dependencies if another package was removed
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Dependent {
+    pub rpm: RpmRef,
+    /// Requirements that would no longer be provided by any available package
+    pub broken_requires: Vec<String>,
+}
+
+/// Find packages in `available` whose requirements are only satisfied by `target`
+///
+/// Requirements are matched by name only, as version constraints are not stored.
+pub fn find_dependents(target: &Rpm, available: &[Rpm]) -> Vec<Dependent> {
+    let others = available.iter().filter(|p| p.id != target.id);
+
+    let removed: HashSet<&str> = target
+        .provides
+        .iter()
+        .map(|d| d.name.as_str())
+        .chain(std::iter::once(target.name.as_str()))
+        .collect();
+
+    let remaining: HashSet<&str> = others
+        .clone()
+        .flat_map(|p| {
+            p.provides
+                .iter()
+                .map(|d| d.name.as_str())
+                .chain(std::iter::once(p.name.as_str()))
+        })
+        .collect();
+
+    others
+        .filter_map(|pkg| {
+            let mut broken_requires = pkg
+                .requires
+                .iter()
+                .map(|d| d.name.as_str())
+                .filter(|name| removed.contains(name) && !remaining.contains(name))
+                .map(ToOwned::to_owned)
+                .collect::<Vec<_>>();
+            broken_requires.dedup();
+
+            (!broken_requires.is_empty()).then(|| Dependent {
+                rpm: pkg.into(),
+                broken_requires,
+            })
+        })
+        .collect()
+}
+
 // we want to replace the id field with a ulid, and the path to be a key to the object
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -133,14 +261,121 @@ pub struct Rpm {
     /// There should only be one package with the same name and architecture that has this flag set.
     // XXX: This flag also determines if the package should be available in a tag,
     // so to delist a package from a tag, we should set this to false.
-    available: bool,
+    pub(crate) available: bool,
+    /// A held package can't be superseded by newer packages of the same name and architecture,
+    /// and is never pruned by retention policies.
+    #[serde(default)]
+    pub held: bool,
+    /// The build this package was uploaded as part of, if any
+    #[serde(default)]
+    pub build: Option<RecordId>,
+    /// Hex-encoded SHA256 digest of the package file
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Hex-encoded SHA256 digest of the signed package file, set with [`Rpm::signed_object_key`]
+    #[serde(default)]
+    pub signed_sha256: Option<String>,
+    /// Size of the signed package file in bytes, set with [`Rpm::signed_object_key`]
+    #[serde(default)]
+    pub signed_size: Option<u64>,
+    /// Source package (SRPM), these are assembled into a separate `source` repo
+    #[serde(default)]
+    pub is_source: bool,
+    /// File name of the source package this package was built from, unset for source packages
+    #[serde(default)]
+    pub source_rpm: Option<String>,
+}
+
+/// NEVRA and tag filter for RPM listings, unset fields match everything
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RpmFilter {
+    pub name: Option<String>,
+    pub epoch: Option<u32>,
+    pub version: Option<String>,
+    pub release: Option<String>,
+    pub arch: Option<String>,
+    pub tag: Option<String>,
+}
+
+/// WHERE clause applying an [`RpmFilter`], with each field bound as a parameter
+const RPM_FILTER_CLAUSE: &str = "($name = NONE OR name = $name) \
+    AND ($epoch = NONE OR epoch = $epoch) \
+    AND ($version = NONE OR version = $version) \
+    AND ($release = NONE OR release = $release) \
+    AND ($arch = NONE OR arch = $arch) \
+    AND ($tag = NONE OR tag = $tag)";
+
+/// WHERE clause matching the packages available in `$tag`, whether uploaded to it or shared into it
+/// with a [`TagMembership`]
+pub const AVAILABLE_IN_TAG_CLAUSE: &str = "((tag = $tag AND available = true) \
+    OR id IN (SELECT VALUE rpm FROM tag_member WHERE tag = $tag AND available = true))";
+
+/// Result of a `SELECT count() ... GROUP ALL` query
+#[derive(Debug, Deserialize)]
+pub struct Count {
+    pub count: u64,
 }
 
-fn get_split_id_string(id: &str) -> String {
+pub fn get_split_id_string(id: &str) -> String {
     // split into a tree-like directory structure using first two chars
     format!("{}/{}/{}", &id[0..1], &id[1..2], id)
 }
 
+/// Hex-encoded SHA256 digest of a file, read in chunks
+pub fn file_sha256(path: &std::path::Path) -> color_eyre::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Architecture of a package as used in repos, `src` for source packages
+///
+/// SRPM headers carry the architecture they were built on, not `src`.
+pub fn package_arch(rpm: &PackageMetadata) -> color_eyre::Result<&str> {
+    if rpm.is_source_package() {
+        Ok("src")
+    } else {
+        Ok(rpm.get_arch()?)
+    }
+}
+
+/// A `name-[epoch:]version-release.arch` package identifier
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nevra {
+    pub name: String,
+    pub epoch: u32,
+    pub version: String,
+    pub release: String,
+    pub arch: String,
+}
+
+impl std::str::FromStr for Nevra {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || eyre!("invalid NEVRA: {s}");
+        let (nevr, arch) = s.rsplit_once('.').ok_or_else(invalid)?;
+        let (nev, release) = nevr.rsplit_once('-').ok_or_else(invalid)?;
+        let (name, ev) = nev.rsplit_once('-').ok_or_else(invalid)?;
+        let (epoch, version) = match ev.split_once(':') {
+            Some((epoch, version)) => (epoch.parse().map_err(|_| invalid())?, version),
+            None => (0, ev),
+        };
+        if [name, version, release, arch].iter().any(|part| part.is_empty()) {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+            epoch,
+            version: version.to_owned(),
+            release: release.to_owned(),
+            arch: arch.to_owned(),
+        })
+    }
+}
+
 fn get_rpm_path(name: &str, epoch: u32, version: &str, release: &str, arch: &str) -> String {
     format!("{name}-{epoch}:{version}-{release}.{arch}.rpm")
 }
@@ -156,16 +391,51 @@ fn rpm_object_key(id: String, rpm: &PackageMetadata) -> (String, String) {
     let epoch = rpm.get_epoch().unwrap_or_default();
     let version = rpm.get_version().unwrap();
     let release = rpm.get_release().unwrap();
-    let arch = rpm.get_arch().unwrap();
+    let arch = package_arch(rpm).unwrap();
 
     let rpm_path = get_rpm_path(name, epoch, version, release, arch);
-    let object_key = format!("{RPM_PREFIX}/{id_string}/{rpm_path}");
-    let signed_key = format!("{RPM_PREFIX}/{id_string}/signed/{rpm_path}");
+    let object_key = crate::namespace::object_key(format!("{RPM_PREFIX}/{id_string}/{rpm_path}"));
+    let signed_key =
+        crate::namespace::object_key(format!("{RPM_PREFIX}/{id_string}/signed/{rpm_path}"));
 
     (object_key, signed_key)
 }
 
+/// Generate a new object key for a signed copy of an RPM object
+///
+/// Every signing gets its own key, so signing again never overwrites a signed object
+/// that published repos may still refer to.
+fn signed_object_key(id: String, rpm: &PackageMetadata) -> String {
+    let (_, signed_key) = rpm_object_key(id, rpm);
+    let (dir, file) = signed_key.rsplit_once('/').unwrap_or(("", &signed_key));
+    format!("{dir}/{}/{file}", ulid::Ulid::new())
+}
+
+/// Generate a content-addressed object key for an RPM object, from the SHA256 digest of the package
+///
+/// Packages with the same contents share the same key, regardless of which tag they are in.
+fn rpm_content_key(sha256: &str, rpm: &PackageMetadata) -> String {
+    let rpm_path = get_rpm_path(
+        rpm.get_name().unwrap(),
+        rpm.get_epoch().unwrap_or_default(),
+        rpm.get_version().unwrap(),
+        rpm.get_release().unwrap(),
+        package_arch(rpm).unwrap(),
+    );
+    let key = format!("{RPM_PREFIX}/sha256/{}/{rpm_path}", get_split_id_string(sha256));
+    crate::namespace::object_key(key)
+}
+
+fn content_addressed() -> bool {
+    CONFIG.get().is_some_and(|c| c.content_addressed_objects)
+}
+
 impl Rpm {
+    /// Object key of the package file to publish, like [`RpmRef::staged_object_key`]
+    pub fn staged_object_key(&self) -> &str {
+        self.signed_object_key.as_deref().unwrap_or(&self.object_key)
+    }
+
     pub fn new(pkg_meta: PackageMetadata, tag: &str) -> color_eyre::Result<Self> {
         let id = Thing::from((RPM_TABLE, surrealdb::sql::Id::ulid()));
 
@@ -173,7 +443,13 @@ impl Rpm {
         let name = pkg_meta.get_name()?.to_owned();
         let version = pkg_meta.get_version()?.to_owned();
         let release = pkg_meta.get_release()?.to_owned();
-        let arch = pkg_meta.get_arch()?.to_owned();
+        let arch = package_arch(&pkg_meta)?.to_owned();
+        let is_source = pkg_meta.is_source_package();
+        let source_rpm = pkg_meta
+            .get_source_rpm()
+            .ok()
+            .filter(|srpm| !srpm.is_empty())
+            .map(ToOwned::to_owned);
         let provides = pkg_meta
             .get_provides()?
             .iter()
@@ -202,39 +478,383 @@ impl Rpm {
             tag: RecordId::from_table_key(TAG_TABLE, tag),
             timestamp: chrono::Utc::now().into(),
             available: false,
+            held: false,
+            build: None,
+            sha256: None,
+            signed_sha256: None,
+            signed_size: None,
+            is_source,
+            source_rpm,
         })
     }
     pub fn from_path(path: impl AsRef<std::path::Path>, tag: &str) -> color_eyre::Result<Self> {
         let pkg = rpm::Package::open(path.as_ref())?;
-        Self::new(pkg.metadata, tag)
+        let sha256 = file_sha256(path.as_ref())?;
+        let object_key = content_addressed().then(|| rpm_content_key(&sha256, &pkg.metadata));
+        let mut rpm = Self::new(pkg.metadata, tag)?;
+        if let Some(object_key) = object_key {
+            rpm.object_key = object_key;
+        }
+        rpm.sha256 = Some(sha256);
+        Ok(rpm)
     }
 
-    /// Mark this package as the latest package, and unmark every package with the same name + architecture
-    /// as not the latest package.
-    pub async fn mark_available(&self) -> color_eyre::Result<Self> {
-        // query all packages with the same name, architecture, and tag
-        // and mark them as not the latest package
+    /// Full `name-epoch:version-release.arch` of the package
+    pub fn nevra(&self) -> String {
+        format!(
+            "{}-{}:{}-{}.{}",
+            self.name, self.epoch, self.version, self.release, self.arch
+        )
+    }
 
-        DB.query("BEGIN;")
-        .query("UPDATE rpm_package SET available = false WHERE name = $name AND arch = $arch AND tag = $tag;")
-        .query("UPDATE rpm_package SET available = true WHERE id = $id;")
-        .query("COMMIT;")
-        .bind(("name", self.name.clone()))
-        .bind(("arch", self.arch.clone()))
-        .bind(("tag", self.tag.clone()))
-        .bind(("id", self.id.clone()))
-        .await?;
+    /// Find the earliest uploaded package with a NEVRA, in any tag
+    pub async fn get_by_nevra(nevra: &Nevra) -> color_eyre::Result<Option<Self>> {
+        let a: Option<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM rpm_package WHERE name = $name AND epoch = $epoch AND version = $version AND release = $release AND arch = $arch ORDER BY id LIMIT 1;")
+                    .bind(("name", nevra.name.clone()))
+                    .bind(("epoch", nevra.epoch))
+                    .bind(("version", nevra.version.clone()))
+                    .bind(("release", nevra.release.clone()))
+                    .bind(("arch", nevra.arch.clone()))
+            })
+            .await?
+            .take(0)?;
 
-        let mut new_entry = self.clone();
-        new_entry.available = true;
+        Ok(a)
+    }
+
+    /// Find this package, or another upload of the same file, among the packages uploaded to
+    /// or shared into a tag, available or not
+    pub async fn find_in_tag(&self, tag: &str) -> color_eyre::Result<Option<Self>> {
         let a: Option<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM rpm_package WHERE (id = $id OR (sha256 != NONE AND sha256 = $sha256 AND name = $name AND epoch = $epoch AND version = $version AND release = $release AND arch = $arch)) AND (tag = $tag OR id IN (SELECT VALUE rpm FROM tag_member WHERE tag = $tag)) LIMIT 1;")
+                    .bind(("id", self.id.clone()))
+                    .bind(("sha256", self.sha256.clone()))
+                    .bind(("name", self.name.clone()))
+                    .bind(("epoch", self.epoch))
+                    .bind(("version", self.version.clone()))
+                    .bind(("release", self.release.clone()))
+                    .bind(("arch", self.arch.clone()))
+                    .bind(("tag", RecordId::from_table_key(TAG_TABLE, tag)))
+            })
+            .await?
+            .take(0)?;
+
+        Ok(a)
+    }
+
+    /// Find a package in this tag with the same NEVRA and digest, i.e. an earlier upload of the same file
+    pub async fn find_duplicate(&self) -> color_eyre::Result<Option<Self>> {
+        if self.sha256.is_none() {
+            return Ok(None);
+        }
+        let a: Option<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM rpm_package WHERE sha256 = $sha256 AND name = $name AND epoch = $epoch AND version = $version AND release = $release AND arch = $arch AND tag = $tag AND id != $id LIMIT 1;")
+                    .bind(("sha256", self.sha256.clone()))
+                    .bind(("name", self.name.clone()))
+                    .bind(("epoch", self.epoch))
+                    .bind(("version", self.version.clone()))
+                    .bind(("release", self.release.clone()))
+                    .bind(("arch", self.arch.clone()))
+                    .bind(("tag", self.tag.clone()))
+                    .bind(("id", self.id.clone()))
+            })
+            .await?
+            .take(0)?;
+
+        Ok(a)
+    }
+
+    /// Find an available, held package with the same name + architecture in this tag,
+    /// which would prevent this package from becoming available
+    pub async fn held_sibling(&self) -> color_eyre::Result<Option<Self>> {
+        self.held_sibling_in(&self.tag).await
+    }
+
+    /// Like [`Rpm::held_sibling`], in any tag this package is or could be shared into
+    pub async fn held_sibling_in(&self, tag: &RecordId) -> color_eyre::Result<Option<Self>> {
+        let a: Option<Self> = DB
+            .retry(|| {
+                DB.query(format!("SELECT * FROM rpm_package WHERE name = $name AND arch = $arch AND held = true AND id != $id AND {AVAILABLE_IN_TAG_CLAUSE} LIMIT 1;"))
+                    .bind(("name", self.name.clone()))
+                    .bind(("arch", self.arch.clone()))
+                    .bind(("tag", tag.clone()))
+                    .bind(("id", self.id.clone()))
+            })
+            .await?
+            .take(0)?;
+
+        Ok(a)
+    }
+
+    /// Compare the epoch:version-release of two packages
+    pub fn evr_cmp(&self, other: &Self) -> std::cmp::Ordering {
+        evr_cmp(
+            (self.epoch, &self.version, &self.release),
+            (other.epoch, &other.version, &other.release),
+        )
+    }
+
+    /// Fetch the other available packages with the same name + architecture in a tag
+    pub async fn available_siblings_in(&self, tag: &RecordId) -> color_eyre::Result<Vec<Self>> {
+        let siblings: Vec<Self> = DB
+            .retry(|| {
+                DB.query(format!("SELECT * FROM rpm_package WHERE name = $name AND arch = $arch AND id != $id AND {AVAILABLE_IN_TAG_CLAUSE};"))
+                    .bind(("name", self.name.clone()))
+                    .bind(("arch", self.arch.clone()))
+                    .bind(("tag", tag.clone()))
+                    .bind(("id", self.id.clone()))
+            })
+            .await?
+            .take(0)?;
+
+        Ok(siblings)
+    }
+
+    /// Find an available package with the same name + architecture in this tag
+    /// that has a newer epoch:version-release than this one
+    pub async fn newer_sibling(&self) -> color_eyre::Result<Option<Self>> {
+        self.newer_sibling_in(&self.tag).await
+    }
+
+    /// Like [`Rpm::newer_sibling`], in any tag this package is or could be shared into
+    pub async fn newer_sibling_in(&self, tag: &RecordId) -> color_eyre::Result<Option<Self>> {
+        Ok(self
+            .available_siblings_in(tag)
+            .await?
+            .into_iter()
+            .find(|s| s.evr_cmp(self) == std::cmp::Ordering::Greater))
+    }
+
+    /// Find available packages in a tag that would break if this package was removed
+    /// or marked unavailable
+    pub async fn dependents(&self, tag: &Tag) -> color_eyre::Result<Vec<Dependent>> {
+        let available = tag.get_available_rpms().await?;
+        Ok(find_dependents(self, &available))
+    }
+
+    /// Hold or release this package, see [`Rpm::held`]
+    pub async fn set_held(&self, held: bool) -> color_eyre::Result<Self> {
+        let res: Option<Self> = DB
             .update((RPM_TABLE, self.id.id.to_raw()))
-            .content(new_entry)
+            .content(Rpm {
+                held,
+                ..self.clone()
+            })
+            .await?;
+
+        res.ok_or_else(|| eyre!("failed to update entry"))
+    }
+
+    /// Mark this package as the latest package, and unmark every package with the same name + architecture
+    /// as not the latest package.
+    ///
+    /// Fails if another package with the same name + architecture is held, or, unless `force` is set,
+    /// if an already available package has a newer epoch:version-release.
+    pub async fn mark_available(&self, force: bool) -> color_eyre::Result<Self> {
+        self.check_available(force).await?;
+        self.supersede_siblings_in(&self.tag).await?;
+
+        let a: Option<Self> = DB
+            .retry(|| DB.select((RPM_TABLE, self.id.id.to_raw())))
             .await?;
-        self.id.id.to_raw();
         a.ok_or_else(|| eyre!("failed to update entry"))
     }
 
+    /// Like [`Rpm::mark_available`], in a tag the package was uploaded to or shared into
+    pub async fn mark_available_in(&self, tag: &str, force: bool) -> color_eyre::Result<()> {
+        if tag == record_key(&self.tag) {
+            self.mark_available(force).await?;
+            return Ok(());
+        }
+        TagMembership::get(self, tag)
+            .await?
+            .ok_or_else(|| eyre!("{} is not in {tag}", self.name))?;
+
+        let tag_id = RecordId::from_table_key(TAG_TABLE, tag);
+        self.check_available_in(&tag_id, force).await?;
+        self.supersede_siblings_in(&tag_id).await
+    }
+
+    /// Fail if [`Rpm::mark_available`] would, without changing anything
+    pub async fn check_available(&self, force: bool) -> color_eyre::Result<()> {
+        self.check_available_in(&self.tag, force).await
+    }
+
+    /// Fail if a held or (without `force`) newer sibling should stay the latest in a tag
+    async fn check_available_in(&self, tag: &RecordId, force: bool) -> color_eyre::Result<()> {
+        if let Some(held) = self.held_sibling_in(tag).await? {
+            return Err(eyre!(
+                "{} is held by {}, not marking as available",
+                self.name,
+                held.id.id.to_raw()
+            ));
+        }
+
+        if !force {
+            if let Some(newer) = self.newer_sibling_in(tag).await? {
+                return Err(eyre!(
+                    "{} has a newer version available ({}:{}-{}), not marking as available",
+                    self.name,
+                    newer.epoch,
+                    newer.version,
+                    newer.release
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark this package available in a tag, and its available siblings there unavailable,
+    /// keeping the tag's `keep_versions` newest versions (counting this package) and held packages
+    ///
+    /// Both happen in one transaction, so the tag never has none or both of them available.
+    async fn supersede_siblings_in(&self, tag: &RecordId) -> color_eyre::Result<()> {
+        let (own, shared) = self.superseded_siblings_in(tag).await?;
+
+        DB.query("BEGIN;")
+            .query("UPDATE rpm_package SET available = false WHERE id IN $own;")
+            .query("UPDATE tag_member SET available = false WHERE tag = $tag AND rpm IN $shared;")
+            .query(self.mark_available_query(tag, "rpm"))
+            .query("COMMIT;")
+            .bind(("own", own))
+            .bind(("shared", shared))
+            .bind(("tag", tag.clone()))
+            .bind((
+                "rpm",
+                RecordId::from_table_key(RPM_TABLE, self.id.id.to_raw()),
+            ))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    /// Siblings [`Rpm::supersede_siblings_in`] marks unavailable, as the IDs of packages
+    /// uploaded to the tag and of packages shared into it
+    async fn superseded_siblings_in(
+        &self,
+        tag: &RecordId,
+    ) -> color_eyre::Result<(Vec<Thing>, Vec<Thing>)> {
+        let tag_entry: Option<Tag> = DB.retry(|| DB.select(tag.clone())).await?;
+        let keep_versions = tag_entry.map_or(1, |t| t.keep_versions.max(1)) as usize;
+
+        let mut siblings = self.available_siblings_in(tag).await?;
+        siblings.sort_by(|a, b| b.evr_cmp(a));
+        // packages shared into the tag only lose their availability in that tag
+        let (own, shared): (Vec<_>, Vec<_>) = siblings
+            .into_iter()
+            .filter(|s| !s.held)
+            .skip(keep_versions - 1)
+            .map(|s| (s.tag == *tag, s.id))
+            .partition(|(own, _)| *own);
+        let own: Vec<Thing> = own.into_iter().map(|(_, id)| id).collect();
+        let shared: Vec<Thing> = shared.into_iter().map(|(_, id)| id).collect();
+
+        tracing::debug!(?own, ?shared, keep_versions, "marking old versions unavailable");
+        Ok((own, shared))
+    }
+
+    /// Share this package into another tag, without storing it again
+    ///
+    /// It's marked available there unless a held or newer (without `force`) sibling
+    /// should stay the latest. Fails if the package, or another upload of the same file,
+    /// is already in that tag, see [`Rpm::find_in_tag`].
+    pub async fn add_to_tag(&self, tag: &str, force: bool) -> color_eyre::Result<TagMembership> {
+        if let Some(existing) = self.find_in_tag(tag).await? {
+            return Err(eyre!(
+                "{} is already in {tag} as {}",
+                self.name,
+                existing.id.id.to_raw()
+            ));
+        }
+
+        let mut membership = TagMembership::new(self, tag).save().await?;
+        let tag_id = RecordId::from_table_key(TAG_TABLE, tag);
+        if let Err(e) = self.check_available_in(&tag_id, force).await {
+            tracing::info!(tag, "{e}");
+        } else {
+            self.supersede_siblings_in(&tag_id).await?;
+            membership.available = true;
+        }
+        Ok(membership)
+    }
+
+    /// Like [`Rpm::mark_unavailable`], in a tag the package was uploaded to or shared into
+    pub async fn mark_unavailable_in(&self, tag: &str) -> color_eyre::Result<()> {
+        if tag == record_key(&self.tag) {
+            self.mark_unavailable().await?;
+            return Ok(());
+        }
+        TagMembership::get(self, tag)
+            .await?
+            .ok_or_else(|| eyre!("{} is not in {tag}", self.name))?
+            .set_available(false)
+            .await
+    }
+
+    /// Remove this package from a tag, keeping it in every other tag it's in
+    ///
+    /// Removing a package from the tag it was uploaded to moves it to the oldest tag it was
+    /// shared into instead. Fails if the package is in no other tag, it should be deleted then.
+    /// If it was the only available version in the tag, the newest version left there is
+    /// marked available in the same transaction.
+    pub async fn remove_from_tag(&self, tag: &str) -> color_eyre::Result<Self> {
+        let tag_id = RecordId::from_table_key(TAG_TABLE, tag);
+        let memberships = TagMembership::get_by_rpm(self).await?;
+
+        let mut query = DB.query("BEGIN;");
+        let available = if tag != record_key(&self.tag) {
+            let membership = memberships
+                .into_iter()
+                .find(|m| m.tag == tag_id)
+                .ok_or_else(|| eyre!("{} is not in {tag}", self.name))?;
+            query = query.query("DELETE $membership;").bind((
+                "membership",
+                RecordId::from_table_key(MEMBERSHIP_TABLE, membership.id.id.to_raw()),
+            ));
+            membership.available
+        } else {
+            let next = memberships
+                .into_iter()
+                .next()
+                .ok_or_else(|| eyre!("{} is in no other tag", self.name))?;
+            query = query
+                .query("UPDATE $rpm SET tag = $next_tag, available = $next_available;")
+                .query("DELETE $membership;")
+                .bind((
+                    "rpm",
+                    RecordId::from_table_key(RPM_TABLE, self.id.id.to_raw()),
+                ))
+                .bind(("next_tag", next.tag.clone()))
+                .bind(("next_available", next.available))
+                .bind((
+                    "membership",
+                    RecordId::from_table_key(MEMBERSHIP_TABLE, next.id.id.to_raw()),
+                ));
+            self.available
+        };
+
+        if let Some(successor) = self.successor_in(&tag_id, available).await? {
+            tracing::debug!(successor = ?successor.id, tag, "marking successor available");
+            query = query
+                .query(successor.mark_available_query(&tag_id, "successor"))
+                .bind(("tag", tag_id.clone()))
+                .bind((
+                    "successor",
+                    RecordId::from_table_key(RPM_TABLE, successor.id.id.to_raw()),
+                ));
+        }
+        query.query("COMMIT;").await?.check()?;
+
+        let id = Ulid::from_string(&self.id.id.to_raw())?;
+        Self::get(id)
+            .await?
+            .ok_or_else(|| eyre!("package disappeared"))
+    }
+
     pub async fn mark_unavailable(&self) -> color_eyre::Result<Self> {
         let mut new_entry = self.clone();
         new_entry.available = false;
@@ -249,18 +869,57 @@ impl Rpm {
 
     pub async fn delete(&self) -> color_eyre::Result<()> {
         let a: Option<Self> = DB.delete((RPM_TABLE, self.id.id.to_raw())).await?;
+        TagMembership::delete_by_rpm(self).await?;
 
         tracing::debug!("deleted from db: {:#?}", a);
 
-        // Delete artifact
+        // Delete artifact, unless another package still shares it
+        self.release_object().await
+    }
+
+    /// Take a reference on this package's object, returning whether it's the first one
+    ///
+    /// Only content-addressed objects are shared between packages, the first reference
+    /// has to store the object. The count is changed in one statement,
+    /// so concurrent uploads and deletes of packages sharing an object can't miss each other.
+    pub async fn acquire_object(&self) -> color_eyre::Result<bool> {
+        let before: Option<Count> = DB
+            .query("UPSERT type::thing($table, $key) SET count += 1 RETURN BEFORE;")
+            .bind(("table", OBJECT_REF_TABLE))
+            .bind(("key", self.object_key.clone()))
+            .await?
+            .take(0)?;
 
-        object_store().remove(&self.object_key).await?;
+        Ok(before.is_none_or(|c| c.count == 0))
+    }
 
+    /// Drop a reference on this package's object, removing the object with the last one
+    pub async fn release_object(&self) -> color_eyre::Result<()> {
+        // decrementing and dropping the last reference in one transaction,
+        // so only one caller sees the count reach zero
+        let mut res = DB
+            .query("BEGIN;")
+            .query("UPDATE type::thing($table, $key) SET count -= 1;")
+            .query("DELETE type::thing($table, $key) WHERE count <= 0 RETURN BEFORE;")
+            .query("COMMIT;")
+            .bind(("table", OBJECT_REF_TABLE))
+            .bind(("key", self.object_key.clone()))
+            .await?;
+        let last: Vec<Count> = res.take(1)?;
+
+        if last.is_empty() {
+            tracing::debug!(key = ?self.object_key, "object still referenced, keeping it");
+        } else {
+            object_store().remove(&self.object_key).await?;
+        }
         Ok(())
     }
 
     /// Commits the RPM object to the database, optionally marking it as the latest version in that tag
-    pub async fn commit_to_db(&self, latest: bool) -> color_eyre::Result<()> {
+    ///
+    /// The package is only marked as latest if no newer version is already available,
+    /// unless `force` is set.
+    pub async fn commit_to_db(&self, latest: bool, force: bool) -> color_eyre::Result<()> {
         trace!("committing to db");
         // insert into db
         let a: Option<Self> = DB
@@ -269,77 +928,360 @@ impl Rpm {
             .content(self.clone())
             .await?;
 
+        tracing::trace!("inserted into db: {:#?}", a);
+
         if latest {
-            tracing::debug!("marking as latest");
-            self.mark_available().await?;
+            self.mark_latest_upload(force).await?;
         }
 
-        tracing::trace!("inserted into db: {:#?}", a);
+        Ok(())
+    }
 
-        // if latest {
-        //     return self.mark_one_latest().await;
-        // }
+    /// Insert several new packages in a single transaction
+    ///
+    /// With `latest`, the packages are marked available like [`Rpm::mark_latest_upload`]
+    /// in the same transaction, superseding their siblings. Only the newest of several
+    /// uploads of one package is marked. Either every change is made or none are.
+    pub async fn insert_all(rpms: &[Self], latest: bool, force: bool) -> color_eyre::Result<()> {
+        if rpms.is_empty() {
+            return Ok(());
+        }
+
+        let mut query = DB
+            .query("BEGIN;")
+            .query("INSERT INTO rpm_package $rpms;")
+            .bind(("rpms", rpms.to_vec()));
+        if latest {
+            let newest = rpms.iter().filter(|rpm| {
+                !rpms.iter().any(|other| {
+                    other.name == rpm.name
+                        && other.arch == rpm.arch
+                        && other.evr_cmp(rpm) == std::cmp::Ordering::Greater
+                })
+            });
+            for (i, rpm) in newest.enumerate() {
+                if !rpm.should_be_latest(force).await? {
+                    continue;
+                }
+                let (own, shared) = rpm.superseded_siblings_in(&rpm.tag).await?;
+                query = query
+                    .query(format!("UPDATE rpm_package SET available = false WHERE id IN $own{i};"))
+                    .query(format!(
+                        "UPDATE tag_member SET available = false WHERE tag = $tag{i} AND rpm IN $shared{i};"
+                    ))
+                    .query(format!("UPDATE $rpm{i} SET available = true;"))
+                    .bind((format!("own{i}"), own))
+                    .bind((format!("shared{i}"), shared))
+                    .bind((format!("tag{i}"), rpm.tag.clone()))
+                    .bind((
+                        format!("rpm{i}"),
+                        RecordId::from_table_key(RPM_TABLE, rpm.id.id.to_raw()),
+                    ));
+            }
+        }
+        query.query("COMMIT;").await?.check()?;
+        Ok(())
+    }
 
+    /// Mark a freshly uploaded package as available, unless a held or newer
+    /// (without `force`) sibling should stay the latest
+    pub async fn mark_latest_upload(&self, force: bool) -> color_eyre::Result<()> {
+        if self.should_be_latest(force).await? {
+            tracing::debug!("marking as latest");
+            self.mark_available(true).await?;
+        }
         Ok(())
     }
 
+    /// Whether [`Rpm::mark_latest_upload`] marks this package available
+    async fn should_be_latest(&self, force: bool) -> color_eyre::Result<bool> {
+        if let Some(held) = self.held_sibling().await? {
+            tracing::info!(held = ?held.id, "package is held, not marking upload as latest");
+            Ok(false)
+        } else if let Some(newer) = self.newer_sibling().await?.filter(|_| !force) {
+            tracing::info!(newer = ?newer.id, "newer version already available, not marking upload as latest");
+            Ok(false)
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// Move this package into another tag, marking it available there unless a held or newer
+    /// (without `force`) sibling should stay the latest
+    ///
+    /// If this was the only available version in its old tag, the newest version left there
+    /// is marked available in the same transaction, so the old tag keeps providing the package.
+    /// Callers check for the same file in the target tag with [`Rpm::find_duplicate`] first.
+    pub async fn promote(&self, tag: &str, force: bool) -> color_eyre::Result<Self> {
+        let promoted = Rpm {
+            tag: RecordId::from_table_key(TAG_TABLE, tag),
+            available: false,
+            held: false,
+            ..self.clone()
+        };
+
+        let successor = self.successor_in(&self.tag, self.available).await?;
+        tracing::debug!(successor = ?successor.as_ref().map(|s| &s.id), "promoting package");
+
+        let mut query = DB
+            .query("BEGIN;")
+            .query("UPDATE $rpm CONTENT $promoted;")
+            .bind((
+                "rpm",
+                RecordId::from_table_key(RPM_TABLE, self.id.id.to_raw()),
+            ))
+            .bind(("promoted", promoted));
+        if let Some(successor) = successor {
+            query = query
+                .query(successor.mark_available_query(&self.tag, "successor"))
+                .bind(("tag", self.tag.clone()))
+                .bind((
+                    "successor",
+                    RecordId::from_table_key(RPM_TABLE, successor.id.id.to_raw()),
+                ));
+        }
+        query.query("COMMIT;").await?.check()?;
+
+        let id = Ulid::from_string(&self.id.id.to_raw())?;
+        let promoted = Self::get(id)
+            .await?
+            .ok_or_else(|| eyre!("promoted package disappeared"))?;
+        promoted.mark_latest_upload(force).await?;
+        Self::get(id)
+            .await?
+            .ok_or_else(|| eyre!("promoted package disappeared"))
+    }
+
+    /// Find the sibling to mark available in a tag when this package leaves it, so the tag
+    /// keeps providing the package: the newest other version in the tag, if this one was
+    /// `available` there and no other version is
+    async fn successor_in(
+        &self,
+        tag: &RecordId,
+        available: bool,
+    ) -> color_eyre::Result<Option<Self>> {
+        if !available || !self.available_siblings_in(tag).await?.is_empty() {
+            return Ok(None);
+        }
+        let siblings: Vec<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM rpm_package WHERE name = $name AND arch = $arch AND id != $id AND (tag = $tag OR id IN (SELECT VALUE rpm FROM tag_member WHERE tag = $tag));")
+                    .bind(("name", self.name.clone()))
+                    .bind(("arch", self.arch.clone()))
+                    .bind(("tag", tag.clone()))
+                    .bind(("id", self.id.clone()))
+            })
+            .await?
+            .take(0)?;
+
+        Ok(siblings.into_iter().max_by(|a, b| a.evr_cmp(b)))
+    }
+
+    /// Statement marking this package, bound to `$<var>`, available in `$tag`
+    fn mark_available_query(&self, tag: &RecordId, var: &str) -> String {
+        if *tag == self.tag {
+            format!("UPDATE ${var} SET available = true;")
+        } else {
+            format!("UPDATE tag_member SET available = true WHERE tag = $tag AND rpm = ${var};")
+        }
+    }
+
     /// Fetches the RPM object from the database
     #[tracing::instrument]
     pub async fn get(id: ulid::Ulid) -> color_eyre::Result<Option<Self>> {
-        let a: Option<Self> = DB.get().select((RPM_TABLE, id.to_string())).await?;
+        let a: Option<Self> = DB
+            .retry(|| async move { DB.select((RPM_TABLE, id.to_string())).await })
+            .await?;
 
         tracing::trace!(item = ?a, "got from db");
 
-        Ok(a)
+        match a {
+            Some(rpm) if Tag::visible(&record_key(&rpm.tag)).await? => Ok(Some(rpm)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Fetches a page of RPM objects matching a filter, along with the total number of matches
+    ///
+    /// Every match from `offset` on is returned without a `limit`.
+    pub async fn get_page(
+        filter: &RpmFilter,
+        limit: Option<u32>,
+        offset: u32,
+    ) -> color_eyre::Result<(Vec<Self>, u64)> {
+        let limit_clause = if limit.is_some() { "LIMIT $limit" } else { "" };
+        let mut query = DB
+            .retry(|| {
+                DB.query(format!(
+                    "SELECT * FROM rpm_package WHERE {RPM_FILTER_CLAUSE} ORDER BY id {limit_clause} START $offset;"
+                ))
+                .query(format!(
+                    "SELECT count() FROM rpm_package WHERE {RPM_FILTER_CLAUSE} GROUP ALL;"
+                ))
+                .bind(("name", filter.name.clone()))
+                .bind(("epoch", filter.epoch))
+                .bind(("version", filter.version.clone()))
+                .bind(("release", filter.release.clone()))
+                .bind(("arch", filter.arch.clone()))
+                .bind((
+                    "tag",
+                    filter
+                        .tag
+                        .as_ref()
+                        .map(|t| RecordId::from_table_key(TAG_TABLE, t)),
+                ))
+                .bind(("limit", limit))
+                .bind(("offset", offset))
+            })
+            .await?;
+
+        let page: Vec<Self> = query.take(0)?;
+        let total: Option<Count> = query.take(1)?;
+
+        Ok((page, total.map_or(0, |c| c.count)))
     }
 
     pub async fn get_all() -> color_eyre::Result<Vec<Self>> {
-        let a: Vec<Self> = DB.get().select(RPM_TABLE).await?;
+        let a: Vec<Self> = DB.retry(|| DB.select(RPM_TABLE)).await?;
 
         tracing::info!("got from db: {:#?}", a);
 
         Ok(a)
     }
 
-    pub async fn sign(&self, key: GpgKey) -> color_eyre::Result<Self> {
-        tracing::debug!("signing rpm");
-        let object_file = object_store().get(&self.object_key).await?;
-        tracing::trace!("got object file: {:?}", object_file);
+    /// Fetches every RPM object belonging to a tag, available or not
+    pub async fn get_by_tag(tag: &str) -> color_eyre::Result<Vec<Self>> {
+        let a: Vec<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM rpm_package WHERE tag = $tag;")
+                    .bind(("tag", RecordId::from_table_key(TAG_TABLE, tag)))
+            })
+            .await?
+            .take(0)?;
 
-        let signer = rpm::signature::pgp::Signer::load_from_asc(&key.secret_key)?;
-        tracing::trace!(?signer, "loaded signer");
+        Ok(a)
+    }
 
-        tracing::trace!("opening rpm");
-        let mut rpm = rpm::Package::open(object_file)?;
+    /// Re-extract the package metadata from the stored object, using the current parsing code
+    ///
+    /// Only metadata fields are updated, the identity, object keys, tag and availability
+    /// of the package are kept as-is.
+    #[tracing::instrument(skip(self), fields(id = %self.id))]
+    pub async fn reindex(&self) -> color_eyre::Result<Self> {
+        let object_file = object_store()
+            .get_verified(&self.object_key, self.sha256.as_deref())
+            .await?;
+        let fresh = Rpm::from_path(object_file, &record_key(&self.tag))?;
 
-        tracing::trace!("signing rpm");
-        rpm.sign(&signer)?;
+        let updated = Rpm {
+            id: self.id.clone(),
+            object_key: self.object_key.clone(),
+            signed_object_key: self.signed_object_key.clone(),
+            tag: self.tag.clone(),
+            timestamp: self.timestamp.clone(),
+            available: self.available,
+            held: self.held,
+            build: self.build.clone(),
+            ..fresh
+        };
 
-        // write the signed rpm to the object store
-        let mut buf = Vec::new();
-        tracing::trace!("writing signed rpm to buffer");
-        rpm.write(&mut buf)?;
+        let res: Option<Self> = DB
+            .update((RPM_TABLE, self.id.id.to_raw()))
+            .content(updated)
+            .await?;
 
-        let signed_key = self.signed_object_key.clone().unwrap_or_else(|| {
-            let (_, signed_key) = rpm_object_key(self.id.id.to_raw(), &rpm.metadata);
-            signed_key
-        });
+        res.ok_or_else(|| eyre!("failed to update entry"))
+    }
 
-        tracing::trace!("putting signed rpm in object store");
-        object_store().put_bytes(&signed_key, buf).await?;
+    pub async fn sign(&self, key: GpgKey) -> color_eyre::Result<Self> {
+        let signed = self.sign_object(key).await?;
 
         tracing::trace!("updating db with signed key");
         let res: Option<Self> = DB
             .update((RPM_TABLE, self.id.id.to_raw()))
-            .content(Rpm {
-                signed_object_key: Some(signed_key),
-                ..self.clone()
-            })
+            .content(signed)
+            .await?;
+        let res = res.ok_or_else(|| eyre!("failed to update entry"))?;
+
+        if let Some(old) = &self.signed_object_key {
+            self.remove_signed_object(old).await;
+        }
+        Ok(res)
+    }
+
+    /// Point packages at the signed objects written by [`Rpm::sign_object`], in one transaction
+    ///
+    /// Also restores the previous signatures when given the packages as they were before.
+    pub async fn save_signed(pkgs: &[Rpm]) -> color_eyre::Result<()> {
+        let mut query = DB.query("BEGIN;");
+        for (i, pkg) in pkgs.iter().enumerate() {
+            query = query
+                .query(format!(
+                    "UPDATE $pkg{i} SET signed_object_key = $key{i}, signed_sha256 = $sha256{i}, signed_size = $size{i};"
+                ))
+                .bind((
+                    format!("pkg{i}"),
+                    RecordId::from_table_key(RPM_TABLE, pkg.id.id.to_raw()),
+                ))
+                .bind((format!("key{i}"), pkg.signed_object_key.clone()))
+                .bind((format!("sha256{i}"), pkg.signed_sha256.clone()))
+                .bind((format!("size{i}"), pkg.signed_size));
+        }
+        query.query("COMMIT;").await?.check()?;
+        Ok(())
+    }
+
+    /// Remove a signed object that was replaced, only logging failures
+    pub async fn remove_signed_object(&self, key: &str) {
+        if let Err(e) = object_store().remove(key).await {
+            tracing::warn!(rpm = ?self.id, key, ?e, "failed to remove old signed object");
+        }
+    }
+
+    /// Write the signed package to a new object, returning the entry pointing at it
+    ///
+    /// The entry isn't updated in the database, see [`Rpm::sign`].
+    pub async fn sign_object(&self, key: GpgKey) -> color_eyre::Result<Self> {
+        tracing::debug!("signing rpm");
+        let object_file = object_store()
+            .get_verified(&self.object_key, self.sha256.as_deref())
             .await?;
+        tracing::trace!("got object file: {:?}", object_file);
+
+        // signing is CPU-bound, keep it off the async runtime
+        let signer = key.signer()?;
+        let runtime = tokio::runtime::Handle::current();
+        let (buf, metadata) = tokio::task::spawn_blocking(move || {
+            let signer = BlockingSigner::new(signer, runtime);
+            tracing::trace!(?signer, "loaded signer");
+
+            tracing::trace!("opening rpm");
+            let mut rpm = rpm::Package::open(object_file)?;
+
+            tracing::trace!("signing rpm");
+            rpm.sign(&signer)?;
+
+            // write the signed rpm to the object store
+            let mut buf = Vec::new();
+            tracing::trace!("writing signed rpm to buffer");
+            rpm.write(&mut buf)?;
+            color_eyre::Result::<_>::Ok((buf, rpm.metadata))
+        })
+        .await??;
 
-        Ok(res.ok_or_else(|| eyre!("failed to update entry"))?)
+        let signed_key = signed_object_key(self.id.id.to_raw(), &metadata);
+        let signed_sha256 = hex::encode(Sha256::digest(&buf));
+        let signed_size = buf.len() as u64;
 
-        // todo!()
+        tracing::trace!("putting signed rpm in object store");
+        object_store().put_bytes(&signed_key, buf).await?;
+
+        Ok(Rpm {
+            signed_object_key: Some(signed_key),
+            signed_sha256: Some(signed_sha256),
+            signed_size: Some(signed_size),
+            ..self.clone()
+        })
     }
 }
 
@@ -350,6 +1292,25 @@ mod tests {
     use super::*;
 
     const RPM_PATH: &str = "test/data/anda-srpm-macros-0:0.2.6-1.fc41.noarch.rpm";
+
+    #[test]
+    fn test_parse_nevra() {
+        let nevra: Nevra = "anda-srpm-macros-0:0.2.6-1.fc41.noarch".parse().unwrap();
+        assert_eq!(nevra.name, "anda-srpm-macros");
+        assert_eq!(nevra.epoch, 0);
+        assert_eq!(nevra.version, "0.2.6");
+        assert_eq!(nevra.release, "1.fc41");
+        assert_eq!(nevra.arch, "noarch");
+
+        let nevra: Nevra = "bash-5.2.26-3.fc40.x86_64".parse().unwrap();
+        assert_eq!((nevra.epoch, nevra.version.as_str()), (0, "5.2.26"));
+        let nevra: Nevra = "kernel-2:6.8.0-1.fc40.aarch64".parse().unwrap();
+        assert_eq!(nevra.epoch, 2);
+
+        assert!("bash".parse::<Nevra>().is_err());
+        assert!("bash-5.2.26.x86_64".parse::<Nevra>().is_err());
+        assert!("bash-x:5.2.26-3.fc40.x86_64".parse::<Nevra>().is_err());
+    }
     #[test]
     fn test_rpm_from_path() {
         let rpm = Rpm::from_path(RPM_PATH, "foobar").unwrap();
@@ -359,6 +1320,47 @@ mod tests {
         assert_eq!(rpm.version, "0.2.6");
         assert_eq!(rpm.release, "1.fc41");
         assert_eq!(rpm.arch, "noarch");
+        assert!(!rpm.is_source);
+        assert_eq!(rpm.sha256.as_deref().map(str::len), Some(64));
+    }
+
+    #[test]
+    fn test_find_dependents() {
+        let target = Rpm::from_path(RPM_PATH, "foobar").unwrap();
+
+        let mut dependent = Rpm::from_path(RPM_PATH, "foobar").unwrap();
+        dependent.name = "dependent".to_owned();
+        dependent.provides = vec![];
+        dependent.requires = vec![PkgDependency {
+            name: "anda-srpm-macros".to_owned(),
+            ..Default::default()
+        }];
+
+        let dependents = find_dependents(&target, &[target.clone(), dependent.clone()]);
+        assert_eq!(dependents.len(), 1);
+        assert_eq!(dependents[0].broken_requires, vec!["anda-srpm-macros"]);
+
+        // another package still provides the requirement
+        let mut alternative = target.clone();
+        alternative.id = Thing::from((RPM_TABLE, surrealdb::sql::Id::ulid()));
+        let dependents = find_dependents(&target, &[target.clone(), dependent, alternative]);
+        assert!(dependents.is_empty());
+    }
+
+    #[test]
+    fn test_rpm_content_key() {
+        let pkg = rpm::Package::open(RPM_PATH).unwrap();
+        let sha256 = file_sha256(RPM_PATH.as_ref()).unwrap();
+        let key = rpm_content_key(&sha256, &pkg.metadata);
+
+        assert_eq!(
+            key,
+            format!(
+                "rpm/sha256/{}/{}/{sha256}/anda-srpm-macros-0:0.2.6-1.fc41.noarch.rpm",
+                &sha256[0..1],
+                &sha256[1..2]
+            )
+        );
     }
 
     #[test]
@@ -368,5 +1370,8 @@ mod tests {
 
         println!("{:#?}", rpm_ref);
         assert_eq!(rpm_ref.name, "anda-srpm-macros");
+        assert_eq!(rpm_ref.version, rpm.version);
+        assert_eq!(rpm_ref.arch, rpm.arch);
+        assert_eq!(rpm_ref.tag.as_deref(), Some("foobar"));
     }
 }
@@ -1,19 +1,83 @@
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
 
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use surrealdb::{sql::Thing, RecordId};
 use tracing::{debug, warn};
 
+use crate::config::RepodataBackend;
+use crate::repodata::{apt, generate::RepodataOptions, ostree, pacman};
 use crate::obj_store::object_store;
+use crate::progress::{self, AssembleStage};
 
-use super::{gpg_key::GPG_KEY_TABLE, rpm::{Rpm, RpmRef}};
+use super::{
+    advisory::Advisory,
+    deb::{Deb, DebRef},
+    generic::{GenericFile, GenericFileRef, FILES_SUBDIR},
+    event_log::{LogEvent, SYSTEM_ACTOR},
+    gpg_key::{GpgKey, GPG_KEY_TABLE},
+    membership::TagMembership,
+    oci::OciManifest,
+    ostree::{OstreeCommit, OstreeCommitRef, OSTREE_SUBDIR},
+    pacman::{PacmanPackage, PacmanRef},
+    rpm::{Count, Rpm, RpmRef, AVAILABLE_IN_TAG_CLAUSE},
+};
 pub const TAG_TABLE: &str = "repo_tag";
 pub const COMPOSE_TABLE: &str = "repo_assemble";
+pub const COMPOSE_ARTIFACT_PREFIX: &str = "compose";
+pub const COMPS_PREFIX: &str = "comps";
+pub const MODULES_PREFIX: &str = "modules";
+/// Sub-repository source packages are assembled into
+pub const SOURCE_SUBREPO: &str = "source";
+/// Sub-repository debuginfo packages are assembled into, with [`Tag::split_debuginfo`]
+pub const DEBUG_SUBREPO: &str = "debug";
+/// Placeholders of [`Tag::export_path`] templates
+pub const EXPORT_PATH_PLACEHOLDERS: [&str; 2] = ["{tag}", "{arch}"];
+
+/// Locks held while assembling a tag, so assemblies of one tag don't write the same
+/// export directory at once
+static ASSEMBLY_LOCKS: LazyLock<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// A file produced by the image build stage of a compose, e.g. a boot.iso
+pub struct ComposeArtifact {
+    /// Path of the artifact, relative to the image build output directory
+    pub name: String,
+    pub object_key: String,
+    pub size: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TagCompose {
     pub id: Thing,
     pub tag: RecordId,
     pub packages: Vec<RpmRef>,
+    /// Debian packages of the compose, assembled into an APT repository
+    #[serde(default)]
+    pub debs: Vec<DebRef>,
+    /// pacman packages of the compose, in `pacman` tags
+    #[serde(default)]
+    pub pacman: Vec<PacmanRef>,
+    /// Generic files of the compose, published under `files/`
+    #[serde(default)]
+    pub files: Vec<GenericFileRef>,
+    /// OSTree commits of the compose in upload order, assembled into a repo under `ostree/`
+    #[serde(default)]
+    pub ostree: Vec<OstreeCommitRef>,
+    /// Available packages left out of the compose by the tag's architecture filter
+    #[serde(default)]
+    pub excluded: Vec<RpmRef>,
+    #[serde(default)]
+    pub artifacts: Vec<ComposeArtifact>,
+    #[serde(default)]
+    pub created_at: surrealdb::sql::Datetime,
+    /// When the compose was last published to the export directory, unset if it never was
+    #[serde(default)]
+    pub published_at: Option<surrealdb::sql::Datetime>,
 }
 
 impl TagCompose {
@@ -22,9 +86,70 @@ impl TagCompose {
             id: Thing::from((COMPOSE_TABLE, surrealdb::sql::Id::ulid())),
             tag: RecordId::from_table_key(TAG_TABLE, tag),
             packages,
+            debs: Vec::new(),
+            pacman: Vec::new(),
+            files: Vec::new(),
+            ostree: Vec::new(),
+            excluded: Vec::new(),
+            artifacts: Vec::new(),
+            created_at: surrealdb::sql::Datetime::default(),
+            published_at: None,
         }
     }
 
+    pub async fn get(id: &str) -> color_eyre::Result<Option<Self>> {
+        Ok(super::DB
+            .retry(|| async move { super::DB.select((COMPOSE_TABLE, id)).await })
+            .await?)
+    }
+
+    /// Fetch the most recent compose of a tag
+    pub async fn latest(tag: &RecordId) -> color_eyre::Result<Option<Self>> {
+        let compose: Option<Self> = super::DB
+            .retry(|| {
+                super::DB
+                    .query("SELECT * FROM repo_assemble WHERE tag = $tag ORDER BY id DESC LIMIT 1;")
+                    .bind(("tag", tag.clone()))
+            })
+            .await?
+            .take(0)?;
+
+        Ok(compose)
+    }
+
+    /// Fetch a page of a tag's composes, newest first, along with the total number of composes
+    pub async fn get_page(
+        tag: &str,
+        limit: u32,
+        offset: u32,
+    ) -> color_eyre::Result<(Vec<Self>, u64)> {
+        let mut query = super::DB
+            .retry(|| {
+                super::DB
+                    .query("SELECT * FROM repo_assemble WHERE tag = $tag ORDER BY id DESC LIMIT $limit START $offset;")
+                    .query("SELECT count() FROM repo_assemble WHERE tag = $tag GROUP ALL;")
+                    .bind(("tag", RecordId::from_table_key(TAG_TABLE, tag)))
+                    .bind(("limit", limit))
+                    .bind(("offset", offset))
+            })
+            .await?;
+
+        let page: Vec<Self> = query.take(0)?;
+        let total: Option<Count> = query.take(1)?;
+
+        Ok((page, total.map_or(0, |c| c.count)))
+    }
+
+    /// Record that the compose was just published
+    pub async fn mark_published(&self) -> color_eyre::Result<()> {
+        super::DB
+            .query("UPDATE $id SET published_at = time::now();")
+            .bind(("id", RecordId::from_table_key(COMPOSE_TABLE, self.id.id.to_raw())))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
     pub async fn save(&self) -> color_eyre::Result<Self> {
         let query = super::DB
             .upsert((COMPOSE_TABLE, self.id.id.to_raw()))
@@ -40,9 +165,193 @@ impl TagCompose {
 pub struct Tag {
     pub id: Thing,
     pub name: String,
+    /// Namespace the tag was created in, only visible from its scope, see [`crate::namespace`]
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Kind of packages the tag holds
+    #[serde(default, rename = "type")]
+    pub repo_type: RepoType,
+    /// Inline comps document, superseded by [`Tag::comps_key`]
     pub comps_xml: Option<String>,
+    /// Object key of the tag's comps document, included as group metadata in composes
+    #[serde(default)]
+    pub comps_key: Option<String>,
+    /// Object key of the tag's modulemd documents, included as module metadata in composes
+    #[serde(default)]
+    pub modules_key: Option<String>,
     #[serde(default)]
     pub signing_key: Option<RecordId>,
+    /// Sign uploaded packages with the signing key right away, so they never sit unsigned
+    #[serde(default)]
+    pub auto_sign: bool,
+    /// Fail assembly if any available package is unsigned, instead of publishing it as-is
+    #[serde(default)]
+    pub require_signed: bool,
+    /// Run the configured image build command after each assembly
+    #[serde(default)]
+    pub build_images: bool,
+    /// Architectures allowed in composes of this tag, empty allows everything.
+    ///
+    /// `noarch` and source packages are always allowed.
+    #[serde(default)]
+    pub arches: Vec<String>,
+    /// Split composes into a sub-repository per architecture, i.e `<tag>/x86_64`, instead of one flat repo
+    #[serde(default)]
+    pub split_arches: bool,
+    /// Where `noarch` packages go when composes are split by architecture
+    #[serde(default)]
+    pub noarch: NoarchPolicy,
+    /// Publish `-debuginfo` and `-debugsource` packages in a separate `<tag>-debug` repo
+    #[serde(default)]
+    pub split_debuginfo: bool,
+    /// Number of versions of each package name + architecture to keep available at once
+    #[serde(default = "default_keep_versions")]
+    pub keep_versions: u32,
+    /// Generate delta RPMs against the packages of the previous compose
+    #[serde(default)]
+    pub deltas: bool,
+    /// Also publish zchunk-compressed package metadata, for incremental metadata downloads
+    #[serde(default)]
+    pub zchunk: bool,
+    /// Frozen, i.e. during a release freeze. Uploads, availability changes and deletions are rejected
+    #[serde(default)]
+    pub locked: bool,
+    /// Which old package versions to keep when the tag is pruned
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+    /// Cron expression to assemble the tag on, i.e `0 3 * * *` for nightly repos
+    #[serde(default)]
+    pub schedule: Option<String>,
+    /// When the tag was last checked for a scheduled assembly, see [`crate::scheduler`]
+    #[serde(default)]
+    pub last_scheduled: Option<surrealdb::sql::Datetime>,
+    /// Where to publish the tag instead of `<export_dir>/<tag>`, i.e `/srv/repos/terra/{tag}/{arch}`
+    ///
+    /// A template of `{tag}` and `{arch}`, relative to the export dir unless absolute. With
+    /// `{arch}`, the compose stays published at the default location and each of its
+    /// architecture repos is linked to the rendered path too, see [`Tag::arch_export_dirs`].
+    #[serde(default)]
+    pub export_path: Option<String>,
+}
+
+fn default_keep_versions() -> u32 {
+    1
+}
+
+/// Outcome of signing one package, see [`Tag::sign_unsigned`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignResult {
+    pub rpm: RpmRef,
+    /// Why the package couldn't be signed, unset if it was
+    pub error: Option<String>,
+}
+
+/// Outcome of [`Tag::prefetch`], counting objects
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrefetchReport {
+    /// Objects downloaded into the cache
+    pub downloaded: usize,
+    /// Objects that were already cached
+    pub cached: usize,
+    /// Objects that couldn't be downloaded, with why
+    pub failed: BTreeMap<String, String>,
+}
+
+/// Kind of repository a tag is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RepoType {
+    /// A yum repository, Debian packages can be uploaded alongside the RPMs
+    #[default]
+    Rpm,
+    /// Only Debian packages
+    Deb,
+    /// Only pacman packages, assembled into a repo per architecture
+    Pacman,
+}
+
+impl RepoType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Rpm => "rpm",
+            Self::Deb => "deb",
+            Self::Pacman => "pacman",
+        }
+    }
+
+    /// Whether RPMs can be uploaded to tags of this type
+    pub fn accepts_rpms(&self) -> bool {
+        *self == Self::Rpm
+    }
+
+    /// Whether Debian packages can be uploaded to tags of this type
+    pub fn accepts_debs(&self) -> bool {
+        matches!(self, Self::Rpm | Self::Deb)
+    }
+}
+
+/// Placement of `noarch` packages in composes split by architecture
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NoarchPolicy {
+    /// Include them in every architecture's repo, like Fedora does
+    #[default]
+    Merge,
+    /// Give them their own `noarch` repo
+    Separate,
+}
+
+/// Rules for which versions of each package name + architecture a tag keeps
+///
+/// A version is kept if any rule matches. The newest version and held packages are
+/// always kept, and a policy without rules keeps everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Keep the newest N versions
+    #[serde(default)]
+    pub keep_last: Option<u32>,
+    /// Keep versions uploaded within the last N days
+    #[serde(default)]
+    pub max_age_days: Option<u32>,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.keep_last.is_none() && self.max_age_days.is_none()
+    }
+
+    /// Select the packages this policy doesn't keep, out of every package of a tag
+    ///
+    /// Only available packages are ranked and pruned, unavailable ones are already out of
+    /// the repo and mustn't count as one of the kept versions.
+    pub fn expired(&self, pkgs: Vec<Rpm>, now: chrono::DateTime<chrono::Utc>) -> Vec<Rpm> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+
+        let mut groups: BTreeMap<(String, String), Vec<Rpm>> = BTreeMap::new();
+        for pkg in pkgs.into_iter().filter(|pkg| pkg.available) {
+            groups
+                .entry((pkg.name.clone(), pkg.arch.clone()))
+                .or_default()
+                .push(pkg);
+        }
+
+        let mut expired = Vec::new();
+        for mut versions in groups.into_values() {
+            versions.sort_by(|a, b| b.evr_cmp(a));
+            for (i, pkg) in versions.into_iter().enumerate() {
+                let by_count = self.keep_last.is_some_and(|n| i < n as usize);
+                let by_age = self
+                    .max_age_days
+                    .is_some_and(|days| now - *pkg.timestamp < chrono::Duration::days(days.into()));
+                if i > 0 && !pkg.held && !by_count && !by_age {
+                    expired.push(pkg);
+                }
+            }
+        }
+        expired
+    }
 }
 
 impl Tag {
@@ -50,30 +359,229 @@ impl Tag {
         Self {
             id: Thing::from((TAG_TABLE, surrealdb::sql::Id::String(name.clone()))),
             name,
+            namespace: crate::namespace::current(),
+            repo_type: RepoType::default(),
             comps_xml: None,
+            comps_key: None,
+            modules_key: None,
             signing_key: None,
+            auto_sign: false,
+            require_signed: false,
+            build_images: false,
+            arches: Vec::new(),
+            split_arches: false,
+            noarch: NoarchPolicy::default(),
+            split_debuginfo: false,
+            keep_versions: default_keep_versions(),
+            deltas: false,
+            zchunk: false,
+            locked: false,
+            retention: RetentionPolicy::default(),
+            schedule: None,
+            last_scheduled: None,
+            export_path: None,
         }
     }
 
+    /// Fetch a tag, if it's visible from the current namespace scope
     pub async fn get(id: &str) -> color_eyre::Result<Option<Self>> {
-        Ok(super::DB.select((TAG_TABLE, id)).await?)
+        let tag: Option<Self> = super::DB
+            .retry(|| async move { super::DB.select((TAG_TABLE, id)).await })
+            .await?;
+        Ok(tag.filter(|tag| crate::namespace::visible(tag.namespace.as_deref())))
+    }
+
+    /// Whether a tag exists in any namespace, tag names are unique across namespaces
+    pub async fn exists(id: &str) -> color_eyre::Result<bool> {
+        let tag: Option<Self> = super::DB
+            .retry(|| super::DB.select((TAG_TABLE, id)))
+            .await?;
+        Ok(tag.is_some())
+    }
+
+    /// Whether the records of a tag, i.e. its packages, are visible from the current namespace
+    /// scope
+    ///
+    /// Records of tags that don't exist anymore are only visible from the global scope.
+    pub async fn visible(id: &str) -> color_eyre::Result<bool> {
+        if crate::namespace::current_scope().is_none() {
+            return Ok(true);
+        }
+        let tag: Option<Self> = super::DB
+            .retry(|| super::DB.select((TAG_TABLE, id)))
+            .await?;
+        Ok(crate::namespace::visible(
+            tag.as_ref().and_then(|tag| tag.namespace.as_deref()),
+        ))
+    }
+
+    /// Fetch every tag with an assembly schedule
+    pub async fn get_scheduled() -> color_eyre::Result<Vec<Self>> {
+        let tags: Vec<Self> = super::DB
+            .retry(|| super::DB.query("SELECT * FROM repo_tag WHERE schedule != NONE;"))
+            .await?
+            .take(0)?;
+        Ok(tags)
+    }
+
+    /// Record when the tag's schedule was last checked, without touching the rest of the tag
+    pub async fn set_last_scheduled(
+        &self,
+        at: chrono::DateTime<chrono::Utc>,
+    ) -> color_eyre::Result<()> {
+        super::DB
+            .query("UPDATE $id SET last_scheduled = $at;")
+            .bind(("id", RecordId::from_table_key(TAG_TABLE, &self.name)))
+            .bind(("at", surrealdb::sql::Datetime::from(at)))
+            .await?
+            .check()?;
+        Ok(())
     }
 
     pub async fn delete(&self) -> color_eyre::Result<()> {
+        TagMembership::delete_by_tag(&self.name).await?;
         super::DB
             .delete((TAG_TABLE, self.id.id.to_raw()))
             .await?
             .map_or(Ok(()), Ok)
     }
 
+    /// Fetch every tag visible from the current namespace scope
     pub async fn get_all() -> color_eyre::Result<Vec<Self>> {
-        Ok(super::DB.select(TAG_TABLE).await?)
+        let tags: Vec<Self> = super::DB
+            .retry(|| async { super::DB.select(TAG_TABLE).await })
+            .await?;
+        Ok(tags
+            .into_iter()
+            .filter(|tag| crate::namespace::visible(tag.namespace.as_deref()))
+            .collect())
+    }
+
+    /// Fetch every tag signed with a key
+    pub async fn get_by_signing_key(key: &str) -> color_eyre::Result<Vec<Self>> {
+        let tags: Vec<Self> = super::DB
+            .retry(|| {
+                super::DB
+                    .query("SELECT * FROM repo_tag WHERE signing_key = $key;")
+                    .bind(("key", RecordId::from_table_key(GPG_KEY_TABLE, key)))
+            })
+            .await?
+            .take(0)?;
+        Ok(tags)
     }
     
+    /// Mark the packages expired by the tag's retention policy unavailable, returning them
+    ///
+    /// With `delete_when_prune` set, the packages and their objects are deleted instead.
+    /// Nothing is changed with `dry_run`.
+    pub async fn prune(&self, dry_run: bool) -> color_eyre::Result<Vec<Rpm>> {
+        let pkgs = Rpm::get_by_tag(&self.name).await?;
+        let expired = self.retention.expired(pkgs, chrono::Utc::now());
+        debug!(tag = %self.name, count = expired.len(), dry_run, "pruning packages");
+        if dry_run {
+            return Ok(expired);
+        }
+
+        let delete =
+            crate::config::CONFIG.get().is_some() && crate::config::dynamic().delete_when_prune;
+        for pkg in &expired {
+            if delete && !TagMembership::get_by_rpm(pkg).await?.is_empty() {
+                // still shared into other tags, only this tag drops it
+                pkg.remove_from_tag(&self.name).await?;
+            } else if delete {
+                // the object itself goes with its last reference, see `Rpm::release_object`
+                pkg.delete().await?;
+            } else {
+                pkg.mark_unavailable().await?;
+            }
+        }
+        Ok(expired)
+    }
+
+    /// Whether packages of this architecture should be included in composes
+    pub fn arch_allowed(&self, arch: &str) -> bool {
+        self.arches.is_empty()
+            || arch == "noarch"
+            || arch == "src"
+            || self.arches.iter().any(|a| a == arch)
+    }
+
     pub fn set_gpg_key(&mut self, key: &str) {
         self.signing_key = Some(RecordId::from_table_key(GPG_KEY_TABLE, key));
     }
 
+    /// Store a comps document in the object store and use it for this tag
+    ///
+    /// The tag itself still has to be saved.
+    pub async fn set_comps(&mut self, xml: String) -> color_eyre::Result<()> {
+        let key = crate::namespace::object_key(format!("{COMPS_PREFIX}/{}/comps.xml", self.name));
+        object_store().put_bytes(&key, xml.into_bytes()).await?;
+        self.comps_key = Some(key);
+        self.comps_xml = None;
+        Ok(())
+    }
+
+    /// Store a modulemd YAML stream in the object store and use it for this tag,
+    /// or stop including module metadata if `None`
+    ///
+    /// The tag itself still has to be saved.
+    pub async fn set_modules(&mut self, yaml: Option<String>) -> color_eyre::Result<()> {
+        let key =
+            crate::namespace::object_key(format!("{MODULES_PREFIX}/{}/modules.yaml", self.name));
+        match yaml {
+            Some(yaml) => {
+                object_store().put_bytes(&key, yaml.into_bytes()).await?;
+                self.modules_key = Some(key);
+            }
+            None => {
+                if self.modules_key.take().is_some() {
+                    object_store().remove(&key).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the tag's modulemd documents to `dir`, returning their path
+    async fn stage_modules(&self, dir: &Path) -> color_eyre::Result<Option<PathBuf>> {
+        let Some(key) = &self.modules_key else {
+            return Ok(None);
+        };
+        let dest = dir.join("modules.yaml");
+        tokio::fs::copy(object_store().get(key).await?, &dest).await?;
+        Ok(Some(dest))
+    }
+
+    /// Write `updateinfo.xml` for the tag's advisories to `dir`, returning its path
+    async fn stage_updateinfo(
+        &self,
+        dir: &Path,
+        packages: &[RpmRef],
+    ) -> color_eyre::Result<Option<PathBuf>> {
+        let advisories = Advisory::get_by_tag(&self.name).await?;
+        if advisories.is_empty() {
+            return Ok(None);
+        }
+        let published = packages.iter().map(|p| p.id).collect::<Vec<_>>();
+        let dest = dir.join("updateinfo.xml");
+        let xml = crate::repodata::updateinfo::updateinfo_xml(&advisories, &published)?;
+        tokio::fs::write(&dest, xml).await?;
+        Ok(Some(dest))
+    }
+
+    /// Write the tag's comps document to `dir`, returning its path
+    async fn stage_comps(&self, dir: &Path) -> color_eyre::Result<Option<PathBuf>> {
+        let dest = dir.join("comps.xml");
+        if let Some(key) = &self.comps_key {
+            tokio::fs::copy(object_store().get(key).await?, &dest).await?;
+        } else if let Some(xml) = &self.comps_xml {
+            tokio::fs::write(&dest, xml).await?;
+        } else {
+            return Ok(None);
+        }
+        Ok(Some(dest))
+    }
+
     /// Create or update a tag in the database
     pub async fn save(&self) -> color_eyre::Result<Self> {
         // if already exists return error
@@ -103,15 +611,21 @@ impl Tag {
     // The assembly process is as follows:
     // 1. Get all packages that are tagged to this repo
     // 2. Symlink them to a staging repo directory we create
-    // 3. In that directory, run createrepo_c with the options we want
+    // 3. In that directory, generate repodata natively or with createrepo_c
     // 4. Finally, force symlink the successful staging repo to the export directory, with the tag name
 
     // ln -sf $staging_repo $export_dir/$tag_name
 
+    /// Fetch the packages available in this tag, including those shared into it from other tags
     pub async fn get_available_rpms(&self) -> color_eyre::Result<Vec<Rpm>> {
         let mut query = super::DB
-            .query("SELECT * FROM rpm_package WHERE tag = $tag_id AND available = true;")
-            .bind(("tag_id", self.id.clone()))
+            .retry(|| {
+                super::DB
+                    .query(format!(
+                        "SELECT * FROM rpm_package WHERE {AVAILABLE_IN_TAG_CLAUSE};"
+                    ))
+                    .bind(("tag", RecordId::from_table_key(TAG_TABLE, &self.name)))
+            })
             .await?;
 
         let pkgs: Vec<Rpm> = query.take(0)?;
@@ -119,111 +633,1546 @@ impl Tag {
         Ok(pkgs)
     }
 
+    /// Directory the tag's compose is published to, see [`Tag::export_path`]
     pub fn export_dir(&self) -> PathBuf {
-        crate::config::CONFIG
-            .get()
-            .unwrap()
-            .export_dir
-            .join(&self.name)
+        self.export_dir_in(&crate::config::CONFIG.get().unwrap().export_dir)
+    }
+
+    fn export_dir_in(&self, base: &Path) -> PathBuf {
+        match &self.export_path {
+            Some(_) if !self.exports_by_arch() => self.render_export_path(base, ""),
+            _ => base.join(&self.name),
+        }
+    }
+
+    /// Export directory of the tag's debuginfo repo next to [`Tag::export_dir`], as `<dir>-debug`
+    ///
+    /// See [`Tag::split_debuginfo`].
+    pub fn debug_export_dir(&self) -> PathBuf {
+        self.debug_export_dir_in(&crate::config::CONFIG.get().unwrap().export_dir)
+    }
+
+    fn debug_export_dir_in(&self, base: &Path) -> PathBuf {
+        let export_dir = self.export_dir_in(base);
+        let name = export_dir.file_name().unwrap_or_default().to_string_lossy();
+        export_dir.with_file_name(format!("{name}-{DEBUG_SUBREPO}"))
+    }
+
+    /// Directories the top-level sub-repositories of a compose are additionally published to,
+    /// when [`Tag::export_path`] has an `{arch}` placeholder
+    pub fn arch_export_dirs<'a>(
+        &self,
+        subrepos: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<(String, PathBuf)> {
+        let base = &crate::config::CONFIG.get().unwrap().export_dir;
+        self.arch_export_dirs_in(base, subrepos)
+    }
+
+    fn arch_export_dirs_in<'a>(
+        &self,
+        base: &Path,
+        subrepos: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<(String, PathBuf)> {
+        if !self.exports_by_arch() {
+            return Vec::new();
+        }
+        subrepos
+            .into_iter()
+            .filter(|dir| !dir.is_empty() && !dir.contains('/'))
+            .map(|dir| (dir.to_owned(), self.render_export_path(base, dir)))
+            .collect()
+    }
+
+    /// Whether [`Tag::export_path`] places each architecture repo separately
+    pub fn exports_by_arch(&self) -> bool {
+        self.export_path.as_ref().is_some_and(|t| t.contains("{arch}"))
+    }
+
+    fn render_export_path(&self, base: &Path, arch: &str) -> PathBuf {
+        let template = self.export_path.as_deref().unwrap_or_default();
+        base.join(template.replace("{tag}", &self.name).replace("{arch}", arch))
+    }
+
+    /// Every path the tag may be published to, so other tags can't be published over them
+    ///
+    /// With `{arch}` and no [`Tag::arches`] set, any architecture can be published, so the
+    /// rendered path up to the placeholder is claimed as a whole.
+    fn claimed_export_dirs_in(&self, base: &Path) -> Vec<PathBuf> {
+        let mut dirs = vec![self.export_dir_in(base)];
+        if self.split_debuginfo {
+            dirs.push(self.debug_export_dir_in(base));
+        }
+        if self.exports_by_arch() {
+            if self.arches.is_empty() {
+                let rendered = self.render_export_path(base, "{arch}");
+                let root = rendered
+                    .components()
+                    .take_while(|c| !c.as_os_str().to_string_lossy().contains("{arch}"))
+                    .collect();
+                dirs.push(root);
+            } else {
+                let subrepos = self.arches.iter().map(String::as_str);
+                let subrepos = subrepos.chain(["noarch", SOURCE_SUBREPO]);
+                let arch_dirs = self.arch_export_dirs_in(base, subrepos);
+                dirs.extend(arch_dirs.into_iter().map(|(_, dir)| dir));
+            }
+        }
+        dirs
+    }
+
+    /// Another tag published to, or inside, one of this tag's export paths, if any
+    ///
+    /// Tags of every namespace are checked, they share the export dir.
+    pub async fn export_conflict(&self) -> color_eyre::Result<Option<String>> {
+        let base = &crate::config::CONFIG.get().unwrap().export_dir;
+        let dirs = self.claimed_export_dirs_in(base);
+        let tags: Vec<Self> = super::DB.retry(|| super::DB.select(TAG_TABLE)).await?;
+        let conflict = tags
+            .into_iter()
+            .filter(|other| other.name != self.name)
+            .find(|other| {
+                let other_dirs = other.claimed_export_dirs_in(base);
+                dirs.iter().any(|dir| {
+                    other_dirs
+                        .iter()
+                        .any(|claimed| dir.starts_with(claimed) || claimed.starts_with(dir))
+                })
+            });
+        Ok(conflict.map(|other| other.name))
+    }
+
+    /// Check an [`Tag::export_path`] template, returning why it's invalid
+    pub fn validate_export_path(template: &str) -> Result<(), String> {
+        if template.trim().is_empty() {
+            return Err("export path is empty".to_owned());
+        }
+        let mut rest = template.to_owned();
+        for placeholder in EXPORT_PATH_PLACEHOLDERS {
+            rest = rest.replace(placeholder, "");
+        }
+        if rest.contains(['{', '}']) {
+            return Err(format!(
+                "unknown placeholder, expected one of {}",
+                EXPORT_PATH_PLACEHOLDERS.join(", ")
+            ));
+        }
+        let components: Vec<_> = Path::new(template).components().collect();
+        if components.contains(&Component::ParentDir) {
+            return Err("export path can't contain `..`".to_owned());
+        }
+        if components.contains(&Component::CurDir) {
+            return Err("export path can't contain `.`".to_owned());
+        }
+        // the export dir or the root itself would be replaced by the tag's repo
+        if !components.iter().any(|c| matches!(c, Component::Normal(_))) {
+            return Err("export path must name a directory".to_owned());
+        }
+        Ok(())
     }
 
+    /// Assemble the tag into a new compose and publish it, reporting progress to [`crate::progress`]
+    ///
+    /// Assemblies of the same tag run one after the other.
     pub async fn assemble(&self) -> color_eyre::Result<()> {
+        let lock = ASSEMBLY_LOCKS
+            .lock()
+            .unwrap()
+            .entry(self.name.clone())
+            .or_default()
+            .clone();
+        let _guard = lock.lock().await;
+
+        let res = self.run_assembly().await;
+        let stage = match &res {
+            Ok(()) => AssembleStage::Finished,
+            Err(e) => AssembleStage::Failed {
+                error: format!("{e:#}"),
+            },
+        };
+        progress::emit(&self.name, stage);
+
+        let event = match &res {
+            Ok(()) => LogEvent::new(
+                "compose_finished",
+                SYSTEM_ACTOR.to_owned(),
+                serde_json::json!({ "compose": self.published_compose() }),
+            ),
+            Err(e) => LogEvent::new(
+                "compose_failed",
+                SYSTEM_ACTOR.to_owned(),
+                serde_json::json!({ "error": format!("{e:#}") }),
+            ),
+        }
+        .tag(&self.name)
+        .resource(TAG_TABLE, &self.name);
+        if let Err(e) = event.record().await {
+            warn!(tag = %self.name, ?e, "failed to record assembly event");
+        }
+        res
+    }
+
+    async fn run_assembly(&self) -> color_eyre::Result<()> {
         // let mut pkgs: surrealdb::Response = super::DB.query("SELECT * FROM rpm_package WHERE id IN (SELECT id, name, timestamp FROM rpm_package GROUP BY name,timestamp ORDER BY timestamp DESC LIMIT 1).id;").await?;
 
         debug!("assembling tag: {}", self.name);
         // let pkgs_vec: Vec<Rpm> = pkgs.take(0)?;
         // let p: Option<Rpm> = pkgs_vec.into_iter().next();
-        let config = crate::config::CONFIG
-            .get()
-            .ok_or_else(|| color_eyre::eyre::eyre!("config not loaded"))?;
 
-        let pkgs = self.get_available_rpms().await?;
+        let (pkgs, excluded): (Vec<_>, Vec<_>) = self
+            .get_available_rpms()
+            .await?
+            .into_iter()
+            .partition(|pkg| self.arch_allowed(&pkg.arch));
 
-        let compose = TagCompose::new(&self.name, pkgs.iter().map(|r| r.into()).collect())
-            .save()
-            .await?;
+        if !excluded.is_empty() {
+            debug!(count = excluded.len(), "excluding packages by architecture");
+        }
 
-        let staging_id = compose.id.id.to_raw();
-        let staging_dir_name = format!("{tag}/{tag}_{staging_id}", tag = self.name);
+        if self.require_signed {
+            let unsigned = pkgs
+                .iter()
+                .filter(|pkg| pkg.signed_object_key.is_none())
+                .map(|pkg| pkg.name.as_str())
+                .collect::<Vec<_>>();
+            if !unsigned.is_empty() {
+                return Err(color_eyre::eyre::eyre!(
+                    "{} unsigned package(s) in signed tag: {}",
+                    unsigned.len(),
+                    unsigned.join(", ")
+                ));
+            }
+        }
+
+        let mut compose = TagCompose::new(&self.name, pkgs.iter().map(|r| r.into()).collect());
+        compose.excluded = excluded.iter().map(|r| r.into()).collect();
+        compose.debs = Deb::get_available_by_tag(&self.name)
+            .await?
+            .iter()
+            .map(DebRef::from)
+            .collect();
+        compose.files = GenericFile::get_available_by_tag(&self.name)
+            .await?
+            .iter()
+            .map(GenericFileRef::from)
+            .collect();
+        compose.ostree = OstreeCommit::get_available_by_tag(&self.name)
+            .await?
+            .iter()
+            .map(OstreeCommitRef::from)
+            .collect();
+        if self.repo_type == RepoType::Pacman {
+            compose.pacman = PacmanPackage::get_available_by_tag(&self.name)
+                .await?
+                .iter()
+                .map(PacmanRef::from)
+                .collect();
+        }
 
-        let staging_dir = config.repo_cache_dir.join(&staging_dir_name);
+        // look up the previous compose before this one is saved as the latest
+        let previous = if self.deltas {
+            TagCompose::latest(&compose.tag).await?
+        } else {
+            None
+        };
+        let compose = compose.save().await?;
+        progress::emit(
+            &self.name,
+            AssembleStage::Started {
+                compose: compose.id.id.to_raw(),
+            },
+        );
 
+        let staging_dir = self.staging_dir(&compose)?;
         if staging_dir.exists() {
             return Err(color_eyre::eyre::eyre!("staging directory already exists"));
         }
 
-        tokio::fs::create_dir_all(&staging_dir).await?;
+        self.build_repo(&compose, previous.as_ref(), &staging_dir).await?;
+
+        // images are built from the staged repo, so a failed build never gets published
+        let compose = if self.build_images {
+            self.build_images(&compose, &staging_dir.canonicalize()?).await?
+        } else {
+            compose
+        };
+        self.publish(&compose).await?;
+
+        Ok(())
+    }
+
+    /// Staging directory of a compose, in the repo cache
+    fn staging_dir(&self, compose: &TagCompose) -> color_eyre::Result<PathBuf> {
+        let config = crate::config::CONFIG
+            .get()
+            .ok_or_else(|| color_eyre::eyre::eyre!("config not loaded"))?;
+        Ok(config.repo_cache_dir.join(format!(
+            "{tag}/{tag}_{staging_id}",
+            tag = self.name,
+            staging_id = compose.id.id.to_raw()
+        )))
+    }
+
+    /// Group packages into the sub-repositories of a compose, keyed by their directory relative to the repo root
+    ///
+    /// Without [`Tag::split_arches`], binary packages go into the repo root (`""`).
+    /// Source packages always go into their own [`SOURCE_SUBREPO`], and debuginfo packages
+    /// into a [`DEBUG_SUBREPO`] with the same layout as the main repo if [`Tag::split_debuginfo`] is set.
+    pub fn subrepos(&self, packages: &[RpmRef]) -> BTreeMap<String, Vec<RpmRef>> {
+        let (sources, packages): (Vec<_>, Vec<_>) =
+            packages.iter().cloned().partition(|pkg| pkg.is_source);
+        let (debug, packages): (Vec<_>, Vec<_>) = packages
+            .into_iter()
+            .partition(|pkg| self.split_debuginfo && pkg.is_debuginfo());
+
+        let mut subrepos = self.binary_subrepos(&packages);
+        if !debug.is_empty() {
+            for (dir, pkgs) in self.binary_subrepos(&debug) {
+                let dir = if dir.is_empty() {
+                    DEBUG_SUBREPO.to_owned()
+                } else {
+                    format!("{DEBUG_SUBREPO}/{dir}")
+                };
+                subrepos.insert(dir, pkgs);
+            }
+        }
+        if !sources.is_empty() {
+            subrepos.insert(SOURCE_SUBREPO.to_owned(), sources);
+        }
+        subrepos
+    }
+
+    fn binary_subrepos(&self, packages: &[RpmRef]) -> BTreeMap<String, Vec<RpmRef>> {
+        let mut subrepos: BTreeMap<String, Vec<RpmRef>> = BTreeMap::new();
+        if !self.split_arches {
+            subrepos.insert(String::new(), packages.to_vec());
+            return subrepos;
+        }
 
-        futures::future::try_join_all(pkgs.into_iter().map(|pkg| {
-            let staging_dir = staging_dir.clone();
-            async move {
-                let cache_key = &pkg.object_key;
-                let cache_key_filename = cache_key.split('/').last().unwrap();
-                let obj_store = object_store();
-                let src = obj_store.get(cache_key).await?.canonicalize()?;
-                tracing::debug!(?src);
+        // configured architectures always get a repo, even before anything is built for them
+        for arch in self.arches.iter().filter(|arch| *arch != "noarch") {
+            subrepos.entry(arch.clone()).or_default();
+        }
+        let (noarch, arched): (Vec<_>, Vec<_>) =
+            packages.iter().partition(|pkg| pkg.arch == "noarch");
+        for pkg in arched {
+            subrepos.entry(pkg.arch.clone()).or_default().push(pkg.clone());
+        }
 
-                if staging_dir.join(cache_key_filename).exists() {
-                    warn!(
-                        ?cache_key,
-                        "File name seems to conflict, removing already existing file"
-                    );
-                    tokio::fs::remove_file(&staging_dir.join(cache_key_filename)).await?;
+        match self.noarch {
+            NoarchPolicy::Merge if !subrepos.is_empty() => {
+                for pkgs in subrepos.values_mut() {
+                    pkgs.extend(noarch.iter().map(|pkg| (*pkg).clone()));
                 }
+            }
+            _ if noarch.is_empty() => {}
+            _ => {
+                subrepos.insert("noarch".to_owned(), noarch.into_iter().cloned().collect());
+            }
+        }
+        subrepos
+    }
 
-                let target_path = staging_dir.join(format!(
-                    "{ulid}-{cache_key_filename}",
-                    ulid = pkg.id.id.to_raw()
-                ));
-                tokio::fs::remove_file(&target_path).await.ok();
-                let metadata = tokio::fs::metadata(&src).await?;
-                tracing::trace!(?metadata);
-                if target_path.metadata().is_ok() {
-                    warn!(
-                        ?cache_key,
-                        "File name seems to conflict, removing already existing file"
-                    );
-                    tokio::fs::remove_file(&target_path).await?;
+    /// Stage a compose in `staging_dir`: a yum repo of its RPMs in `rpm` tags, an APT repo of its
+    /// Debian packages if there are any or the tag is a `deb` tag, pacman repos in `pacman` tags,
+    /// an ostree repo of its commits if there are any, and its generic files
+    async fn build_repo(
+        &self,
+        compose: &TagCompose,
+        previous: Option<&TagCompose>,
+        staging_dir: &Path,
+    ) -> color_eyre::Result<()> {
+        if self.repo_type.accepts_rpms() {
+            self.build_yum_repo(compose, previous, staging_dir).await?;
+        }
+        if self.has_apt_repo(compose) {
+            self.build_apt_repo(compose, staging_dir).await?;
+        }
+        if self.repo_type == RepoType::Pacman {
+            self.build_pacman_repo(compose, staging_dir).await?;
+        }
+        if !compose.ostree.is_empty() {
+            self.build_ostree_repo(compose, staging_dir).await?;
+        }
+        self.stage_files(compose, staging_dir).await?;
+        Ok(())
+    }
+
+    fn has_apt_repo(&self, compose: &TagCompose) -> bool {
+        self.repo_type == RepoType::Deb || !compose.debs.is_empty()
+    }
+
+    /// Whether the staging directory of a compose still holds all of its repositories
+    fn is_staged(&self, compose: &TagCompose, staging_dir: &Path) -> bool {
+        let yum = !self.repo_type.accepts_rpms()
+            || self
+                .subrepos(&compose.packages)
+                .keys()
+                .all(|dir| staging_dir.join(dir).join("repodata/repomd.xml").exists());
+        let apt = !self.has_apt_repo(compose)
+            || staging_dir
+                .join(apt::dists_dir(&self.name))
+                .join("Release")
+                .exists();
+        let pacman = self.repo_type != RepoType::Pacman
+            || self.pacman_architectures(compose).iter().all(|arch| {
+                staging_dir
+                    .join(arch)
+                    .join(pacman::db_file_name(&self.name))
+                    .exists()
+            });
+        let ostree = compose.ostree.is_empty()
+            || staging_dir.join(OSTREE_SUBDIR).join("summary").exists();
+        let files = compose
+            .files
+            .iter()
+            .all(|file| staging_dir.join(FILES_SUBDIR).join(&file.path).exists());
+        yum && apt && pacman && ostree && files
+    }
+
+    fn pacman_architectures(&self, compose: &TagCompose) -> Vec<String> {
+        pacman::architectures(compose.pacman.iter().map(|pkg| pkg.arch.as_str()), &self.arches)
+    }
+
+    /// Link the packages of a compose into `staging_dir` and generate repodata for each of its sub-repositories
+    ///
+    /// Delta RPMs are generated against the packages of `previous`, if given.
+    async fn build_yum_repo(
+        &self,
+        compose: &TagCompose,
+        previous: Option<&TagCompose>,
+        staging_dir: &Path,
+    ) -> color_eyre::Result<()> {
+        let config = crate::config::CONFIG
+            .get()
+            .ok_or_else(|| color_eyre::eyre::eyre!("config not loaded"))?;
+        let staging_id = compose.id.id.to_raw();
+        let subrepos = self.subrepos(&compose.packages);
+        for dir in subrepos.keys() {
+            tokio::fs::create_dir_all(staging_dir.join(dir)).await?;
+        }
+
+        // packages already in the published repo are linked from there, skipping the object store
+        let published_dir = std::fs::read_link(self.export_dir())
+            .ok()
+            .filter(|dir| dir != staging_dir);
+        let published_dir = &published_dir;
+
+        let total = subrepos.values().map(Vec::len).sum();
+        let counter = AtomicUsize::new(0);
+        let reused = AtomicUsize::new(0);
+        let (counter, reused) = (&counter, &reused);
+        progress::emit(&self.name, AssembleStage::Staging { staged: 0, total });
+        let links = subrepos
+            .iter()
+            .flat_map(|(dir, pkgs)| pkgs.iter().map(move |pkg| (dir, pkg)));
+        futures::future::try_join_all(links.map(move |(dir, pkg)| async move {
+            let repo_dir = staging_dir.join(dir);
+            let file_name = pkg.file_name();
+            // only if it links the same object, i.e. not the unsigned one of a since signed package
+            let published = published_dir
+                .as_ref()
+                .and_then(|published| {
+                    std::fs::read_link(published.join(dir).join(&file_name)).ok()
+                })
+                .filter(|src| src.exists() && src.ends_with(pkg.staged_object_key()));
+            match published {
+                Some(src) => {
+                    let target_path = repo_dir.join(&file_name);
+                    tokio::fs::remove_file(&target_path).await.ok();
+                    tokio::fs::symlink(src, target_path).await?;
+                    reused.fetch_add(1, Ordering::Relaxed);
+                }
+                None => {
+                    let (key, sha256) = (pkg.staged_object_key(), pkg.staged_sha256());
+                    link_object(key, sha256, pkg.id.to_string(), &repo_dir).await?
                 }
+            }
+            let staged = counter.fetch_add(1, Ordering::Relaxed) + 1;
+            if staged % progress::STAGING_REPORT_INTERVAL == 0 || staged == total {
+                progress::emit(&self.name, AssembleStage::Staging { staged, total });
+            }
+            color_eyre::Result::<()>::Ok(())
+        }))
+        .await?;
+        debug!(
+            tag = %self.name,
+            reused = reused.load(Ordering::Relaxed),
+            total,
+            "staged packages"
+        );
 
-                debug!("Symlinking {} to {}", src.display(), target_path.display());
-                tokio::fs::symlink(src, target_path).await?;
+        // the packages of the previous compose that were replaced, to generate deltas against
+        let old_subrepos = previous.map(|previous| {
+            let old_pkgs = previous
+                .packages
+                .iter()
+                .filter(|old| !compose.packages.iter().any(|p| p.id == old.id))
+                .cloned()
+                .collect::<Vec<_>>();
+            debug!(count = old_pkgs.len(), previous = ?previous.id, "generating deltas");
+            self.subrepos(&old_pkgs)
+        });
+
+        // comps, modulemd and updateinfo are staged outside of the repo, so they're not published as-is
+        let meta_dir = config
+            .repo_cache_dir
+            .join(format!("{tag}/meta_{staging_id}", tag = self.name));
+        tokio::fs::create_dir_all(&meta_dir).await?;
+        let groupfile = self.stage_comps(&meta_dir).await?;
+        let modules = self.stage_modules(&meta_dir).await?;
+
+        let native = previous.is_none() && config.repodata_backend == RepodataBackend::Native;
+        progress::emit(
+            &self.name,
+            AssembleStage::Repodata {
+                backend: if native { "native" } else { "createrepo" }.to_owned(),
+            },
+        );
+        for (dir, pkgs) in &subrepos {
+            let subrepo_meta_dir = meta_dir.join(dir);
+            tokio::fs::create_dir_all(&subrepo_meta_dir).await?;
+            let options = RepodataOptions {
+                groupfile: groupfile.clone(),
+                modules: modules.clone(),
+                updateinfo: self.stage_updateinfo(&subrepo_meta_dir, pkgs).await?,
+                zchunk: self.zchunk,
+                // the signed copy is published for signed packages
+                checksums: pkgs
+                    .iter()
+                    .filter_map(|pkg| Some((pkg.file_name(), pkg.staged_sha256()?.to_owned())))
+                    .collect(),
+                sizes: pkgs
+                    .iter()
+                    .filter_map(|pkg| Some((pkg.file_name(), pkg.signed_size?)))
+                    .collect(),
+                cache_dir: Some(config.cache_dir.join("package_metadata")),
+            };
+
+            let repo_dir = staging_dir.join(dir);
+            if native {
+                tokio::task::spawn_blocking(move || {
+                    crate::repodata::generate::generate(&repo_dir, &options)
+                })
+                .await??;
+                continue;
+            }
+
+            let old_dir = config
+                .repo_cache_dir
+                .join(format!("{tag}/old_{staging_id}", tag = self.name))
+                .join(dir);
+            let old_pkgs = old_subrepos
+                .as_ref()
+                .map(|old| old.get(dir).map(Vec::as_slice).unwrap_or_default());
+            // createrepo_c scans subdirectories too, so sub-repositories nested in the root are listed explicitly
+            let pkglist = subrepo_meta_dir.join("pkglist");
+            let files = pkgs.iter().map(|pkg| pkg.file_name() + "\n").collect::<String>();
+            tokio::fs::write(&pkglist, files).await?;
+
+            let old = old_pkgs.map(|pkgs| (pkgs, old_dir.as_path()));
+            let res = createrepo(&repo_dir, &pkglist, &options, old).await;
+            if old_pkgs.is_some() {
+                tokio::fs::remove_dir_all(&old_dir).await.ok();
+            }
+            res?;
+        }
+        tokio::fs::remove_dir_all(&meta_dir).await.ok();
 
-                Result::<_, color_eyre::Report>::Ok(())
+        if self.signing_key.is_some() {
+            progress::emit(&self.name, AssembleStage::Signing);
+            let key = self.get_signing_key().await?;
+            for dir in subrepos.keys() {
+                self.sign_repodata(&key, &staging_dir.join(dir)).await?;
             }
+            // split repos get the public key at their root too, so one `gpgkey=` URL covers every sub-repository
+            let debug_dir = staging_dir.join(DEBUG_SUBREPO);
+            let roots = std::iter::once(staging_dir.to_path_buf())
+                .chain(debug_dir.is_dir().then_some(debug_dir));
+            for root in roots {
+                tokio::fs::write(root.join(self.gpg_key_file_name()), &key.public_key).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Link the Debian packages of a compose into the pool of `staging_dir` and generate the
+    /// indexes of the tag's suite
+    async fn build_apt_repo(
+        &self,
+        compose: &TagCompose,
+        staging_dir: &Path,
+    ) -> color_eyre::Result<()> {
+        let mut debs = Vec::with_capacity(compose.debs.len());
+        for deb in &compose.debs {
+            let deb = Deb::get(deb.id)
+                .await?
+                .ok_or_else(|| color_eyre::eyre::eyre!("package {} was deleted", deb.id))?;
+            debs.push(deb);
+        }
+
+        let total = debs.len();
+        progress::emit(&self.name, AssembleStage::Staging { staged: 0, total });
+        futures::future::try_join_all(debs.iter().map(|deb| async move {
+            let target_path = staging_dir.join(apt::pool_path(deb));
+            tokio::fs::create_dir_all(target_path.parent().unwrap()).await?;
+            let src = object_store().get(&deb.object_key).await?.canonicalize()?;
+            tokio::fs::remove_file(&target_path).await.ok();
+            tokio::fs::symlink(src, target_path).await?;
+            color_eyre::Result::<()>::Ok(())
+        }))
+        .await?;
+        progress::emit(&self.name, AssembleStage::Staging { staged: total, total });
+
+        let repo_dir = staging_dir.to_path_buf();
+        let suite = self.name.clone();
+        tokio::task::spawn_blocking(move || apt::generate(&repo_dir, &suite, &debs)).await??;
+
+        if self.signing_key.is_some() {
+            progress::emit(&self.name, AssembleStage::Signing);
+            let key = self.get_signing_key().await?;
+            self.sign_release(&key, &staging_dir.join(apt::dists_dir(&self.name))).await?;
+            tokio::fs::write(staging_dir.join(self.apt_key_file_name()), &key.public_key).await?;
+        }
+        Ok(())
+    }
+
+    /// Link the generic files of a compose into the `files/` directory of `staging_dir`
+    async fn stage_files(&self, compose: &TagCompose, staging_dir: &Path) -> color_eyre::Result<()> {
+        tokio::fs::create_dir_all(staging_dir).await?;
+        futures::future::try_join_all(compose.files.iter().map(|file| async move {
+            let target_path = staging_dir.join(FILES_SUBDIR).join(&file.path);
+            tokio::fs::create_dir_all(target_path.parent().unwrap()).await?;
+            let src = object_store().get(&file.object_key).await?.canonicalize()?;
+            tokio::fs::remove_file(&target_path).await.ok();
+            tokio::fs::symlink(src, target_path).await?;
+            color_eyre::Result::<()>::Ok(())
         }))
         .await?;
+        Ok(())
+    }
+
+    /// Link the pacman packages of a compose into a directory per architecture of `staging_dir`,
+    /// with their signatures, and write the repo database of each
+    ///
+    /// With a signing key, packages without a signature are signed first, and the databases are
+    /// signed too.
+    async fn build_pacman_repo(
+        &self,
+        compose: &TagCompose,
+        staging_dir: &Path,
+    ) -> color_eyre::Result<()> {
+        let key = if self.signing_key.is_some() {
+            Some(self.get_signing_key().await?)
+        } else {
+            None
+        };
+        let mut pkgs = Vec::with_capacity(compose.pacman.len());
+        for pkg in &compose.pacman {
+            let mut pkg = PacmanPackage::get(pkg.id)
+                .await?
+                .ok_or_else(|| color_eyre::eyre::eyre!("package {} was deleted", pkg.id))?;
+            if let (Some(key), None) = (&key, &pkg.signature) {
+                debug!(package = %pkg.full_name(), "signing package");
+                let data = tokio::fs::read(object_store().get(&pkg.object_key).await?).await?;
+                pkg = pkg.set_signature(&key.sign_detached_binary(&data).await?).await?;
+            }
+            pkgs.push(pkg);
+        }
+
+        let db_name = pacman::db_file_name(&self.name);
+        for arch in self.pacman_architectures(compose) {
+            let repo_dir = staging_dir.join(&arch);
+            tokio::fs::create_dir_all(&repo_dir).await?;
+            let arch_pkgs: Vec<&PacmanPackage> = pkgs
+                .iter()
+                .filter(|pkg| pkg.arch == arch || pkg.arch == pacman::ARCH_ANY)
+                .collect();
+            for pkg in &arch_pkgs {
+                let src = object_store().get(&pkg.object_key).await?.canonicalize()?;
+                let target_path = repo_dir.join(pkg.file_name());
+                tokio::fs::remove_file(&target_path).await.ok();
+                tokio::fs::symlink(src, target_path).await?;
+                if let Some(signature) = pkg.signature_bytes()? {
+                    let sig_path = repo_dir.join(format!("{}.sig", pkg.file_name()));
+                    tokio::fs::write(sig_path, signature).await?;
+                }
+            }
+
+            let db_path = repo_dir.join(&db_name);
+            let entries = arch_pkgs.into_iter().cloned().collect::<Vec<_>>();
+            tokio::task::spawn_blocking(move || {
+                pacman::write_db(&db_path, &entries.iter().collect::<Vec<_>>())
+            })
+            .await??;
+            tokio::fs::symlink(&db_name, repo_dir.join(format!("{}.db", self.name))).await?;
+
+            if let Some(key) = &key {
+                let db = tokio::fs::read(repo_dir.join(&db_name)).await?;
+                let signature = key.sign_detached_binary(&db).await?;
+                tokio::fs::write(repo_dir.join(format!("{db_name}.sig")), signature).await?;
+                tokio::fs::symlink(
+                    format!("{db_name}.sig"),
+                    repo_dir.join(format!("{}.db.sig", self.name)),
+                )
+                .await?;
+            }
+        }
+
+        if let Some(key) = &key {
+            tokio::fs::write(staging_dir.join(self.pacman_key_file_name()), &key.public_key)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Import the OSTree commits of a compose into an archive repo under `staging_dir`, pointing
+    /// each ref at its newest commit
+    ///
+    /// Commits are imported in upload order, so static deltas apply on top of their parents.
+    async fn build_ostree_repo(
+        &self,
+        compose: &TagCompose,
+        staging_dir: &Path,
+    ) -> color_eyre::Result<()> {
+        let config = crate::config::CONFIG
+            .get()
+            .ok_or_else(|| color_eyre::eyre::eyre!("config not loaded"))?;
+        let repo = staging_dir.join(OSTREE_SUBDIR);
+        ostree::init_repo(&repo).await?;
+        for commit in &compose.ostree {
+            debug!(ref_name = %commit.ref_name, checksum = %commit.checksum, "importing commit");
+            let path = object_store().get(&commit.object_key).await?;
+            let scratch_dir = config.cache_dir.join(format!("ostree-{}", commit.id));
+            ostree::import_commit(&repo, commit.artifact, &path, &commit.checksum, &scratch_dir)
+                .await?;
+        }
+        for (ref_name, checksum) in crate::db::ostree::heads(&compose.ostree) {
+            ostree::set_ref(&repo, ref_name, checksum).await?;
+        }
+        ostree::update_summary(&repo).await
+    }
+
+    /// Point the tag's export directory at a compose's staging directory, returning its canonical path
+    async fn publish(&self, compose: &TagCompose) -> color_eyre::Result<PathBuf> {
+        progress::emit(&self.name, AssembleStage::Publishing);
+        let staging_dir = self.staging_dir(compose)?.canonicalize()?;
+
+        let subrepos = self.subrepos(&compose.packages);
+        self.link_compose(&staging_dir, subrepos.keys().map(String::as_str))
+            .await?;
+
+        compose.mark_published().await?;
+        OciManifest::publish_all(&self.name).await?;
+        Ok(staging_dir)
+    }
+
+    /// Link a compose's staging directory, and its sub-repositories, to the tag's export paths
+    async fn link_compose<'a>(
+        &self,
+        staging_dir: &Path,
+        subrepos: impl IntoIterator<Item = &'a str>,
+    ) -> color_eyre::Result<()> {
+        link_export(staging_dir, &self.export_dir()).await?;
+
+        // architecture repos are linked to their own paths with an `{arch}` export path
+        for (dir, export_dir) in self.arch_export_dirs(subrepos) {
+            let subrepo_dir = staging_dir.join(dir);
+            if subrepo_dir.is_dir() {
+                link_export(&subrepo_dir, &export_dir).await?;
+            }
+        }
+
+        // the debuginfo repo is published alongside, i.e as `<tag>-debug`
+        let debug_export_dir = self.debug_export_dir();
+        let debug_dir = staging_dir.join(DEBUG_SUBREPO);
+        if debug_dir.is_dir() {
+            link_export(&debug_dir, &debug_export_dir).await
+        } else {
+            unlink_export(&debug_export_dir).await
+        }
+    }
+
+    /// Move the published compose from the export paths of `old`, the tag before its
+    /// [`Tag::export_path`] changed, to the current ones
+    ///
+    /// Nothing is published at the new paths until the next assembly if no compose was.
+    pub async fn move_export(&self, old: &Tag) -> color_eyre::Result<()> {
+        let old_dir = old.export_dir();
+        let Ok(staging_dir) = std::fs::read_link(&old_dir) else {
+            return Ok(());
+        };
+        let subrepos = top_level_subrepos(&staging_dir)?;
+        let subrepos = subrepos.iter().map(String::as_str);
+        for (_, dir) in old.arch_export_dirs(subrepos.clone()) {
+            unlink_export(&dir).await?;
+        }
+        unlink_export(&old.debug_export_dir()).await?;
+        unlink_export(&old_dir).await?;
+
+        tracing::info!(tag = %self.name, from = ?old_dir, to = ?self.export_dir(), "moving export");
+        self.link_compose(&staging_dir, subrepos).await
+    }
+
+    /// ID of the compose currently published to the export directory
+    pub fn published_compose(&self) -> Option<String> {
+        let staging_dir = std::fs::read_link(self.export_dir()).ok()?;
+        let name = staging_dir.file_name()?.to_str()?;
+        name.strip_prefix(&format!("{}_", self.name)).map(str::to_owned)
+    }
+
+    /// Re-publish a previous compose of this tag
+    ///
+    /// The compose is re-assembled from its package list if its staging directory was removed.
+    pub async fn rollback(&self, compose: &TagCompose) -> color_eyre::Result<()> {
+        if super::record_key(&compose.tag) != self.name {
+            return Err(color_eyre::eyre::eyre!("compose belongs to another tag"));
+        }
+        debug!(tag = %self.name, compose = ?compose.id, "rolling back");
+
+        let staging_dir = self.staging_dir(compose)?;
+        if !self.is_staged(compose, &staging_dir) {
+            tokio::fs::remove_dir_all(&staging_dir).await.ok();
+            self.build_repo(compose, None, &staging_dir).await?;
+        }
+        self.publish(compose).await?;
+        Ok(())
+    }
+
+    /// Fetch the tag's signing key, failing if it has none
+    pub async fn get_signing_key(&self) -> color_eyre::Result<GpgKey> {
+        let key_id = self
+            .signing_key
+            .clone()
+            .ok_or_else(|| color_eyre::eyre::eyre!("tag has no signing key"))?;
+        let key: Option<GpgKey> = super::DB.retry(|| super::DB.select(key_id.clone())).await?;
+        let key = key.ok_or_else(|| color_eyre::eyre::eyre!("signing key not found"))?;
+        if !key.allows(&self.name) {
+            return Err(color_eyre::eyre::eyre!(
+                "{} may not be signed with key {}",
+                self.name,
+                key.id.id.to_raw()
+            ));
+        }
+        Ok(key)
+    }
+
+    /// Download the objects of every available package of the tag into the cache,
+    /// `concurrency` at a time, so the next assembly doesn't have to
+    ///
+    /// An object failing to download doesn't stop the others.
+    pub async fn prefetch(&self, concurrency: usize) -> color_eyre::Result<PrefetchReport> {
+        let store = object_store();
+        // packages can share an object, which must only be downloaded once
+        let keys: std::collections::BTreeSet<String> = self
+            .get_available_rpms()
+            .await?
+            .iter()
+            .map(|pkg| pkg.staged_object_key().to_owned())
+            .collect();
+        debug!(tag = %self.name, count = keys.len(), "prefetching packages");
+
+        let results = futures::stream::iter(keys)
+            .map(|key| {
+                let store = &store;
+                async move {
+                    if store.cache.get(&key).is_some() {
+                        return (key, Ok(false));
+                    }
+                    let res = store.get(&key).await.map(|_| true);
+                    (key, res)
+                }
+            })
+            .buffer_unordered(concurrency.max(1))
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut report = PrefetchReport::default();
+        for (key, res) in results {
+            match res {
+                Ok(true) => report.downloaded += 1,
+                Ok(false) => report.cached += 1,
+                Err(e) => {
+                    warn!(tag = %self.name, ?key, ?e, "failed to prefetch object");
+                    report.failed.insert(key, format!("{e:#}"));
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Sign every available package of the tag that isn't signed yet with the tag's signing key
+    ///
+    /// Packages shared into the tag are left to the tag they were uploaded to, which owns
+    /// their signature. A package failing to sign doesn't stop the others, each package's
+    /// outcome is returned.
+    pub async fn sign_unsigned(&self) -> color_eyre::Result<Vec<SignResult>> {
+        let key = self.get_signing_key().await?;
+        let unsigned = self
+            .get_available_rpms()
+            .await?
+            .into_iter()
+            .filter(|pkg| {
+                pkg.signed_object_key.is_none() && super::record_key(&pkg.tag) == self.name
+            })
+            .collect::<Vec<_>>();
+        Ok(self.sign_packages(&unsigned, &key).await)
+    }
+
+    /// Sign every package of the tag that was signed already again with the tag's current signing key,
+    /// i.e. after the key was rotated, and publish them
+    ///
+    /// Packages shared into the tag are re-signed as well, as the tag publishes them. The new
+    /// signatures are written to new objects and swapped in with the tag's re-assembly, so the
+    /// published repos keep serving the old ones until then. Other tags publishing the packages
+    /// are re-assembled before the old objects are removed. Packages failing to sign keep their
+    /// old signature.
+    pub async fn resign(&self) -> color_eyre::Result<Vec<SignResult>> {
+        let key = self.get_signing_key().await?;
+        let pkgs: Vec<Rpm> = super::DB
+            .retry(|| {
+                super::DB.query(
+                    "SELECT * FROM rpm_package WHERE signed_object_key != NONE \
+                     AND (tag = $tag OR id IN (SELECT VALUE rpm FROM tag_member WHERE tag = $tag));",
+                )
+                .bind(("tag", RecordId::from_table_key(TAG_TABLE, &self.name)))
+            })
+            .await?
+            .take(0)?;
+        debug!(tag = %self.name, count = pkgs.len(), "re-signing packages");
+        let results = crate::signing::sign_objects(&pkgs, &key).await;
+        let (old, signed): (Vec<&Rpm>, Vec<Rpm>) = pkgs
+            .iter()
+            .zip(&results)
+            .filter_map(|(pkg, res)| Some((pkg, res.as_ref().ok()?.clone())))
+            .unzip();
+
+        let swapped = async {
+            Rpm::save_signed(&signed).await?;
+            if let Err(e) = self.assemble().await {
+                let old = old.iter().map(|pkg| (*pkg).clone()).collect::<Vec<_>>();
+                Rpm::save_signed(&old).await?;
+                return Err(e);
+            }
+            color_eyre::Result::<_>::Ok(())
+        }
+        .await;
+        if let Err(e) = swapped {
+            for pkg in &signed {
+                if let Some(signed_key) = &pkg.signed_object_key {
+                    object_store().remove(signed_key).await.ok();
+                }
+            }
+            return Err(e);
+        }
+
+        for tag in self.tags_publishing(&signed).await? {
+            if let Err(e) = tag.assemble().await {
+                warn!(tag = %tag.name, ?e, "failed to re-assemble tag with re-signed packages");
+            }
+        }
+        for pkg in old {
+            if let Some(old_key) = &pkg.signed_object_key {
+                pkg.remove_signed_object(old_key).await;
+            }
+        }
+
+        Ok(pkgs
+            .iter()
+            .zip(results)
+            .map(|(pkg, res)| match res {
+                Ok(signed) => SignResult {
+                    rpm: (&signed).into(),
+                    error: None,
+                },
+                Err(e) => {
+                    warn!(tag = %self.name, rpm = ?pkg.id, ?e, "failed to re-sign package");
+                    SignResult {
+                        rpm: pkg.into(),
+                        error: Some(format!("{e:#}")),
+                    }
+                }
+            })
+            .collect())
+    }
+
+    /// Tags other than this one owning or sharing any of the packages
+    async fn tags_publishing(&self, pkgs: &[Rpm]) -> color_eyre::Result<Vec<Tag>> {
+        let ids = pkgs
+            .iter()
+            .map(|pkg| RecordId::from_table_key(super::rpm::RPM_TABLE, pkg.id.id.to_raw()))
+            .collect::<Vec<_>>();
+        let shared: Vec<RecordId> = super::DB
+            .retry(|| {
+                super::DB
+                    .query("SELECT VALUE tag FROM tag_member WHERE rpm IN $rpms;")
+                    .bind(("rpms", ids.clone()))
+            })
+            .await?
+            .take(0)?;
+        let mut names = pkgs
+            .iter()
+            .map(|pkg| super::record_key(&pkg.tag))
+            .chain(shared.iter().map(super::record_key))
+            .filter(|name| *name != self.name)
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+
+        let mut tags = Vec::with_capacity(names.len());
+        for name in names {
+            tags.extend(Tag::get(&name).await?);
+        }
+        Ok(tags)
+    }
+
+    async fn sign_packages(&self, pkgs: &[Rpm], key: &GpgKey) -> Vec<SignResult> {
+        debug!(tag = %self.name, count = pkgs.len(), "signing packages");
+        let signed = crate::signing::sign_all(pkgs, key).await;
+        pkgs.iter()
+            .zip(signed)
+            .map(|(pkg, res)| match res {
+                Ok(signed) => SignResult {
+                    rpm: (&signed).into(),
+                    error: None,
+                },
+                Err(e) => {
+                    warn!(tag = %self.name, rpm = ?pkg.id, ?e, "failed to sign package");
+                    SignResult {
+                        rpm: pkg.into(),
+                        error: Some(format!("{e:#}")),
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Name of the public key file exported to the repo root
+    pub fn gpg_key_file_name(&self) -> String {
+        format!("RPM-GPG-KEY-{}", self.name)
+    }
+
+    /// Sign `repomd.xml` with the tag's signing key, and export the public key to the repo root
+    ///
+    /// Required for clients using `repo_gpgcheck=1`.
+    pub async fn sign_repodata(&self, key: &GpgKey, repo_dir: &Path) -> color_eyre::Result<()> {
+        let repomd_path = repo_dir.join("repodata/repomd.xml");
+        let repomd = tokio::fs::read(&repomd_path).await?;
+        debug!(key = ?key.id, "signing repomd.xml");
+        let signature = key.sign_detached(&repomd).await?;
+
+        tokio::fs::write(repo_dir.join("repodata/repomd.xml.asc"), signature).await?;
+        tokio::fs::write(repo_dir.join(self.gpg_key_file_name()), &key.public_key).await?;
+        Ok(())
+    }
+
+    /// Name of the public key file exported to the root of APT repos, for `signed-by=`
+    pub fn apt_key_file_name(&self) -> String {
+        format!("{}-archive-keyring.asc", self.name)
+    }
+
+    /// Name of the public key file exported to the root of pacman repos, for `pacman-key --add`
+    pub fn pacman_key_file_name(&self) -> String {
+        format!("{}-keyring.asc", self.name)
+    }
+
+    /// Sign an APT `Release` file with the tag's signing key, inline as `InRelease` and detached
+    /// as `Release.gpg` for older clients
+    pub async fn sign_release(&self, key: &GpgKey, dists_dir: &Path) -> color_eyre::Result<()> {
+        let release = tokio::fs::read_to_string(dists_dir.join("Release")).await?;
+        debug!(key = ?key.id, "signing Release");
+        let detached = key.sign_detached(release.as_bytes()).await?;
+        let inline = key.sign_detached(apt::canonical_text(&release).as_bytes()).await?;
+
+        tokio::fs::write(dists_dir.join("Release.gpg"), detached).await?;
+        tokio::fs::write(dists_dir.join("InRelease"), apt::inrelease(&release, &inline)).await?;
+        Ok(())
+    }
+
+    /// Run the configured image build command against a staged compose,
+    /// uploading everything it outputs as artifacts of that compose.
+    pub async fn build_images(
+        &self,
+        compose: &TagCompose,
+        repo_dir: &Path,
+    ) -> color_eyre::Result<TagCompose> {
+        let config = crate::config::CONFIG
+            .get()
+            .ok_or_else(|| color_eyre::eyre::eyre!("config not loaded"))?;
+
+        let Some(cmd) = &config.image_build_cmd else {
+            warn!(tag = ?self.name, "image builds requested, but no image build command is configured");
+            return Ok(compose.clone());
+        };
+
+        let compose_id = compose.id.id.to_raw();
+        let output_dir = config
+            .repo_cache_dir
+            .join(format!("{tag}/images_{compose_id}", tag = self.name));
+        tokio::fs::create_dir_all(&output_dir).await?;
+
+        tracing::info!(?cmd, ?output_dir, "building images for compose {compose_id}");
+        let status = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .env("SUBATOMIC_REPO_DIR", repo_dir)
+            .env("SUBATOMIC_OUTPUT_DIR", &output_dir)
+            .env("SUBATOMIC_TAG", &self.name)
+            .env("SUBATOMIC_COMPOSE_ID", &compose_id)
+            .kill_on_drop(true)
+            .status()
+            .await;
+
+        match status {
+            Ok(status) if status.success() => {}
+            res => {
+                tokio::fs::remove_dir_all(&output_dir).await.ok();
+                return Err(match res {
+                    Ok(status) => color_eyre::eyre::eyre!("image build failed: {status}"),
+                    Err(e) => e.into(),
+                });
+            }
+        }
+
+        let files = walkdir::WalkDir::new(&output_dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .collect::<Vec<_>>();
+
+        let mut artifacts = Vec::with_capacity(files.len());
+        for entry in files {
+            let name = entry
+                .path()
+                .strip_prefix(&output_dir)?
+                .to_string_lossy()
+                .to_string();
+            let object_key = crate::namespace::object_key(format!(
+                "{COMPOSE_ARTIFACT_PREFIX}/{compose_id}/{name}"
+            ));
+            let size = entry.metadata()?.len();
+
+            debug!(?name, ?object_key, "uploading compose artifact");
+            object_store()
+                .put(&object_key, &entry.path().to_path_buf())
+                .await?;
+
+            artifacts.push(ComposeArtifact {
+                name,
+                object_key,
+                size,
+            });
+        }
 
-        let mut process = tokio::process::Command::new("createrepo_c")
-            .arg(&staging_dir)
-            .spawn()?;
+        tokio::fs::remove_dir_all(&output_dir).await.ok();
 
-        let status = process.wait().await?;
+        let mut compose = compose.clone();
+        compose.artifacts = artifacts;
+        compose.save().await
+    }
+}
+
+/// Generate repodata for the packages of `repo_dir` listed in `pkglist` with createrepo_c and modifyrepo_c
+///
+/// If `old` is given, its packages are staged into its directory and delta RPMs are generated against them.
+async fn createrepo(
+    repo_dir: &Path,
+    pkglist: &Path,
+    options: &RepodataOptions,
+    old: Option<(&[RpmRef], &Path)>,
+) -> color_eyre::Result<()> {
+    let mut createrepo = tokio::process::Command::new("createrepo_c");
+    createrepo.arg("--pkglist").arg(pkglist);
+    if let Some(groupfile) = &options.groupfile {
+        createrepo.arg("--groupfile").arg(groupfile);
+    }
+    if options.zchunk {
+        createrepo.arg("--zck");
+    }
+
+    if let Some((old_pkgs, old_dir)) = old {
+        if stage_old_packages(old_pkgs, old_dir).await? > 0 {
+            createrepo.arg("--deltas").arg("--oldpackagedirs").arg(old_dir);
+        }
+    }
+
+    let output = createrepo.arg(repo_dir).output().await?;
+    if !output.status.success() {
+        return Err(color_eyre::eyre::eyre!(
+            "createrepo_c failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
 
-        if !status.success() {
-            return Err(color_eyre::eyre::eyre!("createrepo_c failed"));
+    let extra = [
+        ("modules", &options.modules),
+        ("updateinfo", &options.updateinfo),
+    ];
+    for (mdtype, file) in extra {
+        let Some(file) = file else { continue };
+        let output = tokio::process::Command::new("modifyrepo_c")
+            .arg(format!("--mdtype={mdtype}"))
+            .arg(file)
+            .arg(repo_dir.join("repodata"))
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(color_eyre::eyre::eyre!(
+                "modifyrepo_c failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
         }
+    }
+    Ok(())
+}
 
-        // symlink to export directory
+/// Prefetch tags one after the other in the background, i.e. to warm up the cache on startup
+pub fn spawn_prefetch(tags: Vec<String>, concurrency: usize) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        for name in tags {
+            let tag = match Tag::get(&name).await {
+                Ok(Some(tag)) => tag,
+                Ok(None) => {
+                    warn!(tag = %name, "cannot prefetch unknown tag");
+                    continue;
+                }
+                Err(e) => {
+                    warn!(tag = %name, ?e, "cannot get tag to prefetch");
+                    continue;
+                }
+            };
+            match tag.prefetch(concurrency).await {
+                Ok(report) => tracing::info!(tag = %name, ?report, "prefetched tag"),
+                Err(e) => tracing::error!(tag = %name, ?e, "failed to prefetch tag"),
+            }
+        }
+    })
+}
 
-        let staging_dir = staging_dir.canonicalize()?;
+/// Replace `export_dir` with a symlink to `src`, creating its parent directories
+///
+/// Anything but a symlink at `export_dir` is left alone, failing instead.
+async fn link_export(src: &Path, export_dir: &Path) -> color_eyre::Result<()> {
+    if let Some(parent) = export_dir.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
 
-        let export_dir = self.export_dir();
+    tracing::info!("symlinking {} to {}", src.display(), export_dir.display());
 
-        tokio::fs::create_dir_all(&export_dir.parent().unwrap()).await?;
+    unlink_export(export_dir).await?;
+    tokio::fs::symlink(src, export_dir).await?;
+    Ok(())
+}
 
-        tracing::info!(
-            "symlinking {} to {}",
-            staging_dir.display(),
+/// Remove the symlink at `export_dir`, if there's one
+///
+/// Fails if it's anything else, i.e. a directory an export path was pointed at by mistake.
+async fn unlink_export(export_dir: &Path) -> color_eyre::Result<()> {
+    match export_dir.symlink_metadata() {
+        Ok(meta) if meta.file_type().is_symlink() => Ok(tokio::fs::remove_file(export_dir).await?),
+        Ok(_) => Err(color_eyre::eyre::eyre!(
+            "{} exists and isn't a published repo, refusing to replace it",
             export_dir.display()
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Names of the sub-repositories at the top level of a compose, i.e. its architecture repos
+fn top_level_subrepos(staging_dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut subrepos = Vec::new();
+    for entry in std::fs::read_dir(staging_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name != DEBUG_SUBREPO && entry.path().join("repodata").is_dir() {
+            subrepos.push(name);
+        }
+    }
+    Ok(subrepos)
+}
+
+/// Stage the packages of a previous compose to generate deltas against, returning how many were
+///
+/// Deltas are optional, so packages that can't be staged, i.e. because they were deleted since,
+/// are skipped with a warning instead of failing the assembly.
+pub(crate) async fn stage_old_packages(
+    old_pkgs: &[RpmRef],
+    old_dir: &Path,
+) -> color_eyre::Result<usize> {
+    tokio::fs::create_dir_all(old_dir).await?;
+    let results = futures::future::join_all(old_pkgs.iter().map(|old| {
+        let (key, sha256) = (old.staged_object_key(), old.staged_sha256());
+        link_object(key, sha256, old.id.to_string(), old_dir)
+    }))
+    .await;
+
+    let mut staged = 0;
+    for (old, result) in old_pkgs.iter().zip(results) {
+        match result {
+            Ok(()) => staged += 1,
+            Err(e) => warn!(?e, pkg = %old.id, "cannot stage old package, skipping its delta"),
+        }
+    }
+    Ok(staged)
+}
+
+/// Symlink a cached object into a repo directory, prefixed with the package ID
+///
+/// The object is checked against `sha256`, the package's digest in the database, if known.
+async fn link_object(
+    object_key: &str,
+    sha256: Option<&str>,
+    id: String,
+    dir: &Path,
+) -> color_eyre::Result<()> {
+    let cache_key_filename = object_key.rsplit('/').next().unwrap();
+    let obj_store = object_store();
+    let src = obj_store
+        .get_verified(object_key, sha256)
+        .await?
+        .canonicalize()?;
+    tracing::debug!(?src);
+
+    if dir.join(cache_key_filename).exists() {
+        warn!(
+            ?object_key,
+            "File name seems to conflict, removing already existing file"
+        );
+        tokio::fs::remove_file(&dir.join(cache_key_filename)).await?;
+    }
+
+    let target_path = dir.join(format!("{id}-{cache_key_filename}"));
+    tokio::fs::remove_file(&target_path).await.ok();
+    let metadata = tokio::fs::metadata(&src).await?;
+    tracing::trace!(?metadata);
+    if target_path.metadata().is_ok() {
+        warn!(
+            ?object_key,
+            "File name seems to conflict, removing already existing file"
+        );
+        tokio::fs::remove_file(&target_path).await?;
+    }
+
+    debug!("Symlinking {} to {}", src.display(), target_path.display());
+    tokio::fs::symlink(src, target_path).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::rpm::RPM_TABLE;
+
+    #[test]
+    fn test_arch_allowed() {
+        let mut tag = Tag::new("foobar".to_owned());
+        assert!(tag.arch_allowed("s390x"));
+
+        tag.arches = vec!["x86_64".to_owned(), "aarch64".to_owned()];
+        assert!(tag.arch_allowed("x86_64"));
+        assert!(tag.arch_allowed("noarch"));
+        assert!(tag.arch_allowed("src"));
+        assert!(!tag.arch_allowed("i686"));
+    }
+
+    #[test]
+    fn test_retention_expired() {
+        let base = Rpm::from_path(
+            "test/data/anda-srpm-macros-0:0.2.6-1.fc41.noarch.rpm",
+            "foobar",
+        )
+        .unwrap();
+        let now = chrono::Utc::now();
+        let version = |release: &str, age_days: i64| {
+            let mut pkg = base.clone();
+            pkg.id = Thing::from((RPM_TABLE, surrealdb::sql::Id::ulid()));
+            pkg.release = release.to_owned();
+            pkg.timestamp = (now - chrono::Duration::days(age_days)).into();
+            pkg.available = true;
+            pkg
+        };
+        // a newer upload that never became available doesn't push the others out
+        let mut unavailable = version("5", 0);
+        unavailable.available = false;
+        let pkgs = vec![
+            version("1", 30),
+            version("2", 20),
+            version("3", 10),
+            version("4", 1),
+            unavailable,
+        ];
+        let releases = |policy: &RetentionPolicy| {
+            let mut expired = policy
+                .expired(pkgs.clone(), now)
+                .into_iter()
+                .map(|p| p.release)
+                .collect::<Vec<_>>();
+            expired.sort();
+            expired
+        };
+
+        assert!(releases(&RetentionPolicy::default()).is_empty());
+
+        let keep_two = RetentionPolicy {
+            keep_last: Some(2),
+            max_age_days: None,
+        };
+        assert_eq!(releases(&keep_two), ["1", "2"]);
+
+        let two_weeks = RetentionPolicy {
+            keep_last: None,
+            max_age_days: Some(14),
+        };
+        assert_eq!(releases(&two_weeks), ["1", "2"]);
+
+        // the newest version is kept even when it's too old
+        let one_day = RetentionPolicy {
+            keep_last: Some(1),
+            max_age_days: Some(0),
+        };
+        assert_eq!(releases(&one_day), ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn test_subrepos() {
+        let pkg = |name: &str, arch: &str| {
+            let object_key = format!("rpm/{name}.rpm");
+            let mut pkg = RpmRef::new(ulid::Ulid::new(), name.to_owned(), object_key);
+            pkg.arch = arch.to_owned();
+            pkg
+        };
+        let pkgs = [
+            pkg("bash", "x86_64"),
+            pkg("bash", "aarch64"),
+            pkg("fonts", "noarch"),
+        ];
+        let layout = |tag: &Tag| {
+            tag.subrepos(&pkgs)
+                .into_iter()
+                .map(|(dir, pkgs)| {
+                    let arches = pkgs.into_iter().map(|p| p.arch).collect::<Vec<_>>();
+                    (dir, arches)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut tag = Tag::new("foobar".to_owned());
+        let flat = layout(&tag);
+        assert_eq!(flat.len(), 1);
+        assert_eq!(flat[0].0, "");
+        assert_eq!(flat[0].1, ["x86_64", "aarch64", "noarch"]);
+
+        tag.split_arches = true;
+        tag.arches = vec!["x86_64".to_owned(), "aarch64".to_owned(), "s390x".to_owned()];
+        let merged = layout(&tag);
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged[0].0, "aarch64");
+        assert_eq!(merged[0].1, ["aarch64", "noarch"]);
+        // configured architectures get a repo even without packages of their own
+        assert_eq!(merged[1].0, "s390x");
+        assert_eq!(merged[1].1, ["noarch"]);
+
+        tag.noarch = NoarchPolicy::Separate;
+        let separate = layout(&tag);
+        assert_eq!(separate.len(), 4);
+        assert_eq!(separate[0].1, ["aarch64"]);
+        assert_eq!(separate[1].0, "noarch");
+        assert_eq!(separate[1].1, ["noarch"]);
+
+        // source packages get their own repo, whatever the layout
+        let mut srpm = pkg("bash", "src");
+        srpm.is_source = true;
+        let pkgs = [pkgs[0].clone(), srpm];
+        for split_arches in [false, true] {
+            tag.split_arches = split_arches;
+            let subrepos = tag.subrepos(&pkgs);
+            assert_eq!(subrepos[SOURCE_SUBREPO].len(), 1);
+            assert!(subrepos[SOURCE_SUBREPO][0].is_source);
+            assert_eq!(subrepos.values().flatten().filter(|p| p.is_source).count(), 1);
+        }
+
+        let debug = [pkg("bash", "x86_64"), pkg("bash-debuginfo", "x86_64")];
+        tag.split_arches = false;
+        assert_eq!(tag.subrepos(&debug).len(), 1);
+
+        tag.split_debuginfo = true;
+        let subrepos = tag.subrepos(&debug);
+        assert_eq!(subrepos[""].len(), 1);
+        assert_eq!(subrepos[DEBUG_SUBREPO][0].name, "bash-debuginfo");
+
+        tag.split_arches = true;
+        let subrepos = tag.subrepos(&debug);
+        assert_eq!(subrepos["x86_64"][0].name, "bash");
+        assert_eq!(subrepos["debug/x86_64"][0].name, "bash-debuginfo");
+    }
+
+    #[test]
+    fn test_export_path() {
+        let base = Path::new("/srv/export");
+        let mut tag = Tag::new("terra41".to_owned());
+        assert_eq!(tag.export_dir_in(base), base.join("terra41"));
+        assert!(tag.arch_export_dirs_in(base, ["x86_64"]).is_empty());
+
+        tag.export_path = Some("terra/{tag}".to_owned());
+        assert_eq!(tag.export_dir_in(base), base.join("terra/terra41"));
+
+        tag.export_path = Some("/srv/repos/legacy".to_owned());
+        assert_eq!(tag.export_dir_in(base), Path::new("/srv/repos/legacy"));
+
+        // with `{arch}`, the compose itself stays at the default location
+        tag.export_path = Some("/srv/repos/terra/{tag}/{arch}".to_owned());
+        assert_eq!(tag.export_dir_in(base), base.join("terra41"));
+        let dirs = tag.arch_export_dirs_in(base, ["", "x86_64", SOURCE_SUBREPO, "debug/x86_64"]);
+        assert_eq!(
+            dirs,
+            [
+                (
+                    "x86_64".to_owned(),
+                    PathBuf::from("/srv/repos/terra/terra41/x86_64")
+                ),
+                (
+                    SOURCE_SUBREPO.to_owned(),
+                    PathBuf::from("/srv/repos/terra/terra41/source")
+                ),
+            ]
         );
 
-        if export_dir.exists() {
-            tokio::fs::remove_dir_all(&export_dir).await?;
+        assert!(Tag::validate_export_path("/srv/repos/{tag}/{arch}").is_ok());
+        assert!(Tag::validate_export_path("").is_err());
+        assert!(Tag::validate_export_path("{tag}/{release}").is_err());
+        assert!(Tag::validate_export_path("../{tag}").is_err());
+        for template in [".", "./{tag}", "/", "//"] {
+            assert!(Tag::validate_export_path(template).is_err(), "{template}");
         }
+    }
 
-        tokio::fs::symlink(&staging_dir.canonicalize()?, &export_dir).await?;
+    #[test]
+    fn test_claimed_export_dirs() {
+        let base = Path::new("/srv/export");
+        let mut tag = Tag::new("terra41".to_owned());
+        tag.split_debuginfo = true;
+        assert_eq!(
+            tag.claimed_export_dirs_in(base),
+            [base.join("terra41"), base.join("terra41-debug")]
+        );
 
-        Ok(())
+        tag.split_debuginfo = false;
+        tag.export_path = Some("/srv/repos/{tag}/{arch}/os".to_owned());
+        assert_eq!(
+            tag.claimed_export_dirs_in(base),
+            [base.join("terra41"), PathBuf::from("/srv/repos/terra41")]
+        );
+        tag.arches = vec!["x86_64".to_owned()];
+        assert_eq!(
+            tag.claimed_export_dirs_in(base),
+            [
+                base.join("terra41"),
+                PathBuf::from("/srv/repos/terra41/x86_64/os"),
+                PathBuf::from("/srv/repos/terra41/noarch/os"),
+                PathBuf::from("/srv/repos/terra41/source/os"),
+            ]
+        );
     }
 }
@@ -0,0 +1,94 @@
+use color_eyre::{eyre::ContextCompat, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use surrealdb::sql::Thing;
+
+use super::{permission::Permission, DB};
+
+pub const TOKEN_TABLE: &str = "api_token";
+const TOKEN_PREFIX: &str = "sat_";
+
+/// Hash a bearer token for storage and lookup
+///
+/// Tokens are random, so a plain SHA256 is enough here.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// An API token, only the hash of the token itself is stored
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: Thing,
+    pub name: String,
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    token_hash: String,
+    /// Admin tokens may manage other tokens
+    #[serde(default)]
+    pub admin: bool,
+    pub created_at: surrealdb::sql::Datetime,
+}
+
+impl ApiToken {
+    /// Create a new token, returning it along with the plaintext token
+    ///
+    /// The plaintext token can't be recovered after this.
+    pub fn new(name: String, admin: bool) -> (Self, String) {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = format!("{TOKEN_PREFIX}{}", hex::encode(bytes));
+
+        let api_token = Self {
+            id: Thing::from((TOKEN_TABLE, surrealdb::sql::Id::ulid())),
+            name,
+            token_hash: hash_token(&token),
+            admin,
+            created_at: surrealdb::sql::Datetime::default(),
+        };
+
+        (api_token, token)
+    }
+
+    /// Strip the token hash, for returning over the API
+    pub fn redacted(mut self) -> Self {
+        self.token_hash = String::new();
+        self
+    }
+
+    pub async fn save(&self) -> Result<Self> {
+        let query = DB
+            .upsert((TOKEN_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+
+        query.context("nothing returned from insert")
+    }
+
+    pub async fn get(id: &str) -> Result<Option<Self>> {
+        Ok(DB.retry(|| DB.select((TOKEN_TABLE, id))).await?)
+    }
+
+    pub async fn get_all() -> Result<Vec<Self>> {
+        Ok(DB.retry(|| DB.select(TOKEN_TABLE)).await?)
+    }
+
+    /// Look up a token by its plaintext value
+    pub async fn find(token: &str) -> Result<Option<Self>> {
+        let a: Option<Self> = DB
+            .retry(|| {
+                DB.query("SELECT * FROM api_token WHERE token_hash = $hash LIMIT 1;")
+                    .bind(("hash", hash_token(token)))
+            })
+            .await?
+            .take(0)?;
+
+        Ok(a)
+    }
+
+    /// Revoke the token, along with every permission granted to it
+    pub async fn delete(&self) -> Result<()> {
+        Permission::delete_for_token(&self.id).await?;
+        let _: Option<Self> = DB.delete((TOKEN_TABLE, self.id.id.to_raw())).await?;
+        Ok(())
+    }
+}
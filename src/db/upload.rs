@@ -0,0 +1,82 @@
+use std::path::PathBuf;
+
+use color_eyre::{eyre::ContextCompat, Result};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Thing;
+
+use super::DB;
+use crate::config::CONFIG;
+
+pub const UPLOAD_SESSION_TABLE: &str = "upload_session";
+
+/// A resumable upload in progress
+///
+/// Chunks are appended to a staging file in the cache dir, `offset` is the
+/// number of bytes received so far, which is where the client should resume.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub id: Thing,
+    pub tag: String,
+    pub filename: String,
+    pub offset: u64,
+    pub created_at: surrealdb::sql::Datetime,
+}
+
+impl UploadSession {
+    pub fn new(tag: String, filename: String) -> Self {
+        Self {
+            id: Thing::from((UPLOAD_SESSION_TABLE, surrealdb::sql::Id::ulid())),
+            tag,
+            filename,
+            offset: 0,
+            created_at: surrealdb::sql::Datetime::default(),
+        }
+    }
+
+    /// Where the received chunks are staged
+    pub fn staging_path(&self) -> PathBuf {
+        CONFIG
+            .get()
+            .unwrap()
+            .cache_dir
+            .join(format!("{}.part", self.id.id.to_raw()))
+    }
+
+    pub async fn save(&self) -> Result<Self> {
+        let query = DB
+            .upsert((UPLOAD_SESSION_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+
+        query.context("nothing returned from insert")
+    }
+
+    /// Move the session to a new offset, if nothing else moved it since it was read
+    ///
+    /// Returns `None` if the session changed or is gone, instead of overwriting it.
+    pub async fn advance(&self, offset: u64) -> Result<Option<Self>> {
+        let updated: Option<Self> = DB
+            .query("UPDATE $upload SET offset = $offset WHERE offset = $from RETURN AFTER;")
+            .bind(("upload", self.id.clone()))
+            .bind(("offset", offset))
+            .bind(("from", self.offset))
+            .await?
+            .take(0)?;
+        Ok(updated)
+    }
+
+    pub async fn get(id: &str) -> Result<Option<Self>> {
+        Ok(DB.retry(|| DB.select((UPLOAD_SESSION_TABLE, id))).await?)
+    }
+
+    /// Remove the session along with its staging file
+    pub async fn delete(&self) -> Result<()> {
+        let _: Option<Self> = DB
+            .delete((UPLOAD_SESSION_TABLE, self.id.id.to_raw()))
+            .await?;
+        match tokio::fs::remove_file(self.staging_path()).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Webhook subscriptions, receiving events from the event log over HTTP
+use color_eyre::{eyre::ContextCompat, Result};
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    sql::{Datetime, Thing},
+    RecordId,
+};
+
+use super::{event_log::LogEvent, DB};
+
+pub const WEBHOOK_TABLE: &str = "webhook";
+pub const WEBHOOK_DELIVERY_TABLE: &str = "webhook_delivery";
+
+/// A URL subscribed to events
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: Thing,
+    pub url: String,
+    /// Event types to receive, i.e. `upload`, empty for all types
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Tags to receive events for, empty for all events, including those not about a tag
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Shared secret the payload is signed with, see [`crate::webhook`]
+    #[serde(default)]
+    pub secret: Option<String>,
+    pub created_at: Datetime,
+}
+
+/// A webhook without its secret, for listing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookRef {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<String>,
+    pub tags: Vec<String>,
+    /// Whether deliveries are signed
+    pub signed: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<&Webhook> for WebhookRef {
+    fn from(hook: &Webhook) -> Self {
+        Self {
+            id: hook.id.id.to_raw(),
+            url: hook.url.clone(),
+            events: hook.events.clone(),
+            tags: hook.tags.clone(),
+            signed: hook.secret.is_some(),
+            created_at: hook.created_at.to_utc(),
+        }
+    }
+}
+
+impl Webhook {
+    pub fn new(
+        url: String,
+        events: Vec<String>,
+        tags: Vec<String>,
+        secret: Option<String>,
+    ) -> Self {
+        Self {
+            id: Thing::from((WEBHOOK_TABLE, surrealdb::sql::Id::ulid())),
+            url,
+            events,
+            tags,
+            secret,
+            created_at: Datetime::default(),
+        }
+    }
+
+    /// Whether this webhook wants to receive the given event
+    pub fn matches(&self, event: &LogEvent) -> bool {
+        let tag_matches = self.tags.is_empty()
+            || event.tag.as_ref().is_some_and(|t| self.tags.contains(t));
+        let action_matches = self.events.is_empty() || self.events.contains(&event.action);
+
+        tag_matches && action_matches
+    }
+
+    pub async fn save(&self) -> Result<Self> {
+        let query = DB
+            .upsert((WEBHOOK_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+
+        query.context("nothing returned from insert")
+    }
+
+    pub async fn get(id: &str) -> Result<Option<Self>> {
+        Ok(DB.retry(|| DB.select((WEBHOOK_TABLE, id))).await?)
+    }
+
+    pub async fn get_all() -> Result<Vec<Self>> {
+        Ok(DB.retry(|| DB.select(WEBHOOK_TABLE)).await?)
+    }
+
+    pub async fn delete(&self) -> Result<()> {
+        let _: Option<Self> = DB.delete((WEBHOOK_TABLE, self.id.id.to_raw())).await?;
+        Ok(())
+    }
+}
+
+/// An event waiting to be delivered to a webhook, kept until it's delivered or given up on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    pub id: Thing,
+    pub webhook: RecordId,
+    pub event: LogEvent,
+    /// Failed attempts so far
+    #[serde(default)]
+    pub attempts: u32,
+    /// When the next attempt is due
+    pub next_attempt: Datetime,
+}
+
+impl WebhookDelivery {
+    /// A delivery of the event to the webhook, due right away
+    pub fn new(hook: &Webhook, event: &LogEvent) -> Self {
+        Self {
+            id: Thing::from((WEBHOOK_DELIVERY_TABLE, surrealdb::sql::Id::ulid())),
+            webhook: RecordId::from_table_key(WEBHOOK_TABLE, hook.id.id.to_raw()),
+            event: event.clone(),
+            attempts: 0,
+            next_attempt: Datetime::default(),
+        }
+    }
+
+    pub async fn save(&self) -> Result<Self> {
+        let query = DB
+            .upsert((WEBHOOK_DELIVERY_TABLE, self.id.id.to_raw()))
+            .content(self.clone())
+            .await?;
+
+        query.context("nothing returned from insert")
+    }
+
+    /// Claim the deliveries that are due, pushing their next attempt back by `ttl_secs`
+    ///
+    /// Other instances skip claimed deliveries while they're attempted here, and pick them up
+    /// again once the claim runs out if this instance goes away in the meantime.
+    pub async fn claim_due(ttl_secs: u64) -> Result<Vec<Self>> {
+        // a single statement, so a delivery is never claimed twice
+        let due: Vec<Self> = DB
+            .query(
+                "UPDATE webhook_delivery \
+                 SET next_attempt = time::now() + duration::from::secs($ttl) \
+                 WHERE next_attempt <= time::now() RETURN BEFORE;",
+            )
+            .bind(("ttl", ttl_secs))
+            .await?
+            .take(0)?;
+        Ok(due)
+    }
+
+    pub async fn delete(&self) -> Result<()> {
+        let _: Option<Self> = DB
+            .delete((WEBHOOK_DELIVERY_TABLE, self.id.id.to_raw()))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let event = LogEvent::new("upload", "ci".to_owned(), serde_json::Value::Null).tag("f41");
+
+        let all = Webhook::new("https://example.com".to_owned(), Vec::new(), Vec::new(), None);
+        assert!(all.matches(&event));
+
+        let uploads = Webhook::new(
+            "https://example.com".to_owned(),
+            vec!["upload".to_owned()],
+            vec!["f41".to_owned()],
+            None,
+        );
+        assert!(uploads.matches(&event));
+        let delete = LogEvent::new("delete", "ci".to_owned(), serde_json::Value::Null).tag("f41");
+        assert!(!uploads.matches(&delete));
+
+        let other_tag = Webhook::new(
+            "https://example.com".to_owned(),
+            Vec::new(),
+            vec!["f40".to_owned()],
+            None,
+        );
+        assert!(!other_tag.matches(&event));
+    }
+}
@@ -0,0 +1,121 @@
+//! Debian version comparison, following the semantics of dpkg's `verrevcmp`
+use std::cmp::Ordering;
+
+/// Weight of a character in the non-digit part of a version, the end of the string weighs 0
+fn order(c: Option<&u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => i32::from(*c),
+        // a tilde sorts before everything, even the end of the string
+        Some(b'~') => -1,
+        Some(c) => i32::from(*c) + 256,
+    }
+}
+
+/// Compare two upstream versions (or revisions) the way dpkg does
+pub fn verrevcmp(a: &str, b: &str) -> Ordering {
+    let mut one = a.as_bytes();
+    let mut two = b.as_bytes();
+    let is_digit = |s: &[u8]| s.first().is_some_and(u8::is_ascii_digit);
+
+    while !one.is_empty() || !two.is_empty() {
+        while (!one.is_empty() && !is_digit(one)) || (!two.is_empty() && !is_digit(two)) {
+            let ord = order(one.first()).cmp(&order(two.first()));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            one = one.get(1..).unwrap_or_default();
+            two = two.get(1..).unwrap_or_default();
+        }
+
+        while one.first() == Some(&b'0') {
+            one = &one[1..];
+        }
+        while two.first() == Some(&b'0') {
+            two = &two[1..];
+        }
+
+        let mut first_diff = Ordering::Equal;
+        while is_digit(one) && is_digit(two) {
+            if first_diff == Ordering::Equal {
+                first_diff = one[0].cmp(&two[0]);
+            }
+            one = &one[1..];
+            two = &two[1..];
+        }
+
+        // the longer number is bigger, leading zeros are already gone
+        if is_digit(one) {
+            return Ordering::Greater;
+        }
+        if is_digit(two) {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Split a full `[epoch:]upstream[-revision]` version into its parts
+///
+/// A missing epoch is 0 and a missing revision is empty, which compares equal to `0`.
+pub fn split_version(version: &str) -> (u32, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => (epoch.parse().unwrap_or_default(), rest),
+        None => (0, version),
+    };
+    let (upstream, revision) = rest.rsplit_once('-').unwrap_or((rest, ""));
+    (epoch, upstream, revision)
+}
+
+/// Compare two full Debian versions, i.e. `1:2.0-1` and `2.1-3`
+pub fn version_cmp(a: &str, b: &str) -> Ordering {
+    let (epoch_a, upstream_a, revision_a) = split_version(a);
+    let (epoch_b, upstream_b, revision_b) = split_version(b);
+    epoch_a
+        .cmp(&epoch_b)
+        .then_with(|| verrevcmp(upstream_a, upstream_b))
+        .then_with(|| verrevcmp(revision_a, revision_b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verrevcmp() {
+        let cases = [
+            ("1.0", "1.0", Ordering::Equal),
+            ("1.0", "2.0", Ordering::Less),
+            ("1.10", "1.9", Ordering::Greater),
+            ("001", "1", Ordering::Equal),
+            ("1.0a", "1.0", Ordering::Greater),
+            ("1.0", "1.0.1", Ordering::Less),
+            ("1.0~rc1", "1.0", Ordering::Less),
+            ("1.0~rc1", "1.0~rc2", Ordering::Less),
+            ("1.0~~", "1.0~", Ordering::Less),
+            ("1.0+dfsg", "1.0", Ordering::Greater),
+            ("1.0+dfsg", "1.0a", Ordering::Greater),
+            ("", "0", Ordering::Equal),
+        ];
+
+        for (a, b, expected) in cases {
+            assert_eq!(verrevcmp(a, b), expected, "{a} vs {b}");
+            assert_eq!(verrevcmp(b, a), expected.reverse(), "{b} vs {a}");
+        }
+    }
+
+    #[test]
+    fn test_version_cmp() {
+        assert_eq!(split_version("1:2.0-1ubuntu1"), (1, "2.0", "1ubuntu1"));
+        assert_eq!(split_version("2.0-rc1-3"), (0, "2.0-rc1", "3"));
+        assert_eq!(split_version("2.0"), (0, "2.0", ""));
+
+        assert_eq!(version_cmp("1:1.0-1", "2.0-1"), Ordering::Greater);
+        assert_eq!(version_cmp("1.0-2", "1.0-10"), Ordering::Less);
+        assert_eq!(version_cmp("1.0", "1.0-0"), Ordering::Equal);
+    }
+}
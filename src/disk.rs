@@ -0,0 +1,68 @@
+//! Upload size and disk space limits
+//!
+//! Uploads are staged in the cache directory before they reach the object store, so they're
+//! checked against [`Config::max_upload_size`] and the free space left on its filesystem before
+//! they're accepted, instead of failing halfway once the disk is full.
+//!
+//! [`Config::max_upload_size`]: crate::config::Config::max_upload_size
+use std::path::Path;
+
+use crate::config::CONFIG;
+use crate::errors::{Error, Result};
+
+/// Bytes available to unprivileged users on the filesystem holding `path`
+pub fn free_space(path: &Path) -> std::io::Result<u64> {
+    let stat = rustix::fs::statvfs(path)?;
+    Ok(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+/// Check that an upload of `size` bytes can be accepted into `dir`
+///
+/// `size` is 0 when it isn't known in advance, i.e. without a `Content-Length`, in which case
+/// only the free space left is checked and the size is enforced with [`check_size`] as the
+/// upload is received.
+pub fn check_upload(dir: &Path, size: u64) -> Result<()> {
+    let config = CONFIG.get().unwrap();
+    check_size(size)?;
+    check_space(size, free_space(dir)?, config.min_free_space)
+}
+
+/// Check that `size` bytes are within the configured upload size limit
+pub fn check_size(size: u64) -> Result<()> {
+    let max = CONFIG.get().unwrap().max_upload_size;
+    if size > max {
+        return Err(Error::PayloadTooLarge(format!(
+            "upload of {size} bytes exceeds the limit of {max} bytes"
+        )));
+    }
+    Ok(())
+}
+
+fn check_space(size: u64, free: u64, min_free: u64) -> Result<()> {
+    if free < size.saturating_add(min_free) {
+        return Err(Error::InsufficientStorage(format!(
+            "{free} bytes free, cannot accept an upload of {size} bytes"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_space() {
+        assert!(check_space(100, 1000, 500).is_ok());
+        assert!(check_space(600, 1000, 500).is_err());
+        // unknown size, only the free space is checked
+        assert!(check_space(0, 400, 500).is_err());
+        assert!(check_space(2000, 1000, 0).is_err());
+    }
+
+    #[test]
+    fn test_free_space() {
+        let dir = std::env::temp_dir();
+        assert!(free_space(&dir).unwrap() > 0);
+    }
+}
@@ -20,7 +20,43 @@ pub enum Error {
     #[error("Not Found")]
     #[status_code(StatusCode::NOT_FOUND)]
     NotFound,
+
+    #[error("Unauthorized")]
+    #[status_code(StatusCode::UNAUTHORIZED)]
+    Unauthorized,
+
+    #[error("Forbidden")]
+    #[status_code(StatusCode::FORBIDDEN)]
+    Forbidden,
+
+    #[error("Conflict: {0}")]
+    #[status_code(StatusCode::CONFLICT)]
+    Conflict(String),
+
+    #[error("Bad Request: {0}")]
+    #[status_code(StatusCode::BAD_REQUEST)]
+    BadRequest(String),
+
+    #[error("Payload Too Large: {0}")]
+    #[status_code(StatusCode::PAYLOAD_TOO_LARGE)]
+    PayloadTooLarge(String),
+
+    #[error("Insufficient Storage: {0}")]
+    #[status_code(StatusCode::INSUFFICIENT_STORAGE)]
+    InsufficientStorage(String),
     
     #[error("Tag error: {0}")]
     Tag(#[from] crate::router::tag::TagError),
+
+    #[error("RPM error: {0}")]
+    Rpm(#[from] crate::router::rpm::RpmError),
+
+    #[error("OCI error: {0}")]
+    Oci(#[from] crate::router::oci::OciError),
+
+    #[error("OSTree error: {0}")]
+    Ostree(#[from] crate::router::ostree::OstreeError),
+
+    #[error("Notification error: {0}")]
+    Notify(#[from] crate::router::notify::NotifyError),
 }
@@ -0,0 +1,169 @@
+//! Health checks of the services and tools Subatomic depends on
+//!
+//! Each component is checked separately, so a failing check points at what's actually broken.
+//! Only required components make the server unhealthy, the others mark it as degraded.
+//!
+//! [`ready`] is the subset of checks deciding whether the server should get traffic, i.e. for a
+//! Kubernetes readiness probe. Liveness isn't checked here, a running server is alive.
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use serde::Serialize;
+
+use crate::config::{Config, RepodataBackend};
+use crate::db::DB;
+use crate::obj_store::object_store;
+
+/// Time a single component gets to answer its check
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Status of a component, ordered from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    /// An optional component failed, i.e. `createrepo_c` while generating repodata natively
+    Degraded,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ComponentHealth {
+    pub status: HealthStatus,
+    /// Whether the server can work without the component
+    pub required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Health {
+    /// Worst status of the components
+    pub status: HealthStatus,
+    pub components: BTreeMap<&'static str, ComponentHealth>,
+}
+
+impl Health {
+    fn new(components: BTreeMap<&'static str, ComponentHealth>) -> Self {
+        let status = components
+            .values()
+            .map(|c| c.status)
+            .max()
+            .unwrap_or(HealthStatus::Ok);
+        Self { status, components }
+    }
+}
+
+/// Check every component
+pub async fn check(config: &Config) -> Health {
+    let (database, objects, cache, repo_cache, createrepo) = tokio::join!(
+        component(true, check_database()),
+        component(true, check_object_store()),
+        component(true, check_writable(&config.cache_dir)),
+        component(true, check_writable(&config.repo_cache_dir)),
+        component(
+            config.repodata_backend == RepodataBackend::Createrepo,
+            check_createrepo(),
+        ),
+    );
+    let components = BTreeMap::from([
+        ("database", database),
+        ("object_store", objects),
+        ("cache_dir", cache),
+        ("repo_cache_dir", repo_cache),
+        ("createrepo_c", createrepo),
+    ]);
+
+    Health::new(components)
+}
+
+/// Check whether the server is ready to serve requests
+///
+/// Every component is required: the config must be usable, and the database connected
+/// and the object store reachable.
+pub async fn ready(config: &Config) -> Health {
+    let (config, database, objects) = tokio::join!(
+        component(true, check_config(config)),
+        component(true, check_database()),
+        component(true, check_object_store()),
+    );
+    let components = BTreeMap::from([
+        ("config", config),
+        ("database", database),
+        ("object_store", objects),
+    ]);
+
+    Health::new(components)
+}
+
+async fn component(required: bool, check: impl Future<Output = Result<()>>) -> ComponentHealth {
+    let res = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(res) => res,
+        Err(_) => Err(eyre!("timed out after {CHECK_TIMEOUT:?}")),
+    };
+    match res {
+        Ok(()) => ComponentHealth {
+            status: HealthStatus::Ok,
+            required,
+            error: None,
+        },
+        Err(e) => ComponentHealth {
+            status: if required {
+                HealthStatus::Error
+            } else {
+                HealthStatus::Degraded
+            },
+            required,
+            error: Some(format!("{e:#}")),
+        },
+    }
+}
+
+async fn check_database() -> Result<()> {
+    if !DB.is_connected() {
+        return Err(eyre!("not connected yet"));
+    }
+    Ok(DB.get().health().await?)
+}
+
+async fn check_object_store() -> Result<()> {
+    object_store().check().await
+}
+
+/// Check that the directories the config points to can be written to
+async fn check_config(config: &Config) -> Result<()> {
+    for dir in [&config.cache_dir, &config.repo_cache_dir, &config.export_dir] {
+        check_writable(dir).await?;
+    }
+    Ok(())
+}
+
+/// Check that a file can be created in the directory
+///
+/// Missing directories are created, like they would be on first use.
+async fn check_writable(dir: &Path) -> Result<()> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .map_err(|e| eyre!("cannot create {}: {e}", dir.display()))?;
+    let probe = dir.join(format!(".health-check-{}", ulid::Ulid::new()));
+    tokio::fs::write(&probe, b"ok")
+        .await
+        .map_err(|e| eyre!("{} is not writable: {e}", dir.display()))?;
+    tokio::fs::remove_file(&probe).await?;
+    Ok(())
+}
+
+async fn check_createrepo() -> Result<()> {
+    let output = tokio::process::Command::new("createrepo_c")
+        .arg("--version")
+        .output()
+        .await
+        .map_err(|e| eyre!("cannot run createrepo_c: {e}"))?;
+    if !output.status.success() {
+        return Err(eyre!("createrepo_c --version exited with {}", output.status));
+    }
+    Ok(())
+}
@@ -0,0 +1,264 @@
+//! Bulk import of RPMs from a directory on the server, i.e. to migrate an existing repo tree
+//!
+//! Each file imported is recorded, so running the same import again skips the files it already
+//! handled, unless they changed since.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use color_eyre::{eyre::eyre, Result};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Thing;
+use ulid::Ulid;
+
+use crate::config::CONFIG;
+use crate::db::{
+    event_log::{LogEvent, SYSTEM_ACTOR},
+    import::ImportEntry,
+    job::Job,
+    tag::TAG_TABLE,
+};
+use crate::router::rpm::{store_upload_path, StoredUpload};
+
+/// Files imported at the same time, unless the import asks otherwise
+pub const IMPORT_CONCURRENCY: usize = 8;
+/// Most files an import may ask to import at the same time
+pub const MAX_IMPORT_CONCURRENCY: usize = 64;
+/// How often, in files, the progress of an import is recorded on its job
+const PROGRESS_INTERVAL: usize = 50;
+
+/// An RPM found in the imported directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportFile {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Modification time, in seconds since the epoch
+    pub modified: i64,
+}
+
+/// Outcome of [`run`], counting files
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportReport {
+    /// RPMs found in the directory
+    pub total: usize,
+    pub imported: usize,
+    /// Files already uploaded to the tag by other means
+    pub duplicates: usize,
+    /// Files handled by an earlier run of the import, and unchanged since
+    pub resumed: usize,
+    /// Files that couldn't be imported, by path, with why
+    pub failed: BTreeMap<String, String>,
+}
+
+/// Progress of a running import, recorded on its job
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportProgress {
+    pub total: usize,
+    pub done: usize,
+    pub failed: usize,
+}
+
+/// Find every RPM under a directory, sorted by path
+///
+/// Symlinks aren't followed, so nothing outside of the directory is imported through them.
+pub fn scan(dir: &Path) -> Result<Vec<ImportFile>> {
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() || entry.path().extension() != Some("rpm".as_ref()) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        let modified = metadata
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        files.push(ImportFile {
+            path: entry.into_path(),
+            size: metadata.len(),
+            modified,
+        });
+    }
+    Ok(files)
+}
+
+/// Resolve a directory to import, relative to `IMPORT_ROOT`, refusing anything outside of it
+pub fn resolve_dir(root: &Path, dir: Option<&str>) -> Result<PathBuf> {
+    let root = root
+        .canonicalize()
+        .map_err(|e| eyre!("cannot open {}: {e}", root.display()))?;
+    let dir = match dir {
+        Some(dir) => root.join(dir.trim_start_matches('/')),
+        None => root.clone(),
+    };
+    let dir = dir
+        .canonicalize()
+        .map_err(|e| eyre!("cannot open {}: {e}", dir.display()))?;
+    if !dir.starts_with(&root) || !dir.is_dir() {
+        return Err(eyre!("{} is not a directory under the import root", dir.display()));
+    }
+    Ok(dir)
+}
+
+/// What happened to a single file
+enum Outcome {
+    Imported,
+    Duplicate,
+    Resumed,
+}
+
+/// Copy a file into the cache dir and upload it to the tag, recording it as imported
+///
+/// The source file is left untouched, uploads consume the staged copy.
+async fn import_file(tag: &str, file: &ImportFile) -> Result<Outcome> {
+    let path = file.path.to_string_lossy();
+    if let Some(entry) = ImportEntry::get(tag, &path).await? {
+        if entry.matches(file.size, file.modified) {
+            return Ok(Outcome::Resumed);
+        }
+    }
+
+    let cache_dir = &CONFIG.get().unwrap().cache_dir;
+    crate::disk::check_upload(cache_dir, file.size).map_err(|e| eyre!("{e}"))?;
+    let dest = cache_dir.join(format!("{}.rpm", Ulid::new()));
+    tokio::fs::copy(&file.path, &dest).await?;
+    let upload = match store_upload_path(&dest, tag).await {
+        Ok(upload) => upload,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&dest).await;
+            return Err(eyre!("{e}"));
+        }
+    };
+    let outcome = match &upload {
+        StoredUpload::New(rpm) => {
+            if let Err(e) = rpm.commit_to_db(true, false).await {
+                rpm.release_object().await?;
+                return Err(e);
+            }
+            Outcome::Imported
+        }
+        StoredUpload::Duplicate(_) => Outcome::Duplicate,
+    };
+
+    let rpm = upload.rpm().id.id.to_raw();
+    ImportEntry::new(tag, &path, file.size, file.modified, rpm)
+        .save()
+        .await?;
+    Ok(outcome)
+}
+
+/// Import every RPM under `dir` into a tag, `concurrency` at a time, reporting progress on a job
+///
+/// Imported packages become available like uploads with `prune=true` do. A file failing to
+/// import doesn't stop the others.
+pub async fn run(
+    job: Thing,
+    tag: String,
+    dir: PathBuf,
+    actor: String,
+    concurrency: usize,
+) -> Result<ImportReport> {
+    let scan_dir = dir.clone();
+    let files = tokio::task::spawn_blocking(move || scan(&scan_dir)).await??;
+    tracing::info!(%tag, dir = %dir.display(), count = files.len(), "importing packages");
+
+    let done = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    let total = files.len();
+    let (tag, job, done, failed) = (&tag, &job, &done, &failed);
+    let results = futures::stream::iter(files)
+        .map(|file| async move {
+            let res = import_file(tag, &file).await;
+            if res.is_err() {
+                failed.fetch_add(1, Ordering::Relaxed);
+            }
+            let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+            if done % PROGRESS_INTERVAL == 0 {
+                let failed = failed.load(Ordering::Relaxed);
+                let progress = ImportProgress {
+                    total,
+                    done,
+                    failed,
+                };
+                if let Err(e) = Job::set_progress(job, progress).await {
+                    tracing::warn!(?e, "failed to record import progress");
+                }
+            }
+            (file, res)
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut report = ImportReport {
+        total,
+        ..Default::default()
+    };
+    for (file, res) in results {
+        match res {
+            Ok(Outcome::Imported) => report.imported += 1,
+            Ok(Outcome::Duplicate) => report.duplicates += 1,
+            Ok(Outcome::Resumed) => report.resumed += 1,
+            Err(e) => {
+                let path = file.path.to_string_lossy().into_owned();
+                tracing::warn!(%tag, %path, ?e, "failed to import package");
+                report.failed.insert(path, format!("{e:#}"));
+            }
+        }
+    }
+
+    LogEvent::new(
+        "import_finished",
+        SYSTEM_ACTOR.to_owned(),
+        serde_json::json!({
+            "job": job.id.to_raw(),
+            "dir": dir,
+            "actor": actor,
+            "imported": report.imported,
+            "failed": report.failed.len(),
+        }),
+    )
+    .tag(tag)
+    .resource(TAG_TABLE, tag)
+    .record()
+    .await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_and_resolve_dir() {
+        let root = std::env::temp_dir().join(format!("subatomic-import-{}", Ulid::new()));
+        let dir = root.join("f41/x86_64");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("b.rpm"), b"b").unwrap();
+        std::fs::write(dir.join("a.rpm"), b"a").unwrap();
+        std::fs::write(dir.join("repomd.xml"), b"").unwrap();
+        // links leading out of the directory are skipped
+        let outside = std::env::temp_dir().join(format!("subatomic-outside-{}", Ulid::new()));
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("c.rpm"), b"c").unwrap();
+        std::os::unix::fs::symlink(outside.join("c.rpm"), dir.join("c.rpm")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("outside")).unwrap();
+
+        let files = scan(&root).unwrap();
+        let names = files
+            .iter()
+            .map(|file| file.path.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a.rpm", "b.rpm"]);
+        assert_eq!(files[0].size, 1);
+
+        let resolved = resolve_dir(&root, Some("f41")).unwrap();
+        assert_eq!(resolved, root.canonicalize().unwrap().join("f41"));
+        assert!(resolve_dir(&root, Some("../")).is_err());
+        assert!(resolve_dir(&root, Some("f41/x86_64/a.rpm")).is_err());
+        assert!(resolve_dir(&root, Some("missing")).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+}
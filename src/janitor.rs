@@ -0,0 +1,253 @@
+//! Cleanup of files left behind on local disk
+//!
+//! Failed uploads leave their staged files in the cache dir, failed assemblies their staging
+//! directory in the repo cache dir, and deleted packages their cached objects. The janitor
+//! removes whatever isn't referenced by the database or a compose anymore, once it's older than
+//! [`DynamicConfig::janitor_min_age`] so uploads and assemblies in progress are left alone.
+//!
+//! [`DynamicConfig::janitor_min_age`]: crate::config::DynamicConfig::janitor_min_age
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use color_eyre::Result;
+use serde::Serialize;
+
+use crate::cache::linked_files;
+use crate::config::Config;
+use crate::db::{
+    deb::DEB_PREFIX,
+    generic::GENERIC_PREFIX,
+    oci::OCI_PREFIX,
+    ostree::OSTREE_PREFIX,
+    pacman::PACMAN_PREFIX,
+    rpm::RPM_PREFIX,
+    tag::{Tag, TagCompose},
+    upload::UploadSession,
+    DB,
+};
+use crate::obj_store::object_store;
+
+/// What the janitor removed
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct JanitorReport {
+    /// Files of uploads that never finished, in the cache dir
+    pub staged_files: usize,
+    /// Staging directories of composes that were never published, or don't exist anymore
+    pub staging_dirs: usize,
+    /// Cached packages and files no record refers to anymore
+    pub cache_entries: usize,
+    pub freed_bytes: u64,
+}
+
+/// Whether a file or directory was last modified before `min_age`
+fn is_older(path: &Path, min_age: Duration) -> bool {
+    std::fs::symlink_metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age >= min_age)
+}
+
+fn size_of(path: &Path) -> u64 {
+    walkdir::WalkDir::new(path)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.metadata().ok())
+        .filter(|meta| meta.is_file())
+        .map(|meta| meta.len())
+        .sum()
+}
+
+/// Remove everything left behind
+pub async fn run(config: &Config) -> Result<JanitorReport> {
+    let min_age = Duration::from_secs(crate::config::dynamic().janitor_min_age);
+    let mut report = JanitorReport::default();
+    clean_staged_files(&config.cache_dir, min_age, &mut report).await?;
+    clean_staging_dirs(config, min_age, &mut report).await?;
+    clean_cache(&config.repo_cache_dir, min_age, &mut report).await?;
+    tracing::info!(?report, "cleaned up left over files");
+    Ok(report)
+}
+
+/// Remove uploads staged at the top of the cache dir, except those of resumable upload sessions
+async fn clean_staged_files(
+    cache_dir: &Path,
+    min_age: Duration,
+    report: &mut JanitorReport,
+) -> Result<()> {
+    let mut entries = tokio::fs::read_dir(cache_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if !entry.file_type().await?.is_file() || !is_older(&path, min_age) {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(session) = name.strip_suffix(".part") {
+            if UploadSession::get(session).await?.is_some() {
+                continue;
+            }
+        }
+
+        let size = entry.metadata().await?.len();
+        tracing::debug!(?path, "removing left over upload");
+        tokio::fs::remove_file(&path).await?;
+        report.staged_files += 1;
+        report.freed_bytes += size;
+    }
+    Ok(())
+}
+
+/// Remove compose staging directories, `<tag>/<tag>_<compose>`, of composes that don't exist
+/// anymore or were never published, i.e. failed assemblies
+///
+/// Directories currently published to the export dir or a tag's export path are always kept.
+async fn clean_staging_dirs(
+    config: &Config,
+    min_age: Duration,
+    report: &mut JanitorReport,
+) -> Result<()> {
+    // tags with an export path are published outside of the export dir
+    let custom_exports = Tag::get_all()
+        .await?
+        .into_iter()
+        .filter(|tag| tag.export_path.is_some())
+        .map(|tag| tag.export_dir());
+    let published: HashSet<PathBuf> = std::fs::read_dir(&config.export_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .chain(custom_exports)
+        .filter_map(|path| std::fs::canonicalize(path).ok())
+        .collect();
+
+    let dirs = walkdir::WalkDir::new(&config.repo_cache_dir)
+        .min_depth(2)
+        .max_depth(2)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_dir())
+        .map(|e| e.into_path())
+        .collect::<Vec<_>>();
+
+    for dir in dirs {
+        let Some(compose_id) = dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.rsplit_once('_'))
+            .map(|(_, id)| id.to_owned())
+        else {
+            continue;
+        };
+        if !is_older(&dir, min_age) || published.contains(&dir.canonicalize()?) {
+            continue;
+        }
+        let compose = TagCompose::get(&compose_id).await?;
+        if compose.is_some_and(|compose| compose.published_at.is_some()) {
+            continue;
+        }
+
+        let size = size_of(&dir);
+        tracing::debug!(?dir, "removing abandoned staging directory");
+        tokio::fs::remove_dir_all(&dir).await?;
+        report.staging_dirs += 1;
+        report.freed_bytes += size;
+    }
+    Ok(())
+}
+
+/// Whether an object key is a package's, in the global scope or a namespace's
+fn is_package(key: &str) -> bool {
+    // namespaced keys are under `ns/{namespace}/`, see `crate::namespace::object_key`
+    let key = key
+        .strip_prefix(&format!("{}/", crate::namespace::OBJECT_PREFIX))
+        .and_then(|rest| rest.split_once('/'))
+        .map_or(key, |(_, key)| key);
+    [
+        RPM_PREFIX,
+        DEB_PREFIX,
+        PACMAN_PREFIX,
+        GENERIC_PREFIX,
+        OCI_PREFIX,
+        OSTREE_PREFIX,
+    ]
+    .iter()
+    .any(|prefix| key.starts_with(&format!("{prefix}/")))
+}
+
+/// Remove cached packages no package record refers to, unless a compose still links them
+async fn clean_cache(
+    repo_cache_dir: &Path,
+    min_age: Duration,
+    report: &mut JanitorReport,
+) -> Result<()> {
+    let mut res = DB
+        .retry(|| {
+            DB.query("SELECT VALUE object_key FROM rpm_package;")
+                .query(
+                    "SELECT VALUE signed_object_key FROM rpm_package WHERE signed_object_key != NONE;",
+                )
+                .query("SELECT VALUE object_key FROM deb_package;")
+                .query("SELECT VALUE object_key FROM pacman_package;")
+                .query("SELECT VALUE object_key FROM generic_file;")
+                .query("SELECT VALUE object_key FROM oci_blob;")
+                .query("SELECT VALUE object_key FROM ostree_commit;")
+        })
+        .await?;
+    let mut referenced: HashSet<String> = res.take::<Vec<String>>(0)?.into_iter().collect();
+    referenced.extend(res.take::<Vec<String>>(1)?);
+    referenced.extend(res.take::<Vec<String>>(2)?);
+    referenced.extend(res.take::<Vec<String>>(3)?);
+    referenced.extend(res.take::<Vec<String>>(4)?);
+    referenced.extend(res.take::<Vec<String>>(5)?);
+    referenced.extend(res.take::<Vec<String>>(6)?);
+
+    let store = object_store();
+    let cache = &store.cache;
+    let cache_dir = cache.cache_dir().canonicalize()?;
+    let linked = linked_files(repo_cache_dir);
+    for key in cache.list_cached().await? {
+        if !is_package(&key) || referenced.contains(&key) {
+            continue;
+        }
+        let path = cache_dir.join(&key);
+        if !is_older(&path, min_age) || linked.contains(&path) {
+            continue;
+        }
+
+        let size = size_of(&path);
+        tracing::debug!(?key, "removing unreferenced cache entry");
+        cache.remove(&key).await?;
+        report.cache_entries += 1;
+        report.freed_bytes += size;
+    }
+    Ok(())
+}
+
+/// Spawn the background task cleaning up left over files
+pub fn spawn_janitor_task(config: Config, interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = run(&config).await {
+                tracing::error!(?e, "failed to clean up left over files");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_package() {
+        assert!(is_package(&format!("{RPM_PREFIX}/01/a.rpm")));
+        assert!(is_package(&format!("ns/team/{DEB_PREFIX}/01/a.deb")));
+        assert!(!is_package("ns/team/comps/tag/comps.xml"));
+        assert!(!is_package("comps/tag/comps.xml"));
+        assert!(!is_package(&format!("ns/{RPM_PREFIX}")));
+    }
+}
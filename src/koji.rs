@@ -0,0 +1,464 @@
+//! Importing builds from a Koji hub into a tag
+//!
+//! The hub is asked over its XML-RPC API for the RPMs of a build, or of the latest builds in a
+//! Koji tag, which are then downloaded from the hub's `topurl` and uploaded like mirrored
+//! packages are.
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+use color_eyre::{eyre::eyre, Result};
+use quick_xml::{escape::escape, events::Event, Reader};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::digest::Digest;
+
+use crate::auth::Identity;
+use crate::db::{rpm::Rpm, tag::Tag};
+use crate::mirror::{self, RemotePackage, SyncReport};
+
+/// `COMPLETE` in Koji's build states
+const BUILD_COMPLETE: i64 = 1;
+/// Size of the lead at the start of every RPM, before the signature header
+const RPM_LEAD_SIZE: usize = 96;
+/// Magic number starting RPM headers
+const RPM_HEADER_MAGIC: [u8; 3] = [0x8e, 0xad, 0xe8];
+
+/// What to import from the hub
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KojiSource {
+    /// A single build, by NVR
+    Build(String),
+    /// The latest build of every package in a Koji tag
+    Tag(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct KojiBuild {
+    id: i64,
+    package_name: String,
+    version: String,
+    release: String,
+    #[serde(default)]
+    state: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct KojiRpm {
+    name: String,
+    version: String,
+    release: String,
+    #[serde(default)]
+    epoch: Option<u32>,
+    arch: String,
+    build_id: i64,
+    #[serde(default)]
+    size: Option<u64>,
+    /// MD5 of the header and payload, see [`payloadhash`]
+    #[serde(default)]
+    payloadhash: Option<String>,
+}
+
+impl KojiRpm {
+    fn nevra(&self) -> String {
+        let epoch = self.epoch.unwrap_or(0);
+        format!(
+            "{}-{epoch}:{}-{}.{}",
+            self.name, self.version, self.release, self.arch
+        )
+    }
+
+    /// Where the hub publishes the package, under `topurl`
+    fn url(&self, topurl: &str, build: &KojiBuild) -> String {
+        format!(
+            "{}/packages/{}/{}/{}/{}/{}-{}-{}.{}.rpm",
+            topurl.trim_end_matches('/'),
+            build.package_name,
+            build.version,
+            build.release,
+            self.arch,
+            self.name,
+            self.version,
+            self.release,
+            self.arch
+        )
+    }
+}
+
+/// Koji's `payloadhash` of an RPM file, the hex MD5 of its header and payload
+///
+/// It's what the RPM's own `SIGMD5` should be, and unlike a digest of the whole file doesn't
+/// change when the package is signed.
+pub fn payloadhash(path: &std::path::Path) -> Result<String> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut lead = [0; RPM_LEAD_SIZE];
+    file.read_exact(&mut lead)?;
+    let mut intro = [0; 16];
+    file.read_exact(&mut intro)?;
+    if intro[..3] != RPM_HEADER_MAGIC {
+        return Err(eyre!(
+            "{} is not an RPM, no signature header",
+            path.display()
+        ));
+    }
+    let count = u32::from_be_bytes(intro[8..12].try_into()?) as u64;
+    let data_size = u32::from_be_bytes(intro[12..16].try_into()?) as u64;
+    // the signature header is padded to 8 bytes
+    let size = 16 + count * 16 + data_size;
+    let padded = size.next_multiple_of(8);
+    std::io::copy(&mut file.by_ref().take(padded - 16), &mut std::io::sink())?;
+
+    let mut hasher = md5::Md5::new();
+    std::io::copy(&mut file, &mut DigestWriter(&mut hasher))?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Feeds whatever is written to it into a digest
+struct DigestWriter<'a, D>(&'a mut D);
+
+impl<D: Digest> std::io::Write for DigestWriter<'_, D> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A client for the XML-RPC API of a Koji hub
+///
+/// Only public hubs are reachable, see [`crate::remote`].
+pub struct KojiClient {
+    hub: String,
+}
+
+impl KojiClient {
+    pub fn new(hub: &str) -> Self {
+        Self {
+            hub: hub.to_owned(),
+        }
+    }
+
+    async fn call(&self, method: &str, params: &[Value]) -> Result<Value> {
+        tracing::debug!(hub = %self.hub, %method, "calling koji hub");
+        let res = crate::remote::client()
+            .post(crate::remote::check_url(&self.hub)?)
+            .header(reqwest::header::CONTENT_TYPE, "text/xml")
+            .body(encode_call(method, params))
+            .send()
+            .await?
+            .error_for_status()?;
+        parse_response(&res.text().await?)
+    }
+
+    /// The RPMs of a completed build, and the build itself
+    async fn build_rpms(&self, nvr: &str) -> Result<(Vec<KojiRpm>, Vec<KojiBuild>)> {
+        let build = self.call("getBuild", &[json!(nvr)]).await?;
+        if build.is_null() {
+            return Err(eyre!("no build {nvr} on {}", self.hub));
+        }
+        let build: KojiBuild = serde_json::from_value(build)?;
+        if build.state != Some(BUILD_COMPLETE) {
+            return Err(eyre!("build {nvr} isn't complete"));
+        }
+        let rpms = self.call("listRPMs", &[json!(build.id)]).await?;
+        Ok((serde_json::from_value(rpms)?, vec![build]))
+    }
+
+    /// The RPMs of the latest build of every package in a Koji tag, and those builds
+    async fn tagged_rpms(&self, tag: &str) -> Result<(Vec<KojiRpm>, Vec<KojiBuild>)> {
+        let kwargs = json!({ "__starstar": true, "latest": true });
+        let res = self.call("listTaggedRPMS", &[json!(tag), kwargs]).await?;
+        Ok(serde_json::from_value(res)?)
+    }
+}
+
+/// Encode an XML-RPC `methodCall`
+///
+/// Koji takes keyword arguments as a trailing struct with `__starstar` set.
+pub fn encode_call(method: &str, params: &[Value]) -> String {
+    let mut out = String::from(r#"<?xml version="1.0"?><methodCall><methodName>"#);
+    out.push_str(&escape(method));
+    out.push_str("</methodName><params>");
+    for param in params {
+        out.push_str("<param>");
+        encode_value(param, &mut out);
+        out.push_str("</param>");
+    }
+    out.push_str("</params></methodCall>");
+    out
+}
+
+fn encode_value(value: &Value, out: &mut String) {
+    out.push_str("<value>");
+    match value {
+        Value::Null => out.push_str("<nil/>"),
+        Value::Bool(b) => out.push_str(&format!("<boolean>{}</boolean>", u8::from(*b))),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => out.push_str(&format!("<int>{i}</int>")),
+            None => out.push_str(&format!("<double>{n}</double>")),
+        },
+        Value::String(s) => out.push_str(&format!("<string>{}</string>", escape(s.as_str()))),
+        Value::Array(items) => {
+            out.push_str("<array><data>");
+            for item in items {
+                encode_value(item, out);
+            }
+            out.push_str("</data></array>");
+        }
+        Value::Object(members) => {
+            out.push_str("<struct>");
+            for (name, value) in members {
+                out.push_str(&format!("<member><name>{}</name>", escape(name.as_str())));
+                encode_value(value, out);
+                out.push_str("</member>");
+            }
+            out.push_str("</struct>");
+        }
+    }
+    out.push_str("</value>");
+}
+
+/// An element of an XML-RPC response
+#[derive(Debug, Default)]
+struct Node {
+    name: String,
+    children: Vec<Node>,
+    text: String,
+}
+
+impl Node {
+    fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    fn expect(&self, name: &str) -> Result<&Node> {
+        self.child(name)
+            .ok_or_else(|| eyre!("<{}> without <{name}>", self.name))
+    }
+}
+
+fn parse_tree(xml: &str) -> Result<Node> {
+    let mut reader = Reader::from_str(xml);
+    let mut stack = vec![Node::default()];
+    let node = |name: &[u8]| Node {
+        name: String::from_utf8_lossy(name).into_owned(),
+        ..Default::default()
+    };
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => stack.push(node(e.local_name().as_ref())),
+            Event::Empty(e) => {
+                let empty = node(e.local_name().as_ref());
+                stack.last_mut().unwrap().children.push(empty);
+            }
+            Event::End(_) => {
+                let done = stack.pop().unwrap();
+                let parent = stack.last_mut().ok_or_else(|| eyre!("unbalanced XML"))?;
+                parent.children.push(done);
+            }
+            Event::Text(t) => stack.last_mut().unwrap().text.push_str(&t.unescape()?),
+            Event::CData(t) => {
+                let text = String::from_utf8_lossy(&t);
+                stack.last_mut().unwrap().text.push_str(&text);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    match (stack.pop(), stack.is_empty()) {
+        (Some(root), true) => Ok(root),
+        _ => Err(eyre!("unbalanced XML")),
+    }
+}
+
+fn decode_value(value: &Node) -> Result<Value> {
+    // a `<value>` without a type element is a string
+    let Some(inner) = value.children.first() else {
+        return Ok(Value::String(value.text.clone()));
+    };
+    let text = inner.text.trim();
+    let invalid = |e: &dyn std::fmt::Display| eyre!("invalid <{}> {text:?}: {e}", inner.name);
+    Ok(match inner.name.as_str() {
+        "string" => Value::String(inner.text.clone()),
+        "int" | "i4" | "i8" => Value::from(text.parse::<i64>().map_err(|e| invalid(&e))?),
+        "boolean" => Value::Bool(text == "1"),
+        "double" => Value::from(text.parse::<f64>().map_err(|e| invalid(&e))?),
+        "nil" => Value::Null,
+        "dateTime.iso8601" | "base64" => Value::String(text.to_owned()),
+        "array" => Value::Array(
+            inner
+                .expect("data")?
+                .children
+                .iter()
+                .filter(|c| c.name == "value")
+                .map(decode_value)
+                .collect::<Result<_>>()?,
+        ),
+        "struct" => {
+            let mut members = serde_json::Map::new();
+            for member in inner.children.iter().filter(|c| c.name == "member") {
+                let name = member.expect("name")?.text.clone();
+                members.insert(name, decode_value(member.expect("value")?)?);
+            }
+            Value::Object(members)
+        }
+        other => return Err(eyre!("unsupported XML-RPC type {other}")),
+    })
+}
+
+/// Decode an XML-RPC `methodResponse`, turning faults into errors
+pub fn parse_response(xml: &str) -> Result<Value> {
+    let root = parse_tree(xml)?;
+    let response = root.expect("methodResponse")?;
+    if let Some(fault) = response.child("fault") {
+        let fault = decode_value(fault.expect("value")?)?;
+        return Err(eyre!(
+            "koji fault {}: {}",
+            fault["faultCode"],
+            fault["faultString"].as_str().unwrap_or_default()
+        ));
+    }
+    let value = response.expect("params")?.expect("param")?.expect("value")?;
+    decode_value(value)
+}
+
+/// Import the RPMs of a Koji build or tag missing from the tag, `concurrency` at a time
+///
+/// Packages are downloaded from `topurl`, the hub's file server, and matched by NEVRA against
+/// those uploaded to the tag like [`mirror::sync`] does.
+pub async fn import(
+    identity: Identity,
+    tag: Tag,
+    hub: String,
+    topurl: String,
+    source: KojiSource,
+    concurrency: usize,
+) -> Result<SyncReport> {
+    let client = KojiClient::new(&hub);
+    let (rpms, builds) = match &source {
+        KojiSource::Build(nvr) => client.build_rpms(nvr).await?,
+        KojiSource::Tag(koji_tag) => client.tagged_rpms(koji_tag).await?,
+    };
+    let builds: HashMap<i64, KojiBuild> = builds.into_iter().map(|b| (b.id, b)).collect();
+
+    let existing: HashSet<String> = Rpm::get_by_tag(&tag.name)
+        .await?
+        .iter()
+        .map(Rpm::nevra)
+        .collect();
+    let mut skipped = 0;
+    let mut missing = Vec::new();
+    for rpm in rpms {
+        let nevra = rpm.nevra();
+        if !tag.arch_allowed(&rpm.arch) || existing.contains(&nevra) {
+            skipped += 1;
+            continue;
+        }
+        let build = builds
+            .get(&rpm.build_id)
+            .ok_or_else(|| eyre!("{nevra} belongs to unknown build {}", rpm.build_id))?;
+        missing.push(RemotePackage {
+            url: rpm.url(&topurl, build),
+            nevra,
+            size: rpm.size,
+            checksum: None,
+            payloadhash: rpm.payloadhash,
+        });
+    }
+    tracing::info!(
+        tag = %tag.name,
+        %hub,
+        ?source,
+        missing = missing.len(),
+        "importing koji packages"
+    );
+
+    let mut report = mirror::import_all(&identity, &tag.name, missing, concurrency).await;
+    report.skipped = skipped;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_call() {
+        let kwargs = json!({ "__starstar": true, "latest": true });
+        let xml = encode_call("listTaggedRPMS", &[json!("f41 & co"), json!(3), kwargs]);
+        assert_eq!(
+            xml,
+            concat!(
+                r#"<?xml version="1.0"?><methodCall><methodName>listTaggedRPMS</methodName>"#,
+                "<params><param><value><string>f41 &amp; co</string></value></param>",
+                "<param><value><int>3</int></value></param>",
+                "<param><value><struct>",
+                "<member><name>__starstar</name><value><boolean>1</boolean></value></member>",
+                "<member><name>latest</name><value><boolean>1</boolean></value></member>",
+                "</struct></value></param></params></methodCall>"
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let xml = r#"<?xml version='1.0'?>
+<methodResponse>
+<params>
+<param>
+<value><array><data>
+<value><struct>
+<member><name>name</name><value><string>hello</string></value></member>
+<member><name>version</name><value>1.0</value></member>
+<member><name>release</name><value><string>3.fc41</string></value></member>
+<member><name>epoch</name><value><nil/></value></member>
+<member><name>arch</name><value><string>x86_64</string></value></member>
+<member><name>build_id</name><value><int>42</int></value></member>
+<member><name>size</name><value><i8>10456</i8></value></member>
+</struct></value>
+</data></array></value>
+</param>
+</params>
+</methodResponse>"#;
+        let value = parse_response(xml).unwrap();
+        assert_eq!(value[0]["version"], "1.0");
+        assert_eq!(value[0]["epoch"], Value::Null);
+        let rpms: Vec<KojiRpm> = serde_json::from_value(value).unwrap();
+        assert_eq!(rpms[0].nevra(), "hello-0:1.0-3.fc41.x86_64");
+        assert_eq!(rpms[0].size, Some(10456));
+
+        let build = KojiBuild {
+            id: 42,
+            package_name: "hello".to_owned(),
+            version: "1.0".to_owned(),
+            release: "3.fc41".to_owned(),
+            state: Some(BUILD_COMPLETE),
+        };
+        assert_eq!(
+            rpms[0].url("https://kojipkgs.example.com/", &build),
+            "https://kojipkgs.example.com/packages/hello/1.0/3.fc41/x86_64/hello-1.0-3.fc41.x86_64.rpm"
+        );
+    }
+
+    #[test]
+    fn test_payloadhash() {
+        let rpm = "test/data/anda-srpm-macros-0:0.2.6-1.fc41.noarch.rpm";
+        let hash = payloadhash(std::path::Path::new(rpm)).unwrap();
+        assert_eq!(hash, "b0791ccb30efb6ed2d20402492bcd2ff");
+        let deb = "test/data/subatomic-hello_1.0.2-1_all.deb";
+        assert!(payloadhash(std::path::Path::new(deb)).is_err());
+    }
+
+    #[test]
+    fn test_parse_fault() {
+        let xml = r#"<?xml version='1.0'?>
+<methodResponse><fault><value><struct>
+<member><name>faultCode</name><value><int>1000</int></value></member>
+<member><name>faultString</name><value><string>No such build</string></value></member>
+</struct></value></fault></methodResponse>"#;
+        let err = parse_response(xml).unwrap_err();
+        assert_eq!(err.to_string(), "koji fault 1000: No such build");
+    }
+}
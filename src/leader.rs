@@ -0,0 +1,82 @@
+//! Leader election for background work
+//!
+//! When multiple replicas share a database and object store, scheduled tasks
+//! (digests, garbage collection, scheduled assembly...) should only run on one of them.
+//! Every instance competes for a lease in the database, the holder of the lease is the leader.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    LazyLock,
+};
+use std::time::Duration;
+
+use crate::db::{job::Job, lease::Lease};
+
+const LEADER_LEASE: &str = "leader";
+/// Prefix of the leases every instance holds while it's running
+const INSTANCE_LEASE_PREFIX: &str = "instance-";
+
+/// Unique ID of this instance, used as the lease holder
+pub static INSTANCE_ID: LazyLock<String> = LazyLock::new(|| ulid::Ulid::new().to_string());
+
+static IS_LEADER: AtomicBool = AtomicBool::new(false);
+
+/// Whether this instance currently holds the leader lease
+///
+/// Background tasks should check this before doing any work.
+pub fn is_leader() -> bool {
+    IS_LEADER.load(Ordering::Relaxed)
+}
+
+/// Whether an instance is still running, i.e. renewing its instance lease
+pub async fn is_alive(instance: &str) -> color_eyre::Result<bool> {
+    let lease = Lease::get(&format!("{INSTANCE_LEASE_PREFIX}{instance}")).await?;
+    Ok(lease.is_some_and(|l| *l.expires_at > chrono::Utc::now()))
+}
+
+/// Spawn the task competing for (and renewing) the leader lease
+///
+/// The lease is renewed at a third of its TTL, so a leader that goes away
+/// is replaced within `ttl`. Every instance also renews its own instance lease, and the
+/// leader fails the jobs of instances whose lease ran out, see [`Job::fail_interrupted`].
+pub fn spawn_election_task(ttl: Duration) -> tokio::task::JoinHandle<()> {
+    let instance_lease = format!("{INSTANCE_LEASE_PREFIX}{}", *INSTANCE_ID);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(ttl / 3);
+        loop {
+            interval.tick().await;
+            if let Err(e) = Lease::try_acquire(&instance_lease, &INSTANCE_ID, ttl).await {
+                tracing::warn!(?e, "failed to renew instance lease");
+            }
+            let leader = match Lease::try_acquire(LEADER_LEASE, &INSTANCE_ID, ttl).await {
+                Ok(leader) => leader,
+                Err(e) => {
+                    // can't confirm we still hold the lease, so step down
+                    tracing::error!(?e, "failed to renew leader lease");
+                    false
+                }
+            };
+
+            if IS_LEADER.swap(leader, Ordering::Relaxed) != leader {
+                tracing::info!(instance = %*INSTANCE_ID, leader, "leadership changed");
+            }
+            if leader {
+                if let Err(e) = Job::fail_interrupted().await {
+                    tracing::warn!(?e, "failed to check for interrupted jobs");
+                }
+            }
+        }
+    })
+}
+
+/// Step down as leader, allowing another instance to take over immediately
+pub async fn resign() {
+    IS_LEADER.store(false, Ordering::Relaxed);
+    if let Err(e) = Lease::release(LEADER_LEASE, &INSTANCE_ID).await {
+        tracing::warn!(?e, "failed to release leader lease");
+    }
+    let instance_lease = format!("{INSTANCE_LEASE_PREFIX}{}", *INSTANCE_ID);
+    if let Err(e) = Lease::release(&instance_lease, &INSTANCE_ID).await {
+        tracing::warn!(?e, "failed to release instance lease");
+    }
+}
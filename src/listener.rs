@@ -0,0 +1,56 @@
+//! Listeners for the HTTP API besides TCP
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use rustix::fs::Mode;
+use tokio::net::UnixListener;
+
+/// Bind a unix socket at `path` with the given permissions
+///
+/// A socket left behind by a previous run is removed first, any other file at the path is an error.
+pub fn bind_unix(path: &Path, mode: u32) -> Result<UnixListener> {
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(eyre!("{} exists and is not a socket", path.display()));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    // create the socket accessible to the owner only, so nobody else can connect before its
+    // permissions are set. The umask is process-wide, restore it right away
+    let umask = rustix::process::umask(Mode::from_raw_mode(0o177));
+    let listener = UnixListener::bind(path);
+    rustix::process::umask(umask);
+    let listener =
+        listener.wrap_err_with(|| format!("cannot bind unix socket {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    tracing::info!(path = %path.display(), mode = format!("{mode:o}"), "listening on unix socket");
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_unix() {
+        let path = std::env::temp_dir().join(format!("subatomic-{}.sock", ulid::Ulid::new()));
+
+        let listener = bind_unix(&path, 0o600).unwrap();
+        let meta = std::fs::metadata(&path).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o600);
+        drop(listener);
+
+        // a stale socket is replaced
+        bind_unix(&path, 0o660).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        std::fs::write(&path, b"not a socket").unwrap();
+        assert!(bind_unix(&path, 0o660).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,15 +1,42 @@
-use axum::{http::Response, routing::get, Router};
-use color_eyre::eyre::eyre;
-use db::DB;
-use errors::Error;
+use axum::{
+    extract::DefaultBodyLimit,
+    http::{Response, StatusCode},
+    routing::get,
+    Json, Router,
+};
 use pgp::VERSION;
+mod auth;
+mod backup;
 mod cache;
 mod config;
 mod db;
+mod debvercmp;
+mod disk;
 mod errors;
+mod health;
+mod import;
+mod janitor;
+mod koji;
+mod leader;
+mod listener;
+mod mirror;
+mod namespace;
+mod notify;
 mod obj_store;
+mod progress;
+mod ratelimit;
+mod remote;
+mod repodata;
+mod request_id;
+mod rpmvercmp;
+mod scheduler;
+mod signing;
+mod tls;
+#[cfg(all(test, feature = "test-harness"))]
+mod testing;
 mod router;
-use std::{net::SocketAddr, str::FromStr};
+mod webhook;
+use std::{net::SocketAddr, str::FromStr, time::Duration};
 
 
 
@@ -17,29 +44,83 @@ fn router() -> Router {
     let app = Router::new()
         .route("/", get(root))
         .route("/health", get(health))
-        .route("/version", get(version));
+        .route("/livez", get(livez))
+        .route("/readyz", get(readyz))
+        .route("/version", get(version))
+        .merge(router::oci::registry_route());
+    let max_upload_size = config::CONFIG.get().unwrap().max_upload_size;
     router::route(app)
+        .layer(DefaultBodyLimit::max(
+            usize::try_from(max_upload_size).unwrap_or(usize::MAX),
+        ))
+        .layer(axum::middleware::from_fn(request_id::propagate))
 }
 
 #[tokio::main]
 async fn main() {
     // initialize tracing
     dotenvy::dotenv().ok();
-    tracing_subscriber::fmt::init();
+    config::init_logging();
     let cfg = config::Config::init();
-
-    db::connect_db(&cfg.surreal_ns, &cfg.surreal_db)
-        .await
-        .unwrap();
+    config::spawn_reload_task();
+    if !cfg.skip_object_store_check {
+        if let Err(e) = obj_store::object_store().verify_access().await {
+            tracing::error!("object store check failed, refusing to start: {e:?}");
+            std::process::exit(1);
+        }
+    }
 
     let app = router();
     // run our app with hyper, listening globally on port 3000
     let addr = SocketAddr::from_str(&cfg.listen_addr).unwrap();
-    
-    axum_server::bind(addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+
+    // serve while connecting to the database, so /livez answers and /readyz reports when it's up
+    let server = if let Some(path) = &cfg.listen_socket {
+        let listener = listener::bind_unix(path, cfg.listen_socket_mode).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await })
+    } else {
+        match tls::rustls_config(&cfg).await.unwrap() {
+            Some(tls) => {
+                if let (Some(interval), Some(cert), Some(key)) =
+                    (cfg.tls_reload_interval, &cfg.tls_cert, &cfg.tls_key)
+                {
+                    tls::spawn_reload_task(
+                        tls.clone(),
+                        cert.clone(),
+                        key.clone(),
+                        Duration::from_secs(interval),
+                    );
+                }
+                let app = app.into_make_service_with_connect_info::<SocketAddr>();
+                tokio::spawn(axum_server::bind_rustls(addr, tls).serve(app))
+            }
+            None => {
+                let app = app.into_make_service_with_connect_info::<SocketAddr>();
+                tokio::spawn(axum_server::bind(addr).serve(app))
+            }
+        }
+    };
+
+    db::connect_db(&cfg).await.unwrap();
+    db::spawn_health_task(db::HEALTH_CHECK_INTERVAL);
+
+    leader::spawn_election_task(Duration::from_secs(cfg.leader_lease_ttl));
+    notify::spawn_digest_task(Duration::from_secs(cfg.digest_interval));
+    scheduler::spawn_schedule_task(scheduler::SCHEDULE_INTERVAL);
+    webhook::spawn_delivery_task(webhook::DELIVERY_INTERVAL);
+    if let Some(interval) = cfg.cache_scrub_interval {
+        cache::spawn_scrub_task(Duration::from_secs(interval));
+    }
+    if let Some(interval) = cfg.janitor_interval {
+        janitor::spawn_janitor_task(cfg.clone(), Duration::from_secs(interval));
+    }
+    if !cfg.prefetch_tags.is_empty() {
+        db::tag::spawn_prefetch(cfg.prefetch_tags.clone(), cfg.prefetch_concurrency);
+    }
+
+    server.await.unwrap().unwrap();
+
+    leader::resign().await;
 }
 
 /// Returns the version of the server
@@ -47,15 +128,37 @@ async fn version() -> &'static str {
     env!("CARGO_PKG_VERSION")
 }
 
-/// Returns the health of the server
-async fn health() -> Result<&'static str, Error> {
-    let h = DB.get().health().await.is_ok();
-    
-    if h {
-        Ok("OK")
-    } else {
-        Err(Error::Other(eyre!("health check failed")))
-    }
+/// Returns the health of each component of the server
+///
+/// Responds with 503 if a required component is broken, and 200 if the server is only degraded.
+async fn health() -> (StatusCode, Json<health::Health>) {
+    let health = health::check(config::CONFIG.get().unwrap()).await;
+    let status = match health.status {
+        health::HealthStatus::Error => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+    (status, Json(health))
+}
+
+/// Liveness probe, the server is alive as long as it answers
+///
+/// Nothing else is checked, so an unreachable database or object store doesn't get the server
+/// restarted.
+async fn livez() -> &'static str {
+    "OK"
+}
+
+/// Readiness probe, whether the server should get traffic
+///
+/// Responds with 503 until the database is connected, and while it or the object store is
+/// unreachable.
+async fn readyz() -> (StatusCode, Json<health::Health>) {
+    let health = health::ready(config::CONFIG.get().unwrap()).await;
+    let status = match health.status {
+        health::HealthStatus::Ok => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(health))
 }
 
 // basic handler that responds with a static string
@@ -0,0 +1,179 @@
+//! Pull-based mirroring of remote yum repositories into a tag
+//!
+//! A sync reads the remote repo's `primary.xml`, downloads the packages the tag doesn't have
+//! yet and uploads them to it, as if they had been pushed.
+use std::collections::{BTreeMap, HashSet};
+
+use color_eyre::{eyre::eyre, Result};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use ulid::Ulid;
+
+use crate::auth::Identity;
+use crate::config::CONFIG;
+use crate::db::{
+    rpm::{Rpm, RpmRef},
+    tag::Tag,
+};
+use crate::repodata::{self, Checksum};
+use crate::router::rpm::{finish_upload, store_upload_path};
+
+/// Outcome of [`sync`] and other imports of remote packages
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncReport {
+    /// Packages downloaded and uploaded to the tag
+    pub imported: Vec<RpmRef>,
+    /// Packages the tag already had, or whose architecture it doesn't take
+    pub skipped: usize,
+    /// Packages that couldn't be imported, by NEVRA, with why
+    pub failed: BTreeMap<String, String>,
+}
+
+/// A package to download from a remote location
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemotePackage {
+    /// Full `name-epoch:version-release.arch` of the package
+    pub nevra: String,
+    pub url: String,
+    pub size: Option<u64>,
+    pub checksum: Option<Checksum>,
+    /// Koji's `payloadhash`, see [`crate::koji::payloadhash`]
+    pub payloadhash: Option<String>,
+}
+
+/// Download a remote file into a local one, chunk by chunk
+///
+/// Only public hosts are reachable, see [`crate::remote`].
+async fn download(url: &str, dest: &std::path::Path) -> Result<()> {
+    let res = crate::remote::get(url).await?;
+    let mut file = tokio::fs::File::create(dest).await?;
+    let mut stream = res.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        file.write_all(&chunk?).await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// Download a remote package and upload it to the tag, checking its checksums if known
+pub async fn import_package(identity: &Identity, tag: &str, pkg: &RemotePackage) -> Result<Rpm> {
+    let cache_dir = &CONFIG.get().unwrap().cache_dir;
+    crate::disk::check_upload(cache_dir, pkg.size.unwrap_or(0)).map_err(|e| eyre!("{e}"))?;
+    let dest = cache_dir.join(format!("{}.rpm", Ulid::new()));
+
+    let staged = async {
+        download(&pkg.url, &dest).await?;
+        if let Some(checksum) = &pkg.checksum {
+            checksum.verify_file(&dest)?;
+        }
+        if let Some(expected) = &pkg.payloadhash {
+            let payloadhash = crate::koji::payloadhash(&dest)?;
+            if !payloadhash.eq_ignore_ascii_case(expected) {
+                return Err(eyre!(
+                    "payload hash mismatch, got {payloadhash}, expected {expected}"
+                ));
+            }
+        }
+        store_upload_path(&dest, tag).await.map_err(|e| eyre!("{e}"))
+    }
+    .await;
+    let upload = match staged {
+        Ok(upload) => upload,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&dest).await;
+            return Err(e);
+        }
+    };
+    finish_upload(identity, &upload, tag, true, false)
+        .await
+        .map_err(|e| eyre!("{e}"))
+}
+
+/// Import remote packages into the tag, `concurrency` at a time
+///
+/// A package failing to import doesn't stop the others, it's listed in the report instead.
+pub async fn import_all(
+    identity: &Identity,
+    tag: &str,
+    packages: Vec<RemotePackage>,
+    concurrency: usize,
+) -> SyncReport {
+    let results = futures::stream::iter(packages)
+        .map(|pkg| async move {
+            let res = import_package(identity, tag, &pkg).await;
+            (pkg, res)
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut report = SyncReport::default();
+    for (pkg, res) in results {
+        match res {
+            Ok(rpm) => report.imported.push(RpmRef::from(&rpm)),
+            Err(e) => {
+                tracing::warn!(%tag, nevra = %pkg.nevra, ?e, "failed to import package");
+                report.failed.insert(pkg.nevra.clone(), format!("{e:#}"));
+            }
+        }
+    }
+    report
+}
+
+/// Mirror the packages of the yum repo at `base_url` missing from the tag, `concurrency` at
+/// a time
+///
+/// Packages are matched by NEVRA against those uploaded to the tag, and become available
+/// like uploads do.
+pub async fn sync(
+    identity: Identity,
+    tag: Tag,
+    base_url: String,
+    concurrency: usize,
+) -> Result<SyncReport> {
+    let records = repodata::fetch_repomd(&base_url).await?;
+    let primary = records
+        .iter()
+        .find(|record| record.data_type == "primary")
+        .ok_or_else(|| eyre!("{base_url} has no primary metadata"))?;
+    let checksum = primary
+        .checksum
+        .as_ref()
+        .ok_or_else(|| eyre!("{base_url} lists no checksum of its primary metadata"))?;
+    let data = repodata::fetch(&repodata::join_url(&base_url, &primary.location)).await?;
+    checksum
+        .verify(&data)
+        .map_err(|e| eyre!("primary metadata of {base_url}: {e}"))?;
+    let data = repodata::decompress(&primary.location, data)?;
+    let packages = repodata::primary::parse_primary(std::str::from_utf8(&data)?)?;
+
+    let existing: HashSet<String> = Rpm::get_by_tag(&tag.name)
+        .await?
+        .iter()
+        .map(Rpm::nevra)
+        .collect();
+    let (missing, skipped): (Vec<_>, Vec<_>) = packages.into_iter().partition(|pkg| {
+        tag.arch_allowed(&pkg.nevra.arch) && !existing.contains(&pkg.nevra_string())
+    });
+    tracing::info!(
+        tag = %tag.name,
+        url = %base_url,
+        missing = missing.len(),
+        "syncing remote repository"
+    );
+
+    let missing = missing
+        .into_iter()
+        .map(|pkg| RemotePackage {
+            nevra: pkg.nevra_string(),
+            url: repodata::join_url(&base_url, &pkg.location),
+            size: pkg.size,
+            checksum: pkg.checksum,
+            payloadhash: None,
+        })
+        .collect();
+    let mut report = import_all(&identity, &tag.name, missing, concurrency).await;
+    report.skipped = skipped.len();
+    Ok(report)
+}
@@ -0,0 +1,81 @@
+//! Namespace scoping of requests and the work they start
+//!
+//! Requests under `/ns/{name}/` run in that namespace's scope, and other API requests in the
+//! global one. Tags and signing keys are only visible from the scope they were created in, and
+//! objects stored in a namespace's scope get keys under `ns/{name}/`. Background work outside of
+//! any request, i.e. scheduled assemblies, sees everything unless it enters a scope itself.
+//!
+//! [`Job::spawn`](crate::db::job::Job::spawn) carries the scope over to the job.
+use std::future::Future;
+
+/// Prefix of the object keys of a namespace
+pub const OBJECT_PREFIX: &str = "ns";
+
+/// Namespace a request was made under, from its `/ns/{namespace}/` prefix
+///
+/// Added to the request extensions by the route forwarding namespaced requests, and checked by
+/// [`crate::auth::require_auth`] which runs the request in its scope.
+#[derive(Debug, Clone)]
+pub struct ScopedNamespace(pub String);
+
+tokio::task_local! {
+    /// Namespace of the current request, `None` for global requests
+    static SCOPE: Option<String>;
+}
+
+/// Run a future in a namespace's scope, or the global scope with `None`
+pub fn scope<F: Future>(namespace: Option<String>, f: F) -> impl Future<Output = F::Output> {
+    SCOPE.scope(namespace, f)
+}
+
+/// The scope of the current task, unset outside of a request or job
+pub fn current_scope() -> Option<Option<String>> {
+    SCOPE.try_with(Clone::clone).ok()
+}
+
+/// Namespace of the current request, `None` in the global scope or outside of a request
+pub fn current() -> Option<String> {
+    current_scope().flatten()
+}
+
+/// Whether a record of a namespace is visible from the current scope
+pub fn visible(namespace: Option<&str>) -> bool {
+    match current_scope() {
+        Some(scope) => scope.as_deref() == namespace,
+        None => true,
+    }
+}
+
+/// Object key of a new object, prefixed with the current namespace
+pub fn object_key(key: String) -> String {
+    match current() {
+        Some(namespace) => format!("{OBJECT_PREFIX}/{namespace}/{key}"),
+        None => key,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scope() {
+        assert!(visible(Some("team")));
+        assert_eq!(object_key("rpm/a.rpm".to_owned()), "rpm/a.rpm");
+
+        scope(Some("team".to_owned()), async {
+            assert!(visible(Some("team")));
+            assert!(!visible(Some("other")));
+            assert!(!visible(None));
+            assert_eq!(object_key("rpm/a.rpm".to_owned()), "ns/team/rpm/a.rpm");
+        })
+        .await;
+
+        scope(None, async {
+            assert!(visible(None));
+            assert!(!visible(Some("team")));
+            assert_eq!(current(), None);
+        })
+        .await;
+    }
+}
@@ -0,0 +1,252 @@
+//! Notifications for events happening in Subatomic
+//!
+//! Notifications are currently delivered over email (SMTP), either immediately
+//! or collected into periodic digests, depending on the subscription.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use color_eyre::{eyre::eyre, Result};
+use lettre::{
+    transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message,
+    Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::CONFIG;
+use crate::db::gpg_key::GpgKey;
+use crate::db::notification::{DigestMode, EmailSubscription};
+use crate::db::tag::Tag;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    /// A package was uploaded
+    Upload,
+    /// Assembling a tag failed
+    AssembleFailed,
+    /// A signing key is about to expire
+    KeyExpiring,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    /// Tag the notification relates to, if any
+    pub tag: Option<String>,
+    pub message: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Notification {
+    pub fn new(kind: NotificationKind, tag: Option<&str>, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            tag: tag.map(ToOwned::to_owned),
+            message: message.into(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn summary(&self) -> String {
+        let tag = self.tag.as_deref().unwrap_or("-");
+        format!(
+            "[{}] {:?} ({tag}): {}",
+            self.timestamp.to_rfc3339(),
+            self.kind,
+            self.message
+        )
+    }
+}
+
+/// Send a notification to every matching subscriber, in the background
+///
+/// This never fails, errors are logged instead so notifications can't break or hold up
+/// the operation that triggered them, i.e. on a slow SMTP relay.
+pub fn notify(notification: Notification) {
+    tokio::spawn(async move {
+        if let Err(e) = dispatch(&notification).await {
+            tracing::error!(?e, ?notification, "failed to dispatch notification");
+        }
+    });
+}
+
+async fn dispatch(notification: &Notification) -> Result<()> {
+    if mailer()?.is_none() {
+        return Ok(());
+    }
+
+    for sub in EmailSubscription::get_all().await? {
+        if !sub.matches(notification) {
+            continue;
+        }
+
+        match sub.mode {
+            DigestMode::Immediate => {
+                let subject = format!("[subatomic] {:?}", notification.kind);
+                send_mail(&sub.address, &subject, notification.summary()).await?;
+            }
+            DigestMode::Daily => sub.enqueue(notification).await?,
+        }
+    }
+
+    Ok(())
+}
+
+fn mailer() -> Result<Option<AsyncSmtpTransport<Tokio1Executor>>> {
+    let config = CONFIG.get().ok_or_else(|| eyre!("config not loaded"))?;
+    let Some(host) = &config.smtp_host else {
+        return Ok(None);
+    };
+
+    let mut builder =
+        AsyncSmtpTransport::<Tokio1Executor>::relay(host)?.port(config.smtp_port);
+    if let (Some(user), Some(pass)) = (&config.smtp_user, &config.smtp_pass) {
+        builder = builder.credentials(Credentials::new(user.clone(), pass.clone()));
+    }
+
+    Ok(Some(builder.build()))
+}
+
+async fn send_mail(to: &str, subject: &str, body: String) -> Result<()> {
+    let config = CONFIG.get().ok_or_else(|| eyre!("config not loaded"))?;
+    let Some(mailer) = mailer()? else {
+        return Ok(());
+    };
+
+    let message = Message::builder()
+        .from(config.smtp_from.parse()?)
+        .to(to.parse()?)
+        .subject(subject)
+        .body(body)?;
+
+    tracing::debug!(?to, ?subject, "sending notification email");
+    mailer.send(message).await?;
+    Ok(())
+}
+
+/// Send out queued notifications for every digest subscription
+pub async fn send_digests() -> Result<()> {
+    for sub in EmailSubscription::get_all().await? {
+        if sub.mode != DigestMode::Daily {
+            continue;
+        }
+
+        let queued = sub.take_queued().await?;
+        if queued.is_empty() {
+            continue;
+        }
+
+        let body = queued
+            .iter()
+            .map(Notification::summary)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let subject = format!("[subatomic] Digest: {} notifications", queued.len());
+        if let Err(e) = send_mail(&sub.address, &subject, body).await {
+            // queue them again for the next digest, instead of losing them
+            for notification in &queued {
+                sub.enqueue(notification).await?;
+            }
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Notify subscribers of signing keys expiring within `within`, for each tag signed with them
+pub async fn check_expiring_keys(within: chrono::Duration) -> Result<()> {
+    let keys = GpgKey::get_all().await?;
+    for key in expiring_keys(&keys, Utc::now(), within) {
+        let key_id = key.id.id.to_raw();
+        let expires_at = key.expires_at.as_ref().map(|at| at.to_utc().to_rfc3339());
+        let message = format!("signing key {key_id} expires at {}", expires_at.unwrap_or_default());
+        let tags = Tag::get_by_signing_key(&key_id).await?;
+        if tags.is_empty() {
+            notify(Notification::new(NotificationKind::KeyExpiring, None, message));
+            continue;
+        }
+        for tag in tags {
+            let notification =
+                Notification::new(NotificationKind::KeyExpiring, Some(&tag.name), &message);
+            notify(notification);
+        }
+    }
+    Ok(())
+}
+
+/// Keys that haven't expired yet, but will within `within` of `now`
+fn expiring_keys(
+    keys: &[GpgKey],
+    now: DateTime<Utc>,
+    within: chrono::Duration,
+) -> impl Iterator<Item = &GpgKey> {
+    keys.iter().filter(move |key| {
+        key.expires_at
+            .as_ref()
+            .map(|at| at.to_utc())
+            .is_some_and(|at| at > now && at <= now + within)
+    })
+}
+
+/// Spawn the background task sending out digests on the configured interval
+///
+/// Digests are only sent by the leader instance.
+pub fn spawn_digest_task(interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        // the first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if !crate::leader::is_leader() {
+                continue;
+            }
+            let warning_days = CONFIG.get().map_or(14, |config| config.key_expiry_warning_days);
+            if let Err(e) = check_expiring_keys(chrono::Duration::days(warning_days.into())).await
+            {
+                tracing::error!(?e, "failed to check for expiring signing keys");
+            }
+            if let Err(e) = send_digests().await {
+                tracing::error!(?e, "failed to send notification digests");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiring_keys() {
+        let now = Utc::now();
+        let key = |id: &str, days: Option<i64>| {
+            let expires_at = days.map(|days| (now + chrono::Duration::days(days)).into());
+            GpgKey {
+                id: (crate::db::gpg_key::GPG_KEY_TABLE, id).into(),
+                description: None,
+                user_id: "Test <test@example.com>".to_owned(),
+                secret_key: None,
+                public_key: String::new(),
+                algorithm: Default::default(),
+                backend: Default::default(),
+                allowed_tags: Vec::new(),
+                namespace: None,
+                created_at: now.into(),
+                expires_at,
+            }
+        };
+        let keys = [
+            key("soon", Some(3)),
+            key("later", Some(60)),
+            key("expired", Some(-1)),
+            key("never", None),
+        ];
+        let expiring = expiring_keys(&keys, now, chrono::Duration::days(14))
+            .map(|key| key.id.id.to_raw())
+            .collect::<Vec<_>>();
+        assert_eq!(expiring, ["soon"]);
+    }
+}
@@ -5,15 +5,30 @@
 use crate::cache::{cache, Cache};
 use crate::config::CONFIG;
 use async_trait::async_trait;
-use color_eyre::{eyre::eyre, Result};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use object_store::buffered::BufWriter;
 use object_store::path::Path as ObjectPath;
-use object_store::{ObjectStore, PutPayload};
+use object_store::signer::Signer;
+use object_store::{GetResult, ObjectStore, PutPayload};
+use futures::TryStreamExt;
+use replicated::ResyncReport;
+use retry::RetryPolicy;
+use reqwest::Method;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::StreamReader;
 use tracing::{debug, info};
 // use std::io::Read;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
-use std::sync::{Arc, OnceLock};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 // pub mod local_backend;
 // pub mod s3_backend;
+pub mod replicated;
+pub mod retry;
 
 pub struct CacheOnlyBackend;
 
@@ -44,6 +59,11 @@ impl StorageBackend for CacheOnlyBackend {
     }
 }
 
+/// Key probed by [`StorageBackend::check`], it doesn't need to exist
+const HEALTH_CHECK_KEY: &str = "subatomic-health-check";
+/// Prefix of the sentinel objects written by [`ObjectStorage::verify_access`]
+const SENTINEL_PREFIX: &str = "subatomic-sentinel";
+
 #[async_trait]
 pub trait StorageBackend: Send + Sync {
     
@@ -51,6 +71,27 @@ pub trait StorageBackend: Send + Sync {
     async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<()>;
     async fn get_object(&self, key: &str) -> Result<PathBuf>;
     async fn delete_object(&self, key: &str) -> Result<()>;
+
+    /// Generate a time-limited URL the object can be downloaded from directly,
+    /// if the backend supports it
+    async fn presigned_url(&self, _key: &str, _expires_in: Duration) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Check that the backend is reachable, with a request as cheap as possible
+    async fn check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Key and size in bytes of every stored object
+    async fn list_objects(&self) -> Result<BTreeMap<String, u64>> {
+        Err(eyre!("this object store cannot list its objects"))
+    }
+
+    /// Bring diverged replicas back in sync, see [`replicated::ReplicatedBackend::resync`]
+    async fn resync(&self) -> Result<ResyncReport> {
+        Err(eyre!("this object store isn't replicated"))
+    }
     
     fn file_name(&self, key: &str) -> String {
         key.split('/').last().unwrap().to_string()
@@ -61,11 +102,85 @@ fn object_cache_dir() -> PathBuf {
     CONFIG.get().unwrap().cache_dir.clone()
 }
 
+/// Removes a file when dropped unless kept, so failed or cancelled downloads don't leave it behind
+struct RemoveOnDrop(PathBuf);
+
+impl RemoveOnDrop {
+    fn keep(self) -> PathBuf {
+        std::mem::take(&mut std::mem::ManuallyDrop::new(self).0)
+    }
+}
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Unregisters a request from [`ObjectStorage::downloads`] when dropped, even if it was cancelled
+struct DownloadGuard<'a> {
+    downloads: &'a Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    key: &'a str,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Drop for DownloadGuard<'_> {
+    fn drop(&mut self) {
+        let mut downloads = self.downloads.lock().unwrap();
+        // nobody else is waiting, only the map and this request hold the lock
+        if Arc::strong_count(&self.lock) == 2 {
+            downloads.remove(self.key);
+        }
+    }
+}
+
+/// Size of the buffer objects are written to disk through
+const DOWNLOAD_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Write a fetched object to a file chunk by chunk
+///
+/// Fails if fewer or more bytes than the store reported for the object were received, i.e. a
+/// connection dropped in the middle of the download.
+async fn write_stream(result: GetResult, dest: &std::path::Path) -> Result<()> {
+    let expected = result.meta.size as u64;
+    let stream = result.into_stream().map_err(std::io::Error::other);
+    let mut reader = StreamReader::new(stream);
+    let file = tokio::fs::File::create(dest).await?;
+    let mut writer = tokio::io::BufWriter::with_capacity(DOWNLOAD_BUFFER_SIZE, file);
+    let written = tokio::io::copy(&mut reader, &mut writer).await?;
+    writer.flush().await?;
+    if written != expected {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("received {written} bytes of an object of {expected} bytes"),
+        )
+        .into());
+    }
+    Ok(())
+}
+
 #[async_trait]
 impl StorageBackend for Arc<dyn ObjectStore> {
+    /// Stream the file from disk, as a multipart upload if it's larger than a part
     async fn put_file(&self, key: &str, path: PathBuf) -> Result<()> {
-        let s = tokio::fs::read(&path).await?;
-        self.put(&ObjectPath::from(key), PutPayload::from_bytes(s.into())).await?;
+        let config = CONFIG.get().unwrap();
+        let mut file = tokio::fs::File::open(&path).await?;
+        let part_size = usize::try_from(config.upload_part_size)?;
+        let mut writer = BufWriter::with_capacity(self.clone(), ObjectPath::from(key), part_size)
+            .with_max_concurrency(config.upload_concurrency);
+
+        let res = async {
+            tokio::io::copy(&mut file, &mut writer).await?;
+            writer.shutdown().await
+        }
+        .await;
+        if let Err(e) = res {
+            // don't leave the parts uploaded so far behind
+            if let Err(abort) = writer.abort().await {
+                tracing::warn!(?key, ?abort, "failed to abort multipart upload");
+            }
+            return Err(e.into());
+        }
         Ok(())
     }
     
@@ -76,24 +191,101 @@ impl StorageBackend for Arc<dyn ObjectStore> {
     
     async fn get_object(&self, key: &str) -> Result<PathBuf> {
         let result = self.get(&ObjectPath::from(key)).await?;
-        let bytes = result.bytes().await?;
-        
-        let dest = object_cache_dir().join(self.file_name(key));
+
+        // unique, so downloads of different objects with the same file name don't collide
+        let dest = object_cache_dir().join(format!(
+            ".download-{}-{}",
+            ulid::Ulid::new(),
+            self.file_name(key)
+        ));
         info!(?dest, "Writing object to object cache");
-        tokio::fs::write(&dest, bytes).await?;
-        Ok(dest)
+        let partial = RemoveOnDrop(dest);
+        // Stream the object so large packages are never held in memory
+        write_stream(result, &partial.0).await?;
+        Ok(partial.keep())
     }
     
     async fn delete_object(&self, key: &str) -> Result<()> {
         self.delete(&ObjectPath::from(key)).await?;
         Ok(())
     }
+
+    async fn check(&self) -> Result<()> {
+        match self.head(&ObjectPath::from(HEALTH_CHECK_KEY)).await {
+            Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list_objects(&self) -> Result<BTreeMap<String, u64>> {
+        let objects: BTreeMap<String, u64> = self
+            .list(None)
+            .map_ok(|meta| (meta.location.to_string(), meta.size as u64))
+            .try_collect()
+            .await?;
+        Ok(objects)
+    }
+}
+
+/// An object store that can also presign download URLs, i.e S3
+pub struct SigningBackend {
+    store: Arc<dyn ObjectStore>,
+    signer: Arc<dyn Signer>,
+}
+
+impl SigningBackend {
+    pub fn new<S: ObjectStore + Signer>(store: S) -> Self {
+        let store = Arc::new(store);
+        Self {
+            store: store.clone(),
+            signer: store,
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SigningBackend {
+    async fn put_file(&self, key: &str, path: PathBuf) -> Result<()> {
+        self.store.put_file(key, path).await
+    }
+
+    async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+        self.store.put_bytes(key, bytes).await
+    }
+
+    async fn get_object(&self, key: &str) -> Result<PathBuf> {
+        self.store.get_object(key).await
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<()> {
+        self.store.delete_object(key).await
+    }
+
+    async fn check(&self) -> Result<()> {
+        self.store.check().await
+    }
+
+    async fn list_objects(&self) -> Result<BTreeMap<String, u64>> {
+        self.store.list_objects().await
+    }
+
+    async fn presigned_url(&self, key: &str, expires_in: Duration) -> Result<Option<String>> {
+        let url = self
+            .signer
+            .signed_url(Method::GET, &ObjectPath::from(key), expires_in)
+            .await?;
+        Ok(Some(url.to_string()))
+    }
 }
 
 #[derive(Clone)]
 pub struct ObjectStorage {
     pub backend: Arc<dyn StorageBackend>,
     pub cache: Arc<Cache>,
+    /// Retries of backend operations failing with transient errors
+    pub retry: RetryPolicy,
+    /// Locks of the objects being downloaded, so each is only downloaded once at a time
+    downloads: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
 }
 
 impl ObjectStorage {
@@ -101,33 +293,83 @@ impl ObjectStorage {
         Self {
             backend,
             cache: Arc::new(cache),
+            retry: RetryPolicy::default(),
+            downloads: Arc::default(),
         }
     }
 
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Number of objects with requests waiting for their download
+    #[cfg(test)]
+    pub fn pending_downloads(&self) -> usize {
+        self.downloads.lock().unwrap().len()
+    }
+
     /// Get or download an object from the cache if it exists
     // #[tracing::instrument]
+    ///
+    /// Concurrent requests for the same uncached object share a single download, the others
+    /// wait for it and read the object from the cache.
     pub async fn get(&self, key: &str) -> Result<PathBuf> {
-            if let Some(path) = self.cache.get(key) {
-                return Ok(path);
-            }
+        self.get_verified(key, None).await
+    }
 
-            let path = self.backend.get_object(key).await?;
-            debug!(?path, "Putting object in cache");
-            let cache_path = self.cache.put(&key, &path).await?;
-            Ok(cache_path)
+    /// Get an object like [`ObjectStorage::get`], checking it against its SHA256 digest
+    ///
+    /// For objects whose digest is known from the database, i.e. packages, so a corrupt copy is
+    /// neither cached nor served.
+    pub async fn get_verified(&self, key: &str, sha256: Option<&str>) -> Result<PathBuf> {
+        let cached = self.cache.get_expecting(key, sha256);
+        crate::cache::record_lookup(cached.is_some());
+        if let Some(path) = cached {
+            return Ok(path);
         }
 
+        let lock = self
+            .downloads
+            .lock()
+            .unwrap()
+            .entry(key.to_owned())
+            .or_default()
+            .clone();
+        let registered = DownloadGuard {
+            downloads: &self.downloads,
+            key,
+            lock,
+        };
+        let _guard = registered.lock.lock().await;
+        // downloaded while waiting for the lock
+        if let Some(path) = self.cache.get_expecting(key, sha256) {
+            debug!(?key, "Object downloaded by a concurrent request");
+            return Ok(path);
+        }
+
+        let path = self
+            .retry
+            .run("get", key, || self.backend.get_object(key))
+            .await?;
+        let downloaded = RemoveOnDrop(path);
+        debug!(path = ?downloaded.0, "Putting object in cache");
+        self.cache.put_expecting(key, &downloaded.0, sha256).await
+    }
+
     pub async fn put(&self, key: &str, path: &PathBuf) -> Result<PathBuf> {
         debug!(?path, "Putting object");
         // let s = tokio::fs::read(path).await?;
-        self.backend
-            .put_file(key, path.clone())
+        self.retry
+            .run("put", key, || self.backend.put_file(key, path.clone()))
             .await?;
         self.cache.put(key, path).await
     }
 
     pub async fn remove(&self, key: &str) -> Result<()> {
-        self.backend.delete_object(key).await?;
+        self.retry
+            .run("delete", key, || self.backend.delete_object(key))
+            .await?;
         self.cache.remove(key).await
     }
 
@@ -137,10 +379,41 @@ impl ObjectStorage {
     }
 
     pub async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<PathBuf> {
-        self.backend.put_bytes(key, bytes).await?;
-        self.cache
-            .get(key)
-            .ok_or_else(|| eyre!("object not found in cache"))
+        self.retry
+            .run("put", key, || self.backend.put_bytes(key, bytes.clone()))
+            .await?;
+        self.cache.put_bytes(key, &bytes).await
+    }
+
+    /// Presign a download URL for an object, if the backend supports it
+    pub async fn presigned_url(&self, key: &str, expires_in: Duration) -> Result<Option<String>> {
+        self.backend.presigned_url(key, expires_in).await
+    }
+
+    /// Check that the backend is reachable, see [`StorageBackend::check`]
+    pub async fn check(&self) -> Result<()> {
+        self.backend.check().await
+    }
+
+    /// Check that objects can be written to and deleted from the backend, with a sentinel object
+    ///
+    /// Unlike [`StorageBackend::check`], this catches credentials that can only read.
+    pub async fn verify_access(&self) -> Result<()> {
+        let key = format!("{SENTINEL_PREFIX}/{}", ulid::Ulid::new());
+        self.retry
+            .run("put", &key, || self.backend.put_bytes(&key, b"ok".to_vec()))
+            .await
+            .wrap_err("cannot write to the object store")?;
+        self.retry
+            .run("delete", &key, || self.backend.delete_object(&key))
+            .await
+            .wrap_err("cannot delete from the object store")?;
+        Ok(())
+    }
+
+    /// Resync the backend's replicas, see [`StorageBackend::resync`]
+    pub async fn resync(&self) -> Result<ResyncReport> {
+        self.backend.resync().await
     }
 }
 
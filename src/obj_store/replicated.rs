@@ -0,0 +1,181 @@
+//! Object store replicated to a second backend, for disaster recovery
+//!
+//! Every write goes to both backends and only succeeds once both stored the object. Reads go to
+//! the primary backend, which should be the fast one (i.e. local storage mirrored to S3), and
+//! fall back to the replica when it's missing the object.
+//!
+//! A write that failed on one backend leaves the other with an extra object, and objects can go
+//! missing from a backend restored from a backup. [`ReplicatedBackend::resync`] copies objects
+//! over until both backends hold the same objects again.
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use color_eyre::{eyre::WrapErr, Report, Result};
+use serde::Serialize;
+
+use super::StorageBackend;
+
+pub struct ReplicatedBackend {
+    primary: Arc<dyn StorageBackend>,
+    replica: Arc<dyn StorageBackend>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResyncFailure {
+    pub key: String,
+    pub error: String,
+}
+
+/// Objects copied by [`ReplicatedBackend::resync`]
+#[derive(Debug, Default, Serialize)]
+pub struct ResyncReport {
+    /// Objects only the replica had
+    pub copied_to_primary: usize,
+    /// Objects missing from the replica, or with a different size
+    pub copied_to_replica: usize,
+    pub failed: Vec<ResyncFailure>,
+}
+
+impl ReplicatedBackend {
+    pub fn new(primary: Arc<dyn StorageBackend>, replica: Arc<dyn StorageBackend>) -> Self {
+        Self { primary, replica }
+    }
+}
+
+/// Combine the results of an operation on both backends, failing if either failed
+fn both(primary: Result<()>, replica: Result<()>) -> Result<()> {
+    match (primary, replica) {
+        (Ok(()), Ok(())) => Ok(()),
+        (Err(e), _) => Err(e.wrap_err("primary object store")),
+        (Ok(()), Err(e)) => Err(e.wrap_err("replica object store")),
+    }
+}
+
+/// Treat an object already missing from a backend as deleted
+fn ignore_not_found(res: Result<()>) -> Result<()> {
+    match res {
+        Err(e) if !is_not_found(&e) => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn is_not_found(e: &Report) -> bool {
+    matches!(
+        e.downcast_ref::<object_store::Error>(),
+        Some(object_store::Error::NotFound { .. })
+    )
+}
+
+/// Copy an object from one backend to the other, through a temporary download
+async fn copy(from: &dyn StorageBackend, to: &dyn StorageBackend, key: &str) -> Result<()> {
+    let path = from.get_object(key).await?;
+    let res = to.put_file(key, path.clone()).await;
+    let _ = tokio::fs::remove_file(&path).await;
+    res
+}
+
+#[async_trait]
+impl StorageBackend for ReplicatedBackend {
+    async fn put_file(&self, key: &str, path: PathBuf) -> Result<()> {
+        let (primary, replica) = tokio::join!(
+            self.primary.put_file(key, path.clone()),
+            self.replica.put_file(key, path),
+        );
+        both(primary, replica)
+    }
+
+    async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+        let (primary, replica) = tokio::join!(
+            self.primary.put_bytes(key, bytes.clone()),
+            self.replica.put_bytes(key, bytes),
+        );
+        both(primary, replica)
+    }
+
+    async fn get_object(&self, key: &str) -> Result<PathBuf> {
+        match self.primary.get_object(key).await {
+            Ok(path) => Ok(path),
+            Err(e) => {
+                tracing::warn!(?key, ?e, "cannot get object from primary store, trying replica");
+                self.replica.get_object(key).await
+            }
+        }
+    }
+
+    /// Delete the object from both backends, a backend already missing it isn't an error
+    async fn delete_object(&self, key: &str) -> Result<()> {
+        let (primary, replica) = tokio::join!(
+            self.primary.delete_object(key),
+            self.replica.delete_object(key),
+        );
+        both(ignore_not_found(primary), ignore_not_found(replica))
+    }
+
+    /// Presign with the primary backend, or the replica if the primary can't presign
+    async fn presigned_url(&self, key: &str, expires_in: Duration) -> Result<Option<String>> {
+        match self.primary.presigned_url(key, expires_in).await? {
+            Some(url) => Ok(Some(url)),
+            None => self.replica.presigned_url(key, expires_in).await,
+        }
+    }
+
+    async fn check(&self) -> Result<()> {
+        let (primary, replica) = tokio::join!(self.primary.check(), self.replica.check());
+        both(primary, replica)
+    }
+
+    async fn list_objects(&self) -> Result<BTreeMap<String, u64>> {
+        self.primary.list_objects().await
+    }
+
+    /// Copy objects between the backends until both hold the same objects
+    ///
+    /// Objects are compared by key and size. When both have an object with different sizes,
+    /// the primary's copy wins.
+    async fn resync(&self) -> Result<ResyncReport> {
+        let (primary, replica) = tokio::try_join!(
+            async {
+                let objects = self.primary.list_objects().await;
+                objects.wrap_err("cannot list primary object store")
+            },
+            async {
+                let objects = self.replica.list_objects().await;
+                objects.wrap_err("cannot list replica object store")
+            },
+        )?;
+
+        let mut report = ResyncReport::default();
+        let mut record = |key: &str, res: Result<()>| match res {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!(?key, ?e, "failed to resync object");
+                report.failed.push(ResyncFailure {
+                    key: key.to_owned(),
+                    error: format!("{e:#}"),
+                });
+                false
+            }
+        };
+
+        let mut to_replica = 0;
+        for (key, size) in &primary {
+            if replica.get(key) != Some(size) {
+                let res = copy(self.primary.as_ref(), self.replica.as_ref(), key).await;
+                to_replica += usize::from(record(key, res));
+            }
+        }
+        let mut to_primary = 0;
+        for key in replica.keys().filter(|key| !primary.contains_key(*key)) {
+            let res = copy(self.replica.as_ref(), self.primary.as_ref(), key).await;
+            to_primary += usize::from(record(key, res));
+        }
+
+        report.copied_to_replica = to_replica;
+        report.copied_to_primary = to_primary;
+        tracing::info!(?report, "resynced object store replica");
+        Ok(report)
+    }
+}
@@ -0,0 +1,138 @@
+//! Retries of object store operations failing with transient errors
+//!
+//! S3 occasionally answers with a 500 or times out, which shouldn't fail a whole upload or
+//! assembly. Errors that can't go away by trying again, i.e. a missing object or denied
+//! access, are returned right away.
+use std::future::Future;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use color_eyre::{Report, Result};
+
+/// Longest delay between two attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts after the first one, 0 to never retry
+    pub retries: u32,
+    /// Delay before the first retry, doubled after each one
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            base_delay: Duration::from_millis(200),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Run an operation, retrying it with exponential backoff while it fails with a
+    /// [retryable](is_retryable) error
+    pub async fn run<T, F, Fut>(&self, op: &str, key: &str, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut delay = self.base_delay;
+        let mut attempt = 0;
+        loop {
+            match f().await {
+                Ok(res) => return Ok(res),
+                Err(e) if attempt < self.retries && is_retryable(&e) => {
+                    attempt += 1;
+                    tracing::warn!(op, ?key, attempt, ?e, "retrying object store operation");
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Whether an error may go away by trying again, i.e. a server error or a timeout
+pub fn is_retryable(e: &Report) -> bool {
+    if let Some(e) = e.downcast_ref::<object_store::Error>() {
+        return is_retryable_store_error(e);
+    }
+    if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        // streamed uploads report store errors as I/O errors
+        if let Some(inner) = e.get_ref().and_then(|e| e.downcast_ref::<object_store::Error>()) {
+            return is_retryable_store_error(inner);
+        }
+        return matches!(
+            e.kind(),
+            ErrorKind::TimedOut
+                | ErrorKind::Interrupted
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe
+                | ErrorKind::UnexpectedEof
+        );
+    }
+    false
+}
+
+/// Requests failing after the store's own retries, or a panicked upload task
+fn is_retryable_store_error(e: &object_store::Error) -> bool {
+    matches!(
+        e,
+        object_store::Error::Generic { .. } | object_store::Error::JoinError { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn test_is_retryable() {
+        let generic = object_store::Error::Generic {
+            store: "S3",
+            source: "500 Internal Server Error".into(),
+        };
+        assert!(is_retryable(&Report::new(generic)));
+        let not_found = object_store::Error::NotFound {
+            path: "rpm/foo.rpm".into(),
+            source: "not found".into(),
+        };
+        assert!(!is_retryable(&Report::new(not_found)));
+        let timeout = std::io::Error::from(ErrorKind::TimedOut);
+        assert!(is_retryable(&Report::new(timeout)));
+        let denied = std::io::Error::from(ErrorKind::PermissionDenied);
+        assert!(!is_retryable(&Report::new(denied)));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = RetryPolicy {
+            retries: 2,
+            base_delay: Duration::from_millis(1),
+        };
+        let attempts = AtomicU32::new(0);
+        let res = policy
+            .run("get", "key", || async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(Report::new(std::io::Error::from(ErrorKind::TimedOut)))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+
+        // errors that can't go away aren't retried
+        attempts.store(0, Ordering::Relaxed);
+        let res = policy
+            .run("get", "key", || async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(Report::new(std::io::Error::from(ErrorKind::NotFound)))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+}
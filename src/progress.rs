@@ -0,0 +1,90 @@
+//! Live progress of assemblies
+//!
+//! The assemble task emits an event at each stage, which clients can follow with
+//! `GET /repo/{id}/assemble/events`. Events are only kept in memory, so clients only
+//! see progress of assemblies running on the instance they're connected to.
+
+use std::sync::LazyLock;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events buffered for slow subscribers before they start missing some
+const EVENT_BUFFER: usize = 256;
+
+/// How often to report staging progress, in packages
+pub const STAGING_REPORT_INTERVAL: usize = 100;
+
+static EVENTS: LazyLock<broadcast::Sender<AssembleEvent>> =
+    LazyLock::new(|| broadcast::channel(EVENT_BUFFER).0);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum AssembleStage {
+    /// A compose of the tag was created
+    Started { compose: String },
+    /// Packages linked into the staging directory so far
+    Staging { staged: usize, total: usize },
+    /// Generating repodata, with `native` or `createrepo`
+    Repodata { backend: String },
+    Signing,
+    Publishing,
+    Finished,
+    Failed { error: String },
+}
+
+impl AssembleStage {
+    /// Name of the stage, used as the SSE event type
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Started { .. } => "started",
+            Self::Staging { .. } => "staging",
+            Self::Repodata { .. } => "repodata",
+            Self::Signing => "signing",
+            Self::Publishing => "publishing",
+            Self::Finished => "finished",
+            Self::Failed { .. } => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AssembleEvent {
+    pub tag: String,
+    #[serde(flatten)]
+    pub stage: AssembleStage,
+}
+
+/// Report the progress of an assembly to every subscriber
+pub fn emit(tag: &str, stage: AssembleStage) {
+    tracing::trace!(tag, ?stage, "assemble progress");
+    // nobody listening isn't an error
+    let _ = EVENTS.send(AssembleEvent {
+        tag: tag.to_owned(),
+        stage,
+    });
+}
+
+/// Follow the progress of every assembly
+pub fn subscribe() -> broadcast::Receiver<AssembleEvent> {
+    EVENTS.subscribe()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emit() {
+        let mut rx = subscribe();
+        emit("foo", AssembleStage::Staging { staged: 1, total: 2 });
+
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event.tag, "foo");
+        assert_eq!(event.stage.name(), "staging");
+
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["stage"], "staging");
+        assert_eq!(json["total"], 2);
+    }
+}
@@ -0,0 +1,231 @@
+//! Per-caller rate limiting of the HTTP API
+//!
+//! Each caller gets a token bucket per [`RequestClass`], refilled continuously up to the configured
+//! requests per minute, so cheap reads can't be starved by a caller hammering uploads or assemblies.
+//! Callers are identified by their IP address: this runs before authentication, so bearer tokens
+//! aren't verified yet and anybody could make up a new one for each request.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Buckets kept before idle ones are dropped
+const MAX_BUCKETS: usize = 10_000;
+/// Minimum time between looking for idle buckets to drop while full
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+/// Caller sharing a bucket for new callers while every bucket is in use
+const OVERFLOW_CALLER: &str = "overflow";
+/// Header a reverse proxy appends the address of its client to
+const FORWARDED_FOR: &str = "x-forwarded-for";
+
+static LIMITER: LazyLock<RateLimiter> = LazyLock::new(RateLimiter::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestClass {
+    /// Read-only requests, i.e. listing packages
+    Read,
+    /// Requests changing state, i.e. uploads, signing and assemblies
+    Write,
+}
+
+impl RequestClass {
+    fn of(method: &Method) -> Self {
+        if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+            Self::Read
+        } else {
+            Self::Write
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<(String, RequestClass), Bucket>,
+    pruned: Option<Instant>,
+}
+
+impl Buckets {
+    /// Drop idle buckets, at most once per [`PRUNE_INTERVAL`] so a full map isn't scanned on
+    /// every request
+    fn prune(&mut self, now: Instant) {
+        if self
+            .pruned
+            .is_some_and(|at| now.duration_since(at) < PRUNE_INTERVAL)
+        {
+            return;
+        }
+        // a bucket idle for a minute is full again, forgetting it changes nothing
+        self.buckets
+            .retain(|_, b| now.duration_since(b.updated) < Duration::from_secs(60));
+        self.pruned = Some(now);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Take a token from the caller's bucket, returning how long to wait if it's empty
+    pub fn check(
+        &self,
+        caller: &str,
+        class: RequestClass,
+        per_minute: u32,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let capacity = f64::from(per_minute.max(1));
+        let per_second = capacity / 60.0;
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut key = (caller.to_owned(), class);
+        if !buckets.buckets.contains_key(&key) && buckets.buckets.len() >= MAX_BUCKETS {
+            buckets.prune(now);
+            if buckets.buckets.len() >= MAX_BUCKETS {
+                key.0 = OVERFLOW_CALLER.to_owned();
+            }
+        }
+
+        let bucket = buckets.buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+/// Who a request counts against, its IP address
+///
+/// Requests over a unix socket have no peer address. They come from a local reverse proxy with
+/// access to the socket, so the address it appended to `X-Forwarded-For` is used, or a bucket
+/// shared by every unix socket caller without one.
+fn caller(req: &Request) -> String {
+    if let Some(ConnectInfo(addr)) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        return addr.ip().to_string();
+    }
+    let forwarded = req
+        .headers()
+        .get(FORWARDED_FOR)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit(',').next())
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty());
+    match forwarded {
+        Some(addr) => format!("unix:{addr}"),
+        None => "unix".to_owned(),
+    }
+}
+
+/// Middleware rejecting requests over the caller's rate limit with 429 Too Many Requests
+pub async fn rate_limit(req: Request, next: Next) -> Response {
+    let config = crate::config::dynamic();
+    let class = RequestClass::of(req.method());
+    let limit = match class {
+        RequestClass::Read => config.rate_limit_reads,
+        RequestClass::Write => config.rate_limit_writes,
+    };
+    let Some(limit) = limit else {
+        return next.run(req).await;
+    };
+
+    match LIMITER.check(&caller(&req), class, limit, Instant::now()) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            tracing::debug!(?class, uri = %req.uri(), "rate limited request");
+            let retry_after = wait.as_secs().max(1).to_string();
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after)],
+                "Too Many Requests",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+
+        for _ in 0..60 {
+            assert!(limiter.check("ci", RequestClass::Write, 60, start).is_ok());
+        }
+        let wait = limiter.check("ci", RequestClass::Write, 60, start).unwrap_err();
+        assert!(wait <= Duration::from_secs(1));
+
+        // other classes and callers have their own buckets
+        assert!(limiter.check("ci", RequestClass::Read, 60, start).is_ok());
+        assert!(limiter.check("other", RequestClass::Write, 60, start).is_ok());
+
+        // refilled at the configured rate
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.check("ci", RequestClass::Write, 60, later).is_ok());
+        assert!(limiter.check("ci", RequestClass::Write, 60, later).is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter_full() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+        for i in 0..MAX_BUCKETS {
+            assert!(limiter
+                .check(&i.to_string(), RequestClass::Read, 1, start)
+                .is_ok());
+        }
+
+        // new callers share a bucket while every bucket is in use
+        assert!(limiter.check("new", RequestClass::Read, 1, start).is_ok());
+        assert!(limiter
+            .check("other", RequestClass::Read, 1, start)
+            .is_err());
+        assert_eq!(
+            limiter.buckets.lock().unwrap().buckets.len(),
+            MAX_BUCKETS + 1
+        );
+
+        // and get their own once idle ones were dropped
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.check("new", RequestClass::Read, 1, later).is_ok());
+        assert!(limiter.check("other", RequestClass::Read, 1, later).is_ok());
+        assert_eq!(limiter.buckets.lock().unwrap().buckets.len(), 2);
+    }
+
+    #[test]
+    fn test_unix_caller() {
+        let req = Request::builder().body(axum::body::Body::empty()).unwrap();
+        assert_eq!(caller(&req), "unix");
+
+        let req = Request::builder()
+            .header(FORWARDED_FOR, "203.0.113.7, 198.51.100.2")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        assert_eq!(caller(&req), "unix:198.51.100.2");
+    }
+}
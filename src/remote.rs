@@ -0,0 +1,170 @@
+//! Outbound HTTP requests to caller-supplied URLs, i.e. remote comps, mirrored repos and Koji hubs
+//!
+//! API callers choose these URLs, so requests are only made to public addresses: hosts resolving
+//! to loopback, private or link-local addresses are refused unless listed in
+//! `REMOTE_ALLOWED_HOSTS`. This is enforced when resolving, so redirects and DNS rebinding can't
+//! reach them either.
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{redirect, Url};
+
+/// Time to wait for a connection to a remote host
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time to wait for a remote host to send anything, so a hung server doesn't block forever
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// Redirects followed before giving up
+const MAX_REDIRECTS: usize = 10;
+
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .dns_resolver(Arc::new(PublicResolver))
+        .redirect(redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if let Err(e) = check_url(attempt.url().as_str()) {
+                attempt.error(e.to_string())
+            } else {
+                attempt.follow()
+            }
+        }))
+        .connect_timeout(CONNECT_TIMEOUT)
+        .read_timeout(READ_TIMEOUT)
+        .build()
+        .expect("cannot build HTTP client")
+});
+
+/// HTTP client for remote requests, refusing non-public addresses
+pub fn client() -> &'static reqwest::Client {
+    &CLIENT
+}
+
+/// GET a remote URL, failing on non-success status codes
+pub async fn get(url: &str) -> Result<reqwest::Response> {
+    let url = check_url(url)?;
+    tracing::debug!(%url, "fetching remote file");
+    Ok(client().get(url).send().await?.error_for_status()?)
+}
+
+/// Parse a remote URL, refusing anything but HTTP(S) and literal non-public addresses
+///
+/// Host names are checked when they're resolved, see [`PublicResolver`].
+pub fn check_url(url: &str) -> Result<Url> {
+    let url = Url::parse(url)?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(eyre!("unsupported URL scheme {}", url.scheme()));
+    }
+    let host = url.host_str().ok_or_else(|| eyre!("URL has no host"))?;
+    let ip = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>();
+    if let Ok(ip) = ip {
+        if !is_public(ip) && !is_allowed_host(host) {
+            return Err(eyre!("refusing to connect to non-public address {ip}"));
+        }
+    }
+    Ok(url)
+}
+
+/// Whether requests to a host are allowed whatever it resolves to, from `REMOTE_ALLOWED_HOSTS`
+fn is_allowed_host(host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    crate::config::CONFIG.get().is_some_and(|config| {
+        config
+            .remote_allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    })
+}
+
+/// Whether an address is publicly routable
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // shared address space (CGNAT)
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local, fc00::/7
+                    || (first & 0xfe00) == 0xfc00
+                    // link-local, fe80::/10
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Resolver dropping non-public addresses of hosts that aren't explicitly allowed
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_owned();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .collect();
+            let allowed = is_allowed_host(&host);
+            let addrs: Vec<SocketAddr> = addrs
+                .into_iter()
+                .filter(|addr| allowed || is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{host} has no public address").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_public() {
+        for ip in ["1.1.1.1", "2606:4700:4700::1111", "93.184.216.34"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn test_check_url() {
+        assert!(check_url("https://dl.fedoraproject.org/pub/").is_ok());
+        assert!(check_url("http://169.254.169.254/latest/meta-data").is_err());
+        assert!(check_url("http://[::1]:8000/").is_err());
+        assert!(check_url("file:///etc/passwd").is_err());
+        assert!(check_url("not a url").is_err());
+    }
+}
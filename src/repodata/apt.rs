@@ -0,0 +1,240 @@
+//! APT repository generation
+//!
+//! Debian packages are laid out like a Debian archive with a single `main` component, the tag
+//! being the suite:
+//!
+//! - `pool/main/<prefix>/<name>/<file>.deb`, linking the packages
+//! - `dists/<suite>/main/binary-<arch>/Packages{,.gz}`, indexing the packages of each architecture
+//! - `dists/<suite>/Release`, with the checksums of the indexes, signed as `InRelease` and
+//!   `Release.gpg` when the tag has a signing key
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use color_eyre::{eyre::eyre, Result};
+use flate2::{write::GzEncoder, Compression};
+use sha2::{Digest, Sha256};
+
+use crate::db::deb::Deb;
+
+/// The only component of generated repositories
+pub const COMPONENT: &str = "main";
+/// Architecture of packages installable on every architecture
+const ARCH_ALL: &str = "all";
+
+/// Directory of a suite's indexes, relative to the repo root
+pub fn dists_dir(suite: &str) -> String {
+    format!("dists/{suite}")
+}
+
+/// Location of a package in the pool, relative to the repo root
+///
+/// Like Debian, packages are grouped by their first letter, or first four for `lib*` packages.
+pub fn pool_path(deb: &Deb) -> String {
+    let prefix = match deb.name.get(..4) {
+        Some(prefix) if deb.name.starts_with("lib") && deb.name.len() > 4 => prefix,
+        _ => deb.name.get(..1).unwrap_or_default(),
+    };
+    format!("pool/{COMPONENT}/{prefix}/{}/{}", deb.name, deb.file_name())
+}
+
+/// Architectures to generate indexes for, `all` packages are listed in each of them
+///
+/// Only `all` if every package is architecture independent.
+pub fn architectures(debs: &[Deb]) -> Vec<String> {
+    let arches: BTreeSet<&str> = debs
+        .iter()
+        .map(|deb| deb.arch.as_str())
+        .filter(|arch| *arch != ARCH_ALL)
+        .collect();
+    if arches.is_empty() {
+        return vec![ARCH_ALL.to_owned()];
+    }
+    arches.into_iter().map(str::to_owned).collect()
+}
+
+/// A package's stanza in a `Packages` index, its control file plus where to download it
+pub fn packages_entry(deb: &Deb) -> Result<String> {
+    let sha256 = deb
+        .sha256
+        .as_deref()
+        .ok_or_else(|| eyre!("{} has no digest", deb.full_name()))?;
+    Ok(format!(
+        "{}\nFilename: {}\nSize: {}\nSHA256: {sha256}\n",
+        deb.control.trim_end(),
+        pool_path(deb),
+        deb.size
+    ))
+}
+
+/// The `Packages` index of an architecture, stanzas separated by blank lines
+pub fn packages_index(debs: &[Deb], arch: &str) -> Result<String> {
+    let mut debs: Vec<&Deb> = debs
+        .iter()
+        .filter(|deb| deb.arch == arch || deb.arch == ARCH_ALL)
+        .collect();
+    debs.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+    let entries = debs
+        .into_iter()
+        .map(packages_entry)
+        .collect::<Result<Vec<_>>>()?;
+    Ok(entries.join("\n"))
+}
+
+/// An index listed in a `Release` file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReleaseEntry {
+    /// Path of the index, relative to the suite's directory
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl ReleaseEntry {
+    pub fn new(path: String, data: &[u8]) -> Self {
+        Self {
+            path,
+            size: data.len() as u64,
+            sha256: hex::encode(Sha256::digest(data)),
+        }
+    }
+}
+
+/// The `Release` file of a suite
+pub fn release(
+    suite: &str,
+    arches: &[String],
+    entries: &[ReleaseEntry],
+    date: DateTime<Utc>,
+) -> String {
+    let mut release = format!(
+        "Origin: Subatomic\nLabel: {suite}\nSuite: {suite}\nCodename: {suite}\n\
+         Date: {}\nArchitectures: {}\nComponents: {COMPONENT}\nSHA256:\n",
+        date.format("%a, %d %b %Y %H:%M:%S UTC"),
+        arches.join(" ")
+    );
+    for entry in entries {
+        release.push_str(&format!(" {} {:>16} {}\n", entry.sha256, entry.size, entry.path));
+    }
+    release
+}
+
+/// Text covered by the signature of a cleartext signed message: trailing whitespace removed,
+/// CRLF line endings and no line ending after the last line
+pub fn canonical_text(text: &str) -> String {
+    text.lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// Assemble the `InRelease` file out of the `Release` file and the armored signature of its
+/// [canonical text](canonical_text)
+pub fn inrelease(release: &str, signature: &str) -> String {
+    let mut message = String::from("-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA256\n\n");
+    for line in release.lines() {
+        // dash-escaping, so lines can't be mistaken for armor headers
+        if line.starts_with('-') {
+            message.push_str("- ");
+        }
+        message.push_str(line.trim_end());
+        message.push('\n');
+    }
+    message.push_str(signature);
+    if !signature.ends_with('\n') {
+        message.push('\n');
+    }
+    message
+}
+
+/// Write the `Packages` indexes and `Release` file of a suite into `repo_dir`
+///
+/// The packages must already be linked into the pool, see [`pool_path`].
+pub fn generate(repo_dir: &Path, suite: &str, debs: &[Deb]) -> Result<()> {
+    let dists_dir = repo_dir.join(dists_dir(suite));
+    let arches = architectures(debs);
+    let mut entries = Vec::new();
+    for arch in &arches {
+        let dir = format!("{COMPONENT}/binary-{arch}");
+        std::fs::create_dir_all(dists_dir.join(&dir))?;
+
+        let index = packages_index(debs, arch)?;
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(index.as_bytes())?;
+        let gz = gz.finish()?;
+
+        std::fs::write(dists_dir.join(&dir).join("Packages"), &index)?;
+        std::fs::write(dists_dir.join(&dir).join("Packages.gz"), &gz)?;
+        entries.push(ReleaseEntry::new(format!("{dir}/Packages"), index.as_bytes()));
+        entries.push(ReleaseEntry::new(format!("{dir}/Packages.gz"), &gz));
+    }
+
+    std::fs::write(
+        dists_dir.join("Release"),
+        release(suite, &arches, &entries, Utc::now()),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEB_PATH: &str = "test/data/subatomic-hello_1.0.2-1_all.deb";
+
+    #[test]
+    fn test_pool_path() {
+        let mut deb = Deb::from_path(DEB_PATH, "foobar").unwrap();
+        assert_eq!(
+            pool_path(&deb),
+            "pool/main/s/subatomic-hello/subatomic-hello_1.0.2-1_all.deb"
+        );
+        deb.name = "libhello".to_owned();
+        assert!(pool_path(&deb).starts_with("pool/main/libh/libhello/"));
+    }
+
+    #[test]
+    fn test_packages_index() {
+        let all = Deb::from_path(DEB_PATH, "foobar").unwrap();
+        let mut amd64 = all.clone();
+        amd64.name = "other".to_owned();
+        amd64.arch = "amd64".to_owned();
+        let debs = [all, amd64];
+
+        assert_eq!(architectures(&debs), vec!["amd64"]);
+        assert_eq!(architectures(&debs[..1]), vec!["all"]);
+
+        let index = packages_index(&debs, "amd64").unwrap();
+        assert_eq!(index.matches("\nFilename: pool/main/").count(), 2);
+        assert!(index.contains("Package: subatomic-hello\n"));
+        assert!(index.contains("\n\nPackage: subatomic-hello"));
+        let index = packages_index(&debs, "arm64").unwrap();
+        assert_eq!(index.matches("\nSHA256: ").count(), 1);
+    }
+
+    #[test]
+    fn test_release() {
+        let entry = ReleaseEntry::new("main/binary-all/Packages".to_owned(), b"");
+        let date = DateTime::from_timestamp(0, 0).unwrap();
+        let release = release("foobar", &["all".to_owned()], &[entry], date);
+        assert!(release.contains("Suite: foobar\n"));
+        assert!(release.contains("Date: Thu, 01 Jan 1970 00:00:00 UTC\n"));
+        assert!(release.ends_with(concat!(
+            " e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            "                0 main/binary-all/Packages\n"
+        )));
+    }
+
+    #[test]
+    fn test_inrelease() {
+        let release = "Suite: foobar  \n-dashed\n";
+        assert_eq!(canonical_text(release), "Suite: foobar\r\n-dashed");
+        let signed = inrelease(release, "-----BEGIN PGP SIGNATURE-----\n");
+        assert_eq!(
+            signed,
+            "-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA256\n\nSuite: foobar\n- -dashed\n\
+             -----BEGIN PGP SIGNATURE-----\n"
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! comps.xml (group metadata) handling
+
+use color_eyre::{eyre::eyre, Result};
+use quick_xml::{events::Event, Reader, Writer};
+
+use super::{decompress, fetch, fetch_repomd, join_url};
+
+/// Metadata types that may carry group data, in order of preference
+const GROUP_TYPES: &[&str] = &["group", "group_gz", "group_zst"];
+
+/// Fetch a comps document from a remote source
+///
+/// `url` may either point directly to a comps file (`.xml`, `.xml.gz`), or to the root
+/// of a yum repository, in which case the group metadata is looked up from `repomd.xml`.
+pub async fn fetch_comps(url: &str) -> Result<String> {
+    let is_file = [".xml", ".xml.gz", ".xml.zst"]
+        .iter()
+        .any(|ext| url.ends_with(ext));
+
+    let (location, data) = if is_file {
+        (url.to_owned(), fetch(url).await?)
+    } else {
+        let records = fetch_repomd(url).await?;
+        let record = GROUP_TYPES
+            .iter()
+            .find_map(|t| records.iter().find(|r| r.data_type == *t))
+            .ok_or_else(|| eyre!("repository has no group metadata"))?;
+        let location = join_url(url, &record.location);
+        let data = fetch(&location).await?;
+        (location, data)
+    };
+
+    Ok(String::from_utf8(decompress(&location, data)?)?)
+}
+
+/// Check that a document is a comps file with at least one group, category or environment
+pub fn validate(xml: &str) -> Result<()> {
+    let mut reader = Reader::from_str(xml);
+    let mut root = None;
+    let mut entries = 0;
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) => {
+                let name = e.local_name();
+                if root.is_none() {
+                    root = Some(name.as_ref().to_vec());
+                } else if matches!(name.as_ref(), b"group" | b"category" | b"environment") {
+                    entries += 1;
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    match root.as_deref() {
+        Some(b"comps") if entries > 0 => Ok(()),
+        Some(b"comps") => Err(eyre!("comps document contains no groups")),
+        _ => Err(eyre!("document is not a comps file")),
+    }
+}
+
+/// Remove groups with the given IDs from a comps document, along with every category and
+/// environment reference to them
+pub fn exclude_groups(xml: &str, exclude: &[String]) -> Result<String> {
+    if exclude.is_empty() {
+        return Ok(xml.to_owned());
+    }
+
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    // events of the group or group reference currently being read, held back until we know its ID
+    let mut held: Option<Vec<Event<'static>>> = None;
+    let mut held_id = None;
+    let mut is_group = false;
+    let mut depth = 0;
+    let mut in_id = false;
+
+    loop {
+        let event = reader.read_event()?;
+        if matches!(event, Event::Eof) {
+            break;
+        }
+
+        if let Some(buffered) = held.as_mut() {
+            match &event {
+                Event::Start(e) => {
+                    depth += 1;
+                    in_id = is_group && depth == 2 && e.local_name().as_ref() == b"id";
+                }
+                // a `<groupid>` reference is its ID
+                Event::Text(t) if in_id || (!is_group && depth == 1) => {
+                    held_id = Some(t.unescape()?.trim().to_owned())
+                }
+                Event::End(_) => {
+                    depth -= 1;
+                    in_id = false;
+                }
+                _ => {}
+            }
+            buffered.push(event.into_owned());
+
+            if depth == 0 {
+                let events = held.take().unwrap_or_default();
+                let id = held_id.take().unwrap_or_default();
+                if exclude.contains(&id) {
+                    tracing::debug!(?id, is_group, "excluding group from comps");
+                } else {
+                    for e in events {
+                        writer.write_event(e)?;
+                    }
+                }
+            }
+            continue;
+        }
+
+        if let Event::Start(e) = &event {
+            let name = e.local_name();
+            if matches!(name.as_ref(), b"group" | b"groupid") {
+                is_group = name.as_ref() == b"group";
+                held = Some(vec![event.into_owned()]);
+                depth = 1;
+                continue;
+            }
+        }
+
+        writer.write_event(event)?;
+    }
+
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<comps>
+  <group>
+    <id>core</id>
+    <packagelist><packagereq>bash</packagereq></packagelist>
+  </group>
+  <group>
+    <id>extras</id>
+  </group>
+  <category>
+    <id>base</id>
+    <grouplist><groupid>core</groupid><groupid>extras</groupid></grouplist>
+  </category>
+  <environment>
+    <id>workstation</id>
+    <grouplist><groupid>core</groupid></grouplist>
+    <optionlist><groupid>extras</groupid></optionlist>
+  </environment>
+</comps>"#;
+
+    #[test]
+    fn test_validate_comps() {
+        assert!(validate(COMPS).is_ok());
+        assert!(validate("<comps></comps>").is_err());
+        assert!(validate("<repomd><group/></repomd>").is_err());
+    }
+
+    #[test]
+    fn test_exclude_groups() {
+        let out = exclude_groups(COMPS, &["extras".to_owned()]).unwrap();
+        assert!(out.contains("<id>core</id>"));
+        // categories and environments don't reference the excluded group anymore
+        assert!(!out.contains("extras"));
+        assert_eq!(out.matches("<groupid>core</groupid>").count(), 2);
+        assert!(validate(&out).is_ok());
+
+        // nothing is left once every group is excluded
+        let out = exclude_groups(COMPS, &["core".to_owned(), "extras".to_owned()]).unwrap();
+        assert!(!out.contains("<group>"));
+        assert!(!out.contains("<groupid>"));
+    }
+}
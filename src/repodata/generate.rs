@@ -0,0 +1,793 @@
+//! Native repodata generation, producing the same metadata as `createrepo_c`
+//!
+//! Writes `primary.xml.gz`, `filelists.xml.gz` and `other.xml.gz` for every package
+//! in a repo directory, plus optional group, module and advisory metadata, and indexes
+//! them in `repodata/repomd.xml`.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use color_eyre::{eyre::eyre, Result};
+use flate2::{write::GzEncoder, Compression};
+use quick_xml::{
+    events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event},
+    Writer,
+};
+use rpm::{Dependency, DependencyFlags, FileEntry, FileFlags, FileMode, PackageMetadata};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::db::rpm::file_sha256;
+
+const NS_COMMON: &str = "http://linux.duke.edu/metadata/common";
+const NS_RPM: &str = "http://linux.duke.edu/metadata/rpm";
+const NS_FILELISTS: &str = "http://linux.duke.edu/metadata/filelists";
+const NS_OTHER: &str = "http://linux.duke.edu/metadata/other";
+const NS_REPO: &str = "http://linux.duke.edu/metadata/repo";
+
+/// A package file in the repo, with the metadata needed for repodata
+struct RepoPackage {
+    metadata: PackageMetadata,
+    /// Location of the package, relative to the repo root
+    location: String,
+    checksum: String,
+    size: u64,
+    mtime: u64,
+}
+
+impl RepoPackage {
+    fn open(repo_dir: &Path, path: &Path, checksum: String, size: Option<u64>) -> Result<Self> {
+        // only the headers, the payload isn't needed
+        let metadata = PackageMetadata::open(path)?;
+        // follows the symlink to the cached object
+        let meta = std::fs::metadata(path)?;
+        let mtime = meta
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+
+        Ok(Self {
+            metadata,
+            location: path.strip_prefix(repo_dir)?.to_string_lossy().to_string(),
+            checksum,
+            size: size.unwrap_or(meta.len()),
+            mtime,
+        })
+    }
+
+    fn name(&self) -> &str {
+        self.metadata.get_name().unwrap_or_default()
+    }
+
+    fn arch(&self) -> &str {
+        crate::db::rpm::package_arch(&self.metadata).unwrap_or_default()
+    }
+
+    /// `epoch`, `ver` and `rel` attributes of a `<version>` element
+    fn version_attrs(&self) -> [(&str, String); 3] {
+        [
+            (
+                "epoch",
+                self.metadata.get_epoch().unwrap_or_default().to_string(),
+            ),
+            (
+                "ver",
+                self.metadata.get_version().unwrap_or_default().to_owned(),
+            ),
+            (
+                "rel",
+                self.metadata.get_release().unwrap_or_default().to_owned(),
+            ),
+        ]
+    }
+
+    /// `pkgid`, `name` and `arch` attributes of filelists and other `<package>` elements
+    fn package_attrs(&self) -> [(&str, &str); 3] {
+        [
+            ("pkgid", self.checksum.as_str()),
+            ("name", self.name()),
+            ("arch", self.arch()),
+        ]
+    }
+}
+
+/// Metadata file written to `repodata/`, as recorded in `repomd.xml`
+struct RepoMdData {
+    data_type: String,
+    location: String,
+    checksum: String,
+    /// Checksum and size of the uncompressed file, for compressed metadata
+    open_checksum: Option<String>,
+    size: u64,
+    open_size: Option<u64>,
+    /// Checksum and size of the zchunk header, for zchunk metadata
+    header_checksum: Option<String>,
+    header_size: Option<u64>,
+    timestamp: u64,
+}
+
+/// Additional metadata to include in the repodata
+#[derive(Debug, Clone, Default)]
+pub struct RepodataOptions {
+    /// comps document, for group installs
+    pub groupfile: Option<PathBuf>,
+    /// modulemd YAML stream, for module streams
+    pub modules: Option<PathBuf>,
+    /// `updateinfo.xml` document, for advisories
+    pub updateinfo: Option<PathBuf>,
+    /// Also write zchunk copies of the package metadata, for incremental downloads
+    ///
+    /// Requires the `zck` and `zck_read_header` tools.
+    pub zchunk: bool,
+    /// Known SHA256 digests of package files by file name, so they don't have to be read
+    pub checksums: HashMap<String, String>,
+    /// Known sizes of package files by file name, i.e. of signed copies
+    pub sizes: HashMap<String, u64>,
+    /// Directory to cache the metadata of each package in, see [`PackageCache`]
+    pub cache_dir: Option<PathBuf>,
+}
+
+/// The `<package>` entries of a single package in primary, filelists and other metadata
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct PackageFragments {
+    checksum: String,
+    location: String,
+    primary: String,
+    filelists: String,
+    other: String,
+}
+
+impl PackageFragments {
+    fn new(pkg: &RepoPackage) -> Result<Self> {
+        let fragment = |write: fn(&mut Writer<Vec<u8>>, &RepoPackage) -> Result<()>| {
+            let mut w = Writer::new_with_indent(Vec::new(), b' ', 2);
+            write(&mut w, pkg)?;
+            Ok::<_, color_eyre::Report>(String::from_utf8(w.into_inner())?)
+        };
+        Ok(Self {
+            checksum: pkg.checksum.clone(),
+            location: pkg.location.clone(),
+            primary: fragment(primary_package)?,
+            filelists: fragment(filelists_package)?,
+            other: fragment(other_package)?,
+        })
+    }
+}
+
+/// On-disk cache of package metadata, keyed by the checksum of the package file
+///
+/// Parsing every package header is the slow part of generating repodata, this lets
+/// unchanged packages be skipped when a tag is assembled again.
+struct PackageCache {
+    dir: PathBuf,
+}
+
+impl PackageCache {
+    fn path(&self, checksum: &str) -> PathBuf {
+        self.dir.join(&checksum[0..2]).join(format!("{checksum}.json"))
+    }
+
+    /// Cached metadata of a package, if it was cached at the same location
+    fn get(&self, checksum: &str, location: &str) -> Option<PackageFragments> {
+        let data = std::fs::read(self.path(checksum)).ok()?;
+        serde_json::from_slice::<PackageFragments>(&data)
+            .ok()
+            .filter(|f| f.location == location)
+    }
+
+    fn put(&self, fragments: &PackageFragments) -> Result<()> {
+        let path = self.path(&fragments.checksum);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, serde_json::to_vec(fragments)?)?;
+        Ok(())
+    }
+}
+
+/// Collect the `.rpm` files of a repo directory, sorted by file name
+fn find_packages(repo_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = std::fs::read_dir(repo_dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "rpm"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Generate repodata for every package in `repo_dir`, including any extra metadata
+///
+/// This is blocking, and should be run with [`tokio::task::spawn_blocking`].
+pub fn generate(repo_dir: &Path, options: &RepodataOptions) -> Result<()> {
+    let cache = options.cache_dir.clone().map(|dir| PackageCache { dir });
+    let mut pkgs = Vec::new();
+    let mut cached = 0;
+    for path in find_packages(repo_dir)? {
+        let location = path.strip_prefix(repo_dir)?.to_string_lossy().to_string();
+        let checksum = match options.checksums.get(&location) {
+            Some(checksum) => checksum.clone(),
+            None => file_sha256(&path)?,
+        };
+
+        if let Some(fragments) = cache.as_ref().and_then(|c| c.get(&checksum, &location)) {
+            cached += 1;
+            pkgs.push(fragments);
+            continue;
+        }
+        let size = options.sizes.get(&location).copied();
+        let fragments =
+            PackageFragments::new(&RepoPackage::open(repo_dir, &path, checksum, size)?)?;
+        if let Some(cache) = &cache {
+            cache.put(&fragments)?;
+        }
+        pkgs.push(fragments);
+    }
+    tracing::debug!(count = pkgs.len(), cached, ?repo_dir, "generating repodata");
+
+    let repodata_dir = repo_dir.join("repodata");
+    std::fs::create_dir_all(&repodata_dir)?;
+
+    let timestamp = chrono::Utc::now().timestamp() as u64;
+    let mut data = Vec::new();
+    for (data_type, xml) in [
+        ("primary", primary_xml(&pkgs)?),
+        ("filelists", filelists_xml(&pkgs)?),
+        ("other", other_xml(&pkgs)?),
+    ] {
+        let file_name = format!("{data_type}.xml");
+        data.push(write_metadata(&repodata_dir, data_type, &file_name, &xml, timestamp)?);
+        if options.zchunk {
+            data.push(write_zck(&repodata_dir, data_type, &xml, timestamp)?);
+        }
+    }
+
+    if let Some(groupfile) = &options.groupfile {
+        let comps = std::fs::read(groupfile)?;
+        data.push(write_group(&repodata_dir, &comps, timestamp)?);
+        data.push(write_metadata(
+            &repodata_dir,
+            "group_gz",
+            "comps.xml",
+            &comps,
+            timestamp,
+        )?);
+    }
+    if let Some(modules) = &options.modules {
+        let modules = std::fs::read(modules)?;
+        data.push(write_metadata(
+            &repodata_dir,
+            "modules",
+            "modules.yaml",
+            &modules,
+            timestamp,
+        )?);
+    }
+    if let Some(updateinfo) = &options.updateinfo {
+        let updateinfo = std::fs::read(updateinfo)?;
+        data.push(write_metadata(
+            &repodata_dir,
+            "updateinfo",
+            "updateinfo.xml",
+            &updateinfo,
+            timestamp,
+        )?);
+    }
+
+    std::fs::write(repodata_dir.join("repomd.xml"), repomd_xml(&data, timestamp)?)?;
+    Ok(())
+}
+
+/// Compress and write a metadata file, named after its checksum like `createrepo_c` does
+fn write_metadata(
+    repodata_dir: &Path,
+    data_type: &str,
+    file_name: &str,
+    xml: &[u8],
+    timestamp: u64,
+) -> Result<RepoMdData> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(xml)?;
+    let compressed = encoder.finish()?;
+
+    let checksum = hex::encode(Sha256::digest(&compressed));
+    let file_name = format!("{checksum}-{file_name}.gz");
+    std::fs::write(repodata_dir.join(&file_name), &compressed)?;
+
+    Ok(RepoMdData {
+        data_type: data_type.to_owned(),
+        location: format!("repodata/{file_name}"),
+        checksum,
+        open_checksum: Some(hex::encode(Sha256::digest(xml))),
+        size: compressed.len() as u64,
+        open_size: Some(xml.len() as u64),
+        header_checksum: None,
+        header_size: None,
+        timestamp,
+    })
+}
+
+/// Run a command, failing with its stderr if it exits unsuccessfully
+fn run(cmd: &mut Command) -> Result<String> {
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "{:?} failed: {}",
+            cmd.get_program(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Read the header size and checksum out of `zck_read_header` output
+fn parse_zck_header(output: &str) -> Result<(u64, String)> {
+    let field = |name: &str| {
+        output
+            .lines()
+            .find_map(|l| l.strip_prefix(name))
+            .map(|v| v.trim().to_owned())
+            .ok_or_else(|| eyre!("zck header has no {name}"))
+    };
+    Ok((field("Header size:")?.parse()?, field("Header checksum:")?))
+}
+
+/// Write a zchunk-compressed copy of a metadata file, i.e `primary_zck`
+fn write_zck(
+    repodata_dir: &Path,
+    data_type: &str,
+    xml: &[u8],
+    timestamp: u64,
+) -> Result<RepoMdData> {
+    let source = repodata_dir.join(format!("{data_type}.xml"));
+    let staged = repodata_dir.join(format!("{data_type}.xml.zck"));
+    std::fs::write(&source, xml)?;
+    let compressed = run(Command::new("zck").arg("-o").arg(&staged).arg(&source));
+    std::fs::remove_file(&source)?;
+    compressed?;
+
+    let (header_size, header_checksum) =
+        parse_zck_header(&run(Command::new("zck_read_header").arg(&staged))?)?;
+    let zck = std::fs::read(&staged)?;
+    let checksum = hex::encode(Sha256::digest(&zck));
+    let file_name = format!("{checksum}-{data_type}.xml.zck");
+    std::fs::rename(&staged, repodata_dir.join(&file_name))?;
+
+    Ok(RepoMdData {
+        data_type: format!("{data_type}_zck"),
+        location: format!("repodata/{file_name}"),
+        checksum,
+        open_checksum: Some(hex::encode(Sha256::digest(xml))),
+        size: zck.len() as u64,
+        open_size: Some(xml.len() as u64),
+        header_checksum: Some(header_checksum),
+        header_size: Some(header_size),
+        timestamp,
+    })
+}
+
+/// Write the uncompressed comps document, which older clients only look for
+fn write_group(repodata_dir: &Path, comps: &[u8], timestamp: u64) -> Result<RepoMdData> {
+    let checksum = hex::encode(Sha256::digest(comps));
+    let file_name = format!("{checksum}-comps.xml");
+    std::fs::write(repodata_dir.join(&file_name), comps)?;
+
+    Ok(RepoMdData {
+        data_type: "group".to_owned(),
+        location: format!("repodata/{file_name}"),
+        checksum,
+        open_checksum: None,
+        size: comps.len() as u64,
+        open_size: None,
+        header_checksum: None,
+        header_size: None,
+        timestamp,
+    })
+}
+
+pub(super) fn new_writer() -> Result<Writer<Vec<u8>>> {
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    Ok(writer)
+}
+
+pub(super) fn start(writer: &mut Writer<Vec<u8>>, name: &str, attrs: &[(&str, &str)]) -> Result<()> {
+    let elem = BytesStart::new(name).with_attributes(attrs.iter().copied());
+    writer.write_event(Event::Start(elem))?;
+    Ok(())
+}
+
+pub(super) fn end(writer: &mut Writer<Vec<u8>>, name: &str) -> Result<()> {
+    writer.write_event(Event::End(BytesEnd::new(name)))?;
+    Ok(())
+}
+
+pub(super) fn empty(writer: &mut Writer<Vec<u8>>, name: &str, attrs: &[(&str, &str)]) -> Result<()> {
+    let elem = BytesStart::new(name).with_attributes(attrs.iter().copied());
+    writer.write_event(Event::Empty(elem))?;
+    Ok(())
+}
+
+pub(super) fn text(
+    writer: &mut Writer<Vec<u8>>,
+    name: &str,
+    attrs: &[(&str, &str)],
+    value: &str,
+) -> Result<()> {
+    start(writer, name, attrs)?;
+    writer.write_event(Event::Text(BytesText::new(value)))?;
+    end(writer, name)
+}
+
+pub(super) fn as_refs<'a>(attrs: &'a [(&'a str, String)]) -> Vec<(&'a str, &'a str)> {
+    attrs.iter().map(|(k, v)| (*k, v.as_str())).collect()
+}
+
+/// Split a dependency version of the form `[epoch:]version[-release]`
+fn split_evr(evr: &str) -> (&str, &str, Option<&str>) {
+    let (epoch, vr) = evr.split_once(':').unwrap_or(("0", evr));
+    match vr.rsplit_once('-') {
+        Some((version, release)) => (epoch, version, Some(release)),
+        None => (epoch, vr, None),
+    }
+}
+
+/// Comparison flag of a versioned dependency, i.e `GE`
+fn dep_flag(flags: DependencyFlags) -> Option<&'static str> {
+    let less = flags.contains(DependencyFlags::LESS);
+    let greater = flags.contains(DependencyFlags::GREATER);
+    let equal = flags.contains(DependencyFlags::EQUAL);
+    match (less, greater, equal) {
+        (true, false, true) => Some("LE"),
+        (true, false, false) => Some("LT"),
+        (false, true, true) => Some("GE"),
+        (false, true, false) => Some("GT"),
+        (false, false, true) => Some("EQ"),
+        _ => None,
+    }
+}
+
+fn write_deps(writer: &mut Writer<Vec<u8>>, name: &str, deps: &[Dependency]) -> Result<()> {
+    // rpmlib() requirements are internal to rpm, createrepo_c leaves them out as well
+    let deps = deps
+        .iter()
+        .filter(|d| !d.name.starts_with("rpmlib("))
+        .collect::<Vec<_>>();
+    if deps.is_empty() {
+        return Ok(());
+    }
+
+    start(writer, name, &[])?;
+    for dep in deps {
+        let mut attrs = vec![("name", dep.name.as_str())];
+        if let Some(flag) = dep_flag(dep.flags).filter(|_| !dep.version.is_empty()) {
+            let (epoch, version, release) = split_evr(&dep.version);
+            attrs.extend([("flags", flag), ("epoch", epoch), ("ver", version)]);
+            if let Some(release) = release {
+                attrs.push(("rel", release));
+            }
+        }
+        if dep
+            .flags
+            .intersects(DependencyFlags::SCRIPT_PRE | DependencyFlags::SCRIPT_POST)
+        {
+            attrs.push(("pre", "1"));
+        }
+        empty(writer, "rpm:entry", &attrs)?;
+    }
+    end(writer, name)
+}
+
+/// Whether a file is listed in primary.xml, and not only in filelists.xml
+fn is_primary_file(path: &str) -> bool {
+    path.starts_with("/etc/") || path.contains("bin/") || path == "/usr/lib/sendmail"
+}
+
+fn primary_package(w: &mut Writer<Vec<u8>>, pkg: &RepoPackage) -> Result<()> {
+    let meta = &pkg.metadata;
+    start(w, "package", &[("type", "rpm")])?;
+    text(w, "name", &[], pkg.name())?;
+    text(w, "arch", &[], pkg.arch())?;
+    empty(w, "version", &as_refs(&pkg.version_attrs()))?;
+    text(w, "checksum", &[("type", "sha256"), ("pkgid", "YES")], &pkg.checksum)?;
+    text(w, "summary", &[], meta.get_summary().unwrap_or_default())?;
+    text(w, "description", &[], meta.get_description().unwrap_or_default())?;
+    text(w, "packager", &[], meta.get_packager().unwrap_or_default())?;
+    text(w, "url", &[], meta.get_url().unwrap_or_default())?;
+    empty(
+        w,
+        "time",
+        &[
+            ("file", &pkg.mtime.to_string()),
+            ("build", &meta.get_build_time().unwrap_or_default().to_string()),
+        ],
+    )?;
+    empty(
+        w,
+        "size",
+        &[
+            ("package", &pkg.size.to_string()),
+            (
+                "installed",
+                &meta.get_installed_size().unwrap_or_default().to_string(),
+            ),
+        ],
+    )?;
+    empty(w, "location", &[("href", &pkg.location)])?;
+
+    start(w, "format", &[])?;
+    text(w, "rpm:license", &[], meta.get_license().unwrap_or_default())?;
+    text(w, "rpm:vendor", &[], meta.get_vendor().unwrap_or_default())?;
+    text(w, "rpm:group", &[], meta.get_group().unwrap_or_default())?;
+    text(w, "rpm:buildhost", &[], meta.get_build_host().unwrap_or_default())?;
+    text(w, "rpm:sourcerpm", &[], meta.get_source_rpm().unwrap_or_default())?;
+    let offsets = meta.get_package_segment_offsets();
+    empty(
+        w,
+        "rpm:header-range",
+        &[
+            ("start", &offsets.header.to_string()),
+            ("end", &offsets.payload.to_string()),
+        ],
+    )?;
+
+    write_deps(w, "rpm:provides", &meta.get_provides().unwrap_or_default())?;
+    write_deps(w, "rpm:requires", &meta.get_requires().unwrap_or_default())?;
+    write_deps(w, "rpm:conflicts", &meta.get_conflicts().unwrap_or_default())?;
+    write_deps(w, "rpm:obsoletes", &meta.get_obsoletes().unwrap_or_default())?;
+    write_deps(w, "rpm:suggests", &meta.get_suggests().unwrap_or_default())?;
+    write_deps(w, "rpm:enhances", &meta.get_enhances().unwrap_or_default())?;
+    write_deps(w, "rpm:recommends", &meta.get_recommends().unwrap_or_default())?;
+    write_deps(w, "rpm:supplements", &meta.get_supplements().unwrap_or_default())?;
+
+    for entry in meta.get_file_entries().unwrap_or_default() {
+        let path = entry.path.to_string_lossy();
+        if is_primary_file(&path) {
+            write_file(w, &entry)?;
+        }
+    }
+    end(w, "format")?;
+    end(w, "package")?;
+    Ok(())
+}
+
+/// `type` attribute of a `<file>` element, unset for regular files
+fn file_type(entry: &FileEntry) -> Option<&'static str> {
+    if matches!(entry.mode, FileMode::Dir { .. }) {
+        Some("dir")
+    } else if entry.flags.contains(FileFlags::GHOST) {
+        Some("ghost")
+    } else {
+        None
+    }
+}
+
+fn write_file(w: &mut Writer<Vec<u8>>, entry: &FileEntry) -> Result<()> {
+    let path = entry.path.to_string_lossy();
+    match file_type(entry) {
+        Some(file_type) => text(w, "file", &[("type", file_type)], &path),
+        None => text(w, "file", &[], &path),
+    }
+}
+
+fn filelists_package(w: &mut Writer<Vec<u8>>, pkg: &RepoPackage) -> Result<()> {
+    start(w, "package", &pkg.package_attrs())?;
+    empty(w, "version", &as_refs(&pkg.version_attrs()))?;
+    for entry in pkg.metadata.get_file_entries().unwrap_or_default() {
+        write_file(w, &entry)?;
+    }
+    end(w, "package")?;
+    Ok(())
+}
+
+fn other_package(w: &mut Writer<Vec<u8>>, pkg: &RepoPackage) -> Result<()> {
+    start(w, "package", &pkg.package_attrs())?;
+    empty(w, "version", &as_refs(&pkg.version_attrs()))?;
+    for entry in pkg.metadata.get_changelog_entries().unwrap_or_default() {
+        text(
+            w,
+            "changelog",
+            &[
+                ("author", &entry.name),
+                ("date", &entry.timestamp.to_string()),
+            ],
+            &entry.description,
+        )?;
+    }
+    end(w, "package")?;
+    Ok(())
+}
+
+/// Write a metadata document made of the given `<package>` entries
+fn package_document<'a>(
+    root: &str,
+    attrs: &[(&str, &str)],
+    entries: impl ExactSizeIterator<Item = &'a str>,
+) -> Result<Vec<u8>> {
+    let mut w = new_writer()?;
+    let count = entries.len().to_string();
+    let mut attrs = attrs.to_vec();
+    attrs.push(("packages", &count));
+    start(&mut w, root, &attrs)?;
+    for entry in entries {
+        w.get_mut().push(b'\n');
+        w.get_mut().extend_from_slice(entry.as_bytes());
+    }
+    end(&mut w, root)?;
+    Ok(w.into_inner())
+}
+
+fn primary_xml(pkgs: &[PackageFragments]) -> Result<Vec<u8>> {
+    package_document(
+        "metadata",
+        &[("xmlns", NS_COMMON), ("xmlns:rpm", NS_RPM)],
+        pkgs.iter().map(|p| p.primary.as_str()),
+    )
+}
+
+fn filelists_xml(pkgs: &[PackageFragments]) -> Result<Vec<u8>> {
+    package_document(
+        "filelists",
+        &[("xmlns", NS_FILELISTS)],
+        pkgs.iter().map(|p| p.filelists.as_str()),
+    )
+}
+
+fn other_xml(pkgs: &[PackageFragments]) -> Result<Vec<u8>> {
+    package_document(
+        "otherdata",
+        &[("xmlns", NS_OTHER)],
+        pkgs.iter().map(|p| p.other.as_str()),
+    )
+}
+
+fn repomd_xml(data: &[RepoMdData], revision: u64) -> Result<Vec<u8>> {
+    let mut w = new_writer()?;
+    start(&mut w, "repomd", &[("xmlns", NS_REPO), ("xmlns:rpm", NS_RPM)])?;
+    text(&mut w, "revision", &[], &revision.to_string())?;
+
+    for d in data {
+        start(&mut w, "data", &[("type", &d.data_type)])?;
+        text(&mut w, "checksum", &[("type", "sha256")], &d.checksum)?;
+        if let Some(open_checksum) = &d.open_checksum {
+            text(&mut w, "open-checksum", &[("type", "sha256")], open_checksum)?;
+        }
+        empty(&mut w, "location", &[("href", &d.location)])?;
+        text(&mut w, "timestamp", &[], &d.timestamp.to_string())?;
+        text(&mut w, "size", &[], &d.size.to_string())?;
+        if let Some(open_size) = d.open_size {
+            text(&mut w, "open-size", &[], &open_size.to_string())?;
+        }
+        if let Some(header_checksum) = &d.header_checksum {
+            text(&mut w, "header-checksum", &[("type", "sha256")], header_checksum)?;
+        }
+        if let Some(header_size) = d.header_size {
+            text(&mut w, "header-size", &[], &header_size.to_string())?;
+        }
+        end(&mut w, "data")?;
+    }
+
+    end(&mut w, "repomd")?;
+    Ok(w.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repodata::{decompress, parse_repomd};
+
+    const RPM_PATH: &str = "test/data/anda-srpm-macros-0:0.2.6-1.fc41.noarch.rpm";
+
+    #[test]
+    fn test_split_evr() {
+        assert_eq!(split_evr("1:2.0-3.fc41"), ("1", "2.0", Some("3.fc41")));
+        assert_eq!(split_evr("2.0"), ("0", "2.0", None));
+    }
+
+    #[test]
+    fn test_parse_zck_header() {
+        let output = "Overall checksum type: SHA-256\n\
+            Header size: 1234\n\
+            Header checksum: abcdef\n";
+        assert_eq!(parse_zck_header(output).unwrap(), (1234, "abcdef".to_owned()));
+        assert!(parse_zck_header("Header size: 1\n").is_err());
+    }
+
+    #[test]
+    fn test_generate() {
+        let dir = std::env::temp_dir().join(format!("subatomic-repodata-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy(RPM_PATH, dir.join("anda-srpm-macros.rpm")).unwrap();
+
+        let groupfile = dir.with_extension("comps.xml");
+        std::fs::write(&groupfile, "<comps><group><id>core</id></group></comps>").unwrap();
+
+        let options = RepodataOptions {
+            groupfile: Some(groupfile.clone()),
+            ..Default::default()
+        };
+        generate(&dir, &options).unwrap();
+
+        let repomd = std::fs::read_to_string(dir.join("repodata/repomd.xml")).unwrap();
+        let records = parse_repomd(&repomd).unwrap();
+        assert_eq!(records.len(), 5);
+        assert!(records.iter().any(|r| r.data_type == "group"));
+
+        let primary = records.iter().find(|r| r.data_type == "primary").unwrap();
+        let data = std::fs::read(dir.join(&primary.location)).unwrap();
+        let primary = decompress(&primary.location, data).unwrap();
+        let primary = String::from_utf8(primary).unwrap();
+        assert!(primary.contains("<name>anda-srpm-macros</name>"));
+        assert!(primary.contains(r#"<location href="anda-srpm-macros.rpm"/>"#));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&groupfile).unwrap();
+    }
+
+    /// Read the uncompressed primary.xml of a generated repo
+    fn read_primary(dir: &Path) -> String {
+        let repomd = std::fs::read_to_string(dir.join("repodata/repomd.xml")).unwrap();
+        let records = parse_repomd(&repomd).unwrap();
+        let primary = records.iter().find(|r| r.data_type == "primary").unwrap();
+        let data = std::fs::read(dir.join(&primary.location)).unwrap();
+        String::from_utf8(decompress(&primary.location, data).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_generate_cached() {
+        let dir = std::env::temp_dir().join(format!("subatomic-repodata-{}", ulid::Ulid::new()));
+        let repo_dir = dir.join("repo");
+        std::fs::create_dir_all(&repo_dir).unwrap();
+        std::fs::copy(RPM_PATH, repo_dir.join("anda-srpm-macros.rpm")).unwrap();
+
+        let options = RepodataOptions {
+            cache_dir: Some(dir.join("cache")),
+            ..Default::default()
+        };
+        generate(&repo_dir, &options).unwrap();
+        let uncached = read_primary(&repo_dir);
+
+        let checksum = file_sha256(&repo_dir.join("anda-srpm-macros.rpm")).unwrap();
+        let cache = PackageCache {
+            dir: dir.join("cache"),
+        };
+        assert!(cache.get(&checksum, "anda-srpm-macros.rpm").is_some());
+        assert!(cache.get(&checksum, "elsewhere.rpm").is_none());
+
+        // the package isn't parsed again, so even a broken file is served from the cache
+        std::fs::remove_dir_all(repo_dir.join("repodata")).unwrap();
+        std::fs::write(repo_dir.join("anda-srpm-macros.rpm"), b"not an rpm").unwrap();
+        let options = RepodataOptions {
+            checksums: HashMap::from([("anda-srpm-macros.rpm".to_owned(), checksum)]),
+            ..options
+        };
+        generate(&repo_dir, &options).unwrap();
+        assert_eq!(read_primary(&repo_dir), uncached);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_type() {
+        let entry = |mode: FileMode, flags: FileFlags| FileEntry {
+            path: "/var/log/app.log".into(),
+            mode,
+            ownership: rpm::FileOwnership {
+                user: "root".to_owned(),
+                group: "root".to_owned(),
+            },
+            modified_at: rpm::Timestamp(0),
+            size: 0,
+            flags,
+            digest: None,
+            caps: None,
+            linkto: String::new(),
+            ima_signature: None,
+        };
+        let file = FileMode::Regular { permissions: 0o644 };
+        let dir = FileMode::Dir { permissions: 0o755 };
+        assert_eq!(file_type(&entry(file, FileFlags::empty())), None);
+        assert_eq!(file_type(&entry(file, FileFlags::GHOST)), Some("ghost"));
+        assert_eq!(file_type(&entry(dir, FileFlags::empty())), Some("dir"));
+    }
+}
@@ -0,0 +1,241 @@
+//! Helpers for reading and writing yum repository metadata, APT and pacman repository indexes,
+//! and assembling ostree repos
+pub mod apt;
+pub mod comps;
+pub mod generate;
+pub mod modules;
+pub mod ostree;
+pub mod pacman;
+pub mod primary;
+pub mod updateinfo;
+
+use std::io::Read;
+
+use color_eyre::{eyre::eyre, Result};
+use quick_xml::{events::Event, Reader};
+use sha2::digest::DynDigest;
+
+/// A checksum listed in repository metadata, i.e. `<checksum type="sha256">`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    /// `md5`, `sha`/`sha1`, `sha224`, `sha256`, `sha384` or `sha512`
+    pub algorithm: String,
+    /// Hex digest
+    pub value: String,
+}
+
+impl Checksum {
+    pub fn new(algorithm: &str, value: &str) -> Self {
+        Self {
+            algorithm: algorithm.to_owned(),
+            value: value.to_owned(),
+        }
+    }
+
+    fn hasher(&self) -> Result<Box<dyn DynDigest + Send>> {
+        Ok(match self.algorithm.as_str() {
+            "md5" => Box::new(md5::Md5::default()),
+            "sha" | "sha1" => Box::new(sha1::Sha1::default()),
+            "sha224" => Box::new(sha2::Sha224::default()),
+            "sha256" => Box::new(sha2::Sha256::default()),
+            "sha384" => Box::new(sha2::Sha384::default()),
+            "sha512" => Box::new(sha2::Sha512::default()),
+            other => return Err(eyre!("unsupported checksum type {other:?}")),
+        })
+    }
+
+    fn check(&self, hasher: Box<dyn DynDigest + Send>) -> Result<()> {
+        let digest = hex::encode(hasher.finalize());
+        if !digest.eq_ignore_ascii_case(&self.value) {
+            return Err(eyre!(
+                "{} checksum mismatch, got {digest}, expected {}",
+                self.algorithm,
+                self.value
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check data against the checksum, failing on a mismatch or an unknown algorithm
+    pub fn verify(&self, data: &[u8]) -> Result<()> {
+        let mut hasher = self.hasher()?;
+        hasher.update(data);
+        self.check(hasher)
+    }
+
+    /// Check a file against the checksum, like [`Checksum::verify`]
+    pub fn verify_file(&self, path: &std::path::Path) -> Result<()> {
+        let mut hasher = self.hasher()?;
+        let mut file = std::fs::File::open(path)?;
+        let mut buf = vec![0; 1024 * 1024];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        self.check(hasher)
+    }
+}
+
+/// A single `<data>` entry from a `repomd.xml` index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepoMdRecord {
+    /// Metadata type, i.e `primary`, `group`, `group_gz`
+    pub data_type: String,
+    /// Location of the metadata file, relative to the repo root
+    pub location: String,
+    /// Checksum of the file as stored, i.e. still compressed
+    pub checksum: Option<Checksum>,
+}
+
+/// Fetch a file over HTTP(S), failing on non-success status codes
+///
+/// Only public hosts are reachable, see [`crate::remote`].
+pub async fn fetch(url: &str) -> Result<Vec<u8>> {
+    let res = crate::remote::get(url).await?;
+    Ok(res.bytes().await?.to_vec())
+}
+
+/// Join a repository base URL with a path relative to it
+pub fn join_url(base: &str, path: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), path.trim_start_matches('/'))
+}
+
+/// Parse the `<data>` records out of a `repomd.xml` document
+pub fn parse_repomd(xml: &str) -> Result<Vec<RepoMdRecord>> {
+    let mut reader = Reader::from_str(xml);
+    let mut records = Vec::new();
+    let mut current_type: Option<String> = None;
+    let mut location: Option<String> = None;
+    let mut checksum: Option<Checksum> = None;
+    // type of the `<checksum>` being read, `<open-checksum>` is of the decompressed file
+    let mut checksum_type: Option<String> = None;
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.local_name().as_ref() == b"data" => {
+                current_type = e
+                    .try_get_attribute("type")?
+                    .map(|a| a.unescape_value().map(|v| v.to_string()))
+                    .transpose()?;
+            }
+            Event::Start(e) if e.local_name().as_ref() == b"checksum" => {
+                checksum_type = e
+                    .try_get_attribute("type")?
+                    .map(|a| a.unescape_value().map(|v| v.to_string()))
+                    .transpose()?;
+            }
+            Event::Text(t) if current_type.is_some() => {
+                if let Some(algorithm) = checksum_type.take() {
+                    checksum = Some(Checksum::new(&algorithm, t.unescape()?.trim()));
+                }
+            }
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"location" => {
+                location = e
+                    .try_get_attribute("href")?
+                    .map(|a| a.unescape_value().map(|v| v.to_string()))
+                    .transpose()?;
+            }
+            Event::End(e) if e.local_name().as_ref() == b"checksum" => checksum_type = None,
+            Event::End(e) if e.local_name().as_ref() == b"data" => {
+                if let (Some(data_type), Some(location)) = (current_type.take(), location.take()) {
+                    records.push(RepoMdRecord {
+                        data_type,
+                        location,
+                        checksum: checksum.take(),
+                    });
+                }
+                checksum = None;
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(records)
+}
+
+/// Fetch and parse `repodata/repomd.xml` from a remote repository
+pub async fn fetch_repomd(base_url: &str) -> Result<Vec<RepoMdRecord>> {
+    let repomd = fetch(&join_url(base_url, "repodata/repomd.xml")).await?;
+    parse_repomd(std::str::from_utf8(&repomd)?)
+}
+
+/// Decompress a metadata file based on its file extension
+pub fn decompress(location: &str, data: Vec<u8>) -> Result<Vec<u8>> {
+    if location.ends_with(".gz") {
+        let mut out = Vec::new();
+        flate2::read::GzDecoder::new(data.as_slice()).read_to_end(&mut out)?;
+        Ok(out)
+    } else if location.ends_with(".zst") {
+        Ok(zstd::decode_all(data.as_slice())?)
+    } else if location.ends_with(".xz") || location.ends_with(".bz2") {
+        Err(eyre!("unsupported metadata compression: {location}"))
+    } else {
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPOMD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<repomd xmlns="http://linux.duke.edu/metadata/repo">
+  <revision>1</revision>
+  <data type="primary">
+    <checksum type="sha256">abc</checksum>
+    <location href="repodata/abc-primary.xml.gz"/>
+  </data>
+  <data type="group">
+    <location href="repodata/def-comps.xml"/>
+  </data>
+  <data type="filelists">
+    <checksum type="sha1">f1</checksum>
+    <open-checksum type="sha1">f2</open-checksum>
+    <location href="repodata/f1-filelists.xml.gz"/>
+  </data>
+</repomd>"#;
+
+    #[test]
+    fn test_parse_repomd() {
+        let records = parse_repomd(REPOMD).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].data_type, "primary");
+        assert_eq!(records[0].checksum, Some(Checksum::new("sha256", "abc")));
+        assert_eq!(records[1].location, "repodata/def-comps.xml");
+        assert_eq!(records[1].checksum, None);
+        // the checksum of the compressed file, not the open one
+        assert_eq!(records[2].checksum, Some(Checksum::new("sha1", "f1")));
+    }
+
+    #[test]
+    fn test_checksum() {
+        let data = b"hello";
+        for (algorithm, value) in [
+            ("md5", "5d41402abc4b2a76b9719d911017c592"),
+            ("sha", "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"),
+            ("sha1", "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"),
+            (
+                "sha256",
+                "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+            ),
+        ] {
+            let checksum = Checksum::new(algorithm, value);
+            assert!(checksum.verify(data).is_ok(), "{algorithm}");
+            assert!(checksum.verify(b"other").is_err(), "{algorithm}");
+        }
+        assert!(Checksum::new("sha512", "00").verify(data).is_err());
+        assert!(Checksum::new("crc32", "00").verify(data).is_err());
+    }
+
+    #[test]
+    fn test_join_url() {
+        assert_eq!(
+            join_url("https://example.com/repo/", "/repodata/repomd.xml"),
+            "https://example.com/repo/repodata/repomd.xml"
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! modulemd (module metadata) handling
+
+use color_eyre::{eyre::eyre, Result};
+use serde::Deserialize;
+
+/// Document types allowed in a `modules.yaml` stream
+const DOCUMENT_TYPES: &[&str] = &[
+    "modulemd",
+    "modulemd-defaults",
+    "modulemd-obsoletes",
+    "modulemd-translations",
+];
+
+/// Check that a YAML stream only contains modulemd documents, returning how many it has
+pub fn validate(yaml: &str) -> Result<usize> {
+    let mut count = 0;
+    for doc in serde_yaml::Deserializer::from_str(yaml) {
+        let value = serde_yaml::Value::deserialize(doc)?;
+        if value.is_null() {
+            continue;
+        }
+        let document = value
+            .get("document")
+            .and_then(|d| d.as_str())
+            .ok_or_else(|| eyre!("document {count} has no document type"))?;
+        if !DOCUMENT_TYPES.contains(&document) {
+            return Err(eyre!("unsupported document type: {document}"));
+        }
+        if value.get("data").is_none() {
+            return Err(eyre!("{document} document {count} has no data"));
+        }
+        count += 1;
+    }
+
+    if count == 0 {
+        return Err(eyre!("no modulemd documents found"));
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODULES: &str = r#"---
+document: modulemd
+version: 2
+data:
+  name: nodejs
+  stream: "20"
+  version: 1
+  context: abcdef
+  arch: x86_64
+  summary: Node.js runtime
+  description: Node.js runtime
+  license:
+    module: [MIT]
+...
+---
+document: modulemd-defaults
+version: 1
+data:
+  module: nodejs
+  stream: "20"
+...
+"#;
+
+    #[test]
+    fn test_validate_modules() {
+        assert_eq!(validate(MODULES).unwrap(), 2);
+        assert!(validate("").is_err());
+        assert!(validate("document: comps\ndata: {}\n").is_err());
+        assert!(validate("foo: bar\n").is_err());
+    }
+}
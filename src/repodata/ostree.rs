@@ -0,0 +1,132 @@
+//! OSTree repository assembly, with the `ostree` CLI
+//!
+//! Repos are created in `archive` mode, which is what's served over HTTP to clients.
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use color_eyre::{eyre::eyre, Result};
+
+use crate::db::ostree::OstreeArtifact;
+
+/// Run `ostree` against a repo, returning its trimmed stdout
+async fn ostree(repo: &Path, args: &[&str]) -> Result<String> {
+    let output = tokio::process::Command::new("ostree")
+        .args(args)
+        .arg(format!("--repo={}", repo.display()))
+        .output()
+        .await
+        .map_err(|e| eyre!("cannot run ostree: {e}"))?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "ostree {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Create an empty archive repo
+pub async fn init_repo(repo: &Path) -> Result<()> {
+    tokio::fs::create_dir_all(repo).await?;
+    ostree(repo, &["init", "--mode=archive"]).await?;
+    Ok(())
+}
+
+/// Unpack a tarball of an ostree repo into `dest`, gzipped or not
+pub fn unpack_tarball(tarball: &Path, dest: &Path) -> Result<()> {
+    let mut magic = [0; 2];
+    let gzipped =
+        std::fs::File::open(tarball)?.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    let reader = BufReader::new(std::fs::File::open(tarball)?);
+    let reader: Box<dyn Read> = if gzipped {
+        Box::new(flate2::read::GzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+    std::fs::create_dir_all(dest)?;
+    tar::Archive::new(reader).unpack(dest)?;
+    Ok(())
+}
+
+/// The commit a ref of an unpacked repo points at
+pub async fn resolve_ref(repo: &Path, ref_name: &str) -> Result<String> {
+    ostree(repo, &["rev-parse", ref_name]).await
+}
+
+/// Check that a repo holds a commit
+pub async fn has_commit(repo: &Path, checksum: &str) -> Result<bool> {
+    Ok(ostree(repo, &["show", checksum]).await.is_ok())
+}
+
+/// Import a commit from an uploaded tarball or static delta into a repo
+///
+/// Tarballs are unpacked next to the repo in `scratch_dir`, and the commit pulled from there.
+pub async fn import_commit(
+    repo: &Path,
+    artifact: OstreeArtifact,
+    path: &Path,
+    checksum: &str,
+    scratch_dir: &Path,
+) -> Result<()> {
+    match artifact {
+        OstreeArtifact::Tarball => {
+            let (tarball, unpacked) = (path.to_owned(), scratch_dir.to_owned());
+            tokio::task::spawn_blocking(move || unpack_tarball(&tarball, &unpacked)).await??;
+            let source = scratch_dir.to_string_lossy();
+            let res = ostree(repo, &["pull-local", &source, checksum]).await;
+            tokio::fs::remove_dir_all(scratch_dir).await.ok();
+            res?;
+        }
+        OstreeArtifact::Delta => {
+            let delta = path.to_string_lossy();
+            ostree(repo, &["static-delta", "apply-offline", &delta]).await?;
+        }
+    }
+    if !has_commit(repo, checksum).await? {
+        return Err(eyre!("{} didn't contain commit {checksum}", artifact.as_str()));
+    }
+    Ok(())
+}
+
+/// Point a ref at a commit, creating it if needed
+pub async fn set_ref(repo: &Path, ref_name: &str, checksum: &str) -> Result<()> {
+    ostree(repo, &["refs", "--force", &format!("--create={ref_name}"), checksum]).await?;
+    Ok(())
+}
+
+/// Regenerate the repo's summary file, which clients read to find its refs
+pub async fn update_summary(repo: &Path) -> Result<()> {
+    ostree(repo, &["summary", "--update"]).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unpack_tarball() {
+        let dir = std::env::temp_dir().join(format!("subatomic-ostree-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tarball = dir.join("repo.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(&tarball).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut builder = tar::Builder::new(encoder);
+        let config = b"[core]\nrepo_version=1\nmode=archive-z2\n";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(config.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "config", config.as_slice())
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let unpacked = dir.join("repo");
+        unpack_tarball(&tarball, &unpacked).unwrap();
+        assert_eq!(std::fs::read(unpacked.join("config")).unwrap(), config);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
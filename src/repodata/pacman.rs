@@ -0,0 +1,165 @@
+//! pacman repository generation, following `repo-add`
+//!
+//! Packages are published in a directory per architecture, `any` packages in each of them, next
+//! to the `<repo>.db.tar.gz` database listing them. The database is a gzipped tarball with a
+//! `<name>-<version>/desc` entry per package.
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use base64::Engine;
+use color_eyre::{eyre::eyre, Result};
+use flate2::{write::GzEncoder, Compression};
+
+use crate::db::pacman::PacmanPackage;
+
+/// Architecture of packages installable on every architecture
+pub const ARCH_ANY: &str = "any";
+
+/// File name of a repo's database, `<repo>.db` is a symlink to it
+pub fn db_file_name(repo: &str) -> String {
+    format!("{repo}.db.tar.gz")
+}
+
+/// Architectures to generate repos for: the tag's configured ones and those of its packages
+///
+/// Only `any` if there are neither, `any` packages are listed in every repo.
+pub fn architectures<'a>(
+    arches: impl IntoIterator<Item = &'a str>,
+    configured: &'a [String],
+) -> Vec<String> {
+    let arches: BTreeSet<&str> = arches
+        .into_iter()
+        .chain(configured.iter().map(String::as_str))
+        .filter(|arch| *arch != ARCH_ANY && *arch != "noarch")
+        .collect();
+    if arches.is_empty() {
+        return vec![ARCH_ANY.to_owned()];
+    }
+    arches.into_iter().map(str::to_owned).collect()
+}
+
+fn section(desc: &mut String, name: &str, values: &[impl AsRef<str>]) {
+    if values.is_empty() {
+        return;
+    }
+    desc.push_str(&format!("%{name}%\n"));
+    for value in values {
+        desc.push_str(value.as_ref());
+        desc.push('\n');
+    }
+    desc.push('\n');
+}
+
+/// A package's `desc` entry in the repo database
+pub fn desc(pkg: &PacmanPackage) -> Result<String> {
+    let sha256 = pkg
+        .sha256
+        .as_deref()
+        .ok_or_else(|| eyre!("{} has no digest", pkg.full_name()))?;
+    let signature = pkg
+        .signature_bytes()?
+        .map(|sig| base64::engine::general_purpose::STANDARD.encode(sig));
+    let installed_size = pkg.installed_size.map(|size| size.to_string());
+    let build_date = pkg.build_date.map(|date| date.to_string());
+
+    let mut desc = String::new();
+    section(&mut desc, "FILENAME", &[pkg.file_name()]);
+    section(&mut desc, "NAME", &[&pkg.name]);
+    section(&mut desc, "BASE", pkg.base.as_slice());
+    section(&mut desc, "VERSION", &[&pkg.version]);
+    section(&mut desc, "DESC", pkg.description.as_slice());
+    section(&mut desc, "GROUPS", &pkg.groups);
+    section(&mut desc, "CSIZE", &[pkg.size.to_string()]);
+    section(&mut desc, "ISIZE", installed_size.as_slice());
+    section(&mut desc, "SHA256SUM", &[sha256]);
+    section(&mut desc, "PGPSIG", signature.as_slice());
+    section(&mut desc, "URL", pkg.url.as_slice());
+    section(&mut desc, "LICENSE", &pkg.licenses);
+    section(&mut desc, "ARCH", &[&pkg.arch]);
+    section(&mut desc, "BUILDDATE", build_date.as_slice());
+    section(&mut desc, "PACKAGER", pkg.packager.as_slice());
+    section(&mut desc, "REPLACES", &pkg.replaces);
+    section(&mut desc, "CONFLICTS", &pkg.conflicts);
+    section(&mut desc, "PROVIDES", &pkg.provides);
+    section(&mut desc, "DEPENDS", &pkg.depends);
+    section(&mut desc, "OPTDEPENDS", &pkg.optdepends);
+    section(&mut desc, "MAKEDEPENDS", &pkg.makedepends);
+    section(&mut desc, "CHECKDEPENDS", &pkg.checkdepends);
+    Ok(desc)
+}
+
+/// Write a repo database listing `pkgs` to `path`
+pub fn write_db(path: &Path, pkgs: &[&PacmanPackage]) -> Result<()> {
+    let file = std::fs::File::create(path)?;
+    let mut db = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    for pkg in pkgs {
+        let dir = format!("{}-{}", pkg.name, pkg.version);
+        let mtime = pkg.build_date.unwrap_or_default().max(0) as u64;
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_mtime(mtime);
+        header.set_size(0);
+        db.append_data(&mut header, format!("{dir}/"), std::io::empty())?;
+
+        let desc = desc(pkg)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header.set_size(desc.len() as u64);
+        db.append_data(&mut header, format!("{dir}/desc"), desc.as_bytes())?;
+    }
+    db.into_inner()?.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    const PKG_PATH: &str = "test/data/subatomic-hello-1:1.0.2-1-any.pkg.tar.zst";
+
+    #[test]
+    fn test_architectures() {
+        let configured = ["x86_64".to_owned(), "noarch".to_owned()];
+        assert_eq!(architectures(["any"], &[]), vec!["any"]);
+        assert_eq!(architectures(["any"], &configured), vec!["x86_64"]);
+        assert_eq!(
+            architectures(["aarch64", "any"], &configured),
+            vec!["aarch64", "x86_64"]
+        );
+    }
+
+    #[test]
+    fn test_desc() {
+        let pkg = PacmanPackage::from_path(PKG_PATH, "foobar").unwrap();
+        let desc = desc(&pkg).unwrap();
+        assert!(desc.starts_with("%FILENAME%\nsubatomic-hello-1:1.0.2-1-any.pkg.tar.zst\n\n"));
+        assert!(desc.contains("%VERSION%\n1:1.0.2-1\n\n"));
+        assert!(desc.contains("%DEPENDS%\nglibc\nbash>=5\n\n"));
+        assert!(!desc.contains("%PGPSIG%"));
+        assert!(!desc.contains("%GROUPS%"));
+    }
+
+    #[test]
+    fn test_write_db() {
+        let dir = std::env::temp_dir().join(format!("subatomic-pacman-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pkg = PacmanPackage::from_path(PKG_PATH, "foobar").unwrap();
+        let path = dir.join(db_file_name("foobar"));
+        write_db(&path, &[&pkg]).unwrap();
+
+        let file = std::fs::File::open(&path).unwrap();
+        let mut db = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        let mut entries = db.entries().unwrap().map(|e| e.unwrap());
+        let entry = entries.next().unwrap();
+        assert!(entry.path().unwrap().starts_with("subatomic-hello-1:1.0.2-1"));
+        let mut desc = String::new();
+        entries.next().unwrap().read_to_string(&mut desc).unwrap();
+        assert!(desc.contains("%NAME%\nsubatomic-hello\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+//! Reading the package list of a remote yum repository from its `primary.xml`
+use color_eyre::{eyre::eyre, Result};
+use quick_xml::{events::Event, Reader};
+
+use super::Checksum;
+use crate::db::rpm::Nevra;
+
+/// A package listed in `primary.xml`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrimaryPackage {
+    pub nevra: Nevra,
+    /// Location of the package, relative to the repo root
+    pub location: String,
+    /// Checksum of the package file
+    pub checksum: Option<Checksum>,
+    pub size: Option<u64>,
+}
+
+impl PrimaryPackage {
+    /// Full `name-epoch:version-release.arch` of the package
+    pub fn nevra_string(&self) -> String {
+        let n = &self.nevra;
+        format!("{}-{}:{}-{}.{}", n.name, n.epoch, n.version, n.release, n.arch)
+    }
+}
+
+/// Fields of a `<package>` element, filled in as it's read
+#[derive(Default)]
+struct PartialPackage {
+    name: Option<String>,
+    arch: Option<String>,
+    evr: Option<(u32, String, String)>,
+    checksum_type: Option<String>,
+    checksum: Option<String>,
+    location: Option<String>,
+    size: Option<u64>,
+}
+
+impl PartialPackage {
+    fn finish(self) -> Result<PrimaryPackage> {
+        let name = self.name.ok_or_else(|| eyre!("package without a name"))?;
+        let missing = |field: &str| eyre!("package {name} has no {field}");
+        let arch = self.arch.ok_or_else(|| missing("arch"))?;
+        let (epoch, version, release) = self.evr.ok_or_else(|| missing("version"))?;
+        let location = self.location.ok_or_else(|| missing("location"))?;
+        let checksum = match (self.checksum_type, self.checksum) {
+            (Some(algorithm), Some(value)) => Some(Checksum { algorithm, value }),
+            (None, Some(_)) => return Err(missing("checksum type")),
+            (_, None) => None,
+        };
+        Ok(PrimaryPackage {
+            nevra: Nevra {
+                name,
+                epoch,
+                version,
+                release,
+                arch,
+            },
+            location,
+            checksum,
+            size: self.size,
+        })
+    }
+}
+
+/// Parse the packages out of a `primary.xml` document
+pub fn parse_primary(xml: &str) -> Result<Vec<PrimaryPackage>> {
+    let mut reader = Reader::from_str(xml);
+    let mut packages = Vec::new();
+    let mut current: Option<PartialPackage> = None;
+    // element whose text is being read, only direct children of `<package>` are
+    let mut text_field: Option<&'static str> = None;
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.local_name().as_ref() == b"package" => {
+                current = Some(PartialPackage::default());
+            }
+            Event::End(e) if e.local_name().as_ref() == b"package" => {
+                if let Some(pkg) = current.take() {
+                    packages.push(pkg.finish()?);
+                }
+            }
+            Event::Start(e) | Event::Empty(e) if current.is_some() => {
+                let pkg = current.as_mut().unwrap();
+                let attr = |name: &str| -> Result<Option<String>> {
+                    Ok(e.try_get_attribute(name)?
+                        .map(|a| a.unescape_value().map(|v| v.to_string()))
+                        .transpose()?)
+                };
+                match e.local_name().as_ref() {
+                    b"name" if pkg.name.is_none() => text_field = Some("name"),
+                    b"arch" if pkg.arch.is_none() => text_field = Some("arch"),
+                    b"checksum" => {
+                        pkg.checksum_type = attr("type")?;
+                        text_field = Some("checksum");
+                    }
+                    b"version" => {
+                        let epoch = attr("epoch")?
+                            .map(|epoch| epoch.parse())
+                            .transpose()
+                            .map_err(|_| eyre!("invalid epoch"))?
+                            .unwrap_or(0);
+                        let version = attr("ver")?.ok_or_else(|| eyre!("version without ver"))?;
+                        let release = attr("rel")?.ok_or_else(|| eyre!("version without rel"))?;
+                        pkg.evr = Some((epoch, version, release));
+                    }
+                    b"location" => pkg.location = attr("href")?,
+                    b"size" => pkg.size = attr("package")?.and_then(|size| size.parse().ok()),
+                    _ => {}
+                }
+            }
+            Event::Text(t) => {
+                if let (Some(field), Some(pkg)) = (text_field, current.as_mut()) {
+                    let text = t.unescape()?.trim().to_owned();
+                    match field {
+                        "name" => pkg.name = Some(text),
+                        "arch" => pkg.arch = Some(text),
+                        _ => pkg.checksum = Some(text),
+                    }
+                }
+            }
+            Event::End(_) => text_field = None,
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(packages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIMARY: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<metadata xmlns="http://linux.duke.edu/metadata/common" xmlns:rpm="http://linux.duke.edu/metadata/rpm" packages="2">
+<package type="rpm">
+  <name>anda-srpm-macros</name>
+  <arch>noarch</arch>
+  <version epoch="0" ver="0.2.6" rel="1.fc41"/>
+  <checksum type="sha256" pkgid="YES">0123abcd</checksum>
+  <summary>SRPM macros for extra Fedora packages</summary>
+  <size package="10456" installed="2048" archive="3000"/>
+  <location href="Packages/a/anda-srpm-macros-0.2.6-1.fc41.noarch.rpm"/>
+  <format>
+    <rpm:license>MIT</rpm:license>
+    <rpm:provides>
+      <rpm:entry name="anda-srpm-macros" flags="EQ" epoch="0" ver="0.2.6" rel="1.fc41"/>
+    </rpm:provides>
+  </format>
+</package>
+<package type="rpm">
+  <name>hello</name>
+  <arch>x86_64</arch>
+  <version epoch="2" ver="1.0" rel="3"/>
+  <checksum type="sha1" pkgid="YES">feed</checksum>
+  <location href="hello-1.0-3.x86_64.rpm"/>
+</package>
+</metadata>"#;
+
+    #[test]
+    fn test_parse_primary() {
+        let packages = parse_primary(PRIMARY).unwrap();
+        assert_eq!(packages.len(), 2);
+        assert_eq!(
+            packages[0].nevra_string(),
+            "anda-srpm-macros-0:0.2.6-1.fc41.noarch"
+        );
+        assert_eq!(
+            packages[0].checksum,
+            Some(Checksum::new("sha256", "0123abcd"))
+        );
+        assert_eq!(packages[0].size, Some(10456));
+        assert_eq!(
+            packages[0].location,
+            "Packages/a/anda-srpm-macros-0.2.6-1.fc41.noarch.rpm"
+        );
+        assert_eq!(packages[1].nevra.epoch, 2);
+        assert_eq!(packages[1].checksum, Some(Checksum::new("sha1", "feed")));
+    }
+}
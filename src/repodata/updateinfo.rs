@@ -0,0 +1,136 @@
+//! `updateinfo.xml` generation, publishing advisories for `dnf updateinfo`
+use color_eyre::Result;
+
+use super::generate::{as_refs, empty, end, new_writer, start, text};
+use crate::db::{advisory::Advisory, record_key};
+
+/// Render advisories as an `updateinfo.xml` document
+///
+/// Only packages in `published` are listed, so advisories don't reference packages
+/// missing from the repo.
+pub fn updateinfo_xml(advisories: &[Advisory], published: &[ulid::Ulid]) -> Result<Vec<u8>> {
+    let mut w = new_writer()?;
+    start(&mut w, "updates", &[])?;
+
+    for advisory in advisories {
+        let issued = advisory.issued.format("%Y-%m-%d %H:%M:%S").to_string();
+        let updated = advisory.updated.format("%Y-%m-%d %H:%M:%S").to_string();
+
+        start(
+            &mut w,
+            "update",
+            &[
+                ("from", "subatomic"),
+                ("status", "stable"),
+                ("type", advisory.kind.as_str()),
+                ("version", "1"),
+            ],
+        )?;
+        text(&mut w, "id", &[], &advisory.id.id.to_raw())?;
+        text(&mut w, "title", &[], &advisory.title)?;
+        empty(&mut w, "issued", &[("date", &issued)])?;
+        empty(&mut w, "updated", &[("date", &updated)])?;
+        if let Some(severity) = &advisory.severity {
+            text(&mut w, "severity", &[], severity)?;
+        }
+        text(&mut w, "description", &[], &advisory.description)?;
+
+        start(&mut w, "references", &[])?;
+        for reference in &advisory.references {
+            let mut attrs = vec![
+                ("href", reference.href.clone()),
+                ("type", reference.kind.clone()),
+            ];
+            if let Some(id) = &reference.id {
+                attrs.push(("id", id.clone()));
+            }
+            if let Some(title) = &reference.title {
+                attrs.push(("title", title.clone()));
+            }
+            empty(&mut w, "reference", &as_refs(&attrs))?;
+        }
+        end(&mut w, "references")?;
+
+        start(&mut w, "pkglist", &[])?;
+        start(&mut w, "collection", &[("short", &record_key(&advisory.tag))])?;
+        for pkg in advisory
+            .packages
+            .iter()
+            .filter(|pkg| published.contains(&pkg.id))
+        {
+            let filename = pkg.file_name();
+            let mut attrs = vec![
+                ("name", pkg.name.clone()),
+                ("epoch", pkg.epoch.to_string()),
+                ("version", pkg.version.clone()),
+                ("release", pkg.release.clone()),
+                ("arch", pkg.arch.clone()),
+            ];
+            // the source package the package was built from, left out if it isn't known
+            let src = match &pkg.source_rpm {
+                Some(source_rpm) => Some(source_rpm.clone()),
+                None if pkg.is_source => Some(format!(
+                    "{}-{}-{}.src.rpm",
+                    pkg.name, pkg.version, pkg.release
+                )),
+                None => None,
+            };
+            if let Some(src) = src {
+                attrs.push(("src", src));
+            }
+            start(&mut w, "package", &as_refs(&attrs))?;
+            text(&mut w, "filename", &[], &filename)?;
+            end(&mut w, "package")?;
+        }
+        end(&mut w, "collection")?;
+        end(&mut w, "pkglist")?;
+
+        end(&mut w, "update")?;
+    }
+
+    end(&mut w, "updates")?;
+    Ok(w.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::advisory::{AdvisoryKind, AdvisoryReference};
+    use crate::db::rpm::RpmRef;
+
+    #[test]
+    fn test_updateinfo_xml() {
+        let id = ulid::Ulid::new();
+        let mut pkg = RpmRef::new(id, "foo".to_owned(), "rpm/foo-0:1.0-1.fc41.x86_64.rpm".to_owned());
+        pkg.version = "1.0".to_owned();
+        pkg.release = "1.fc41".to_owned();
+        pkg.arch = "x86_64".to_owned();
+        pkg.source_rpm = Some("foo-1.0-1.fc41.src.rpm".to_owned());
+        let unpublished = RpmRef::new(ulid::Ulid::new(), "bar".to_owned(), "rpm/bar.rpm".to_owned());
+
+        let mut advisory = Advisory::new(
+            "FYRA-2025-0001",
+            "updates",
+            "foo security update".to_owned(),
+            AdvisoryKind::Security,
+            "Fixes a CVE".to_owned(),
+            vec![pkg, unpublished],
+        );
+        advisory.severity = Some("Important".to_owned());
+        advisory.references.push(AdvisoryReference {
+            href: "https://example.com/CVE-2025-0001".to_owned(),
+            kind: "cve".to_owned(),
+            id: Some("CVE-2025-0001".to_owned()),
+            title: None,
+        });
+
+        let xml = String::from_utf8(updateinfo_xml(&[advisory], &[id]).unwrap()).unwrap();
+        assert!(xml.contains(r#"type="security""#));
+        assert!(xml.contains("<id>FYRA-2025-0001</id>"));
+        assert!(xml.contains("<severity>Important</severity>"));
+        assert!(xml.contains(r#"id="CVE-2025-0001""#));
+        assert!(xml.contains(&format!("<filename>{id}-foo-0:1.0-1.fc41.x86_64.rpm</filename>")));
+        assert!(xml.contains(r#"src="foo-1.0-1.fc41.src.rpm""#));
+        assert!(!xml.contains(r#"name="bar""#));
+    }
+}
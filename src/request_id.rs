@@ -0,0 +1,103 @@
+//! Request IDs, to correlate the logs of a request and the jobs it starts
+//!
+//! Each request gets the ID in its `X-Request-Id` header, or a new ULID without a usable one.
+//! The request is handled in a span carrying the ID, and the ID is sent back in the response
+//! headers, and in JSON error bodies so it survives clients that only log the body.
+use axum::{
+    body::{Body, HttpBody as _},
+    extract::Request,
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tracing::Instrument;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Longest request ID accepted from clients, longer ones are replaced
+const MAX_ID_LEN: usize = 128;
+/// Largest error body the request ID is added to
+const MAX_ERROR_BODY: u64 = 64 * 1024;
+
+/// Whether a client-provided request ID can be used as is
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+/// Middleware assigning every request an ID, see the module docs
+pub async fn propagate(mut req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| is_valid_id(id))
+        .map_or_else(|| ulid::Ulid::new().to_string(), str::to_owned);
+    // only made of visible ASCII, see `is_valid_id`
+    let value = HeaderValue::from_str(&id).unwrap();
+    req.headers_mut().insert(REQUEST_ID_HEADER, value.clone());
+
+    let span = tracing::info_span!(
+        "request",
+        request_id = %id,
+        method = %req.method(),
+        uri = %req.uri(),
+    );
+    let res = next.run(req).instrument(span).await;
+    let mut res = if res.status().is_client_error() || res.status().is_server_error() {
+        with_request_id(res, &id).await
+    } else {
+        res
+    };
+    res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    res
+}
+
+/// Add the request ID to a JSON error body, leaving other bodies alone
+async fn with_request_id(res: Response, id: &str) -> Response {
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    let small = res
+        .body()
+        .size_hint()
+        .exact()
+        .is_some_and(|len| len <= MAX_ERROR_BODY);
+    if !is_json || !small {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, MAX_ERROR_BODY as usize).await else {
+        return Response::from_parts(parts, Body::empty());
+    };
+    let body = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(serde_json::Value::Object(mut error)) => {
+            error.insert("request_id".to_owned(), id.into());
+            let body = serde_json::to_vec(&error).unwrap_or_else(|_| bytes.to_vec());
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Body::from(body)
+        }
+        _ => Body::from(bytes),
+    };
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_id() {
+        assert!(is_valid_id("01JABCDEF"));
+        assert!(is_valid_id("4bf92f35-77b3-4da6-a3ce-929d0e0e4736"));
+        assert!(!is_valid_id(""));
+        assert!(!is_valid_id("a b"));
+        assert!(!is_valid_id("id\nInjected: header"));
+        assert!(!is_valid_id(&"a".repeat(MAX_ID_LEN + 1)));
+    }
+}
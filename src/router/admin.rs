@@ -0,0 +1,275 @@
+//! Administrative routes for Subatomic-NG
+use axum::{
+    extract::{Json, Path, Query},
+    http::StatusCode,
+    routing::{delete, get, post},
+    Router,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Identity;
+use crate::backup::BackupInfo;
+use crate::cache::{Cache, CacheStats};
+use crate::config::{DynamicConfig, CONFIG};
+use crate::db::{event_log::LogEvent, job::Job, rpm::Rpm, tag::Tag};
+use crate::errors::{Error, Result};
+use crate::obj_store::object_store;
+use crate::router::rpm::require_rpm_repo;
+use crate::router::tag::{require_unlocked, TagError};
+
+/// Number of packages reindexed concurrently
+const REINDEX_CONCURRENCY: usize = 8;
+
+pub fn route() -> Router {
+    Router::new().nest("/admin", route_operations())
+}
+
+fn route_operations() -> Router {
+    Router::new()
+        .route("/reindex", post(reindex))
+        .route("/resync", post(resync))
+        .route("/janitor", post(run_janitor))
+        .route("/import", post(import))
+        .route("/backups", get(list_backups).post(create_backup))
+        .route("/backups/{name}/restore", post(restore_backup))
+        .route("/cache", get(get_cache_stats))
+        .route("/cache/{*key}", delete(evict_cache_entry))
+        .route("/reload", post(reload_config))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReindexParams {
+    /// Only reindex packages in this tag
+    tag: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReindexFailure {
+    id: String,
+    error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReindexReport {
+    reindexed: usize,
+    failed: Vec<ReindexFailure>,
+}
+
+/// Re-extract metadata for stored packages, to backfill newly added fields
+pub async fn reindex(
+    identity: Identity,
+    Query(params): Query<ReindexParams>,
+) -> Result<Json<ReindexReport>> {
+    identity.require_admin()?;
+    let rpms = match &params.tag {
+        Some(tag) => Rpm::get_by_tag(tag).await?,
+        None => Rpm::get_all().await?,
+    };
+
+    tracing::info!(count = rpms.len(), tag = ?params.tag, "reindexing packages");
+
+    let results = futures::stream::iter(rpms)
+        .map(|rpm| async move {
+            let id = rpm.id.id.to_raw();
+            rpm.reindex().await.map_err(|e| ReindexFailure {
+                id,
+                error: e.to_string(),
+            })
+        })
+        .buffer_unordered(REINDEX_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut report = ReindexReport::default();
+    for result in results {
+        match result {
+            Ok(_) => report.reindexed += 1,
+            Err(failure) => {
+                tracing::warn!(id = ?failure.id, error = ?failure.error, "failed to reindex package");
+                report.failed.push(failure);
+            }
+        }
+    }
+
+    Ok(Json(report))
+}
+
+/// Start copying objects between the object store and its replica in the background
+///
+/// The job's result counts the objects copied each way, and lists those that failed.
+pub async fn resync(identity: Identity) -> Result<(StatusCode, Json<Job>)> {
+    identity.require_admin()?;
+    if CONFIG.get().unwrap().object_store_replica.is_none() {
+        return Err(Error::Conflict("no object store replica is configured".into()));
+    }
+
+    let job = Job::new("resync", None).save().await?;
+    LogEvent::new(
+        "resync",
+        identity.name(),
+        serde_json::json!({ "job": job.id.id.to_raw() }),
+    )
+    .record()
+    .await?;
+    job.clone().spawn(async move { object_store().resync().await });
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Clean up left over uploads, staging directories and cache entries
+pub async fn run_janitor(identity: Identity) -> Result<(StatusCode, Json<Job>)> {
+    identity.require_admin()?;
+    let job = Job::new("janitor", None).save().await?;
+    LogEvent::new(
+        "janitor",
+        identity.name(),
+        serde_json::json!({ "job": job.id.id.to_raw() }),
+    )
+    .record()
+    .await?;
+    job.clone()
+        .spawn(async move { crate::janitor::run(CONFIG.get().unwrap()).await });
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportRequest {
+    tag: String,
+    /// Directory to import, relative to `IMPORT_ROOT`, the whole root if unset
+    dir: Option<String>,
+    /// Files imported at the same time, at most [`crate::import::MAX_IMPORT_CONCURRENCY`]
+    concurrency: Option<usize>,
+}
+
+/// Start importing every RPM under a directory of the server into a tag in the background
+///
+/// Running an import again resumes it, skipping the files it already handled. The job reports
+/// its progress while running, and counts the files imported, skipped and failed when done.
+pub async fn import(
+    identity: Identity,
+    Json(req): Json<ImportRequest>,
+) -> Result<(StatusCode, Json<Job>)> {
+    identity.require_admin()?;
+    let tag = Tag::get(&req.tag).await?.ok_or(TagError::NotFound)?;
+    require_unlocked(&tag.name).await?;
+    require_rpm_repo(&tag)?;
+    let Some(root) = &CONFIG.get().unwrap().import_root else {
+        return Err(Error::Conflict("no import root is configured".into()));
+    };
+    let dir = crate::import::resolve_dir(root, req.dir.as_deref())
+        .map_err(|e| Error::Conflict(e.to_string()))?;
+
+    let job = Job::new("import", Some(&tag.name)).save().await?;
+    LogEvent::new(
+        "import",
+        identity.name(),
+        serde_json::json!({ "job": job.id.id.to_raw(), "dir": dir }),
+    )
+    .tag(&tag.name)
+    .record()
+    .await?;
+    let concurrency = req
+        .concurrency
+        .unwrap_or(crate::import::IMPORT_CONCURRENCY)
+        .clamp(1, crate::import::MAX_IMPORT_CONCURRENCY);
+    let id = job.id.clone();
+    job.clone().spawn(crate::import::run(id, tag.name, dir, identity.name(), concurrency));
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// The configured backup directory, backups are disabled without one
+fn backup_dir() -> Result<std::path::PathBuf> {
+    CONFIG
+        .get()
+        .unwrap()
+        .backup_dir
+        .clone()
+        .ok_or_else(|| Error::Conflict("no backup directory is configured".into()))
+}
+
+/// Every finished backup, oldest first
+pub async fn list_backups(identity: Identity) -> Result<Json<Vec<BackupInfo>>> {
+    identity.require_admin()?;
+    Ok(Json(crate::backup::list(&backup_dir()?).await?))
+}
+
+/// Start backing up the database and the list of stored objects in the background
+///
+/// The job's result names the backup and counts what it holds.
+pub async fn create_backup(identity: Identity) -> Result<(StatusCode, Json<Job>)> {
+    identity.require_admin()?;
+    let dir = backup_dir()?;
+    let job = Job::new("backup", None).save().await?;
+    LogEvent::new(
+        "backup",
+        identity.name(),
+        serde_json::json!({ "job": job.id.id.to_raw() }),
+    )
+    .record()
+    .await?;
+    job.clone().spawn(crate::backup::create(dir));
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Start restoring a backup into the empty database in the background
+///
+/// The job's result lists the objects of the backup missing from the object store.
+pub async fn restore_backup(
+    identity: Identity,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, Json<Job>)> {
+    identity.require_admin()?;
+    let dir = backup_dir()?;
+    if !crate::backup::is_valid_name(&name) || !dir.join(&name).is_dir() {
+        return Err(Error::NotFound);
+    }
+    let manifest = crate::backup::read_manifest(&dir, &name).await?;
+    crate::backup::check_restorable(&manifest)
+        .await
+        .map_err(|e| Error::Conflict(e.to_string()))?;
+
+    let job = Job::new("restore", None).save().await?;
+    LogEvent::new(
+        "restore",
+        identity.name(),
+        serde_json::json!({ "job": job.id.id.to_raw(), "backup": name }),
+    )
+    .record()
+    .await?;
+    job.clone().spawn(crate::backup::restore(dir, name));
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Statistics of the local object cache
+pub async fn get_cache_stats(identity: Identity) -> Result<Json<CacheStats>> {
+    identity.require_admin()?;
+    Ok(Json(object_store().cache.stats().await?))
+}
+
+/// Evict an object from the local cache, it's downloaded again on its next use
+pub async fn evict_cache_entry(identity: Identity, Path(key): Path<String>) -> Result<StatusCode> {
+    identity.require_admin()?;
+    let cache = &object_store().cache;
+    if !Cache::is_valid_key(&key) || cache.get(&key).is_none() {
+        return Err(Error::NotFound);
+    }
+
+    cache.remove(&key).await?;
+    LogEvent::new("evict", identity.name(), serde_json::json!({ "key": key }))
+        .record()
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+/// Read the config file and environment again, applying the options that can change at runtime
+///
+/// Responds with the options now in effect. An invalid config is rejected, keeping the current one.
+pub async fn reload_config(identity: Identity) -> Result<Json<DynamicConfig>> {
+    identity.require_admin()?;
+    let dynamic = crate::config::reload()
+        .map_err(|e| Error::BadRequest(format!("invalid configuration: {e}")))?;
+    LogEvent::new("reload", identity.name(), serde_json::json!({}))
+        .record()
+        .await?;
+    Ok(Json(DynamicConfig::clone(&dynamic)))
+}
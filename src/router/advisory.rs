@@ -0,0 +1,105 @@
+//! Advisory (errata) routes, published in each tag's `updateinfo.xml`
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    response::Json,
+    routing::{delete, get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Identity;
+use crate::db::advisory::{Advisory, AdvisoryKind, AdvisoryReference};
+use crate::db::permission::Role;
+use crate::db::record_key;
+use crate::db::rpm::{Rpm, RpmRef};
+use crate::db::tag::Tag;
+use crate::errors::{Error, Result};
+use crate::router::tag::TagError;
+
+pub fn route() -> Router {
+    Router::new()
+        .route("/repo/{id}/advisories", get(get_tag_advisories))
+        .route("/repo/{id}/advisories", post(create_advisory))
+        .route("/advisory/{id}", get(get_advisory))
+        .route("/advisory/{id}", delete(delete_advisory))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateAdvisory {
+    /// The advisory ID, i.e `FYRA-2025-0001`
+    id: String,
+    title: String,
+    #[serde(rename = "type")]
+    kind: AdvisoryKind,
+    #[serde(default)]
+    severity: Option<String>,
+    description: String,
+    #[serde(default)]
+    references: Vec<AdvisoryReference>,
+    /// IDs of the packages fixing the issue, which must belong to the tag
+    packages: Vec<ulid::Ulid>,
+}
+
+pub async fn get_tag_advisories(Path(tag_id): Path<String>) -> Result<Json<Vec<Advisory>>> {
+    Tag::get(&tag_id).await?.ok_or(TagError::NotFound)?;
+    Ok(Json(Advisory::get_by_tag(&tag_id).await?))
+}
+
+/// Create or replace an advisory for packages of a tag
+///
+/// The advisory is published with the tag's next assembly.
+pub async fn create_advisory(
+    identity: Identity,
+    Path(tag_id): Path<String>,
+    Json(req): Json<CreateAdvisory>,
+) -> Result<(StatusCode, Json<Advisory>)> {
+    identity.require(Role::Upload, &tag_id).await?;
+    Tag::get(&tag_id).await?.ok_or(TagError::NotFound)?;
+
+    let mut packages = Vec::with_capacity(req.packages.len());
+    for id in req.packages {
+        let rpm = Rpm::get(id).await?.ok_or(Error::NotFound)?;
+        if record_key(&rpm.tag) != tag_id {
+            return Err(Error::Conflict(format!(
+                "package {id} does not belong to tag {tag_id}"
+            )));
+        }
+        packages.push(RpmRef::from(&rpm));
+    }
+
+    let existing = Advisory::get(&req.id).await?;
+    if existing.as_ref().is_some_and(|a| record_key(&a.tag) != tag_id) {
+        return Err(Error::Conflict(format!(
+            "advisory {} belongs to another tag",
+            req.id
+        )));
+    }
+
+    let mut advisory = Advisory::new(
+        &req.id,
+        &tag_id,
+        req.title,
+        req.kind,
+        req.description,
+        packages,
+    );
+    advisory.severity = req.severity;
+    advisory.references = req.references;
+    if let Some(existing) = existing {
+        advisory.issued = existing.issued;
+    }
+
+    Ok((StatusCode::CREATED, Json(advisory.save().await?)))
+}
+
+pub async fn get_advisory(Path(id): Path<String>) -> Result<Json<Advisory>> {
+    Ok(Json(Advisory::get(&id).await?.ok_or(Error::NotFound)?))
+}
+
+pub async fn delete_advisory(identity: Identity, Path(id): Path<String>) -> Result<StatusCode> {
+    let advisory = Advisory::get(&id).await?.ok_or(Error::NotFound)?;
+    identity.require(Role::Upload, &record_key(&advisory.tag)).await?;
+    advisory.delete().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
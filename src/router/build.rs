@@ -0,0 +1,189 @@
+//! Build routes for Subatomic-NG
+//!
+//! A build groups an SRPM, its binary subpackages and build logs, uploaded in one session.
+//! Promoting, signing and deleting a build acts on all of its packages at once.
+use axum::{
+    extract::{Json, Multipart, Path, Query},
+    http::StatusCode,
+    routing::{delete, get, post, put},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+
+use super::rpm::StoredUpload;
+use crate::auth::Identity;
+use crate::db::{
+    build::Build, generic::normalize_path, permission::Role, record_key, rpm::Rpm, tag::Tag,
+};
+use crate::errors::{Error, Result};
+use crate::router::tag::{require_unlocked, TagError};
+
+pub fn route() -> Router {
+    Router::new()
+        .route("/builds", get(get_all_builds))
+        .nest("/build", route_operations())
+}
+
+fn route_operations() -> Router {
+    Router::new()
+        .route("/", post(create_build))
+        .route("/{id}", get(get_build))
+        .route("/{id}", delete(delete_build))
+        .route("/{id}/rpms", get(get_build_rpms))
+        .route("/{id}/upload", put(upload_to_build))
+        .route("/{id}/available", post(mark_build_available))
+        .route("/{id}/sign", post(sign_build))
+        .route("/{id}/promote", post(promote_build))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateBuild {
+    tag: String,
+    /// Name-version-release of the build, taken from the first package if unset
+    #[serde(default)]
+    nvr: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromoteBuild {
+    /// Tag to move the build to
+    tag: String,
+    /// Mark the build's packages as available in the new tag
+    #[serde(default)]
+    available: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarkAvailableParams {
+    #[serde(default)]
+    force: bool,
+}
+
+async fn find_build(id: &str) -> Result<Build> {
+    Build::get(id).await?.ok_or(Error::NotFound)
+}
+
+pub async fn get_all_builds() -> Result<Json<Vec<Build>>> {
+    Ok(Json(Build::get_all().await?))
+}
+
+pub async fn create_build(
+    identity: Identity,
+    Json(req): Json<CreateBuild>,
+) -> Result<(StatusCode, Json<Build>)> {
+    identity.require(Role::Upload, &req.tag).await?;
+    Tag::get(&req.tag).await?.ok_or(TagError::NotFound)?;
+    require_unlocked(&req.tag).await?;
+    let build = Build::new(&req.tag, req.nvr).save().await?;
+    Ok((StatusCode::CREATED, Json(build)))
+}
+
+pub async fn get_build(Path(id): Path<String>) -> Result<Json<Build>> {
+    Ok(Json(find_build(&id).await?))
+}
+
+pub async fn get_build_rpms(Path(id): Path<String>) -> Result<Json<Vec<Rpm>>> {
+    Ok(Json(find_build(&id).await?.packages().await?))
+}
+
+/// Upload a package or log file to a build
+///
+/// Every `file_upload` field is added to the build, `.rpm` files as packages
+/// and anything else as a log.
+pub async fn upload_to_build(
+    identity: Identity,
+    Path(id): Path<String>,
+    mut multipart: Multipart,
+) -> Result<Json<Build>> {
+    let mut build = find_build(&id).await?;
+    let tag = record_key(&build.tag);
+    identity.require(Role::Upload, &tag).await?;
+    require_unlocked(&tag).await?;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| color_eyre::eyre::eyre!(e))?
+    {
+        if field.name() != Some("file_upload") {
+            continue;
+        }
+        let Some(filename) = field.file_name().map(|f| f.to_string()) else {
+            continue;
+        };
+        let data = field
+            .bytes()
+            .await
+            .map_err(|e| color_eyre::eyre::eyre!(e))?;
+
+        if filename.ends_with(".rpm") {
+            match super::rpm::store_upload(&filename, &data, &tag).await? {
+                StoredUpload::New(rpm) => {
+                    build.add_package(rpm).await?;
+                }
+                StoredUpload::Duplicate(rpm) => {
+                    tracing::info!(
+                        ?filename,
+                        existing = ?rpm.id,
+                        "package already uploaded, not adding to build"
+                    );
+                }
+            }
+        } else {
+            normalize_path(&filename).map_err(|e| TagError::InvalidFilePath(e.to_string()))?;
+            build = build.add_log(&filename, data.to_vec()).await?;
+        }
+    }
+
+    Ok(Json(build))
+}
+
+pub async fn mark_build_available(
+    identity: Identity,
+    Path(id): Path<String>,
+    Query(params): Query<MarkAvailableParams>,
+) -> Result<Json<Vec<Rpm>>> {
+    let build = find_build(&id).await?;
+    identity
+        .require(Role::Upload, &record_key(&build.tag))
+        .await?;
+    require_unlocked(&record_key(&build.tag)).await?;
+    Ok(Json(build.mark_available(params.force).await?))
+}
+
+pub async fn sign_build(identity: Identity, Path(id): Path<String>) -> Result<Json<Vec<Rpm>>> {
+    let build = find_build(&id).await?;
+    identity
+        .require(Role::Sign, &record_key(&build.tag))
+        .await?;
+    Ok(Json(build.sign().await?))
+}
+
+pub async fn promote_build(
+    identity: Identity,
+    Path(id): Path<String>,
+    Json(req): Json<PromoteBuild>,
+) -> Result<Json<Build>> {
+    let build = find_build(&id).await?;
+    identity
+        .require(Role::Upload, &record_key(&build.tag))
+        .await?;
+    identity.require(Role::Upload, &req.tag).await?;
+    require_unlocked(&record_key(&build.tag)).await?;
+    require_unlocked(&req.tag).await?;
+    let build = build.promote(&req.tag).await?;
+    if req.available {
+        build.mark_available(false).await?;
+    }
+    Ok(Json(build))
+}
+
+pub async fn delete_build(identity: Identity, Path(id): Path<String>) -> Result<StatusCode> {
+    let build = find_build(&id).await?;
+    identity
+        .require(Role::Admin, &record_key(&build.tag))
+        .await?;
+    require_unlocked(&record_key(&build.tag)).await?;
+    build.delete().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,101 @@
+//! Compose routes for Subatomic-NG
+//!
+//! A compose is a single assembly of a tag, optionally with artifacts
+//! such as installer images built from the composed repo.
+use axum::{
+    extract::{Path, Query},
+    http::HeaderName,
+    response::{Json, Response},
+    routing::get,
+    Router,
+};
+use serde::Serialize;
+
+use super::{page_headers, PageParams};
+use crate::db::{
+    record_key,
+    tag::{ComposeArtifact, Tag, TagCompose},
+};
+use crate::errors::{Error, Result};
+use crate::router::tag::TagError;
+
+pub fn route() -> Router {
+    Router::new()
+        .route("/repo/{id}/composes", get(get_tag_composes))
+        .nest("/compose", route_operations())
+}
+
+fn route_operations() -> Router {
+    Router::new()
+        .route("/{id}", get(get_compose))
+        .route("/{id}/artifacts", get(get_compose_artifacts))
+        .route("/{id}/artifacts/{*name}", get(download_compose_artifact))
+}
+
+/// A compose, along with whether it's the one currently published for its tag
+#[derive(Debug, Clone, Serialize)]
+pub struct ComposeStatus {
+    #[serde(flatten)]
+    pub compose: TagCompose,
+    pub published: bool,
+}
+
+impl ComposeStatus {
+    fn new(compose: TagCompose, published: Option<&str>) -> Self {
+        let published = published == Some(compose.id.id.to_raw().as_str());
+        Self { compose, published }
+    }
+}
+
+/// List the composes of a tag, newest first
+pub async fn get_tag_composes(
+    Path(tag_id): Path<String>,
+    Query(page): Query<PageParams>,
+) -> Result<([(HeaderName, String); 1], Json<Vec<ComposeStatus>>)> {
+    let tag = Tag::get(&tag_id).await?.ok_or(TagError::NotFound)?;
+    let published = tag.published_compose();
+    let (composes, total) = TagCompose::get_page(&tag.name, page.limit(), page.offset()).await?;
+    Ok((
+        page_headers(total),
+        Json(
+            composes
+                .into_iter()
+                .map(|c| ComposeStatus::new(c, published.as_deref()))
+                .collect(),
+        ),
+    ))
+}
+
+pub async fn get_compose(Path(compose_id): Path<String>) -> Result<Json<ComposeStatus>> {
+    let compose = TagCompose::get(&compose_id).await?.ok_or(Error::NotFound)?;
+    let published = Tag::get(&record_key(&compose.tag))
+        .await?
+        .and_then(|tag| tag.published_compose());
+    Ok(Json(ComposeStatus::new(compose, published.as_deref())))
+}
+
+pub async fn get_compose_artifacts(
+    Path(compose_id): Path<String>,
+) -> Result<Json<Vec<ComposeArtifact>>> {
+    let compose = TagCompose::get(&compose_id)
+        .await?
+        .ok_or(Error::NotFound)?;
+    Ok(Json(compose.artifacts))
+}
+
+pub async fn download_compose_artifact(
+    Path((compose_id, name)): Path<(String, String)>,
+) -> Result<Response> {
+    let compose = TagCompose::get(&compose_id)
+        .await?
+        .ok_or(Error::NotFound)?;
+    let artifact = compose
+        .artifacts
+        .iter()
+        .find(|a| a.name == name)
+        .ok_or(Error::NotFound)?;
+
+    let filename = name.rsplit('/').next().unwrap_or(&name);
+
+    super::serve_object(&artifact.object_key, filename, "application/octet-stream").await
+}
@@ -0,0 +1,199 @@
+//! Debian package routes
+//!
+//! Debian packages can be uploaded to `deb` tags, or alongside the RPMs of an `rpm` tag.
+use axum::{
+    body::Body,
+    extract::{Json, Path, Query},
+    http::{HeaderMap, HeaderName, StatusCode},
+    response::Response,
+    routing::{delete, get, post, put},
+    Router,
+};
+use serde::Deserialize;
+use ulid::Ulid;
+
+use super::{content_length, page_headers, rpm::stream_to_file, PageParams};
+use crate::auth::Identity;
+use crate::config::CONFIG;
+use crate::db::{
+    deb::{Deb, DebFilter, DebRef, DEB_CONTENT_TYPE},
+    event_log::LogEvent,
+    permission::Role,
+    record_key,
+    tag::Tag,
+};
+use crate::disk::check_upload;
+use crate::errors::{Error, Result};
+use crate::notify::{notify, Notification, NotificationKind};
+use crate::obj_store::object_store;
+use crate::router::tag::{require_unlocked, TagError};
+
+pub fn route() -> Router {
+    Router::new()
+        .route("/debs", get(get_all_debs))
+        .nest("/deb", route_operations())
+}
+
+fn route_operations() -> Router {
+    Router::new()
+        .route("/{ulid}", get(get_deb))
+        .route("/{ulid}", delete(delete_deb))
+        .route("/{ulid}/available", post(mark_deb_available))
+        .route("/{ulid}/available", delete(mark_deb_unavailable))
+        .route("/{ulid}/download", get(download_deb))
+        .route("/upload/raw", put(upload_deb_raw))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DebUploadParams {
+    tag: String,
+    #[serde(default)]
+    prune: bool,
+    /// Mark the upload as latest even if a newer version is already available
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarkAvailableParams {
+    /// Mark the package as available even if a newer version is already available
+    #[serde(default)]
+    force: bool,
+}
+
+/// List Debian packages, optionally filtered by name, version, architecture and tag
+pub async fn get_all_debs(
+    Query(page): Query<PageParams>,
+    Query(filter): Query<DebFilter>,
+) -> Result<([(HeaderName, String); 1], Json<Vec<DebRef>>)> {
+    let (debs, total) = Deb::get_page(&filter, page.limit(), page.offset()).await?;
+    Ok((
+        page_headers(total),
+        Json(debs.iter().map(DebRef::from).collect()),
+    ))
+}
+
+pub async fn get_deb(Path(pkg_id): Path<Ulid>) -> Result<Json<Deb>> {
+    Ok(Json(Deb::get(pkg_id).await?.ok_or(Error::NotFound)?))
+}
+
+/// Download the package file
+pub async fn download_deb(Path(pkg_id): Path<Ulid>) -> Result<Response> {
+    let deb = Deb::get(pkg_id).await?.ok_or(Error::NotFound)?;
+    super::serve_object(&deb.object_key, deb.file_name(), DEB_CONTENT_TYPE).await
+}
+
+pub async fn mark_deb_available(
+    identity: Identity,
+    Path(pkg_id): Path<Ulid>,
+    Query(params): Query<MarkAvailableParams>,
+) -> Result<StatusCode> {
+    let deb = Deb::get(pkg_id).await?.ok_or(Error::NotFound)?;
+    let tag = record_key(&deb.tag);
+    identity.require(Role::Upload, &tag).await?;
+    require_unlocked(&tag).await?;
+    if !params.force {
+        if let Some(newer) = deb.newer_sibling().await? {
+            return Err(Error::Conflict(format!(
+                "newer version {} is already available",
+                newer.version
+            )));
+        }
+    }
+    deb.mark_available(params.force).await?;
+    LogEvent::for_deb("available", identity.name(), &deb, &tag)
+        .record()
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn mark_deb_unavailable(
+    identity: Identity,
+    Path(pkg_id): Path<Ulid>,
+) -> Result<StatusCode> {
+    let deb = Deb::get(pkg_id).await?.ok_or(Error::NotFound)?;
+    let tag = record_key(&deb.tag);
+    identity.require(Role::Upload, &tag).await?;
+    require_unlocked(&tag).await?;
+    deb.mark_unavailable().await?;
+    LogEvent::for_deb("unavailable", identity.name(), &deb, &tag)
+        .record()
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn delete_deb(identity: Identity, Path(pkg_id): Path<Ulid>) -> Result<StatusCode> {
+    let deb = Deb::get(pkg_id).await?.ok_or(Error::NotFound)?;
+    let tag = record_key(&deb.tag);
+    identity.require(Role::Admin, &tag).await?;
+    require_unlocked(&tag).await?;
+    deb.delete().await?;
+    LogEvent::for_deb("delete", identity.name(), &deb, &tag)
+        .record()
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+/// Parse a Debian package staged in the cache dir and push it to the object store
+///
+/// If the same file is already in the tag, the staged file is dropped and the existing
+/// package is returned with `false`.
+async fn store_upload(dest: &std::path::PathBuf, tag: &str) -> Result<(Deb, bool)> {
+    let deb = Deb::from_path(dest, tag)?;
+    if let Some(existing) = deb.find_duplicate().await? {
+        tracing::info!(existing = ?existing.id, "package was already uploaded, skipping");
+        let _ = tokio::fs::remove_file(dest).await;
+        return Ok((existing, false));
+    }
+    object_store().put(&deb.object_key, dest).await?;
+    Ok((deb, true))
+}
+
+/// Upload a Debian package sent as the raw request body
+pub async fn upload_deb_raw(
+    identity: Identity,
+    Query(params): Query<DebUploadParams>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<DebRef>> {
+    identity.require(Role::Upload, &params.tag).await?;
+    let tag = Tag::get(&params.tag).await?.ok_or(TagError::NotFound)?;
+    if !tag.repo_type.accepts_debs() {
+        return Err(Error::Conflict(format!(
+            "{} only holds {} packages",
+            tag.name,
+            tag.repo_type.as_str()
+        )));
+    }
+    require_unlocked(&params.tag).await?;
+    let cache_dir = &CONFIG.get().unwrap().cache_dir;
+    check_upload(cache_dir, content_length(&headers))?;
+
+    let dest = cache_dir.join(format!("{}.deb", Ulid::new()));
+    let staged = match stream_to_file(body, &dest).await {
+        Ok(()) => store_upload(&dest, &params.tag).await,
+        Err(e) => Err(e),
+    };
+    let (deb, new) = match staged {
+        Ok(staged) => staged,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&dest).await;
+            return Err(e);
+        }
+    };
+    if !new {
+        return Ok(Json(DebRef::from(&deb)));
+    }
+
+    let deb = deb.commit_to_db(params.prune, params.force).await?;
+    notify(Notification::new(
+        NotificationKind::Upload,
+        Some(params.tag.as_str()),
+        format!("uploaded {}", deb.full_name()),
+    ));
+    LogEvent::for_deb("upload", identity.name(), &deb, &params.tag)
+        .record()
+        .await?;
+
+    Ok(Json(DebRef::from(&deb)))
+}
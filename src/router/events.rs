@@ -0,0 +1,87 @@
+//! Event log routes
+use axum::{
+    body::Body,
+    extract::Query,
+    http::{header, HeaderName},
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use futures::{StreamExt, TryStreamExt};
+use serde::Deserialize;
+
+use super::{page_headers, PageParams};
+use crate::auth::Identity;
+use crate::db::event_log::{EventFilter, LogEvent, CSV_HEADER};
+use crate::errors::Result;
+
+pub fn route() -> Router {
+    Router::new()
+        .route("/events", get(get_events))
+        .route("/audit", get(get_audit))
+}
+
+/// List recorded events, newest first, optionally filtered by type, tag and time range
+pub async fn get_events(
+    Query(page): Query<PageParams>,
+    Query(filter): Query<EventFilter>,
+) -> Result<([(HeaderName, String); 1], Json<Vec<LogEvent>>)> {
+    let (events, total) = LogEvent::get_page(&filter, false, page.limit(), page.offset()).await?;
+    Ok((page_headers(total), Json(events)))
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditParams {
+    #[serde(default)]
+    format: AuditFormat,
+}
+
+/// Audit trail of the event log, in the order events happened, as JSON or a CSV export
+///
+/// Filtered like [`get_events`], plus by `actor` and `resource`, i.e. every action taken on a
+/// package with `?resource=<ulid>`. The CSV export isn't paginated, it streams every matching
+/// event.
+pub async fn get_audit(
+    identity: Identity,
+    Query(page): Query<PageParams>,
+    Query(filter): Query<EventFilter>,
+    Query(params): Query<AuditParams>,
+) -> Result<Response> {
+    identity.require_admin()?;
+
+    Ok(match params.format {
+        AuditFormat::Json => {
+            let (events, total) =
+                LogEvent::get_page(&filter, true, page.limit(), page.offset()).await?;
+            (page_headers(total), Json(events)).into_response()
+        }
+        AuditFormat::Csv => {
+            let rows = LogEvent::stream(filter)
+                .map_ok(|events| LogEvent::to_csv_rows(&events))
+                .map_err(|e| {
+                    tracing::error!(?e, "failed to export audit log");
+                    std::io::Error::other("failed to export audit log")
+                });
+            let csv = futures::stream::once(async { Ok(CSV_HEADER.to_owned()) }).chain(rows);
+            (
+                [
+                    (header::CONTENT_TYPE, "text/csv"),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        "attachment; filename=\"audit.csv\"",
+                    ),
+                ],
+                Body::from_stream(csv),
+            )
+                .into_response()
+        }
+    })
+}
@@ -0,0 +1,138 @@
+//! Generic file routes, for anything that isn't a package
+use axum::{
+    body::Body,
+    extract::{Json, Path, Query},
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    response::Response,
+    routing::{delete, get, put},
+    Router,
+};
+use serde::Deserialize;
+use ulid::Ulid;
+
+use super::{content_length, page_headers, rpm::stream_to_file, PageParams};
+use crate::auth::Identity;
+use crate::config::CONFIG;
+use crate::db::{
+    event_log::LogEvent,
+    generic::{normalize_path, GenericFile, GenericFileFilter, GenericFileRef},
+    permission::Role,
+    record_key,
+    tag::Tag,
+};
+use crate::disk::check_upload;
+use crate::errors::{Error, Result};
+use crate::notify::{notify, Notification, NotificationKind};
+use crate::obj_store::object_store;
+use crate::router::tag::{require_unlocked, TagError};
+
+pub fn route() -> Router {
+    Router::new()
+        .route("/files", get(get_all_files))
+        .nest("/file", route_operations())
+}
+
+fn route_operations() -> Router {
+    Router::new()
+        .route("/{ulid}", get(get_file))
+        .route("/{ulid}", delete(delete_file))
+        .route("/{ulid}/download", get(download_file))
+        .route("/upload/raw", put(upload_file_raw))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FileUploadParams {
+    tag: String,
+    /// Path of the file in the tag's `files/` directory
+    path: String,
+}
+
+/// List files, optionally filtered by path, tag and availability
+pub async fn get_all_files(
+    Query(page): Query<PageParams>,
+    Query(filter): Query<GenericFileFilter>,
+) -> Result<([(HeaderName, String); 1], Json<Vec<GenericFileRef>>)> {
+    let (files, total) = GenericFile::get_page(&filter, page.limit(), page.offset()).await?;
+    Ok((
+        page_headers(total),
+        Json(files.iter().map(GenericFileRef::from).collect()),
+    ))
+}
+
+pub async fn get_file(Path(file_id): Path<Ulid>) -> Result<Json<GenericFile>> {
+    Ok(Json(GenericFile::get(file_id).await?.ok_or(Error::NotFound)?))
+}
+
+/// Download the file, with the content type it was uploaded with
+pub async fn download_file(Path(file_id): Path<Ulid>) -> Result<Response> {
+    let file = GenericFile::get(file_id).await?.ok_or(Error::NotFound)?;
+    super::serve_object(&file.object_key, file.file_name(), &file.content_type).await
+}
+
+pub async fn delete_file(identity: Identity, Path(file_id): Path<Ulid>) -> Result<StatusCode> {
+    let file = GenericFile::get(file_id).await?.ok_or(Error::NotFound)?;
+    let tag = record_key(&file.tag);
+    identity.require(Role::Admin, &tag).await?;
+    require_unlocked(&tag).await?;
+    file.delete().await?;
+    LogEvent::for_file("delete", identity.name(), &file, &tag)
+        .record()
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+/// Upload a file sent as the raw request body to a path of a tag, replacing the file there
+///
+/// The request's `Content-Type` is kept, and used when the file is downloaded.
+pub async fn upload_file_raw(
+    identity: Identity,
+    Query(params): Query<FileUploadParams>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<GenericFileRef>> {
+    identity.require(Role::Upload, &params.tag).await?;
+    Tag::get(&params.tag).await?.ok_or(TagError::NotFound)?;
+    require_unlocked(&params.tag).await?;
+    normalize_path(&params.path).map_err(|e| TagError::InvalidFilePath(e.to_string()))?;
+    let cache_dir = &CONFIG.get().unwrap().cache_dir;
+    check_upload(cache_dir, content_length(&headers))?;
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+
+    let dest = cache_dir.join(format!("{}.file", Ulid::new()));
+    let staged = match stream_to_file(body, &dest).await {
+        Ok(()) => GenericFile::from_path(&dest, &params.path, content_type, &params.tag)
+            .map_err(Error::from),
+        Err(e) => Err(e),
+    };
+    let file = match staged {
+        Ok(file) => file,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&dest).await;
+            return Err(e);
+        }
+    };
+    if let Some(existing) = file.find_duplicate().await? {
+        tracing::info!(existing = ?existing.id, "file was already uploaded, skipping");
+        let _ = tokio::fs::remove_file(&dest).await;
+        return Ok(Json(GenericFileRef::from(&existing)));
+    }
+    if let Err(e) = object_store().put(&file.object_key, &dest).await {
+        let _ = tokio::fs::remove_file(&dest).await;
+        return Err(e.into());
+    }
+
+    let file = file.commit_to_db().await?;
+    notify(Notification::new(
+        NotificationKind::Upload,
+        Some(params.tag.as_str()),
+        format!("uploaded {}", file.path),
+    ));
+    LogEvent::for_file("upload", identity.name(), &file, &params.tag)
+        .record()
+        .await?;
+
+    Ok(Json(GenericFileRef::from(&file)))
+}
@@ -4,16 +4,21 @@
 
 use axum::{
     extract::{Multipart, Path},
-    http::StatusCode,
-    response::Json,
+    http::{header, StatusCode},
+    response::{IntoResponse, Json},
     routing::{delete, get, post, put},
     Router,
 };
 
 use crate::{config::CONFIG, db::gpg_key};
-use crate::errors::Result;
-use crate::db::gpg_key::GpgKeyRef;
+use crate::auth::Identity;
+use crate::errors::{Error, Result};
+use crate::db::event_log::LogEvent;
+use crate::db::gpg_key::{GpgKeyRef, KeyAlgorithm, KeyOptions, SignerBackend, GPG_KEY_TABLE};
+use crate::db::job::Job;
+use crate::db::tag::{SignResult, Tag};
 use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
 
 pub fn route() -> Router {
     Router::new()
@@ -24,6 +29,10 @@ pub fn route() -> Router {
 fn route_operations() -> Router {
     Router::new()
         .route("/", post(create_key))
+        .route("/external", post(create_external_key))
+        .route("/{id}/public", get(get_public_key))
+        .route("/{id}/rotate", post(rotate_key))
+        .route("/{id}/tags", put(set_allowed_tags))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,16 +44,254 @@ pub struct CreateGpgKey {
     /// Optional description of the key
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    /// Key algorithm and expiry, an Ed25519 key that never expires by default
+    #[serde(flatten)]
+    pub options: KeyOptions,
+    /// Tags allowed to be signed with the key, any tag if empty
+    #[serde(default)]
+    pub allowed_tags: Vec<String>,
 }
 
+/// A key whose secret key is kept outside of Subatomic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateExternalGpgKey {
+    /// The ID of the key in the keyring
+    pub id: String,
+    /// The user ID of the key, i.e `John Doe <john@example.com>`
+    pub user_id: String,
+    /// Optional description of the key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Armored public key, served to clients verifying signatures, its algorithm is the key's
+    pub public_key: String,
+    /// How signatures are made with the secret key
+    pub backend: SignerBackend,
+    /// Tags allowed to be signed with the key, any tag if empty
+    #[serde(default)]
+    pub allowed_tags: Vec<String>,
+}
+
+/// Replacement of a key, see [`rotate_key`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RotateGpgKey {
+    /// The ID of the new key in the keyring
+    pub id: String,
+    /// Algorithm of the new key, the old key's by default
+    #[serde(default)]
+    pub algorithm: Option<KeyAlgorithm>,
+    /// Days until the new key expires, never if unset
+    #[serde(default)]
+    pub expires_in_days: Option<NonZeroU32>,
+}
+
+/// Outcome of moving one tag to a rotated key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RotatedTag {
+    pub tag: String,
+    /// Each re-signed package, with an error for those that failed to sign
+    pub signed: Vec<SignResult>,
+    /// Why the tag couldn't be re-signed or re-assembled, unset if it was
+    pub error: Option<String>,
+}
+
+
+/// Fail if the ID is taken by a key of another namespace, key IDs are unique across namespaces
+///
+/// Keys of the current namespace are replaced, like they always were.
+async fn check_key_id(id: &str) -> Result<()> {
+    if gpg_key::GpgKey::exists(id).await? && gpg_key::GpgKey::get(id).await?.is_none() {
+        return Err(Error::Conflict(format!("key {id} already exists")));
+    }
+    Ok(())
+}
 
 pub async fn get_all_keys() -> Result<Json<Vec<GpgKeyRef>>> {
     let keys = gpg_key::GpgKey::get_all().await?;
     Ok(Json(keys.into_iter().map(|r| GpgKeyRef::from(&r)).collect()))
 }
 
-pub async fn create_key(Json(key): Json<CreateGpgKey>) -> Result<Json<GpgKeyRef>> {
-    let key = gpg_key::GpgKey::new(&key.id, key.description, &key.user_id)?;
-    
-    Ok(Json(GpgKeyRef::from(&key.save().await?)))
-}
\ No newline at end of file
+pub async fn create_key(
+    identity: Identity,
+    Json(key): Json<CreateGpgKey>,
+) -> Result<Json<GpgKeyRef>> {
+    identity.require_namespace_admin().await?;
+    check_key_id(&key.id).await?;
+    let mut new_key =
+        gpg_key::GpgKey::generate(&key.id, key.description, &key.user_id, &key.options).await?;
+    new_key.allowed_tags = key.allowed_tags;
+    let new_key = GpgKeyRef::from(&new_key.save().await?);
+    key_event(
+        "create_key",
+        &identity,
+        &new_key.id,
+        serde_json::json!({
+            "algorithm": new_key.algorithm,
+            "backend": new_key.backend,
+            "allowed_tags": new_key.allowed_tags,
+        }),
+    )
+    .await?;
+
+    Ok(Json(new_key))
+}
+
+pub async fn create_external_key(
+    identity: Identity,
+    Json(key): Json<CreateExternalGpgKey>,
+) -> Result<Json<GpgKeyRef>> {
+    identity.require_namespace_admin().await?;
+    match &key.backend {
+        SignerBackend::Database => {
+            return Err(Error::Conflict(
+                "external keys need an http or command backend".to_owned(),
+            ));
+        }
+        SignerBackend::Command { command }
+            if CONFIG.get().unwrap().signing_command(command).is_none() =>
+        {
+            return Err(Error::Conflict(format!(
+                "signing command {command} is not configured"
+            )));
+        }
+        _ => {}
+    }
+    check_key_id(&key.id).await?;
+    let mut new_key = gpg_key::GpgKey::external(
+        &key.id,
+        key.description,
+        &key.user_id,
+        key.public_key,
+        key.backend,
+    )?;
+    new_key.allowed_tags = key.allowed_tags;
+    let new_key = GpgKeyRef::from(&new_key.save().await?);
+    key_event(
+        "create_key",
+        &identity,
+        &new_key.id,
+        serde_json::json!({
+            "algorithm": new_key.algorithm,
+            "backend": new_key.backend,
+            "allowed_tags": new_key.allowed_tags,
+        }),
+    )
+    .await?;
+
+    Ok(Json(new_key))
+}
+
+/// Armored public key, i.e. for `gpgkey=` in `.repo` files
+pub async fn get_public_key(Path(id): Path<String>) -> Result<impl IntoResponse> {
+    let key = gpg_key::GpgKey::get(&id).await?.ok_or(Error::NotFound)?;
+    Ok(([(header::CONTENT_TYPE, "application/pgp-keys")], key.public_key))
+}
+
+/// Replace a key with a newly generated one in every tag using it
+///
+/// The tags are switched to the new key right away, re-signing their packages and
+/// re-assembling them happens in a background job, see [`Tag::resign`]. The old key is kept,
+/// so packages signed with it can still be verified.
+pub async fn rotate_key(
+    identity: Identity,
+    Path(id): Path<String>,
+    Json(rotate): Json<RotateGpgKey>,
+) -> Result<(StatusCode, Json<Job>)> {
+    identity.require_namespace_admin().await?;
+    let old = gpg_key::GpgKey::get(&id).await?.ok_or(Error::NotFound)?;
+    // the replacement is generated here, which would move an external key's secret into the database
+    if old.backend != SignerBackend::Database {
+        return Err(Error::Conflict(format!("{id} is an external key")));
+    }
+    if gpg_key::GpgKey::exists(&rotate.id).await? {
+        return Err(Error::Conflict(format!("key {} already exists", rotate.id)));
+    }
+
+    let options = KeyOptions {
+        algorithm: rotate.algorithm.unwrap_or(old.algorithm),
+        expires_in_days: rotate.expires_in_days,
+    };
+    let mut key =
+        gpg_key::GpgKey::generate(&rotate.id, old.description.clone(), &old.user_id, &options)
+            .await?;
+    key.allowed_tags = old.allowed_tags.clone();
+    let key = key.save().await?;
+
+    let mut tags = Tag::get_by_signing_key(&id).await?;
+    for tag in &mut tags {
+        tag.set_gpg_key(&rotate.id);
+        *tag = tag.save().await?;
+    }
+    tracing::info!(old = %id, new = ?key.id, tags = tags.len(), "rotated signing key");
+
+    let job = Job::new("rotate", None).save().await?;
+    key_event(
+        "rotate_key",
+        &identity,
+        &id,
+        serde_json::json!({
+            "new_key": rotate.id,
+            "tags": tags.iter().map(|t| &t.name).collect::<Vec<_>>(),
+            "job": job.id.id.to_raw(),
+        }),
+    )
+    .await?;
+    job.clone().spawn(async move {
+        let mut rotated = Vec::with_capacity(tags.len());
+        for tag in tags {
+            rotated.push(match tag.resign().await {
+                Ok(signed) => RotatedTag {
+                    tag: tag.name,
+                    signed,
+                    error: None,
+                },
+                Err(e) => {
+                    tracing::warn!(tag = %tag.name, ?e, "failed to move tag to rotated key");
+                    RotatedTag {
+                        tag: tag.name,
+                        signed: Vec::new(),
+                        error: Some(format!("{e:#}")),
+                    }
+                }
+            });
+        }
+        color_eyre::Result::<_>::Ok(rotated)
+    });
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Restrict which tags may be signed with a key, an empty list allows every tag
+///
+/// Tags already using the key aren't changed, but can't be signed with it anymore.
+pub async fn set_allowed_tags(
+    identity: Identity,
+    Path(id): Path<String>,
+    Json(tags): Json<Vec<String>>,
+) -> Result<Json<GpgKeyRef>> {
+    identity.require_namespace_admin().await?;
+    let mut key = gpg_key::GpgKey::get(&id).await?.ok_or(Error::NotFound)?;
+    key.allowed_tags = tags;
+    let key = GpgKeyRef::from(&key.save().await?);
+    key_event(
+        "set_key_tags",
+        &identity,
+        &id,
+        serde_json::json!({ "allowed_tags": key.allowed_tags }),
+    )
+    .await?;
+
+    Ok(Json(key))
+}
+
+/// Record an operation on a key in the event log
+async fn key_event(
+    action: &str,
+    identity: &Identity,
+    id: &str,
+    data: serde_json::Value,
+) -> Result<()> {
+    LogEvent::new(action, identity.name(), data)
+        .resource(GPG_KEY_TABLE, id)
+        .record()
+        .await?;
+    Ok(())
+}
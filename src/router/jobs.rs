@@ -0,0 +1,14 @@
+//! Background job routes
+use axum::{extract::Path, response::Json, routing::get, Router};
+
+use crate::db::job::Job;
+use crate::errors::{Error, Result};
+
+pub fn route() -> Router {
+    Router::new().route("/jobs/{id}", get(get_job))
+}
+
+/// Status of a background job, with the error output if it failed
+pub async fn get_job(Path(id): Path<String>) -> Result<Json<Job>> {
+    Ok(Json(Job::get(&id).await?.ok_or(Error::NotFound)?))
+}
@@ -1,16 +1,208 @@
-use axum::Router;
+use std::path::Path;
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderName},
+    middleware,
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use serde::Deserialize;
+use tokio_util::io::ReaderStream;
+
+use crate::config::CONFIG;
+use crate::obj_store::object_store;
+
+pub mod admin;
+pub mod advisory;
+pub mod build;
+pub mod compose;
+pub mod deb;
+pub mod events;
+pub mod generic;
 pub mod gpg_keys;
+pub mod jobs;
+pub mod namespace;
+pub mod notify;
+pub mod oci;
+pub mod ostree;
+pub mod pacman;
 pub mod rpm;
 pub mod tag;
+pub mod tokens;
+pub mod upload;
 macro_rules! apply_routes {
     ([$($module:ident),*]) => {
-        pub fn route(mut router: Router) -> Router {
+        /// Merge every API route into the router, behind rate limiting and authentication
+        ///
+        /// The API is also served under `/ns/{namespace}/`, see [`crate::namespace`].
+        pub fn route(router: Router) -> Router {
+            let mut api = Router::new();
             $(
-                router = router.merge($module::route());
+                api = api.merge($module::route());
             )*
+            let api = api
+                .route_layer(middleware::from_fn(crate::auth::require_auth))
+                .route_layer(middleware::from_fn(crate::ratelimit::rate_limit));
             router
+                .merge(api.clone())
+                .merge(namespace::scoped_route(api))
         }
     };
 }
 
-apply_routes!([rpm, tag, gpg_keys]);
+apply_routes!([
+    rpm, deb, pacman, generic, oci, ostree, tag, gpg_keys, compose, notify, admin, build,
+    tokens, upload, advisory, jobs, events, namespace
+]);
+
+/// Header carrying the total number of items of a paginated listing
+pub const TOTAL_COUNT_HEADER: &str = "x-total-count";
+/// Upper bound for the page size of listings
+pub const MAX_PAGE_SIZE: u32 = 1000;
+
+fn default_page_size() -> u32 {
+    100
+}
+
+/// `limit`/`offset` query parameters for paginated listings
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct PageParams {
+    #[serde(default)]
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub offset: Option<u32>,
+}
+
+impl PageParams {
+    /// The page size, clamped to [`MAX_PAGE_SIZE`]
+    pub fn limit(&self) -> u32 {
+        self.limit
+            .unwrap_or_else(default_page_size)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.offset.unwrap_or_default()
+    }
+
+    /// Whether the caller asked for a page at all, for listings that predate pagination
+    pub fn is_requested(&self) -> bool {
+        self.limit.is_some() || self.offset.is_some()
+    }
+}
+
+/// Size of the request body from its `Content-Length`, 0 if it isn't known
+pub fn content_length(headers: &HeaderMap) -> u64 {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Headers for a paginated response
+pub fn page_headers(total: u64) -> [(HeaderName, String); 1] {
+    [(HeaderName::from_static(TOTAL_COUNT_HEADER), total.to_string())]
+}
+
+/// Serve an object from the object store as a download response
+///
+/// With presigned downloads enabled, this redirects to the object store instead
+/// of proxying the object through the API server.
+pub async fn serve_object(
+    key: &str,
+    filename: &str,
+    content_type: &str,
+) -> crate::errors::Result<Response> {
+    let config = CONFIG.get().unwrap();
+    if config.presigned_downloads {
+        let ttl = Duration::from_secs(config.presigned_url_ttl);
+        if let Some(url) = object_store().presigned_url(key, ttl).await? {
+            return Ok(Redirect::temporary(&url).into_response());
+        }
+    }
+
+    let path = object_store().get(key).await?;
+    serve_file(&path, filename, content_type).await
+}
+
+/// Stream a local file as a download response
+pub async fn serve_file(
+    path: &Path,
+    filename: &str,
+    content_type: &str,
+) -> crate::errors::Result<Response> {
+    let file = tokio::fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+    let body = Body::from_stream(ReaderStream::new(file));
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_LENGTH, len.to_string()),
+            (header::CONTENT_DISPOSITION, content_disposition(filename)),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// `Content-Disposition` of a download named `filename`
+///
+/// The quoted `filename` is an ASCII fallback with quotes, backslashes and control characters
+/// replaced, the exact name is in the percent-encoded `filename*` (RFC 6266).
+pub fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect();
+    let encoded: String = filename
+        .bytes()
+        .map(|b| match b {
+            b if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) => (b as char).to_string(),
+            b => format!("%{b:02X}"),
+        })
+        .collect();
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("boot.iso"),
+            "attachment; filename=\"boot.iso\"; filename*=UTF-8''boot.iso"
+        );
+        assert_eq!(
+            content_disposition("a\"b\r\nx-injected: 1.iso"),
+            "attachment; filename=\"a_b__x-injected: 1.iso\"; \
+             filename*=UTF-8''a%22b%0D%0Ax-injected%3A%201.iso"
+        );
+        assert_eq!(
+            content_disposition("café.iso"),
+            "attachment; filename=\"caf_.iso\"; filename*=UTF-8''caf%C3%A9.iso"
+        );
+    }
+
+    #[test]
+    fn test_page_params() {
+        let page = |query: serde_json::Value| serde_json::from_value::<PageParams>(query).unwrap();
+        let unset = page(serde_json::json!({}));
+        assert!(!unset.is_requested());
+        assert_eq!((unset.limit(), unset.offset()), (100, 0));
+
+        let offset = page(serde_json::json!({ "offset": 10 }));
+        assert!(offset.is_requested());
+        assert_eq!((offset.limit(), offset.offset()), (100, 10));
+        assert_eq!(page(serde_json::json!({ "limit": 5000 })).limit(), MAX_PAGE_SIZE);
+    }
+}
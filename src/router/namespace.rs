@@ -0,0 +1,137 @@
+//! Namespace management routes, and the `/ns/{namespace}/` prefix scoping the rest of the API
+//!
+//! Only admins may manage namespaces.
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Path, Request, State},
+    http::{Extensions, StatusCode},
+    response::{IntoResponse, Json, Response},
+    routing::{any, get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use tower::ServiceExt;
+
+use crate::auth::Identity;
+use crate::db::{
+    event_log::LogEvent,
+    namespace::{Namespace, NAMESPACE_TABLE},
+};
+use crate::errors::{Error, Result};
+use crate::namespace::ScopedNamespace;
+
+pub fn route() -> Router {
+    Router::new()
+        .route("/namespaces", get(get_all_namespaces))
+        .nest("/namespace", route_operations())
+}
+
+fn route_operations() -> Router {
+    Router::new()
+        .route("/", post(create_namespace))
+        .route("/{name}", get(get_namespace).delete(delete_namespace))
+}
+
+/// Route serving the API under `/ns/{namespace}/`, in the namespace's scope
+///
+/// Requests are forwarded to `api` without the prefix, so it must handle authentication.
+pub fn scoped_route(api: Router) -> Router {
+    Router::new()
+        .route("/ns/{namespace}/{*rest}", any(forward))
+        .with_state(api)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateNamespace {
+    /// Lowercase letters, digits and dashes, used in URLs and object keys
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+}
+
+/// Handle a namespaced request with the API routes, see [`crate::namespace`]
+async fn forward(
+    State(api): State<Router>,
+    Path((namespace, _)): Path<(String, String)>,
+    mut req: Request,
+) -> Response {
+    if !Namespace::is_valid_name(&namespace) {
+        return Error::NotFound.into_response();
+    }
+    // the raw path, the extracted one is percent-decoded
+    let prefix = format!("/ns/{namespace}");
+    let path = req.uri().path().strip_prefix(&prefix).unwrap_or("/");
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_owned(),
+    };
+    let Ok(uri) = path_and_query.parse() else {
+        return Error::NotFound.into_response();
+    };
+    *req.uri_mut() = uri;
+    // the outer route's path parameters are kept in the extensions, and would be extracted
+    // along with the forwarded route's own
+    let connect_info = req.extensions().get::<ConnectInfo<SocketAddr>>().cloned();
+    *req.extensions_mut() = Extensions::new();
+    if let Some(connect_info) = connect_info {
+        req.extensions_mut().insert(connect_info);
+    }
+    req.extensions_mut().insert(ScopedNamespace(namespace));
+
+    match api.oneshot(req).await {
+        Ok(res) => res,
+        Err(infallible) => match infallible {},
+    }
+}
+
+pub async fn get_all_namespaces(identity: Identity) -> Result<Json<Vec<Namespace>>> {
+    identity.require_admin()?;
+    Ok(Json(Namespace::get_all().await?))
+}
+
+pub async fn get_namespace(
+    identity: Identity,
+    Path(name): Path<String>,
+) -> Result<Json<Namespace>> {
+    identity.require_admin()?;
+    Ok(Json(Namespace::get(&name).await?.ok_or(Error::NotFound)?))
+}
+
+pub async fn create_namespace(
+    identity: Identity,
+    Json(req): Json<CreateNamespace>,
+) -> Result<(StatusCode, Json<Namespace>)> {
+    identity.require_admin()?;
+    if !Namespace::is_valid_name(&req.name) {
+        return Err(Error::Conflict(format!("invalid namespace name: {}", req.name)));
+    }
+    if Namespace::get(&req.name).await?.is_some() {
+        return Err(Error::Conflict(format!("namespace {} already exists", req.name)));
+    }
+
+    let namespace = Namespace::new(req.name, req.description).save().await?;
+    LogEvent::new("create_namespace", identity.name(), serde_json::json!({}))
+        .resource(NAMESPACE_TABLE, &namespace.name)
+        .record()
+        .await?;
+    Ok((StatusCode::CREATED, Json(namespace)))
+}
+
+/// Delete a namespace, once its tags and keys are gone
+pub async fn delete_namespace(identity: Identity, Path(name): Path<String>) -> Result<StatusCode> {
+    identity.require_admin()?;
+    let namespace = Namespace::get(&name).await?.ok_or(Error::NotFound)?;
+    if !namespace.is_empty().await? {
+        return Err(Error::Conflict(format!(
+            "namespace {name} still has tags or signing keys"
+        )));
+    }
+
+    namespace.delete().await?;
+    LogEvent::new("delete_namespace", identity.name(), serde_json::json!({}))
+        .resource(NAMESPACE_TABLE, &name)
+        .record()
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,113 @@
+//! Notification subscription routes, for email and webhooks
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    response::Json,
+    routing::{delete, get, post},
+    Router,
+};
+use axum_error_handler::AxumErrorResponse;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Identity;
+use crate::db::notification::{DigestMode, EmailSubscription};
+use crate::db::webhook::{Webhook, WebhookRef};
+use crate::errors::{Error, Result};
+use crate::notify::NotificationKind;
+
+#[derive(thiserror::Error, Debug, AxumErrorResponse)]
+pub enum NotifyError {
+    #[error("Invalid webhook URL: {0}")]
+    #[status_code("BAD_REQUEST")]
+    InvalidUrl(String),
+}
+
+pub fn route() -> Router {
+    Router::new().nest("/notifications", route_operations())
+}
+
+fn route_operations() -> Router {
+    Router::new()
+        .route("/email", get(get_email_subscriptions))
+        .route("/email", post(create_email_subscription))
+        .route("/email/{id}", delete(delete_email_subscription))
+        .route("/webhooks", get(get_webhooks))
+        .route("/webhooks", post(create_webhook))
+        .route("/webhooks/{id}", delete(delete_webhook))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateEmailSubscription {
+    address: String,
+    /// Tags to receive notifications for, empty for all tags
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Notification kinds to receive, empty for all kinds
+    #[serde(default)]
+    events: Vec<NotificationKind>,
+    mode: DigestMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateWebhook {
+    /// HTTP(S) URL events are POSTed to
+    url: String,
+    /// Event types to receive, i.e. `compose_finished`, empty for all types
+    #[serde(default)]
+    events: Vec<String>,
+    /// Tags to receive events for, empty for all tags
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Secret to sign deliveries with, unsigned if unset
+    #[serde(default)]
+    secret: Option<String>,
+}
+
+pub async fn get_email_subscriptions() -> Result<Json<Vec<EmailSubscription>>> {
+    Ok(Json(EmailSubscription::get_all().await?))
+}
+
+pub async fn create_email_subscription(
+    identity: Identity,
+    Json(sub): Json<CreateEmailSubscription>,
+) -> Result<(StatusCode, Json<EmailSubscription>)> {
+    identity.require_admin()?;
+    let sub = EmailSubscription::new(sub.address, sub.tags, sub.events, sub.mode);
+    Ok((StatusCode::CREATED, Json(sub.save().await?)))
+}
+
+pub async fn delete_email_subscription(
+    identity: Identity,
+    Path(id): Path<String>,
+) -> Result<StatusCode> {
+    identity.require_admin()?;
+    let sub = EmailSubscription::get(&id).await?.ok_or(Error::NotFound)?;
+    sub.delete().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_webhooks(identity: Identity) -> Result<Json<Vec<WebhookRef>>> {
+    identity.require_admin()?;
+    let hooks = Webhook::get_all().await?;
+    Ok(Json(hooks.iter().map(WebhookRef::from).collect()))
+}
+
+pub async fn create_webhook(
+    identity: Identity,
+    Json(hook): Json<CreateWebhook>,
+) -> Result<(StatusCode, Json<WebhookRef>)> {
+    identity.require_admin()?;
+    let url = reqwest::Url::parse(&hook.url).map_err(|e| NotifyError::InvalidUrl(e.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(NotifyError::InvalidUrl("must be http or https".to_owned()).into());
+    }
+    let hook = Webhook::new(hook.url, hook.events, hook.tags, hook.secret);
+    Ok((StatusCode::CREATED, Json(WebhookRef::from(&hook.save().await?))))
+}
+
+pub async fn delete_webhook(identity: Identity, Path(id): Path<String>) -> Result<StatusCode> {
+    identity.require_admin()?;
+    let hook = Webhook::get(&id).await?.ok_or(Error::NotFound)?;
+    hook.delete().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
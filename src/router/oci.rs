@@ -0,0 +1,332 @@
+//! OCI image routes
+//!
+//! Images are pushed with the authenticated `/oci` routes, blobs first and then the manifests
+//! referring to them. They're pulled through a read-only subset of the distribution spec under
+//! `/v2/`, which like exported repos needs no token, as `<host>/<tag>/<name>:<image tag>`, once
+//! the tag was assembled.
+use axum::{
+    body::{Body, Bytes},
+    extract::{Json, Path, Query},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, put},
+    Router,
+};
+use axum_error_handler::AxumErrorResponse;
+use serde::Deserialize;
+use ulid::Ulid;
+
+use super::{content_length, page_headers, rpm::stream_to_file, PageParams};
+use crate::auth::Identity;
+use crate::config::CONFIG;
+use crate::db::{
+    event_log::LogEvent,
+    oci::{parse_digest, OciBlob, OciManifest, OciManifestFilter, OciManifestRef},
+    permission::Role,
+    record_key,
+    rpm::file_sha256,
+    tag::Tag,
+};
+use crate::disk::check_upload;
+use crate::errors::{Error, Result};
+use crate::notify::{notify, Notification, NotificationKind};
+use crate::obj_store::object_store;
+use crate::router::tag::{require_unlocked, TagError};
+
+/// Header carrying the digest of served manifests and blobs
+const DIGEST_HEADER: &str = "docker-content-digest";
+
+#[derive(thiserror::Error, Debug, AxumErrorResponse)]
+pub enum OciError {
+    #[error("Invalid digest: {0}")]
+    #[status_code("BAD_REQUEST")]
+    InvalidDigest(String),
+    #[error("Digest mismatch, content is {0}")]
+    #[status_code("BAD_REQUEST")]
+    DigestMismatch(String),
+    #[error("Invalid manifest: {0}")]
+    #[status_code("BAD_REQUEST")]
+    InvalidManifest(String),
+    #[error("Unknown blob or manifest {0}, push it first")]
+    #[status_code("BAD_REQUEST")]
+    UnknownReference(String),
+}
+
+pub fn route() -> Router {
+    Router::new()
+        .route("/oci/blob", put(upload_blob))
+        .route("/oci/manifest", put(upload_manifest))
+        .route("/oci/manifests", get(get_all_manifests))
+        .route("/oci/manifest/{ulid}", get(get_manifest))
+        .route("/oci/manifest/{ulid}", delete(delete_manifest))
+}
+
+/// The public, read-only registry API
+pub fn registry_route() -> Router {
+    Router::new()
+        .route("/v2/", get(registry_root))
+        .route("/v2/{*path}", get(registry_get))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlobUploadParams {
+    /// Tag the blob is pushed for, only used for permissions as blobs are shared by every tag
+    tag: String,
+    digest: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ManifestUploadParams {
+    tag: String,
+    /// Name of the image in the tag
+    name: String,
+    /// Image tag to point at the manifest, if any
+    reference: Option<String>,
+}
+
+/// Upload a blob sent as the raw request body, checked against its digest
+pub async fn upload_blob(
+    identity: Identity,
+    Query(params): Query<BlobUploadParams>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<OciBlob>> {
+    identity.require(Role::Upload, &params.tag).await?;
+    Tag::get(&params.tag).await?.ok_or(TagError::NotFound)?;
+    require_unlocked(&params.tag).await?;
+    let blob = OciBlob::new(&params.digest, 0)
+        .map_err(|e| OciError::InvalidDigest(e.to_string()))?;
+    if let Some(existing) = OciBlob::get(&blob.digest).await? {
+        return Ok(Json(existing));
+    }
+    let cache_dir = &CONFIG.get().unwrap().cache_dir;
+    check_upload(cache_dir, content_length(&headers))?;
+
+    let dest = cache_dir.join(format!("{}.blob", Ulid::new()));
+    let stored = async {
+        stream_to_file(body, &dest).await?;
+        let digest = format!("sha256:{}", file_sha256(&dest)?);
+        if digest != blob.digest {
+            return Err(OciError::DigestMismatch(digest).into());
+        }
+        let size = tokio::fs::metadata(&dest).await?.len();
+        object_store().put(&blob.object_key, &dest).await?;
+        Ok::<_, Error>(OciBlob { size, ..blob })
+    }
+    .await;
+    let blob = match stored {
+        Ok(blob) => blob,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&dest).await;
+            return Err(e);
+        }
+    };
+
+    Ok(Json(blob.save().await?))
+}
+
+/// Upload a manifest sent as the request body, once everything it refers to was pushed
+///
+/// Its media type is read from the manifest, or the request's `Content-Type`.
+pub async fn upload_manifest(
+    identity: Identity,
+    Query(params): Query<ManifestUploadParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<OciManifestRef>> {
+    identity.require(Role::Upload, &params.tag).await?;
+    Tag::get(&params.tag).await?.ok_or(TagError::NotFound)?;
+    require_unlocked(&params.tag).await?;
+
+    let content = String::from_utf8(body.to_vec())
+        .map_err(|_| OciError::InvalidManifest("manifest isn't UTF-8".to_owned()))?;
+    let media_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let invalid = |e: color_eyre::Report| OciError::InvalidManifest(e.to_string());
+    let manifest =
+        OciManifest::new(&params.tag, &params.name, content, media_type).map_err(invalid)?;
+    let references = manifest.references().map_err(invalid)?;
+    for digest in &references.blobs {
+        if OciBlob::get(digest).await?.is_none() {
+            return Err(OciError::UnknownReference(digest.clone()).into());
+        }
+    }
+    for digest in &references.manifests {
+        if OciManifest::find(&params.tag, &params.name, digest)
+            .await?
+            .is_none()
+        {
+            return Err(OciError::UnknownReference(digest.clone()).into());
+        }
+    }
+    if let Some(reference) = params.reference.as_deref() {
+        if reference.starts_with("sha256:") && reference != manifest.digest {
+            return Err(OciError::DigestMismatch(manifest.digest).into());
+        }
+    }
+
+    let manifest = manifest.commit(params.reference.as_deref()).await?;
+    notify(Notification::new(
+        NotificationKind::Upload,
+        Some(params.tag.as_str()),
+        format!("pushed {}/{}@{}", params.tag, manifest.name, manifest.digest),
+    ));
+    LogEvent::for_oci("push", identity.name(), &manifest, &params.tag)
+        .record()
+        .await?;
+
+    Ok(Json(OciManifestRef::from(&manifest)))
+}
+
+/// List manifests, optionally filtered by tag and image name
+pub async fn get_all_manifests(
+    Query(page): Query<PageParams>,
+    Query(filter): Query<OciManifestFilter>,
+) -> Result<([(HeaderName, String); 1], Json<Vec<OciManifestRef>>)> {
+    let (manifests, total) = OciManifest::get_page(&filter, page.limit(), page.offset()).await?;
+    Ok((
+        page_headers(total),
+        Json(manifests.iter().map(OciManifestRef::from).collect()),
+    ))
+}
+
+pub async fn get_manifest(Path(id): Path<Ulid>) -> Result<Json<OciManifest>> {
+    Ok(Json(OciManifest::get(id).await?.ok_or(Error::NotFound)?))
+}
+
+pub async fn delete_manifest(identity: Identity, Path(id): Path<Ulid>) -> Result<StatusCode> {
+    let manifest = OciManifest::get(id).await?.ok_or(Error::NotFound)?;
+    let tag = record_key(&manifest.tag);
+    identity.require(Role::Admin, &tag).await?;
+    require_unlocked(&tag).await?;
+    manifest.delete().await?;
+    LogEvent::for_oci("delete", identity.name(), &manifest, &tag)
+        .record()
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+/// An error in the distribution spec's format, which registry clients show to users
+pub struct RegistryError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl RegistryError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, code, message)
+    }
+}
+
+impl From<color_eyre::Report> for RegistryError {
+    fn from(e: color_eyre::Report) -> Self {
+        tracing::error!(?e, "registry request failed");
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "UNKNOWN", "internal error")
+    }
+}
+
+impl From<Error> for RegistryError {
+    fn from(e: Error) -> Self {
+        tracing::error!(?e, "registry request failed");
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "UNKNOWN", "internal error")
+    }
+}
+
+impl IntoResponse for RegistryError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({
+            "errors": [{ "code": self.code, "message": self.message }],
+        });
+        (self.status, api_version_header(), Json(body)).into_response()
+    }
+}
+
+fn api_version_header() -> [(HeaderName, HeaderValue); 1] {
+    [(
+        HeaderName::from_static("docker-distribution-api-version"),
+        HeaderValue::from_static("registry/2.0"),
+    )]
+}
+
+/// Tells clients the registry API is supported, no authentication is needed to pull
+pub async fn registry_root() -> impl IntoResponse {
+    (api_version_header(), Json(serde_json::json!({})))
+}
+
+/// Split a repository, `<tag>/<name>`, into the tag and image name
+fn split_repository(repository: &str) -> std::result::Result<(&str, &str), RegistryError> {
+    repository
+        .split_once('/')
+        .filter(|(tag, name)| !tag.is_empty() && !name.is_empty())
+        .ok_or_else(|| {
+            RegistryError::not_found("NAME_UNKNOWN", "repositories are named <tag>/<image>")
+        })
+}
+
+/// Serve manifests, blobs and image tag lists
+///
+/// Image names can contain slashes, so the path is matched by hand.
+pub async fn registry_get(
+    Path(path): Path<String>,
+) -> std::result::Result<Response, RegistryError> {
+    if let Some((repository, reference)) = path.rsplit_once("/manifests/") {
+        let (tag, name) = split_repository(repository)?;
+        let manifest = OciManifest::find_published(tag, name, reference)
+            .await?
+            .ok_or_else(|| RegistryError::not_found("MANIFEST_UNKNOWN", "manifest unknown"))?;
+        let digest = HeaderValue::from_str(&manifest.digest).map_err(color_eyre::Report::from)?;
+        return Ok((
+            [
+                (header::CONTENT_TYPE, manifest.media_type.clone()),
+                (header::CONTENT_LENGTH, manifest.size.to_string()),
+            ],
+            [(HeaderName::from_static(DIGEST_HEADER), digest)],
+            manifest.content,
+        )
+            .into_response());
+    }
+
+    if let Some((repository, digest)) = path.rsplit_once("/blobs/") {
+        let (tag, name) = split_repository(repository)?;
+        let blob_unknown = || RegistryError::not_found("BLOB_UNKNOWN", "blob unknown");
+        // blobs are shared by every tag, but only served as part of an image referring to them
+        if parse_digest(digest).is_err() || !OciManifest::references_blob(tag, name, digest).await?
+        {
+            return Err(blob_unknown());
+        }
+        let blob = OciBlob::get(digest)
+            .await
+            .ok()
+            .flatten()
+            .ok_or_else(blob_unknown)?;
+        let mut res =
+            super::serve_object(&blob.object_key, &blob.digest, "application/octet-stream")
+                .await?;
+        let digest = HeaderValue::from_str(&blob.digest).map_err(color_eyre::Report::from)?;
+        res.headers_mut().insert(DIGEST_HEADER, digest);
+        return Ok(res);
+    }
+
+    if let Some(repository) = path.strip_suffix("/tags/list") {
+        let (tag, name) = split_repository(repository)?;
+        let tags = OciManifest::list_tags(tag, name).await?;
+        if tags.is_empty() {
+            return Err(RegistryError::not_found("NAME_UNKNOWN", "repository unknown"));
+        }
+        let body = serde_json::json!({ "name": repository, "tags": tags });
+        return Ok((api_version_header(), Json(body)).into_response());
+    }
+
+    Err(RegistryError::not_found("NOT_FOUND", "unsupported registry route"))
+}
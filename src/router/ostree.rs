@@ -0,0 +1,233 @@
+//! OSTree commit routes
+//!
+//! Commits can be uploaded to any tag, and are published as an ostree repo under its `ostree/`
+//! directory.
+use axum::{
+    body::Body,
+    extract::{Json, Path, Query},
+    http::{HeaderMap, HeaderName, StatusCode},
+    response::Response,
+    routing::{delete, get, post, put},
+    Router,
+};
+use axum_error_handler::AxumErrorResponse;
+use serde::Deserialize;
+use ulid::Ulid;
+
+use super::{content_length, page_headers, rpm::stream_to_file, PageParams};
+use crate::auth::Identity;
+use crate::config::CONFIG;
+use crate::db::{
+    event_log::LogEvent,
+    ostree::{
+        validate_checksum, validate_ref, OstreeArtifact, OstreeCommit, OstreeCommitFilter,
+        OstreeCommitRef,
+    },
+    permission::Role,
+    record_key,
+    tag::Tag,
+};
+use crate::disk::check_upload;
+use crate::errors::{Error, Result};
+use crate::notify::{notify, Notification, NotificationKind};
+use crate::obj_store::object_store;
+use crate::repodata::ostree;
+use crate::router::tag::{require_unlocked, TagError};
+
+#[derive(thiserror::Error, Debug, AxumErrorResponse)]
+pub enum OstreeError {
+    #[error("Invalid ref: {0}")]
+    #[status_code("BAD_REQUEST")]
+    InvalidRef(String),
+    #[error("Invalid commit: {0}")]
+    #[status_code("BAD_REQUEST")]
+    InvalidCommit(String),
+}
+
+pub fn route() -> Router {
+    Router::new().nest("/ostree", route_operations())
+}
+
+fn route_operations() -> Router {
+    Router::new()
+        .route("/commits", get(get_all_commits))
+        .route("/{ulid}", get(get_commit))
+        .route("/{ulid}", delete(delete_commit))
+        .route("/{ulid}/available", post(mark_commit_available))
+        .route("/{ulid}/available", delete(mark_commit_unavailable))
+        .route("/{ulid}/download", get(download_commit))
+        .route("/upload/raw", put(upload_commit_raw))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CommitUploadParams {
+    tag: String,
+    #[serde(rename = "ref")]
+    ref_name: String,
+    /// Checksum of the commit, read from the tarball's ref if not given. Required for deltas
+    commit: Option<String>,
+    #[serde(default)]
+    artifact: OstreeArtifact,
+}
+
+/// List commits, optionally filtered by ref, tag and availability
+pub async fn get_all_commits(
+    Query(page): Query<PageParams>,
+    Query(filter): Query<OstreeCommitFilter>,
+) -> Result<([(HeaderName, String); 1], Json<Vec<OstreeCommitRef>>)> {
+    let (commits, total) = OstreeCommit::get_page(&filter, page.limit(), page.offset()).await?;
+    Ok((
+        page_headers(total),
+        Json(commits.iter().map(OstreeCommitRef::from).collect()),
+    ))
+}
+
+pub async fn get_commit(Path(commit_id): Path<Ulid>) -> Result<Json<OstreeCommit>> {
+    Ok(Json(OstreeCommit::get(commit_id).await?.ok_or(Error::NotFound)?))
+}
+
+/// Download the tarball or delta the commit was uploaded as
+pub async fn download_commit(Path(commit_id): Path<Ulid>) -> Result<Response> {
+    let commit = OstreeCommit::get(commit_id).await?.ok_or(Error::NotFound)?;
+    let file_name = commit.object_key.rsplit('/').next().unwrap_or_default();
+    super::serve_object(&commit.object_key, file_name, "application/octet-stream").await
+}
+
+async fn set_commit_available(
+    identity: Identity,
+    commit_id: Ulid,
+    available: bool,
+) -> Result<StatusCode> {
+    let commit = OstreeCommit::get(commit_id).await?.ok_or(Error::NotFound)?;
+    let tag = record_key(&commit.tag);
+    identity.require(Role::Upload, &tag).await?;
+    require_unlocked(&tag).await?;
+    let commit = commit.mark_available(available).await?;
+    let action = if available { "available" } else { "unavailable" };
+    LogEvent::for_ostree(action, identity.name(), &commit, &tag)
+        .record()
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn mark_commit_available(
+    identity: Identity,
+    Path(commit_id): Path<Ulid>,
+) -> Result<StatusCode> {
+    set_commit_available(identity, commit_id, true).await
+}
+
+/// Take a commit out of the published history, i.e. a broken one
+pub async fn mark_commit_unavailable(
+    identity: Identity,
+    Path(commit_id): Path<Ulid>,
+) -> Result<StatusCode> {
+    set_commit_available(identity, commit_id, false).await
+}
+
+pub async fn delete_commit(identity: Identity, Path(commit_id): Path<Ulid>) -> Result<StatusCode> {
+    let commit = OstreeCommit::get(commit_id).await?.ok_or(Error::NotFound)?;
+    let tag = record_key(&commit.tag);
+    identity.require(Role::Admin, &tag).await?;
+    require_unlocked(&tag).await?;
+    commit.delete().await?;
+    LogEvent::for_ostree("delete", identity.name(), &commit, &tag)
+        .record()
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+/// Find the commit an uploaded artifact holds, checking tarballs actually contain it
+async fn resolve_commit(path: &std::path::Path, params: &CommitUploadParams) -> Result<String> {
+    if let Some(commit) = &params.commit {
+        validate_checksum(commit).map_err(|e| OstreeError::InvalidCommit(e.to_string()))?;
+    }
+    if params.artifact == OstreeArtifact::Delta {
+        return params.commit.clone().ok_or_else(|| {
+            OstreeError::InvalidCommit("the commit of a delta must be given".to_owned()).into()
+        });
+    }
+
+    let unpacked = path.with_extension("unpacked");
+    let resolved = async {
+        let (tarball, dest) = (path.to_owned(), unpacked.clone());
+        tokio::task::spawn_blocking(move || ostree::unpack_tarball(&tarball, &dest))
+            .await
+            .map_err(color_eyre::Report::from)?
+            .map_err(|e| OstreeError::InvalidCommit(format!("{e:#}")))?;
+        let commit = match &params.commit {
+            Some(commit) => commit.clone(),
+            None => ostree::resolve_ref(&unpacked, &params.ref_name)
+                .await
+                .map_err(|e| OstreeError::InvalidCommit(format!("{e:#}")))?,
+        };
+        if !ostree::has_commit(&unpacked, &commit).await? {
+            let msg = format!("tarball has no commit {commit}");
+            return Err(OstreeError::InvalidCommit(msg).into());
+        }
+        Ok::<_, Error>(commit)
+    }
+    .await;
+    tokio::fs::remove_dir_all(&unpacked).await.ok();
+    resolved
+}
+
+/// Upload a commit for a ref of a tag, sent as the raw request body
+///
+/// Tarballs hold an ostree repo with the commit, deltas must apply on top of the commits
+/// uploaded to the tag before them.
+pub async fn upload_commit_raw(
+    identity: Identity,
+    Query(params): Query<CommitUploadParams>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<OstreeCommitRef>> {
+    identity.require(Role::Upload, &params.tag).await?;
+    Tag::get(&params.tag).await?.ok_or(TagError::NotFound)?;
+    require_unlocked(&params.tag).await?;
+    validate_ref(&params.ref_name).map_err(|e| OstreeError::InvalidRef(e.to_string()))?;
+    let cache_dir = &CONFIG.get().unwrap().cache_dir;
+    check_upload(cache_dir, content_length(&headers))?;
+
+    let dest = cache_dir.join(format!("{}.{}", Ulid::new(), params.artifact.as_str()));
+    let staged = async {
+        stream_to_file(body, &dest).await?;
+        let checksum = resolve_commit(&dest, &params).await?;
+        Ok::<_, Error>(OstreeCommit::from_path(
+            &dest,
+            &params.ref_name,
+            &checksum,
+            params.artifact,
+            &params.tag,
+        )?)
+    }
+    .await;
+    let commit = match staged {
+        Ok(commit) => commit,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&dest).await;
+            return Err(e);
+        }
+    };
+    if let Some(existing) = commit.find_duplicate().await? {
+        tracing::info!(existing = ?existing.id, "commit was already uploaded, skipping");
+        let _ = tokio::fs::remove_file(&dest).await;
+        return Ok(Json(OstreeCommitRef::from(&existing)));
+    }
+    if let Err(e) = object_store().put(&commit.object_key, &dest).await {
+        let _ = tokio::fs::remove_file(&dest).await;
+        return Err(e.into());
+    }
+
+    let commit = commit.commit_to_db().await?;
+    notify(Notification::new(
+        NotificationKind::Upload,
+        Some(params.tag.as_str()),
+        format!("uploaded {} commit {}", commit.ref_name, commit.checksum),
+    ));
+    LogEvent::for_ostree("upload", identity.name(), &commit, &params.tag)
+        .record()
+        .await?;
+
+    Ok(Json(OstreeCommitRef::from(&commit)))
+}
@@ -0,0 +1,223 @@
+//! pacman package routes
+//!
+//! pacman packages can only be uploaded to `pacman` tags.
+use axum::{
+    body::{Body, Bytes},
+    extract::{Json, Path, Query},
+    http::{HeaderMap, HeaderName, StatusCode},
+    response::Response,
+    routing::{delete, get, post, put},
+    Router,
+};
+use pgp::Deserializable;
+use serde::Deserialize;
+use ulid::Ulid;
+
+use super::{content_length, page_headers, rpm::stream_to_file, PageParams};
+use crate::auth::Identity;
+use crate::config::CONFIG;
+use crate::db::{
+    event_log::LogEvent,
+    pacman::{PacmanFilter, PacmanPackage, PacmanRef, PACMAN_CONTENT_TYPE, PACMAN_PKG_EXT},
+    permission::Role,
+    record_key,
+    tag::{RepoType, Tag},
+};
+use crate::disk::check_upload;
+use crate::errors::{Error, Result};
+use crate::notify::{notify, Notification, NotificationKind};
+use crate::obj_store::object_store;
+use crate::router::tag::{require_unlocked, TagError};
+use crate::signing::signer::dearmor;
+
+pub fn route() -> Router {
+    Router::new().nest("/pacman", route_operations())
+}
+
+fn route_operations() -> Router {
+    Router::new()
+        .route("/packages", get(get_all_pacman))
+        .route("/{ulid}", get(get_pacman))
+        .route("/{ulid}", delete(delete_pacman))
+        .route("/{ulid}/available", post(mark_pacman_available))
+        .route("/{ulid}/available", delete(mark_pacman_unavailable))
+        .route("/{ulid}/download", get(download_pacman))
+        .route("/{ulid}/signature", put(upload_pacman_signature))
+        .route("/upload/raw", put(upload_pacman_raw))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PacmanUploadParams {
+    tag: String,
+    #[serde(default)]
+    prune: bool,
+    /// Mark the upload as latest even if a newer version is already available
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarkAvailableParams {
+    /// Mark the package as available even if a newer version is already available
+    #[serde(default)]
+    force: bool,
+}
+
+/// List pacman packages, optionally filtered by name, version, architecture and tag
+pub async fn get_all_pacman(
+    Query(page): Query<PageParams>,
+    Query(filter): Query<PacmanFilter>,
+) -> Result<([(HeaderName, String); 1], Json<Vec<PacmanRef>>)> {
+    let (pkgs, total) = PacmanPackage::get_page(&filter, page.limit(), page.offset()).await?;
+    Ok((
+        page_headers(total),
+        Json(pkgs.iter().map(PacmanRef::from).collect()),
+    ))
+}
+
+pub async fn get_pacman(Path(pkg_id): Path<Ulid>) -> Result<Json<PacmanPackage>> {
+    Ok(Json(PacmanPackage::get(pkg_id).await?.ok_or(Error::NotFound)?))
+}
+
+/// Download the package file
+pub async fn download_pacman(Path(pkg_id): Path<Ulid>) -> Result<Response> {
+    let pkg = PacmanPackage::get(pkg_id).await?.ok_or(Error::NotFound)?;
+    super::serve_object(&pkg.object_key, pkg.file_name(), PACMAN_CONTENT_TYPE).await
+}
+
+pub async fn mark_pacman_available(
+    identity: Identity,
+    Path(pkg_id): Path<Ulid>,
+    Query(params): Query<MarkAvailableParams>,
+) -> Result<StatusCode> {
+    let pkg = PacmanPackage::get(pkg_id).await?.ok_or(Error::NotFound)?;
+    let tag = record_key(&pkg.tag);
+    identity.require(Role::Upload, &tag).await?;
+    require_unlocked(&tag).await?;
+    if !params.force {
+        if let Some(newer) = pkg.newer_sibling().await? {
+            return Err(Error::Conflict(format!(
+                "newer version {} is already available",
+                newer.version
+            )));
+        }
+    }
+    pkg.mark_available(params.force).await?;
+    LogEvent::for_pacman("available", identity.name(), &pkg, &tag)
+        .record()
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn mark_pacman_unavailable(
+    identity: Identity,
+    Path(pkg_id): Path<Ulid>,
+) -> Result<StatusCode> {
+    let pkg = PacmanPackage::get(pkg_id).await?.ok_or(Error::NotFound)?;
+    let tag = record_key(&pkg.tag);
+    identity.require(Role::Upload, &tag).await?;
+    require_unlocked(&tag).await?;
+    pkg.mark_unavailable().await?;
+    LogEvent::for_pacman("unavailable", identity.name(), &pkg, &tag)
+        .record()
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn delete_pacman(identity: Identity, Path(pkg_id): Path<Ulid>) -> Result<StatusCode> {
+    let pkg = PacmanPackage::get(pkg_id).await?.ok_or(Error::NotFound)?;
+    let tag = record_key(&pkg.tag);
+    identity.require(Role::Admin, &tag).await?;
+    require_unlocked(&tag).await?;
+    pkg.delete().await?;
+    LogEvent::for_pacman("delete", identity.name(), &pkg, &tag)
+        .record()
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+/// Attach a detached signature made by the packager, binary or armored, published as the
+/// package's `.sig` file
+pub async fn upload_pacman_signature(
+    identity: Identity,
+    Path(pkg_id): Path<Ulid>,
+    body: Bytes,
+) -> Result<Json<PacmanRef>> {
+    let pkg = PacmanPackage::get(pkg_id).await?.ok_or(Error::NotFound)?;
+    let tag = record_key(&pkg.tag);
+    identity.require(Role::Sign, &tag).await?;
+    require_unlocked(&tag).await?;
+
+    let signature = dearmor(body.to_vec())?;
+    pgp::StandaloneSignature::from_bytes(signature.as_slice())
+        .map_err(|e| color_eyre::eyre::eyre!("invalid signature: {e}"))?;
+    let pkg = pkg.set_signature(&signature).await?;
+    LogEvent::for_pacman("sign", identity.name(), &pkg, &tag)
+        .record()
+        .await?;
+    Ok(Json(PacmanRef::from(&pkg)))
+}
+
+/// Parse a pacman package staged in the cache dir and push it to the object store
+///
+/// If the same file is already in the tag, the staged file is dropped and the existing
+/// package is returned with `false`.
+async fn store_upload(dest: &std::path::PathBuf, tag: &str) -> Result<(PacmanPackage, bool)> {
+    let pkg = PacmanPackage::from_path(dest, tag)?;
+    if let Some(existing) = pkg.find_duplicate().await? {
+        tracing::info!(existing = ?existing.id, "package was already uploaded, skipping");
+        let _ = tokio::fs::remove_file(dest).await;
+        return Ok((existing, false));
+    }
+    object_store().put(&pkg.object_key, dest).await?;
+    Ok((pkg, true))
+}
+
+/// Upload a `.pkg.tar.zst` sent as the raw request body
+pub async fn upload_pacman_raw(
+    identity: Identity,
+    Query(params): Query<PacmanUploadParams>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<PacmanRef>> {
+    identity.require(Role::Upload, &params.tag).await?;
+    let tag = Tag::get(&params.tag).await?.ok_or(TagError::NotFound)?;
+    if tag.repo_type != RepoType::Pacman {
+        return Err(Error::Conflict(format!(
+            "{} only holds {} packages",
+            tag.name,
+            tag.repo_type.as_str()
+        )));
+    }
+    require_unlocked(&params.tag).await?;
+    let cache_dir = &CONFIG.get().unwrap().cache_dir;
+    check_upload(cache_dir, content_length(&headers))?;
+
+    let dest = cache_dir.join(format!("{}.{PACMAN_PKG_EXT}", Ulid::new()));
+    let staged = match stream_to_file(body, &dest).await {
+        Ok(()) => store_upload(&dest, &params.tag).await,
+        Err(e) => Err(e),
+    };
+    let (pkg, new) = match staged {
+        Ok(staged) => staged,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&dest).await;
+            return Err(e);
+        }
+    };
+    if !new {
+        return Ok(Json(PacmanRef::from(&pkg)));
+    }
+
+    let pkg = pkg.commit_to_db(params.prune, params.force).await?;
+    notify(Notification::new(
+        NotificationKind::Upload,
+        Some(params.tag.as_str()),
+        format!("uploaded {}", pkg.full_name()),
+    ));
+    LogEvent::for_pacman("upload", identity.name(), &pkg, &params.tag)
+        .record()
+        .await?;
+
+    Ok(Json(PacmanRef::from(&pkg)))
+}
@@ -1,22 +1,82 @@
-use crate::errors::Result;
+use crate::errors::{Error, Result};
 use crate::obj_store::object_store;
+use axum::body::Body;
 use axum::debug_handler;
 use axum::extract::{Json, Query};
 use axum::{
-    extract::{Multipart, Path},
-    http::StatusCode,
+    extract::{multipart::MultipartError, Multipart, Path},
+    http::{HeaderMap, HeaderName, StatusCode},
+    response::{IntoResponse, Response},
     routing::{delete, get, post, put},
     Router,
 };
-use serde::Deserialize;
+use color_eyre::eyre::eyre;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use surrealdb::RecordId;
 use ulid::Ulid;
 
 use crate::config::CONFIG;
-use crate::db::rpm::{Rpm, RpmRef};
+use crate::db::rpm::{Dependent, Rpm, RpmFilter, RpmRef, RPM_TABLE};
+use super::{content_length, page_headers, PageParams};
+use crate::auth::Identity;
+use crate::db::permission::Role;
+use crate::disk::{check_size, check_upload};
+use crate::db::{
+    event_log::LogEvent,
+    gpg_key::GpgKey,
+    job::Job,
+    membership::TagMembership,
+    record_key,
+    tag::{Tag, TAG_TABLE},
+    DB,
+};
+use crate::notify::{notify, Notification, NotificationKind};
+use crate::router::tag::{require_unlocked, spawn_assemble, TagError};
+use axum_error_handler::AxumErrorResponse;
+
+#[derive(thiserror::Error, Debug, AxumErrorResponse)]
+pub enum RpmError {
+    #[error("Package not found")]
+    #[status_code("NOT_FOUND")]
+    NotFound,
+    #[error("Package is not in {0}")]
+    #[status_code("NOT_FOUND")]
+    NotInTag(String),
+    #[error("Package is not signed")]
+    #[status_code("NOT_FOUND")]
+    NotSigned,
+    #[error("Invalid package: {0}")]
+    #[status_code("BAD_REQUEST")]
+    InvalidPackage(String),
+    #[error("Package is held by {0}")]
+    #[status_code("CONFLICT")]
+    Held(String),
+    #[error("Newer version {0} is already available")]
+    #[status_code("CONFLICT")]
+    NewerAvailable(String),
+    #[error("Package is already in {0}")]
+    #[status_code("CONFLICT")]
+    AlreadyInTag(String),
+    #[error("Package is already in {0} as {1}")]
+    #[status_code("CONFLICT")]
+    Duplicate(String, String),
+    #[error("{0} only holds {1} packages")]
+    #[status_code("CONFLICT")]
+    WrongRepoType(String, String),
+    #[error("{0} has no signing key")]
+    #[status_code("CONFLICT")]
+    NoSigningKey(String),
+    #[error("Package is still shared into {0}, remove it from those tags first")]
+    #[status_code("CONFLICT")]
+    StillShared(String),
+}
 
 pub fn route() -> Router {
     Router::new()
         .route("/rpms", get(get_all_rpms))
+        .route("/rpms/promote", post(promote_rpms))
         .nest("/rpm", route_operations())
 }
 
@@ -26,84 +86,833 @@ fn route_operations() -> Router {
         .route("/{ulid}", delete(delete_rpm))
         .route("/{ulid}/available", post(mark_rpm_available))
         .route("/{ulid}/available", delete(mark_rpm_unavailable))
+        .route("/{ulid}/dependents", get(get_rpm_dependents))
+        .route("/{ulid}/download", get(download_rpm))
+        .route("/{ulid}/download/signed", get(download_signed_rpm))
+        .route("/{ulid}/hold", post(hold_rpm))
+        .route("/{ulid}/promote", post(promote_rpm))
+        .route("/{ulid}/sign", post(sign_rpm))
+        .route("/{ulid}/tags", get(get_rpm_tags))
+        .route("/{ulid}/tags/{tag}", post(add_rpm_tag))
+        .route("/{ulid}/tags/{tag}", delete(remove_rpm_tag))
+        .route("/{ulid}/hold", delete(release_rpm))
         .route("/upload", put(upload_rpm))
+        .route("/upload/raw", put(upload_rpm_raw))
 }
 #[derive(Debug, Deserialize)]
 pub struct RpmUploadParams {
     prune: bool,
+    /// Mark the upload as latest even if a newer version is already available
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarkAvailableParams {
+    /// Mark the package as available even if a newer version is already available
+    #[serde(default)]
+    force: bool,
+    /// Tag the package was shared into to change its availability in, defaults to the package's own tag
+    #[serde(default)]
+    tag: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagParams {
+    /// Tag the package was shared into, defaults to the package's own tag
+    #[serde(default)]
+    tag: Option<String>,
+}
+
+/// Resolve the tag a package's availability is changed in, which it must be a member of
+async fn member_tag(rpm: &Rpm, tag: Option<String>) -> Result<String> {
+    let home = record_key(&rpm.tag);
+    match tag {
+        Some(tag) if tag != home => {
+            TagMembership::get(rpm, &tag)
+                .await?
+                .ok_or_else(|| RpmError::NotInTag(tag.clone()))?;
+            Ok(tag)
+        }
+        _ => Ok(home),
+    }
 }
 pub async fn get_rpm(Path(pkg_id): Path<Ulid>) -> Result<Json<Rpm>> {
-    let rpm = Rpm::get(pkg_id).await?.unwrap();
+    let rpm = Rpm::get(pkg_id).await?.ok_or(RpmError::NotFound)?;
     Ok(Json(rpm))
 }
 
 
-pub async fn get_all_rpms() -> Result<Json<Vec<RpmRef>>> {
-    let rpms = Rpm::get_all().await?;
-    Ok(Json(rpms.into_iter().map(|r| RpmRef::from(&r)).collect()))
+/// MIME type RPM packages are served as
+pub const RPM_CONTENT_TYPE: &str = "application/x-rpm";
+
+/// Download the package file
+pub async fn download_rpm(Path(pkg_id): Path<Ulid>) -> Result<Response> {
+    let rpm = Rpm::get(pkg_id).await?.ok_or(RpmError::NotFound)?;
+    serve_object(&rpm.object_key).await
 }
 
-pub async fn mark_rpm_available(Path(pkg_id): Path<Ulid>) -> Result<StatusCode> {
-    let rpm = Rpm::get(pkg_id).await?.unwrap();
-    rpm.mark_available().await?;
+/// Download the signed package file, if the package has been signed
+pub async fn download_signed_rpm(Path(pkg_id): Path<Ulid>) -> Result<Response> {
+    let rpm = Rpm::get(pkg_id).await?.ok_or(RpmError::NotFound)?;
+    let key = rpm.signed_object_key.ok_or(RpmError::NotSigned)?;
+    serve_object(&key).await
+}
+
+/// Serve an RPM object, named after the last component of its key
+async fn serve_object(key: &str) -> Result<Response> {
+    let filename = key.rsplit('/').next().unwrap_or(key);
+    super::serve_object(key, filename, RPM_CONTENT_TYPE).await
+}
+
+/// List RPMs, optionally filtered by NEVRA and tag
+pub async fn get_all_rpms(
+    Query(page): Query<PageParams>,
+    Query(filter): Query<RpmFilter>,
+) -> Result<([(HeaderName, String); 1], Json<Vec<RpmRef>>)> {
+    // unpaginated unless asked, like before pagination was added
+    let limit = page.is_requested().then(|| page.limit());
+    let (rpms, total) = Rpm::get_page(&filter, limit, page.offset()).await?;
+    Ok((
+        page_headers(total),
+        Json(rpms.into_iter().map(|r| RpmRef::from(&r)).collect()),
+    ))
+}
+
+pub async fn mark_rpm_available(
+    identity: Identity,
+    Path(pkg_id): Path<Ulid>,
+    Query(params): Query<MarkAvailableParams>,
+) -> Result<StatusCode> {
+    let rpm = Rpm::get(pkg_id).await?.ok_or(RpmError::NotFound)?;
+    let tag = member_tag(&rpm, params.tag).await?;
+    identity.require(Role::Upload, &tag).await?;
+    require_unlocked(&tag).await?;
+    let tag_id = RecordId::from_table_key(TAG_TABLE, &tag);
+    if let Some(held) = rpm.held_sibling_in(&tag_id).await? {
+        return Err(RpmError::Held(held.id.id.to_raw()).into());
+    }
+    if !params.force {
+        if let Some(newer) = rpm.newer_sibling_in(&tag_id).await? {
+            let evr = format!("{}:{}-{}", newer.epoch, newer.version, newer.release);
+            return Err(RpmError::NewerAvailable(evr).into());
+        }
+    }
+    rpm.mark_available_in(&tag, params.force).await?;
+    LogEvent::for_rpm("available", identity.name(), &rpm, &tag)
+        .record()
+        .await?;
     Ok(StatusCode::OK)
 }
 
-pub async fn mark_rpm_unavailable(Path(pkg_id): Path<Ulid>) -> Result<StatusCode> {
-    let rpm = Rpm::get(pkg_id).await?.unwrap();
-    rpm.mark_unavailable().await?;
+#[derive(Debug, Deserialize)]
+pub struct DependentsParams {
+    /// Tag to check for dependents in, defaults to the package's own tag
+    tag: Option<String>,
+}
+
+pub async fn get_rpm_dependents(
+    Path(pkg_id): Path<Ulid>,
+    Query(params): Query<DependentsParams>,
+) -> Result<Json<Vec<Dependent>>> {
+    let rpm = Rpm::get(pkg_id).await?.ok_or(RpmError::NotFound)?;
+    let tag = match params.tag {
+        Some(tag) => Tag::get(&tag).await?,
+        None => DB.retry(|| DB.select(rpm.tag.clone())).await?,
+    }
+    .ok_or(TagError::NotFound)?;
+
+    Ok(Json(rpm.dependents(&tag).await?))
+}
+
+pub async fn hold_rpm(identity: Identity, Path(pkg_id): Path<Ulid>) -> Result<Json<Rpm>> {
+    let rpm = Rpm::get(pkg_id).await?.ok_or(RpmError::NotFound)?;
+    identity.require(Role::Upload, &record_key(&rpm.tag)).await?;
+    Ok(Json(rpm.set_held(true).await?))
+}
+
+pub async fn release_rpm(identity: Identity, Path(pkg_id): Path<Ulid>) -> Result<Json<Rpm>> {
+    let rpm = Rpm::get(pkg_id).await?.ok_or(RpmError::NotFound)?;
+    identity.require(Role::Upload, &record_key(&rpm.tag)).await?;
+    Ok(Json(rpm.set_held(false).await?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignParams {
+    /// ID of the GPG key to sign with, defaults to the tag's signing key
+    ///
+    /// Has to be the signing key of a tag the caller can sign packages of.
+    key: Option<String>,
+}
+
+/// Sign a package, storing the signed copy alongside the original
+pub async fn sign_rpm(
+    identity: Identity,
+    Path(pkg_id): Path<Ulid>,
+    Query(params): Query<SignParams>,
+) -> Result<Json<Rpm>> {
+    let rpm = Rpm::get(pkg_id).await?.ok_or(RpmError::NotFound)?;
+    let tag_id = record_key(&rpm.tag);
+    identity.require(Role::Sign, &tag_id).await?;
+    require_unlocked(&tag_id).await?;
+
+    let key_id = match &params.key {
+        Some(key) => key.clone(),
+        None => {
+            let tag = Tag::get(&tag_id).await?.ok_or(TagError::NotFound)?;
+            let key = tag
+                .signing_key
+                .ok_or_else(|| RpmError::NoSigningKey(tag_id.clone()))?;
+            record_key(&key)
+        }
+    };
+    let key = GpgKey::get(&key_id).await?.ok_or(Error::NotFound)?;
+    if !key.allows(&tag_id) {
+        return Err(Error::Forbidden);
+    }
+    if params.key.is_some() {
+        // only keys the caller can already sign with, through one of the tags using them
+        let mut allowed = false;
+        for tag in Tag::get_by_signing_key(&key_id).await? {
+            if identity.require(Role::Sign, &tag.name).await.is_ok() {
+                allowed = true;
+                break;
+            }
+        }
+        if !allowed {
+            return Err(Error::Forbidden);
+        }
+    }
+
+    let signed = crate::signing::sign(&rpm, &key).await?;
+    LogEvent::for_rpm("sign", identity.name(), &signed, &tag_id)
+        .record()
+        .await?;
+    Ok(Json(signed))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PromoteParams {
+    /// Tag to move the package into
+    tag: String,
+    /// Mark the package as available even if a newer version is already available
+    #[serde(default)]
+    force: bool,
+    /// Start assembling the target tag after promoting, see `GET /jobs/{id}`
+    #[serde(default)]
+    assemble: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkPromoteParams {
+    packages: Vec<Ulid>,
+    #[serde(flatten)]
+    params: PromoteParams,
+}
+
+/// A promoted package, with the assembly job of the target tag if one was started
+#[derive(Debug, Serialize)]
+pub struct Promoted {
+    #[serde(flatten)]
+    rpm: Rpm,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job: Option<Job>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkPromoted {
+    packages: Vec<Rpm>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job: Option<Job>,
+}
+
+/// Move a package into another tag, i.e. from `updates-testing` to `updates`
+pub async fn promote_rpm(
+    identity: Identity,
+    Path(pkg_id): Path<Ulid>,
+    Json(params): Json<PromoteParams>,
+) -> Result<Json<Promoted>> {
+    let (rpms, job) = promote(&identity, &[pkg_id], &params).await?;
+    let rpm = rpms.into_iter().next().ok_or(RpmError::NotFound)?;
+    Ok(Json(Promoted { rpm, job }))
+}
+
+/// Move several packages into another tag, assembling it at most once
+pub async fn promote_rpms(
+    identity: Identity,
+    Json(bulk): Json<BulkPromoteParams>,
+) -> Result<Json<BulkPromoted>> {
+    let (packages, job) = promote(&identity, &bulk.packages, &bulk.params).await?;
+    Ok(Json(BulkPromoted { packages, job }))
+}
+
+/// Promote packages, returning them and the assembly job of the target tag if one was started
+async fn promote(
+    identity: &Identity,
+    ids: &[Ulid],
+    params: &PromoteParams,
+) -> Result<(Vec<Rpm>, Option<Job>)> {
+    let target = Tag::get(&params.tag).await?.ok_or(TagError::NotFound)?;
+    identity.require(Role::Upload, &target.name).await?;
+    require_rpm_repo(&target)?;
+    if target.locked {
+        return Err(TagError::Locked(target.name).into());
+    }
+    if params.assemble {
+        identity.require(Role::Assemble, &target.name).await?;
+    }
+
+    // check everything first, so a bad package doesn't leave a partial promotion
+    let mut rpms = Vec::with_capacity(ids.len());
+    for id in ids {
+        let rpm = Rpm::get(*id).await?.ok_or(RpmError::NotFound)?;
+        let source = record_key(&rpm.tag);
+        identity.require(Role::Upload, &source).await?;
+        require_unlocked(&source).await?;
+        if source == target.name {
+            return Err(RpmError::AlreadyInTag(source).into());
+        }
+        let mut candidate = rpm.clone();
+        candidate.tag = RecordId::from_table_key(TAG_TABLE, &target.name);
+        if let Some(existing) = candidate.find_duplicate().await? {
+            let existing = existing.id.id.to_raw();
+            return Err(RpmError::Duplicate(target.name.clone(), existing).into());
+        }
+        rpms.push((rpm, source));
+    }
+
+    let mut promoted = Vec::with_capacity(rpms.len());
+    for (rpm, source) in rpms {
+        let rpm = rpm.promote(&target.name, params.force).await?;
+        LogEvent::new(
+            "promote",
+            identity.name(),
+            serde_json::json!({
+                "rpm": rpm.id.id.to_raw(),
+                "nevra": rpm.nevra(),
+                "from": source,
+                "to": target.name,
+            }),
+        )
+        .tag(&target.name)
+        .resource(RPM_TABLE, &rpm.id.id.to_raw())
+        .record()
+        .await?;
+        promoted.push(rpm);
+    }
+
+    let job = if params.assemble {
+        Some(spawn_assemble(identity, target).await?)
+    } else {
+        None
+    };
+
+    Ok((promoted, job))
+}
+
+pub async fn mark_rpm_unavailable(
+    identity: Identity,
+    Path(pkg_id): Path<Ulid>,
+    Query(params): Query<TagParams>,
+) -> Result<StatusCode> {
+    let rpm = Rpm::get(pkg_id).await?.ok_or(RpmError::NotFound)?;
+    let tag = member_tag(&rpm, params.tag).await?;
+    identity.require(Role::Upload, &tag).await?;
+    require_unlocked(&tag).await?;
+    rpm.mark_unavailable_in(&tag).await?;
+    LogEvent::for_rpm("unavailable", identity.name(), &rpm, &tag)
+        .record()
+        .await?;
     Ok(StatusCode::OK)
 }
 
-pub async fn delete_rpm(Path(pkg_id): Path<Ulid>) -> Result<StatusCode> {
-    let rpm = Rpm::get(pkg_id).await?.unwrap();
+/// List the tags a package was shared into, besides its own
+pub async fn get_rpm_tags(Path(pkg_id): Path<Ulid>) -> Result<Json<Vec<TagMembership>>> {
+    let rpm = Rpm::get(pkg_id).await?.ok_or(RpmError::NotFound)?;
+    Ok(Json(TagMembership::get_by_rpm(&rpm).await?))
+}
+
+/// Share a package into another tag, without uploading it again
+pub async fn add_rpm_tag(
+    identity: Identity,
+    Path((pkg_id, tag_id)): Path<(Ulid, String)>,
+    Query(params): Query<MarkAvailableParams>,
+) -> Result<Json<TagMembership>> {
+    let rpm = Rpm::get(pkg_id).await?.ok_or(RpmError::NotFound)?;
+    Ok(Json(share_rpm(&identity, &rpm, &tag_id, params.force).await?))
+}
+
+/// Share a package into a tag for [`add_rpm_tag`] and `POST /repo/{id}/packages`
+pub(crate) async fn share_rpm(
+    identity: &Identity,
+    rpm: &Rpm,
+    tag_id: &str,
+    force: bool,
+) -> Result<TagMembership> {
+    let tag = Tag::get(tag_id).await?.ok_or(TagError::NotFound)?;
+    identity.require(Role::Upload, &tag.name).await?;
+    require_rpm_repo(&tag)?;
+    if tag.locked {
+        return Err(TagError::Locked(tag.name).into());
+    }
+    if let Some(existing) = rpm.find_in_tag(&tag.name).await? {
+        let existing = existing.id.id.to_raw();
+        return Err(RpmError::Duplicate(tag.name, existing).into());
+    }
+
+    let membership = rpm.add_to_tag(&tag.name, force).await?;
+    LogEvent::for_rpm("tag", identity.name(), rpm, &tag.name)
+        .record()
+        .await?;
+    Ok(membership)
+}
+
+/// Remove a package from a tag it was shared into
+pub async fn remove_rpm_tag(
+    identity: Identity,
+    Path((pkg_id, tag_id)): Path<(Ulid, String)>,
+) -> Result<StatusCode> {
+    let rpm = Rpm::get(pkg_id).await?.ok_or(RpmError::NotFound)?;
+    identity.require(Role::Upload, &tag_id).await?;
+    require_unlocked(&tag_id).await?;
+    TagMembership::get(&rpm, &tag_id)
+        .await?
+        .ok_or_else(|| RpmError::NotInTag(tag_id.clone()))?
+        .delete()
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_rpm(identity: Identity, Path(pkg_id): Path<Ulid>) -> Result<StatusCode> {
+    let rpm = Rpm::get(pkg_id).await?.ok_or(RpmError::NotFound)?;
+    identity.require(Role::Admin, &record_key(&rpm.tag)).await?;
+    require_unlocked(&record_key(&rpm.tag)).await?;
+    // deleting would take the package out of the tags it's shared into as well
+    let shared = TagMembership::get_by_rpm(&rpm).await?;
+    if !shared.is_empty() {
+        let tags: Vec<String> = shared.iter().map(|m| record_key(&m.tag)).collect();
+        return Err(RpmError::StillShared(tags.join(", ")).into());
+    }
     rpm.delete().await?;
+    LogEvent::for_rpm("delete", identity.name(), &rpm, &record_key(&rpm.tag))
+        .record()
+        .await?;
     Ok(StatusCode::OK)
 }
-#[debug_handler]
-pub async fn upload_rpm(
-    Query(params): Query<RpmUploadParams>,
-    mut multipart: Multipart,
-) -> Result<StatusCode> {
-    let mut filename = None;
-    let mut data = None;
+/// Reject RPMs going into a tag that only holds other kinds of packages
+pub fn require_rpm_repo(tag: &Tag) -> Result<()> {
+    if tag.repo_type.accepts_rpms() {
+        Ok(())
+    } else {
+        let repo_type = tag.repo_type.as_str().to_owned();
+        Err(RpmError::WrongRepoType(tag.name.clone(), repo_type).into())
+    }
+}
 
-    let mut tag = None;
+/// A parsed upload, after its file has been handled
+#[derive(Debug, Clone)]
+pub enum StoredUpload {
+    /// A new package pushed to the object store, not committed to the database yet
+    New(Rpm),
+    /// The same file was already uploaded to the tag, nothing was stored
+    Duplicate(Rpm),
+}
+
+impl StoredUpload {
+    pub fn rpm(&self) -> &Rpm {
+        match self {
+            Self::New(rpm) | Self::Duplicate(rpm) => rpm,
+        }
+    }
+}
+
+/// Parse an uploaded RPM and push it to the object store & cache
+pub async fn store_upload(filename: &str, data: &[u8], tag: &str) -> Result<StoredUpload> {
+    tracing::info!("filename: {:?}", filename);
+    let dest = CONFIG.get().unwrap().cache_dir.join(filename);
+    tracing::info!("dest: {:?}", dest);
+
+    tokio::fs::write(&dest, data).await?;
+
+    store_upload_path(&dest, tag).await
+}
+
+/// Parse an RPM already staged in the cache dir and push it to the object store
+///
+/// If a package with the same NEVRA and digest is already in the tag, the
+/// staged file is dropped and the existing record is returned instead. The tag must exist and
+/// be visible from the current namespace scope.
+pub async fn store_upload_path(dest: &std::path::PathBuf, tag: &str) -> Result<StoredUpload> {
+    let record = Tag::get(tag).await?.ok_or(TagError::NotFound)?;
+    require_rpm_repo(&record)?;
+    let rpm =
+        Rpm::from_path(dest, tag).map_err(|e| RpmError::InvalidPackage(format!("{e:#}")))?;
+    tracing::trace!("RPM: {:?}", rpm);
+
+    if let Some(existing) = rpm.find_duplicate().await? {
+        tracing::info!(existing = ?existing.id, "package was already uploaded, skipping");
+        let _ = tokio::fs::remove_file(dest).await;
+        return Ok(StoredUpload::Duplicate(existing));
+    }
+
+    // Now push and upload to object store & cache, unless the content-addressed object is already stored
+
+    if rpm.acquire_object().await? {
+        if let Err(e) = object_store().put(&rpm.object_key, dest).await {
+            rpm.release_object().await?;
+            return Err(e.into());
+        }
+    } else {
+        tracing::debug!(key = ?rpm.object_key, "object already stored");
+        let _ = tokio::fs::remove_file(dest).await;
+    }
+
+    Ok(StoredUpload::New(rpm))
+}
+
+/// Commit a stored upload to the database and announce it
+///
+/// Uploads to tags signing packages on upload are signed before they're committed, so a
+/// package that fails to sign is rejected rather than published unsigned. Duplicates are
+/// left untouched, except for signing them if they weren't yet.
+pub async fn finish_upload(
+    identity: &Identity,
+    upload: &StoredUpload,
+    tag: &str,
+    prune: bool,
+    force: bool,
+) -> Result<Rpm> {
+    let key = auto_sign_key(tag).await?;
+    let rpm = match (upload, &key) {
+        (StoredUpload::New(rpm), _) => rpm,
+        (StoredUpload::Duplicate(rpm), Some(key)) if rpm.signed_object_key.is_none() => {
+            return Ok(crate::signing::sign(rpm, key).await?);
+        }
+        (StoredUpload::Duplicate(rpm), _) => return Ok(rpm.clone()),
+    };
+
+    let rpm = match &key {
+        Some(key) => match crate::signing::sign_object(rpm, key).await {
+            Ok(signed) => signed,
+            Err(e) => {
+                rpm.release_object().await?;
+                return Err(e.into());
+            }
+        },
+        None => rpm.clone(),
+    };
+    if let Err(e) = rpm.commit_to_db(prune, force).await {
+        discard_upload(&rpm).await?;
+        return Err(e.into());
+    }
+    announce_upload(identity, &rpm, tag).await?;
+    Ok(rpm)
+}
+
+/// Drop the objects of an upload that wasn't committed
+async fn discard_upload(rpm: &Rpm) -> Result<()> {
+    if let Some(signed) = &rpm.signed_object_key {
+        rpm.remove_signed_object(signed).await;
+    }
+    rpm.release_object().await?;
+    Ok(())
+}
+
+/// Key to sign uploads to a tag with, if the tag signs packages on upload, see [`Tag::auto_sign`]
+async fn auto_sign_key(tag: &str) -> Result<Option<GpgKey>> {
+    match Tag::get(tag).await? {
+        Some(tag) if tag.auto_sign && tag.signing_key.is_some() => {
+            Ok(Some(tag.get_signing_key().await?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Notify subscribers of a new package and record it in the event log
+async fn announce_upload(identity: &Identity, rpm: &Rpm, tag: &str) -> Result<()> {
+    notify(Notification::new(
+        NotificationKind::Upload,
+        Some(tag),
+        format!("uploaded {}-{}-{}.{}", rpm.name, rpm.version, rpm.release, rpm.arch),
+    ));
+    LogEvent::for_rpm("upload", identity.name(), rpm, tag)
+        .record()
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RawUploadParams {
+    tag: String,
+    #[serde(default)]
+    prune: bool,
+    /// Mark the upload as latest even if a newer version is already available
+    #[serde(default)]
+    force: bool,
+    /// File name to stage the package as, generated if unset
+    filename: Option<String>,
+}
+
+/// Write a request body to disk chunk by chunk, up to the upload size limit
+pub async fn stream_to_file(body: Body, dest: &std::path::PathBuf) -> Result<()> {
+    let mut file = tokio::fs::File::create(dest).await?;
+    let mut stream = body.into_data_stream();
+    let mut written = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| eyre!("failed to read upload body: {e}"))?;
+        written += chunk.len() as u64;
+        check_size(written)?;
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// Upload an RPM sent as the raw request body
+///
+/// The body is streamed to disk and then to the object store, so the package
+/// is never held in memory as a whole.
+pub async fn upload_rpm_raw(
+    identity: Identity,
+    Query(params): Query<RawUploadParams>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<RpmRef>> {
+    identity.require(Role::Upload, &params.tag).await?;
+    require_unlocked(&params.tag).await?;
+    let cache_dir = &CONFIG.get().unwrap().cache_dir;
+    check_upload(cache_dir, content_length(&headers))?;
+
+    // Only keep the final path component so the file stays in the cache dir
+    let filename = params
+        .filename
+        .as_deref()
+        .and_then(|f| std::path::Path::new(f).file_name())
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_else(|| format!("{}.rpm", Ulid::new()));
+    let dest = cache_dir.join(&filename);
+    tracing::info!("dest: {:?}", dest);
+
+    let staged = match stream_to_file(body, &dest).await {
+        Ok(()) => store_upload_path(&dest, &params.tag).await,
+        Err(e) => Err(e),
+    };
 
-    while let Some(field) = multipart.next_field().await.unwrap() {
+    let upload = match staged {
+        Ok(upload) => upload,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&dest).await;
+            return Err(e);
+        }
+    };
+
+    let rpm = finish_upload(&identity, &upload, &params.tag, params.prune, params.force).await?;
+
+    Ok(Json(RpmRef::from(&rpm)))
+}
+
+/// Outcome of a single file in a batch upload
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadResult {
+    pub filename: String,
+    /// The stored package
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rpm: Option<RpmRef>,
+    /// The same file was already in the tag, so the existing package was returned
+    pub duplicate: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Update the result of the uploaded file that stored a package
+fn set_upload_result(
+    results: &mut [UploadResult],
+    rpm: &Rpm,
+    update: impl FnOnce(&mut UploadResult),
+) {
+    let id = RpmRef::from(rpm).id;
+    if let Some(result) = results
+        .iter_mut()
+        .find(|r| r.rpm.as_ref().is_some_and(|r| r.id == id))
+    {
+        update(result);
+    }
+}
+
+/// Stage every `file_upload` field of a multipart form in the cache dir, returning the tag field
+///
+/// Staged files are pushed to `files` as they're created, so the caller can clean them up.
+async fn receive_multipart(
+    multipart: &mut Multipart,
+    cache_dir: &std::path::Path,
+    files: &mut Vec<(String, std::path::PathBuf)>,
+) -> Result<Option<String>> {
+    let mut tag = None;
+    while let Some(mut field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| multipart_error(e, "invalid multipart body"))?
+    {
         let name = field.name();
         if name == Some("file_upload") {
-            filename = field.file_name().map(|f| f.to_string());
-            data = field.bytes().await.ok();
+            let Some(filename) = field.file_name().map(|f| f.to_string()) else {
+                continue;
+            };
+            // stage under a unique name, so files with the same name don't collide
+            let staged = cache_dir.join(format!(
+                "{}-{}",
+                Ulid::new(),
+                std::path::Path::new(&filename)
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+            ));
+            let mut file = tokio::fs::File::create(&staged).await?;
+            files.push((filename, staged));
+            while let Some(chunk) = field
+                .chunk()
+                .await
+                .map_err(|e| multipart_error(e, "failed to read upload"))?
+            {
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
         } else if name == Some("id") || name == Some("tag") {
             tag = field.text().await.ok();
         }
     }
+    Ok(tag)
+}
 
-    if let (Some(filename), Some(data), Some(tag)) = (filename, data, tag) {
-        let objstore = object_store();
-        tracing::info!("filename: {:?}", filename);
-        // tracing::info!("data: {:?}", data);
-        let dest = CONFIG.get().unwrap().cache_dir.join(filename);
-        tracing::info!("dest: {:?}", dest);
+/// Map a multipart error, keeping the body limit being hit as 413 Payload Too Large
+fn multipart_error(e: MultipartError, context: &str) -> Error {
+    if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        Error::PayloadTooLarge(e.body_text())
+    } else {
+        eyre!("{context}: {e}").into()
+    }
+}
 
-        tokio::fs::write(&dest, &data).await?;
+/// Upload one or more RPMs as `file_upload` fields of a multipart form
+///
+/// Every file that parses is committed in a single transaction, the response
+/// lists the outcome of each file.
+#[debug_handler]
+pub async fn upload_rpm(
+    identity: Identity,
+    Query(params): Query<RpmUploadParams>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Response> {
+    let cache_dir = &CONFIG.get().unwrap().cache_dir;
+    check_upload(cache_dir, content_length(&headers))?;
+    // (original file name, staged path)
+    let mut files = Vec::new();
 
-        let rpm = Rpm::from_path(&dest, &tag)?;
-        tracing::trace!("RPM: {:?}", rpm);
+    let tag = match receive_multipart(&mut multipart, cache_dir, &mut files).await {
+        Ok(tag) => tag,
+        Err(e) => {
+            for (_, staged) in files {
+                let _ = tokio::fs::remove_file(staged).await;
+            }
+            return Err(e);
+        }
+    };
+    let Some(tag) = tag.filter(|_| !files.is_empty()) else {
+        for (_, staged) in files {
+            let _ = tokio::fs::remove_file(staged).await;
+        }
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    let allowed = match identity.require(Role::Upload, &tag).await {
+        Ok(()) => require_unlocked(&tag).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = allowed {
+        for (_, staged) in files {
+            let _ = tokio::fs::remove_file(staged).await;
+        }
+        return Err(e);
+    }
 
-        // Now push and upload to object store & cache
+    let mut results = Vec::with_capacity(files.len());
+    let mut rpms = Vec::new();
+    let mut duplicates = Vec::new();
+    for (filename, staged) in files {
+        match store_upload_path(&staged, &tag).await {
+            Ok(upload) => {
+                results.push(UploadResult {
+                    filename,
+                    rpm: Some(RpmRef::from(upload.rpm())),
+                    duplicate: matches!(upload, StoredUpload::Duplicate(_)),
+                    error: None,
+                });
+                match upload {
+                    StoredUpload::New(rpm) => rpms.push(rpm),
+                    StoredUpload::Duplicate(rpm) => duplicates.push(rpm),
+                }
+            }
+            Err(e) => {
+                tracing::warn!(?filename, "failed to store upload: {e}");
+                let _ = tokio::fs::remove_file(&staged).await;
+                results.push(UploadResult {
+                    filename,
+                    rpm: None,
+                    duplicate: false,
+                    error: Some(e.to_string()),
+                });
+            }
+        }
+    }
 
-        objstore.put(&rpm.object_key, &dest).await?;
+    // Sign before committing, so a package failing to sign is left out instead of
+    // being published unsigned
+
+    if let Some(key) = auto_sign_key(&tag).await? {
+        let signed = crate::signing::sign_objects(&rpms, &key).await;
+        let mut committed = Vec::with_capacity(rpms.len());
+        for (rpm, res) in rpms.iter().zip(signed) {
+            match res {
+                Ok(signed) => committed.push(signed),
+                Err(e) => {
+                    tracing::warn!(rpm = ?rpm.id, ?e, "failed to sign upload");
+                    rpm.release_object().await?;
+                    set_upload_result(&mut results, rpm, |result| {
+                        result.rpm = None;
+                        result.error = Some(format!("failed to sign: {e:#}"));
+                    });
+                }
+            }
+        }
+        rpms = committed;
 
-        // Now commit to db
+        let unsigned: Vec<Rpm> = duplicates
+            .into_iter()
+            .filter(|rpm| rpm.signed_object_key.is_none())
+            .collect();
+        let signed = crate::signing::sign_all(&unsigned, &key).await;
+        for (rpm, res) in unsigned.iter().zip(signed) {
+            set_upload_result(&mut results, rpm, |result| match res {
+                Ok(signed) => result.rpm = Some(RpmRef::from(&signed)),
+                Err(e) => result.error = Some(format!("failed to sign: {e:#}")),
+            });
+        }
+    }
 
-        rpm.commit_to_db(params.prune).await?;
+    // Now commit to db
 
-        Ok(StatusCode::OK)
-    } else {
-        Ok(StatusCode::from_u16(400).unwrap())
+    if let Err(e) = Rpm::insert_all(&rpms, params.prune, params.force).await {
+        for rpm in &rpms {
+            discard_upload(rpm).await?;
+        }
+        return Err(e.into());
+    }
+    for rpm in &rpms {
+        set_upload_result(&mut results, rpm, |result| {
+            result.rpm = Some(RpmRef::from(rpm));
+        });
+        announce_upload(&identity, rpm, &tag).await?;
     }
 
-    // StatusCode::from_u16(500).unwrap()
+    Ok(Json(results).into_response())
 }
@@ -8,10 +8,13 @@
 //! - Unavailable artifacts are no longer deleted, but marked as such
 //! - Exported repos are now rebuilt from scratch when a new artifact is marked available
 use axum::{
-    extract::Path,
+    extract::{Path, Query},
     http::StatusCode,
-    response::Json,
-    routing::{delete, get, post},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Json,
+    },
+    routing::{delete, get, patch, post, put},
     Router,
 };
 
@@ -25,26 +28,68 @@ pub enum TagError {
     #[error("Tag already exists")]
     #[status_code("CONFLICT")]
     AlreadyExists,
+    #[error("Invalid comps: {0}")]
+    #[status_code("BAD_REQUEST")]
+    InvalidComps(String),
+    #[error("Invalid modulemd: {0}")]
+    #[status_code("BAD_REQUEST")]
+    InvalidModules(String),
+    #[error("Invalid schedule: {0}")]
+    #[status_code("BAD_REQUEST")]
+    InvalidSchedule(String),
+    #[error("Invalid export path: {0}")]
+    #[status_code("BAD_REQUEST")]
+    InvalidExportPath(String),
+    #[error("Invalid file path: {0}")]
+    #[status_code("BAD_REQUEST")]
+    InvalidFilePath(String),
+    #[error("Invalid URL: {0}")]
+    #[status_code("BAD_REQUEST")]
+    InvalidUrl(String),
+    #[error("Either a build or a Koji tag must be given, not both")]
+    #[status_code("BAD_REQUEST")]
+    InvalidKojiSource,
+    #[error("Tag {0} is locked")]
+    #[status_code("LOCKED")]
+    Locked(String),
+    #[error("Tag names can't end in -debug, it's reserved for debuginfo repos")]
+    #[status_code("BAD_REQUEST")]
+    ReservedName,
+    #[error("require_signed needs a signing key to be set first")]
+    #[status_code("BAD_REQUEST")]
+    NoSigningKey,
 }
 
 use crate::errors::Result;
 
-// single enum for now
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum RepoType {
-    Rpm,
-}
-
 use serde::{Deserialize, Serialize};
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateTag {
     name: String,
-    #[serde(rename = "type")]
+    #[serde(rename = "type", default)]
     repo_type: RepoType,
 }
 
-use crate::db::{rpm::RpmRef, tag::Tag};
+use crate::auth::Identity;
+use crate::config::CONFIG;
+use crate::db::{
+    event_log::LogEvent,
+    gpg_key::GpgKey,
+    job::Job,
+    membership::TagMembership,
+    permission::Role,
+    rpm::{Nevra, Rpm, RpmRef, RPM_TABLE},
+    tag::{
+        NoarchPolicy, RepoType, RetentionPolicy, Tag, TagCompose, DEBUG_SUBREPO, TAG_TABLE,
+    },
+};
+use crate::koji::KojiSource;
+use crate::router::rpm::{require_rpm_repo, share_rpm};
+use crate::notify::{notify, Notification, NotificationKind};
+use crate::progress;
+use futures::Stream;
+use tokio::sync::broadcast::error::RecvError;
+use crate::repodata;
 
 pub fn route() -> Router {
     Router::new()
@@ -58,9 +103,25 @@ fn route_operations() -> Router {
         .route("/", get(get_all_tags))
         .route("/{id}", get(get_tag))
         .route("/{id}", delete(delete_tag))
+        .route("/{id}", patch(update_tag))
         .route("/{id}/key", post(set_gpg_key))
+        .route("/{id}/comps", put(upload_comps))
+        .route("/{id}/comps/import", post(import_comps))
+        .route("/{id}/modules", put(upload_modules))
+        .route("/{id}/modules", delete(delete_modules))
         .route("/{id}/rpms", get(get_tag_rpms))
+        .route("/{id}/packages", post(add_tag_package))
+        .route("/{id}/packages/{ulid}", delete(remove_tag_package))
         .route("/{id}/assemble", post(assemble_tag))
+        .route("/{id}/prefetch", post(prefetch_tag))
+        .route("/{id}/sync", post(sync_tag))
+        .route("/{id}/koji", post(koji_import))
+        .route("/{id}/assemble/events", get(assemble_events))
+        .route("/{id}/prune", post(prune_tag))
+        .route("/{id}/sign", post(sign_tag))
+        .route("/{id}/lock", post(lock_tag))
+        .route("/{id}/unlock", post(unlock_tag))
+        .route("/{id}/rollback/{compose_id}", post(rollback_tag))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -68,6 +129,68 @@ pub struct SetGpgKey {
     key_id: String,
 }
 
+/// Partial update of a tag's settings, unset fields are left unchanged
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateTag {
+    #[serde(default)]
+    auto_sign: Option<bool>,
+    #[serde(default)]
+    require_signed: Option<bool>,
+    #[serde(default)]
+    build_images: Option<bool>,
+    #[serde(default)]
+    arches: Option<Vec<String>>,
+    #[serde(default)]
+    split_arches: Option<bool>,
+    #[serde(default)]
+    noarch: Option<NoarchPolicy>,
+    #[serde(default)]
+    split_debuginfo: Option<bool>,
+    #[serde(default)]
+    keep_versions: Option<u32>,
+    #[serde(default)]
+    deltas: Option<bool>,
+    #[serde(default)]
+    zchunk: Option<bool>,
+    #[serde(default)]
+    retention: Option<RetentionPolicy>,
+    /// Cron expression to assemble the tag on, an empty string removes the schedule
+    #[serde(default)]
+    schedule: Option<String>,
+    /// Export path template of the tag, an empty string publishes it under the export dir again
+    #[serde(default)]
+    export_path: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportComps {
+    /// URL of a remote yum repository, or of a comps file directly
+    url: String,
+    /// Group IDs to leave out of the imported comps
+    #[serde(default)]
+    exclude_groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncTag {
+    /// Base URL of the remote yum repository to mirror
+    url: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KojiImport {
+    /// URL of the hub's XML-RPC API, i.e. `https://koji.fedoraproject.org/kojihub`
+    hub: String,
+    /// Base URL of the hub's package files, i.e. `https://kojipkgs.fedoraproject.org`
+    topurl: String,
+    /// NVR of a build to import
+    #[serde(default)]
+    build: Option<String>,
+    /// Koji tag whose latest builds to import, instead of a single build
+    #[serde(default)]
+    koji_tag: Option<String>,
+}
+
 pub async fn get_tag(Path(tag_id): Path<String>) -> Result<Json<Tag>> {
     let tag = Tag::get(&tag_id)
         .await?
@@ -76,15 +199,166 @@ pub async fn get_tag(Path(tag_id): Path<String>) -> Result<Json<Tag>> {
 }
 
 pub async fn set_gpg_key(
+    identity: Identity,
     Path(tag_id): Path<String>,
     Json(key): Json<SetGpgKey>,
 ) -> Result<Json<Tag>> {
+    identity.require(Role::Admin, &tag_id).await?;
+    let mut tag = Tag::get(&tag_id)
+        .await?
+        .ok_or_else(|| TagError::NotFound)?;
+    let key = GpgKey::get(&key.key_id)
+        .await?
+        .ok_or(crate::errors::Error::NotFound)?;
+    if !key.allows(&tag.name) {
+        return Err(crate::errors::Error::Forbidden);
+    }
+    tag.set_gpg_key(&key.id.id.to_raw());
+    let tag = tag.save().await?;
+    LogEvent::new(
+        "set_signing_key",
+        identity.name(),
+        serde_json::json!({ "key": key.id.id.to_raw() }),
+    )
+    .tag(&tag.name)
+    .resource(TAG_TABLE, &tag.name)
+    .record()
+    .await?;
+
+    Ok(Json(tag))
+}
+
+pub async fn update_tag(
+    identity: Identity,
+    Path(tag_id): Path<String>,
+    Json(update): Json<UpdateTag>,
+) -> Result<Json<Tag>> {
+    identity.require(Role::Admin, &tag_id).await?;
+    let mut tag = Tag::get(&tag_id)
+        .await?
+        .ok_or_else(|| TagError::NotFound)?;
+    let old = tag.clone();
+
+    if let Some(auto_sign) = update.auto_sign {
+        tag.auto_sign = auto_sign;
+    }
+    if let Some(require_signed) = update.require_signed {
+        // without a key nothing could ever be signed, so assembly would always fail
+        if require_signed && tag.signing_key.is_none() {
+            return Err(TagError::NoSigningKey.into());
+        }
+        tag.require_signed = require_signed;
+    }
+    if let Some(build_images) = update.build_images {
+        tag.build_images = build_images;
+    }
+    if let Some(arches) = update.arches {
+        tag.arches = arches;
+    }
+    if let Some(split_arches) = update.split_arches {
+        tag.split_arches = split_arches;
+    }
+    if let Some(noarch) = update.noarch {
+        tag.noarch = noarch;
+    }
+    if let Some(split_debuginfo) = update.split_debuginfo {
+        // tags from before the suffix was reserved could take the debuginfo repo's place
+        let debug_tag = format!("{}-{DEBUG_SUBREPO}", tag.name);
+        if split_debuginfo && Tag::exists(&debug_tag).await? {
+            return Err(crate::errors::Error::Conflict(format!(
+                "tag {debug_tag} already exists, its repo would be replaced by the debuginfo repo"
+            )));
+        }
+        tag.split_debuginfo = split_debuginfo;
+    }
+    if let Some(keep_versions) = update.keep_versions {
+        tag.keep_versions = keep_versions.max(1);
+    }
+    if let Some(deltas) = update.deltas {
+        tag.deltas = deltas;
+    }
+    if let Some(zchunk) = update.zchunk {
+        tag.zchunk = zchunk;
+    }
+    if let Some(retention) = update.retention {
+        tag.retention = retention;
+    }
+    if let Some(schedule) = update.schedule {
+        if schedule.is_empty() {
+            tag.schedule = None;
+        } else {
+            crate::scheduler::parse_schedule(&schedule)
+                .map_err(|e| TagError::InvalidSchedule(e.to_string()))?;
+            tag.schedule = Some(schedule);
+        }
+    }
+    let moved = update.export_path.is_some();
+    if let Some(export_path) = update.export_path {
+        // the tag is published wherever its export path points, so only admins may move it
+        identity.require_admin()?;
+        if export_path.is_empty() {
+            tag.export_path = None;
+        } else {
+            Tag::validate_export_path(&export_path).map_err(TagError::InvalidExportPath)?;
+            tag.export_path = Some(export_path);
+        }
+    }
+    if tag.exports_by_arch() && !tag.split_arches {
+        return Err(TagError::InvalidExportPath(
+            "`{arch}` needs split_arches to be set".to_owned(),
+        )
+        .into());
+    }
+    if moved {
+        if let Some(other) = tag.export_conflict().await? {
+            return Err(crate::errors::Error::Conflict(format!(
+                "export path overlaps the export path of tag {other}"
+            )));
+        }
+    }
+
+    let tag = tag.save().await?;
+    if moved {
+        tag.move_export(&old).await?;
+    }
+    Ok(Json(tag))
+}
+
+pub async fn import_comps(
+    identity: Identity,
+    Path(tag_id): Path<String>,
+    Json(req): Json<ImportComps>,
+) -> Result<Json<Tag>> {
+    identity.require(Role::Admin, &tag_id).await?;
     let mut tag = Tag::get(&tag_id)
         .await?
         .ok_or_else(|| TagError::NotFound)?;
-    let key = key.key_id;
-    tag.set_gpg_key(&key);
 
+    parse_remote_url(&req.url)?;
+    let comps = repodata::comps::fetch_comps(&req.url).await?;
+    // validated after excluding groups, so excluding everything is refused too
+    let comps = repodata::comps::exclude_groups(&comps, &req.exclude_groups)
+        .and_then(|comps| repodata::comps::validate(&comps).map(|()| comps))
+        .map_err(|e| TagError::InvalidComps(e.to_string()))?;
+
+    tag.set_comps(comps).await?;
+    Ok(Json(tag.save().await?))
+}
+
+/// Replace the tag's comps with an uploaded comps XML document
+pub async fn upload_comps(
+    identity: Identity,
+    Path(tag_id): Path<String>,
+    comps: String,
+) -> Result<Json<Tag>> {
+    identity.require(Role::Admin, &tag_id).await?;
+    let mut tag = Tag::get(&tag_id)
+        .await?
+        .ok_or_else(|| TagError::NotFound)?;
+
+    repodata::comps::validate(&comps).map_err(|e| TagError::InvalidComps(e.to_string()))?;
+
+    tag.set_comps(comps).await?;
     Ok(Json(tag.save().await?))
 }
 
@@ -97,33 +371,397 @@ pub async fn get_tag_rpms(Path(tag_id): Path<String>) -> Result<Json<Vec<RpmRef>
     Ok(Json(rpms))
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddPackage {
+    /// ULID or `name-[epoch:]version-release.arch` of an already uploaded package
+    package: String,
+    /// Mark the package as available even if a newer version is already available
+    #[serde(default)]
+    force: bool,
+}
+
+/// Add an already uploaded package to the tag, without uploading it again
+///
+/// Like `POST /rpm/{id}/tags/{tag}`, looking the package up by ULID or NEVRA.
+pub async fn add_tag_package(
+    identity: Identity,
+    Path(tag_id): Path<String>,
+    Json(req): Json<AddPackage>,
+) -> Result<(StatusCode, Json<TagMembership>)> {
+    // before looking the package up, so its existence isn't leaked
+    identity.require(Role::Upload, &tag_id).await?;
+    let rpm = match req.package.parse::<ulid::Ulid>() {
+        Ok(id) => Rpm::get(id).await?,
+        Err(_) => match req.package.parse::<Nevra>() {
+            Ok(nevra) => Rpm::get_by_nevra(&nevra).await?,
+            Err(_) => None,
+        },
+    }
+    .ok_or(crate::errors::Error::NotFound)?;
+
+    let membership = share_rpm(&identity, &rpm, &tag_id, req.force).await?;
+    Ok((StatusCode::CREATED, Json(membership)))
+}
+
+/// Remove a package from the tag, without deleting it or removing it from other tags
+pub async fn remove_tag_package(
+    identity: Identity,
+    Path((tag_id, pkg_id)): Path<(String, ulid::Ulid)>,
+) -> Result<StatusCode> {
+    identity.require(Role::Upload, &tag_id).await?;
+    require_unlocked(&tag_id).await?;
+    let rpm = Rpm::get(pkg_id).await?.ok_or(crate::errors::Error::NotFound)?;
+
+    let home = crate::db::record_key(&rpm.tag);
+    let shared = TagMembership::get_by_rpm(&rpm).await?;
+    if home != tag_id && !shared.iter().any(|m| crate::db::record_key(&m.tag) == tag_id) {
+        return Err(crate::errors::Error::NotFound);
+    }
+    if home == tag_id && shared.is_empty() {
+        return Err(crate::errors::Error::Conflict(format!(
+            "{pkg_id} is only in {tag_id}, delete it instead"
+        )));
+    }
+
+    rpm.remove_from_tag(&tag_id).await?;
+    LogEvent::new(
+        "untag",
+        identity.name(),
+        serde_json::json!({
+            "rpm": pkg_id.to_string(),
+            "nevra": rpm.nevra(),
+            "tag": tag_id,
+        }),
+    )
+    .tag(&tag_id)
+    .resource(RPM_TABLE, &pkg_id.to_string())
+    .record()
+    .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
 pub async fn get_all_tags() -> Result<Json<Vec<Tag>>> {
     let tags = Tag::get_all().await?;
     Ok(Json(tags))
 }
 // #[debug_handler]
-pub async fn create_tag(tag: Json<CreateTag>) -> Result<(StatusCode, Json<Tag>)> {
-    let existing_tag = Tag::get(&tag.name).await?;
-    if existing_tag.is_some() {
+pub async fn create_tag(
+    identity: Identity,
+    tag: Json<CreateTag>,
+) -> Result<(StatusCode, Json<Tag>)> {
+    identity.require(Role::Admin, &tag.name).await?;
+    if tag.name.ends_with(&format!("-{DEBUG_SUBREPO}")) {
+        return Err(TagError::ReservedName.into());
+    }
+    if Tag::exists(&tag.name).await? {
         return Err(TagError::AlreadyExists.into());
     }
-    let tag = Tag::new(tag.name.clone());
+    let mut new_tag = Tag::new(tag.name.clone());
+    new_tag.repo_type = tag.repo_type;
+    if let Some(other) = new_tag.export_conflict().await? {
+        return Err(crate::errors::Error::Conflict(format!(
+            "tag {other} is published at this tag's export path"
+        )));
+    }
 
-    Ok((StatusCode::CREATED, Json(tag.save().await?)))
+    Ok((StatusCode::CREATED, Json(new_tag.save().await?)))
 }
 
-pub async fn delete_tag(Path(tag_id): Path<String>) -> Result<StatusCode> {
+pub async fn delete_tag(identity: Identity, Path(tag_id): Path<String>) -> Result<StatusCode> {
+    identity.require(Role::Admin, &tag_id).await?;
     let tag = Tag::get(&tag_id)
         .await?
         .ok_or_else(|| crate::errors::Error::NotFound)?;
+    if tag.locked {
+        return Err(TagError::Locked(tag.name).into());
+    }
     tag.delete().await?;
+    LogEvent::new("delete", identity.name(), serde_json::json!({}))
+        .tag(&tag.name)
+        .resource(TAG_TABLE, &tag.name)
+        .record()
+        .await?;
     Ok(StatusCode::NO_CONTENT)
 }
 
-pub async fn assemble_tag(Path(tag_id): Path<String>) -> Result<StatusCode> {
+/// Start assembling the tag in the background, returning the job to poll with `GET /jobs/{id}`
+pub async fn assemble_tag(
+    identity: Identity,
+    Path(tag_id): Path<String>,
+) -> Result<(StatusCode, Json<Job>)> {
+    identity.require(Role::Assemble, &tag_id).await?;
     let tag = Tag::get(&tag_id)
         .await?
         .ok_or_else(|| crate::errors::Error::NotFound)?;
-    tag.assemble().await?;
-    Ok(StatusCode::ACCEPTED)
+
+    let job = spawn_assemble(&identity, tag).await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Start an assembly job for the tag, notifying subscribers if it fails
+pub(crate) async fn spawn_assemble(identity: &Identity, tag: Tag) -> Result<Job> {
+    let job = Job::new("assemble", Some(&tag.name)).save().await?;
+    job_event("assemble", identity, &job, &tag.name).await?;
+    job.clone().spawn(async move {
+        let res = tag.assemble().await;
+        if let Err(e) = &res {
+            notify(Notification::new(
+                NotificationKind::AssembleFailed,
+                Some(&tag.name),
+                format!("assembly failed: {e}"),
+            ));
+        }
+        res
+    });
+    Ok(job)
+}
+
+/// Start downloading every available package of the tag into the cache in the background
+///
+/// The job's result counts the packages downloaded, and lists those that failed.
+pub async fn prefetch_tag(
+    identity: Identity,
+    Path(tag_id): Path<String>,
+) -> Result<(StatusCode, Json<Job>)> {
+    identity.require(Role::Assemble, &tag_id).await?;
+    let tag = Tag::get(&tag_id).await?.ok_or(TagError::NotFound)?;
+
+    let concurrency = CONFIG.get().unwrap().prefetch_concurrency;
+    let job = Job::new("prefetch", Some(&tag.name)).save().await?;
+    job_event("prefetch", &identity, &job, &tag.name).await?;
+    job.clone().spawn(async move { tag.prefetch(concurrency).await });
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Start mirroring the packages of a remote yum repository into the tag in the background
+///
+/// The job's result lists the imported packages, and those that failed to import.
+pub async fn sync_tag(
+    identity: Identity,
+    Path(tag_id): Path<String>,
+    Json(req): Json<SyncTag>,
+) -> Result<(StatusCode, Json<Job>)> {
+    identity.require(Role::Upload, &tag_id).await?;
+    let tag = Tag::get(&tag_id).await?.ok_or(TagError::NotFound)?;
+    require_unlocked(&tag.name).await?;
+    require_rpm_repo(&tag)?;
+    parse_remote_url(&req.url)?;
+
+    let concurrency = CONFIG.get().unwrap().prefetch_concurrency;
+    let job = Job::new("sync", Some(&tag.name)).save().await?;
+    job_event("sync", &identity, &job, &tag.name).await?;
+    job.clone()
+        .spawn(async move { crate::mirror::sync(identity, tag, req.url, concurrency).await });
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Start importing the RPMs of a Koji build, or of the latest builds in a Koji tag, into the
+/// tag in the background
+///
+/// The job's result lists the imported packages, and those that failed to import.
+pub async fn koji_import(
+    identity: Identity,
+    Path(tag_id): Path<String>,
+    Json(req): Json<KojiImport>,
+) -> Result<(StatusCode, Json<Job>)> {
+    identity.require(Role::Upload, &tag_id).await?;
+    let tag = Tag::get(&tag_id).await?.ok_or(TagError::NotFound)?;
+    require_unlocked(&tag.name).await?;
+    require_rpm_repo(&tag)?;
+    parse_remote_url(&req.hub)?;
+    parse_remote_url(&req.topurl)?;
+    let source = match (req.build, req.koji_tag) {
+        (Some(nvr), None) => KojiSource::Build(nvr),
+        (None, Some(koji_tag)) => KojiSource::Tag(koji_tag),
+        _ => return Err(TagError::InvalidKojiSource.into()),
+    };
+
+    let concurrency = CONFIG.get().unwrap().prefetch_concurrency;
+    let job = Job::new("koji", Some(&tag.name)).save().await?;
+    job_event("koji", &identity, &job, &tag.name).await?;
+    job.clone().spawn(async move {
+        crate::koji::import(identity, tag, req.hub, req.topurl, source, concurrency).await
+    });
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Parse the URL of a remote server to pull from, see [`crate::remote::check_url`]
+fn parse_remote_url(url: &str) -> Result<reqwest::Url> {
+    Ok(crate::remote::check_url(url).map_err(|e| TagError::InvalidUrl(format!("{url}: {e}")))?)
+}
+
+/// Start signing every unsigned available package of the tag in the background
+///
+/// The job's result lists each package, with an error for those that failed to sign.
+pub async fn sign_tag(
+    identity: Identity,
+    Path(tag_id): Path<String>,
+) -> Result<(StatusCode, Json<Job>)> {
+    identity.require(Role::Sign, &tag_id).await?;
+    let tag = Tag::get(&tag_id).await?.ok_or(TagError::NotFound)?;
+    let Some(key) = &tag.signing_key else {
+        return Err(crate::errors::Error::Conflict(format!(
+            "{} has no signing key",
+            tag.name
+        )));
+    };
+    let key = GpgKey::get(&crate::db::record_key(key))
+        .await?
+        .ok_or(crate::errors::Error::NotFound)?;
+    if !key.allows(&tag.name) {
+        return Err(crate::errors::Error::Forbidden);
+    }
+
+    let job = Job::new("sign", Some(&tag.name)).save().await?;
+    job_event("sign", &identity, &job, &tag.name).await?;
+    job.clone().spawn(async move { tag.sign_unsigned().await });
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Record a job started on a tag in the event log
+async fn job_event(action: &str, identity: &Identity, job: &Job, tag: &str) -> Result<()> {
+    LogEvent::new(
+        action,
+        identity.name(),
+        serde_json::json!({ "job": job.id.id.to_raw() }),
+    )
+    .tag(tag)
+    .resource(TAG_TABLE, tag)
+    .record()
+    .await?;
+    Ok(())
+}
+
+/// Stream the progress of the tag's assemblies as Server-Sent Events
+///
+/// Only assemblies started after subscribing are reported.
+pub async fn assemble_events(
+    Path(tag_id): Path<String>,
+) -> Result<Sse<impl Stream<Item = std::result::Result<Event, axum::Error>>>> {
+    let tag = Tag::get(&tag_id)
+        .await?
+        .ok_or_else(|| TagError::NotFound)?;
+
+    let stream = futures::stream::unfold(progress::subscribe(), move |mut rx| {
+        let tag = tag.name.clone();
+        async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) if event.tag == tag => {
+                        let sse = Event::default().event(event.stage.name()).json_data(&event);
+                        return Some((sse, rx));
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!(skipped, "assemble event subscriber lagging behind");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PruneParams {
+    /// Only report which packages would be pruned
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Apply the tag's retention policy, returning the pruned packages
+pub async fn prune_tag(
+    identity: Identity,
+    Path(tag_id): Path<String>,
+    Query(params): Query<PruneParams>,
+) -> Result<Json<Vec<RpmRef>>> {
+    identity.require(Role::Admin, &tag_id).await?;
+    let tag = Tag::get(&tag_id)
+        .await?
+        .ok_or_else(|| TagError::NotFound)?;
+    if tag.locked && !params.dry_run {
+        return Err(TagError::Locked(tag.name).into());
+    }
+
+    let pruned = tag.prune(params.dry_run).await?;
+    Ok(Json(pruned.iter().map(RpmRef::from).collect()))
+}
+
+/// Fail if a tag is locked, see [`Tag::locked`]
+///
+/// Unknown tags are left for the caller to handle, tags of other namespaces aren't found.
+pub async fn require_unlocked(tag_id: &str) -> Result<()> {
+    match Tag::get(tag_id).await? {
+        Some(tag) if tag.locked => Err(TagError::Locked(tag.name).into()),
+        Some(_) => Ok(()),
+        None if Tag::exists(tag_id).await? => Err(TagError::NotFound.into()),
+        None => Ok(()),
+    }
+}
+
+async fn set_locked(identity: Identity, tag_id: String, locked: bool) -> Result<Json<Tag>> {
+    identity.require(Role::Admin, &tag_id).await?;
+    let mut tag = Tag::get(&tag_id)
+        .await?
+        .ok_or_else(|| TagError::NotFound)?;
+    tag.locked = locked;
+    Ok(Json(tag.save().await?))
+}
+
+/// Freeze a tag, rejecting uploads, availability changes and deletions until it's unlocked
+pub async fn lock_tag(identity: Identity, Path(tag_id): Path<String>) -> Result<Json<Tag>> {
+    set_locked(identity, tag_id, true).await
+}
+
+pub async fn unlock_tag(identity: Identity, Path(tag_id): Path<String>) -> Result<Json<Tag>> {
+    set_locked(identity, tag_id, false).await
+}
+
+/// Re-publish a previous compose of the tag
+pub async fn rollback_tag(
+    identity: Identity,
+    Path((tag_id, compose_id)): Path<(String, String)>,
+) -> Result<Json<TagCompose>> {
+    identity.require(Role::Assemble, &tag_id).await?;
+    let tag = Tag::get(&tag_id)
+        .await?
+        .ok_or_else(|| TagError::NotFound)?;
+    let compose = TagCompose::get(&compose_id)
+        .await?
+        .filter(|c| crate::db::record_key(&c.tag) == tag.name)
+        .ok_or_else(|| crate::errors::Error::NotFound)?;
+
+    tag.rollback(&compose).await?;
+    Ok(Json(compose))
+}
+
+/// Replace the tag's module metadata with an uploaded modulemd YAML stream
+pub async fn upload_modules(
+    identity: Identity,
+    Path(tag_id): Path<String>,
+    modules: String,
+) -> Result<Json<Tag>> {
+    identity.require(Role::Admin, &tag_id).await?;
+    let mut tag = Tag::get(&tag_id)
+        .await?
+        .ok_or_else(|| TagError::NotFound)?;
+
+    repodata::modules::validate(&modules)
+        .map_err(|e| TagError::InvalidModules(e.to_string()))?;
+
+    tag.set_modules(Some(modules)).await?;
+    Ok(Json(tag.save().await?))
+}
+
+pub async fn delete_modules(identity: Identity, Path(tag_id): Path<String>) -> Result<Json<Tag>> {
+    identity.require(Role::Admin, &tag_id).await?;
+    let mut tag = Tag::get(&tag_id)
+        .await?
+        .ok_or_else(|| TagError::NotFound)?;
+
+    tag.set_modules(None).await?;
+    Ok(Json(tag.save().await?))
 }
@@ -0,0 +1,138 @@
+//! API token management routes
+//!
+//! Only admins may manage tokens.
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    response::Json,
+    routing::{delete, get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Identity;
+use crate::db::namespace::Namespace;
+use crate::db::permission::{Permission, Role};
+use crate::db::record_key;
+use crate::db::token::ApiToken;
+use crate::errors::{Error, Result};
+
+pub fn route() -> Router {
+    Router::new()
+        .route("/tokens", get(get_all_tokens))
+        .nest("/token", route_operations())
+}
+
+fn route_operations() -> Router {
+    Router::new()
+        .route("/", post(create_token))
+        .route("/{id}", delete(revoke_token))
+        .route("/{id}/permissions", get(get_token_permissions))
+        .route("/{id}/permissions", post(grant_permission))
+        .route(
+            "/{id}/permissions/{permission_id}",
+            delete(revoke_permission),
+        )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateToken {
+    name: String,
+    #[serde(default)]
+    admin: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrantPermission {
+    /// Name of the tag, or `*` for every tag
+    tag: String,
+    role: Role,
+    /// Namespace to limit the grant to, the namespace of the request by default
+    #[serde(default)]
+    namespace: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatedToken {
+    #[serde(flatten)]
+    info: ApiToken,
+    /// The plaintext token, only ever returned on creation
+    token: String,
+}
+
+pub async fn get_all_tokens(identity: Identity) -> Result<Json<Vec<ApiToken>>> {
+    identity.require_admin()?;
+    let tokens = ApiToken::get_all().await?;
+    Ok(Json(tokens.into_iter().map(ApiToken::redacted).collect()))
+}
+
+pub async fn create_token(
+    identity: Identity,
+    Json(req): Json<CreateToken>,
+) -> Result<(StatusCode, Json<CreatedToken>)> {
+    identity.require_admin()?;
+    let (info, token) = ApiToken::new(req.name, req.admin);
+    let info = info.save().await?.redacted();
+    tracing::info!(name = ?info.name, creator = ?identity.name(), "created API token");
+
+    Ok((StatusCode::CREATED, Json(CreatedToken { info, token })))
+}
+
+pub async fn revoke_token(identity: Identity, Path(id): Path<String>) -> Result<StatusCode> {
+    identity.require_admin()?;
+    let token = ApiToken::get(&id).await?.ok_or(Error::NotFound)?;
+    token.delete().await?;
+    tracing::info!(name = ?token.name, revoker = ?identity.name(), "revoked API token");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_token_permissions(
+    identity: Identity,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<Permission>>> {
+    identity.require_admin()?;
+    let token = ApiToken::get(&id).await?.ok_or(Error::NotFound)?;
+    Ok(Json(Permission::get_for_token(&token.id).await?))
+}
+
+pub async fn grant_permission(
+    identity: Identity,
+    Path(id): Path<String>,
+    Json(req): Json<GrantPermission>,
+) -> Result<(StatusCode, Json<Permission>)> {
+    identity.require_admin()?;
+    let token = ApiToken::get(&id).await?.ok_or(Error::NotFound)?;
+    let namespace = req.namespace.or_else(crate::namespace::current);
+    if let Some(namespace) = &namespace {
+        Namespace::get(namespace).await?.ok_or(Error::NotFound)?;
+    }
+    let permission = Permission::new(&token.id, req.tag, req.role)
+        .namespace(namespace)
+        .save()
+        .await?;
+    tracing::info!(
+        token = ?token.name,
+        tag = ?permission.tag,
+        role = ?permission.role,
+        namespace = ?permission.namespace,
+        "granted permission"
+    );
+
+    Ok((StatusCode::CREATED, Json(permission)))
+}
+
+pub async fn revoke_permission(
+    identity: Identity,
+    Path((id, permission_id)): Path<(String, String)>,
+) -> Result<StatusCode> {
+    identity.require_admin()?;
+    let token = ApiToken::get(&id).await?.ok_or(Error::NotFound)?;
+    let permission = Permission::get(&permission_id)
+        .await?
+        .filter(|p| record_key(&p.token) == token.id.id.to_raw())
+        .ok_or(Error::NotFound)?;
+    permission.delete().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
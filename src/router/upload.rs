@@ -0,0 +1,213 @@
+//! Resumable upload routes
+//!
+//! A client opens a session, sends the package in chunks with `PATCH`, each
+//! starting at the session's current offset, then finalizes the session to
+//! store and commit the package. An interrupted upload can be resumed by
+//! fetching the session and continuing from its offset.
+use std::collections::HashSet;
+use std::sync::{LazyLock, Mutex};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::Json,
+    routing::{delete, get, patch, post},
+    Router,
+};
+use color_eyre::eyre::eyre;
+use futures::StreamExt;
+use serde::Deserialize;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use super::content_length;
+use super::rpm::{finish_upload, store_upload_path};
+use crate::auth::Identity;
+use crate::config::CONFIG;
+use crate::db::permission::Role;
+use crate::db::rpm::RpmRef;
+use crate::db::upload::UploadSession;
+use crate::disk::{check_size, check_upload};
+use crate::errors::{Error, Result};
+use crate::router::tag::require_unlocked;
+
+pub fn route() -> Router {
+    Router::new().nest("/rpm/upload/session", route_operations())
+}
+
+fn route_operations() -> Router {
+    Router::new()
+        .route("/", post(create_session))
+        .route("/{id}", get(get_session))
+        .route("/{id}", patch(upload_chunk))
+        .route("/{id}", delete(abort_session))
+        .route("/{id}/finalize", post(finalize_session))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateSession {
+    tag: String,
+    filename: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChunkParams {
+    /// Offset the chunk starts at, must match the session's offset
+    offset: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FinalizeParams {
+    #[serde(default)]
+    prune: bool,
+    /// Mark the upload as latest even if a newer version is already available
+    #[serde(default)]
+    force: bool,
+}
+
+/// Sessions with a request in flight, so chunks can't be written concurrently
+/// or while the session is finalized
+static BUSY: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+
+/// Claim on a session, released when dropped
+struct SessionClaim(String);
+
+impl SessionClaim {
+    fn take(id: &str) -> Result<Self> {
+        if !BUSY.lock().unwrap().insert(id.to_owned()) {
+            return Err(Error::Conflict(format!(
+                "upload session {id} is busy with another request"
+            )));
+        }
+        Ok(Self(id.to_owned()))
+    }
+}
+
+impl Drop for SessionClaim {
+    fn drop(&mut self) {
+        BUSY.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Look up a session, checking the caller may upload to its tag
+async fn session_for(identity: &Identity, id: &str) -> Result<UploadSession> {
+    let session = UploadSession::get(id).await?.ok_or(Error::NotFound)?;
+    identity.require(Role::Upload, &session.tag).await?;
+    Ok(session)
+}
+
+pub async fn create_session(
+    identity: Identity,
+    Json(req): Json<CreateSession>,
+) -> Result<Json<UploadSession>> {
+    identity.require(Role::Upload, &req.tag).await?;
+    require_unlocked(&req.tag).await?;
+    let session = UploadSession::new(req.tag, req.filename);
+    tokio::fs::File::create(session.staging_path()).await?;
+    Ok(Json(session.save().await?))
+}
+
+pub async fn get_session(
+    identity: Identity,
+    Path(id): Path<String>,
+) -> Result<Json<UploadSession>> {
+    Ok(Json(session_for(&identity, &id).await?))
+}
+
+/// Append a chunk to the session
+///
+/// If the body is cut off, the bytes received so far are kept and the
+/// session's offset reflects them, so the client can resume from there.
+pub async fn upload_chunk(
+    identity: Identity,
+    Path(id): Path<String>,
+    Query(params): Query<ChunkParams>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<UploadSession>> {
+    let _claim = SessionClaim::take(&id)?;
+    let session = session_for(&identity, &id).await?;
+    if params.offset != session.offset {
+        return Err(Error::Conflict(format!(
+            "chunk starts at {}, expected offset {}",
+            params.offset, session.offset
+        )));
+    }
+    // earlier chunks are already on disk, only the new one needs room
+    let size = content_length(&headers);
+    check_size(session.offset + size)?;
+    check_upload(&CONFIG.get().unwrap().cache_dir, size)?;
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(session.staging_path())
+        .await?;
+    // drop anything past the last recorded offset
+    file.set_len(session.offset).await?;
+    file.seek(std::io::SeekFrom::Start(session.offset)).await?;
+
+    let mut stream = body.into_data_stream();
+    let mut written = session.offset;
+    let mut result: Result<()> = Ok(());
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                result = Err(eyre!("upload interrupted: {e}").into());
+                break;
+            }
+        };
+        if let Err(e) = check_size(written + chunk.len() as u64) {
+            result = Err(e);
+            break;
+        }
+        written += chunk.len() as u64;
+        if let Err(e) = file.write_all(&chunk).await {
+            result = Err(e.into());
+            break;
+        }
+    }
+    file.flush().await?;
+
+    let offset = file.metadata().await?.len();
+    let session = session.advance(offset).await?.ok_or_else(|| {
+        Error::Conflict(format!("upload session {id} changed during the upload"))
+    })?;
+    result.map(|()| Json(session))
+}
+
+pub async fn finalize_session(
+    identity: Identity,
+    Path(id): Path<String>,
+    Query(params): Query<FinalizeParams>,
+) -> Result<Json<RpmRef>> {
+    let _claim = SessionClaim::take(&id)?;
+    let session = session_for(&identity, &id).await?;
+    require_unlocked(&session.tag).await?;
+
+    let upload = store_upload_path(&session.staging_path(), &session.tag).await?;
+    let rpm = finish_upload(&identity, &upload, &session.tag, params.prune, params.force).await?;
+    session.delete().await?;
+
+    Ok(Json(RpmRef::from(&rpm)))
+}
+
+pub async fn abort_session(identity: Identity, Path(id): Path<String>) -> Result<StatusCode> {
+    let _claim = SessionClaim::take(&id)?;
+    session_for(&identity, &id).await?.delete().await?;
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_claim() {
+        let claim = SessionClaim::take("claimed").unwrap();
+        assert!(SessionClaim::take("claimed").is_err());
+        assert!(SessionClaim::take("other").is_ok());
+        drop(claim);
+        assert!(SessionClaim::take("claimed").is_ok());
+    }
+}
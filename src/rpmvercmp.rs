@@ -0,0 +1,146 @@
+//! RPM version comparison, following the semantics of rpm's `rpmvercmp`
+use std::cmp::Ordering;
+
+/// Compare two version (or release) strings the way rpm does
+pub fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    let mut one = a.as_bytes();
+    let mut two = b.as_bytes();
+    let is_separator = |c: &u8| !c.is_ascii_alphanumeric() && *c != b'~' && *c != b'^';
+
+    loop {
+        while one.first().is_some_and(is_separator) {
+            one = &one[1..];
+        }
+        while two.first().is_some_and(is_separator) {
+            two = &two[1..];
+        }
+
+        // a tilde sorts before everything, even the end of the string
+        if one.first() == Some(&b'~') || two.first() == Some(&b'~') {
+            if one.first() != Some(&b'~') {
+                return Ordering::Greater;
+            }
+            if two.first() != Some(&b'~') {
+                return Ordering::Less;
+            }
+            one = &one[1..];
+            two = &two[1..];
+            continue;
+        }
+
+        // a caret sorts after the end of the string, but before anything else
+        if one.first() == Some(&b'^') || two.first() == Some(&b'^') {
+            if one.is_empty() {
+                return Ordering::Less;
+            }
+            if two.is_empty() {
+                return Ordering::Greater;
+            }
+            if one[0] != b'^' {
+                return Ordering::Greater;
+            }
+            if two[0] != b'^' {
+                return Ordering::Less;
+            }
+            one = &one[1..];
+            two = &two[1..];
+            continue;
+        }
+
+        if one.is_empty() || two.is_empty() {
+            break;
+        }
+
+        let is_num = one[0].is_ascii_digit();
+        let segment_len = |s: &[u8]| {
+            s.iter()
+                .take_while(|c| {
+                    if is_num {
+                        c.is_ascii_digit()
+                    } else {
+                        c.is_ascii_alphabetic()
+                    }
+                })
+                .count()
+        };
+
+        let (seg1, rest1) = one.split_at(segment_len(one));
+        let (seg2, rest2) = two.split_at(segment_len(two));
+        one = rest1;
+        two = rest2;
+
+        // segments of different types, numeric segments are newer
+        if seg2.is_empty() {
+            return if is_num {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+
+        let ord = if is_num {
+            let trim = |s: &'_ [u8]| -> usize { s.iter().take_while(|c| **c == b'0').count() };
+            let seg1 = &seg1[trim(seg1)..];
+            let seg2 = &seg2[trim(seg2)..];
+            seg1.len().cmp(&seg2.len()).then_with(|| seg1.cmp(seg2))
+        } else {
+            seg1.cmp(seg2)
+        };
+
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+
+    // whichever version has characters left over wins
+    match (one.is_empty(), two.is_empty()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        (false, _) => Ordering::Greater,
+    }
+}
+
+/// Compare two epoch:version-release triples
+pub fn evr_cmp(a: (u32, &str, &str), b: (u32, &str, &str)) -> Ordering {
+    a.0.cmp(&b.0)
+        .then_with(|| rpmvercmp(a.1, b.1))
+        .then_with(|| rpmvercmp(a.2, b.2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rpmvercmp() {
+        let cases = [
+            ("1.0", "1.0", Ordering::Equal),
+            ("1.0", "2.0", Ordering::Less),
+            ("1.10", "1.9", Ordering::Greater),
+            ("001", "1", Ordering::Equal),
+            ("1.0a", "1.0", Ordering::Greater),
+            ("1.0", "1.0.1", Ordering::Less),
+            ("2a", "2.0", Ordering::Less),
+            ("1.0~rc1", "1.0", Ordering::Less),
+            ("1.0~rc1", "1.0~rc2", Ordering::Less),
+            ("1.0^git1", "1.0", Ordering::Greater),
+            ("1.0^git1", "1.0.1", Ordering::Less),
+            ("1.fc41", "1.fc40", Ordering::Greater),
+        ];
+
+        for (a, b, expected) in cases {
+            assert_eq!(rpmvercmp(a, b), expected, "{a} vs {b}");
+            assert_eq!(rpmvercmp(b, a), expected.reverse(), "{b} vs {a}");
+        }
+    }
+
+    #[test]
+    fn test_evr_cmp() {
+        assert_eq!(evr_cmp((1, "1.0", "1"), (0, "2.0", "1")), Ordering::Greater);
+        assert_eq!(evr_cmp((0, "1.0", "2"), (0, "1.0", "10")), Ordering::Less);
+    }
+}
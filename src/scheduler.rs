@@ -0,0 +1,116 @@
+//! Scheduled assembly of tags
+//!
+//! Tags with a cron [`Tag::schedule`] are assembled by the leader instance whenever
+//! the schedule comes due, so nightly repos don't need an external cron calling the API.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use croner::Cron;
+
+use crate::db::tag::Tag;
+use crate::notify::{notify, Notification, NotificationKind};
+
+/// How often schedules are checked, which is also their resolution
+pub const SCHEDULE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Parse a cron expression, i.e `0 3 * * *`
+pub fn parse_schedule(expr: &str) -> color_eyre::Result<Cron> {
+    Ok(Cron::new(expr).parse()?)
+}
+
+/// Whether a schedule came due between its last check and `now`
+///
+/// A schedule that was never checked only starts counting from `now`.
+fn is_due(
+    cron: &Cron,
+    last: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> color_eyre::Result<bool> {
+    let Some(last) = last else {
+        return Ok(false);
+    };
+    Ok(cron.find_next_occurrence(&last, false)? <= now)
+}
+
+/// Start assembling every tag whose schedule came due
+///
+/// Each assembly runs in its own task, so a slow tag doesn't hold back the others or the
+/// next check. A tag that can't be checked is logged and skipped.
+async fn run_schedules() -> color_eyre::Result<()> {
+    let now = Utc::now();
+    for tag in Tag::get_scheduled().await? {
+        match check_schedule(&tag, now).await {
+            Ok(true) => {
+                tokio::spawn(run_scheduled(tag));
+            }
+            Ok(false) => {}
+            Err(e) => tracing::warn!(tag = %tag.name, ?e, "failed to check assembly schedule"),
+        }
+    }
+    Ok(())
+}
+
+/// Record a schedule check of the tag, returning whether its schedule came due
+async fn check_schedule(tag: &Tag, now: DateTime<Utc>) -> color_eyre::Result<bool> {
+    let Some(expr) = &tag.schedule else {
+        return Ok(false);
+    };
+    let cron = parse_schedule(expr)?;
+    let due = is_due(&cron, tag.last_scheduled.as_ref().map(|t| t.0), now)?;
+    tag.set_last_scheduled(now).await?;
+    Ok(due)
+}
+
+/// Assemble a tag whose schedule came due, notifying subscribers if it fails
+async fn run_scheduled(tag: Tag) {
+    tracing::info!(tag = %tag.name, "running scheduled assembly");
+    // so its artifacts are stored under the tag's namespace, like API assemblies
+    let assembled = crate::namespace::scope(tag.namespace.clone(), tag.assemble()).await;
+    if let Err(e) = assembled {
+        tracing::error!(tag = %tag.name, ?e, "scheduled assembly failed");
+        notify(Notification::new(
+            NotificationKind::AssembleFailed,
+            Some(&tag.name),
+            format!("scheduled assembly failed: {e}"),
+        ));
+    }
+}
+
+/// Spawn the background task assembling tags on their schedules
+///
+/// Schedules are only run by the leader instance.
+pub fn spawn_schedule_task(interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if !crate::leader::is_leader() {
+                continue;
+            }
+            if let Err(e) = run_schedules().await {
+                tracing::error!(?e, "failed to run assembly schedules");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_due() {
+        let cron = parse_schedule("0 3 * * *").unwrap();
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().to_utc();
+
+        let due = |last: &str, now: &str| is_due(&cron, Some(at(last)), at(now)).unwrap();
+
+        assert!(!is_due(&cron, None, at("2025-01-02T03:00:00Z")).unwrap());
+        assert!(!due("2025-01-02T02:00:00Z", "2025-01-02T02:59:00Z"));
+        assert!(due("2025-01-02T02:59:00Z", "2025-01-02T03:00:00Z"));
+        assert!(!due("2025-01-02T03:00:00Z", "2025-01-02T03:01:00Z"));
+
+        assert!(parse_schedule("not a cron").is_err());
+    }
+}
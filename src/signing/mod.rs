@@ -0,0 +1,55 @@
+//! Bounded worker pool for signing packages
+//!
+//! Signing is CPU-bound, so at most `SIGN_WORKERS` packages are signed at once,
+//! each on the blocking thread pool.
+pub mod signer;
+
+use std::sync::LazyLock;
+
+use tokio::sync::Semaphore;
+
+use crate::config::CONFIG;
+use crate::db::{gpg_key::GpgKey, rpm::Rpm};
+
+static WORKERS: LazyLock<Semaphore> = LazyLock::new(|| Semaphore::new(worker_count()));
+
+/// Number of packages signed at once
+pub fn worker_count() -> usize {
+    CONFIG
+        .get()
+        .and_then(|c| c.sign_workers)
+        .filter(|n| *n > 0)
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+/// Sign a package, waiting for a free worker first
+pub async fn sign(rpm: &Rpm, key: &GpgKey) -> color_eyre::Result<Rpm> {
+    let _permit = WORKERS.acquire().await?;
+    rpm.sign(key.clone()).await
+}
+
+/// Sign several packages across the worker pool, returning each package's result in order
+pub async fn sign_all(rpms: &[Rpm], key: &GpgKey) -> Vec<color_eyre::Result<Rpm>> {
+    futures::future::join_all(rpms.iter().map(|rpm| sign(rpm, key))).await
+}
+
+/// Like [`sign`], only writing the signed object, see [`Rpm::sign_object`]
+pub async fn sign_object(rpm: &Rpm, key: &GpgKey) -> color_eyre::Result<Rpm> {
+    let _permit = WORKERS.acquire().await?;
+    rpm.sign_object(key.clone()).await
+}
+
+/// Like [`sign_all`], only writing the signed objects, see [`Rpm::sign_object`]
+pub async fn sign_objects(rpms: &[Rpm], key: &GpgKey) -> Vec<color_eyre::Result<Rpm>> {
+    futures::future::join_all(rpms.iter().map(|rpm| sign_object(rpm, key))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_count() {
+        assert!(worker_count() >= 1);
+    }
+}
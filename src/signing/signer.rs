@@ -0,0 +1,233 @@
+//! Signing backends, producing signatures with a key's secret key wherever it lives
+//!
+//! The secret key is either stored armored in the database with the key, or kept outside of
+//! Subatomic entirely, behind an external signing service or a local command.
+use std::fmt::Debug;
+use std::io::Read;
+use std::process::Stdio;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::SubsecRound;
+use color_eyre::{
+    eyre::{eyre, ContextCompat},
+    Result,
+};
+use pgp::{
+    crypto::{hash::HashAlgorithm, public_key::PublicKeyAlgorithm},
+    packet::{SignatureConfig, SignatureType, Subpacket, SubpacketData},
+    ser::Serialize as _,
+    types::PublicKeyTrait,
+    Deserializable,
+};
+use rpm::signature::{AlgorithmType, Signing};
+use tokio::io::AsyncWriteExt;
+
+#[async_trait]
+pub trait Signer: Debug + Send + Sync {
+    /// Create a binary detached OpenPGP signature of `data`
+    async fn sign(&self, data: Vec<u8>) -> Result<Vec<u8>>;
+
+    /// Public key algorithm of the key, rpm stores RSA and EdDSA signatures in different header tags
+    fn algorithm(&self) -> AlgorithmType;
+}
+
+/// Armored secret key stored in the database with the key
+///
+/// Signs like rpm's own signer does, which only takes RSA and EdDSA keys, for ECDSA keys too.
+#[derive(Debug)]
+pub struct ArmoredSigner(pgp::SignedSecretKey);
+
+impl ArmoredSigner {
+    pub fn new(secret_key: &str) -> Result<Self> {
+        let (secret_key, _) = pgp::SignedSecretKey::from_string(secret_key)?;
+        Ok(Self(secret_key))
+    }
+}
+
+#[async_trait]
+impl Signer for ArmoredSigner {
+    async fn sign(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        let mut config = SignatureConfig::v4(
+            SignatureType::Binary,
+            self.0.algorithm(),
+            HashAlgorithm::SHA2_256,
+        );
+        config
+            .hashed_subpackets
+            .push(Subpacket::critical(SubpacketData::SignatureCreationTime(
+                chrono::Utc::now().trunc_subsecs(0),
+            )));
+        config
+            .hashed_subpackets
+            .push(Subpacket::critical(SubpacketData::Issuer(self.0.key_id())));
+        let signature = config.sign(&self.0, String::new, data.as_slice())?;
+
+        let mut buf = Vec::new();
+        pgp::packet::write_packet(&mut buf, &signature)?;
+        Ok(buf)
+    }
+
+    /// ECDSA signatures go in the same rpm header tag as EdDSA ones
+    fn algorithm(&self) -> AlgorithmType {
+        match self.0.algorithm() {
+            PublicKeyAlgorithm::RSA | PublicKeyAlgorithm::RSASign => AlgorithmType::RSA,
+            _ => AlgorithmType::EdDSA,
+        }
+    }
+}
+
+/// Time to wait for a signing service to return a signature, so a hung service doesn't block signing forever
+const HTTP_SIGN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Client shared by every [`HttpSigner`], so connections to the signing service are reused
+static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(HTTP_SIGN_TIMEOUT)
+        .build()
+        .expect("cannot build HTTP client")
+});
+
+/// External signing service, the data is POSTed to it and the signature returned in the response body
+#[derive(Debug)]
+pub struct HttpSigner {
+    url: String,
+    token: Option<String>,
+    algorithm: AlgorithmType,
+}
+
+impl HttpSigner {
+    pub fn new(url: &str, token: Option<String>, algorithm: AlgorithmType) -> Self {
+        Self {
+            url: url.to_owned(),
+            token,
+            algorithm,
+        }
+    }
+}
+
+#[async_trait]
+impl Signer for HttpSigner {
+    async fn sign(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        let mut req = HTTP_CLIENT
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .body(data);
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+        let res = req.send().await?.error_for_status()?;
+        dearmor(res.bytes().await?.to_vec())
+    }
+
+    fn algorithm(&self) -> AlgorithmType {
+        self.algorithm
+    }
+}
+
+/// Local command reading the data from stdin and writing the signature to stdout
+///
+/// i.e. `gpg --detach-sign`, with the secret key on a PKCS#11 token through `gnupg-pkcs11-scd`.
+#[derive(Debug)]
+pub struct CommandSigner {
+    program: String,
+    args: Vec<String>,
+    algorithm: AlgorithmType,
+}
+
+impl CommandSigner {
+    pub fn new(program: &str, args: Vec<String>, algorithm: AlgorithmType) -> Self {
+        Self {
+            program: program.to_owned(),
+            args,
+            algorithm,
+        }
+    }
+}
+
+#[async_trait]
+impl Signer for CommandSigner {
+    async fn sign(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        let mut child = tokio::process::Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // write from a separate task, so a command writing output early can't block on a full stdout
+        let mut stdin = child.stdin.take().context("no stdin for signing command")?;
+        let writer = tokio::spawn(async move { stdin.write_all(&data).await });
+        let output = child.wait_with_output().await?;
+        writer.await??;
+
+        if !output.status.success() {
+            return Err(eyre!(
+                "signing command failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        dearmor(output.stdout)
+    }
+
+    fn algorithm(&self) -> AlgorithmType {
+        self.algorithm
+    }
+}
+
+/// Convert an armored signature to binary, binary signatures are returned as-is
+pub fn dearmor(signature: Vec<u8>) -> Result<Vec<u8>> {
+    if !signature.starts_with(b"-----BEGIN") {
+        return Ok(signature);
+    }
+    let (signature, _headers) =
+        pgp::StandaloneSignature::from_string(std::str::from_utf8(&signature)?)?;
+    Ok(signature.to_bytes()?)
+}
+
+/// Adapts a [`Signer`] to rpm's synchronous signing
+///
+/// Blocks on the runtime for each signature, so it must only be used off of it, i.e. in `spawn_blocking`.
+#[derive(Debug)]
+pub struct BlockingSigner {
+    signer: Arc<dyn Signer>,
+    runtime: tokio::runtime::Handle,
+}
+
+impl BlockingSigner {
+    pub fn new(signer: Arc<dyn Signer>, runtime: tokio::runtime::Handle) -> Self {
+        Self { signer, runtime }
+    }
+}
+
+impl Signing for BlockingSigner {
+    type Signature = Vec<u8>;
+
+    fn sign(&self, mut data: impl Read, _t: rpm::Timestamp) -> Result<Vec<u8>, rpm::Error> {
+        let mut sign = || -> Result<Vec<u8>> {
+            let mut buf = Vec::new();
+            data.read_to_end(&mut buf)?;
+            self.runtime.block_on(self.signer.sign(buf))
+        };
+        sign().map_err(|e| rpm::Error::SignError(pgp::errors::Error::Message(e.to_string())))
+    }
+
+    fn algorithm(&self) -> AlgorithmType {
+        self.signer.algorithm()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_command_signer() {
+        let signer = CommandSigner::new("cat", Vec::new(), AlgorithmType::EdDSA);
+        assert_eq!(signer.sign(b"signature".to_vec()).await.unwrap(), b"signature");
+
+        let signer = CommandSigner::new("false", Vec::new(), AlgorithmType::EdDSA);
+        assert!(signer.sign(b"data".to_vec()).await.is_err());
+    }
+}
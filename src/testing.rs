@@ -0,0 +1,2849 @@
+//! Embedded test harness
+//!
+//! Runs the full router against an in-memory SurrealDB and a tempdir-backed local object store,
+//! so flows like upload → sign → assemble can be tested without any external services.
+//!
+//! Enabled with the `test-harness` feature: `cargo test --features test-harness`
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    Router,
+};
+use color_eyre::Result;
+use tower::ServiceExt;
+
+use crate::config::Config;
+use crate::db::tag::{RepoType, Tag};
+
+/// Image build command of the harness, "building" the repomd.xml of the compose as its image
+///
+/// Fails for tags ending in `-fail`.
+const IMAGE_BUILD_CMD: &str = r#"case "$SUBATOMIC_TAG" in *-fail) exit 1;; esac;
+cp "$SUBATOMIC_REPO_DIR/repodata/repomd.xml" "$SUBATOMIC_OUTPUT_DIR/""#;
+pub const FIXTURE_RPM: &str = "test/data/anda-srpm-macros-0:0.2.6-1.fc41.noarch.rpm";
+pub const FIXTURE_DEB: &str = "test/data/subatomic-hello_1.0.2-1_all.deb";
+pub const FIXTURE_PACMAN: &str = "test/data/subatomic-hello-1:1.0.2-1-any.pkg.tar.zst";
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+const MULTIPART_BOUNDARY: &str = "subatomic-test-boundary";
+/// Config file of the harness, under its directory
+const CONFIG_FILE: &str = "config.toml";
+
+/// Runtime shared by every harness test
+///
+/// The in-memory database lives on the runtime it was connected from,
+/// so tests can't each use their own runtime.
+static RUNTIME: LazyLock<tokio::runtime::Runtime> = LazyLock::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("cannot build test runtime")
+});
+
+static HARNESS: tokio::sync::OnceCell<TestHarness> = tokio::sync::OnceCell::const_new();
+
+/// Run a future on the shared harness runtime
+pub fn run<F: Future>(f: F) -> F::Output {
+    RUNTIME.block_on(f)
+}
+
+pub struct TestHarness {
+    /// Root of every directory used by the harness
+    pub dir: PathBuf,
+    pub config: Config,
+}
+
+impl TestHarness {
+    /// Get the harness, setting up the global config, object store and database on first use
+    pub async fn get() -> &'static Self {
+        HARNESS
+            .get_or_init(|| async { Self::init().await.expect("cannot set up test harness") })
+            .await
+    }
+
+    async fn init() -> Result<Self> {
+        let dir = tempfile::tempdir()?.into_path();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        // changed by test_reload_config
+        std::fs::write(dir.join(CONFIG_FILE), "")?;
+
+        let config = Config::init_from([
+            "subatomic-ng".to_owned(),
+            format!("--config={}", path(CONFIG_FILE)),
+            "--host=mem://".to_owned(),
+            "--object-store-type=local".to_owned(),
+            format!("--cache-dir={}", path("cache")),
+            format!("--repo-cache-dir={}", path("repo")),
+            format!("--object-cache-dir={}", path("objects")),
+            format!("--export-dir={}", path("export")),
+            format!("--import-root={}", path("import")),
+            format!("--backup-dir={}", path("backups")),
+            format!("--admin-token={ADMIN_TOKEN}"),
+            format!("--image-build-cmd={IMAGE_BUILD_CMD}"),
+        ]);
+
+        crate::db::connect_mem(&config.surreal_ns, &config.surreal_db).await?;
+
+        Ok(Self { dir, config })
+    }
+
+    pub fn router(&self) -> Router {
+        crate::router()
+    }
+
+    /// Send a request through the router, returning the status and body
+    pub async fn request(&self, req: Request<Body>) -> Result<(StatusCode, Vec<u8>)> {
+        let res = self.router().oneshot(req).await?;
+        let status = res.status();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        Ok((status, body.to_vec()))
+    }
+}
+
+/// Build a multipart upload request for several files to a tag
+pub fn multipart_upload(
+    tag: &str,
+    files: &[(&str, &[u8])],
+    prune: bool,
+) -> Result<Request<Body>> {
+    let mut body = Vec::new();
+    body.extend_from_slice(
+        format!(
+            "--{MULTIPART_BOUNDARY}\r\nContent-Disposition: form-data; name=\"tag\"\r\n\r\n{tag}\r\n"
+        )
+        .as_bytes(),
+    );
+    for (filename, data) in files {
+        body.extend_from_slice(
+            format!(
+                "--{MULTIPART_BOUNDARY}\r\nContent-Disposition: form-data; name=\"file_upload\"; filename=\"{filename}\"\r\nContent-Type: application/x-rpm\r\n\r\n"
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{MULTIPART_BOUNDARY}--\r\n").as_bytes());
+
+    Ok(Request::builder()
+        .method("PUT")
+        .uri(format!("/rpm/upload?prune={prune}"))
+        .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={MULTIPART_BOUNDARY}"),
+        )
+        .body(Body::from(body))?)
+}
+
+/// Builder for fixture tags
+pub struct TagBuilder {
+    tag: Tag,
+}
+
+impl TagBuilder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            tag: Tag::new(name.to_owned()),
+        }
+    }
+
+    pub fn repo_type(mut self, repo_type: RepoType) -> Self {
+        self.tag.repo_type = repo_type;
+        self
+    }
+
+    pub fn signing_key(mut self, key: &str) -> Self {
+        self.tag.set_gpg_key(key);
+        self
+    }
+
+    /// Split composes into a repo per architecture
+    pub fn split_arches(mut self, arches: &[&str]) -> Self {
+        self.tag.split_arches = true;
+        self.tag.arches = arches.iter().map(|a| a.to_string()).collect();
+        self
+    }
+
+    /// Run the image build command after each assembly
+    pub fn build_images(mut self) -> Self {
+        self.tag.build_images = true;
+        self
+    }
+
+    pub async fn create(self) -> Result<Tag> {
+        self.tag.save().await
+    }
+}
+
+/// Builder for fixture RPM upload requests
+pub struct RpmUpload {
+    path: PathBuf,
+    tag: String,
+    prune: bool,
+}
+
+impl RpmUpload {
+    pub fn new(tag: &str) -> Self {
+        Self {
+            path: PathBuf::from(FIXTURE_RPM),
+            tag: tag.to_owned(),
+            prune: false,
+        }
+    }
+
+    pub fn prune(mut self, prune: bool) -> Self {
+        self.prune = prune;
+        self
+    }
+
+    /// Build the multipart upload request
+    pub fn request(&self) -> Result<Request<Body>> {
+        let data = std::fs::read(&self.path)?;
+        // uploads are staged by file name, so keep them unique across tests
+        let filename = format!(
+            "{}-{}",
+            ulid::Ulid::new(),
+            self.path.file_name().unwrap_or_default().to_string_lossy()
+        );
+
+        multipart_upload(&self.tag, &[(filename.as_str(), data.as_slice())], self.prune)
+    }
+
+    /// Build the raw-body upload request
+    pub fn raw_request(&self) -> Result<Request<Body>> {
+        let data = std::fs::read(&self.path)?;
+        let filename = format!(
+            "{}-{}",
+            ulid::Ulid::new(),
+            self.path.file_name().unwrap_or_default().to_string_lossy()
+        );
+
+        Ok(Request::builder()
+            .method("PUT")
+            .uri(format!(
+                "/rpm/upload/raw?tag={}&prune={}&filename={filename}",
+                self.tag, self.prune
+            ))
+            .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+            .header(header::CONTENT_TYPE, "application/x-rpm")
+            .body(Body::from(data))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{
+        event_log::LogEvent,
+        gpg_key::{GpgKey, KeyOptions},
+        notification::{DigestMode, EmailSubscription},
+        rpm::{Rpm, RpmRef},
+        tag::{TagCompose, TAG_TABLE},
+        webhook::{Webhook, WebhookDelivery},
+    };
+    use crate::notify::{Notification, NotificationKind};
+    use surrealdb::RecordId;
+
+    #[test]
+    fn test_upload() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-upload").create().await.unwrap();
+
+            let req = RpmUpload::new("harness-upload").prune(true).request().unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            let rpms = Rpm::get_by_tag("harness-upload").await.unwrap();
+            assert_eq!(rpms.len(), 1);
+            assert_eq!(rpms[0].name, "anda-srpm-macros");
+        })
+    }
+
+    #[test]
+    fn test_upload_batch() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-upload-batch").create().await.unwrap();
+
+            let data = std::fs::read(FIXTURE_RPM).unwrap();
+            let req = multipart_upload(
+                "harness-upload-batch",
+                &[("good.rpm", &data[..]), ("bad.rpm", &b"not an rpm"[..])],
+                true,
+            )
+            .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            let results: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(results.len(), 2);
+            assert_eq!(results[0]["rpm"]["name"], "anda-srpm-macros");
+            assert!(results[1]["error"].is_string());
+
+            // marked available along with the insert
+            let rpms = Rpm::get_by_tag("harness-upload-batch").await.unwrap();
+            assert_eq!(rpms.len(), 1);
+            assert!(rpms[0].available);
+        })
+    }
+
+    #[test]
+    fn test_upload_raw() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-upload-raw").create().await.unwrap();
+
+            let req = RpmUpload::new("harness-upload-raw")
+                .prune(true)
+                .raw_request()
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(created["tag"], "harness-upload-raw");
+
+            let rpms = Rpm::get_by_tag("harness-upload-raw").await.unwrap();
+            assert_eq!(rpms.len(), 1);
+            assert_eq!(rpms[0].name, "anda-srpm-macros");
+            assert_eq!(created["object_key"], rpms[0].object_key);
+        })
+    }
+
+    #[test]
+    fn test_upload_duplicate() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-upload-dup").create().await.unwrap();
+
+            let upload = RpmUpload::new("harness-upload-dup");
+            let (_, first) = harness.request(upload.raw_request().unwrap()).await.unwrap();
+            let (status, second) = harness.request(upload.raw_request().unwrap()).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            let first: serde_json::Value = serde_json::from_slice(&first).unwrap();
+            let second: serde_json::Value = serde_json::from_slice(&second).unwrap();
+            assert_eq!(first["id"], second["id"]);
+
+            let rpms = Rpm::get_by_tag("harness-upload-dup").await.unwrap();
+            assert_eq!(rpms.len(), 1);
+        })
+    }
+
+    #[test]
+    fn test_upload_too_large() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-upload-large").create().await.unwrap();
+
+            let mut req = RpmUpload::new("harness-upload-large")
+                .raw_request()
+                .unwrap();
+            let size = harness.config.max_upload_size + 1;
+            req.headers_mut()
+                .insert(header::CONTENT_LENGTH, size.to_string().parse().unwrap());
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+            let rpms = Rpm::get_by_tag("harness-upload-large").await.unwrap();
+            assert!(rpms.is_empty());
+        })
+    }
+
+    #[test]
+    fn test_download() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-download").create().await.unwrap();
+
+            let req = RpmUpload::new("harness-download").raw_request().unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = created["id"].as_str().unwrap();
+
+            let auth = format!("Bearer {ADMIN_TOKEN}");
+            let req = Request::get(format!("/rpm/{id}/download"))
+                .header(header::AUTHORIZATION, &auth)
+                .body(Body::empty())
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, std::fs::read(FIXTURE_RPM).unwrap());
+
+            // not signed yet
+            let req = Request::get(format!("/rpm/{id}/download/signed"))
+                .header(header::AUTHORIZATION, &auth)
+                .body(Body::empty())
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        })
+    }
+
+    #[test]
+    fn test_rpm_errors() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-rpm-errors").create().await.unwrap();
+            let auth = format!("Bearer {ADMIN_TOKEN}");
+
+            // unknown packages are 404s, not panics
+            let missing = ulid::Ulid::new();
+            for (method, uri) in [
+                ("GET", format!("/rpm/{missing}")),
+                ("DELETE", format!("/rpm/{missing}")),
+                ("POST", format!("/rpm/{missing}/available")),
+                ("POST", format!("/rpm/{missing}/hold")),
+            ] {
+                let req = Request::builder()
+                    .method(method)
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, &auth)
+                    .body(Body::empty())
+                    .unwrap();
+                let (status, _) = harness.request(req).await.unwrap();
+                assert_eq!(status, StatusCode::NOT_FOUND, "{method} {uri}");
+            }
+
+            let req = Request::put("/rpm/upload/raw?tag=harness-rpm-errors")
+                .header(header::AUTHORIZATION, &auth)
+                .body(Body::from("not an rpm"))
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+
+            let req = RpmUpload::new("harness-rpm-errors").raw_request().unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = created["id"].as_str().unwrap();
+            let req = Request::post(format!("/rpm/{id}/tags/harness-rpm-errors"))
+                .header(header::AUTHORIZATION, &auth)
+                .body(Body::empty())
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+            let req = Request::delete(format!("/rpm/{id}/tags/harness-rpm-errors"))
+                .header(header::AUTHORIZATION, &auth)
+                .body(Body::empty())
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        })
+    }
+
+    #[test]
+    fn test_upload_chunked() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-upload-chunked")
+                .create()
+                .await
+                .unwrap();
+            let auth = format!("Bearer {ADMIN_TOKEN}");
+
+            let req = Request::post("/rpm/upload/session")
+                .header(header::AUTHORIZATION, &auth)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    r#"{"tag": "harness-upload-chunked", "filename": "chunked.rpm"}"#,
+                ))
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let session: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = session["id"]["id"]["String"].as_str().unwrap().to_owned();
+
+            let data = std::fs::read(FIXTURE_RPM).unwrap();
+            let (first, second) = data.split_at(data.len() / 2);
+            let chunk = |offset: usize, bytes: &[u8]| {
+                Request::patch(format!("/rpm/upload/session/{id}?offset={offset}"))
+                    .header(header::AUTHORIZATION, &auth)
+                    .body(Body::from(bytes.to_vec()))
+                    .unwrap()
+            };
+
+            let (status, _) = harness.request(chunk(0, first)).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            // resending from a stale offset is rejected
+            let (status, _) = harness.request(chunk(0, first)).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+            let (status, _) = harness
+                .request(chunk(first.len(), second))
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            let req = Request::post(format!("/rpm/upload/session/{id}/finalize?prune=true"))
+                .header(header::AUTHORIZATION, &auth)
+                .body(Body::empty())
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            let rpms = Rpm::get_by_tag("harness-upload-chunked").await.unwrap();
+            assert_eq!(rpms.len(), 1);
+        })
+    }
+
+    #[test]
+    fn test_requires_auth() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let req = Request::get("/rpms").body(Body::empty()).unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+            let req = Request::get("/health").body(Body::empty()).unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+        })
+    }
+
+    #[test]
+    fn test_verify_access() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let store = crate::obj_store::object_store();
+            store.verify_access().await.unwrap();
+
+            // the sentinel object is deleted
+            let sentinels = harness.config.object_cache_dir.join("subatomic-sentinel");
+            let left = std::fs::read_dir(sentinels).map_or(0, |dir| dir.count());
+            assert_eq!(left, 0);
+        })
+    }
+
+    #[test]
+    fn test_deb_upload() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-deb")
+                .repo_type(RepoType::Deb)
+                .create()
+                .await
+                .unwrap();
+            let upload = |tag: &str| {
+                Request::builder()
+                    .method("PUT")
+                    .uri(format!("/deb/upload/raw?tag={tag}&prune=true"))
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::from(std::fs::read(FIXTURE_DEB).unwrap()))
+                    .unwrap()
+            };
+
+            let (status, body) = harness.request(upload("harness-deb")).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let deb: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(deb["name"], "subatomic-hello");
+            assert_eq!(deb["version"], "1:1.0.2-1");
+            assert_eq!(deb["available"], true);
+
+            let req = Request::builder()
+                .uri("/debs?tag=harness-deb")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let debs: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(debs.len(), 1);
+
+            let req = Request::builder()
+                .uri(format!("/deb/{}/download", deb["id"].as_str().unwrap()))
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, std::fs::read(FIXTURE_DEB).unwrap());
+
+            // deb tags only hold Debian packages
+            let req = RpmUpload::new("harness-deb").raw_request().unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+
+            // rpm tags hold both
+            TagBuilder::new("harness-deb-mixed").create().await.unwrap();
+            let (status, _) = harness.request(upload("harness-deb-mixed")).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+        })
+    }
+
+    #[test]
+    fn test_apt_assemble() {
+        run(async {
+            let harness = TestHarness::get().await;
+            GpgKey::new(
+                "harness-apt-key",
+                None,
+                "Harness <harness@example.com>",
+                &KeyOptions::default(),
+            )
+            .unwrap()
+            .save()
+            .await
+            .unwrap();
+            let tag = TagBuilder::new("harness-apt")
+                .repo_type(RepoType::Deb)
+                .signing_key("harness-apt-key")
+                .create()
+                .await
+                .unwrap();
+            let req = Request::builder()
+                .method("PUT")
+                .uri("/deb/upload/raw?tag=harness-apt&prune=true")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::from(std::fs::read(FIXTURE_DEB).unwrap()))
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            tag.assemble().await.unwrap();
+            let export_dir = tag.export_dir();
+            let dists_dir = export_dir.join("dists/harness-apt");
+            assert!(export_dir
+                .join("pool/main/s/subatomic-hello/subatomic-hello_1.0.2-1_all.deb")
+                .exists());
+            let packages =
+                std::fs::read_to_string(dists_dir.join("main/binary-all/Packages")).unwrap();
+            assert!(packages.contains("Package: subatomic-hello\n"));
+            assert!(packages.contains(
+                "Filename: pool/main/s/subatomic-hello/subatomic-hello_1.0.2-1_all.deb\n"
+            ));
+            let release = std::fs::read_to_string(dists_dir.join("Release")).unwrap();
+            assert!(release.contains(" main/binary-all/Packages.gz\n"));
+            let inrelease = std::fs::read_to_string(dists_dir.join("InRelease")).unwrap();
+            assert!(inrelease.starts_with("-----BEGIN PGP SIGNED MESSAGE-----"));
+            assert!(dists_dir.join("Release.gpg").exists());
+            assert!(export_dir.join("harness-apt-archive-keyring.asc").exists());
+            // no yum repo in deb tags
+            assert!(!export_dir.join("repodata").exists());
+        })
+    }
+
+    #[test]
+    fn test_pacman_assemble() {
+        run(async {
+            let harness = TestHarness::get().await;
+            GpgKey::new(
+                "harness-pacman-key",
+                None,
+                "Harness <harness@example.com>",
+                &KeyOptions::default(),
+            )
+            .unwrap()
+            .save()
+            .await
+            .unwrap();
+            let tag = TagBuilder::new("harness-pacman")
+                .repo_type(RepoType::Pacman)
+                .split_arches(&["x86_64"])
+                .signing_key("harness-pacman-key")
+                .create()
+                .await
+                .unwrap();
+            let upload = |path: &str, uri: &str| {
+                Request::builder()
+                    .method("PUT")
+                    .uri(uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::from(std::fs::read(path).unwrap()))
+                    .unwrap()
+            };
+
+            let req = upload(FIXTURE_DEB, "/deb/upload/raw?tag=harness-pacman");
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+
+            let req = upload(FIXTURE_PACMAN, "/pacman/upload/raw?tag=harness-pacman&prune=true");
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let pkg: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(pkg["version"], "1:1.0.2-1");
+            assert_eq!(pkg["available"], true);
+            assert_eq!(pkg["signed"], false);
+
+            tag.assemble().await.unwrap();
+            let repo_dir = tag.export_dir().join("x86_64");
+            let file_name = "subatomic-hello-1:1.0.2-1-any.pkg.tar.zst";
+            assert!(repo_dir.join(file_name).exists());
+            // signed on assembly, with the tag's key
+            assert!(repo_dir.join(format!("{file_name}.sig")).exists());
+            assert!(repo_dir.join("harness-pacman.db").exists());
+            assert!(repo_dir.join("harness-pacman.db.tar.gz.sig").exists());
+            assert!(tag.export_dir().join("harness-pacman-keyring.asc").exists());
+
+            let req = Request::builder()
+                .uri("/pacman/packages?tag=harness-pacman")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let pkgs: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(pkgs.len(), 1);
+            assert_eq!(pkgs[0]["signed"], true);
+        })
+    }
+
+    #[test]
+    fn test_generic_files() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let tag = TagBuilder::new("harness-files").create().await.unwrap();
+            let upload = |path: &str, body: &'static [u8]| {
+                Request::builder()
+                    .method("PUT")
+                    .uri(format!("/file/upload/raw?tag=harness-files&path={path}"))
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .header(header::CONTENT_TYPE, "text/plain")
+                    .body(Body::from(body))
+                    .unwrap()
+            };
+
+            let (status, _) = harness.request(upload("../escape", b"no")).await.unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+
+            let (status, body) = harness.request(upload("iso/SHA256SUMS", b"old")).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let old: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let (status, body) = harness.request(upload("iso/SHA256SUMS", b"new")).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let new: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_ne!(old["id"], new["id"]);
+            assert_eq!(new["available"], true);
+
+            let req = Request::builder()
+                .uri("/files?tag=harness-files&available=true")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let files: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(files.len(), 1);
+            assert_eq!(files[0]["id"], new["id"]);
+
+            let req = Request::builder()
+                .uri(format!("/file/{}/download", old["id"].as_str().unwrap()))
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, b"old".as_slice());
+
+            tag.assemble().await.unwrap();
+            let exported = tag.export_dir().join("files/iso/SHA256SUMS");
+            assert_eq!(std::fs::read(exported).unwrap(), b"new");
+        })
+    }
+
+    #[test]
+    fn test_oci_registry() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let tag = TagBuilder::new("harness-oci").create().await.unwrap();
+            let put = |uri: String, body: Vec<u8>| {
+                Request::builder()
+                    .method("PUT")
+                    .uri(uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::from(body))
+                    .unwrap()
+            };
+            let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let push_blob = |data: &[u8]| {
+                let digest = crate::db::oci::digest_of(data);
+                put(format!("/oci/blob?tag=harness-oci&digest={digest}"), data.to_vec())
+            };
+
+            let config = br#"{"architecture":"amd64","os":"linux"}"#;
+            let layer = b"not really a tarball";
+            let wrong = format!(
+                "/oci/blob?tag=harness-oci&digest={}",
+                crate::db::oci::digest_of(b"x")
+            );
+            let (status, _) = harness.request(put(wrong, layer.to_vec())).await.unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+
+            let (status, _) = harness.request(push_blob(config)).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let manifest = serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "config": {
+                    "mediaType": "application/vnd.oci.image.config.v1+json",
+                    "digest": crate::db::oci::digest_of(config),
+                    "size": config.len(),
+                },
+                "layers": [{
+                    "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                    "digest": crate::db::oci::digest_of(layer),
+                    "size": layer.len(),
+                }],
+            })
+            .to_string();
+            let push_manifest = || {
+                let uri = "/oci/manifest?tag=harness-oci&name=hello&reference=latest";
+                put(uri.to_owned(), manifest.clone().into_bytes())
+            };
+            // the layer wasn't pushed yet
+            let (status, _) = harness.request(push_manifest()).await.unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let (status, _) = harness.request(push_blob(layer)).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = harness.request(push_manifest()).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            let (status, _) = harness.request(get("/v2/")).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let layer_uri = |image: &str| {
+                format!(
+                    "/v2/harness-oci/{image}/blobs/{}",
+                    crate::db::oci::digest_of(layer)
+                )
+            };
+            // nothing is served before the tag is published
+            let (status, _) = harness
+                .request(get("/v2/harness-oci/hello/manifests/latest"))
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            let (status, _) = harness.request(get(&layer_uri("hello"))).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            tag.assemble().await.unwrap();
+            let (status, body) = harness
+                .request(get("/v2/harness-oci/hello/manifests/latest"))
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, manifest.as_bytes());
+            let uri = format!(
+                "/v2/harness-oci/hello/manifests/{}",
+                crate::db::oci::digest_of(manifest.as_bytes())
+            );
+            let (status, _) = harness.request(get(&uri)).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let (status, body) = harness.request(get(&layer_uri("hello"))).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, layer.as_slice());
+            // blobs are only served as part of an image referring to them
+            let (status, _) = harness.request(get(&layer_uri("other"))).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            let (status, body) = harness
+                .request(get("/v2/harness-oci/hello/tags/list"))
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let tags: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(tags["tags"], serde_json::json!(["latest"]));
+            let (status, body) = harness
+                .request(get("/v2/harness-oci/missing/manifests/latest"))
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(error["errors"][0]["code"], "MANIFEST_UNKNOWN");
+        })
+    }
+
+    #[test]
+    fn test_request_id() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let req = Request::get("/version").body(Body::empty()).unwrap();
+            let res = harness.router().oneshot(req).await.unwrap();
+            let id = res.headers()[crate::request_id::REQUEST_ID_HEADER]
+                .to_str()
+                .unwrap();
+            assert!(ulid::Ulid::from_string(id).is_ok());
+
+            // IDs from clients are kept, and added to JSON error bodies
+            let req = Request::get("/v2/harness-request-id/missing/manifests/latest")
+                .header(crate::request_id::REQUEST_ID_HEADER, "release-42")
+                .body(Body::empty())
+                .unwrap();
+            let res = harness.router().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            assert_eq!(res.headers()[crate::request_id::REQUEST_ID_HEADER], "release-42");
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(error["request_id"], "release-42");
+            assert!(error["errors"][0]["code"].is_string());
+        })
+    }
+
+    #[test]
+    fn test_namespaces() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let request = |method: &str, uri: &str, token: &str, body: serde_json::Value| {
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap()
+            };
+
+            let create = serde_json::json!({ "name": "harness-team" });
+            let req = request("POST", "/namespace", ADMIN_TOKEN, create);
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::CREATED);
+            let create = serde_json::json!({ "name": "Harness/Team" });
+            let req = request("POST", "/namespace", ADMIN_TOKEN, create);
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+
+            // a token administering every tag of the namespace, and nothing outside of it
+            let (token, secret) = crate::db::token::ApiToken::new("harness-team".to_owned(), false);
+            let token = token.save().await.unwrap();
+            let uri = format!("/ns/harness-team/token/{}/permissions", token.id.id.to_raw());
+            let grant = serde_json::json!({ "tag": "*", "role": "admin" });
+            let (status, body) = harness
+                .request(request("POST", &uri, ADMIN_TOKEN, grant))
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::CREATED);
+            let permission: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(permission["namespace"], "harness-team");
+
+            let tag = serde_json::json!({ "name": "harness-namespaced" });
+            let req = request("POST", "/repo", &secret, tag.clone());
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::FORBIDDEN);
+            let req = request("POST", "/ns/harness-team/repo", &secret, tag);
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::CREATED);
+            let tag: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(tag["namespace"], "harness-team");
+
+            // only visible from the namespace
+            let get = |uri: &str| request("GET", uri, ADMIN_TOKEN, serde_json::Value::Null);
+            let (status, _) = harness.request(get("/repo/harness-namespaced")).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            let uri = "/ns/harness-team/repo/harness-namespaced";
+            let (status, _) = harness.request(get(uri)).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let (_, body) = harness.request(get("/ns/harness-team/repos")).await.unwrap();
+            let tags: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(tags.len(), 1);
+            let (status, _) = harness.request(get("/ns/missing/repos")).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            // objects are stored under the namespace's prefix
+            let mut req = RpmUpload::new("harness-namespaced").raw_request().unwrap();
+            *req.uri_mut() = format!("/ns/harness-team{}", req.uri()).parse().unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let rpms = Rpm::get_by_tag("harness-namespaced").await.unwrap();
+            assert!(rpms[0].object_key.starts_with("ns/harness-team/rpm/"));
+
+            // records of the namespace's tags aren't visible from the global scope
+            let id: ulid::Ulid = rpms[0].id.id.to_raw().parse().unwrap();
+            let global = crate::namespace::scope(None, Rpm::get(id)).await.unwrap();
+            assert!(global.is_none());
+            let scoped = crate::namespace::scope(Some("harness-team".to_owned()), Rpm::get(id));
+            assert!(scoped.await.unwrap().is_some());
+            // nor can packages be uploaded to them
+            let req = RpmUpload::new("harness-namespaced").raw_request().unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            // a global `*` grant doesn't reach into namespaces
+            let (global, global_secret) =
+                crate::db::token::ApiToken::new("harness-global".to_owned(), false);
+            let global = global.save().await.unwrap();
+            let uri = format!("/token/{}/permissions", global.id.id.to_raw());
+            let grant = serde_json::json!({ "tag": "*", "role": "admin" });
+            let (status, _) = harness
+                .request(request("POST", &uri, ADMIN_TOKEN, grant))
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::CREATED);
+            let tag = serde_json::json!({ "name": "harness-namespaced-other" });
+            let req = request("POST", "/ns/harness-team/repo", &global_secret, tag);
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::FORBIDDEN);
+
+            let uri = "/namespace/harness-team";
+            let req = request("DELETE", uri, ADMIN_TOKEN, serde_json::Value::Null);
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+        })
+    }
+
+    #[test]
+    fn test_reload_config() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let reload = || {
+                Request::post("/admin/reload")
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let req = Request::post("/admin/reload").body(Body::empty()).unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+            let config = harness.dir.join(CONFIG_FILE);
+            // still above the age of the files left over in test_janitor
+            std::fs::write(&config, "janitor_min_age = 90000\n").unwrap();
+            let (status, body) = harness.request(reload()).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let dynamic: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(dynamic["janitor_min_age"], 90000);
+            assert_eq!(crate::config::dynamic().janitor_min_age, 90000);
+
+            // invalid configs are rejected, keeping the current one
+            std::fs::write(
+                &config,
+                "janitor_min_age = 60\nlog_level = \"subatomic_ng=loud\"\n",
+            )
+            .unwrap();
+            let (status, _) = harness.request(reload()).await.unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(crate::config::dynamic().janitor_min_age, 90000);
+
+            // options removed from the file go back to their defaults
+            std::fs::write(&config, "").unwrap();
+            let (status, _) = harness.request(reload()).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(crate::config::dynamic().janitor_min_age, 86400);
+        })
+    }
+
+    #[test]
+    fn test_notification_queue() {
+        run(async {
+            let _harness = TestHarness::get().await;
+            let sub = EmailSubscription::new(
+                "harness@example.com".to_owned(),
+                vec!["harness-notify".to_owned()],
+                vec![],
+                DigestMode::Daily,
+            )
+            .save()
+            .await
+            .unwrap();
+
+            let first = Notification::new(NotificationKind::Upload, Some("harness-notify"), "a");
+            let second = Notification::new(NotificationKind::Upload, Some("harness-notify"), "b");
+            sub.enqueue(&second).await.unwrap();
+            sub.enqueue(&first).await.unwrap();
+
+            // oldest first, and taken only once
+            let queued = sub.take_queued().await.unwrap();
+            assert_eq!(queued, [first, second]);
+            assert!(sub.take_queued().await.unwrap().is_empty());
+            sub.delete().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_janitor() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let config = &harness.config;
+            let old = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 86400);
+            let age = |path: &std::path::Path| {
+                std::fs::File::open(path).unwrap().set_modified(old).unwrap();
+            };
+
+            let left_over = config.cache_dir.join("harness-janitor-upload");
+            std::fs::write(&left_over, b"left over").unwrap();
+            age(&left_over);
+            let fresh = config.cache_dir.join("harness-janitor-fresh");
+            std::fs::write(&fresh, b"in progress").unwrap();
+            let staging = config
+                .repo_cache_dir
+                .join("harness-janitor")
+                .join("harness-janitor_missing");
+            std::fs::create_dir_all(&staging).unwrap();
+            age(&staging);
+
+            let report = crate::janitor::run(config).await.unwrap();
+            assert!(report.staged_files >= 1);
+            assert!(report.staging_dirs >= 1);
+            assert!(!left_over.exists());
+            assert!(!staging.exists());
+            // younger than the minimum age, may still be in use
+            assert!(fresh.exists());
+            std::fs::remove_file(fresh).unwrap();
+        })
+    }
+
+    #[test]
+    fn test_concurrent_get() {
+        use crate::obj_store::{ObjectStorage, StorageBackend};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        /// Backend counting and slowing down downloads, so concurrent ones overlap
+        struct Counting {
+            inner: Arc<dyn StorageBackend>,
+            downloads: AtomicUsize,
+        }
+
+        #[async_trait::async_trait]
+        impl StorageBackend for Counting {
+            async fn put_file(&self, key: &str, path: PathBuf) -> Result<()> {
+                self.inner.put_file(key, path).await
+            }
+            async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+                self.inner.put_bytes(key, bytes).await
+            }
+            async fn get_object(&self, key: &str) -> Result<PathBuf> {
+                self.downloads.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                self.inner.get_object(key).await
+            }
+            async fn delete_object(&self, key: &str) -> Result<()> {
+                self.inner.delete_object(key).await
+            }
+        }
+
+        run(async {
+            let harness = TestHarness::get().await;
+            let dir = harness.dir.join("concurrent-get");
+            std::fs::create_dir_all(&dir).unwrap();
+            let local = object_store::local::LocalFileSystem::new_with_prefix(&dir).unwrap();
+            let local = Arc::new(local) as Arc<dyn object_store::ObjectStore>;
+            let backend = Arc::new(Counting {
+                inner: Arc::new(local),
+                downloads: AtomicUsize::new(0),
+            });
+            let cache = crate::cache::Cache::new(harness.dir.join("concurrent-get-cache"));
+            let store = ObjectStorage::new(backend.clone(), cache);
+
+            backend.put_bytes("harness/shared.bin", b"shared".to_vec()).await.unwrap();
+            let gets = (0..8).map(|_| store.get("harness/shared.bin"));
+            let paths = futures::future::try_join_all(gets).await.unwrap();
+            assert_eq!(backend.downloads.load(Ordering::Relaxed), 1);
+            for path in paths {
+                assert_eq!(std::fs::read(path).unwrap(), b"shared");
+            }
+            assert_eq!(store.pending_downloads(), 0);
+
+            // a cancelled request doesn't leave its download registered
+            backend
+                .put_bytes("harness/cancelled.bin", b"cancelled".to_vec())
+                .await
+                .unwrap();
+            let get = store.get("harness/cancelled.bin");
+            let res = tokio::time::timeout(std::time::Duration::from_millis(10), get).await;
+            assert!(res.is_err());
+            assert_eq!(store.pending_downloads(), 0);
+            let path = store.get("harness/cancelled.bin").await.unwrap();
+            assert_eq!(std::fs::read(path).unwrap(), b"cancelled");
+        })
+    }
+
+    #[test]
+    fn test_get_object_streamed() {
+        run(async {
+            TestHarness::get().await;
+            let store = crate::obj_store::object_store();
+            // spans several chunks of the download buffer
+            let data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+            store
+                .backend
+                .put_bytes("harness/streamed.bin", data.clone())
+                .await
+                .unwrap();
+
+            let path = store.backend.get_object("harness/streamed.bin").await.unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), data);
+            std::fs::remove_file(path).unwrap();
+        })
+    }
+
+    #[test]
+    fn test_cache_admin() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-cache").create().await.unwrap();
+            let req = RpmUpload::new("harness-cache").raw_request().unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let rpm: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let key = rpm["object_key"].as_str().unwrap();
+
+            let admin = |method: &str, uri: &str| {
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let (status, body) = harness.request(admin("GET", "/admin/cache")).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert!(stats["entries"].as_u64().unwrap() >= 1);
+            assert!(stats["prefixes"]["rpm"]["bytes"].as_u64().unwrap() > 0);
+
+            let uri = format!("/admin/cache/{key}");
+            let (status, _) = harness.request(admin("DELETE", &uri)).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = harness.request(admin("DELETE", &uri)).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            let uri = "/admin/cache/rpm/../../etc/passwd";
+            let (status, _) = harness.request(admin("DELETE", uri)).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            // evicted objects are downloaded again
+            let path = crate::obj_store::object_store().get(key).await.unwrap();
+            assert!(path.exists());
+        })
+    }
+
+    #[test]
+    fn test_replicated_resync() {
+        use crate::obj_store::{replicated::ReplicatedBackend, StorageBackend};
+        use std::sync::Arc;
+
+        run(async {
+            let harness = TestHarness::get().await;
+            let local = |name: &str| {
+                let dir = harness.dir.join(name);
+                std::fs::create_dir_all(&dir).unwrap();
+                let store = object_store::local::LocalFileSystem::new_with_prefix(dir).unwrap();
+                let store = Arc::new(store) as Arc<dyn object_store::ObjectStore>;
+                Arc::new(store) as Arc<dyn StorageBackend>
+            };
+            let primary = local("resync-primary");
+            let replica = local("resync-replica");
+            let backend = ReplicatedBackend::new(primary.clone(), replica.clone());
+
+            backend.put_bytes("both", b"both".to_vec()).await.unwrap();
+            primary.put_bytes("primary", b"primary".to_vec()).await.unwrap();
+            replica.put_bytes("replica", b"replica".to_vec()).await.unwrap();
+
+            let report = backend.resync().await.unwrap();
+            assert_eq!(report.copied_to_replica, 1);
+            assert_eq!(report.copied_to_primary, 1);
+            assert!(report.failed.is_empty());
+
+            let primary = primary.list_objects().await.unwrap();
+            let replica = replica.list_objects().await.unwrap();
+            assert_eq!(primary.len(), 3);
+            assert_eq!(primary, replica);
+
+            // the harness has no replica to resync
+            let req = Request::builder()
+                .method("POST")
+                .uri("/admin/resync")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+        })
+    }
+
+    #[test]
+    fn test_health() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let req = Request::get("/health").body(Body::empty()).unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let health: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_ne!(health["status"], "error", "{health}");
+            for component in ["database", "object_store", "cache_dir", "repo_cache_dir"] {
+                assert_eq!(health["components"][component]["status"], "ok", "{health}");
+            }
+            // only needed with REPODATA_BACKEND=createrepo
+            assert_eq!(health["components"]["createrepo_c"]["required"], false);
+        })
+    }
+
+    #[test]
+    fn test_probes() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let req = Request::get("/livez").body(Body::empty()).unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            let req = Request::get("/readyz").body(Body::empty()).unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            let ready: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(status, StatusCode::OK, "{ready}");
+            for component in ["config", "database", "object_store"] {
+                assert_eq!(ready["components"][component]["status"], "ok", "{ready}");
+            }
+        })
+    }
+
+    #[test]
+    fn test_upload_sign_assemble() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let key = GpgKey::new(
+                "harness-key",
+                None,
+                "Harness <harness@example.com>",
+                &KeyOptions::default(),
+            )
+            .unwrap()
+            .save()
+            .await
+            .unwrap();
+            let tag = TagBuilder::new("harness-assemble")
+                .signing_key("harness-key")
+                .create()
+                .await
+                .unwrap();
+
+            let req = RpmUpload::new("harness-assemble").prune(true).request().unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            let rpm = Rpm::get_by_tag("harness-assemble").await.unwrap().remove(0);
+            let signed = rpm.sign(key).await.unwrap();
+            assert!(signed.signed_object_key.is_some());
+
+            tag.assemble().await.unwrap();
+            assert!(tag.export_dir().join("repodata/repomd.xml").exists());
+            assert!(tag.export_dir().join("repodata/repomd.xml.asc").exists());
+            assert!(tag
+                .export_dir()
+                .join("RPM-GPG-KEY-harness-assemble")
+                .exists());
+        })
+    }
+
+    #[test]
+    fn test_comps_assemble() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let tag = TagBuilder::new("harness-comps").create().await.unwrap();
+
+            let req = Request::put("/repo/harness-comps/comps")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .header(header::CONTENT_TYPE, "application/xml")
+                .body(Body::from(
+                    "<comps><group><id>core</id></group></comps>",
+                ))
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            let req = RpmUpload::new("harness-comps").prune(true).request().unwrap();
+            harness.request(req).await.unwrap();
+
+            let tag = Tag::get(&tag.name).await.unwrap().unwrap();
+            assert!(tag.comps_key.is_some());
+            tag.assemble().await.unwrap();
+
+            let repomd =
+                std::fs::read_to_string(tag.export_dir().join("repodata/repomd.xml")).unwrap();
+            assert!(repomd.contains(r#"type="group""#));
+        })
+    }
+
+    #[test]
+    fn test_advisory_assemble() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let tag = TagBuilder::new("harness-advisory").create().await.unwrap();
+
+            let req = RpmUpload::new("harness-advisory")
+                .prune(true)
+                .raw_request()
+                .unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = created["id"].as_str().unwrap();
+
+            let advisory = serde_json::json!({
+                "id": "HARNESS-2025-0001",
+                "title": "anda-srpm-macros bugfix update",
+                "type": "bugfix",
+                "description": "Fixes macros",
+                "packages": [id],
+            });
+            let req = Request::post("/repo/harness-advisory/advisories")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(advisory.to_string()))
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::CREATED);
+
+            let req = Request::get("/repo/harness-advisory/advisories")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let advisories: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(advisories.len(), 1);
+
+            tag.assemble().await.unwrap();
+            let repomd =
+                std::fs::read_to_string(tag.export_dir().join("repodata/repomd.xml")).unwrap();
+            assert!(repomd.contains(r#"type="updateinfo""#));
+        })
+    }
+
+    #[test]
+    fn test_build_images() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let tag = TagBuilder::new("harness-images").build_images().create().await.unwrap();
+            let req = RpmUpload::new("harness-images").prune(true).request().unwrap();
+            harness.request(req).await.unwrap();
+            tag.assemble().await.unwrap();
+
+            let compose_id = tag.published_compose().unwrap();
+            let compose = TagCompose::get(&compose_id).await.unwrap().unwrap();
+            assert_eq!(compose.artifacts.len(), 1);
+            assert_eq!(compose.artifacts[0].name, "repomd.xml");
+            let req = Request::get(format!("/compose/{compose_id}/artifacts/repomd.xml"))
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let res = harness.router().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.headers()[header::CONTENT_DISPOSITION],
+                "attachment; filename=\"repomd.xml\"; filename*=UTF-8''repomd.xml"
+            );
+
+            // a failed image build fails the assembly before anything is published
+            let tag = TagBuilder::new("harness-images-fail")
+                .build_images()
+                .create()
+                .await
+                .unwrap();
+            let req = RpmUpload::new("harness-images-fail").prune(true).request().unwrap();
+            harness.request(req).await.unwrap();
+            assert!(tag.assemble().await.is_err());
+            assert!(tag.published_compose().is_none());
+            assert!(!tag.export_dir().exists());
+        })
+    }
+
+    #[test]
+    fn test_split_arches() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let tag = TagBuilder::new("harness-split-arches")
+                .split_arches(&["x86_64", "aarch64"])
+                .create()
+                .await
+                .unwrap();
+
+            let req = RpmUpload::new("harness-split-arches").prune(true).request().unwrap();
+            harness.request(req).await.unwrap();
+            tag.assemble().await.unwrap();
+
+            assert!(!tag.export_dir().join("repodata").exists());
+            for arch in ["x86_64", "aarch64"] {
+                let repo_dir = tag.export_dir().join(arch);
+                assert!(repo_dir.join("repodata/repomd.xml").exists());
+                // the noarch fixture is merged into every architecture's repo
+                let pkgs = std::fs::read_dir(&repo_dir)
+                    .unwrap()
+                    .filter_map(|e| e.ok())
+                    .filter(|e| e.path().extension().is_some_and(|ext| ext == "rpm"))
+                    .count();
+                assert_eq!(pkgs, 1);
+            }
+        })
+    }
+
+    #[test]
+    fn test_export_path() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-export-path").create().await.unwrap();
+            let update = |body: serde_json::Value| {
+                Request::patch("/repo/harness-export-path")
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap()
+            };
+
+            for export_path in [
+                "{tag}/{release}",
+                "../{tag}",
+                ".",
+                "/",
+                "products/{tag}/{arch}",
+            ] {
+                let req = update(serde_json::json!({ "export_path": export_path }));
+                let (status, _) = harness.request(req).await.unwrap();
+                assert_eq!(status, StatusCode::BAD_REQUEST, "{export_path}");
+            }
+
+            let req = update(serde_json::json!({
+                "split_arches": true,
+                "arches": ["x86_64"],
+                "export_path": "products/{tag}/{arch}",
+            }));
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            let req = RpmUpload::new("harness-export-path").prune(true).request().unwrap();
+            harness.request(req).await.unwrap();
+            let tag = Tag::get("harness-export-path").await.unwrap().unwrap();
+            tag.assemble().await.unwrap();
+
+            let products = harness.config.export_dir.join("products/harness-export-path");
+            assert!(products.join("x86_64/repodata/repomd.xml").exists());
+            // the compose itself stays published at the default location
+            assert!(tag.export_dir().join("x86_64/repodata/repomd.xml").exists());
+
+            let default_dir = tag.export_dir();
+            let req = update(serde_json::json!({ "export_path": "legacy/{tag}" }));
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let tag = Tag::get("harness-export-path").await.unwrap().unwrap();
+            assert_eq!(
+                tag.export_dir(),
+                harness.config.export_dir.join("legacy/harness-export-path")
+            );
+            // the published compose moves right away, without links left at the old paths
+            assert!(tag.export_dir().join("x86_64/repodata/repomd.xml").exists());
+            assert!(products.join("x86_64").symlink_metadata().is_err());
+            assert!(default_dir.symlink_metadata().is_err());
+            tag.assemble().await.unwrap();
+            assert!(tag.export_dir().join("x86_64/repodata/repomd.xml").exists());
+            assert!(tag.published_compose().is_some());
+
+            // other tags can't be published over it
+            TagBuilder::new("harness-export-other")
+                .create()
+                .await
+                .unwrap();
+            let update_other = |export_path: &str| {
+                Request::patch("/repo/harness-export-other")
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        serde_json::json!({ "export_path": export_path }).to_string(),
+                    ))
+                    .unwrap()
+            };
+            for export_path in ["legacy", "legacy/harness-export-path/{tag}"] {
+                let (status, _) = harness.request(update_other(export_path)).await.unwrap();
+                assert_eq!(status, StatusCode::CONFLICT, "{export_path}");
+            }
+
+            // nor is anything but a link replaced
+            let occupied = harness
+                .config
+                .export_dir
+                .join("occupied/harness-export-other");
+            std::fs::create_dir_all(&occupied).unwrap();
+            let (status, _) = harness
+                .request(update_other("occupied/{tag}"))
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let req = RpmUpload::new("harness-export-other").prune(true).request().unwrap();
+            harness.request(req).await.unwrap();
+            let other = Tag::get("harness-export-other").await.unwrap().unwrap();
+            assert!(other.assemble().await.is_err());
+            assert!(occupied.is_dir() && !occupied.is_symlink());
+        })
+    }
+
+    #[test]
+    fn test_sign_rpm() {
+        run(async {
+            let harness = TestHarness::get().await;
+            GpgKey::new(
+                "harness-sign-key",
+                None,
+                "Harness <harness@example.com>",
+                &KeyOptions::default(),
+            )
+            .unwrap()
+            .save()
+            .await
+            .unwrap();
+            let mut tag = TagBuilder::new("harness-sign").create().await.unwrap();
+
+            let req = RpmUpload::new("harness-sign").raw_request().unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = created["id"].as_str().unwrap().to_owned();
+
+            let sign = |query: &str| {
+                Request::post(format!("/rpm/{id}/sign{query}"))
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let (status, _) = harness.request(sign("")).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+            let (status, _) = harness.request(sign("?key=nonexistent")).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            // no tag signs with the key
+            let (status, _) = harness.request(sign("?key=harness-sign-key")).await.unwrap();
+            assert_eq!(status, StatusCode::FORBIDDEN);
+
+            // defaults to the tag's key
+            tag.set_gpg_key("harness-sign-key");
+            tag.save().await.unwrap();
+            let (status, body) = harness.request(sign("")).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let signed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let first = signed["signed_object_key"].as_str().unwrap().to_owned();
+
+            // signing again writes a new object, replacing the old one
+            let (status, body) = harness.request(sign("?key=harness-sign-key")).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let signed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_ne!(signed["signed_object_key"].as_str().unwrap(), first);
+            assert!(crate::obj_store::object_store().refresh(&first).await.is_err());
+
+            tag.locked = true;
+            tag.save().await.unwrap();
+            let (status, _) = harness.request(sign("")).await.unwrap();
+            assert_eq!(status, StatusCode::LOCKED);
+        })
+    }
+
+    #[test]
+    fn test_auto_sign() {
+        run(async {
+            let harness = TestHarness::get().await;
+            GpgKey::new(
+                "harness-auto-sign-key",
+                None,
+                "Harness <harness@example.com>",
+                &KeyOptions::default(),
+            )
+            .unwrap()
+            .save()
+            .await
+            .unwrap();
+
+            // a signing key alone doesn't sign uploads
+            TagBuilder::new("harness-auto-sign-off")
+                .signing_key("harness-auto-sign-key")
+                .create()
+                .await
+                .unwrap();
+            let req = RpmUpload::new("harness-auto-sign-off").raw_request().unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert!(created["signed_object_key"].is_null());
+
+            let mut tag = TagBuilder::new("harness-auto-sign")
+                .signing_key("harness-auto-sign-key")
+                .create()
+                .await
+                .unwrap();
+            tag.auto_sign = true;
+            tag.save().await.unwrap();
+            let req = RpmUpload::new("harness-auto-sign").request().unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let results: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert!(results[0]["rpm"]["signed_object_key"].is_string());
+            // committed signed
+            let id = results[0]["rpm"]["id"].as_str().unwrap().parse().unwrap();
+            let rpm = Rpm::get(id).await.unwrap().unwrap();
+            assert!(rpm.signed_object_key.is_some());
+
+            // uploading an unsigned package again signs it once the tag signs uploads
+            let mut off = Tag::get("harness-auto-sign-off").await.unwrap().unwrap();
+            off.auto_sign = true;
+            off.save().await.unwrap();
+            let req = RpmUpload::new("harness-auto-sign-off").raw_request().unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let duplicate: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(duplicate["id"], created["id"]);
+            assert!(duplicate["signed_object_key"].is_string());
+        })
+    }
+
+    #[test]
+    fn test_assemble_signed() {
+        run(async {
+            let harness = TestHarness::get().await;
+            GpgKey::new(
+                "harness-assemble-signed-key",
+                None,
+                "Harness <harness@example.com>",
+                &KeyOptions::default(),
+            )
+            .unwrap()
+            .save()
+            .await
+            .unwrap();
+            let mut tag = TagBuilder::new("harness-assemble-signed")
+                .signing_key("harness-assemble-signed-key")
+                .create()
+                .await
+                .unwrap();
+            tag.require_signed = true;
+            tag.save().await.unwrap();
+
+            let req = RpmUpload::new("harness-assemble-signed").prune(true).request().unwrap();
+            harness.request(req).await.unwrap();
+            assert!(tag.assemble().await.is_err());
+
+            tag.sign_unsigned().await.unwrap();
+            tag.assemble().await.unwrap();
+            let pkg = &tag.get_available_rpms().await.unwrap()[0];
+            let signed_key = pkg.signed_object_key.clone().unwrap();
+            let exported = tag.export_dir().join(RpmRef::from(pkg).file_name());
+            let src = std::fs::read_link(&exported).unwrap();
+            assert!(src.ends_with(&signed_key));
+            // repodata describes the signed copy, not the upload
+            let digest = crate::db::rpm::file_sha256(&exported).unwrap();
+            assert_eq!(pkg.signed_sha256.as_deref(), Some(digest.as_str()));
+            assert_ne!(pkg.sha256, pkg.signed_sha256);
+            assert_eq!(pkg.signed_size, Some(std::fs::metadata(&exported).unwrap().len()));
+
+            // requiring signatures without a key to sign with is refused
+            TagBuilder::new("harness-assemble-unsigned").create().await.unwrap();
+            let req = Request::patch("/repo/harness-assemble-unsigned")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"require_signed": true}"#))
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        })
+    }
+
+    #[test]
+    fn test_public_key() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let key = GpgKey::new(
+                "harness-public-key",
+                None,
+                "Harness <harness@example.com>",
+                &KeyOptions::default(),
+            )
+            .unwrap()
+            .save()
+            .await
+            .unwrap();
+
+            let get_key = |id: &str| {
+                Request::get(format!("/key/{id}/public"))
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let (status, _) = harness.request(get_key("nonexistent")).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            let (status, body) = harness.request(get_key("harness-public-key")).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(String::from_utf8(body).unwrap(), key.public_key);
+
+            // split repos get the key at their root as well
+            let tag = TagBuilder::new("harness-public-key")
+                .signing_key("harness-public-key")
+                .split_arches(&["x86_64"])
+                .create()
+                .await
+                .unwrap();
+            let req = RpmUpload::new("harness-public-key").prune(true).request().unwrap();
+            harness.request(req).await.unwrap();
+            tag.assemble().await.unwrap();
+            let exported = std::fs::read_to_string(tag.export_dir().join(tag.gpg_key_file_name()));
+            assert_eq!(exported.unwrap(), key.public_key);
+        })
+    }
+
+    #[test]
+    fn test_rotate_key() {
+        run(async {
+            let harness = TestHarness::get().await;
+            GpgKey::new(
+                "harness-rotate-old",
+                None,
+                "Harness <harness@example.com>",
+                &KeyOptions::default(),
+            )
+            .unwrap()
+            .save()
+            .await
+            .unwrap();
+            let tag = TagBuilder::new("harness-rotate")
+                .signing_key("harness-rotate-old")
+                .create()
+                .await
+                .unwrap();
+            let req = RpmUpload::new("harness-rotate").prune(true).request().unwrap();
+            harness.request(req).await.unwrap();
+            tag.sign_unsigned().await.unwrap();
+            let owned = tag.get_available_rpms().await.unwrap().remove(0);
+            let old_signed_key = owned.signed_object_key.clone().unwrap();
+
+            // a package shared into the tag is published with its key too
+            TagBuilder::new("harness-rotate-home").create().await.unwrap();
+            let mut shared = Rpm::from_path(FIXTURE_RPM, "harness-rotate-home").unwrap();
+            shared.name = "harness-rotate-shared".to_owned();
+            shared.sha256 = None;
+            crate::obj_store::object_store()
+                .put_bytes(&shared.object_key, std::fs::read(FIXTURE_RPM).unwrap())
+                .await
+                .unwrap();
+            shared.commit_to_db(false, false).await.unwrap();
+            let old_key = GpgKey::get("harness-rotate-old").await.unwrap().unwrap();
+            let shared = shared.sign(old_key).await.unwrap();
+            shared.add_to_tag("harness-rotate", false).await.unwrap();
+            let shared_signed_key = shared.signed_object_key.clone();
+
+            let rotate = |id: &str| {
+                Request::post("/key/harness-rotate-old/rotate")
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::json!({ "id": id }).to_string()))
+                    .unwrap()
+            };
+            let (status, _) = harness.request(rotate("harness-rotate-old")).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+
+            // a key expiring right away could never sign anything
+            let req = Request::post("/key/harness-rotate-old/rotate")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "id": "harness-rotate-new", "expires_in_days": 0 })
+                        .to_string(),
+                ))
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+            let (status, body) = harness.request(rotate("harness-rotate-new")).await.unwrap();
+            assert_eq!(status, StatusCode::ACCEPTED);
+            let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = job["id"]["id"]["String"].as_str().unwrap().to_owned();
+
+            let job = wait_for_job(harness, &id).await;
+            assert_eq!(job["status"], "success", "{job}");
+            let rotated = job["result"].as_array().unwrap();
+            assert_eq!(rotated.len(), 1);
+            assert!(rotated[0]["error"].is_null());
+            assert_eq!(rotated[0]["signed"].as_array().unwrap().len(), 2);
+            let shared = Rpm::get(shared.id.id.to_raw().parse().unwrap())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_ne!(shared.signed_object_key, shared_signed_key);
+
+            // the old signatures are only removed once the new ones are published
+            let owned = Rpm::get(owned.id.id.to_raw().parse().unwrap())
+                .await
+                .unwrap()
+                .unwrap();
+            let new_signed_key = owned.signed_object_key.clone().unwrap();
+            assert_ne!(new_signed_key, old_signed_key);
+            assert!(crate::obj_store::object_store()
+                .get(&old_signed_key)
+                .await
+                .is_err());
+
+            let tag = Tag::get("harness-rotate").await.unwrap().unwrap();
+            let src = std::fs::read_link(tag.export_dir().join(RpmRef::from(&owned).file_name()))
+                .unwrap();
+            assert!(src.ends_with(&new_signed_key));
+            let new_key = tag.get_signing_key().await.unwrap();
+            assert_eq!(new_key.id.id.to_raw(), "harness-rotate-new");
+            let exported = std::fs::read_to_string(tag.export_dir().join(tag.gpg_key_file_name()));
+            assert_eq!(exported.unwrap(), new_key.public_key);
+        })
+    }
+
+    #[test]
+    fn test_key_allowed_tags() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let mut key = GpgKey::new(
+                "harness-release-key",
+                None,
+                "Harness <harness@example.com>",
+                &KeyOptions::default(),
+            )
+            .unwrap();
+            key.allowed_tags = vec!["harness-release".to_owned()];
+            key.save().await.unwrap();
+            TagBuilder::new("harness-release").create().await.unwrap();
+            TagBuilder::new("harness-nightly").create().await.unwrap();
+
+            let set_key = |tag: &str| {
+                Request::post(format!("/repo/{tag}/key"))
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        serde_json::json!({ "key_id": "harness-release-key" }).to_string(),
+                    ))
+                    .unwrap()
+            };
+            let (status, _) = harness.request(set_key("harness-nightly")).await.unwrap();
+            assert_eq!(status, StatusCode::FORBIDDEN);
+            let (status, _) = harness.request(set_key("harness-release")).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            let req = RpmUpload::new("harness-nightly").raw_request().unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = created["id"].as_str().unwrap();
+            let req = Request::post(format!("/rpm/{id}/sign?key=harness-release-key"))
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        })
+    }
+
+    #[test]
+    fn test_rollback() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let tag = TagBuilder::new("harness-rollback").create().await.unwrap();
+
+            let req = RpmUpload::new("harness-rollback").prune(true).request().unwrap();
+            harness.request(req).await.unwrap();
+            tag.assemble().await.unwrap();
+            let first = TagCompose::latest(&RecordId::from_table_key(TAG_TABLE, &tag.name))
+                .await
+                .unwrap()
+                .unwrap();
+            let first_dir = std::fs::read_link(tag.export_dir()).unwrap();
+
+            tag.assemble().await.unwrap();
+            assert_ne!(std::fs::read_link(tag.export_dir()).unwrap(), first_dir);
+
+            let rollback = |compose_id: String| {
+                Request::post(format!("/repo/harness-rollback/rollback/{compose_id}"))
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let (status, _) = harness.request(rollback(first.id.id.to_raw())).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(std::fs::read_link(tag.export_dir()).unwrap(), first_dir);
+
+            // re-assembled from the compose's package list
+            std::fs::remove_dir_all(&first_dir).unwrap();
+            let (status, _) = harness.request(rollback(first.id.id.to_raw())).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert!(tag.export_dir().join("repodata/repomd.xml").exists());
+
+            let (status, _) = harness.request(rollback("nonexistent".to_owned())).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        })
+    }
+
+    #[test]
+    fn test_compose_listing() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let tag = TagBuilder::new("harness-composes").create().await.unwrap();
+
+            let req = RpmUpload::new("harness-composes").prune(true).request().unwrap();
+            harness.request(req).await.unwrap();
+            tag.assemble().await.unwrap();
+            tag.assemble().await.unwrap();
+
+            let get = |uri: String| {
+                Request::get(uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let (status, body) = harness
+                .request(get("/repo/harness-composes/composes".to_owned()))
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let composes: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(composes.len(), 2);
+            assert_eq!(composes.iter().filter(|c| c["published"] == true).count(), 1);
+            assert_eq!(composes[0]["packages"].as_array().unwrap().len(), 1);
+
+            let (_, body) = harness
+                .request(get("/repo/harness-composes/composes?limit=1".to_owned()))
+                .await
+                .unwrap();
+            let page: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(page.len(), 1);
+
+            let published = tag.published_compose().unwrap();
+            let (status, body) = harness.request(get(format!("/compose/{published}"))).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let compose: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(compose["published"], true);
+            assert!(!compose["published_at"].is_null());
+        })
+    }
+
+    #[test]
+    fn test_promote() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-promote-testing").create().await.unwrap();
+            let target = TagBuilder::new("harness-promote").create().await.unwrap();
+
+            let req = RpmUpload::new("harness-promote-testing")
+                .prune(true)
+                .raw_request()
+                .unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = created["id"].as_str().unwrap().to_owned();
+
+            let promote = || {
+                Request::post(format!("/rpm/{id}/promote"))
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        r#"{"tag": "harness-promote", "assemble": true}"#,
+                    ))
+                    .unwrap()
+            };
+            // an older build stays behind, and takes over once the available one is promoted
+            let mut older = Rpm::from_path(FIXTURE_RPM, "harness-promote-testing").unwrap();
+            older.version = "0.2.5".to_owned();
+            older.sha256 = None;
+            older.commit_to_db(false, false).await.unwrap();
+
+            let (status, body) = harness.request(promote()).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let promoted: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let job_id = promoted["job"]["id"]["id"]["String"].as_str().unwrap().to_owned();
+            wait_for_job(harness, &job_id).await;
+
+            let rpm = Rpm::get(id.parse().unwrap()).await.unwrap().unwrap();
+            assert_eq!(crate::db::record_key(&rpm.tag), "harness-promote");
+            assert_eq!(target.get_available_rpms().await.unwrap().len(), 1);
+            assert!(target.export_dir().join("repodata/repomd.xml").exists());
+
+            let source = Tag::get("harness-promote-testing").await.unwrap().unwrap();
+            let left: Vec<String> = source
+                .get_available_rpms()
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|rpm| rpm.version)
+                .collect();
+            assert_eq!(left, ["0.2.5"]);
+
+            let events = crate::db::event_log::LogEvent::get_by_action("promote")
+                .await
+                .unwrap();
+            assert!(events.iter().any(|e| e.data["rpm"] == id.as_str()));
+
+            let (status, _) = harness.request(promote()).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+        })
+    }
+
+    #[test]
+    fn test_events() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-events").create().await.unwrap();
+
+            let req = RpmUpload::new("harness-events").prune(true).raw_request().unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = created["id"].as_str().unwrap().to_owned();
+
+            let request = |method: &str, uri: &str| {
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let (_, body) = harness
+                .request(request("POST", "/repo/harness-events/assemble"))
+                .await
+                .unwrap();
+            let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let job_id = job["id"]["id"]["String"].as_str().unwrap().to_owned();
+            wait_for_job(harness, &job_id).await;
+
+            let events = |uri: &str| {
+                let req = request("GET", uri);
+                async move {
+                    let (status, body) = harness.request(req).await.unwrap();
+                    assert_eq!(status, StatusCode::OK);
+                    serde_json::from_slice::<Vec<serde_json::Value>>(&body).unwrap()
+                }
+            };
+
+            // newest first
+            let all = events("/events?tag=harness-events").await;
+            let actions: Vec<_> = all.iter().map(|e| e["action"].as_str().unwrap()).collect();
+            assert_eq!(actions, ["compose_finished", "assemble", "upload"]);
+            assert_eq!(all[2]["resource"], format!("rpm_package:{id}"));
+            assert_eq!(all[1]["data"]["job"], job_id.as_str());
+
+            let uploads = events("/events?tag=harness-events&type=upload").await;
+            assert_eq!(uploads.len(), 1);
+            assert_eq!(uploads[0]["data"]["rpm"], id.as_str());
+
+            let later = events("/events?tag=harness-events&since=2100-01-01T00:00:00Z").await;
+            assert!(later.is_empty());
+            let earlier = events("/events?tag=harness-events&until=2100-01-01T00:00:00Z").await;
+            assert_eq!(earlier.len(), 3);
+        })
+    }
+
+    #[test]
+    fn test_audit() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-audit").create().await.unwrap();
+
+            let req = RpmUpload::new("harness-audit").raw_request().unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = created["id"].as_str().unwrap().to_owned();
+
+            let request = |method: &str, uri: String| {
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let (status, _) = harness
+                .request(request("POST", format!("/rpm/{id}/available")))
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            // who made the package available, and when
+            let uri = format!("/audit?resource={id}&action=available");
+            let (status, body) = harness.request(request("GET", uri)).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let events: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0]["tag"], "harness-audit");
+            assert!(!events[0]["actor"].as_str().unwrap().is_empty());
+
+            // in the order they happened, the export isn't paginated
+            let uri = format!("/audit?resource=rpm_package:{id}&format=csv&limit=1");
+            let (status, body) = harness.request(request("GET", uri)).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let csv = String::from_utf8(body).unwrap();
+            let actions: Vec<_> = csv
+                .lines()
+                .skip(1)
+                .map(|row| row.split(',').nth(3).unwrap())
+                .collect();
+            assert_eq!(actions, ["upload", "available"]);
+        })
+    }
+
+    #[test]
+    fn test_webhook() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-webhook").create().await.unwrap();
+
+            // receiver forwarding each delivery's signature and body
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            let receive = move |headers: axum::http::HeaderMap, body: String| {
+                let signature = headers[crate::webhook::SIGNATURE_HEADER].to_str().unwrap();
+                tx.send((signature.to_owned(), body)).unwrap();
+                async { StatusCode::OK }
+            };
+            let receiver = Router::new().route("/hook", axum::routing::post(receive));
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move { axum::serve(listener, receiver).await });
+
+            let hook = serde_json::json!({
+                "url": format!("http://{addr}/hook"),
+                "events": ["upload"],
+                "tags": ["harness-webhook"],
+                "secret": "harness-secret",
+            });
+            let req = Request::post("/notifications/webhooks")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(hook.to_string()))
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::CREATED);
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(created["signed"], true);
+            assert!(created.get("secret").is_none());
+
+            let req = RpmUpload::new("harness-webhook").prune(true).request().unwrap();
+            harness.request(req).await.unwrap();
+
+            let delivery = tokio::time::timeout(std::time::Duration::from_secs(10), rx.recv());
+            let (signature, body) = delivery.await.unwrap().unwrap();
+            let event: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(event["action"], "upload");
+            assert_eq!(event["tag"], "harness-webhook");
+
+            let expected = crate::webhook::sign("harness-secret", body.as_bytes());
+            assert_eq!(signature, format!("sha256={expected}"));
+
+            // deliveries queued before a restart are picked up again
+            let hook = Webhook::get(created["id"].as_str().unwrap())
+                .await
+                .unwrap()
+                .unwrap();
+            let event = LogEvent::new("upload", "ci".to_owned(), serde_json::Value::Null)
+                .tag("harness-webhook");
+            let delivery = WebhookDelivery::new(&hook, &event);
+            delivery.save().await.unwrap();
+            crate::webhook::deliver_due().await.unwrap();
+            let delivery = tokio::time::timeout(std::time::Duration::from_secs(10), rx.recv());
+            let (_, body) = delivery.await.unwrap().unwrap();
+            let delivered: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(delivered["id"], serde_json::to_value(&event.id).unwrap());
+
+            for url in ["not a url", "ftp://example.com/hook"] {
+                let req = Request::post("/notifications/webhooks")
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::json!({ "url": url }).to_string()))
+                    .unwrap();
+                let (status, _) = harness.request(req).await.unwrap();
+                assert_eq!(status, StatusCode::BAD_REQUEST, "{url}");
+            }
+        })
+    }
+
+    #[test]
+    fn test_shared_tags() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let home = TagBuilder::new("harness-shared-home").create().await.unwrap();
+            let other = TagBuilder::new("harness-shared-other").create().await.unwrap();
+
+            let req = RpmUpload::new("harness-shared-home")
+                .prune(true)
+                .raw_request()
+                .unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = created["id"].as_str().unwrap().to_owned();
+
+            let request = |method: &str, uri: String| {
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let share = || request("POST", format!("/rpm/{id}/tags/harness-shared-other"));
+            let (status, body) = harness.request(share()).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let membership: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(membership["available"], true);
+            assert_eq!(other.get_available_rpms().await.unwrap().len(), 1);
+
+            let (status, _) = harness.request(share()).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+
+            // availability is tracked separately in each tag
+            let uri = format!("/rpm/{id}/available?tag=harness-shared-other");
+            let (status, _) = harness.request(request("DELETE", uri)).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert!(other.get_available_rpms().await.unwrap().is_empty());
+            assert_eq!(home.get_available_rpms().await.unwrap().len(), 1);
+
+            let uri = format!("/rpm/{id}/tags/harness-shared-other");
+            let (status, _) = harness.request(request("DELETE", uri.clone())).await.unwrap();
+            assert_eq!(status, StatusCode::NO_CONTENT);
+            let (status, _) = harness.request(request("DELETE", uri)).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            // a package still shared into other tags can't be deleted from under them
+            harness.request(share()).await.unwrap();
+            let (status, _) = harness.request(request("DELETE", format!("/rpm/{id}"))).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+            assert_eq!(other.get_available_rpms().await.unwrap().len(), 1);
+
+            let uri = format!("/rpm/{id}/tags/harness-shared-other");
+            harness.request(request("DELETE", uri)).await.unwrap();
+            let (status, _) = harness.request(request("DELETE", format!("/rpm/{id}"))).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert!(home.get_available_rpms().await.unwrap().is_empty());
+        })
+    }
+
+    #[test]
+    fn test_add_tag_package() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-add-package-home").create().await.unwrap();
+            let tag = TagBuilder::new("harness-add-package").create().await.unwrap();
+
+            let req = RpmUpload::new("harness-add-package-home")
+                .prune(true)
+                .raw_request()
+                .unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = created["id"].as_str().unwrap().to_owned();
+
+            let add = |package: &str| {
+                Request::post("/repo/harness-add-package/packages")
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::json!({ "package": package }).to_string()))
+                    .unwrap()
+            };
+            let (status, _) = harness.request(add(&id)).await.unwrap();
+            assert_eq!(status, StatusCode::CREATED);
+            assert_eq!(tag.get_available_rpms().await.unwrap().len(), 1);
+
+            // any upload of the same file counts as already added
+            let nevra = "anda-srpm-macros-0:0.2.6-1.fc41.noarch";
+            let (status, _) = harness.request(add(nevra)).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+
+            let (status, _) = harness.request(add("nonexistent-0:1-1.noarch")).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            let (status, _) = harness.request(add("not a package")).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            let remove = |tag: &str| {
+                Request::delete(format!("/repo/{tag}/packages/{id}"))
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let (status, _) = harness.request(remove("harness-add-package")).await.unwrap();
+            assert_eq!(status, StatusCode::NO_CONTENT);
+            assert!(tag.get_available_rpms().await.unwrap().is_empty());
+            let events = crate::db::event_log::LogEvent::get_by_action("untag")
+                .await
+                .unwrap();
+            assert!(events.iter().any(|e| e.data["rpm"] == id.as_str()));
+
+            let (status, _) = harness.request(remove("harness-add-package")).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            let (status, _) = harness.request(remove("harness-add-package-home")).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+
+            // removing it from its own tag leaves it in the tag it was added to
+            harness.request(add(&id)).await.unwrap();
+            let (status, _) = harness.request(remove("harness-add-package-home")).await.unwrap();
+            assert_eq!(status, StatusCode::NO_CONTENT);
+            let rpm = Rpm::get(id.parse().unwrap()).await.unwrap().unwrap();
+            assert_eq!(crate::db::record_key(&rpm.tag), "harness-add-package");
+            assert_eq!(tag.get_available_rpms().await.unwrap().len(), 1);
+        })
+    }
+
+    #[test]
+    fn test_tag_lock() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-lock").create().await.unwrap();
+
+            let req = RpmUpload::new("harness-lock").raw_request().unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = created["id"].as_str().unwrap().to_owned();
+
+            let post = |uri: String| {
+                Request::post(uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let (status, _) = harness.request(post("/repo/harness-lock/lock".to_owned())).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            let req = RpmUpload::new("harness-lock").raw_request().unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::LOCKED);
+            let (status, _) = harness.request(post(format!("/rpm/{id}/available"))).await.unwrap();
+            assert_eq!(status, StatusCode::LOCKED);
+
+            let (status, _) = harness.request(post("/repo/harness-lock/unlock".to_owned())).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = harness.request(post(format!("/rpm/{id}/available"))).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+        })
+    }
+
+    #[test]
+    fn test_assemble_job() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let tag = TagBuilder::new("harness-job").create().await.unwrap();
+            let req = RpmUpload::new("harness-job").prune(true).request().unwrap();
+            harness.request(req).await.unwrap();
+
+            let req = Request::post("/repo/harness-job/assemble")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::ACCEPTED);
+            let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = job["id"]["id"]["String"].as_str().unwrap().to_owned();
+
+            let job = wait_for_job(harness, &id).await;
+            assert_eq!(job["status"], "success", "{job}");
+            assert!(tag.export_dir().join("repodata/repomd.xml").exists());
+        })
+    }
+
+    #[test]
+    fn test_prefetch_job() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-prefetch").create().await.unwrap();
+            let req = RpmUpload::new("harness-prefetch")
+                .prune(true)
+                .raw_request()
+                .unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let rpm: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let key = rpm["object_key"].as_str().unwrap();
+            // as after a restart with an empty cache
+            crate::obj_store::object_store().cache.remove(key).await.unwrap();
+
+            let req = Request::post("/repo/harness-prefetch/prefetch")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::ACCEPTED);
+            let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = job["id"]["id"]["String"].as_str().unwrap().to_owned();
+
+            let job = wait_for_job(harness, &id).await;
+            assert_eq!(job["status"], "success", "{job}");
+            assert_eq!(job["result"]["downloaded"], 1);
+            assert!(crate::obj_store::object_store().cache.get(key).is_some());
+        })
+    }
+
+    #[test]
+    fn test_import_job() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-import").create().await.unwrap();
+            let dir = harness.dir.join("import/harness-import/Packages");
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::copy(FIXTURE_RPM, dir.join("anda-srpm-macros.rpm")).unwrap();
+            let start = || {
+                let body = serde_json::json!({ "tag": "harness-import", "dir": "harness-import" });
+                Request::post("/admin/import")
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap()
+            };
+
+            let (status, body) = harness.request(start()).await.unwrap();
+            assert_eq!(status, StatusCode::ACCEPTED);
+            let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let job = wait_for_job(harness, job["id"]["id"]["String"].as_str().unwrap()).await;
+            assert_eq!(job["status"], "success", "{job}");
+            assert_eq!(job["result"]["imported"], 1);
+            // the source file is left in place
+            assert!(dir.join("anda-srpm-macros.rpm").exists());
+
+            // running it again resumes, skipping what was already imported
+            let (_, body) = harness.request(start()).await.unwrap();
+            let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let job = wait_for_job(harness, job["id"]["id"]["String"].as_str().unwrap()).await;
+            assert_eq!(job["result"]["imported"], 0);
+            assert_eq!(job["result"]["resumed"], 1);
+
+            let tag = Tag::get("harness-import").await.unwrap().unwrap();
+            assert_eq!(tag.get_available_rpms().await.unwrap().len(), 1);
+        })
+    }
+
+    #[test]
+    fn test_backup() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-backup").create().await.unwrap();
+            let data = std::fs::read(FIXTURE_RPM).unwrap();
+            let req = multipart_upload("harness-backup", &[("a.rpm", &data[..])], true).unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+
+            let req = Request::post("/admin/backups")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::ACCEPTED);
+            let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let job = wait_for_job(harness, job["id"]["id"]["String"].as_str().unwrap()).await;
+            assert_eq!(job["status"], "success", "{job}");
+            let name = job["result"]["name"].as_str().unwrap().to_owned();
+            assert!(job["result"]["tables"]["repo_tag"].as_u64().unwrap() >= 1);
+            assert!(job["result"]["objects"].as_u64().unwrap() >= 1);
+
+            let dir = harness.dir.join("backups").join(&name);
+            let manifest = crate::backup::read_manifest(dir.parent().unwrap(), &name)
+                .await
+                .unwrap();
+            let tag = Tag::get("harness-backup").await.unwrap().unwrap();
+            let rpm = &tag.get_available_rpms().await.unwrap()[0];
+            assert!(manifest.objects.contains_key(&rpm.object_key));
+            let data = std::fs::read_to_string(dir.join(crate::backup::DATA_FILE)).unwrap();
+            assert!(data.contains("harness-backup"));
+            assert!(data
+                .lines()
+                .all(|line| line.starts_with("\"INSERT IGNORE INTO ")));
+
+            // backups from a newer release can't be restored, older ones are migrated
+            let mut newer = manifest.clone();
+            newer.schema_version = crate::db::migrations::latest_version() + 1;
+            let err = crate::backup::check_restorable(&newer).await.unwrap_err();
+            assert!(err.to_string().contains("newer"), "{err}");
+
+            let req = Request::get("/admin/backups")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let backups: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert!(backups
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|backup| backup["name"] == name.as_str()));
+
+            // backups are only restored into an empty database
+            let req = Request::post(format!("/admin/backups/{name}/restore"))
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+
+            let req = Request::post("/admin/backups/backup-missing/restore")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        })
+    }
+
+    /// Poll a background job until it's finished, returning its final state
+    async fn wait_for_job(harness: &TestHarness, id: &str) -> serde_json::Value {
+        let mut job = serde_json::Value::Null;
+        for _ in 0..100 {
+            let req = Request::get(format!("/jobs/{id}"))
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let (status, body) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            job = serde_json::from_slice(&body).unwrap();
+            if job["status"] != "queued" && job["status"] != "running" {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        job
+    }
+
+    #[test]
+    fn test_sign_tag() {
+        run(async {
+            let harness = TestHarness::get().await;
+            GpgKey::new(
+                "harness-sign-tag-key",
+                None,
+                "Harness <harness@example.com>",
+                &KeyOptions::default(),
+            )
+            .unwrap()
+            .save()
+            .await
+            .unwrap();
+            let mut tag = TagBuilder::new("harness-sign-tag").create().await.unwrap();
+            let req = RpmUpload::new("harness-sign-tag").prune(true).request().unwrap();
+            harness.request(req).await.unwrap();
+
+            let sign = || {
+                Request::post("/repo/harness-sign-tag/sign")
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let (status, _) = harness.request(sign()).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+
+            tag.set_gpg_key("harness-sign-tag-key");
+            tag.save().await.unwrap();
+            let (status, body) = harness.request(sign()).await.unwrap();
+            assert_eq!(status, StatusCode::ACCEPTED);
+            let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = job["id"]["id"]["String"].as_str().unwrap().to_owned();
+
+            let job = wait_for_job(harness, &id).await;
+            assert_eq!(job["status"], "success", "{job}");
+            let results = job["result"].as_array().unwrap();
+            assert_eq!(results.len(), 1);
+            assert!(results[0]["error"].is_null());
+            assert!(tag
+                .get_available_rpms()
+                .await
+                .unwrap()
+                .iter()
+                .all(|pkg| pkg.signed_object_key.is_some()));
+
+            // already signed packages are skipped
+            let (_, body) = harness.request(sign()).await.unwrap();
+            let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = job["id"]["id"]["String"].as_str().unwrap().to_owned();
+            let job = wait_for_job(harness, &id).await;
+            assert_eq!(job["status"], "success", "{job}");
+            assert!(job["result"].as_array().unwrap().is_empty());
+        })
+    }
+
+    #[test]
+    fn test_build_logs() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let create = |tag: &str| {
+                Request::post("/build")
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::json!({ "tag": tag }).to_string()))
+                    .unwrap()
+            };
+            let (status, _) = harness.request(create("harness-builds-missing")).await.unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            TagBuilder::new("harness-builds").create().await.unwrap();
+            let (status, body) = harness.request(create("harness-builds")).await.unwrap();
+            assert_eq!(status, StatusCode::CREATED);
+            let build: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = build["id"]["id"]["String"].as_str().unwrap().to_owned();
+
+            let upload = |filename: &str| {
+                let mut req =
+                    multipart_upload("harness-builds", &[(filename, b"build log")], false).unwrap();
+                *req.uri_mut() = format!("/build/{id}/upload").parse().unwrap();
+                req
+            };
+            let (status, _) = harness.request(upload("../../escape.log")).await.unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let (status, body) = harness.request(upload("/logs//build.log")).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let build: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(build["logs"][0]["name"], "logs/build.log");
+        })
+    }
+
+    #[test]
+    fn test_rpm_listing() {
+        run(async {
+            let harness = TestHarness::get().await;
+            for tag in ["harness-listing", "harness-listing-other"] {
+                TagBuilder::new(tag).create().await.unwrap();
+                let req = RpmUpload::new(tag).request().unwrap();
+                let (status, _) = harness.request(req).await.unwrap();
+                assert_eq!(status, StatusCode::OK);
+            }
+            let list = |uri: &str| {
+                let req = Request::get(uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .body(Body::empty())
+                    .unwrap();
+                async {
+                    let res = harness.router().oneshot(req).await.unwrap();
+                    let total: u64 = res.headers()["x-total-count"]
+                        .to_str()
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                        .await
+                        .unwrap();
+                    let rpms: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+                    (rpms.len() as u64, total)
+                }
+            };
+
+            // unpaginated without page parameters
+            let (len, total) = list("/rpms").await;
+            assert!(total >= 2);
+            assert_eq!(len, total);
+            let (len, _) = list("/rpms?limit=1").await;
+            assert_eq!(len, 1);
+        })
+    }
+
+    #[test]
+    fn test_object_refs() {
+        run(async {
+            let _harness = TestHarness::get().await;
+            let objstore = crate::obj_store::object_store();
+            let mut rpm = Rpm::from_path(FIXTURE_RPM, "harness-refs").unwrap();
+            rpm.object_key = format!("harness-refs/{}.rpm", ulid::Ulid::new());
+
+            assert!(rpm.acquire_object().await.unwrap());
+            objstore.put_bytes(&rpm.object_key, b"rpm".to_vec()).await.unwrap();
+            // shared by a second package
+            assert!(!rpm.acquire_object().await.unwrap());
+
+            rpm.release_object().await.unwrap();
+            assert!(objstore.refresh(&rpm.object_key).await.is_ok());
+            rpm.release_object().await.unwrap();
+            assert!(objstore.refresh(&rpm.object_key).await.is_err());
+            // stored again by the next package
+            assert!(rpm.acquire_object().await.unwrap());
+            objstore.put_bytes(&rpm.object_key, b"rpm".to_vec()).await.unwrap();
+            rpm.release_object().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_stage_old_packages() {
+        run(async {
+            let harness = TestHarness::get().await;
+            TagBuilder::new("harness-deltas").create().await.unwrap();
+            let req = RpmUpload::new("harness-deltas").request().unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            let rpm = Rpm::get_by_tag("harness-deltas").await.unwrap().remove(0);
+
+            let present = RpmRef::from(&rpm);
+            let mut gone = Rpm::from_path(FIXTURE_RPM, "harness-deltas").unwrap();
+            gone.object_key = format!("harness-deltas/{}.rpm", ulid::Ulid::new());
+            let gone = RpmRef::from(&gone);
+
+            // the package whose object is gone is skipped instead of failing the assembly
+            let old_dir = harness.config.repo_cache_dir.join("harness-deltas-old");
+            let staged = crate::db::tag::stage_old_packages(&[present, gone], &old_dir)
+                .await
+                .unwrap();
+            assert_eq!(staged, 1);
+            assert_eq!(std::fs::read_dir(&old_dir).unwrap().count(), 1);
+            std::fs::remove_dir_all(old_dir).unwrap();
+        })
+    }
+
+    #[test]
+    fn test_interrupted_jobs() {
+        run(async {
+            let _harness = TestHarness::get().await;
+            use crate::db::job::{Job, JobStatus};
+
+            let mut gone = Job::new("assemble", None);
+            gone.status = JobStatus::Running;
+            gone.instance = Some("harness-gone-instance".to_owned());
+            let gone = gone.save().await.unwrap();
+            // jobs of this instance are still running
+            let mut own = Job::new("assemble", None);
+            own.status = JobStatus::Running;
+            let own = own.save().await.unwrap();
+
+            assert!(Job::fail_interrupted().await.unwrap() >= 1);
+            let job = |job: Job| async move {
+                Job::get(&job.id.id.to_raw()).await.unwrap().unwrap()
+            };
+            let gone = job(gone).await;
+            assert_eq!(gone.status, JobStatus::Failure);
+            assert!(gone.error.is_some());
+            assert_eq!(job(own).await.status, JobStatus::Running);
+        })
+    }
+
+    #[test]
+    fn test_reserved_debug_name() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let create = |name: &str| {
+                Request::post("/repo")
+                    .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(format!(r#"{{"name": "{name}"}}"#)))
+                    .unwrap()
+            };
+            let (status, _) = harness.request(create("harness-reserved-debug")).await.unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+
+            // a tag created before the suffix was reserved blocks splitting debuginfo
+            TagBuilder::new("harness-reserved").create().await.unwrap();
+            TagBuilder::new("harness-reserved-debug").create().await.unwrap();
+            let req = Request::patch("/repo/harness-reserved")
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"split_debuginfo": true}"#))
+                .unwrap();
+            let (status, _) = harness.request(req).await.unwrap();
+            assert_eq!(status, StatusCode::CONFLICT);
+        })
+    }
+
+    #[test]
+    fn test_remove_tag_successor() {
+        run(async {
+            let harness = TestHarness::get().await;
+            let home = TagBuilder::new("harness-successor-home").create().await.unwrap();
+            TagBuilder::new("harness-successor-other").create().await.unwrap();
+
+            let req = RpmUpload::new("harness-successor-home")
+                .prune(true)
+                .raw_request()
+                .unwrap();
+            let (_, body) = harness.request(req).await.unwrap();
+            let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let rpm = Rpm::get(created["id"].as_str().unwrap().parse().unwrap())
+                .await
+                .unwrap()
+                .unwrap();
+            let mut older = Rpm::from_path(FIXTURE_RPM, "harness-successor-home").unwrap();
+            older.version = "0.2.5".to_owned();
+            older.sha256 = None;
+            older.commit_to_db(false, false).await.unwrap();
+            rpm.add_to_tag("harness-successor-other", false).await.unwrap();
+
+            // the package moves to the other tag, the older version takes its place at home
+            let moved = rpm.remove_from_tag("harness-successor-home").await.unwrap();
+            assert_eq!(crate::db::record_key(&moved.tag), "harness-successor-other");
+            assert!(moved.available);
+            let left: Vec<String> = home
+                .get_available_rpms()
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|rpm| rpm.version)
+                .collect();
+            assert_eq!(left, ["0.2.5"]);
+        })
+    }
+}
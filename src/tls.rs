@@ -0,0 +1,66 @@
+//! Native TLS for the HTTP API, so it can be served over HTTPS without a proxy in front
+//!
+//! Renewed certificates are picked up without a restart by polling the certificate and key
+//! files for changes, see [`spawn_reload_task`].
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use axum_server::tls_rustls::RustlsConfig;
+use color_eyre::{eyre::WrapErr, Result};
+
+use crate::config::Config;
+
+/// Load the configured certificate and key, `None` if TLS isn't configured
+pub async fn rustls_config(config: &Config) -> Result<Option<RustlsConfig>> {
+    let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) else {
+        return Ok(None);
+    };
+    // pick ring explicitly, rustls can't choose on its own if a dependency enables another provider
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    let tls = RustlsConfig::from_pem_file(cert, key)
+        .await
+        .wrap_err_with(|| format!("cannot load TLS certificate {}", cert.display()))?;
+    Ok(Some(tls))
+}
+
+/// Last modification of the certificate or key
+fn modified(cert: &Path, key: &Path) -> Option<SystemTime> {
+    let cert = std::fs::metadata(cert).and_then(|m| m.modified()).ok()?;
+    let key = std::fs::metadata(key).and_then(|m| m.modified()).ok()?;
+    Some(cert.max(key))
+}
+
+/// Spawn the background task reloading the certificate and key whenever either file changes
+///
+/// A failed reload keeps serving the previous certificate, and is retried on the next check
+/// in case the certificate and key were caught mid-renewal.
+pub fn spawn_reload_task(
+    tls: RustlsConfig,
+    cert: PathBuf,
+    key: PathBuf,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut loaded = modified(&cert, &key);
+        let mut interval = tokio::time::interval(interval);
+        // the first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let current = modified(&cert, &key);
+            if current.is_none() || current == loaded {
+                continue;
+            }
+            match tls.reload_from_pem_file(&cert, &key).await {
+                Ok(()) => {
+                    tracing::info!(cert = %cert.display(), "reloaded TLS certificate");
+                    loaded = current;
+                }
+                Err(e) => {
+                    tracing::error!(?e, cert = %cert.display(), "failed to reload TLS certificate")
+                }
+            }
+        }
+    })
+}
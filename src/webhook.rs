@@ -0,0 +1,159 @@
+//! Delivery of events to webhooks
+//!
+//! Every recorded [`LogEvent`] is queued in the database for each matching [`Webhook`] and POSTed
+//! as JSON in the background, retrying with exponential backoff. Queued deliveries survive
+//! restarts and are picked up by whichever instance gets to them first, see
+//! [`spawn_delivery_task`]. Webhooks with a secret get an HMAC-SHA256 of the body in the
+//! [`SIGNATURE_HEADER`], as `sha256=<hex>`, so receivers can verify where it came from.
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use color_eyre::Result;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use surrealdb::sql::Datetime;
+
+use crate::db::{
+    event_log::LogEvent,
+    record_key,
+    webhook::{Webhook, WebhookDelivery},
+};
+
+/// Header carrying the event type, i.e. `upload`
+pub const EVENT_HEADER: &str = "x-subatomic-event";
+/// Header carrying the ID of the event, the same across retries
+pub const DELIVERY_HEADER: &str = "x-subatomic-delivery";
+/// Header carrying the signature of the body
+pub const SIGNATURE_HEADER: &str = "x-subatomic-signature";
+
+/// Attempts to deliver an event to a webhook before giving up
+const DELIVERY_ATTEMPTS: u32 = 5;
+/// First delay between attempts, doubled after each one
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(10);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long an instance has to attempt a delivery it claimed before others retry it
+const CLAIM_TTL: Duration = Duration::from_secs(60);
+/// How often queued deliveries are checked for ones due for a retry
+pub const DELIVERY_INTERVAL: Duration = Duration::from_secs(5);
+
+static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .build()
+        .expect("cannot build HTTP client")
+});
+
+/// Queue an event for every matching webhook and start delivering it in the background
+///
+/// This never fails, errors are logged instead so webhooks can't break
+/// the operation that triggered them.
+pub async fn fire(event: &LogEvent) {
+    if let Err(e) = enqueue(event).await {
+        tracing::error!(?e, event = ?event.id, "failed to queue event for webhooks");
+        return;
+    }
+    tokio::spawn(async {
+        if let Err(e) = deliver_due().await {
+            tracing::error!(?e, "failed to deliver webhooks");
+        }
+    });
+}
+
+async fn enqueue(event: &LogEvent) -> Result<()> {
+    for hook in Webhook::get_all().await? {
+        if hook.matches(event) {
+            WebhookDelivery::new(&hook, event).save().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Attempt every queued delivery that is due
+pub async fn deliver_due() -> Result<()> {
+    let due = WebhookDelivery::claim_due(CLAIM_TTL.as_secs()).await?;
+    let attempts = due.into_iter().map(|delivery| async move {
+        let id = delivery.id.clone();
+        if let Err(e) = attempt(delivery).await {
+            tracing::error!(?e, delivery = ?id, "failed to attempt webhook delivery");
+        }
+    });
+    futures::future::join_all(attempts).await;
+    Ok(())
+}
+
+/// Attempt a delivery, removing it once it succeeded or ran out of attempts
+async fn attempt(mut delivery: WebhookDelivery) -> Result<()> {
+    let Some(hook) = Webhook::get(&record_key(&delivery.webhook)).await? else {
+        // the webhook was removed since
+        return delivery.delete().await;
+    };
+
+    let e = match send(&hook, &delivery.event).await {
+        Ok(()) => return delivery.delete().await,
+        Err(e) => e,
+    };
+    delivery.attempts += 1;
+    tracing::warn!(hook = ?hook.id, attempt = delivery.attempts, ?e, "webhook delivery failed");
+    if delivery.attempts >= DELIVERY_ATTEMPTS {
+        tracing::error!(hook = ?hook.id, event = ?delivery.event.id, "giving up on webhook delivery");
+        return delivery.delete().await;
+    }
+
+    let delay = RETRY_BASE_DELAY * 2u32.pow(delivery.attempts - 1);
+    delivery.next_attempt =
+        Datetime::from(chrono::Utc::now() + chrono::TimeDelta::from_std(delay)?);
+    delivery.save().await?;
+    Ok(())
+}
+
+/// POST an event to a webhook, failing unless it responds with a success status
+async fn send(hook: &Webhook, event: &LogEvent) -> reqwest::Result<()> {
+    let body = serde_json::to_vec(event).expect("events serialize to JSON");
+    let mut req = HTTP_CLIENT
+        .post(&hook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, &event.action)
+        .header(DELIVERY_HEADER, event.id.id.to_raw());
+    if let Some(secret) = &hook.secret {
+        req = req.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, &body)));
+    }
+
+    req.body(body).send().await?.error_for_status()?;
+    Ok(())
+}
+
+/// Spawn the background task retrying queued deliveries, including those left over from before
+/// a restart
+pub fn spawn_delivery_task(interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = deliver_due().await {
+                tracing::error!(?e, "failed to deliver webhooks");
+            }
+        }
+    })
+}
+
+/// Hex encoded HMAC-SHA256 of the body with the webhook's secret
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -0,0 +1,365 @@
+//! Command line client for the Subatomic-NG HTTP API
+//!
+//! Meant for release scripts and admins: `subatomic-ctl upload *.rpm --tag foo`,
+//! `subatomic-ctl assemble foo`, and so on. The server and token are read from
+//! `SUBATOMIC_URL` and `SUBATOMIC_TOKEN`, or their matching options.
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use color_eyre::{eyre::eyre, Result};
+use serde_json::{json, Value};
+
+/// Interval between polls of a running job
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Parser)]
+#[command(name = "subatomic-ctl", version, about)]
+struct Cli {
+    /// Base URL of the Subatomic server
+    #[arg(long, env = "SUBATOMIC_URL", default_value = "http://localhost:3000")]
+    url: String,
+    /// API token, sent as a bearer token
+    #[arg(long, env = "SUBATOMIC_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// Print the server's responses as JSON
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Upload packages to a tag, the package type is guessed from the file extension
+    Upload {
+        #[arg(long)]
+        tag: String,
+        /// Make older versions of the packages unavailable
+        #[arg(long)]
+        prune: bool,
+        /// Mark the packages as latest even if newer versions are available
+        #[arg(long)]
+        force: bool,
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Manage tags
+    #[command(subcommand)]
+    Tag(TagCommand),
+    /// Assemble the repository of a tag
+    Assemble {
+        tag: String,
+        /// Return once the job is started instead of waiting for it
+        #[arg(long)]
+        no_wait: bool,
+    },
+    /// Manage signing keys
+    #[command(subcommand)]
+    Key(KeyCommand),
+    /// Clean up left over uploads, staging directories and cache entries on the server
+    Gc {
+        /// Return once the job is started instead of waiting for it
+        #[arg(long)]
+        no_wait: bool,
+    },
+    /// Server administration
+    #[command(subcommand)]
+    Admin(AdminCommand),
+}
+
+#[derive(Debug, Subcommand)]
+enum AdminCommand {
+    /// Import every RPM under a directory of the server into a tag, resuming earlier runs
+    Import {
+        #[arg(long)]
+        tag: String,
+        /// Directory to import, relative to the server's `IMPORT_ROOT`, the whole root if unset
+        dir: Option<String>,
+        /// Files imported at the same time
+        #[arg(long)]
+        concurrency: Option<usize>,
+        /// Return once the job is started instead of waiting for it
+        #[arg(long)]
+        no_wait: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum TagCommand {
+    /// List every tag
+    List,
+    /// Create a tag
+    Create {
+        name: String,
+        /// Kind of packages the tag holds, i.e. `rpm` or `deb`
+        #[arg(long = "type", default_value = "rpm")]
+        repo_type: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum KeyCommand {
+    /// List every signing key
+    List,
+    /// Generate a signing key on the server
+    Create {
+        /// ID of the key in the keyring
+        id: String,
+        /// User ID of the key, i.e. `John Doe <john@example.com>`
+        #[arg(long)]
+        user_id: String,
+        #[arg(long)]
+        description: Option<String>,
+        /// `ed25519`, `rsa-4096` or `ecdsa`, `ed25519` by default
+        #[arg(long)]
+        algorithm: Option<String>,
+        /// Days until the key expires, never if unset
+        #[arg(long)]
+        expires_in_days: Option<u32>,
+        /// Tag allowed to be signed with the key, any tag if none are given
+        #[arg(long = "allowed-tag")]
+        allowed_tags: Vec<String>,
+    },
+}
+
+/// A client for the server's HTTP API
+struct Client {
+    base: String,
+    token: Option<String>,
+    http: reqwest::Client,
+}
+
+impl Client {
+    fn new(base: &str, token: Option<String>) -> Self {
+        Self {
+            base: base.trim_end_matches('/').to_owned(),
+            token,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let req = self.http.request(method, format!("{}{path}", self.base));
+        match &self.token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        }
+    }
+
+    /// Send a request, turning error responses into errors with the server's message
+    async fn send(&self, req: reqwest::RequestBuilder) -> Result<Value> {
+        let res = req.send().await?;
+        let status = res.status();
+        let body = res.text().await?;
+        if !status.is_success() {
+            let message = serde_json::from_str::<Value>(&body)
+                .ok()
+                .and_then(|v| v["message"].as_str().map(str::to_owned))
+                .unwrap_or(body);
+            return Err(eyre!("{status}: {message}"));
+        }
+        if body.is_empty() {
+            return Ok(Value::Null);
+        }
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    async fn get(&self, path: &str) -> Result<Value> {
+        self.send(self.request(reqwest::Method::GET, path)).await
+    }
+
+    async fn post(&self, path: &str, body: Value) -> Result<Value> {
+        self.send(self.request(reqwest::Method::POST, path).json(&body))
+            .await
+    }
+
+    /// Stream a package to the raw upload route of its type
+    async fn upload(&self, tag: &str, file: &Path, prune: bool, force: bool) -> Result<Value> {
+        let kind = package_kind(file)?;
+        let filename = file
+            .file_name()
+            .ok_or_else(|| eyre!("{} isn't a file", file.display()))?
+            .to_string_lossy()
+            .into_owned();
+        let handle = tokio::fs::File::open(file).await?;
+        let size = handle.metadata().await?.len();
+        let body = reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::new(handle));
+        let query = [
+            ("tag", tag.to_owned()),
+            ("prune", prune.to_string()),
+            ("force", force.to_string()),
+            ("filename", filename),
+        ];
+        let req = self
+            .request(reqwest::Method::PUT, &format!("/{kind}/upload/raw"))
+            .query(&query)
+            .header(reqwest::header::CONTENT_LENGTH, size)
+            .body(body);
+        self.send(req).await
+    }
+
+    /// Poll a job until it's finished, failing if the job did
+    async fn wait_for_job(&self, job: Value) -> Result<Value> {
+        let id = job_id(&job).ok_or_else(|| eyre!("no job ID in {job}"))?;
+        loop {
+            let job = self.get(&format!("/jobs/{id}")).await?;
+            match job["status"].as_str() {
+                Some("queued" | "running") => tokio::time::sleep(JOB_POLL_INTERVAL).await,
+                Some("success") => return Ok(job),
+                _ => {
+                    let error = job["error"].as_str().unwrap_or("unknown error");
+                    return Err(eyre!("job {id} failed: {error}"));
+                }
+            }
+        }
+    }
+}
+
+/// Route prefix of the package type of a file, from its extension
+fn package_kind(file: &Path) -> Result<&'static str> {
+    let name = file.to_string_lossy();
+    if name.ends_with(".rpm") {
+        Ok("rpm")
+    } else if name.ends_with(".deb") {
+        Ok("deb")
+    } else if name.contains(".pkg.tar") {
+        Ok("pacman")
+    } else {
+        Err(eyre!("cannot tell the package type of {name}"))
+    }
+}
+
+/// ID of a job, as returned by the routes starting one
+fn job_id(job: &Value) -> Option<&str> {
+    let id = &job["id"]["id"];
+    id["String"].as_str().or_else(|| id.as_str())
+}
+
+fn print(value: &Value) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let client = Client::new(&cli.url, cli.token);
+    match cli.command {
+        Command::Upload {
+            tag,
+            prune,
+            force,
+            files,
+        } => {
+            let mut failed = 0;
+            for file in &files {
+                match client.upload(&tag, file, prune, force).await {
+                    Ok(res) if cli.json => print(&res)?,
+                    Ok(_) => println!("uploaded {}", file.display()),
+                    Err(e) => {
+                        eprintln!("failed to upload {}: {e}", file.display());
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                return Err(eyre!("{failed} of {} uploads failed", files.len()));
+            }
+        }
+        Command::Tag(TagCommand::List) => {
+            let tags = client.get("/repos").await?;
+            if cli.json {
+                return print(&tags);
+            }
+            for tag in tags.as_array().into_iter().flatten() {
+                let name = tag["name"].as_str().unwrap_or_default();
+                let repo_type = tag["type"].as_str().unwrap_or("rpm");
+                println!("{name}\t{repo_type}");
+            }
+        }
+        Command::Tag(TagCommand::Create { name, repo_type }) => {
+            let tag = client
+                .post("/repo", json!({ "name": name, "type": repo_type }))
+                .await?;
+            print(&tag)?;
+        }
+        Command::Assemble { tag, no_wait } => {
+            let job = client.post(&format!("/repo/{tag}/assemble"), Value::Null).await?;
+            let job = if no_wait { job } else { client.wait_for_job(job).await? };
+            print(&job)?;
+        }
+        Command::Key(KeyCommand::List) => print(&client.get("/keys").await?)?,
+        Command::Key(KeyCommand::Create {
+            id,
+            user_id,
+            description,
+            algorithm,
+            expires_in_days,
+            allowed_tags,
+        }) => {
+            let mut body = json!({
+                "id": id,
+                "user_id": user_id,
+                "description": description,
+                "expires_in_days": expires_in_days,
+                "allowed_tags": allowed_tags,
+            });
+            if let Some(algorithm) = algorithm {
+                body["algorithm"] = json!(algorithm);
+            }
+            print(&client.post("/key", body).await?)?;
+        }
+        Command::Gc { no_wait } => {
+            let job = client.post("/admin/janitor", Value::Null).await?;
+            let job = if no_wait { job } else { client.wait_for_job(job).await? };
+            print(&job)?;
+        }
+        Command::Admin(AdminCommand::Import {
+            tag,
+            dir,
+            concurrency,
+            no_wait,
+        }) => {
+            let body = json!({ "tag": tag, "dir": dir, "concurrency": concurrency });
+            let job = client.post("/admin/import", body).await?;
+            let job = if no_wait {
+                job
+            } else {
+                client.wait_for_job(job).await?
+            };
+            print(&job)?;
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    run(Cli::parse()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_package_kind() {
+        assert_eq!(package_kind(Path::new("a/hello-1.0-1.x86_64.rpm")).unwrap(), "rpm");
+        assert_eq!(package_kind(Path::new("hello_1.0-1_all.deb")).unwrap(), "deb");
+        assert_eq!(
+            package_kind(Path::new("hello-1.0-1-any.pkg.tar.zst")).unwrap(),
+            "pacman"
+        );
+        assert!(package_kind(Path::new("hello.tar.gz")).is_err());
+    }
+
+    #[test]
+    fn test_job_id() {
+        let job = json!({ "id": { "tb": "job", "id": { "String": "01J" } } });
+        assert_eq!(job_id(&job), Some("01J"));
+        assert_eq!(job_id(&json!({})), None);
+        Cli::try_parse_from(["subatomic-ctl", "upload", "--tag", "foo", "a.rpm"]).unwrap();
+        assert!(Cli::try_parse_from(["subatomic-ctl", "upload", "--tag", "foo"]).is_err());
+        Cli::try_parse_from(["subatomic-ctl", "admin", "import", "--tag", "foo", "f41"]).unwrap();
+    }
+}